@@ -0,0 +1,321 @@
+//! CPU frequency scaling.
+//!
+//! Platform clock drivers register a [`CpufreqDriver`] for each group of
+//! CPUs sharing a clock, which becomes a policy. Each policy is run by a
+//! governor:
+//!
+//! - `performance` keeps the highest allowed frequency,
+//! - `powersave` keeps the lowest,
+//! - `ondemand` samples how busy the CPUs were and scales with the load,
+//! - `userspace` uses whatever was written to `scaling_setspeed`.
+//!
+//! [`init`] registers Enhanced Intel SpeedStep for the CPUs that have it.
+//!
+//! Policies are exposed under `/sys/devices/system/cpu/cpufreq`.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axsync::Mutex;
+use spin::{Once, RwLock};
+
+#[cfg(target_arch = "x86_64")]
+mod speedstep;
+
+/// How often the ondemand governor samples the load.
+const SAMPLING_RATE: Duration = Duration::from_millis(100);
+
+/// The load, in percent, above which ondemand jumps to the highest
+/// frequency.
+const UP_THRESHOLD: usize = 80;
+
+/// A driver for the clock of a group of CPUs.
+pub trait CpufreqDriver: Send + Sync {
+    /// Returns the name of the driver, e.g. `cpufreq-dt`.
+    fn name(&self) -> &str;
+
+    /// Returns the supported frequencies in kHz, in ascending order.
+    fn frequencies(&self) -> &[u32];
+
+    /// Returns the current frequency in kHz.
+    fn get(&self) -> u32;
+
+    /// Switches to `khz`, one of [`frequencies`](Self::frequencies).
+    fn set(&self, khz: u32) -> AxResult<()>;
+
+    /// Returns how long a switch takes, in nanoseconds.
+    fn transition_latency(&self) -> u32 {
+        0
+    }
+}
+
+/// A cpufreq governor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Governor {
+    /// Always run at the highest allowed frequency.
+    Performance,
+    /// Always run at the lowest allowed frequency.
+    Powersave,
+    /// Scale with the load.
+    Ondemand,
+    /// Run at the frequency set by userspace.
+    Userspace,
+}
+
+impl Governor {
+    /// All governors, in the order listed by `scaling_available_governors`.
+    pub const ALL: [Governor; 4] = [
+        Governor::Performance,
+        Governor::Powersave,
+        Governor::Ondemand,
+        Governor::Userspace,
+    ];
+
+    /// Returns the name of the governor.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Governor::Performance => "performance",
+            Governor::Powersave => "powersave",
+            Governor::Ondemand => "ondemand",
+            Governor::Userspace => "userspace",
+        }
+    }
+
+    /// Looks up a governor by name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|it| it.as_str() == name)
+    }
+}
+
+/// A group of CPUs sharing a clock.
+pub struct Policy {
+    driver: Arc<dyn CpufreqDriver>,
+    cpus: Range<usize>,
+    min: AtomicU32,
+    max: AtomicU32,
+    setspeed: AtomicU32,
+    governor: Mutex<Governor>,
+}
+
+impl Policy {
+    /// Returns the driver of the policy.
+    pub fn driver(&self) -> &Arc<dyn CpufreqDriver> {
+        &self.driver
+    }
+
+    /// Returns the CPUs in the policy.
+    pub fn cpus(&self) -> Range<usize> {
+        self.cpus.clone()
+    }
+
+    /// Returns the lowest supported frequency.
+    pub fn cpuinfo_min(&self) -> u32 {
+        self.driver.frequencies().first().copied().unwrap_or(0)
+    }
+
+    /// Returns the highest supported frequency.
+    pub fn cpuinfo_max(&self) -> u32 {
+        self.driver.frequencies().last().copied().unwrap_or(0)
+    }
+
+    /// Returns the lowest frequency the governor may pick.
+    pub fn min(&self) -> u32 {
+        self.min.load(Ordering::Relaxed)
+    }
+
+    /// Returns the highest frequency the governor may pick.
+    pub fn max(&self) -> u32 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Sets the lowest frequency the governor may pick.
+    pub fn set_min(&self, khz: u32) -> AxResult<()> {
+        if khz < self.cpuinfo_min() || khz > self.max() {
+            return Err(AxError::InvalidInput);
+        }
+        self.min.store(khz, Ordering::Relaxed);
+        self.update(None)
+    }
+
+    /// Sets the highest frequency the governor may pick.
+    pub fn set_max(&self, khz: u32) -> AxResult<()> {
+        if khz > self.cpuinfo_max() || khz < self.min() {
+            return Err(AxError::InvalidInput);
+        }
+        self.max.store(khz, Ordering::Relaxed);
+        self.update(None)
+    }
+
+    /// Returns the governor of the policy.
+    pub fn governor(&self) -> Governor {
+        *self.governor.lock()
+    }
+
+    /// Switches to `governor`.
+    pub fn set_governor(&self, governor: Governor) -> AxResult<()> {
+        *self.governor.lock() = governor;
+        if governor == Governor::Userspace {
+            self.setspeed.store(self.driver.get(), Ordering::Relaxed);
+        }
+        self.update(None)
+    }
+
+    /// Returns the frequency set through `scaling_setspeed`.
+    pub fn setspeed(&self) -> u32 {
+        self.setspeed.load(Ordering::Relaxed)
+    }
+
+    /// Sets the frequency used by the `userspace` governor.
+    pub fn set_setspeed(&self, khz: u32) -> AxResult<()> {
+        if self.governor() != Governor::Userspace {
+            return Err(AxError::InvalidInput);
+        }
+        self.setspeed.store(khz, Ordering::Relaxed);
+        self.update(None)
+    }
+
+    /// Picks the lowest supported frequency of at least `khz`, within the
+    /// allowed range.
+    fn resolve(&self, khz: u32) -> u32 {
+        let khz = khz.clamp(self.min(), self.max());
+        let frequencies = self.driver.frequencies();
+        frequencies
+            .iter()
+            .copied()
+            .filter(|&it| it <= self.max())
+            .find(|&it| it >= khz)
+            .or_else(|| frequencies.iter().copied().rfind(|&it| it <= self.max()))
+            .unwrap_or(khz)
+    }
+
+    /// Applies the governor, given the load in percent for `ondemand`.
+    fn update(&self, load: Option<usize>) -> AxResult<()> {
+        let target = match self.governor() {
+            Governor::Performance => self.max(),
+            Governor::Powersave => self.min(),
+            Governor::Userspace => self.setspeed(),
+            Governor::Ondemand => match load {
+                Some(load) if load > UP_THRESHOLD => self.max(),
+                Some(load) => {
+                    let (min, max) = (self.min() as u64, self.max() as u64);
+                    (min + (max - min) * load as u64 / 100) as u32
+                }
+                // Keep the current frequency until the next sample.
+                None => self.driver.get(),
+            },
+        };
+        let target = self.resolve(target);
+        if target != self.driver.get() {
+            self.driver.set(target)?;
+        }
+        Ok(())
+    }
+}
+
+static POLICIES: RwLock<Vec<Arc<Policy>>> = RwLock::new(Vec::new());
+static SAMPLER: Once = Once::new();
+
+static BUSY_TICKS: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Records whether the CPU was busy at a timer tick.
+///
+/// Called from the timer interrupt.
+pub(crate) fn account_tick() {
+    TOTAL_TICKS.fetch_add(1, Ordering::Relaxed);
+    if axtask::current().name() != "idle" {
+        BUSY_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns all policies, indexed by ID.
+pub fn policies() -> Vec<Arc<Policy>> {
+    POLICIES.read().clone()
+}
+
+/// Returns the policy `cpu` belongs to.
+pub fn policy_of(cpu: usize) -> Option<(usize, Arc<Policy>)> {
+    POLICIES
+        .read()
+        .iter()
+        .enumerate()
+        .find(|(_, policy)| policy.cpus.contains(&cpu))
+        .map(|(id, policy)| (id, policy.clone()))
+}
+
+/// Registers the clock driver for `cpus`, returning the ID of the new
+/// policy, which starts out with the `ondemand` governor.
+pub fn register(driver: Arc<dyn CpufreqDriver>, cpus: Range<usize>) -> AxResult<usize> {
+    if driver.frequencies().is_empty() {
+        return Err(AxError::InvalidInput);
+    }
+    let frequencies = driver.frequencies();
+    let policy = Arc::new(Policy {
+        cpus,
+        min: AtomicU32::new(frequencies[0]),
+        max: AtomicU32::new(frequencies[frequencies.len() - 1]),
+        setspeed: AtomicU32::new(driver.get()),
+        governor: Mutex::new(Governor::Ondemand),
+        driver,
+    });
+    let mut policies = POLICIES.write();
+    if policies
+        .iter()
+        .any(|it| it.cpus.start < policy.cpus.end && policy.cpus.start < it.cpus.end)
+    {
+        return Err(AxError::AlreadyExists);
+    }
+    info!(
+        "cpufreq: policy{} for CPUs {:?} ({})",
+        policies.len(),
+        policy.cpus,
+        policy.driver.name()
+    );
+    policies.push(policy);
+    let id = policies.len() - 1;
+    drop(policies);
+
+    SAMPLER.call_once(|| {
+        axtask::spawn(
+            || {
+                let mut last = (0, 0);
+                loop {
+                    axtask::sleep(SAMPLING_RATE);
+                    let now = (
+                        BUSY_TICKS.load(Ordering::Relaxed),
+                        TOTAL_TICKS.load(Ordering::Relaxed),
+                    );
+                    let busy = now.0.wrapping_sub(last.0);
+                    let total = now.1.wrapping_sub(last.1);
+                    last = now;
+                    if total == 0 {
+                        continue;
+                    }
+                    let load = busy * 100 / total;
+                    for policy in policies() {
+                        if let Err(err) = policy.update(Some(load)) {
+                            warn!("cpufreq: failed to scale {:?}: {err:?}", policy.cpus);
+                        }
+                    }
+                }
+            },
+            "cpufreq".into(),
+        );
+    });
+    Ok(id)
+}
+
+/// Registers the clock driver of the platform, if there is one.
+pub fn init() {
+    #[cfg(target_arch = "x86_64")]
+    if let Some(driver) = speedstep::probe()
+        && let Err(err) = register(driver, 0..axconfig::plat::CPU_NUM)
+    {
+        warn!("cpufreq: failed to register SpeedStep: {err:?}");
+    }
+}
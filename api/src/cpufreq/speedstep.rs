@@ -0,0 +1,97 @@
+//! Enhanced Intel SpeedStep, switching the performance state of the CPU by
+//! writing its ratio to the bus clock to `IA32_PERF_CTL`.
+//!
+//! The supported ratios are read from `MSR_PLATFORM_INFO` rather than the
+//! ACPI tables, from the most efficient one to the highest not using
+//! turbo, and the bus clock is taken to be 100 MHz, so only CPUs since
+//! Sandy Bridge are supported.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::arch::x86_64::__cpuid;
+
+use axerrno::AxResult;
+use x86::msr::{
+    IA32_MISC_ENABLE, IA32_PERF_CTL, IA32_PERF_STATUS, MSR_PLATFORM_INFO, rdmsr, wrmsr,
+};
+
+use super::CpufreqDriver;
+
+/// The bus clock, in kHz.
+const BCLK_KHZ: u32 = 100_000;
+
+/// Whether SpeedStep is enabled, in `IA32_MISC_ENABLE`; the firmware may
+/// have turned it off.
+const MISC_ENABLE_EIST: u64 = 1 << 16;
+
+/// `IA32_PM_ENABLE`, set once hardware P-states have taken over, after
+/// which `IA32_PERF_CTL` is ignored.
+const IA32_PM_ENABLE: u32 = 0x770;
+
+struct SpeedStep {
+    frequencies: Vec<u32>,
+}
+
+impl CpufreqDriver for SpeedStep {
+    fn name(&self) -> &str {
+        "speedstep"
+    }
+
+    fn frequencies(&self) -> &[u32] {
+        &self.frequencies
+    }
+
+    fn get(&self) -> u32 {
+        // SAFETY: the MSR exists on CPUs with SpeedStep.
+        let ratio = (unsafe { rdmsr(IA32_PERF_STATUS) } >> 8) & 0xff;
+        ratio as u32 * BCLK_KHZ
+    }
+
+    fn set(&self, khz: u32) -> AxResult<()> {
+        let ratio = (khz / BCLK_KHZ) as u64;
+        // SAFETY: as above; the other fields are left as they are.
+        unsafe {
+            let ctl = rdmsr(IA32_PERF_CTL);
+            wrmsr(IA32_PERF_CTL, ctl & !0xff00 | ratio << 8);
+        }
+        Ok(())
+    }
+
+    fn transition_latency(&self) -> u32 {
+        10_000
+    }
+}
+
+/// Finds SpeedStep on the boot CPU.
+pub fn probe() -> Option<Arc<dyn CpufreqDriver>> {
+    let vendor = unsafe { __cpuid(0) };
+    // "GenuineIntel"
+    let intel = [vendor.ebx, vendor.edx, vendor.ecx] == [0x756e_6547, 0x4965_6e69, 0x6c65_746e];
+    if !intel || vendor.eax < 6 {
+        return None;
+    }
+    let leaf1 = unsafe { __cpuid(1) };
+    let family = (leaf1.eax >> 8) & 0xf;
+    let model = (leaf1.eax >> 12) & 0xf0 | (leaf1.eax >> 4) & 0xf;
+    let eist = leaf1.ecx & (1 << 7) != 0;
+    if !eist || family != 6 || model < 0x2a {
+        return None;
+    }
+    // SAFETY: these MSRs exist on the CPUs checked for above, the last one
+    // only if they have hardware P-states.
+    unsafe {
+        if rdmsr(IA32_MISC_ENABLE) & MISC_ENABLE_EIST == 0 {
+            return None;
+        }
+        if __cpuid(6).eax & (1 << 7) != 0 && rdmsr(IA32_PM_ENABLE) & 1 != 0 {
+            return None;
+        }
+    }
+    let info = unsafe { rdmsr(MSR_PLATFORM_INFO) };
+    let max = ((info >> 8) & 0xff) as u32;
+    let min = ((info >> 40) & 0xff) as u32;
+    if min == 0 || min > max {
+        return None;
+    }
+    let frequencies = (min..=max).map(|ratio| ratio * BCLK_KHZ).collect();
+    Some(Arc::new(SpeedStep { frequencies }))
+}
@@ -0,0 +1,179 @@
+//! Kernel crypto algorithms, exposed to userspace through `AF_ALG` sockets.
+//!
+//! Algorithms are looked up by type and name. Software implementations are
+//! always available; platform drivers can [`register`] accelerated ones with
+//! a higher priority to take their place.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use spin::RwLock;
+
+/// A message digest in progress.
+pub trait Hash: Send + Sync {
+    /// Feeds `data` into the digest.
+    fn update(&mut self, data: &[u8]);
+
+    /// Finishes the digest and returns it.
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+/// An authenticated cipher with its key set.
+pub trait Aead: Send + Sync {
+    /// Encrypts `data` in place and returns the authentication tag.
+    fn encrypt(&self, iv: &[u8], aad: &[u8], data: &mut [u8]) -> AxResult<Vec<u8>>;
+
+    /// Decrypts `data` in place after checking it against `tag`.
+    ///
+    /// Fails with `EBADMSG` if the message does not authenticate.
+    fn decrypt(&self, iv: &[u8], aad: &[u8], data: &mut [u8], tag: &[u8]) -> AxResult;
+}
+
+/// What an algorithm does and how to instantiate it.
+#[derive(Clone, Copy)]
+pub enum AlgorithmKind {
+    /// A message digest.
+    Hash { new: fn() -> Box<dyn Hash> },
+    /// An authenticated cipher.
+    Aead {
+        iv_size: usize,
+        auth_size: usize,
+        new: fn(key: &[u8]) -> AxResult<Box<dyn Aead>>,
+    },
+}
+
+/// An implementation of a crypto algorithm.
+#[derive(Clone, Copy)]
+pub struct Algorithm {
+    /// The generic name, such as `sha256` or `gcm(aes)`.
+    pub name: &'static str,
+    /// The name of this particular implementation.
+    pub driver: &'static str,
+    /// Implementations with a higher priority are preferred.
+    pub priority: u32,
+    pub kind: AlgorithmKind,
+}
+
+impl Algorithm {
+    /// The `AF_ALG` type of the algorithm.
+    pub fn type_name(&self) -> &'static str {
+        match self.kind {
+            AlgorithmKind::Hash { .. } => "hash",
+            AlgorithmKind::Aead { .. } => "aead",
+        }
+    }
+}
+
+mod generic {
+    use aes_gcm::{
+        Aes128Gcm, Aes256Gcm, AesGcm, KeyInit,
+        aead::{AeadInPlace, consts::U12, generic_array::GenericArray},
+        aes::Aes192,
+    };
+    use sha2::Digest;
+
+    use super::*;
+
+    struct Sha256(sha2::Sha256);
+
+    impl Hash for Sha256 {
+        fn update(&mut self, data: &[u8]) {
+            Digest::update(&mut self.0, data);
+        }
+
+        fn finalize(self: Box<Self>) -> Vec<u8> {
+            self.0.finalize().to_vec()
+        }
+    }
+
+    pub fn sha256() -> Box<dyn Hash> {
+        Box::new(Sha256(sha2::Sha256::new()))
+    }
+
+    struct Gcm<C>(C);
+
+    impl<C: AeadInPlace<NonceSize = U12> + Send + Sync> Aead for Gcm<C> {
+        fn encrypt(&self, iv: &[u8], aad: &[u8], data: &mut [u8]) -> AxResult<Vec<u8>> {
+            let tag = self
+                .0
+                .encrypt_in_place_detached(GenericArray::from_slice(iv), aad, data)
+                .map_err(|_| AxError::InvalidInput)?;
+            Ok(tag.to_vec())
+        }
+
+        fn decrypt(&self, iv: &[u8], aad: &[u8], data: &mut [u8], tag: &[u8]) -> AxResult {
+            self.0
+                .decrypt_in_place_detached(
+                    GenericArray::from_slice(iv),
+                    aad,
+                    data,
+                    GenericArray::from_slice(tag),
+                )
+                .map_err(|_| AxError::Other(LinuxError::EBADMSG))
+        }
+    }
+
+    pub fn gcm_aes(key: &[u8]) -> AxResult<Box<dyn Aead>> {
+        let invalid = |_| AxError::InvalidInput;
+        let cipher: Box<dyn Aead> = match key.len() {
+            16 => Box::new(Gcm(Aes128Gcm::new_from_slice(key).map_err(invalid)?)),
+            24 => Box::new(Gcm(
+                AesGcm::<Aes192, U12>::new_from_slice(key).map_err(invalid)?
+            )),
+            32 => Box::new(Gcm(Aes256Gcm::new_from_slice(key).map_err(invalid)?)),
+            _ => return Err(AxError::InvalidInput),
+        };
+        Ok(cipher)
+    }
+}
+
+/// The software implementations, available on every platform.
+const GENERIC_ALGORITHMS: &[Algorithm] = &[
+    Algorithm {
+        name: "sha256",
+        driver: "sha256-generic",
+        priority: 100,
+        kind: AlgorithmKind::Hash {
+            new: generic::sha256,
+        },
+    },
+    Algorithm {
+        name: "gcm(aes)",
+        driver: "gcm(aes-generic)",
+        priority: 100,
+        kind: AlgorithmKind::Aead {
+            iv_size: 12,
+            auth_size: 16,
+            new: generic::gcm_aes,
+        },
+    },
+];
+
+/// Algorithms registered by platform drivers.
+static ALGORITHMS: RwLock<Vec<Algorithm>> = RwLock::new(Vec::new());
+
+/// Registers an implementation of an algorithm, typically a hardware
+/// accelerated one provided by a platform driver.
+///
+/// It is used instead of the existing implementations of the same algorithm
+/// if its priority is higher.
+pub fn register(alg: Algorithm) {
+    info!(
+        "crypto: registered {} ({}), priority {}",
+        alg.name, alg.driver, alg.priority
+    );
+    ALGORITHMS.write().push(alg);
+}
+
+/// Finds the preferred implementation of algorithm `name` of type `ty`.
+///
+/// `name` can also be the driver name of a specific implementation.
+pub fn find(ty: &str, name: &str) -> Option<Algorithm> {
+    let registered = ALGORITHMS.read();
+    GENERIC_ALGORITHMS
+        .iter()
+        .chain(registered.iter())
+        .filter(|alg| alg.type_name() == ty && (alg.name == name || alg.driver == name))
+        .max_by_key(|alg| alg.priority)
+        .copied()
+}
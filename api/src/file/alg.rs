@@ -0,0 +1,411 @@
+//! `AF_ALG` sockets, the userspace interface to [`crate::crypto`].
+//!
+//! A socket is bound to an algorithm, given a key with
+//! `setsockopt(SOL_ALG, ALG_SET_KEY)` if the algorithm needs one, and then
+//! `accept`ed to get an operation socket. Data sent to the operation socket
+//! is hashed, encrypted or decrypted, and the result is read back from it.
+
+use alloc::{borrow::Cow, boxed::Box, format, sync::Arc, vec, vec::Vec};
+use core::{
+    any::Any,
+    ffi::CStr,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axio::{Buf, BufMut, Read, Write};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::future::Poller;
+use bytemuck::AnyBitPattern;
+use linux_raw_sys::{general::S_IFSOCK, net::AF_ALG};
+use spin::Mutex;
+use starry_vm::{VmPtr, vm_load};
+
+use super::{FileLike, Kstat, SealedBuf, SealedBufMut};
+use crate::crypto::{self, Aead, Algorithm, AlgorithmKind, Hash};
+
+/// The socket option and control message level of `AF_ALG` sockets.
+pub const SOL_ALG: u32 = 279;
+
+const ALG_SET_KEY: u32 = 1;
+pub const ALG_SET_IV: u32 = 2;
+pub const ALG_SET_OP: u32 = 3;
+pub const ALG_SET_AEAD_ASSOCLEN: u32 = 4;
+const ALG_SET_AEAD_AUTHSIZE: u32 = 5;
+
+const ALG_OP_DECRYPT: u32 = 0;
+const ALG_OP_ENCRYPT: u32 = 1;
+
+/// The most data a single AEAD request may carry.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// `struct sockaddr_alg`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+pub struct SockAddrAlg {
+    pub salg_family: u16,
+    pub salg_type: [u8; 14],
+    pub salg_feat: u32,
+    pub salg_mask: u32,
+    pub salg_name: [u8; 64],
+}
+
+/// Request parameters passed to an operation socket as `SOL_ALG` control
+/// messages.
+#[derive(Default)]
+pub struct AlgControl {
+    pub op: Option<u32>,
+    pub iv: Option<Vec<u8>>,
+    pub assoc_len: Option<u32>,
+}
+
+fn c_str(bytes: &[u8]) -> AxResult<&str> {
+    CStr::from_bytes_until_nul(bytes)
+        .ok()
+        .and_then(|s| s.to_str().ok())
+        .ok_or(AxError::InvalidInput)
+}
+
+struct Transform {
+    alg: Algorithm,
+    key: Option<Vec<u8>>,
+}
+
+/// An `AF_ALG` socket, which selects an algorithm and holds its key.
+#[derive(Default)]
+pub struct AlgSocket {
+    transform: Mutex<Option<Transform>>,
+}
+
+impl AlgSocket {
+    pub fn bind(&self, addr: *const SockAddrAlg, addrlen: u32) -> AxResult {
+        if (addrlen as usize) < size_of::<SockAddrAlg>() {
+            return Err(AxError::InvalidInput);
+        }
+        let addr = addr.vm_read()?;
+        if addr.salg_family as u32 != AF_ALG {
+            return Err(AxError::InvalidInput);
+        }
+        let ty = c_str(&addr.salg_type)?;
+        let name = c_str(&addr.salg_name)?;
+        debug!("AF_ALG bind <= type: {}, name: {}", ty, name);
+
+        let alg = crypto::find(ty, name).ok_or(AxError::NotFound)?;
+        *self.transform.lock() = Some(Transform { alg, key: None });
+        Ok(())
+    }
+
+    pub fn set_option(&self, level: u32, optname: u32, optval: *const u8, optlen: u32) -> AxResult {
+        let mut transform = self.transform.lock();
+        let Some(transform) = transform.as_mut().filter(|_| level == SOL_ALG) else {
+            return Err(AxError::Other(LinuxError::ENOPROTOOPT));
+        };
+        match (optname, transform.alg.kind) {
+            (ALG_SET_KEY, AlgorithmKind::Aead { new, .. }) => {
+                let key = vm_load(optval, optlen as usize)?;
+                // Reject bad keys here rather than on accept.
+                new(&key)?;
+                transform.key = Some(key);
+            }
+            (ALG_SET_KEY, AlgorithmKind::Hash { .. }) => {
+                return Err(AxError::Other(LinuxError::ENOSYS));
+            }
+            // The tag size is passed in `optlen` itself.
+            (ALG_SET_AEAD_AUTHSIZE, AlgorithmKind::Aead { auth_size, .. }) => {
+                if optlen as usize != auth_size {
+                    return Err(AxError::InvalidInput);
+                }
+            }
+            _ => return Err(AxError::Other(LinuxError::ENOPROTOOPT)),
+        }
+        Ok(())
+    }
+
+    pub fn accept(&self) -> AxResult<AlgOpSocket> {
+        let transform = self.transform.lock();
+        let transform = transform.as_ref().ok_or(AxError::InvalidInput)?;
+        let state = match transform.alg.kind {
+            AlgorithmKind::Hash { new } => OpState::Hash(HashState {
+                new,
+                hasher: None,
+                digest: None,
+            }),
+            AlgorithmKind::Aead {
+                iv_size,
+                auth_size,
+                new,
+            } => {
+                let key = transform
+                    .key
+                    .as_ref()
+                    .ok_or(AxError::Other(LinuxError::ENOKEY))?;
+                OpState::Aead(AeadState {
+                    cipher: new(key)?,
+                    auth_size,
+                    encrypt: false,
+                    iv: vec![0; iv_size],
+                    assoc_len: 0,
+                    data: Vec::new(),
+                    ready: false,
+                })
+            }
+        };
+        Ok(AlgOpSocket {
+            state: Mutex::new(state),
+            non_blocking: AtomicBool::new(false),
+            poll_rx: PollSet::new(),
+            poll_tx: PollSet::new(),
+        })
+    }
+}
+
+impl FileLike for AlgSocket {
+    fn read(&self, _dst: &mut SealedBufMut) -> AxResult<usize> {
+        Err(AxError::OperationNotSupported)
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::OperationNotSupported)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat {
+            mode: S_IFSOCK | 0o777u32,
+            ..Default::default()
+        })
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        format!("socket:[{}]", self as *const _ as usize).into()
+    }
+}
+
+impl Pollable for AlgSocket {
+    fn poll(&self) -> IoEvents {
+        IoEvents::empty()
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+struct HashState {
+    new: fn() -> Box<dyn Hash>,
+    /// The digest being computed, while data is sent with `MSG_MORE`.
+    hasher: Option<Box<dyn Hash>>,
+    /// The finished digest, waiting to be read.
+    digest: Option<Vec<u8>>,
+}
+
+impl HashState {
+    fn send(&mut self, src: &mut impl Buf, more: bool) -> AxResult<usize> {
+        let hasher = self.hasher.get_or_insert_with(self.new);
+        let mut buf = [0; 512];
+        let mut sent = 0;
+        loop {
+            let read = src.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            sent += read;
+        }
+        if !more {
+            self.digest = self.hasher.take().map(|hasher| hasher.finalize());
+        }
+        Ok(sent)
+    }
+
+    fn recv(&mut self, dst: &mut impl BufMut) -> AxResult<usize> {
+        let digest = match (self.hasher.take(), self.digest.take()) {
+            (Some(hasher), _) => hasher.finalize(),
+            (None, Some(digest)) => digest,
+            // Reading without sending anything gives the digest of nothing.
+            (None, None) => (self.new)().finalize(),
+        };
+        let len = digest.len().min(dst.remaining_mut());
+        dst.write(&digest[..len])
+    }
+}
+
+struct AeadState {
+    cipher: Box<dyn Aead>,
+    auth_size: usize,
+    encrypt: bool,
+    iv: Vec<u8>,
+    assoc_len: usize,
+    /// The associated data followed by the plaintext or ciphertext.
+    data: Vec<u8>,
+    /// Whether the request is complete, i.e. was last sent without
+    /// `MSG_MORE`.
+    ready: bool,
+}
+
+impl AeadState {
+    fn send(&mut self, src: &mut impl Buf, more: bool, control: AlgControl) -> AxResult<usize> {
+        // The previous request has to be read before another one is sent.
+        if self.ready {
+            return Err(AxError::InvalidInput);
+        }
+        match control.op {
+            Some(ALG_OP_ENCRYPT) => self.encrypt = true,
+            Some(ALG_OP_DECRYPT) => self.encrypt = false,
+            Some(_) => return Err(AxError::InvalidInput),
+            None => {}
+        }
+        if let Some(iv) = control.iv {
+            if iv.len() != self.iv.len() {
+                return Err(AxError::InvalidInput);
+            }
+            self.iv = iv;
+        }
+        if let Some(assoc_len) = control.assoc_len {
+            self.assoc_len = assoc_len as usize;
+        }
+
+        let sent = src.remaining();
+        if self.data.len() + sent > MAX_REQUEST_SIZE {
+            return Err(AxError::Other(LinuxError::EMSGSIZE));
+        }
+        let start = self.data.len();
+        self.data.resize(start + sent, 0);
+        let sent = src.read(&mut self.data[start..])?;
+        self.data.truncate(start + sent);
+        self.ready = !more;
+        Ok(sent)
+    }
+
+    fn recv(&mut self, dst: &mut impl BufMut) -> AxResult<usize> {
+        let text_len = self
+            .data
+            .len()
+            .checked_sub(self.assoc_len)
+            .ok_or(AxError::InvalidInput)?;
+        // Encryption appends the tag, decryption strips it.
+        let out_len = if self.encrypt {
+            self.data.len() + self.auth_size
+        } else {
+            if text_len < self.auth_size {
+                return Err(AxError::InvalidInput);
+            }
+            self.data.len() - self.auth_size
+        };
+        if dst.remaining_mut() < out_len {
+            return Err(AxError::InvalidInput);
+        }
+
+        let mut data = core::mem::take(&mut self.data);
+        self.ready = false;
+        let (aad, text) = data.split_at_mut(self.assoc_len);
+        if self.encrypt {
+            let tag = self.cipher.encrypt(&self.iv, aad, text)?;
+            dst.write(&data)?;
+            dst.write(&tag)?;
+        } else {
+            let (text, tag) = text.split_at_mut(text_len - self.auth_size);
+            self.cipher.decrypt(&self.iv, aad, text, tag)?;
+            dst.write(&data[..out_len])?;
+        }
+        Ok(out_len)
+    }
+}
+
+enum OpState {
+    Hash(HashState),
+    Aead(AeadState),
+}
+
+/// An operation socket, accepted from an [`AlgSocket`].
+pub struct AlgOpSocket {
+    state: Mutex<OpState>,
+    non_blocking: AtomicBool,
+
+    poll_rx: PollSet,
+    poll_tx: PollSet,
+}
+
+impl AlgOpSocket {
+    pub fn send(&self, src: &mut impl Buf, more: bool, control: AlgControl) -> AxResult<usize> {
+        let sent = match &mut *self.state.lock() {
+            OpState::Hash(hash) => hash.send(src, more)?,
+            OpState::Aead(aead) => aead.send(src, more, control)?,
+        };
+        self.poll_rx.wake();
+        Ok(sent)
+    }
+
+    pub fn recv(&self, dst: &mut impl BufMut, non_blocking: bool) -> AxResult<usize> {
+        Poller::new(self, IoEvents::IN)
+            .non_blocking(non_blocking || self.nonblocking())
+            .poll(|| {
+                let received = match &mut *self.state.lock() {
+                    OpState::Hash(hash) => hash.recv(dst)?,
+                    // Wait for the rest of the request.
+                    OpState::Aead(aead) if !aead.ready => return Err(AxError::WouldBlock),
+                    OpState::Aead(aead) => aead.recv(dst)?,
+                };
+                self.poll_tx.wake();
+                Ok(received)
+            })
+    }
+}
+
+impl FileLike for AlgOpSocket {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        self.recv(dst, false)
+    }
+
+    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+        self.send(src, false, AlgControl::default())
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat {
+            mode: S_IFSOCK | 0o777u32,
+            ..Default::default()
+        })
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        format!("socket:[{}]", self as *const _ as usize).into()
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Pollable for AlgOpSocket {
+    fn poll(&self) -> IoEvents {
+        let (readable, writable) = match &*self.state.lock() {
+            OpState::Hash(_) => (true, true),
+            OpState::Aead(aead) => (aead.ready, !aead.ready),
+        };
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, readable);
+        events.set(IoEvents::OUT, writable);
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+        if events.contains(IoEvents::OUT) {
+            self.poll_tx.register(context.waker());
+        }
+    }
+}
@@ -32,6 +32,18 @@ impl EventFd {
             poll_tx: PollSet::new(),
         })
     }
+
+    /// Adds `value` to the counter without blocking, for the kernel to
+    /// signal events, e.g. from interrupt handlers. The counter saturates
+    /// rather than overflows.
+    pub fn signal(&self, value: u64) {
+        let _ = self
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                Some(count.saturating_add(value).min(u64::MAX - 1))
+            });
+        self.poll_rx.wake();
+    }
 }
 
 impl FileLike for EventFd {
@@ -45,7 +57,7 @@ impl FileLike for EventFd {
             .poll(|| {
                 let result =
                     self.count
-                        .fetch_update(Ordering::Release, Ordering::Acquire, |count| {
+                        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
                             if count > 0 {
                                 let dec = if self.semaphore { 1 } else { count };
                                 Some(count - dec)
@@ -55,7 +67,9 @@ impl FileLike for EventFd {
                         });
                 match result {
                     Ok(count) => {
-                        dst.write(&count.to_ne_bytes())?;
+                        // In semaphore mode each read takes a single unit.
+                        let value = if self.semaphore { 1 } else { count };
+                        dst.write(&value.to_ne_bytes())?;
                         self.poll_tx.wake();
                         Ok(size_of::<u64>())
                     }
@@ -76,12 +90,19 @@ impl FileLike for EventFd {
             return Err(AxError::InvalidInput);
         }
 
+        // Adding zero never blocks and wakes nobody.
+        if value == 0 {
+            return Ok(size_of::<u64>());
+        }
+
+        // The counter may hold at most `u64::MAX - 1`; a write that would
+        // go beyond that blocks until a read makes room for all of it.
         Poller::new(self, IoEvents::OUT)
             .non_blocking(self.nonblocking())
             .poll(|| {
                 let result =
                     self.count
-                        .fetch_update(Ordering::Release, Ordering::Acquire, |count| {
+                        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
                             if u64::MAX - count > value {
                                 Some(count + value)
                             } else {
@@ -0,0 +1,594 @@
+//! fanotify(7) notification groups.
+
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    string::ToString,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    ffi::c_int,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng::{FS_CONTEXT, OpenOptions, OpenResult};
+use axfs_ng_vfs::{Location, Mountpoint, NodeType};
+use axio::{Buf, BufMut, Read, Write};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::{current, future::Poller};
+use bitflags::bitflags;
+use linux_raw_sys::general::{O_RDONLY, O_RDWR, O_WRONLY};
+use spin::Mutex;
+use starry_core::task::AsThread;
+
+use crate::file::{Directory, File, FileLike, Kstat, SealedBuf, SealedBufMut, add_file_like};
+
+bitflags! {
+    /// Events reported by fanotify.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FanEvents: u64 {
+        /// A file was read.
+        const ACCESS = 0x1;
+        /// A file was written.
+        const MODIFY = 0x2;
+        /// A file opened for writing was closed.
+        const CLOSE_WRITE = 0x8;
+        /// A file not opened for writing was closed.
+        const CLOSE_NOWRITE = 0x10;
+        /// A file was opened.
+        const OPEN = 0x20;
+        /// The event queue overflowed.
+        const Q_OVERFLOW = 0x4000;
+        /// A file is about to be opened; the listener decides whether it may.
+        const OPEN_PERM = 0x10000;
+        /// A file is about to be read; the listener decides whether it may.
+        const ACCESS_PERM = 0x20000;
+        /// Report events on the children of a marked directory too.
+        const EVENT_ON_CHILD = 0x0800_0000;
+        /// Report events on directories too.
+        const ONDIR = 0x4000_0000;
+
+        /// Events that block until the listener responds.
+        const PERM = Self::OPEN_PERM.bits() | Self::ACCESS_PERM.bits();
+        /// Events that a mark may ask for.
+        const ALL = Self::ACCESS.bits()
+            | Self::MODIFY.bits()
+            | Self::CLOSE_WRITE.bits()
+            | Self::CLOSE_NOWRITE.bits()
+            | Self::OPEN.bits()
+            | Self::PERM.bits();
+    }
+}
+
+bitflags! {
+    /// Flags for `fanotify_init`.
+    #[derive(Debug, Clone, Copy)]
+    pub struct FanInitFlags: u32 {
+        /// Close the group on `exec`.
+        const CLOEXEC = 0x1;
+        /// Reading doesn't block.
+        const NONBLOCK = 0x2;
+        /// The group may ask for permission events.
+        const CLASS_CONTENT = 0x4;
+        /// Like `CLASS_CONTENT`, for listeners that also provide content.
+        const CLASS_PRE_CONTENT = 0x8;
+        /// Don't limit the length of the event queue.
+        const UNLIMITED_QUEUE = 0x10;
+        /// Don't limit the number of marks.
+        const UNLIMITED_MARKS = 0x20;
+    }
+}
+
+bitflags! {
+    /// Flags for `fanotify_mark`.
+    #[derive(Debug, Clone, Copy)]
+    pub struct FanMarkFlags: u32 {
+        /// Add events to a mark.
+        const ADD = 0x1;
+        /// Remove events from a mark.
+        const REMOVE = 0x2;
+        /// Don't follow a trailing symlink.
+        const DONT_FOLLOW = 0x4;
+        /// Fail unless the path is a directory.
+        const ONLYDIR = 0x8;
+        /// Mark the whole mount the path lives on.
+        const MOUNT = 0x10;
+        /// Change the events to ignore rather than the ones to report.
+        const IGNORED_MASK = 0x20;
+        /// Keep the ignored events across modifications of the file.
+        const IGNORED_SURV_MODIFY = 0x40;
+        /// Remove every mark of the given kind.
+        const FLUSH = 0x80;
+        /// Mark the whole filesystem the path lives on.
+        const FILESYSTEM = 0x100;
+    }
+}
+
+const FANOTIFY_METADATA_VERSION: u8 = 3;
+/// The size of `struct fanotify_event_metadata`.
+const METADATA_LEN: usize = 24;
+/// The size of `struct fanotify_response`.
+const RESPONSE_LEN: usize = 8;
+const FAN_NOFD: c_int = -1;
+const FAN_ALLOW: u32 = 0x1;
+const FAN_DENY: u32 = 0x2;
+/// The longest event queue a group without `FAN_UNLIMITED_QUEUE` may have.
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+/// What a mark watches.
+///
+/// Marks don't keep what they watch alive, so that they don't keep a
+/// filesystem from being unmounted.
+enum MarkTarget {
+    Inode { dev: u64, ino: u64 },
+    Mount(Weak<Mountpoint>),
+    Filesystem(u64),
+}
+
+impl MarkTarget {
+    fn new(loc: &Location, flags: FanMarkFlags) -> AxResult<Self> {
+        let dev = loc.mountpoint().device() as u64;
+        Ok(if flags.contains(FanMarkFlags::MOUNT) {
+            Self::Mount(Arc::downgrade(loc.mountpoint()))
+        } else if flags.contains(FanMarkFlags::FILESYSTEM) {
+            Self::Filesystem(dev)
+        } else {
+            Self::Inode {
+                dev,
+                ino: loc.metadata()?.inode,
+            }
+        })
+    }
+
+    fn is_kind(&self, flags: FanMarkFlags) -> bool {
+        match self {
+            Self::Inode { .. } => !flags.intersects(FanMarkFlags::MOUNT | FanMarkFlags::FILESYSTEM),
+            Self::Mount(_) => flags.contains(FanMarkFlags::MOUNT),
+            Self::Filesystem(_) => flags.contains(FanMarkFlags::FILESYSTEM),
+        }
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Inode { dev, ino }, Self::Inode { dev: d, ino: i }) => dev == d && ino == i,
+            (Self::Mount(a), Self::Mount(b)) => Weak::ptr_eq(a, b),
+            (Self::Filesystem(a), Self::Filesystem(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn matches(&self, object: &Object) -> bool {
+        match self {
+            Self::Inode { dev, ino } => *dev == object.dev && *ino == object.ino,
+            Self::Mount(mount) => Weak::as_ptr(mount) == Arc::as_ptr(object.loc.mountpoint()),
+            Self::Filesystem(dev) => *dev == object.dev,
+        }
+    }
+
+    fn is_parent_of(&self, object: &Object) -> bool {
+        match self {
+            Self::Inode { dev, ino } => *dev == object.dev && Some(*ino) == object.parent_ino,
+            _ => false,
+        }
+    }
+}
+
+struct Mark {
+    target: MarkTarget,
+    mask: FanEvents,
+    ignored: FanEvents,
+}
+
+/// The file an event happened on.
+struct Object<'a> {
+    loc: &'a Location,
+    dev: u64,
+    ino: u64,
+    parent_ino: Option<u64>,
+    is_dir: bool,
+}
+
+/// The listener's verdict on a permission event.
+struct Response {
+    allow: Mutex<Option<bool>>,
+    poll: PollSet,
+}
+
+impl Response {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            allow: Mutex::new(None),
+            poll: PollSet::new(),
+        })
+    }
+
+    fn answer(&self, allow: bool) {
+        let mut answer = self.allow.lock();
+        if answer.is_none() {
+            *answer = Some(allow);
+        }
+        drop(answer);
+        self.poll.wake();
+    }
+
+    /// Blocks until the listener answers, returning whether it allowed the
+    /// access.
+    fn wait(&self) -> AxResult<bool> {
+        Poller::new(self, IoEvents::IN).poll(|| self.allow.lock().ok_or(AxError::WouldBlock))
+    }
+}
+
+impl Pollable for Response {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, self.allow.lock().is_some());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll.register(context.waker());
+        }
+    }
+}
+
+struct Event {
+    mask: FanEvents,
+    /// `None` for `FAN_Q_OVERFLOW`.
+    loc: Option<Location>,
+    pid: u32,
+    response: Option<Arc<Response>>,
+}
+
+/// A fanotify group, as returned by `fanotify_init`.
+pub struct Fanotify {
+    flags: FanInitFlags,
+    /// The `O_*` flags of the file descriptors handed out with events.
+    event_flags: u32,
+    marks: Mutex<Vec<Mark>>,
+    queue: Mutex<VecDeque<Event>>,
+    /// Permission events that have been read but not answered yet, keyed by
+    /// the file descriptor reported with them.
+    pending: Mutex<BTreeMap<c_int, Arc<Response>>>,
+    non_blocking: AtomicBool,
+    poll_rx: PollSet,
+}
+
+/// Every live group, so that filesystem operations can find the ones
+/// interested in them.
+static GROUPS: Mutex<Vec<Weak<Fanotify>>> = Mutex::new(Vec::new());
+
+impl Fanotify {
+    pub fn new(flags: FanInitFlags, event_flags: u32) -> Arc<Self> {
+        let group = Arc::new(Self {
+            flags,
+            event_flags,
+            marks: Mutex::new(Vec::new()),
+            queue: Mutex::new(VecDeque::new()),
+            pending: Mutex::new(BTreeMap::new()),
+            non_blocking: AtomicBool::new(flags.contains(FanInitFlags::NONBLOCK)),
+            poll_rx: PollSet::new(),
+        });
+        GROUPS.lock().push(Arc::downgrade(&group));
+        group
+    }
+
+    /// Adds or removes events of the mark on `loc`, or flushes marks.
+    pub fn update_mark(
+        &self,
+        loc: Option<&Location>,
+        flags: FanMarkFlags,
+        mask: FanEvents,
+    ) -> AxResult<()> {
+        if mask.intersects(FanEvents::PERM)
+            && !self
+                .flags
+                .intersects(FanInitFlags::CLASS_CONTENT | FanInitFlags::CLASS_PRE_CONTENT)
+        {
+            return Err(AxError::InvalidInput);
+        }
+
+        let mut marks = self.marks.lock();
+        if flags.contains(FanMarkFlags::FLUSH) {
+            marks.retain(|m| !m.target.is_kind(flags));
+            return Ok(());
+        }
+        let target = MarkTarget::new(loc.ok_or(AxError::InvalidInput)?, flags)?;
+        let index = marks.iter().position(|m| m.target.same(&target));
+
+        if flags.contains(FanMarkFlags::ADD) {
+            let mark = match index {
+                Some(index) => &mut marks[index],
+                None => {
+                    marks.push(Mark {
+                        target,
+                        mask: FanEvents::empty(),
+                        ignored: FanEvents::empty(),
+                    });
+                    marks.last_mut().unwrap()
+                }
+            };
+            if flags.contains(FanMarkFlags::IGNORED_MASK) {
+                mark.ignored |= mask;
+            } else {
+                mark.mask |= mask;
+            }
+        } else {
+            let index = index.ok_or(AxError::NotFound)?;
+            let mark = &mut marks[index];
+            if flags.contains(FanMarkFlags::IGNORED_MASK) {
+                mark.ignored -= mask;
+            } else {
+                mark.mask -= mask;
+            }
+            if mark.mask.is_empty() && mark.ignored.is_empty() {
+                marks.remove(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the event if a mark asks for it, returning the response to
+    /// wait for if it is a permission event.
+    fn handle(&self, object: &Object, mask: FanEvents, pid: u32) -> Option<Arc<Response>> {
+        let mut wanted = FanEvents::empty();
+        let mut ignored = FanEvents::empty();
+        for mark in self.marks.lock().iter() {
+            if mark.target.matches(object)
+                || (mark.mask.contains(FanEvents::EVENT_ON_CHILD)
+                    && mark.target.is_parent_of(object))
+            {
+                wanted |= mark.mask;
+                ignored |= mark.ignored;
+            }
+        }
+        if object.is_dir && !wanted.contains(FanEvents::ONDIR) {
+            return None;
+        }
+        let mut mask = mask & wanted & !ignored & FanEvents::ALL;
+        if mask.is_empty() {
+            return None;
+        }
+        mask.set(FanEvents::ONDIR, object.is_dir);
+
+        let mut queue = self.queue.lock();
+        if !self.flags.contains(FanInitFlags::UNLIMITED_QUEUE) && queue.len() >= MAX_QUEUED_EVENTS {
+            // Report the overflow once; permission events that don't fit
+            // are allowed.
+            if queue.back().is_none_or(|e| e.mask != FanEvents::Q_OVERFLOW) {
+                queue.push_back(Event {
+                    mask: FanEvents::Q_OVERFLOW,
+                    loc: None,
+                    pid: 0,
+                    response: None,
+                });
+            }
+            return None;
+        }
+        let response = mask.intersects(FanEvents::PERM).then(Response::new);
+        queue.push_back(Event {
+            mask,
+            loc: Some(object.loc.clone()),
+            pid,
+            response: response.clone(),
+        });
+        drop(queue);
+        self.poll_rx.wake();
+        response
+    }
+
+    /// Opens the file of an event for the reader.
+    fn open_event_file(&self, loc: &Location) -> AxResult<c_int> {
+        let mut options = OpenOptions::new();
+        match self.event_flags & 0b11 {
+            O_RDONLY => options.read(true),
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => return Err(AxError::InvalidInput),
+        };
+        let path = loc.absolute_path()?.to_string();
+        let file: Arc<dyn FileLike> = match options.open(&FS_CONTEXT.lock(), path)? {
+            // Accesses through the event file must not be reported, or a
+            // listener reading it would wait for itself.
+            OpenResult::File(file) => Arc::new(File::new(file).without_notify()),
+            OpenResult::Dir(dir) => Arc::new(Directory::new(dir)),
+        };
+        add_file_like(file, self.flags.contains(FanInitFlags::CLOEXEC))
+    }
+}
+
+impl Drop for Fanotify {
+    fn drop(&mut self) {
+        // Nobody is left to answer, so let everyone waiting go ahead.
+        for response in self.pending.get_mut().values() {
+            response.answer(true);
+        }
+        for event in self.queue.get_mut().iter() {
+            if let Some(response) = &event.response {
+                response.answer(true);
+            }
+        }
+    }
+}
+
+impl FileLike for Fanotify {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        if dst.remaining_mut() < METADATA_LEN {
+            return Err(AxError::InvalidInput);
+        }
+
+        Poller::new(self, IoEvents::IN)
+            .non_blocking(self.nonblocking())
+            .poll(|| {
+                let mut queue = self.queue.lock();
+                if queue.is_empty() {
+                    return Err(AxError::WouldBlock);
+                }
+                let mut read = 0;
+                while dst.remaining_mut() >= METADATA_LEN
+                    && let Some(event) = queue.pop_front()
+                {
+                    let fd = match &event.loc {
+                        Some(loc) => self.open_event_file(loc).unwrap_or(FAN_NOFD),
+                        None => FAN_NOFD,
+                    };
+                    if let Some(response) = event.response {
+                        if fd == FAN_NOFD {
+                            response.answer(true);
+                        } else if let Some(stale) = self.pending.lock().insert(fd, response) {
+                            // The listener closed the file descriptor of an
+                            // event it never answered, and can't any more.
+                            stale.answer(true);
+                        }
+                    }
+
+                    let mut buf = [0; METADATA_LEN];
+                    buf[..4].copy_from_slice(&(METADATA_LEN as u32).to_ne_bytes());
+                    buf[4] = FANOTIFY_METADATA_VERSION;
+                    buf[6..8].copy_from_slice(&(METADATA_LEN as u16).to_ne_bytes());
+                    buf[8..16].copy_from_slice(&event.mask.bits().to_ne_bytes());
+                    buf[16..20].copy_from_slice(&fd.to_ne_bytes());
+                    buf[20..].copy_from_slice(&event.pid.to_ne_bytes());
+                    dst.write(&buf)?;
+                    read += METADATA_LEN;
+                }
+                Ok(read)
+            })
+    }
+
+    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+        if src.remaining() < RESPONSE_LEN {
+            return Err(AxError::InvalidInput);
+        }
+
+        let mut written = 0;
+        while src.remaining() >= RESPONSE_LEN {
+            let mut buf = [0; RESPONSE_LEN];
+            src.read(&mut buf)?;
+            let fd = c_int::from_ne_bytes(buf[..4].try_into().unwrap());
+            let allow = match u32::from_ne_bytes(buf[4..].try_into().unwrap()) & 0xff {
+                FAN_ALLOW => true,
+                FAN_DENY => false,
+                _ => return Err(AxError::InvalidInput),
+            };
+            let response = self.pending.lock().remove(&fd).ok_or(AxError::NotFound)?;
+            response.answer(allow);
+            written += RESPONSE_LEN;
+        }
+        Ok(written)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<str> {
+        "anon_inode:[fanotify]".into()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl Pollable for Fanotify {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, !self.queue.lock().is_empty());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
+
+/// Reports an event on `loc` to every group with a matching mark.
+///
+/// For permission events this blocks until every listener has answered, and
+/// fails with `EPERM` if any of them denied the access.
+pub fn notify(loc: &Location, mask: FanEvents) -> AxResult<()> {
+    let groups: Vec<_> = {
+        let mut groups = GROUPS.lock();
+        groups.retain(|g| g.strong_count() > 0);
+        if groups.is_empty() {
+            return Ok(());
+        }
+        groups.iter().filter_map(Weak::upgrade).collect()
+    };
+
+    let metadata = loc.metadata()?;
+    let object = Object {
+        loc,
+        dev: loc.mountpoint().device() as u64,
+        ino: metadata.inode,
+        parent_ino: loc
+            .entry()
+            .parent()
+            .and_then(|parent| parent.metadata().ok())
+            .map(|metadata| metadata.inode),
+        is_dir: metadata.node_type == NodeType::Directory,
+    };
+    // Files closed by the kernel itself, outside of any thread, are not
+    // reported.
+    let Some(pid) = current()
+        .try_as_thread()
+        .map(|thr| thr.proc_data.proc.pid())
+    else {
+        return Ok(());
+    };
+    let responses: Vec<_> = groups
+        .iter()
+        .filter_map(|group| group.handle(&object, mask, pid))
+        .collect();
+    // Drop our references first, so that closing a group while we wait
+    // releases its pending events.
+    drop(groups);
+
+    for response in responses {
+        if !response.wait()? {
+            return Err(AxError::OperationNotPermitted);
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether any group is listening, for callers to skip preparing
+/// events nobody will see.
+pub fn has_listeners() -> bool {
+    GROUPS.lock().iter().any(|g| g.strong_count() > 0)
+}
+
+/// Asks for permission to open `loc`, before the open has any effect on it
+/// such as truncating it.
+pub fn notify_open_perm(loc: &Location) -> AxResult<()> {
+    notify(loc, FanEvents::OPEN_PERM)
+}
+
+/// Reports the opening of a file, after asking for permission to unless
+/// that was done with [`notify_open_perm`] already.
+pub fn notify_open(result: &OpenResult, asked: bool) -> AxResult<()> {
+    let loc = match result {
+        OpenResult::File(file) => file.location(),
+        OpenResult::Dir(dir) => dir,
+    };
+    if !asked {
+        notify(loc, FanEvents::OPEN_PERM)?;
+    }
+    notify(loc, FanEvents::OPEN)
+}
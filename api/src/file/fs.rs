@@ -1,21 +1,37 @@
-use alloc::{borrow::Cow, string::ToString, sync::Arc};
+use alloc::{
+    borrow::Cow,
+    string::{String, ToString},
+    sync::Arc,
+};
 use core::{
     any::Any,
     ffi::c_int,
     hint::likely,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
     task::Context,
 };
 
 use axerrno::{AxError, AxResult};
-use axfs_ng::{FS_CONTEXT, FsContext};
+use axfs_ng::{CachedFile, FS_CONTEXT, FileBackend, FileFlags, FsContext};
 use axfs_ng_vfs::{Location, Metadata, NodeFlags};
+use axio::{Buf, Seek, SeekFrom};
 use axpoll::{IoEvents, Pollable};
 use axsync::Mutex;
 use axtask::future::Poller;
-use linux_raw_sys::general::{AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_NOFOLLOW};
-
-use super::{FileLike, Kstat, get_file_like};
+use bitflags::bitflags;
+use linux_raw_sys::general::{
+    AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_NOFOLLOW, F_SEAL_FUTURE_WRITE, F_SEAL_GROW, F_SEAL_SEAL,
+    F_SEAL_SHRINK, F_SEAL_WRITE,
+};
+use starry_core::vfs::DeviceOps;
+
+use super::{
+    FileLike, Kstat,
+    fanotify::{self, FanEvents},
+    fscrypt, get_file_like, lease,
+    readahead::ReadAhead,
+    seals::{self, MemfdSeals},
+};
 use crate::file::{SealedBuf, SealedBufMut};
 
 pub fn with_fs<R>(dirfd: c_int, f: impl FnOnce(&mut FsContext) -> AxResult<R>) -> AxResult<R> {
@@ -28,6 +44,19 @@ pub fn with_fs<R>(dirfd: c_int, f: impl FnOnce(&mut FsContext) -> AxResult<R>) -
     }
 }
 
+/// Returns the path of `loc` as seen from `root`, or `None` if `loc` is not
+/// below `root`.
+pub fn path_from_root(root: &Location, loc: &Location) -> AxResult<Option<String>> {
+    let root = root.absolute_path()?;
+    let root = root.as_str().trim_end_matches('/');
+    let path = loc.absolute_path()?;
+    Ok(match path.as_str().strip_prefix(root) {
+        Some("") => Some("/".into()),
+        Some(rest) if rest.starts_with('/') => Some(rest.into()),
+        _ => None,
+    })
+}
+
 pub enum ResolveAtResult {
     File(Location),
     Other(Arc<dyn FileLike>),
@@ -43,7 +72,13 @@ impl ResolveAtResult {
 
     pub fn stat(&self) -> AxResult<Kstat> {
         match self {
-            Self::File(file) => file.metadata().map(|it| metadata_to_kstat(&it)),
+            Self::File(file) => {
+                let mut metadata = file.metadata()?;
+                if let Some(size) = fscrypt::plaintext_size(file)? {
+                    metadata.size = size;
+                }
+                Ok(metadata_to_kstat(&metadata))
+            }
             Self::Other(file_like) => file_like.stat(),
         }
     }
@@ -97,10 +132,46 @@ pub fn metadata_to_kstat(metadata: &Metadata) -> Kstat {
     }
 }
 
+bitflags! {
+    /// File seals, see `F_ADD_SEALS` in fcntl(2).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FileSeals: u32 {
+        /// Further seals can't be added.
+        const SEAL = F_SEAL_SEAL;
+        /// The file can't shrink.
+        const SHRINK = F_SEAL_SHRINK;
+        /// The file can't grow.
+        const GROW = F_SEAL_GROW;
+        /// The contents can't be modified.
+        const WRITE = F_SEAL_WRITE;
+        /// Like `WRITE`, but existing shared writable mappings stay usable.
+        const FUTURE_WRITE = F_SEAL_FUTURE_WRITE;
+    }
+}
+
+/// The alignment `O_DIRECT` transfers need in memory and in the file: the
+/// logical block size of the block layer.
+pub const DIRECT_IO_ALIGN: usize = 512;
+
 /// File wrapper for `axfs::fops::File`.
 pub struct File {
     inner: axfs_ng::File,
     nonblock: AtomicBool,
+    /// For the file a memfd was created as, its seals, which last as long as
+    /// the file does.
+    seals: Option<Arc<MemfdSeals>>,
+    /// Whether accesses are reported to fanotify.
+    notify: bool,
+    /// The device opened through this file, to be released on close.
+    device: Option<Arc<dyn DeviceOps>>,
+    /// The signal set by `F_SETSIG`, 0 for `SIGIO`.
+    signal: AtomicU32,
+    /// Whether the file was opened with `O_DIRECT`.
+    direct: bool,
+    /// For `O_DIRECT` files whose reads and writes bypass the page cache,
+    /// the file through the cache, which mappings still go through.
+    cached: Option<axfs_ng::File>,
+    read_ahead: ReadAhead,
 }
 
 impl File {
@@ -108,13 +179,218 @@ impl File {
         Self {
             inner,
             nonblock: AtomicBool::new(false),
+            seals: None,
+            notify: true,
+            device: None,
+            signal: AtomicU32::new(0),
+            direct: false,
+            cached: None,
+            read_ahead: ReadAhead::default(),
         }
     }
 
+    /// Makes reads and writes of the file go straight to the filesystem
+    /// instead of through the page cache (`O_DIRECT`).
+    ///
+    /// Files that only live in the page cache, as on tmpfs, keep using it and
+    /// take transfers of any alignment, as on Linux.
+    pub fn into_direct(mut self) -> AxResult<Self> {
+        self.direct = true;
+        let loc = self.inner.location();
+        if matches!(self.inner.backend()?, FileBackend::Cached(_))
+            && !loc.flags().contains(NodeFlags::ALWAYS_CACHE)
+        {
+            let direct = axfs_ng::File::new(FileBackend::Direct(loc.clone()), self.inner.flags());
+            self.cached = Some(core::mem::replace(&mut self.inner, direct));
+        }
+        Ok(self)
+    }
+
+    /// Returns whether the file was opened with `O_DIRECT`.
+    pub fn direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Prepares an `O_DIRECT` transfer of `len` bytes between `addr` and
+    /// `offset` in the file.
+    ///
+    /// The transfer must be aligned to [`DIRECT_IO_ALIGN`], and what was
+    /// written through the page cache is written back first so that it is
+    /// neither read around nor overwritten later by stale pages.
+    pub fn begin_direct_io(&self, addr: usize, len: usize, offset: u64) -> AxResult<()> {
+        let Some(cached) = &self.cached else {
+            return Ok(());
+        };
+        if (addr | len | offset as usize) % DIRECT_IO_ALIGN != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        cached.sync(true)
+    }
+
+    /// Finishes an `O_DIRECT` write of `len` bytes at `offset` in the file.
+    ///
+    /// The written range is read back from the filesystem and written through
+    /// the page cache, so that buffered readers and mappings see the new data
+    /// and a write past the end grows the cached length too.
+    pub fn end_direct_write(&self, offset: u64, len: usize) -> AxResult<()> {
+        let Some(cached) = &self.cached else {
+            return Ok(());
+        };
+        let mut chunk = alloc::vec![0u8; len.min(64 * 1024)];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let n = (len - done).min(chunk.len());
+            let n = self.inner.read_at(&mut &mut chunk[..n], pos)?;
+            if n == 0 {
+                break;
+            }
+            cached.write_at(&mut &chunk[..n], pos)?;
+            done += n;
+        }
+        // The pages now match the filesystem; don't write them back again.
+        cached.sync(true)
+    }
+
+    /// Returns the backend mappings of the file go through, which is the page
+    /// cache even for `O_DIRECT` files.
+    pub fn mmap_backend(&self) -> AxResult<FileBackend> {
+        Ok(self
+            .cached
+            .as_ref()
+            .unwrap_or(&self.inner)
+            .backend()?
+            .clone())
+    }
+
+    /// Opens the device the file refers to, which is released when the file
+    /// is closed.
+    pub fn open_device(mut self, device: Arc<dyn DeviceOps>) -> AxResult<Self> {
+        device.open()?;
+        self.device = Some(device);
+        Ok(self)
+    }
+
+    /// Stops reporting accesses through this file to fanotify.
+    pub fn without_notify(mut self) -> Self {
+        self.notify = false;
+        self
+    }
+
+    /// Creates a file backing a memfd. Unless `allow_sealing` is set the file
+    /// starts with `F_SEAL_SEAL`, like Linux.
+    pub fn new_memfd(inner: axfs_ng::File, allow_sealing: bool) -> AxResult<Self> {
+        let seals = if allow_sealing {
+            FileSeals::empty()
+        } else {
+            FileSeals::SEAL
+        };
+        let mut file = Self::new(inner);
+        file.seals = Some(seals::register(file.inner.location(), seals)?);
+        Ok(file)
+    }
+
     pub fn inner(&self) -> &axfs_ng::File {
         &self.inner
     }
 
+    /// Returns the signal sent for the file (`F_GETSIG`), 0 meaning `SIGIO`.
+    pub fn signal(&self) -> u32 {
+        self.signal.load(Ordering::Relaxed)
+    }
+
+    /// Sets the signal sent for the file (`F_SETSIG`).
+    pub fn set_signal(&self, signal: u32) {
+        self.signal.store(signal, Ordering::Relaxed);
+    }
+
+    /// Returns the seals of the memfd the file is open on, `None` if it is
+    /// not a memfd.
+    pub fn memfd_seals(&self) -> Option<Arc<MemfdSeals>> {
+        self.seals
+            .clone()
+            .or_else(|| seals::lookup(self.inner.location()))
+    }
+
+    /// Returns the seals of the file (`F_GET_SEALS`).
+    pub fn seals(&self) -> AxResult<FileSeals> {
+        Ok(self.memfd_seals().ok_or(AxError::InvalidInput)?.get())
+    }
+
+    /// Adds seals to the file (`F_ADD_SEALS`).
+    pub fn add_seals(&self, new: FileSeals) -> AxResult<()> {
+        self.memfd_seals().ok_or(AxError::InvalidInput)?.add(new)
+    }
+
+    /// Checks that writing up to offset `end` is allowed by the seals.
+    pub fn check_write(&self, end: u64) -> AxResult<()> {
+        seals::check_write(self.inner.location(), end)
+    }
+
+    /// Checks that resizing the file to `len` is allowed by the seals.
+    pub fn check_set_len(&self, len: u64) -> AxResult<()> {
+        seals::check_set_len(self.inner.location(), len)
+    }
+
+    /// Runs `f` on the page cache of the file, for readers that take the
+    /// cached pages directly instead of going through [`FileLike::read`].
+    ///
+    /// Returns `None` if the file is not backed by the page cache.
+    pub fn with_page_cache<R>(
+        &self,
+        f: impl FnOnce(&CachedFile) -> AxResult<R>,
+    ) -> Option<AxResult<R>> {
+        let cache = match self.inner.access(FileFlags::READ) {
+            Ok(FileBackend::Cached(cache)) => cache,
+            Ok(FileBackend::Direct(_)) => return None,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(self.with_access_notify(|| f(cache)))
+    }
+
+    /// Returns the page cache reads of the file are read ahead into, with
+    /// the size of the file, or `None` if they aren't.
+    ///
+    /// Files that only live in the page cache have nothing to read ahead.
+    fn read_ahead_cache(&self) -> Option<(&CachedFile, u64)> {
+        let loc = self.inner.location();
+        if loc.flags().contains(NodeFlags::ALWAYS_CACHE) {
+            return None;
+        }
+        match self.inner.backend() {
+            Ok(FileBackend::Cached(cache)) => Some((cache, loc.len().ok()?)),
+            _ => None,
+        }
+    }
+
+    /// Notes a read of `len` bytes at `offset` that didn't go through the
+    /// file position, reading ahead after sequential reads.
+    pub fn note_read(&self, offset: u64, len: usize) {
+        if let Some((cache, size)) = self.read_ahead_cache() {
+            self.read_ahead.on_read(cache, offset, len, size);
+        }
+    }
+
+    /// Takes the advice of `posix_fadvise` for `len` bytes at `offset`.
+    pub fn advise(&self, advice: u32, offset: u64, len: u64) {
+        if let Some((cache, size)) = self.read_ahead_cache() {
+            self.read_ahead.advise(cache, advice, offset, len, size);
+        }
+    }
+
+    /// Runs a read of the file, reporting it to fanotify.
+    fn with_access_notify<R>(&self, f: impl FnOnce() -> AxResult<R>) -> AxResult<R> {
+        let location = self.inner.location();
+        if self.notify {
+            fanotify::notify(location, FanEvents::ACCESS_PERM)?;
+        }
+        let result = f()?;
+        if self.notify {
+            let _ = fanotify::notify(location, FanEvents::ACCESS);
+        }
+        Ok(result)
+    }
+
     fn is_blocking(&self) -> bool {
         self.inner.location().flags().contains(NodeFlags::BLOCKING)
     }
@@ -128,24 +404,66 @@ fn path_for(loc: &Location) -> Cow<'static, str> {
 impl FileLike for File {
     fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
         let inner = self.inner();
-        if likely(self.is_blocking()) {
-            inner.read(dst)
-        } else {
-            Poller::new(self, IoEvents::IN)
-                .non_blocking(self.nonblocking())
-                .poll(|| inner.read(dst))
+        if self.cached.is_some() {
+            let (addr, len) = match dst {
+                SealedBufMut::Bytes(bytes) => (bytes.ptr as usize, bytes.len),
+                _ => (0, 0),
+            };
+            self.begin_direct_io(addr, len, inner.seek(SeekFrom::Current(0))?)?;
+        }
+        let read = self.with_access_notify(|| {
+            if likely(self.is_blocking()) {
+                inner.read(dst)
+            } else {
+                Poller::new(self, IoEvents::IN)
+                    .non_blocking(self.nonblocking())
+                    .poll(|| inner.read(dst))
+            }
+        })?;
+        if read > 0
+            && let Some((cache, size)) = self.read_ahead_cache()
+            && let Ok(end) = inner.seek(SeekFrom::Current(0))
+        {
+            self.read_ahead
+                .on_read(cache, end - read as u64, read, size);
         }
+        Ok(read)
     }
 
     fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
         let inner = self.inner();
-        if likely(self.is_blocking()) {
+        if self.memfd_seals().is_some() {
+            // Appending writes start at the end of the file, wherever the
+            // position is.
+            let pos = if inner.access(FileFlags::APPEND).is_ok() {
+                inner.location().len()?
+            } else {
+                inner.seek(SeekFrom::Current(0))?
+            };
+            self.check_write(pos + src.remaining() as u64)?;
+        }
+        if self.cached.is_some() {
+            let (addr, len) = match src {
+                SealedBuf::Bytes(bytes) => (bytes.ptr as usize, bytes.len),
+                _ => (0, 0),
+            };
+            self.begin_direct_io(addr, len, inner.seek(SeekFrom::Current(0))?)?;
+        }
+        let written = if likely(self.is_blocking()) {
             inner.write(src)
         } else {
             Poller::new(self, IoEvents::OUT)
                 .non_blocking(self.nonblocking())
                 .poll(|| inner.write(src))
+        }?;
+        if self.cached.is_some() {
+            let end = inner.seek(SeekFrom::Current(0))?;
+            self.end_direct_write(end - written as u64, written)?;
         }
+        if self.notify {
+            let _ = fanotify::notify(inner.location(), FanEvents::MODIFY);
+        }
+        Ok(written)
     }
 
     fn stat(&self) -> AxResult<Kstat> {
@@ -157,7 +475,8 @@ impl FileLike for File {
     }
 
     fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
-        self.inner().backend()?.location().ioctl(cmd, arg)
+        let location = self.inner().backend()?.location();
+        fscrypt::ioctl(location, cmd, arg).unwrap_or_else(|| location.ioctl(cmd, arg))
     }
 
     fn set_nonblocking(&self, flag: bool) -> AxResult {
@@ -188,6 +507,23 @@ impl FileLike for File {
         })
     }
 }
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Some(device) = &self.device {
+            device.release();
+        }
+        if self.notify {
+            let event = if self.inner.flags().contains(FileFlags::WRITE) {
+                FanEvents::CLOSE_WRITE
+            } else {
+                FanEvents::CLOSE_NOWRITE
+            };
+            let _ = fanotify::notify(self.inner.location(), event);
+        }
+        lease::release_closed();
+    }
+}
+
 impl Pollable for File {
     fn poll(&self) -> IoEvents {
         self.inner().location().poll()
@@ -235,6 +571,10 @@ impl FileLike for Directory {
         path_for(&self.inner)
     }
 
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        fscrypt::ioctl(&self.inner, cmd, arg).unwrap_or(Err(AxError::BadIoctl))
+    }
+
     fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
         self
     }
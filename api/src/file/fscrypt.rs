@@ -0,0 +1,530 @@
+//! Per-directory encryption in the style of fscrypt.
+//!
+//! `FS_IOC_SET_ENCRYPTION_POLICY` makes an empty directory encrypted with a
+//! v1 policy, which names a master key by its descriptor. The policy is kept
+//! in a `.fscrypt_policy` file in the directory, which can't be opened for
+//! writing, renamed or removed, and directories created in an encrypted one
+//! inherit it.
+//!
+//! Regular files opened in an encrypted directory are read and written
+//! through a node that encrypts their contents, once the master key has been
+//! added to the keyring as a `logon` key named `fscrypt:<descriptor>`. Each
+//! file starts with a header holding a random nonce and the size of its
+//! contents. As in fscrypt v1, the key of a file is the master key encrypted
+//! with AES-128-ECB under its nonce, and the contents are encrypted with
+//! AES-256-XTS, each 16-byte block being a data unit tweaked by its index.
+//!
+//! Names can't be encrypted: they are resolved by the filesystem layer,
+//! which has no hook to translate them. Policies are rejected with `EINVAL`
+//! until a filenames mode is implemented, rather than storing the names in
+//! the clear under a policy that claims otherwise. Files are only decrypted
+//! through `open`, so encrypted files can't be mapped or executed. `stat`
+//! reports the size of the contents from the header.
+
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec};
+use core::{any::Any, fmt::Write, task::Context};
+
+use aes_gcm::aes::{
+    Aes128, Aes256,
+    cipher::{BlockDecrypt, BlockEncrypt, KeyInit, consts::U16, generic_array::GenericArray},
+};
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::{FS_CONTEXT, FileBackend, FileFlags, OpenOptions};
+use axfs_ng_vfs::{
+    DirEntry, FileNode, FileNodeOps, FilesystemOps, Location, Metadata, MetadataUpdate, NodeOps,
+    NodeType, Reference, VfsError, VfsResult,
+};
+use axpoll::{IoEvents, Pollable};
+use axtask::current;
+use bytemuck::{AnyBitPattern, NoUninit};
+use inherit_methods_macro::inherit_methods;
+use memory_addr::PAGE_SIZE_4K;
+use starry_core::{
+    keys::{KeyType, Requester},
+    task::AsThread,
+};
+use starry_vm::{VmMutPtr, VmPtr};
+
+pub const FS_IOC_SET_ENCRYPTION_POLICY: u32 = 0x800c_6613;
+pub const FS_IOC_GET_ENCRYPTION_POLICY: u32 = 0x400c_6615;
+
+const FSCRYPT_MODE_AES_256_XTS: u8 = 1;
+/// The filenames modes that are implemented, none so far: Linux pairs
+/// AES-256-XTS contents with AES-256-CTS names (mode 4), which would need
+/// names to be translated on every lookup.
+const FILENAMES_MODES: &[u8] = &[];
+const FSCRYPT_POLICY_FLAGS_PAD_MASK: u8 = 0x03;
+
+/// The size of `struct fscrypt_key`, the payload of a master key.
+const FSCRYPT_KEY_SIZE: usize = 72;
+/// The size of an AES-256-XTS master key.
+const MASTER_KEY_SIZE: usize = 64;
+/// The size of the nonce the key of a file is derived with.
+const FILE_NONCE_SIZE: usize = 16;
+
+/// The size of the header in front of the contents of an encrypted file,
+/// which holds its nonce, then the size of its contents.
+const HEADER_SIZE: u64 = 32;
+/// The offset of the size of the contents in the header.
+const SIZE_OFFSET: u64 = FILE_NONCE_SIZE as u64;
+/// The size of an AES block, which is also the XTS data unit.
+const BLOCK_SIZE: u64 = 16;
+
+/// The file holding the policy of an encrypted directory.
+const POLICY_FILE: &str = ".fscrypt_policy";
+
+/// An encryption policy, `struct fscrypt_policy_v1`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnyBitPattern, NoUninit)]
+struct Policy {
+    version: u8,
+    contents_encryption_mode: u8,
+    filenames_encryption_mode: u8,
+    flags: u8,
+    master_key_descriptor: [u8; 8],
+}
+
+impl Policy {
+    fn check(&self) -> AxResult<()> {
+        if self.version != 0
+            || self.contents_encryption_mode != FSCRYPT_MODE_AES_256_XTS
+            || !FILENAMES_MODES.contains(&self.filenames_encryption_mode)
+            || self.flags & !FSCRYPT_POLICY_FLAGS_PAD_MASK != 0
+        {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// Returns the description of the master key in the keyring.
+    fn key_description(&self) -> String {
+        let mut description = String::from("fscrypt:");
+        for byte in self.master_key_descriptor {
+            let _ = write!(description, "{byte:02x}");
+        }
+        description
+    }
+
+    /// Derives the key of the contents of a file from the master key and the
+    /// file's nonce.
+    fn file_key(&self, nonce: &[u8; FILE_NONCE_SIZE]) -> AxResult<Xts> {
+        // Like fscrypt v1, look the master key up in the keyrings of the
+        // opening thread, which usually finds it in the session keyring.
+        let master = Requester::current().search(KeyType::Logon, &self.key_description())?;
+        master.with_payload(|payload| {
+            // struct fscrypt_key { __u32 mode; __u8 raw[64]; __u32 size; }
+            if payload.len() != FSCRYPT_KEY_SIZE {
+                return Err(AxError::InvalidInput);
+            }
+            let size = u32::from_ne_bytes(payload[68..].try_into().unwrap()) as usize;
+            if size != MASTER_KEY_SIZE {
+                return Err(AxError::InvalidInput);
+            }
+            let mut key = [0; MASTER_KEY_SIZE];
+            key.copy_from_slice(&payload[4..4 + size]);
+            let kdf = Aes128::new(GenericArray::from_slice(nonce));
+            for block in key.chunks_exact_mut(BLOCK_SIZE as usize) {
+                kdf.encrypt_block(GenericArray::from_mut_slice(block));
+            }
+            let xts = Xts {
+                data: Aes256::new(GenericArray::from_slice(&key[..32])),
+                tweak: Aes256::new(GenericArray::from_slice(&key[32..])),
+            };
+            key.fill(0);
+            Ok(xts)
+        })?
+    }
+}
+
+/// Reads the policy of directory `dir`, if it is encrypted.
+fn read_policy(dir: &Location) -> AxResult<Option<Policy>> {
+    let fs = FS_CONTEXT.lock().with_current_dir(dir.clone())?;
+    let file = match OpenOptions::new().read(true).open(&fs, POLICY_FILE) {
+        Ok(file) => file.into_file()?,
+        Err(AxError::NotFound) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut buf = [0; size_of::<Policy>()];
+    if file.read_at(&mut &mut buf[..], 0)? != buf.len() {
+        return Err(AxError::Other(LinuxError::EUCLEAN));
+    }
+    Ok(Some(*bytemuck::from_bytes(&buf)))
+}
+
+fn write_policy(dir: &Location, policy: &Policy) -> AxResult<()> {
+    let fs = FS_CONTEXT.lock().with_current_dir(dir.clone())?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&fs, POLICY_FILE)?
+        .into_file()?;
+    file.write_at(&mut bytemuck::bytes_of(policy), 0)?;
+    Ok(())
+}
+
+/// Whether `dir` is empty, not counting its policy if `with_policy` is set.
+fn is_empty(dir: &Location, with_policy: bool) -> AxResult<bool> {
+    let mut empty = true;
+    dir.read_dir(0, &mut |name: &str, _, _, _| {
+        empty = name == "." || name == ".." || (with_policy && name == POLICY_FILE);
+        empty
+    })?;
+    Ok(empty)
+}
+
+fn parent_of(loc: &Location) -> Option<Location> {
+    let parent = loc.entry().parent()?;
+    Some(Location::new(loc.mountpoint().clone(), parent))
+}
+
+/// Makes the new directory `dir` encrypted if its parent is.
+pub fn inherit(dir: &Location) -> AxResult<()> {
+    if let Some(parent) = parent_of(dir)
+        && let Some(policy) = read_policy(&parent)?
+    {
+        write_policy(dir, &policy)?;
+    }
+    Ok(())
+}
+
+fn set_policy(dir: &Location, arg: usize) -> AxResult<usize> {
+    let policy = (arg as *const Policy).vm_read()?;
+    policy.check()?;
+    if !dir.is_dir() {
+        return Err(AxError::NotADirectory);
+    }
+    let fsuid = current().as_thread().proc_data.cred.read().fsuid;
+    if fsuid != 0 && fsuid != dir.metadata()?.uid {
+        return Err(AxError::PermissionDenied);
+    }
+    match read_policy(dir)? {
+        // Setting the same policy again is how it is checked.
+        Some(existing) if existing == policy => Ok(0),
+        Some(_) => Err(AxError::AlreadyExists),
+        None if !is_empty(dir, false)? => Err(AxError::DirectoryNotEmpty),
+        None => write_policy(dir, &policy).map(|_| 0),
+    }
+}
+
+fn get_policy(loc: &Location, arg: usize) -> AxResult<usize> {
+    let dir = if loc.is_dir() {
+        Some(loc.clone())
+    } else {
+        parent_of(loc)
+    };
+    let policy = match dir {
+        Some(dir) => read_policy(&dir)?,
+        None => None,
+    };
+    let policy = policy.ok_or(AxError::Other(LinuxError::ENODATA))?;
+    (arg as *mut Policy).vm_write(policy)?;
+    Ok(0)
+}
+
+/// Fails with `EPERM` if the last component of `path` names the policy of an
+/// encrypted directory, which is only set through
+/// `FS_IOC_SET_ENCRYPTION_POLICY`.
+pub fn check_path(path: &str) -> AxResult<()> {
+    if path.trim_end_matches('/').rsplit('/').next() == Some(POLICY_FILE) {
+        return Err(AxError::OperationNotPermitted);
+    }
+    Ok(())
+}
+
+/// Removes the policy of the encrypted directory `dir` when nothing else is
+/// left in it, so that the directory can be removed.
+pub fn before_remove_dir(dir: &Location) -> AxResult<()> {
+    if read_policy(dir)?.is_some() && is_empty(dir, true)? {
+        FS_CONTEXT
+            .lock()
+            .with_current_dir(dir.clone())?
+            .remove_file(POLICY_FILE)?;
+    }
+    Ok(())
+}
+
+/// Fails with `EACCES` if `loc` is a file in an encrypted directory, which
+/// would be loaded without being decrypted.
+pub fn check_exec(loc: &Location) -> AxResult<()> {
+    if let Some(parent) = parent_of(loc)
+        && read_policy(&parent)?.is_some()
+    {
+        return Err(AxError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Whether `loc` is an encrypted file opened by [`open`].
+pub fn is_encrypted(loc: &Location) -> bool {
+    loc.entry().downcast::<CryptFile>().is_ok()
+}
+
+/// Returns the size of the contents of `loc` if it is a regular file in an
+/// encrypted directory that was not opened through [`open`], whose size
+/// would otherwise be that of the file underneath.
+pub fn plaintext_size(loc: &Location) -> AxResult<Option<u64>> {
+    if is_encrypted(loc) || loc.node_type() != NodeType::RegularFile || loc.name() == POLICY_FILE {
+        return Ok(None);
+    }
+    let Some(parent) = parent_of(loc) else {
+        return Ok(None);
+    };
+    if read_policy(&parent)?.is_none() {
+        return Ok(None);
+    }
+    // The header is only written when the file is first opened.
+    if loc.metadata()?.size < HEADER_SIZE {
+        return Ok(Some(0));
+    }
+    let mut buf = [0; 8];
+    loc.entry().as_file()?.read_at(&mut buf, SIZE_OFFSET)?;
+    Ok(Some(u64::from_le_bytes(buf)))
+}
+
+/// Handles the encryption ioctls on the file or directory at `loc`, or
+/// returns `None` for other commands.
+pub fn ioctl(loc: &Location, cmd: u32, arg: usize) -> Option<AxResult<usize>> {
+    match cmd {
+        FS_IOC_SET_ENCRYPTION_POLICY => Some(set_policy(loc, arg)),
+        FS_IOC_GET_ENCRYPTION_POLICY => Some(get_policy(loc, arg)),
+        _ => None,
+    }
+}
+
+/// Puts the contents of `file` behind encryption if it is a regular file in
+/// an encrypted directory.
+///
+/// Fails with `ENOKEY` if the master key is not in the keyring.
+pub fn open(file: axfs_ng::File) -> AxResult<axfs_ng::File> {
+    let loc = file.location().clone();
+    let metadata = loc.metadata()?;
+    if metadata.node_type != NodeType::RegularFile || loc.name() == POLICY_FILE {
+        return Ok(file);
+    }
+    let Some(parent) = parent_of(&loc) else {
+        return Ok(file);
+    };
+    let Some(policy) = read_policy(&parent)? else {
+        return Ok(file);
+    };
+
+    // The header is written whatever the file is opened for, like the nonce
+    // Linux gives a file when it is created.
+    let node = loc.entry().as_file()?;
+    let mut nonce = [0; FILE_NONCE_SIZE];
+    if metadata.size == 0 {
+        FS_CONTEXT
+            .lock()
+            .resolve("/dev/urandom")?
+            .entry()
+            .as_file()?
+            .read_at(&mut nonce, 0)?;
+        let mut header = [0; HEADER_SIZE as usize];
+        header[..FILE_NONCE_SIZE].copy_from_slice(&nonce);
+        node.write_at(&header, 0)?;
+    } else if node.read_at(&mut nonce, 0)? != FILE_NONCE_SIZE {
+        return Err(AxError::Other(LinuxError::EUCLEAN));
+    }
+    let xts = policy.file_key(&nonce)?;
+
+    let flags = file.flags();
+    let node = CryptFile { lower: file, xts };
+    let entry = DirEntry::new_file(
+        FileNode::new(Arc::new(node)),
+        NodeType::RegularFile,
+        Reference::new(Some(parent.entry().clone()), loc.name().to_owned()),
+    );
+    let loc = Location::new(loc.mountpoint().clone(), entry);
+    Ok(axfs_ng::File::new(FileBackend::Direct(loc), flags))
+}
+
+/// AES-256-XTS with data units of a single block, each tweaked by its index
+/// in the file.
+struct Xts {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl Xts {
+    fn tweak(&self, index: u64) -> GenericArray<u8, U16> {
+        let mut tweak = GenericArray::from((index as u128).to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+        tweak
+    }
+
+    /// Encrypts `data`, whole blocks starting with block `first` of the file.
+    fn encrypt(&self, first: u64, data: &mut [u8]) {
+        for (index, block) in (first..).zip(data.chunks_exact_mut(BLOCK_SIZE as usize)) {
+            let tweak = self.tweak(index);
+            xor(block, &tweak);
+            self.data.encrypt_block(GenericArray::from_mut_slice(block));
+            xor(block, &tweak);
+        }
+    }
+
+    /// Decrypts `data`, whole blocks starting with block `first` of the file.
+    fn decrypt(&self, first: u64, data: &mut [u8]) {
+        for (index, block) in (first..).zip(data.chunks_exact_mut(BLOCK_SIZE as usize)) {
+            let tweak = self.tweak(index);
+            xor(block, &tweak);
+            self.data.decrypt_block(GenericArray::from_mut_slice(block));
+            xor(block, &tweak);
+        }
+    }
+}
+
+fn xor(block: &mut [u8], tweak: &[u8]) {
+    for (byte, tweak) in block.iter_mut().zip(tweak) {
+        *byte ^= tweak;
+    }
+}
+
+/// A regular file in an encrypted directory, whose contents are kept
+/// encrypted in the file underneath, after its header.
+///
+/// The contents take whole blocks underneath; what the last one holds past
+/// the size in the header is left over from a truncation, and reads as
+/// zeros.
+struct CryptFile {
+    lower: axfs_ng::File,
+    xts: Xts,
+}
+
+impl CryptFile {
+    /// Returns the size of the contents.
+    fn size(&self) -> VfsResult<u64> {
+        let mut buf = [0; 8];
+        self.lower
+            .location()
+            .entry()
+            .as_file()?
+            .read_at(&mut buf, SIZE_OFFSET)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn set_size(&self, size: u64) -> VfsResult<()> {
+        self.lower
+            .location()
+            .entry()
+            .as_file()?
+            .write_at(&size.to_le_bytes(), SIZE_OFFSET)?;
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` in contents of `size` bytes, encrypting it
+    /// together with the rest of the blocks it falls in.
+    fn write_blocks(&self, offset: u64, data: &[u8], size: u64) -> VfsResult<()> {
+        let start = offset / BLOCK_SIZE * BLOCK_SIZE;
+        let end = (offset + data.len() as u64).next_multiple_of(BLOCK_SIZE);
+        let mut buf = vec![0; (end - start) as usize];
+        if start < size {
+            let valid = (size.min(end) - start) as usize;
+            let stored = &mut buf[..valid.next_multiple_of(BLOCK_SIZE as usize)];
+            self.lower.read_at(&mut &mut *stored, HEADER_SIZE + start)?;
+            self.xts.decrypt(start / BLOCK_SIZE, stored);
+            buf[valid..].fill(0);
+        }
+        let skip = (offset - start) as usize;
+        buf[skip..skip + data.len()].copy_from_slice(data);
+        self.xts.encrypt(start / BLOCK_SIZE, &mut buf);
+        self.lower.write_at(&mut &buf[..], HEADER_SIZE + start)?;
+        Ok(())
+    }
+
+    /// Extends the contents from `size` to `end` with zeros, so that a gap
+    /// left by a write past the end reads back as zeros.
+    fn fill(&self, mut size: u64, end: u64) -> VfsResult<()> {
+        let zeros = vec![0; PAGE_SIZE_4K];
+        while size < end {
+            let len = ((end - size) as usize).min(PAGE_SIZE_4K);
+            self.write_blocks(size, &zeros[..len], size)?;
+            size += len as u64;
+        }
+        Ok(())
+    }
+}
+
+#[inherit_methods(from = "self.lower.location()")]
+impl NodeOps for CryptFile {
+    fn inode(&self) -> u64;
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()>;
+
+    fn filesystem(&self) -> &dyn FilesystemOps;
+
+    fn sync(&self, data_only: bool) -> VfsResult<()>;
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let mut metadata = self.lower.location().metadata()?;
+        metadata.size = self.size()?;
+        Ok(metadata)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for CryptFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let size = self.size()?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        let start = offset / BLOCK_SIZE * BLOCK_SIZE;
+        let end = (offset + len as u64).next_multiple_of(BLOCK_SIZE);
+        let mut data = vec![0; (end - start) as usize];
+        self.lower
+            .read_at(&mut &mut data[..], HEADER_SIZE + start)?;
+        self.xts.decrypt(start / BLOCK_SIZE, &mut data);
+        let skip = (offset - start) as usize;
+        buf[..len].copy_from_slice(&data[skip..skip + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let size = self.size()?;
+        self.fill(size, offset)?;
+        self.write_blocks(offset, buf, size.max(offset))?;
+        let end = offset + buf.len() as u64;
+        if end > size {
+            self.set_size(end)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let offset = self.size()?;
+        let written = self.write_at(buf, offset)?;
+        Ok((written, offset + written as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        let size = self.size()?;
+        if len > size {
+            self.fill(size, len)?;
+        } else {
+            self.lower
+                .access(FileFlags::WRITE)?
+                .set_len(HEADER_SIZE + len.next_multiple_of(BLOCK_SIZE))?;
+        }
+        self.set_size(len)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::BadFileDescriptor)
+    }
+}
+
+impl Pollable for CryptFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
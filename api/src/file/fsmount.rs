@@ -0,0 +1,148 @@
+//! File descriptors of the new mount API: filesystem contexts opened with
+//! `fsopen` or `fspick` and configured with `fsconfig`, and the mounts
+//! `fsmount` makes of them until `move_mount` attaches them.
+
+use alloc::{
+    borrow::Cow,
+    string::{String, ToString},
+    sync::Arc,
+};
+use core::{any::Any, task::Context};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::{Filesystem, Location};
+use axpoll::{IoEvents, Pollable};
+use spin::Mutex;
+use starry_core::vfs::MountFlags;
+
+use super::{FileLike, Kstat, SealedBuf, SealedBufMut};
+
+/// Where a filesystem context is in its life.
+pub enum FsContextPhase {
+    /// Taking parameters until `FSCONFIG_CMD_CREATE`.
+    Creating,
+    /// Created and waiting for `fsmount`.
+    Created(Filesystem),
+    /// Handed over to `fsmount`.
+    Mounted,
+    /// Picked by `fspick` to reconfigure the mount with this root.
+    Reconfiguring(Location),
+}
+
+/// The parameters of a filesystem context.
+pub struct FsConfig {
+    pub fs_type: String,
+    pub source: Option<String>,
+    pub flags: MountFlags,
+    pub phase: FsContextPhase,
+}
+
+/// A filesystem context, from `fsopen` or `fspick`.
+pub struct FsContextFile(Mutex<FsConfig>);
+
+impl FsContextFile {
+    pub fn new(config: FsConfig) -> Self {
+        Self(Mutex::new(config))
+    }
+
+    pub fn config(&self) -> &Mutex<FsConfig> {
+        &self.0
+    }
+}
+
+impl FileLike for FsContextFile {
+    fn read(&self, _dst: &mut SealedBufMut) -> AxResult<usize> {
+        // Linux reads out error messages here, and we don't keep any.
+        Err(AxError::NoData)
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn path(&self) -> Cow<str> {
+        "anon_inode:[fscontext]".into()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl Pollable for FsContextFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::empty()
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+/// A mount made by `fsmount` but not attached anywhere yet.
+pub struct DetachedMount {
+    pub fs: Filesystem,
+    pub source: String,
+    pub fs_type: String,
+    pub flags: MountFlags,
+}
+
+/// What a mount file refers to.
+pub enum MountState {
+    Detached(DetachedMount),
+    /// Attached by `move_mount`, with this root.
+    Attached(Location),
+}
+
+/// A mount, from `fsmount`.
+pub struct MountFile(Mutex<MountState>);
+
+impl MountFile {
+    pub fn new(mount: DetachedMount) -> Self {
+        Self(Mutex::new(MountState::Detached(mount)))
+    }
+
+    pub fn state(&self) -> &Mutex<MountState> {
+        &self.0
+    }
+}
+
+impl FileLike for MountFile {
+    fn read(&self, _dst: &mut SealedBufMut) -> AxResult<usize> {
+        Err(AxError::BadFileDescriptor)
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::BadFileDescriptor)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        match &*self.0.lock() {
+            MountState::Detached(_) => Ok(Kstat::default()),
+            MountState::Attached(root) => root.metadata().map(|it| super::metadata_to_kstat(&it)),
+        }
+    }
+
+    fn path(&self) -> Cow<str> {
+        match &*self.0.lock() {
+            MountState::Detached(_) => "/".into(),
+            MountState::Attached(root) => root
+                .absolute_path()
+                .map_or("/".into(), |path| path.to_string().into()),
+        }
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl Pollable for MountFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::empty()
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
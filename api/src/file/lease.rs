@@ -0,0 +1,283 @@
+//! File leases, as taken with `fcntl(F_SETLEASE)`.
+//!
+//! A lease on a regular file makes the kernel tell its holder when somebody
+//! else opens the file in a conflicting way: any open breaks a write lease
+//! and an open for writing breaks a read lease. The holder is sent `SIGIO`,
+//! or the signal set with `F_SETSIG`, and the opener waits until the lease
+//! is given up or downgraded. A holder that doesn't react within
+//! `fs.lease-break-time` seconds loses the lease.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{future::poll_fn, task::Poll, time::Duration};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng::FileFlags;
+use axfs_ng_vfs::{Location, NodeType};
+use axhal::time::monotonic_time;
+use axpoll::PollSet;
+use axtask::{
+    current,
+    future::{block_on, interruptible},
+};
+use lazy_static::lazy_static;
+use linux_raw_sys::general::{F_RDLCK, F_UNLCK, F_WRLCK};
+use spin::Mutex;
+use starry_core::{
+    sysctl::LEASE_BREAK_TIME,
+    task::{AsThread, processes, send_signal_to_process},
+    timer,
+};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
+
+use super::{FD_TABLE, File};
+
+struct Lease {
+    dev: u64,
+    ino: u64,
+    /// The open file the lease was taken through.
+    file: Weak<File>,
+    /// `F_RDLCK` or `F_WRLCK`.
+    kind: u32,
+    /// The process told when the lease is broken.
+    owner: Pid,
+    /// While the lease is being broken, the type it has to go down to and
+    /// when it is revoked if it hasn't.
+    breaking: Option<(u32, Duration)>,
+}
+
+impl Lease {
+    fn held_by(&self, file: &Arc<File>) -> bool {
+        Weak::as_ptr(&self.file) == Arc::as_ptr(file)
+    }
+
+    /// Whether an open, for writing if `write` is set, conflicts with the
+    /// lease.
+    fn conflicts(&self, dev: u64, ino: u64, write: bool) -> bool {
+        self.dev == dev && self.ino == ino && (write || self.kind == F_WRLCK)
+    }
+}
+
+static LEASES: Mutex<Vec<Lease>> = Mutex::new(Vec::new());
+
+lazy_static! {
+    /// Woken whenever a lease is given up, downgraded or revoked.
+    static ref LEASE_CHANGED: PollSet = PollSet::new();
+}
+
+fn inode_of(loc: &Location) -> AxResult<(u64, u64)> {
+    Ok((loc.mountpoint().device() as u64, loc.metadata()?.inode))
+}
+
+/// Returns every open file of the inode `(dev, ino)`, in any process.
+fn open_files(dev: u64, ino: u64) -> Vec<Arc<File>> {
+    let mut files: Vec<Arc<File>> = Vec::new();
+    for proc_data in processes() {
+        let scope = proc_data.scope.read();
+        let table = FD_TABLE.scope(&scope).read();
+        for id in table.ids() {
+            let Ok(file) = table
+                .get(id)
+                .unwrap()
+                .inner
+                .clone()
+                .into_any()
+                .downcast::<File>()
+            else {
+                continue;
+            };
+            if !files.iter().any(|it| Arc::ptr_eq(it, &file))
+                && inode_of(file.inner().location()).is_ok_and(|it| it == (dev, ino))
+            {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+/// Returns the lease held through `file` (`F_GETLEASE`).
+///
+/// A lease being broken reports the type it has to go down to.
+pub fn get_lease(file: &Arc<File>) -> u32 {
+    LEASES
+        .lock()
+        .iter()
+        .find(|it| it.held_by(file))
+        .map_or(F_UNLCK, |it| it.breaking.map_or(it.kind, |(to, _)| to))
+}
+
+/// Takes, changes or gives up the lease held through `file`
+/// (`F_SETLEASE`).
+pub fn set_lease(file: &Arc<File>, kind: u32) -> AxResult<()> {
+    if kind == F_UNLCK {
+        let mut leases = LEASES.lock();
+        leases.retain(|it| !it.held_by(file));
+        drop(leases);
+        LEASE_CHANGED.wake();
+        return Ok(());
+    }
+    if kind != F_RDLCK && kind != F_WRLCK {
+        return Err(AxError::InvalidInput);
+    }
+
+    let loc = file.inner().location();
+    let metadata = loc.metadata()?;
+    if metadata.node_type != NodeType::RegularFile {
+        return Err(AxError::InvalidInput);
+    }
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let cred = proc_data.cred.read();
+    if cred.fsuid != metadata.uid && !cred.is_privileged() {
+        return Err(AxError::PermissionDenied);
+    }
+    drop(cred);
+
+    let (dev, ino) = inode_of(loc)?;
+    // Other opens conflicting with the new lease would have broken it.
+    let others = open_files(dev, ino);
+    let conflict = if kind == F_WRLCK {
+        others.iter().any(|it| !Arc::ptr_eq(it, file))
+    } else {
+        others
+            .iter()
+            .any(|it| it.inner().flags().contains(FileFlags::WRITE))
+    };
+    if conflict {
+        return Err(AxError::WouldBlock);
+    }
+
+    let mut leases = LEASES.lock();
+    leases.retain(|it| it.file.strong_count() > 0);
+    if let Some(lease) = leases.iter_mut().find(|it| it.held_by(file)) {
+        if let Some((to, _)) = lease.breaking {
+            // A lease being broken may only go down.
+            if kind == F_WRLCK && lease.kind == F_RDLCK {
+                return Err(AxError::WouldBlock);
+            }
+            if kind == to {
+                lease.breaking = None;
+            }
+        }
+        lease.kind = kind;
+    } else {
+        leases.push(Lease {
+            dev,
+            ino,
+            file: Arc::downgrade(file),
+            kind,
+            owner: proc_data.proc.pid(),
+            breaking: None,
+        });
+    }
+    drop(leases);
+    LEASE_CHANGED.wake();
+    Ok(())
+}
+
+/// Revokes the leases on `(dev, ino)` whose holders let the break time run
+/// out, returning when the next one runs out among those left conflicting
+/// with an open, for writing if `write` is set. Returns `None` if there are
+/// none.
+fn revoke_expired(dev: u64, ino: u64, write: bool) -> Option<Duration> {
+    let now = monotonic_time();
+    let mut leases = LEASES.lock();
+    let before = leases.len();
+    leases.retain(|it| {
+        it.file.strong_count() > 0 && it.breaking.is_none_or(|(_, deadline)| deadline > now)
+    });
+    if leases.len() != before {
+        LEASE_CHANGED.wake();
+    }
+    leases
+        .iter()
+        .filter(|it| it.conflicts(dev, ino, write))
+        .filter_map(|it| it.breaking.map(|(_, deadline)| deadline))
+        .min()
+}
+
+/// Breaks the leases `loc` is opened against, for writing if `write` is
+/// set, and waits until they are given up.
+///
+/// Fails with [`AxError::WouldBlock`] instead of waiting for a `nonblock`
+/// open.
+pub fn break_lease(loc: &Location, write: bool, nonblock: bool) -> AxResult<()> {
+    if LEASES.lock().is_empty() {
+        return Ok(());
+    }
+    let (dev, ino) = inode_of(loc)?;
+
+    let mut leases = LEASES.lock();
+    leases.retain(|it| it.file.strong_count() > 0);
+    let deadline = monotonic_time() + Duration::from_secs(LEASE_BREAK_TIME.get() as u64);
+    let mut conflicts = false;
+    let mut to_notify = Vec::new();
+    for lease in leases.iter_mut().filter(|it| it.conflicts(dev, ino, write)) {
+        conflicts = true;
+        if lease.breaking.is_none() {
+            let to = if write { F_UNLCK } else { F_RDLCK };
+            lease.breaking = Some((to, deadline));
+            to_notify.push((lease.owner, lease.file.clone()));
+        }
+    }
+    drop(leases);
+    if !conflicts {
+        return Ok(());
+    }
+    // The holder may have closed its file meanwhile, and closing it takes the
+    // lock, so the files are looked at only now.
+    for (owner, file) in to_notify {
+        let signo = match file.upgrade().map_or(0, |it| it.signal()) {
+            0 => Signo::SIGIO,
+            signal => Signo::from_repr(signal as u8).unwrap_or(Signo::SIGIO),
+        };
+        let _ = send_signal_to_process(owner, Some(SignalInfo::new_kernel(signo)));
+    }
+    if nonblock {
+        return Err(AxError::WouldBlock);
+    }
+
+    while let Some(deadline) = revoke_expired(dev, ino, write) {
+        let timeout = deadline.saturating_sub(monotonic_time());
+        let released = poll_fn(|cx| {
+            let pending = || {
+                LEASES
+                    .lock()
+                    .iter()
+                    .any(|it| it.file.strong_count() > 0 && it.conflicts(dev, ino, write))
+            };
+            if !pending() {
+                return Poll::Ready(());
+            }
+            LEASE_CHANGED.register(cx.waker());
+            if pending() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        });
+        match block_on(interruptible(timer::timeout(Some(timeout), released)))? {
+            Ok(()) => return Ok(()),
+            // Revoked on the next round.
+            Err(AxError::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the leases of files that were closed, letting those waiting for
+/// them go on.
+pub fn release_closed() {
+    let mut leases = LEASES.lock();
+    let before = leases.len();
+    leases.retain(|it| it.file.strong_count() > 0);
+    if leases.len() != before {
+        drop(leases);
+        LEASE_CHANGED.wake();
+    }
+}
@@ -1,14 +1,31 @@
+pub mod alg;
 pub mod epoll;
 pub mod event;
+pub mod fanotify;
 mod fs;
+pub mod fscrypt;
+pub mod fsmount;
+pub mod lease;
+pub mod mqueue;
 mod net;
+pub mod netlink;
+pub mod packet;
+pub mod perf;
 mod pidfd;
 mod pipe;
+pub mod readahead;
+pub mod seals;
+pub mod userfaultfd;
+
+use alloc::{borrow::Cow, format, sync::Arc};
+use core::{
+    any::Any,
+    ffi::c_int,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
 
-use alloc::{borrow::Cow, sync::Arc};
-use core::{any::Any, ffi::c_int, time::Duration};
-
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axfs_ng::{FS_CONTEXT, OpenOptions};
 use axfs_ng_vfs::DeviceId;
 use axio::{Buf, BufMut, Read, Write};
@@ -18,10 +35,13 @@ use flatten_objects::FlattenObjects;
 use inherit_methods_macro::inherit_methods;
 use linux_raw_sys::general::{RLIMIT_NOFILE, stat, statx, statx_timestamp};
 use spin::RwLock;
-use starry_core::{resources::AX_FILE_LIMIT, task::AsThread};
+use starry_core::{resources::AX_FILE_LIMIT, sysctl::FILE_MAX, task::AsThread};
 
 pub use self::{
-    fs::{Directory, File, ResolveAtResult, metadata_to_kstat, resolve_at, with_fs},
+    fs::{
+        Directory, File, FileSeals, ResolveAtResult, metadata_to_kstat, path_from_root, resolve_at,
+        with_fs,
+    },
     net::Socket,
     pidfd::PidFd,
     pipe::Pipe,
@@ -278,10 +298,46 @@ pub trait FileLike: Pollable + Send + Sync {
     }
 }
 
+/// The number of file descriptors open in the whole system.
+static OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a file descriptor in [`OPEN_FILES`] for as long as it lives.
+struct OpenFileCount;
+
+impl OpenFileCount {
+    fn new() -> Self {
+        OPEN_FILES.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Clone for OpenFileCount {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for OpenFileCount {
+    fn drop(&mut self) {
+        OPEN_FILES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 #[derive(Clone)]
 pub struct FileDescriptor {
     pub inner: Arc<dyn FileLike>,
     pub cloexec: bool,
+    _count: OpenFileCount,
+}
+
+impl FileDescriptor {
+    pub fn new(inner: Arc<dyn FileLike>, cloexec: bool) -> Self {
+        Self {
+            inner,
+            cloexec,
+            _count: OpenFileCount::new(),
+        }
+    }
 }
 
 scope_local::scope_local! {
@@ -300,12 +356,19 @@ pub fn get_file_like(fd: c_int) -> AxResult<Arc<dyn FileLike>> {
 
 /// Add a file to the file descriptor table.
 pub fn add_file_like(f: Arc<dyn FileLike>, cloexec: bool) -> AxResult<c_int> {
-    let max_nofile = current().as_thread().proc_data.rlim.read()[RLIMIT_NOFILE].current;
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    if OPEN_FILES.load(Ordering::Relaxed) >= FILE_MAX.get()
+        && !proc_data.cred.read().is_privileged()
+    {
+        return Err(AxError::Other(LinuxError::ENFILE));
+    }
+    let max_nofile = proc_data.rlim.read()[RLIMIT_NOFILE].current;
     let mut table = FD_TABLE.write();
     if table.count() as u64 >= max_nofile {
         return Err(AxError::TooManyOpenFiles);
     }
-    let fd = FileDescriptor { inner: f, cloexec };
+    let fd = FileDescriptor::new(f, cloexec);
     Ok(table.add(fd).map_err(|_| AxError::TooManyOpenFiles)? as c_int)
 }
 
@@ -319,34 +382,42 @@ pub fn close_file_like(fd: c_int) -> AxResult {
     Ok(())
 }
 
-pub fn add_stdio(fd_table: &mut FlattenObjects<FileDescriptor, AX_FILE_LIMIT>) -> AxResult<()> {
+/// Opens the console as the standard input, output and error of the init
+/// process.
+///
+/// The console is the device `tty` below `/dev` if given, else the device
+/// given by `console=` on the kernel command line, or `/dev/console`.
+pub fn add_stdio(
+    fd_table: &mut FlattenObjects<FileDescriptor, AX_FILE_LIMIT>,
+    tty: Option<&str>,
+) -> AxResult<()> {
     assert_eq!(fd_table.count(), 0);
     let cx = FS_CONTEXT.lock();
+    let mut console = Cow::Borrowed("/dev/console");
+    if let Some(name) = tty.or_else(|| starry_core::cmdline::console()) {
+        let path = format!("/dev/{name}");
+        if cx.resolve(&path).is_ok() {
+            console = Cow::Owned(path);
+        } else {
+            warn!("{path} does not exist, using /dev/console");
+        }
+    }
     let open = |options: &mut OpenOptions| {
         AxResult::Ok(Arc::new(File::new(
-            options.open(&cx, "/dev/console")?.into_file()?,
+            options.open(&cx, &*console)?.into_file()?,
         )))
     };
 
     let tty_in = open(OpenOptions::new().read(true).write(false))?;
     let tty_out = open(OpenOptions::new().read(false).write(true))?;
     fd_table
-        .add(FileDescriptor {
-            inner: tty_in,
-            cloexec: false,
-        })
+        .add(FileDescriptor::new(tty_in, false))
         .map_err(|_| AxError::TooManyOpenFiles)?;
     fd_table
-        .add(FileDescriptor {
-            inner: tty_out.clone(),
-            cloexec: false,
-        })
+        .add(FileDescriptor::new(tty_out.clone(), false))
         .map_err(|_| AxError::TooManyOpenFiles)?;
     fd_table
-        .add(FileDescriptor {
-            inner: tty_out,
-            cloexec: false,
-        })
+        .add(FileDescriptor::new(tty_out, false))
         .map_err(|_| AxError::TooManyOpenFiles)?;
 
     Ok(())
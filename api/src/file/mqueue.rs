@@ -0,0 +1,467 @@
+//! POSIX message queues, as opened with `mq_open` and listed in
+//! `/dev/mqueue`.
+//!
+//! Messages are received highest priority first, and in the order they were
+//! sent within a priority. A process may ask to be told when a message
+//! arrives on an empty queue nobody is waiting on, by a signal or, for
+//! `SIGEV_THREAD`, by a message to the netlink socket libc listens on.
+
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    ffi::c_int,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axio::{BufMut, Write};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::current;
+use linux_raw_sys::general::S_IFREG;
+use spin::Mutex;
+use starry_core::{
+    sysctl::MQ_QUEUES_MAX,
+    task::{AsThread, send_signal_to_process},
+};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
+
+use super::{FileLike, Kstat, SealedBuf, SealedBufMut, get_file_like, netlink::NetlinkSocket};
+
+/// Priorities must be below this.
+pub const MQ_PRIO_MAX: u32 = 32768;
+/// The length of the cookie `SIGEV_THREAD` notifications carry.
+pub const NOTIFY_COOKIE_LEN: usize = 32;
+
+/// The `si_code` of signals from message queues.
+const SI_MESGQ: i32 = -3;
+/// The last byte of a cookie when a message arrived.
+const NOTIFY_WOKENUP: u8 = 1;
+/// The last byte of a cookie when the notification was removed.
+const NOTIFY_REMOVED: u8 = 2;
+
+/// How a process is told that a message arrived.
+pub enum Notify {
+    /// Not at all, though the registration stands until a message arrives.
+    None,
+    /// By the signal `signo`, carrying `value`.
+    Signal { signo: Signo, value: usize },
+    /// By sending `cookie` to `socket`.
+    Netlink {
+        socket: Weak<NetlinkSocket>,
+        cookie: [u8; NOTIFY_COOKIE_LEN],
+    },
+}
+
+impl Notify {
+    /// Sends the cookie of a `SIGEV_THREAD` notification, with `status` as
+    /// its last byte.
+    fn send_cookie(&self, status: u8) {
+        if let Self::Netlink { socket, cookie } = self
+            && let Some(socket) = socket.upgrade()
+        {
+            let mut cookie = *cookie;
+            cookie[NOTIFY_COOKIE_LEN - 1] = status;
+            socket.deliver(&cookie);
+        }
+    }
+}
+
+struct Notification {
+    pid: Pid,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// The messages by priority.
+    messages: BTreeMap<u32, VecDeque<Vec<u8>>>,
+    len: usize,
+    bytes: usize,
+    notification: Option<Notification>,
+}
+
+/// A POSIX message queue.
+pub struct MessageQueue {
+    /// The name, without the leading `/`.
+    pub name: String,
+    /// The most messages the queue holds.
+    pub max_msg: usize,
+    /// The largest message the queue takes.
+    pub msg_size: usize,
+    owner: (u32, u32),
+    mode: u32,
+    state: Mutex<QueueState>,
+    /// How many receivers are waiting for a message.
+    receivers: AtomicUsize,
+    poll_rx: PollSet,
+    poll_tx: PollSet,
+}
+
+static QUEUES: Mutex<BTreeMap<String, Arc<MessageQueue>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the queue called `name`.
+pub fn lookup(name: &str) -> Option<Arc<MessageQueue>> {
+    QUEUES.lock().get(name).cloned()
+}
+
+/// Returns the names of all queues.
+pub fn names() -> Vec<String> {
+    QUEUES.lock().keys().cloned().collect()
+}
+
+/// Returns the queue called `name`, creating it with `create` if there is
+/// none, and whether it was created. Fails with `EEXIST` if it exists and
+/// `exclusive` is set.
+pub fn open_or_create(
+    name: &str,
+    exclusive: bool,
+    create: impl FnOnce() -> AxResult<MessageQueue>,
+) -> AxResult<(Arc<MessageQueue>, bool)> {
+    let mut queues = QUEUES.lock();
+    if let Some(queue) = queues.get(name) {
+        if exclusive {
+            return Err(AxError::AlreadyExists);
+        }
+        return Ok((queue.clone(), false));
+    }
+    let privileged = current().as_thread().proc_data.cred.read().is_privileged();
+    if queues.len() >= MQ_QUEUES_MAX.get() && !privileged {
+        return Err(AxError::Other(LinuxError::ENOSPC));
+    }
+    let queue = Arc::new(create()?);
+    queues.insert(name.into(), queue.clone());
+    Ok((queue, true))
+}
+
+/// Removes the queue called `name`, which lives on until it is closed.
+pub fn unlink(name: &str) -> AxResult<()> {
+    let mut queues = QUEUES.lock();
+    let queue = queues.get(name).ok_or(AxError::NotFound)?;
+    let cred = current().as_thread().proc_data.cred.read().clone();
+    // Removing a queue takes write access to the directory, which is world
+    // writable and sticky like /tmp.
+    if !cred.is_privileged() && cred.fsuid != queue.owner.0 {
+        return Err(AxError::PermissionDenied);
+    }
+    queues.remove(name);
+    Ok(())
+}
+
+impl MessageQueue {
+    /// Creates a queue owned by the caller.
+    pub fn new(name: &str, max_msg: usize, msg_size: usize, mode: u32) -> Self {
+        let cred = current().as_thread().proc_data.cred.read().clone();
+        Self {
+            name: name.into(),
+            max_msg,
+            msg_size,
+            owner: (cred.fsuid, cred.fsgid),
+            mode: mode & 0o777,
+            state: Mutex::default(),
+            receivers: AtomicUsize::new(0),
+            poll_rx: PollSet::new(),
+            poll_tx: PollSet::new(),
+        }
+    }
+
+    /// Returns the owning user and group.
+    pub fn owner(&self) -> (u32, u32) {
+        self.owner
+    }
+
+    /// Returns the permission bits.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Checks that the caller may open the queue for reading and writing
+    /// as asked, by its mode.
+    pub fn check_access(&self, read: bool, write: bool) -> AxResult<()> {
+        let cred = current().as_thread().proc_data.cred.read().clone();
+        if cred.is_privileged() {
+            return Ok(());
+        }
+        let bits = if cred.fsuid == self.owner.0 {
+            self.mode >> 6
+        } else if cred.fsgid == self.owner.1 {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+        if (read && bits & 0o4 == 0) || (write && bits & 0o2 == 0) {
+            return Err(AxError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of messages in the queue.
+    pub fn len(&self) -> usize {
+        self.state.lock().len
+    }
+
+    /// Returns whether the queue holds no message.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a message, failing with [`AxError::WouldBlock`] if the queue is
+    /// full.
+    pub fn try_send(&self, data: &[u8], prio: u32) -> AxResult<()> {
+        let mut state = self.state.lock();
+        if state.len >= self.max_msg {
+            return Err(AxError::WouldBlock);
+        }
+        let was_empty = state.len == 0;
+        state
+            .messages
+            .entry(prio)
+            .or_default()
+            .push_back(data.into());
+        state.len += 1;
+        state.bytes += data.len();
+        let notification = if was_empty && self.receivers.load(Ordering::Acquire) == 0 {
+            state.notification.take()
+        } else {
+            None
+        };
+        drop(state);
+        self.poll_rx.wake();
+        if let Some(notification) = notification {
+            self.fire(notification);
+        }
+        Ok(())
+    }
+
+    /// Takes the oldest message of the highest priority, failing with
+    /// [`AxError::WouldBlock`] if the queue is empty.
+    pub fn try_receive(&self) -> AxResult<(Vec<u8>, u32)> {
+        let mut state = self.state.lock();
+        let mut entry = state.messages.last_entry().ok_or(AxError::WouldBlock)?;
+        let prio = *entry.key();
+        let data = entry.get_mut().pop_front().unwrap();
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        state.len -= 1;
+        state.bytes -= data.len();
+        drop(state);
+        self.poll_tx.wake();
+        Ok((data, prio))
+    }
+
+    /// Counts the caller as waiting for a message until the returned guard
+    /// is dropped, which holds back notifications.
+    pub fn wait_receive(&self) -> WaitingReceiver<'_> {
+        self.receivers.fetch_add(1, Ordering::AcqRel);
+        WaitingReceiver(self)
+    }
+
+    /// Registers the current process for notification, failing with `EBUSY`
+    /// if another one is.
+    pub fn set_notify(&self, notify: Notify) -> AxResult<()> {
+        let pid = current().as_thread().proc_data.proc.pid();
+        let mut state = self.state.lock();
+        if state.notification.is_some() {
+            return Err(AxError::ResourceBusy);
+        }
+        state.notification = Some(Notification { pid, notify });
+        Ok(())
+    }
+
+    /// Removes the registration of the current process for notification.
+    pub fn clear_notify(&self) {
+        let Some(pid) = current()
+            .try_as_thread()
+            .map(|thr| thr.proc_data.proc.pid())
+        else {
+            return;
+        };
+        let mut state = self.state.lock();
+        if state.notification.as_ref().is_some_and(|it| it.pid == pid) {
+            let notification = state.notification.take().unwrap();
+            drop(state);
+            notification.notify.send_cookie(NOTIFY_REMOVED);
+        }
+    }
+
+    fn fire(&self, notification: Notification) {
+        match notification.notify {
+            Notify::Signal { signo, value } => {
+                let thr = current();
+                let proc_data = &thr.as_thread().proc_data;
+                let uid = proc_data.cred.read().uid;
+                let mut sig = SignalInfo::new_user(signo, SI_MESGQ, proc_data.proc.pid());
+                // SAFETY: every member of the siginfo unions is plain old data.
+                unsafe {
+                    let rt = &mut sig.0.__bindgen_anon_1.__bindgen_anon_1._sifields._rt;
+                    rt._uid = uid;
+                    rt._sigval.sival_ptr = value as _;
+                }
+                let _ = send_signal_to_process(notification.pid, Some(sig));
+            }
+            Notify::None => {}
+            notify @ Notify::Netlink { .. } => notify.send_cookie(NOTIFY_WOKENUP),
+        }
+    }
+
+    /// Returns the status line shown by reading the queue: the bytes
+    /// queued and who is registered for notification.
+    pub fn status(&self) -> String {
+        let state = self.state.lock();
+        let (notify, signo, pid) = match &state.notification {
+            Some(Notification {
+                pid,
+                notify: Notify::Signal { signo, .. },
+            }) => (0, *signo as u32, *pid),
+            Some(Notification {
+                pid,
+                notify: Notify::None,
+            }) => (1, 0, *pid),
+            Some(Notification { pid, .. }) => (2, 0, *pid),
+            None => (0, 0, 0),
+        };
+        format!(
+            "QSIZE:{:<10} NOTIFY:{:<5} SIGNO:{:<5} NOTIFY_PID:{:<6}\n",
+            state.bytes, notify, signo, pid
+        )
+    }
+}
+
+impl Drop for MessageQueue {
+    fn drop(&mut self) {
+        if let Some(notification) = self.state.get_mut().notification.take() {
+            notification.notify.send_cookie(NOTIFY_REMOVED);
+        }
+    }
+}
+
+impl Pollable for MessageQueue {
+    fn poll(&self) -> IoEvents {
+        let len = self.len();
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, len > 0);
+        events.set(IoEvents::OUT, len < self.max_msg);
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+        if events.contains(IoEvents::OUT) {
+            self.poll_tx.register(context.waker());
+        }
+    }
+}
+
+/// A receiver waiting on a [`MessageQueue`].
+pub struct WaitingReceiver<'a>(&'a MessageQueue);
+
+impl Drop for WaitingReceiver<'_> {
+    fn drop(&mut self) {
+        self.0.receivers.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A message queue descriptor.
+pub struct MqueueFile {
+    pub queue: Arc<MessageQueue>,
+    pub readable: bool,
+    pub writable: bool,
+    non_blocking: AtomicBool,
+    /// How much of the status line has been read.
+    offset: AtomicUsize,
+}
+
+impl MqueueFile {
+    pub fn new(
+        queue: Arc<MessageQueue>,
+        readable: bool,
+        writable: bool,
+        non_blocking: bool,
+    ) -> Self {
+        Self {
+            queue,
+            readable,
+            writable,
+            non_blocking: AtomicBool::new(non_blocking),
+            offset: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Drop for MqueueFile {
+    fn drop(&mut self) {
+        self.queue.clear_notify();
+    }
+}
+
+impl FileLike for MqueueFile {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        let status = self.queue.status();
+        let offset = self.offset.load(Ordering::Acquire).min(status.len());
+        let len = dst.write(&status.as_bytes()[offset..])?;
+        self.offset.fetch_add(len, Ordering::AcqRel);
+        Ok(len)
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        let (uid, gid) = self.queue.owner();
+        Ok(Kstat {
+            mode: S_IFREG | self.queue.mode(),
+            uid,
+            gid,
+            ..Default::default()
+        })
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        format!("/{}", self.queue.name).into()
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn from_fd(fd: c_int) -> AxResult<Arc<Self>>
+    where
+        Self: Sized + 'static,
+    {
+        get_file_like(fd)?
+            .into_any()
+            .downcast::<Self>()
+            .map_err(|_| AxError::BadFileDescriptor)
+    }
+}
+
+impl Pollable for MqueueFile {
+    fn poll(&self) -> IoEvents {
+        self.queue.poll()
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        self.queue.register(context, events);
+    }
+}
@@ -1,34 +1,320 @@
-use alloc::{borrow::Cow, format, sync::Arc};
-use core::{ffi::c_int, ops::Deref, task::Context};
+use alloc::{borrow::Cow, format, sync::Arc, vec, vec::Vec};
+use core::{
+    ffi::{CStr, c_int},
+    net::{Ipv4Addr, SocketAddr},
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    task::Context,
+    time::Duration,
+};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
+use axio::{Buf, BufMut};
 use axnet::{
-    SocketOps,
+    RecvFlags, RecvOptions, SendOptions, Shutdown, SocketAddrEx, SocketOps,
+    device::{NetInterface, interface_by_name, interfaces},
     options::{Configurable, GetSocketOption, SetSocketOption},
 };
 use axpoll::{IoEvents, Pollable};
-use linux_raw_sys::general::S_IFSOCK;
+use linux_raw_sys::{
+    general::S_IFSOCK,
+    ioctl::{
+        SIOCGIFADDR, SIOCGIFCONF, SIOCGIFFLAGS, SIOCGIFHWADDR, SIOCGIFINDEX, SIOCGIFMTU,
+        SIOCGIFNETMASK, SIOCSIFADDR, SIOCSIFFLAGS, SIOCSIFNETMASK,
+    },
+    net::{
+        AF_INET, AF_INET6, IFF_LOOPBACK, IFF_PROMISC, IFF_RUNNING, IFF_UP, ifconf, ifreq, in_addr,
+        sockaddr, sockaddr_in,
+    },
+};
+use starry_core::sysctl::BUSY_READ;
+use starry_vm::{VmMutPtr, VmPtr, vm_write_slice};
+
+use super::{FileLike, Kstat, pipe::raise_pipe};
+use crate::{
+    file::{SealedBuf, SealedBufMut, get_file_like},
+    filter::SocketFilter,
+};
+
+/// `ARPHRD_ETHER` from `<linux/if_arp.h>`.
+pub(super) const ARPHRD_ETHER: u16 = 1;
+/// `ARPHRD_LOOPBACK` from `<linux/if_arp.h>`.
+pub(super) const ARPHRD_LOOPBACK: u16 = 772;
+
+/// The largest datagram a socket filter gets to see.
+const MAX_FILTERED_LEN: usize = 65536;
+
+pub struct Socket {
+    inner: axnet::Socket,
+    /// The address family the socket was created with (`AF_*`).
+    domain: u32,
+    /// `IPV6_V6ONLY`: whether an `AF_INET6` socket refuses IPv4-mapped
+    /// addresses.
+    v6only: AtomicBool,
+    /// Whether a non-blocking `connect` is still in flight.
+    connecting: AtomicBool,
+    /// Whether the socket was shut down for reading.
+    shut_read: AtomicBool,
+    /// Whether the socket was shut down for writing.
+    shut_write: AtomicBool,
+    /// The filter attached with `SO_ATTACH_FILTER`.
+    filter: SocketFilter,
+    /// `SO_BUSY_POLL`: how many microseconds a blocking receive spins before
+    /// sleeping.
+    busy_poll: AtomicU32,
+    /// `TCP_FASTOPEN`: the length of the Fast Open queue of a listener.
+    fastopen_qlen: AtomicU32,
+    /// `TCP_FASTOPEN_CONNECT`: whether `connect` should use Fast Open.
+    fastopen_connect: AtomicBool,
+}
+
+impl Socket {
+    pub fn new(inner: axnet::Socket, domain: u32) -> Self {
+        Self {
+            inner,
+            domain,
+            v6only: AtomicBool::new(false),
+            connecting: AtomicBool::new(false),
+            shut_read: AtomicBool::new(false),
+            shut_write: AtomicBool::new(false),
+            filter: SocketFilter::default(),
+            busy_poll: AtomicU32::new(BUSY_READ.get() as _),
+            fastopen_qlen: AtomicU32::new(0),
+            fastopen_connect: AtomicBool::new(false),
+        }
+    }
+
+    pub fn domain(&self) -> u32 {
+        self.domain
+    }
+
+    pub fn v6only(&self) -> bool {
+        self.v6only.load(Ordering::Acquire)
+    }
+
+    pub fn set_v6only(&self, v6only: bool) -> AxResult<()> {
+        if self.domain != AF_INET6 {
+            return Err(AxError::Other(LinuxError::ENOPROTOOPT));
+        }
+        self.v6only.store(v6only, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn connecting(&self) -> bool {
+        self.connecting.load(Ordering::Acquire)
+    }
+
+    pub fn set_connecting(&self, connecting: bool) {
+        self.connecting.store(connecting, Ordering::Release);
+    }
+
+    /// Shuts down one or both directions of the connection.
+    ///
+    /// The state of each direction is kept here as well: reads after
+    /// `SHUT_RD` see the end of the stream once the queued data is gone, and
+    /// writes after `SHUT_WR` fail with `EPIPE`, while the other direction
+    /// keeps working.
+    pub fn shutdown(&self, how: Shutdown) -> AxResult<()> {
+        self.inner.shutdown(how)?;
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.shut_read.store(true, Ordering::Release);
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.shut_write.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn shut_read(&self) -> bool {
+        self.shut_read.load(Ordering::Acquire)
+    }
+
+    fn shut_write(&self) -> bool {
+        self.shut_write.load(Ordering::Acquire)
+    }
+
+    /// Whether the peer of a TCP connection has closed its side, which shows
+    /// as a readable socket with nothing left to read.
+    fn peer_closed(&self) -> bool {
+        let options = RecvOptions {
+            flags: RecvFlags::PEEK,
+            ..Default::default()
+        };
+        self.is_tcp() && matches!(self.inner.recv(&mut &mut [0u8; 1][..], options), Ok(0))
+    }
+
+    /// Sends data, failing with `EPIPE` once the socket is shut down for
+    /// writing.
+    ///
+    /// As on Linux, `EPIPE` comes with a `SIGPIPE` unless `nosignal`
+    /// (`MSG_NOSIGNAL`) is set.
+    pub fn send_checked(
+        &self,
+        src: &mut impl Buf,
+        options: SendOptions,
+        nosignal: bool,
+    ) -> AxResult<usize> {
+        let result = if self.shut_write() {
+            Err(AxError::BrokenPipe)
+        } else {
+            self.inner.send(src, options)
+        };
+        if matches!(result, Err(AxError::BrokenPipe)) && !nosignal {
+            raise_pipe();
+        }
+        result
+    }
+
+    pub fn filter(&self) -> &SocketFilter {
+        &self.filter
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.inner, axnet::Socket::Tcp(_))
+    }
+
+    pub fn busy_poll(&self) -> Duration {
+        Duration::from_micros(self.busy_poll.load(Ordering::Acquire) as _)
+    }
+
+    pub fn set_busy_poll(&self, usecs: u32) {
+        self.busy_poll.store(usecs, Ordering::Release);
+    }
+
+    pub fn fastopen_qlen(&self) -> u32 {
+        self.fastopen_qlen.load(Ordering::Acquire)
+    }
 
-use super::{FileLike, Kstat};
-use crate::file::{SealedBuf, SealedBufMut, get_file_like};
+    pub fn set_fastopen_qlen(&self, qlen: u32) {
+        self.fastopen_qlen.store(qlen, Ordering::Release);
+    }
+
+    pub fn fastopen_connect(&self) -> bool {
+        self.fastopen_connect.load(Ordering::Acquire)
+    }
 
-pub struct Socket(pub axnet::Socket);
+    pub fn set_fastopen_connect(&self, enabled: bool) {
+        self.fastopen_connect.store(enabled, Ordering::Release);
+    }
+
+    /// Connects to `addr`, already converted by [`Socket::addr_from_user`].
+    ///
+    /// A non-blocking connect that can't complete at once fails with
+    /// `EINPROGRESS`, its outcome reported through poll (OUT) and `SO_ERROR`.
+    pub fn start_connect(&self, addr: SocketAddrEx) -> AxResult<()> {
+        self.inner.connect(addr).map_err(|e| {
+            if e == AxError::WouldBlock {
+                self.set_connecting(true);
+                AxError::InProgress
+            } else {
+                e
+            }
+        })
+    }
+
+    /// Receives data, running the attached filter over each datagram.
+    ///
+    /// Datagrams the filter rejects are dropped and the next one is waited
+    /// for; accepted ones are cut to the length the filter returned. Filters
+    /// don't apply to TCP, where dropping data would stall the connection.
+    pub fn recv_filtered(
+        &self,
+        dst: &mut impl BufMut,
+        mut options: RecvOptions,
+    ) -> AxResult<usize> {
+        if self.shut_read() && !self.inner.poll().contains(IoEvents::IN) {
+            return Ok(0);
+        }
+        let filter = match self.filter.get() {
+            Some(filter) if !matches!(self.inner, axnet::Socket::Tcp(_)) => filter,
+            _ => return self.inner.recv(dst, options),
+        };
+
+        let mut buf = vec![0; MAX_FILTERED_LEN];
+        loop {
+            let len = self.inner.recv(
+                &mut &mut buf[..],
+                RecvOptions {
+                    from: options.from.as_deref_mut(),
+                    flags: options.flags,
+                    cmsg: options.cmsg.as_deref_mut(),
+                },
+            )?;
+            let len = len.min(buf.len());
+            let keep = filter.run(&buf[..len]).min(len);
+            if keep > 0 {
+                let mut data = &buf[..keep];
+                let copied = dst.fill(|chunk| {
+                    let n = chunk.len().min(data.len());
+                    chunk[..n].copy_from_slice(&data[..n]);
+                    data = &data[n..];
+                    Ok(n)
+                })?;
+                return Ok(if options.flags.contains(RecvFlags::TRUNCATE) {
+                    keep
+                } else {
+                    copied
+                });
+            }
+
+            if let Some(cmsg) = options.cmsg.as_deref_mut() {
+                cmsg.clear();
+            }
+            if options.flags.contains(RecvFlags::PEEK) {
+                // Peeking leaves the datagram queued, so take it out.
+                self.inner.recv(&mut &mut buf[..], RecvOptions::default())?;
+            }
+        }
+    }
+
+    /// Converts an address supplied by userspace into the form understood by
+    /// the network stack.
+    ///
+    /// On `AF_INET6` sockets, IPv4-mapped addresses (`::ffff:a.b.c.d`) are
+    /// unwrapped into plain IPv4 addresses so that dual-stack sockets can talk
+    /// to IPv4 peers.
+    pub fn addr_from_user(&self, addr: SocketAddrEx) -> AxResult<SocketAddrEx> {
+        let SocketAddrEx::Ip(ip) = addr else {
+            return Ok(addr);
+        };
+        match (self.domain, ip) {
+            (AF_INET, SocketAddr::V4(_)) => Ok(addr),
+            (AF_INET6, SocketAddr::V6(v6)) => match v6.ip().to_ipv4_mapped() {
+                Some(_) if self.v6only() => Err(AxError::Other(LinuxError::ENETUNREACH)),
+                Some(v4) => Ok(SocketAddrEx::Ip((v4, v6.port()).into())),
+                None => Ok(addr),
+            },
+            _ => Err(AxError::Other(LinuxError::EAFNOSUPPORT)),
+        }
+    }
+
+    /// Converts an address reported by the network stack into the form
+    /// expected by userspace, the reverse of [`Socket::addr_from_user`].
+    pub fn addr_to_user(&self, addr: SocketAddrEx) -> SocketAddrEx {
+        match addr {
+            SocketAddrEx::Ip(SocketAddr::V4(v4)) if self.domain == AF_INET6 => {
+                SocketAddrEx::Ip((v4.ip().to_ipv6_mapped(), v4.port()).into())
+            }
+            addr => addr,
+        }
+    }
+}
 
 impl Deref for Socket {
     type Target = axnet::Socket;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inner
     }
 }
 
 impl FileLike for Socket {
     fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
-        self.recv(dst, axnet::RecvOptions::default())
+        self.recv_filtered(dst, RecvOptions::default())
     }
 
     fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
-        self.send(src, axnet::SendOptions::default())
+        self.send_checked(src, SendOptions::default(), false)
     }
 
     fn stat(&self) -> AxResult<Kstat> {
@@ -44,6 +330,10 @@ impl FileLike for Socket {
         self
     }
 
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        interface_ioctl(cmd, arg)
+    }
+
     fn nonblocking(&self) -> bool {
         let mut result = false;
         self.get_option(GetSocketOption::NonBlocking(&mut result))
@@ -52,7 +342,7 @@ impl FileLike for Socket {
     }
 
     fn set_nonblocking(&self, nonblocking: bool) -> AxResult<()> {
-        self.0
+        self.inner
             .set_option(SetSocketOption::NonBlocking(&nonblocking))
     }
 
@@ -72,10 +362,166 @@ impl FileLike for Socket {
 }
 impl Pollable for Socket {
     fn poll(&self) -> IoEvents {
-        self.0.poll()
+        let mut events = self.inner.poll();
+        if self.shut_read() || (events.contains(IoEvents::IN) && self.peer_closed()) {
+            events |= IoEvents::IN | IoEvents::RDHUP;
+        }
+        if self.shut_read() && self.shut_write() {
+            events |= IoEvents::HUP;
+        }
+        events
     }
 
     fn register(&self, context: &mut Context<'_>, events: IoEvents) {
-        self.0.register(context, events);
+        self.inner.register(context, events);
+    }
+}
+
+fn write_ipv4(addr: &mut sockaddr, ip: Ipv4Addr) {
+    let addr_in = sockaddr_in {
+        sin_family: AF_INET as _,
+        sin_port: 0,
+        sin_addr: in_addr {
+            s_addr: u32::from_ne_bytes(ip.octets()),
+        },
+        __pad: [0; 8],
+    };
+    // SAFETY: `sockaddr_in` is the `AF_INET` form of `sockaddr`.
+    *addr = unsafe { core::mem::transmute::<sockaddr_in, sockaddr>(addr_in) };
+}
+
+fn read_ipv4(addr: &sockaddr) -> AxResult<Ipv4Addr> {
+    // SAFETY: `sockaddr_in` is the `AF_INET` form of `sockaddr`.
+    let addr_in = unsafe { core::mem::transmute::<sockaddr, sockaddr_in>(*addr) };
+    if addr_in.sin_family as u32 != AF_INET {
+        return Err(AxError::Other(LinuxError::EAFNOSUPPORT));
+    }
+    Ok(Ipv4Addr::from_bits(u32::from_be(addr_in.sin_addr.s_addr)))
+}
+
+/// Handles the interface ioctls, which work on any socket.
+pub(super) fn interface_ioctl(cmd: u32, arg: usize) -> AxResult<usize> {
+    match cmd {
+        SIOCGIFCONF => ifconf_ioctl(arg as *mut ifconf),
+        SIOCGIFFLAGS | SIOCSIFFLAGS | SIOCGIFADDR | SIOCSIFADDR | SIOCGIFNETMASK
+        | SIOCSIFNETMASK | SIOCGIFHWADDR | SIOCGIFMTU | SIOCGIFINDEX => {
+            ifreq_ioctl(cmd, arg as *mut ifreq)
+        }
+        _ => Err(AxError::BadIoctl),
+    }
+}
+
+fn iface_flags(iface: &NetInterface) -> u32 {
+    let mut flags = 0;
+    if iface.is_up() {
+        flags |= IFF_UP | IFF_RUNNING;
+    }
+    if iface.is_loopback() {
+        flags |= IFF_LOOPBACK;
+    }
+    if iface.is_promiscuous() {
+        flags |= IFF_PROMISC;
+    }
+    flags
+}
+
+/// Handles `SIOCGIFCONF`, listing the IPv4 address of every interface.
+fn ifconf_ioctl(ptr: *mut ifconf) -> AxResult<usize> {
+    let mut conf = unsafe { ptr.vm_read_uninit()?.assume_init() };
+    let ifaces = interfaces()
+        .into_iter()
+        .filter_map(|iface| iface.ipv4().map(|(ip, _)| (iface, ip)));
+
+    // A null buffer asks for the required length only.
+    let buf = unsafe { conf.ifc_ifcu.ifcu_req };
+    if buf.is_null() {
+        conf.ifc_len = (ifaces.count() * size_of::<ifreq>()) as _;
+        ptr.vm_write(conf)?;
+        return Ok(0);
+    }
+
+    let capacity = conf.ifc_len.max(0) as usize / size_of::<ifreq>();
+    let mut reqs = Vec::new();
+    for (iface, ip) in ifaces.take(capacity) {
+        let mut req: ifreq = unsafe { core::mem::zeroed() };
+        let name = iface.name().as_bytes();
+        let len = name.len().min(unsafe { req.ifr_ifrn.ifrn_name.len() } - 1);
+        for (dst, src) in unsafe { req.ifr_ifrn.ifrn_name.iter_mut() }.zip(&name[..len]) {
+            *dst = *src as _;
+        }
+        write_ipv4(unsafe { &mut req.ifr_ifru.ifru_addr }, ip);
+        reqs.push(req);
+    }
+    vm_write_slice(buf, &reqs)?;
+    conf.ifc_len = (reqs.len() * size_of::<ifreq>()) as _;
+    ptr.vm_write(conf)?;
+    Ok(0)
+}
+
+/// Handles the `SIOC[GS]IF*` family operating on a single named interface.
+fn ifreq_ioctl(cmd: u32, ptr: *mut ifreq) -> AxResult<usize> {
+    let mut req = unsafe { ptr.vm_read_uninit()?.assume_init() };
+    let name = unsafe { &req.ifr_ifrn.ifrn_name };
+    let name = CStr::from_bytes_until_nul(unsafe {
+        core::slice::from_raw_parts(name.as_ptr() as *const u8, name.len())
+    })
+    .map_err(|_| AxError::InvalidInput)?
+    .to_str()
+    .map_err(|_| AxError::InvalidInput)?;
+    debug!("socket ioctl <= cmd: {:#x}, ifname: {}", cmd, name);
+
+    let iface = interface_by_name(name).ok_or(AxError::Other(LinuxError::ENODEV))?;
+    let ifru = &mut req.ifr_ifru;
+    unsafe {
+        match cmd {
+            SIOCGIFFLAGS => ifru.ifru_flags = iface_flags(&iface) as _,
+            SIOCSIFFLAGS => iface.set_up(ifru.ifru_flags as u32 & IFF_UP != 0)?,
+            SIOCGIFADDR => {
+                let (ip, _) = iface
+                    .ipv4()
+                    .ok_or(AxError::Other(LinuxError::EADDRNOTAVAIL))?;
+                write_ipv4(&mut ifru.ifru_addr, ip);
+            }
+            SIOCSIFADDR => {
+                let prefix = iface.ipv4().map_or(24, |(_, prefix)| prefix);
+                iface.set_ipv4(read_ipv4(&ifru.ifru_addr)?, prefix)?;
+            }
+            SIOCGIFNETMASK => {
+                let (_, prefix) = iface
+                    .ipv4()
+                    .ok_or(AxError::Other(LinuxError::EADDRNOTAVAIL))?;
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                write_ipv4(&mut ifru.ifru_netmask, Ipv4Addr::from_bits(mask));
+            }
+            SIOCSIFNETMASK => {
+                let mask = read_ipv4(&ifru.ifru_netmask)?.to_bits();
+                if mask.leading_ones() + mask.trailing_zeros() != 32 {
+                    return Err(AxError::InvalidInput);
+                }
+                let (ip, _) = iface
+                    .ipv4()
+                    .ok_or(AxError::Other(LinuxError::EADDRNOTAVAIL))?;
+                iface.set_ipv4(ip, mask.leading_ones() as u8)?;
+            }
+            SIOCGIFHWADDR => {
+                let hwaddr = &mut ifru.ifru_hwaddr;
+                hwaddr.sa_family = if iface.is_loopback() {
+                    ARPHRD_LOOPBACK
+                } else {
+                    ARPHRD_ETHER
+                };
+                hwaddr.sa_data = [0; 14];
+                for (dst, src) in hwaddr.sa_data.iter_mut().zip(iface.mac()) {
+                    *dst = src as _;
+                }
+            }
+            SIOCGIFMTU => ifru.ifru_mtu = iface.mtu() as _,
+            SIOCGIFINDEX => ifru.ifru_ivalue = iface.index() as _,
+            _ => unreachable!(),
+        }
+    }
+    if !matches!(cmd, SIOCSIFFLAGS | SIOCSIFADDR | SIOCSIFNETMASK) {
+        ptr.vm_write(req)?;
     }
+    Ok(0)
 }
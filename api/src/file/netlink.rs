@@ -0,0 +1,539 @@
+//! `AF_NETLINK` sockets of the `NETLINK_KOBJECT_UEVENT` family, which
+//! announce devices coming and going to userspace, as udev and mdev listen
+//! for.
+//!
+//! The kernel sends its uevents to multicast group 1, as
+//! `ACTION@DEVPATH` followed by `KEY=VALUE` pairs, each NUL-terminated.
+//! Privileged processes may multicast messages of their own to other groups,
+//! which is how udevd passes processed events on to libudev monitors.
+//!
+//! `NETLINK_ROUTE` sockets are taken too, as libc opens one to hear from
+//! `mq_notify`, but the kernel answers their requests with `EOPNOTSUPP`.
+
+use alloc::{
+    borrow::Cow,
+    collections::VecDeque,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    ffi::c_int,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axio::{Buf, BufMut, Read, Write};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::{current, future::Poller};
+use bytemuck::{AnyBitPattern, NoUninit};
+use linux_raw_sys::{
+    general::S_IFSOCK,
+    net::{
+        AF_NETLINK, SO_PASSCRED, SO_RCVBUF, SO_RCVBUFFORCE, SO_SNDBUF, SO_SNDBUFFORCE, SOL_SOCKET,
+        sockaddr, socklen_t, ucred,
+    },
+};
+use spin::{Mutex, RwLock};
+use starry_core::task::AsThread;
+use starry_vm::{VmMutPtr, VmPtr};
+
+use super::{FileLike, Kstat, SealedBuf, SealedBufMut, get_file_like};
+use crate::{filter::SocketFilter, socket::fill_addr};
+
+/// The netlink protocol of routing and link requests.
+pub const NETLINK_ROUTE: u32 = 0;
+/// The netlink protocol of uevents.
+pub const NETLINK_KOBJECT_UEVENT: u32 = 15;
+/// The socket option level of netlink sockets.
+const SOL_NETLINK: u32 = 270;
+const NETLINK_ADD_MEMBERSHIP: u32 = 1;
+const NETLINK_DROP_MEMBERSHIP: u32 = 2;
+
+/// The type of error and acknowledgement messages.
+const NLMSG_ERROR: u16 = 2;
+/// The flag of messages that are requests to the kernel.
+const NLM_F_REQUEST: u16 = 1;
+/// The flag of errors carrying only the header of the request.
+const NLM_F_CAPPED: u16 = 0x100;
+
+/// The multicast group of kernel uevents.
+const UEVENT_GROUP: u32 = 1;
+/// The longest message a socket takes.
+const MAX_MESSAGE: usize = 8192;
+/// How many messages a socket queues before dropping new ones.
+const MAX_QUEUED: usize = 256;
+
+/// `struct sockaddr_nl`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, AnyBitPattern, NoUninit)]
+pub struct SockAddrNl {
+    pub nl_family: u16,
+    pub nl_pad: u16,
+    /// The port ID, 0 for the kernel.
+    pub nl_pid: u32,
+    /// The multicast groups, as a bit mask.
+    pub nl_groups: u32,
+}
+
+/// `struct nlmsghdr`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern, NoUninit)]
+struct NlMsgHdr {
+    len: u32,
+    ty: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+impl SockAddrNl {
+    fn new(pid: u32, groups: u32) -> Self {
+        Self {
+            nl_family: AF_NETLINK as _,
+            nl_pad: 0,
+            nl_pid: pid,
+            nl_groups: groups,
+        }
+    }
+
+    /// Reads an address passed to `bind` or `sendto`.
+    pub fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self> {
+        if (addrlen as usize) < size_of::<Self>() {
+            return Err(AxError::InvalidInput);
+        }
+        let addr = addr.cast::<Self>().vm_read()?;
+        if addr.nl_family as u32 != AF_NETLINK {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(addr)
+    }
+
+    /// Writes the address to `addr`, as `getsockname` and `recvfrom` do.
+    pub fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult {
+        fill_addr(addr, addrlen, bytemuck::bytes_of(self))
+    }
+}
+
+#[derive(Clone)]
+struct Message {
+    from: SockAddrNl,
+    /// The credentials of the sender, all 0 for the kernel.
+    cred: ucred,
+    data: Arc<[u8]>,
+}
+
+/// A netlink socket.
+pub struct NetlinkSocket {
+    protocol: u32,
+    /// The port ID, 0 until the socket is bound.
+    port_id: AtomicU32,
+    groups: AtomicU32,
+    queue: Mutex<VecDeque<Message>>,
+    filter: SocketFilter,
+    pass_cred: AtomicBool,
+    non_blocking: AtomicBool,
+    poll_rx: PollSet,
+}
+
+static SOCKETS: RwLock<Vec<Weak<NetlinkSocket>>> = RwLock::new(Vec::new());
+/// The port IDs handed out once the process ID is taken.
+static NEXT_PORT_ID: AtomicU32 = AtomicU32::new(0x8000_0000);
+static UEVENT_SEQNUM: AtomicU64 = AtomicU64::new(0);
+
+/// The credentials of messages from the kernel.
+const KERNEL_CRED: ucred = ucred {
+    pid: 0,
+    uid: 0,
+    gid: 0,
+};
+
+fn sockets() -> Vec<Arc<NetlinkSocket>> {
+    SOCKETS.read().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Sends `data` to every socket of `protocol` in one of `groups` but
+/// `except`.
+fn multicast(message: Message, protocol: u32, groups: u32, except: Option<&NetlinkSocket>) {
+    for socket in sockets() {
+        if socket.protocol == protocol
+            && socket.groups.load(Ordering::Acquire) & groups != 0
+            && except.is_none_or(|it| !core::ptr::eq(it, &*socket))
+        {
+            socket.receive(message.clone());
+        }
+    }
+}
+
+/// Announces a device event, e.g. `add` or `remove`, on the device at
+/// `devpath` in sysfs, with `env` as the properties besides `ACTION`,
+/// `DEVPATH` and `SEQNUM`.
+pub fn send_uevent(action: &str, devpath: &str, env: &[(&str, &str)]) {
+    let seqnum = UEVENT_SEQNUM.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut data = format!("{action}@{devpath}\0ACTION={action}\0DEVPATH={devpath}\0");
+    for (key, value) in env {
+        data += &format!("{key}={value}\0");
+    }
+    data += &format!("SEQNUM={seqnum}\0");
+    debug!("uevent: {action} {devpath}");
+    let message = Message {
+        from: SockAddrNl::new(0, UEVENT_GROUP),
+        cred: KERNEL_CRED,
+        data: String::into_bytes(data).into(),
+    };
+    multicast(
+        message,
+        NETLINK_KOBJECT_UEVENT,
+        1 << (UEVENT_GROUP - 1),
+        None,
+    );
+}
+
+impl NetlinkSocket {
+    pub fn new(protocol: u32) -> AxResult<Arc<Self>> {
+        if protocol != NETLINK_KOBJECT_UEVENT && protocol != NETLINK_ROUTE {
+            return Err(AxError::Other(LinuxError::EPROTONOSUPPORT));
+        }
+        let socket = Arc::new(Self {
+            protocol,
+            port_id: AtomicU32::new(0),
+            groups: AtomicU32::new(0),
+            queue: Mutex::new(VecDeque::new()),
+            filter: SocketFilter::default(),
+            pass_cred: AtomicBool::new(false),
+            non_blocking: AtomicBool::new(false),
+            poll_rx: PollSet::new(),
+        });
+        SOCKETS.write().push(Arc::downgrade(&socket));
+        Ok(socket)
+    }
+
+    /// Sends `data` to the socket from the kernel.
+    pub fn deliver(&self, data: &[u8]) {
+        self.receive(Message {
+            from: SockAddrNl::new(0, 0),
+            cred: KERNEL_CRED,
+            data: data.into(),
+        });
+    }
+
+    /// Answers the requests in `data`, sent to the kernel on a route socket,
+    /// with `EOPNOTSUPP`.
+    fn reject_requests(&self, data: &[u8]) {
+        let mut rest = data;
+        while let Some(header) = rest.get(..size_of::<NlMsgHdr>()) {
+            let header: NlMsgHdr = bytemuck::pod_read_unaligned(header);
+            let len = (header.len as usize).max(size_of::<NlMsgHdr>());
+            if header.flags & NLM_F_REQUEST != 0 {
+                let reply = NlMsgHdr {
+                    len: (size_of::<NlMsgHdr>() * 2 + size_of::<i32>()) as _,
+                    ty: NLMSG_ERROR,
+                    flags: NLM_F_CAPPED,
+                    seq: header.seq,
+                    pid: self.port_id.load(Ordering::Acquire),
+                };
+                let mut data = Vec::with_capacity(reply.len as usize);
+                data.extend_from_slice(bytemuck::bytes_of(&reply));
+                data.extend_from_slice(&(-LinuxError::EOPNOTSUPP.code()).to_ne_bytes());
+                data.extend_from_slice(bytemuck::bytes_of(&header));
+                self.deliver(&data);
+            }
+            // Messages are aligned to 4 bytes.
+            rest = rest.get(len.next_multiple_of(4)..).unwrap_or_default();
+        }
+    }
+
+    fn receive(&self, mut message: Message) {
+        let len = message.data.len();
+        let keep = match self.filter.get() {
+            Some(filter) => filter.run(&message.data).min(len),
+            None => len,
+        };
+        if keep == 0 {
+            return;
+        }
+        if keep < len {
+            message.data = message.data[..keep].into();
+        }
+        let mut queue = self.queue.lock();
+        if queue.len() >= MAX_QUEUED {
+            return;
+        }
+        queue.push_back(message);
+        drop(queue);
+        self.poll_rx.wake();
+    }
+
+    /// Gives the socket a port ID if it doesn't have one: the process ID if
+    /// no other socket uses it, like Linux.
+    fn autobind(&self) -> u32 {
+        let port_id = self.port_id.load(Ordering::Acquire);
+        if port_id != 0 {
+            return port_id;
+        }
+        let pid = current().as_thread().proc_data.proc.pid();
+        let taken = sockets()
+            .iter()
+            .any(|it| it.port_id.load(Ordering::Acquire) == pid);
+        let port_id = if taken {
+            NEXT_PORT_ID.fetch_add(1, Ordering::Relaxed)
+        } else {
+            pid
+        };
+        match self
+            .port_id
+            .compare_exchange(0, port_id, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => port_id,
+            Err(existing) => existing,
+        }
+    }
+
+    /// Binds the socket to the port ID and multicast groups in `addr`.
+    pub fn bind(&self, addr: SockAddrNl) -> AxResult {
+        if addr.nl_pid != 0 {
+            let taken = sockets().iter().any(|it| {
+                !core::ptr::eq(&**it, self) && it.port_id.load(Ordering::Acquire) == addr.nl_pid
+            });
+            if taken {
+                return Err(AxError::AddrInUse);
+            }
+            self.port_id
+                .compare_exchange(0, addr.nl_pid, Ordering::AcqRel, Ordering::Acquire)
+                .map_err(|_| AxError::InvalidInput)?;
+        } else {
+            self.autobind();
+        }
+        self.groups.store(addr.nl_groups, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the address of the socket, as reported by `getsockname`.
+    pub fn local_addr(&self) -> SockAddrNl {
+        SockAddrNl::new(
+            self.port_id.load(Ordering::Acquire),
+            self.groups.load(Ordering::Acquire),
+        )
+    }
+
+    /// Sends a message to the port or the multicast groups in `addr`.
+    /// Messages to the kernel are dropped, as it takes no uevents, or
+    /// rejected on route sockets.
+    pub fn send(&self, src: &mut impl Buf, addr: Option<SockAddrNl>) -> AxResult<usize> {
+        let mut data = Vec::new();
+        let mut buf = [0; 512];
+        loop {
+            let read = src.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..read]);
+            if data.len() > MAX_MESSAGE {
+                return Err(AxError::Other(LinuxError::EMSGSIZE));
+            }
+        }
+        let len = data.len();
+        let addr = addr.unwrap_or_default();
+        if addr.nl_pid == 0 && addr.nl_groups == 0 {
+            if self.protocol == NETLINK_ROUTE {
+                self.autobind();
+                self.reject_requests(&data);
+            }
+            return Ok(len);
+        }
+        let thr = current();
+        let proc_data = &thr.as_thread().proc_data;
+        let cred = proc_data.cred.read().clone();
+        let mut message = Message {
+            from: SockAddrNl::new(self.autobind(), 0),
+            cred: ucred {
+                pid: proc_data.proc.pid() as _,
+                uid: cred.euid,
+                gid: cred.egid,
+            },
+            data: data.into(),
+        };
+        if addr.nl_groups != 0 {
+            if !cred.is_privileged() {
+                return Err(AxError::OperationNotPermitted);
+            }
+            message.from.nl_groups = addr.nl_groups;
+            multicast(message, self.protocol, addr.nl_groups, Some(self));
+        } else if addr.nl_pid != 0 {
+            let target = sockets()
+                .into_iter()
+                .find(|it| {
+                    it.protocol == self.protocol
+                        && it.port_id.load(Ordering::Acquire) == addr.nl_pid
+                })
+                .ok_or(AxError::Other(LinuxError::ECONNREFUSED))?;
+            target.receive(message);
+        }
+        Ok(len)
+    }
+
+    /// Receives a message, returning its length, where it came from and the
+    /// credentials of the sender.
+    pub fn recv(
+        &self,
+        dst: &mut impl BufMut,
+        non_blocking: bool,
+        peek: bool,
+        truncate: bool,
+    ) -> AxResult<(usize, SockAddrNl, ucred)> {
+        Poller::new(self, IoEvents::IN)
+            .non_blocking(non_blocking || self.nonblocking())
+            .poll(|| {
+                let mut queue = self.queue.lock();
+                let message = if peek {
+                    queue.front().cloned()
+                } else {
+                    queue.pop_front()
+                }
+                .ok_or(AxError::WouldBlock)?;
+                drop(queue);
+                let len = message.data.len().min(dst.remaining_mut());
+                dst.write(&message.data[..len])?;
+                Ok((
+                    if truncate { message.data.len() } else { len },
+                    message.from,
+                    message.cred,
+                ))
+            })
+    }
+
+    /// Returns whether credentials are passed along with messages
+    /// (`SO_PASSCRED`).
+    pub fn pass_cred(&self) -> bool {
+        self.pass_cred.load(Ordering::Acquire)
+    }
+
+    /// Handles `setsockopt` of the netlink options, the socket filter and
+    /// the generic socket options that matter to netlink sockets.
+    pub fn set_option(
+        &self,
+        level: u32,
+        optname: u32,
+        optval: *const u8,
+        optlen: socklen_t,
+    ) -> AxResult {
+        if level == SOL_SOCKET && self.filter.set_option(optname, optval, optlen)? {
+            return Ok(());
+        }
+        if (optlen as usize) < size_of::<c_int>() {
+            return Err(AxError::InvalidInput);
+        }
+        let value = optval.cast::<c_int>().vm_read()?;
+        match (level, optname) {
+            (SOL_SOCKET, SO_PASSCRED) => self.pass_cred.store(value != 0, Ordering::Release),
+            // The queue is bounded by message count rather than bytes.
+            (SOL_SOCKET, SO_RCVBUF | SO_RCVBUFFORCE | SO_SNDBUF | SO_SNDBUFFORCE) => {}
+            (SOL_NETLINK, NETLINK_ADD_MEMBERSHIP | NETLINK_DROP_MEMBERSHIP) => {
+                if !(1..=32).contains(&value) {
+                    return Err(AxError::InvalidInput);
+                }
+                let bit = 1 << (value - 1);
+                if optname == NETLINK_ADD_MEMBERSHIP {
+                    self.groups.fetch_or(bit, Ordering::AcqRel);
+                } else {
+                    self.groups.fetch_and(!bit, Ordering::AcqRel);
+                }
+            }
+            _ => return Err(AxError::Other(LinuxError::ENOPROTOOPT)),
+        }
+        Ok(())
+    }
+
+    /// Handles `getsockopt` of the socket filter and `SO_PASSCRED`.
+    pub fn get_option(
+        &self,
+        level: u32,
+        optname: u32,
+        optval: *mut u8,
+        optlen: &mut socklen_t,
+    ) -> AxResult {
+        if level == SOL_SOCKET && self.filter.get_option(optname, optval, optlen)? {
+            return Ok(());
+        }
+        let value: c_int = match (level, optname) {
+            (SOL_SOCKET, SO_PASSCRED) => self.pass_cred() as _,
+            (SOL_SOCKET, SO_RCVBUF | SO_SNDBUF) => (MAX_QUEUED * MAX_MESSAGE) as _,
+            _ => return Err(AxError::Other(LinuxError::ENOPROTOOPT)),
+        };
+        if (*optlen as usize) < size_of::<c_int>() {
+            return Err(AxError::InvalidInput);
+        }
+        optval.cast::<c_int>().vm_write(value)?;
+        *optlen = size_of::<c_int>() as _;
+        Ok(())
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        SOCKETS
+            .write()
+            .retain(|it| !core::ptr::eq(it.as_ptr(), self));
+    }
+}
+
+impl FileLike for NetlinkSocket {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        self.recv(dst, false, false, false).map(|(len, ..)| len)
+    }
+
+    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+        self.send(src, None)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat {
+            mode: S_IFSOCK | 0o777u32,
+            ..Default::default()
+        })
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        format!("socket:[{}]", self as *const _ as usize).into()
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn from_fd(fd: c_int) -> AxResult<Arc<Self>>
+    where
+        Self: Sized + 'static,
+    {
+        get_file_like(fd)?
+            .into_any()
+            .downcast::<Self>()
+            .map_err(|_| AxError::NotASocket)
+    }
+}
+
+impl Pollable for NetlinkSocket {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::OUT;
+        events.set(IoEvents::IN, !self.queue.lock().is_empty());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
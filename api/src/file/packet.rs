@@ -0,0 +1,528 @@
+//! `AF_PACKET` sockets, which send and receive whole link-layer frames.
+//!
+//! `SOCK_RAW` sockets see frames with their Ethernet header, `SOCK_DGRAM`
+//! ("cooked") sockets without it, the header being described by the
+//! `sockaddr_ll` address instead. Frames reach the sockets from the network
+//! drivers through the frame tap of [`axnet::device`], both the ones
+//! received and, for `ETH_P_ALL` sockets, the ones sent.
+
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    format,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    ffi::c_int,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axio::{Buf, BufMut, Read, Write};
+use axnet::device::{NetInterface, interfaces, set_frame_tap};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::future::Poller;
+use bytemuck::{AnyBitPattern, NoUninit};
+use linux_raw_sys::{
+    general::S_IFSOCK,
+    net::{AF_PACKET, SOL_SOCKET, sockaddr, socklen_t},
+};
+use spin::{Mutex, Once, RwLock};
+use starry_vm::{VmPtr, vm_write_slice};
+
+use super::{
+    FileLike, Kstat, SealedBuf, SealedBufMut, get_file_like,
+    net::{ARPHRD_ETHER, ARPHRD_LOOPBACK, interface_ioctl},
+};
+use crate::{filter::SocketFilter, socket::fill_addr};
+
+/// The socket option level of `AF_PACKET` sockets.
+pub const SOL_PACKET: u32 = 263;
+
+const PACKET_ADD_MEMBERSHIP: u32 = 1;
+const PACKET_DROP_MEMBERSHIP: u32 = 2;
+const PACKET_STATISTICS: u32 = 6;
+
+const PACKET_MR_MULTICAST: u16 = 0;
+const PACKET_MR_PROMISC: u16 = 1;
+const PACKET_MR_ALLMULTI: u16 = 2;
+
+const PACKET_HOST: u8 = 0;
+const PACKET_BROADCAST: u8 = 1;
+const PACKET_MULTICAST: u8 = 2;
+const PACKET_OTHERHOST: u8 = 3;
+const PACKET_OUTGOING: u8 = 4;
+
+/// The protocol receiving every frame.
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_ALEN: usize = 6;
+const ETH_HLEN: usize = 14;
+
+/// How many frames a socket queues before dropping new ones.
+const MAX_QUEUED: usize = 256;
+
+/// `struct sockaddr_ll`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, AnyBitPattern, NoUninit)]
+pub struct SockAddrLl {
+    pub sll_family: u16,
+    /// The EtherType, in network byte order.
+    pub sll_protocol: u16,
+    pub sll_ifindex: i32,
+    pub sll_hatype: u16,
+    pub sll_pkttype: u8,
+    pub sll_halen: u8,
+    pub sll_addr: [u8; 8],
+}
+
+impl SockAddrLl {
+    /// Reads an address passed to `bind` or `sendto`.
+    pub fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self> {
+        if (addrlen as usize) < size_of::<Self>() {
+            return Err(AxError::InvalidInput);
+        }
+        let addr = addr.cast::<Self>().vm_read()?;
+        if addr.sll_family as u32 != AF_PACKET {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(addr)
+    }
+
+    /// Writes the address to `addr`, as `getsockname` and `recvfrom` do.
+    pub fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult {
+        fill_addr(addr, addrlen, bytemuck::bytes_of(self))
+    }
+}
+
+/// `struct packet_mreq`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+struct PacketMreq {
+    mr_ifindex: i32,
+    mr_type: u16,
+    mr_alen: u16,
+    mr_address: [u8; 8],
+}
+
+/// `struct tpacket_stats`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct TpacketStats {
+    tp_packets: u32,
+    tp_drops: u32,
+}
+
+#[derive(Clone)]
+struct Frame {
+    addr: SockAddrLl,
+    data: Vec<u8>,
+}
+
+fn interface_by_index(index: u32) -> AxResult<NetInterface> {
+    interfaces()
+        .into_iter()
+        .find(|iface| iface.index() == index)
+        .ok_or(AxError::Other(LinuxError::ENODEV))
+}
+
+fn hatype(iface: &NetInterface) -> u16 {
+    if iface.is_loopback() {
+        ARPHRD_LOOPBACK
+    } else {
+        ARPHRD_ETHER
+    }
+}
+
+static SOCKETS: RwLock<Vec<Weak<PacketSocket>>> = RwLock::new(Vec::new());
+static TAP: Once = Once::new();
+
+/// How many memberships ask for each interface to be promiscuous.
+static PROMISC: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+fn set_promisc(ifindex: u32, on: bool) -> AxResult {
+    let mut promisc = PROMISC.lock();
+    let count = promisc.entry(ifindex).or_default();
+    match (on, *count) {
+        (true, 0) => interface_by_index(ifindex)?.set_promiscuous(true)?,
+        (false, 1) => interface_by_index(ifindex)?.set_promiscuous(false)?,
+        (false, 0) => return Err(AxError::InvalidInput),
+        _ => {}
+    }
+    if on {
+        *count += 1;
+    } else {
+        *count -= 1;
+    }
+    Ok(())
+}
+
+/// Hands a frame received or sent on `iface` to the packet sockets.
+fn deliver(iface: &NetInterface, frame: &[u8], outgoing: bool) {
+    if frame.len() < ETH_HLEN {
+        return;
+    }
+    let dst = &frame[..ETH_ALEN];
+    let pkttype = if outgoing {
+        PACKET_OUTGOING
+    } else if dst == [0xff; ETH_ALEN] {
+        PACKET_BROADCAST
+    } else if dst[0] & 1 != 0 {
+        PACKET_MULTICAST
+    } else if dst == iface.mac() {
+        PACKET_HOST
+    } else {
+        PACKET_OTHERHOST
+    };
+    // For outgoing frames, the address is still the one of the sender.
+    let mut addr = SockAddrLl {
+        sll_family: AF_PACKET as _,
+        sll_protocol: u16::from_ne_bytes([frame[12], frame[13]]),
+        sll_ifindex: iface.index() as _,
+        sll_hatype: hatype(iface),
+        sll_pkttype: pkttype,
+        sll_halen: ETH_ALEN as _,
+        sll_addr: [0; 8],
+    };
+    addr.sll_addr[..ETH_ALEN].copy_from_slice(&frame[ETH_ALEN..2 * ETH_ALEN]);
+
+    // Don't keep the list locked while the sockets are used, as dropping
+    // the last reference to one removes it from the list.
+    let sockets: Vec<_> = SOCKETS.read().iter().filter_map(Weak::upgrade).collect();
+    for socket in sockets {
+        socket.receive(&addr, frame);
+    }
+}
+
+/// An `AF_PACKET` socket.
+pub struct PacketSocket {
+    /// Whether the link-layer header is stripped (`SOCK_DGRAM`).
+    cooked: bool,
+    /// The EtherType received, in host byte order: [`ETH_P_ALL`] for every
+    /// frame, 0 for none.
+    protocol: AtomicU16,
+    /// The interface bound to, 0 for all of them.
+    ifindex: AtomicU32,
+    queue: Mutex<VecDeque<Frame>>,
+    stats: Mutex<TpacketStats>,
+    filter: SocketFilter,
+    /// The interfaces this socket made promiscuous.
+    promisc: Mutex<Vec<u32>>,
+    non_blocking: AtomicBool,
+    poll_rx: PollSet,
+}
+
+impl PacketSocket {
+    /// Creates a socket receiving `protocol`, given in network byte order
+    /// as passed to `socket`.
+    pub fn new(cooked: bool, protocol: u16) -> Arc<Self> {
+        TAP.call_once(|| set_frame_tap(deliver));
+        let socket = Arc::new(Self {
+            cooked,
+            protocol: AtomicU16::new(u16::from_be(protocol)),
+            ifindex: AtomicU32::new(0),
+            queue: Mutex::new(VecDeque::new()),
+            stats: Mutex::new(TpacketStats::default()),
+            filter: SocketFilter::default(),
+            promisc: Mutex::new(Vec::new()),
+            non_blocking: AtomicBool::new(false),
+            poll_rx: PollSet::new(),
+        });
+        SOCKETS.write().push(Arc::downgrade(&socket));
+        socket
+    }
+
+    fn receive(&self, addr: &SockAddrLl, frame: &[u8]) {
+        let protocol = self.protocol.load(Ordering::Acquire);
+        let ethertype = u16::from_be(addr.sll_protocol);
+        let wanted = match protocol {
+            0 => false,
+            ETH_P_ALL => true,
+            _ => protocol == ethertype && addr.sll_pkttype != PACKET_OUTGOING,
+        };
+        let ifindex = self.ifindex.load(Ordering::Acquire);
+        if !wanted || (ifindex != 0 && ifindex != addr.sll_ifindex as u32) {
+            return;
+        }
+
+        let data = if self.cooked {
+            &frame[ETH_HLEN..]
+        } else {
+            frame
+        };
+        let keep = match self.filter.get() {
+            Some(filter) => filter.run(data).min(data.len()),
+            None => data.len(),
+        };
+        if keep == 0 {
+            return;
+        }
+
+        let mut stats = self.stats.lock();
+        stats.tp_packets += 1;
+        let mut queue = self.queue.lock();
+        if queue.len() >= MAX_QUEUED {
+            stats.tp_drops += 1;
+            return;
+        }
+        queue.push_back(Frame {
+            addr: *addr,
+            data: data[..keep].to_vec(),
+        });
+        drop(queue);
+        self.poll_rx.wake();
+    }
+
+    /// Binds the socket to the interface and protocol in `addr`.
+    pub fn bind(&self, addr: SockAddrLl) -> AxResult {
+        if addr.sll_ifindex != 0 {
+            interface_by_index(addr.sll_ifindex as u32)?;
+        }
+        self.ifindex
+            .store(addr.sll_ifindex as u32, Ordering::Release);
+        if addr.sll_protocol != 0 {
+            self.protocol
+                .store(u16::from_be(addr.sll_protocol), Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the socket, as reported by `getsockname`.
+    pub fn local_addr(&self) -> AxResult<SockAddrLl> {
+        let ifindex = self.ifindex.load(Ordering::Acquire);
+        let mut addr = SockAddrLl {
+            sll_family: AF_PACKET as _,
+            sll_protocol: self.protocol.load(Ordering::Acquire).to_be(),
+            sll_ifindex: ifindex as _,
+            ..Default::default()
+        };
+        if ifindex != 0 {
+            let iface = interface_by_index(ifindex)?;
+            addr.sll_hatype = hatype(&iface);
+            addr.sll_halen = ETH_ALEN as _;
+            addr.sll_addr[..ETH_ALEN].copy_from_slice(&iface.mac());
+        }
+        Ok(addr)
+    }
+
+    /// Sends a frame, built from `src` and, for cooked sockets, the
+    /// destination in `addr`.
+    pub fn send(&self, src: &mut impl Buf, addr: Option<SockAddrLl>) -> AxResult<usize> {
+        let ifindex = match addr {
+            Some(addr) if addr.sll_ifindex != 0 => addr.sll_ifindex as u32,
+            _ => self.ifindex.load(Ordering::Acquire),
+        };
+        if ifindex == 0 {
+            return Err(AxError::Other(LinuxError::ENXIO));
+        }
+        let iface = interface_by_index(ifindex)?;
+
+        let mut frame = Vec::new();
+        if self.cooked {
+            let addr = addr.ok_or(AxError::Other(LinuxError::EDESTADDRREQ))?;
+            let protocol = if addr.sll_protocol != 0 {
+                addr.sll_protocol
+            } else {
+                self.protocol.load(Ordering::Acquire).to_be()
+            };
+            frame.extend_from_slice(&addr.sll_addr[..ETH_ALEN]);
+            frame.extend_from_slice(&iface.mac());
+            frame.extend_from_slice(&protocol.to_ne_bytes());
+        }
+        let header = frame.len();
+        let mut buf = [0; 512];
+        loop {
+            let read = src.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            frame.extend_from_slice(&buf[..read]);
+            if frame.len() > iface.mtu() as usize + ETH_HLEN {
+                return Err(AxError::Other(LinuxError::EMSGSIZE));
+            }
+        }
+        if frame.len() < ETH_HLEN {
+            return Err(AxError::InvalidInput);
+        }
+        iface.transmit(&frame)?;
+        Ok(frame.len() - header)
+    }
+
+    /// Receives a frame, returning its length and where it came from.
+    pub fn recv(
+        &self,
+        dst: &mut impl BufMut,
+        non_blocking: bool,
+        peek: bool,
+        truncate: bool,
+    ) -> AxResult<(usize, SockAddrLl)> {
+        Poller::new(self, IoEvents::IN)
+            .non_blocking(non_blocking || self.nonblocking())
+            .poll(|| {
+                let mut queue = self.queue.lock();
+                let frame = if peek {
+                    queue.front().cloned()
+                } else {
+                    queue.pop_front()
+                }
+                .ok_or(AxError::WouldBlock)?;
+                drop(queue);
+                let len = frame.data.len().min(dst.remaining_mut());
+                dst.write(&frame.data[..len])?;
+                Ok((if truncate { frame.data.len() } else { len }, frame.addr))
+            })
+    }
+
+    /// Handles `setsockopt` of the `SOL_PACKET` options and the socket
+    /// filter.
+    pub fn set_option(
+        &self,
+        level: u32,
+        optname: u32,
+        optval: *const u8,
+        optlen: socklen_t,
+    ) -> AxResult {
+        if level == SOL_SOCKET && self.filter.set_option(optname, optval, optlen)? {
+            return Ok(());
+        }
+        if level != SOL_PACKET {
+            return Err(AxError::Other(LinuxError::ENOPROTOOPT));
+        }
+        match optname {
+            PACKET_ADD_MEMBERSHIP | PACKET_DROP_MEMBERSHIP => {
+                if (optlen as usize) < size_of::<PacketMreq>() {
+                    return Err(AxError::InvalidInput);
+                }
+                let mreq = optval.cast::<PacketMreq>().vm_read()?;
+                let ifindex = mreq.mr_ifindex as u32;
+                let add = optname == PACKET_ADD_MEMBERSHIP;
+                match mreq.mr_type {
+                    PACKET_MR_PROMISC => {
+                        let mut promisc = self.promisc.lock();
+                        if add {
+                            set_promisc(ifindex, true)?;
+                            promisc.push(ifindex);
+                        } else {
+                            let index = promisc
+                                .iter()
+                                .position(|&it| it == ifindex)
+                                .ok_or(AxError::InvalidInput)?;
+                            set_promisc(ifindex, false)?;
+                            promisc.swap_remove(index);
+                        }
+                    }
+                    // Drivers pass every multicast frame up already.
+                    PACKET_MR_MULTICAST | PACKET_MR_ALLMULTI => {
+                        interface_by_index(ifindex)?;
+                    }
+                    _ => return Err(AxError::InvalidInput),
+                }
+            }
+            _ => return Err(AxError::Other(LinuxError::ENOPROTOOPT)),
+        }
+        Ok(())
+    }
+
+    /// Handles `getsockopt` of the `SOL_PACKET` options and the socket
+    /// filter.
+    pub fn get_option(
+        &self,
+        level: u32,
+        optname: u32,
+        optval: *mut u8,
+        optlen: &mut socklen_t,
+    ) -> AxResult {
+        if level == SOL_SOCKET && self.filter.get_option(optname, optval, optlen)? {
+            return Ok(());
+        }
+        match (level, optname) {
+            // Reading the statistics resets them.
+            (SOL_PACKET, PACKET_STATISTICS) => {
+                let len = (*optlen as usize).min(size_of::<TpacketStats>());
+                let stats = core::mem::take(&mut *self.stats.lock());
+                let stats = [stats.tp_packets, stats.tp_drops];
+                vm_write_slice(optval, &bytemuck::cast_slice(&stats)[..len])?;
+                *optlen = len as _;
+                Ok(())
+            }
+            _ => Err(AxError::Other(LinuxError::ENOPROTOOPT)),
+        }
+    }
+}
+
+impl Drop for PacketSocket {
+    fn drop(&mut self) {
+        for ifindex in self.promisc.get_mut().drain(..) {
+            if let Err(err) = set_promisc(ifindex, false) {
+                warn!("AF_PACKET: failed to leave promiscuous mode: {err:?}");
+            }
+        }
+        SOCKETS
+            .write()
+            .retain(|it| !core::ptr::eq(it.as_ptr(), self));
+    }
+}
+
+impl FileLike for PacketSocket {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        self.recv(dst, false, false, false).map(|(len, _)| len)
+    }
+
+    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+        self.send(src, None)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat {
+            mode: S_IFSOCK | 0o777u32,
+            ..Default::default()
+        })
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        format!("socket:[{}]", self as *const _ as usize).into()
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        interface_ioctl(cmd, arg)
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn from_fd(fd: c_int) -> AxResult<Arc<Self>>
+    where
+        Self: Sized + 'static,
+    {
+        get_file_like(fd)?
+            .into_any()
+            .downcast::<Self>()
+            .map_err(|_| AxError::NotASocket)
+    }
+}
+
+impl Pollable for PacketSocket {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::OUT;
+        events.set(IoEvents::IN, !self.queue.lock().is_empty());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
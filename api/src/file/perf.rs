@@ -0,0 +1,552 @@
+//! Performance counters, created by `perf_event_open`.
+//!
+//! Only software events are supported. They are derived from the counters
+//! each thread keeps for its CPU time, context switches and page faults, so
+//! counting costs nothing on the hot paths. Samples are taken when the
+//! thread returns to user space after an interrupt or a page fault, and
+//! written to a ring buffer the owner maps with `mmap`.
+
+use alloc::{
+    borrow::Cow,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicU64, Ordering, fence},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult};
+use axhal::{mem::phys_to_virt, paging::PageSize, time::monotonic_time_nanos};
+use axio::{BufMut, Write};
+use axmm::backend::SharedPages;
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::{AxTaskRef, current};
+use bytemuck::AnyBitPattern;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+use starry_core::task::{AsThread, Thread};
+use starry_vm::{VmMutPtr, VmPtr};
+
+use crate::file::{FileLike, Kstat, SealedBuf, SealedBufMut};
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+const PERF_COUNT_SW_PAGE_FAULTS_MIN: u64 = 5;
+const PERF_COUNT_SW_PAGE_FAULTS_MAJ: u64 = 6;
+/// The last software event, `PERF_COUNT_SW_DUMMY`.
+const PERF_COUNT_SW_MAX: u64 = 9;
+
+const ATTR_DISABLED: u64 = 1 << 0;
+const ATTR_FREQ: u64 = 1 << 10;
+const ATTR_ENABLE_ON_EXEC: u64 = 1 << 12;
+
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+const PERF_FORMAT_ID: u64 = 1 << 2;
+
+const PERF_SAMPLE_IP: u64 = 1 << 0;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_ADDR: u64 = 1 << 3;
+const PERF_SAMPLE_ID: u64 = 1 << 6;
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+/// The sample fields we can provide.
+const SUPPORTED_SAMPLE_TYPE: u64 = PERF_SAMPLE_IP
+    | PERF_SAMPLE_TID
+    | PERF_SAMPLE_TIME
+    | PERF_SAMPLE_ADDR
+    | PERF_SAMPLE_ID
+    | PERF_SAMPLE_CPU
+    | PERF_SAMPLE_PERIOD
+    | PERF_SAMPLE_STREAM_ID
+    | PERF_SAMPLE_IDENTIFIER;
+
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_SAMPLE: u32 = 9;
+const PERF_RECORD_MISC_USER: u16 = 2;
+
+const PERF_EVENT_IOC_ENABLE: u32 = 0x2400;
+const PERF_EVENT_IOC_DISABLE: u32 = 0x2401;
+const PERF_EVENT_IOC_REFRESH: u32 = 0x2402;
+const PERF_EVENT_IOC_RESET: u32 = 0x2403;
+const PERF_EVENT_IOC_PERIOD: u32 = 0x4008_2404;
+const PERF_EVENT_IOC_ID: u32 = 0x8008_2407;
+
+/// Offsets into `struct perf_event_mmap_page`, the first page of the ring
+/// buffer.
+const MMAP_TIME_ENABLED: usize = 24;
+const MMAP_TIME_RUNNING: usize = 32;
+const MMAP_DATA_HEAD: usize = 1024;
+const MMAP_DATA_TAIL: usize = 1032;
+const MMAP_DATA_OFFSET: usize = 1040;
+const MMAP_DATA_SIZE: usize = 1048;
+
+/// The first, fixed-size part of `struct perf_event_attr`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+pub struct PerfEventAttr {
+    pub ty: u32,
+    pub size: u32,
+    pub config: u64,
+    /// `sample_period`, or `sample_freq` with `freq` set.
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+    pub wakeup_events: u32,
+    pub bp_type: u32,
+    pub config1: u64,
+}
+
+/// The mapped ring buffer samples are written to.
+struct RingBuffer {
+    pages: Arc<SharedPages>,
+    data_size: u64,
+    head: u64,
+}
+
+impl RingBuffer {
+    fn new(len: usize) -> AxResult<Self> {
+        if len % PAGE_SIZE_4K != 0 || len < 2 * PAGE_SIZE_4K {
+            return Err(AxError::InvalidInput);
+        }
+        let data_pages = len / PAGE_SIZE_4K - 1;
+        if !data_pages.is_power_of_two() {
+            return Err(AxError::InvalidInput);
+        }
+        let ring = Self {
+            pages: Arc::new(SharedPages::new(len, PageSize::Size4K)?),
+            data_size: (data_pages * PAGE_SIZE_4K) as u64,
+            head: 0,
+        };
+        ring.write_u64(MMAP_DATA_OFFSET, PAGE_SIZE_4K as u64);
+        ring.write_u64(MMAP_DATA_SIZE, ring.data_size);
+        Ok(ring)
+    }
+
+    /// Copies `data` to byte `offset` of the buffer.
+    fn write(&self, mut offset: usize, mut data: &[u8]) {
+        while !data.is_empty() {
+            let page = phys_to_virt(self.pages[offset / PAGE_SIZE_4K]).as_mut_ptr();
+            let in_page = offset % PAGE_SIZE_4K;
+            let len = data.len().min(PAGE_SIZE_4K - in_page);
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), page.add(in_page), len) };
+            data = &data[len..];
+            offset += len;
+        }
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, &value.to_ne_bytes());
+    }
+
+    /// Reads `data_tail`, up to which user space has consumed records.
+    fn tail(&self) -> u64 {
+        let page = phys_to_virt(self.pages[0]).as_ptr();
+        let tail = unsafe { (page.add(MMAP_DATA_TAIL) as *const u64).read_volatile() };
+        fence(Ordering::Acquire);
+        tail
+    }
+
+    /// Appends a record, returning `false` if there is no room for it.
+    fn push(&mut self, record: &[u8]) -> bool {
+        let len = record.len() as u64;
+        if self.head - self.tail() + len > self.data_size {
+            return false;
+        }
+        let mut offset = self.head % self.data_size;
+        let mut record = record;
+        while !record.is_empty() {
+            let len = record.len().min((self.data_size - offset) as usize);
+            self.write(PAGE_SIZE_4K + offset as usize, &record[..len]);
+            record = &record[len..];
+            offset = 0;
+        }
+        self.head += len;
+        fence(Ordering::Release);
+        self.write_u64(MMAP_DATA_HEAD, self.head);
+        true
+    }
+}
+
+struct State {
+    enabled: bool,
+    enable_on_exec: bool,
+    /// The count accumulated up to the last enable.
+    count: u64,
+    /// The raw counter value at the last enable.
+    start: u64,
+    /// The time spent enabled, up to the last enable.
+    time_enabled: u64,
+    enabled_since: u64,
+    /// Every how many events a sample is taken, or 0 to only count.
+    period: u64,
+    next_sample: u64,
+    lost: u64,
+    ring: Option<RingBuffer>,
+}
+
+/// A software event counted for a single thread.
+pub struct PerfEvent {
+    id: u64,
+    config: u64,
+    task: AxTaskRef,
+    read_format: u64,
+    sample_type: u64,
+    state: Mutex<State>,
+    poll: PollSet,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// The events that sample or wait for `exec`, checked on the way back to
+/// user space.
+static WATCHED: Mutex<Vec<Weak<PerfEvent>>> = Mutex::new(Vec::new());
+
+impl PerfEvent {
+    /// Creates an event counting for `task`.
+    pub fn new(attr: &PerfEventAttr, task: AxTaskRef) -> AxResult<Arc<Self>> {
+        match attr.ty {
+            PERF_TYPE_SOFTWARE if attr.config <= PERF_COUNT_SW_MAX => {}
+            // Hardware PMUs are not supported yet; `perf` falls back to
+            // software events on `ENOENT`.
+            _ => return Err(AxError::NotFound),
+        }
+        let read_format_supported =
+            PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING | PERF_FORMAT_ID;
+        if attr.read_format & !read_format_supported != 0
+            || attr.sample_type & !SUPPORTED_SAMPLE_TYPE != 0
+        {
+            return Err(AxError::InvalidInput);
+        }
+
+        let period = if attr.flags & ATTR_FREQ != 0 {
+            // Only the clocks have a natural frequency.
+            match attr.config {
+                PERF_COUNT_SW_CPU_CLOCK | PERF_COUNT_SW_TASK_CLOCK if attr.sample_period > 0 => {
+                    (1_000_000_000 / attr.sample_period).max(1)
+                }
+                _ => return Err(AxError::InvalidInput),
+            }
+        } else {
+            attr.sample_period
+        };
+
+        let event = Arc::new(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            config: attr.config,
+            task,
+            read_format: attr.read_format,
+            sample_type: attr.sample_type,
+            state: Mutex::new(State {
+                enabled: false,
+                enable_on_exec: attr.flags & ATTR_ENABLE_ON_EXEC != 0,
+                count: 0,
+                start: 0,
+                time_enabled: 0,
+                enabled_since: 0,
+                period,
+                next_sample: period,
+                lost: 0,
+                ring: None,
+            }),
+            poll: PollSet::new(),
+        });
+        if attr.flags & ATTR_DISABLED == 0 {
+            event.enable();
+        }
+        if period > 0 || attr.flags & ATTR_ENABLE_ON_EXEC != 0 {
+            let mut watched = WATCHED.lock();
+            watched.retain(|it| it.strong_count() > 0);
+            watched.push(Arc::downgrade(&event));
+        }
+        Ok(event)
+    }
+
+    fn thread(&self) -> &Thread {
+        self.task.as_thread()
+    }
+
+    /// Reads the raw counter of the thread.
+    fn raw(&self) -> u64 {
+        let thr = self.thread();
+        let (minor, major) = thr.page_faults();
+        match self.config {
+            PERF_COUNT_SW_CPU_CLOCK | PERF_COUNT_SW_TASK_CLOCK => thr.cpu_time_ns(),
+            PERF_COUNT_SW_PAGE_FAULTS => minor + major,
+            PERF_COUNT_SW_CONTEXT_SWITCHES => thr.context_switches(),
+            PERF_COUNT_SW_PAGE_FAULTS_MIN => minor,
+            PERF_COUNT_SW_PAGE_FAULTS_MAJ => major,
+            // Migrations, alignment and emulation faults never happen, and
+            // the dummy event counts nothing.
+            _ => 0,
+        }
+    }
+
+    fn value(&self, state: &State) -> u64 {
+        if state.enabled {
+            state.count + (self.raw() - state.start)
+        } else {
+            state.count
+        }
+    }
+
+    fn time_enabled(&self, state: &State) -> u64 {
+        if state.enabled {
+            state.time_enabled + (monotonic_time_nanos() - state.enabled_since)
+        } else {
+            state.time_enabled
+        }
+    }
+
+    /// Starts counting.
+    pub fn enable(&self) {
+        let mut state = self.state.lock();
+        if !state.enabled {
+            state.start = self.raw();
+            state.enabled_since = monotonic_time_nanos();
+            state.enabled = true;
+        }
+    }
+
+    /// Stops counting, keeping the count.
+    pub fn disable(&self) {
+        let mut state = self.state.lock();
+        if state.enabled {
+            state.count = self.value(&state);
+            state.time_enabled = self.time_enabled(&state);
+            state.enabled = false;
+        }
+    }
+
+    /// Resets the count to zero.
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        state.count = 0;
+        state.start = self.raw();
+        state.next_sample = state.period;
+    }
+
+    /// Allocates the ring buffer of `len` bytes, one metadata page followed
+    /// by a power of two data pages, for mapping into user space.
+    pub fn ring_buffer(&self, len: usize) -> AxResult<Arc<SharedPages>> {
+        let mut state = self.state.lock();
+        if let Some(ring) = &state.ring {
+            // Every mapping must share the same buffer.
+            if ring.pages.len() * PAGE_SIZE_4K != len {
+                return Err(AxError::InvalidInput);
+            }
+            return Ok(ring.pages.clone());
+        }
+        let ring = RingBuffer::new(len)?;
+        let pages = ring.pages.clone();
+        state.ring = Some(ring);
+        Ok(pages)
+    }
+
+    /// Writes a sample if the count reached the next sample point.
+    fn sample(&self, ip: usize, addr: Option<usize>) {
+        let mut state = self.state.lock();
+        if !state.enabled || state.period == 0 || state.ring.is_none() {
+            return;
+        }
+        let value = self.value(&state);
+        if value < state.next_sample {
+            return;
+        }
+        // Only one sample is taken however many periods have passed.
+        state.next_sample = value - (value - state.next_sample) % state.period + state.period;
+
+        let thr = self.thread();
+        let mut body = Vec::with_capacity(64);
+        let mut put = |value: u64| body.extend_from_slice(&value.to_ne_bytes());
+        let ty = self.sample_type;
+        if ty & PERF_SAMPLE_IDENTIFIER != 0 {
+            put(self.id);
+        }
+        if ty & PERF_SAMPLE_IP != 0 {
+            put(ip as u64);
+        }
+        if ty & PERF_SAMPLE_TID != 0 {
+            put(thr.proc_data.proc.pid() as u64 | (self.task.id().as_u64() << 32));
+        }
+        if ty & PERF_SAMPLE_TIME != 0 {
+            put(monotonic_time_nanos());
+        }
+        if ty & PERF_SAMPLE_ADDR != 0 {
+            put(addr.unwrap_or(0) as u64);
+        }
+        if ty & PERF_SAMPLE_ID != 0 {
+            put(self.id);
+        }
+        if ty & PERF_SAMPLE_STREAM_ID != 0 {
+            put(self.id);
+        }
+        if ty & PERF_SAMPLE_CPU != 0 {
+            put(0);
+        }
+        if ty & PERF_SAMPLE_PERIOD != 0 {
+            put(state.period);
+        }
+
+        let time_enabled = self.time_enabled(&state);
+        let lost = state.lost;
+        let ring = state.ring.as_mut().unwrap();
+        ring.write_u64(MMAP_TIME_ENABLED, time_enabled);
+        ring.write_u64(MMAP_TIME_RUNNING, time_enabled);
+        if lost > 0 {
+            let lost_record = record(
+                PERF_RECORD_LOST,
+                0,
+                &[self.id, lost].map(u64::to_ne_bytes).concat(),
+            );
+            if !ring.push(&lost_record) {
+                state.lost += 1;
+                return;
+            }
+            state.lost = 0;
+        }
+        let ring = state.ring.as_mut().unwrap();
+        if ring.push(&record(PERF_RECORD_SAMPLE, PERF_RECORD_MISC_USER, &body)) {
+            self.poll.wake();
+        } else {
+            state.lost += 1;
+        }
+    }
+}
+
+/// Builds a record with a `struct perf_event_header`.
+fn record(ty: u32, misc: u16, body: &[u8]) -> Vec<u8> {
+    let size = (8 + body.len()) as u16;
+    let mut record = Vec::with_capacity(size as usize);
+    record.extend_from_slice(&ty.to_ne_bytes());
+    record.extend_from_slice(&misc.to_ne_bytes());
+    record.extend_from_slice(&size.to_ne_bytes());
+    record.extend_from_slice(body);
+    record
+}
+
+/// Returns the watched events of the current thread.
+fn watched_by_current() -> Vec<Arc<PerfEvent>> {
+    let watched = WATCHED.lock();
+    if watched.is_empty() {
+        return Vec::new();
+    }
+    let curr = current();
+    watched
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|event| event.task.id() == curr.id())
+        .collect()
+}
+
+/// Takes the samples that are due for the current thread, which is
+/// returning to user space at `ip` after an interrupt or a fault at `addr`.
+pub fn sample(ip: usize, addr: Option<usize>) {
+    for event in watched_by_current() {
+        event.sample(ip, addr);
+    }
+}
+
+/// Enables the events of the current thread that wait for `exec`.
+pub fn on_exec() {
+    for event in watched_by_current() {
+        if core::mem::take(&mut event.state.lock().enable_on_exec) {
+            event.enable();
+        }
+    }
+}
+
+impl FileLike for PerfEvent {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        let state = self.state.lock();
+        let mut values = Vec::with_capacity(4);
+        values.push(self.value(&state));
+        let time_enabled = self.time_enabled(&state);
+        if self.read_format & PERF_FORMAT_TOTAL_TIME_ENABLED != 0 {
+            values.push(time_enabled);
+        }
+        // Software events are never multiplexed.
+        if self.read_format & PERF_FORMAT_TOTAL_TIME_RUNNING != 0 {
+            values.push(time_enabled);
+        }
+        if self.read_format & PERF_FORMAT_ID != 0 {
+            values.push(self.id);
+        }
+        drop(state);
+
+        let len = values.len() * size_of::<u64>();
+        if dst.remaining_mut() < len {
+            return Err(AxError::StorageFull);
+        }
+        for value in values {
+            dst.write(&value.to_ne_bytes())?;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::BadFileDescriptor)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        "anon_inode:[perf_event]".into()
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        match cmd {
+            PERF_EVENT_IOC_ENABLE => self.enable(),
+            PERF_EVENT_IOC_DISABLE => self.disable(),
+            PERF_EVENT_IOC_RESET => self.reset(),
+            PERF_EVENT_IOC_REFRESH => return Err(AxError::InvalidInput),
+            PERF_EVENT_IOC_PERIOD => {
+                let period = (arg as *const u64).vm_read()?;
+                if period == 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                let mut state = self.state.lock();
+                if state.period == 0 {
+                    // A counting event can't become a sampling one.
+                    return Err(AxError::InvalidInput);
+                }
+                state.period = period;
+                state.next_sample = self.value(&state) + period;
+            }
+            PERF_EVENT_IOC_ID => (arg as *mut u64).vm_write(self.id)?,
+            _ => return Err(AxError::BadIoctl),
+        }
+        Ok(0)
+    }
+}
+
+impl Pollable for PerfEvent {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        if let Some(ring) = &self.state.lock().ring {
+            events.set(IoEvents::IN, ring.head != ring.tail());
+        }
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll.register(context.waker());
+        }
+    }
+}
@@ -7,21 +7,30 @@ use core::task::Context;
 use axerrno::{AxError, AxResult};
 use axpoll::{IoEvents, PollSet, Pollable};
 use starry_core::task::ProcessData;
+use starry_process::Process;
 
 use crate::file::{FileLike, Kstat, SealedBuf, SealedBufMut};
 
 pub struct PidFd {
+    proc: Arc<Process>,
     proc_data: Weak<ProcessData>,
     exit_event: Arc<PollSet>,
 }
 impl PidFd {
     pub fn new(proc_data: &Arc<ProcessData>) -> Self {
         Self {
+            proc: proc_data.proc.clone(),
             proc_data: Arc::downgrade(proc_data),
             exit_event: proc_data.exit_event.clone(),
         }
     }
 
+    /// The process this pidfd refers to. Unlike [`PidFd::process_data`] this
+    /// stays available after the process exits, until the pidfd is closed.
+    pub fn process(&self) -> &Arc<Process> {
+        &self.proc
+    }
+
     pub fn process_data(&self) -> AxResult<Arc<ProcessData>> {
         self.proc_data.upgrade().ok_or(AxError::NoSuchProcess)
     }
@@ -50,8 +59,12 @@ impl FileLike for PidFd {
 
 impl Pollable for PidFd {
     fn poll(&self) -> IoEvents {
+        // Readable once the process has exited, like Linux.
         let mut events = IoEvents::empty();
-        events.set(IoEvents::IN, self.proc_data.strong_count() > 0);
+        events.set(
+            IoEvents::IN,
+            self.proc.is_zombie() || self.proc_data.strong_count() == 0,
+        );
         events
     }
 
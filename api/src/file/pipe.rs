@@ -1,8 +1,7 @@
-use alloc::{borrow::Cow, format, sync::Arc};
+use alloc::{borrow::Cow, collections::vec_deque::VecDeque, format, sync::Arc, vec};
 use core::{
     any::Any,
-    mem,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     task::Context,
 };
 
@@ -13,21 +12,179 @@ use axsync::Mutex;
 use axtask::{current, future::Poller};
 use linux_raw_sys::{general::S_IFIFO, ioctl::FIONREAD};
 use memory_addr::PAGE_SIZE_4K;
-use ringbuf::{
-    HeapRb,
-    traits::{Consumer, Observer, Producer},
+use starry_core::{
+    sysctl::PIPE_MAX_SIZE,
+    task::{AsThread, send_signal_to_thread},
 };
-use starry_core::task::{AsThread, send_signal_to_process};
+use starry_process::Pid;
 use starry_signal::{SignalInfo, Signo};
 use starry_vm::VmMutPtr;
 
 use super::{FileLike, Kstat};
 use crate::file::{SealedBuf, SealedBufMut};
 
-const RING_BUFFER_INIT_SIZE: usize = 65536; // 64 KiB
+/// Default number of pages in a pipe, 64 KiB in total.
+const DEFAULT_PIPE_PAGES: usize = 16;
+
+/// Writes up to this size are atomic, see pipe(7).
+pub const PIPE_BUF: usize = PAGE_SIZE_4K;
+
+/// A page of data in the pipe.
+///
+/// Pages are reference counted so that `tee` can share them between pipes
+/// without copying; a shared page is never written to again.
+struct PipeBuffer {
+    page: Arc<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl PipeBuffer {
+    fn new() -> Self {
+        Self {
+            page: vec![0; PAGE_SIZE_4K].into(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns the free space at the end of the page, if it can be appended
+    /// to.
+    fn tail_mut(&mut self) -> Option<&mut [u8]> {
+        let end = self.end;
+        Arc::get_mut(&mut self.page)
+            .map(|page| &mut page[end..])
+            .filter(|tail| !tail.is_empty())
+    }
+}
+
+/// A ring of page-sized buffers.
+///
+/// In packet mode (`O_DIRECT`) every buffer holds exactly one packet.
+struct PipeRing {
+    bufs: VecDeque<PipeBuffer>,
+    max_pages: usize,
+    len: usize,
+    packet: bool,
+}
+
+impl PipeRing {
+    fn new(packet: bool) -> Self {
+        Self {
+            bufs: VecDeque::new(),
+            max_pages: DEFAULT_PIPE_PAGES,
+            len: 0,
+            packet,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_pages * PAGE_SIZE_4K
+    }
+
+    /// Number of bytes that can be written without blocking.
+    fn vacant(&self) -> usize {
+        let free_pages = (self.max_pages - self.bufs.len().min(self.max_pages)) * PAGE_SIZE_4K;
+        let tail = match self.bufs.back() {
+            Some(buf) if !self.packet && Arc::strong_count(&buf.page) == 1 => {
+                PAGE_SIZE_4K - buf.end
+            }
+            _ => 0,
+        };
+        free_pages + tail
+    }
+
+    /// Fills the ring with up to `max` bytes produced by `f`, which is handed
+    /// free space inside the pages and returns how much it filled.
+    fn push(
+        &mut self,
+        max: usize,
+        mut f: impl FnMut(&mut [u8]) -> AxResult<usize>,
+    ) -> AxResult<usize> {
+        let mut pushed = 0;
+        while pushed < max {
+            let appendable = !self.packet
+                && self
+                    .bufs
+                    .back_mut()
+                    .is_some_and(|buf| buf.tail_mut().is_some());
+            if !appendable {
+                if self.bufs.len() >= self.max_pages {
+                    break;
+                }
+                self.bufs.push_back(PipeBuffer::new());
+            }
+            let buf = self.bufs.back_mut().unwrap();
+            let tail = buf.tail_mut().unwrap();
+            let want = tail.len().min(max - pushed);
+            let n = match f(&mut tail[..want]) {
+                Ok(n) => n,
+                Err(err) if pushed == 0 => {
+                    self.drop_empty_tail();
+                    return Err(err);
+                }
+                Err(_) => break,
+            };
+            buf.end += n;
+            self.len += n;
+            pushed += n;
+            if n < want {
+                break;
+            }
+        }
+        self.drop_empty_tail();
+        Ok(pushed)
+    }
+
+    fn drop_empty_tail(&mut self) {
+        if self.bufs.back().is_some_and(|buf| buf.len() == 0) {
+            self.bufs.pop_back();
+        }
+    }
+
+    /// Drains up to `max` bytes into `f`, which returns how much it consumed.
+    ///
+    /// In packet mode at most one packet is consumed, and whatever `f` does
+    /// not take of it is discarded.
+    fn pop(&mut self, max: usize, mut f: impl FnMut(&[u8]) -> AxResult<usize>) -> AxResult<usize> {
+        let mut popped = 0;
+        while popped < max
+            && let Some(buf) = self.bufs.front_mut()
+        {
+            let chunk = &buf.page[buf.start..buf.end];
+            let want = chunk.len().min(max - popped);
+            let n = match f(&chunk[..want]) {
+                Ok(n) => n,
+                Err(err) if popped == 0 => return Err(err),
+                Err(_) => break,
+            };
+            popped += n;
+            if self.packet {
+                self.len -= buf.len();
+                self.bufs.pop_front();
+                break;
+            }
+            buf.start += n;
+            self.len -= n;
+            if buf.len() == 0 {
+                self.bufs.pop_front();
+            }
+            if n < want {
+                break;
+            }
+        }
+        Ok(popped)
+    }
+}
 
 struct Shared {
-    buffer: Mutex<HeapRb<u8>>,
+    ring: Mutex<PipeRing>,
+    readers: AtomicUsize,
+    writers: AtomicUsize,
     poll_rx: PollSet,
     poll_tx: PollSet,
     poll_close: PollSet,
@@ -40,14 +197,23 @@ pub struct Pipe {
 }
 impl Drop for Pipe {
     fn drop(&mut self) {
+        if self.read_side {
+            self.shared.readers.fetch_sub(1, Ordering::AcqRel);
+        } else {
+            self.shared.writers.fetch_sub(1, Ordering::AcqRel);
+        }
         self.shared.poll_close.wake();
     }
 }
 
 impl Pipe {
-    pub fn new() -> (Pipe, Pipe) {
+    /// Creates a pipe, returning its read and write ends. `packet` enables
+    /// packet mode (`O_DIRECT`).
+    pub fn new(packet: bool) -> (Pipe, Pipe) {
         let shared = Arc::new(Shared {
-            buffer: Mutex::new(HeapRb::new(RING_BUFFER_INIT_SIZE)),
+            ring: Mutex::new(PipeRing::new(packet)),
+            readers: AtomicUsize::new(1),
+            writers: AtomicUsize::new(1),
             poll_rx: PollSet::new(),
             poll_tx: PollSet::new(),
             poll_close: PollSet::new(),
@@ -73,66 +239,116 @@ impl Pipe {
         !self.read_side
     }
 
+    /// Whether the other end of the pipe has been closed.
     pub fn closed(&self) -> bool {
-        Arc::strong_count(&self.shared) == 1
+        let peers = if self.read_side {
+            &self.shared.writers
+        } else {
+            &self.shared.readers
+        };
+        peers.load(Ordering::Acquire) == 0
     }
 
     pub fn capacity(&self) -> usize {
-        self.shared.buffer.lock().capacity().get()
+        self.shared.ring.lock().capacity()
     }
 
-    pub fn resize(&self, new_size: usize) -> AxResult<()> {
-        let new_size = new_size.div_ceil(PAGE_SIZE_4K).max(1) * PAGE_SIZE_4K;
-
-        let mut buffer = self.shared.buffer.lock();
-        if new_size == buffer.capacity().get() {
-            return Ok(());
+    /// Resizes the pipe (`F_SETPIPE_SZ`), returning the actual new capacity.
+    ///
+    /// Like Linux the size is rounded up to a power of two pages, and only a
+    /// privileged process may go past [`PIPE_MAX_SIZE`].
+    pub fn resize(&self, new_size: usize) -> AxResult<usize> {
+        let pages = new_size
+            .div_ceil(PAGE_SIZE_4K)
+            .max(1)
+            .checked_next_power_of_two()
+            .ok_or(AxError::InvalidInput)?;
+        if pages * PAGE_SIZE_4K > PIPE_MAX_SIZE.get()
+            && !current().as_thread().proc_data.cred.read().is_privileged()
+        {
+            return Err(AxError::OperationNotPermitted);
         }
-        if new_size < buffer.occupied_len() {
+
+        let mut ring = self.shared.ring.lock();
+        if pages < ring.bufs.len() {
             return Err(AxError::ResourceBusy);
         }
-        let old_buffer = mem::replace(&mut *buffer, HeapRb::new(new_size));
-        let (left, right) = old_buffer.as_slices();
-        buffer.push_slice(left);
-        buffer.push_slice(right);
-        Ok(())
+        ring.max_pages = pages;
+        drop(ring);
+        self.shared.poll_tx.wake();
+        Ok(pages * PAGE_SIZE_4K)
     }
-}
 
-fn raise_pipe() {
-    let curr = current();
-    send_signal_to_process(
-        curr.as_thread().proc_data.proc.pid(),
-        Some(SignalInfo::new_kernel(Signo::SIGPIPE)),
-    )
-    .expect("Failed to send SIGPIPE");
-}
+    /// Moves up to `len` bytes into the pipe, letting `f` fill the pipe's
+    /// pages directly. This is the splice fast path: it avoids bouncing the
+    /// data through an intermediate buffer.
+    ///
+    /// The data is still copied once into the pipe's own pages, even from the
+    /// page cache: a pipe page can't refer to a page of the cache.
+    ///
+    /// `src` is the file `f` reads from, if any, which is waited for too when
+    /// `f` would block.
+    pub fn splice_in(
+        &self,
+        len: usize,
+        non_blocking: bool,
+        src: Option<&dyn Pollable>,
+        mut f: impl FnMut(&mut [u8]) -> AxResult<usize>,
+    ) -> AxResult<usize> {
+        if !self.is_write() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        let transfer = Transfer {
+            pipe: self,
+            events: IoEvents::OUT,
+            peer: src,
+            peer_events: IoEvents::IN,
+        };
+        Poller::new(&transfer, IoEvents::OUT)
+            .non_blocking(non_blocking || self.nonblocking())
+            .poll(|| {
+                if self.closed() {
+                    raise_pipe();
+                    return Err(AxError::BrokenPipe);
+                }
+                let pushed = self.shared.ring.lock().push(len, &mut f)?;
+                if pushed > 0 {
+                    self.shared.poll_rx.wake();
+                    Ok(pushed)
+                } else {
+                    Err(AxError::WouldBlock)
+                }
+            })
+    }
 
-impl FileLike for Pipe {
-    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+    /// Moves up to `len` bytes out of the pipe into `f`, the counterpart of
+    /// [`Pipe::splice_in`].
+    ///
+    /// `dst` is the file `f` writes to, if any, which is waited for too when
+    /// `f` would block.
+    pub fn splice_out(
+        &self,
+        len: usize,
+        non_blocking: bool,
+        dst: Option<&dyn Pollable>,
+        mut f: impl FnMut(&[u8]) -> AxResult<usize>,
+    ) -> AxResult<usize> {
         if !self.is_read() {
             return Err(AxError::BadFileDescriptor);
         }
-        if dst.remaining_mut() == 0 {
-            return Ok(0);
-        }
-
-        Poller::new(self, IoEvents::IN)
-            .non_blocking(self.nonblocking())
+        let transfer = Transfer {
+            pipe: self,
+            events: IoEvents::IN,
+            peer: dst,
+            peer_events: IoEvents::OUT,
+        };
+        Poller::new(&transfer, IoEvents::IN)
+            .non_blocking(non_blocking || self.nonblocking())
             .poll(|| {
-                let read = {
-                    let cons = self.shared.buffer.lock();
-                    let (left, right) = cons.as_slices();
-                    let mut count = dst.write(left)?;
-                    if count >= left.len() {
-                        count += dst.write(right)?;
-                    }
-                    unsafe { cons.advance_read_index(count) };
-                    count
-                };
-                if read > 0 {
+                let popped = self.shared.ring.lock().pop(len, &mut f)?;
+                if popped > 0 {
                     self.shared.poll_tx.wake();
-                    Ok(read)
+                    Ok(popped)
                 } else if self.closed() {
                     Ok(0)
                 } else {
@@ -141,6 +357,124 @@ impl FileLike for Pipe {
             })
     }
 
+    /// Duplicates up to `len` bytes from this pipe into `dst` without
+    /// consuming them (`tee`). The pages are shared rather than copied.
+    pub fn tee(&self, dst: &Pipe, len: usize, non_blocking: bool) -> AxResult<usize> {
+        if !self.is_read() || !dst.is_write() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        if Arc::ptr_eq(&self.shared, &dst.shared) {
+            return Err(AxError::InvalidInput);
+        }
+        let transfer = Transfer {
+            pipe: self,
+            events: IoEvents::IN,
+            peer: Some(dst),
+            peer_events: IoEvents::OUT,
+        };
+        Poller::new(&transfer, IoEvents::IN)
+            .non_blocking(non_blocking || self.nonblocking())
+            .poll(|| {
+                if dst.closed() {
+                    raise_pipe();
+                    return Err(AxError::BrokenPipe);
+                }
+                // Always lock in the same order so two opposite `tee`s can't
+                // deadlock.
+                let (src_ring, mut dst_ring) =
+                    if Arc::as_ptr(&self.shared) < Arc::as_ptr(&dst.shared) {
+                        let src_ring = self.shared.ring.lock();
+                        (src_ring, dst.shared.ring.lock())
+                    } else {
+                        let dst_ring = dst.shared.ring.lock();
+                        (self.shared.ring.lock(), dst_ring)
+                    };
+                let mut copied = 0;
+                for buf in &src_ring.bufs {
+                    if copied >= len || dst_ring.bufs.len() >= dst_ring.max_pages {
+                        break;
+                    }
+                    let n = buf.len().min(len - copied);
+                    dst_ring.bufs.push_back(PipeBuffer {
+                        page: buf.page.clone(),
+                        start: buf.start,
+                        end: buf.start + n,
+                    });
+                    dst_ring.len += n;
+                    copied += n;
+                }
+                let src_empty = src_ring.len == 0;
+                drop((src_ring, dst_ring));
+
+                if copied > 0 {
+                    dst.shared.poll_rx.wake();
+                    Ok(copied)
+                } else if src_empty && self.closed() {
+                    Ok(0)
+                } else {
+                    Err(AxError::WouldBlock)
+                }
+            })
+    }
+}
+
+/// What a transfer between a pipe and another file waits for: `events` on the
+/// pipe, and `peer_events` on the other file, so that the transfer is retried
+/// once either side can make progress.
+struct Transfer<'a> {
+    pipe: &'a Pipe,
+    events: IoEvents,
+    peer: Option<&'a dyn Pollable>,
+    peer_events: IoEvents,
+}
+
+impl Pollable for Transfer<'_> {
+    fn poll(&self) -> IoEvents {
+        let peer_ready = self
+            .peer
+            .is_none_or(|peer| peer.poll().intersects(self.peer_events));
+        if peer_ready {
+            self.pipe.poll()
+        } else {
+            IoEvents::empty()
+        }
+    }
+
+    fn register(&self, context: &mut Context<'_>, _events: IoEvents) {
+        self.pipe.register(context, self.events);
+        if let Some(peer) = self.peer {
+            peer.register(context, self.peer_events);
+        }
+    }
+}
+
+/// Sends `SIGPIPE` to the current thread, as comes with `EPIPE`.
+///
+/// Like Linux, the signal goes to the thread that did the write rather than
+/// to any thread of the process, so that it is the writer that dies or
+/// handles it.
+///
+/// A kernel thread writing to a pipe gets no signal, only the error.
+pub(super) fn raise_pipe() {
+    let _ = send_signal_to_thread(
+        None,
+        current().id().as_u64() as Pid,
+        Some(SignalInfo::new_kernel(Signo::SIGPIPE)),
+    );
+}
+
+impl FileLike for Pipe {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        if !self.is_read() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        let len = dst.remaining_mut();
+        if len == 0 {
+            return Ok(0);
+        }
+        self.splice_out(len, false, None, |chunk| dst.write(chunk))
+    }
+
     fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
         if !self.is_write() {
             return Err(AxError::BadFileDescriptor);
@@ -161,14 +495,27 @@ impl FileLike for Pipe {
                 }
 
                 let written = {
-                    let mut prod = self.shared.buffer.lock();
-                    let (left, right) = prod.vacant_slices_mut();
-                    let mut count = src.read(unsafe { left.assume_init_mut() })?;
-                    if count >= left.len() {
-                        count += src.read(unsafe { right.assume_init_mut() })?;
+                    let mut ring = self.shared.ring.lock();
+                    let remaining = size - total_written;
+                    // Writes of at most PIPE_BUF bytes must not be interleaved
+                    // with other writers, so wait until all of it fits.
+                    if remaining <= PIPE_BUF && ring.vacant() < remaining {
+                        0
+                    } else if ring.packet {
+                        // Each packet takes a page of its own.
+                        let mut written = 0;
+                        while written < remaining {
+                            let packet = (remaining - written).min(PIPE_BUF);
+                            let n = ring.push(packet, |buf| src.read(buf))?;
+                            if n == 0 {
+                                break;
+                            }
+                            written += n;
+                        }
+                        written
+                    } else {
+                        ring.push(remaining, |buf| src.read(buf))?
                     }
-                    unsafe { prod.advance_write_index(count) };
-                    count
                 };
                 if written > 0 {
                     self.shared.poll_rx.wake();
@@ -208,7 +555,7 @@ impl FileLike for Pipe {
     fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
         match cmd {
             FIONREAD => {
-                (arg as *mut u32).vm_write(self.shared.buffer.lock().occupied_len() as u32)?;
+                (arg as *mut u32).vm_write(self.shared.ring.lock().len as u32)?;
                 Ok(0)
             }
             _ => Err(AxError::BadIoctl),
@@ -219,12 +566,15 @@ impl FileLike for Pipe {
 impl Pollable for Pipe {
     fn poll(&self) -> IoEvents {
         let mut events = IoEvents::empty();
-        let buf = self.shared.buffer.lock();
+        let ring = self.shared.ring.lock();
         if self.read_side {
-            events.set(IoEvents::IN, buf.occupied_len() > 0);
+            events.set(IoEvents::IN, ring.len > 0);
+            // Reported once all writers are gone, even if data remains.
             events.set(IoEvents::HUP, self.closed());
         } else {
-            events.set(IoEvents::OUT, buf.vacant_len() > 0);
+            events.set(IoEvents::OUT, ring.vacant() >= PIPE_BUF);
+            // Writing would fail with EPIPE.
+            events.set(IoEvents::ERR, self.closed());
         }
         events
     }
@@ -0,0 +1,198 @@
+//! Read-ahead into the page cache.
+//!
+//! Every open file remembers where its last read ended. A read starting
+//! there is sequential, and the pages past it are read into the page cache
+//! by the `readahead` task before they are asked for. The window starts at a
+//! quarter of `vm.read_ahead_kb` and doubles with each sequential read up to
+//! `vm.read_ahead_kb`, or twice that after `POSIX_FADV_SEQUENTIAL`; a read
+//! elsewhere starts it over. `POSIX_FADV_RANDOM` turns read-ahead off for
+//! the file and `POSIX_FADV_WILLNEED` reads the given range in right away.
+//! The counters are in `/proc/readahead`.
+
+use alloc::{collections::VecDeque, string::String};
+use core::{
+    fmt::Write,
+    future::poll_fn,
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Poll,
+};
+
+use axfs_ng::CachedFile;
+use axpoll::PollSet;
+use axtask::future::block_on;
+use lazy_static::lazy_static;
+use linux_raw_sys::general::{
+    POSIX_FADV_NORMAL, POSIX_FADV_RANDOM, POSIX_FADV_SEQUENTIAL, POSIX_FADV_WILLNEED,
+};
+use memory_addr::PAGE_SIZE_4K;
+use spin::{Mutex, Once};
+use starry_core::{mm::register_shrinker, sysctl::READ_AHEAD_KB};
+
+use crate::oom::under_pressure;
+
+/// The most requests waiting for the `readahead` task; read-ahead is
+/// dropped rather than queued past it, or when memory runs low.
+const MAX_QUEUED: usize = 64;
+
+static SEQUENTIAL_READS: AtomicU64 = AtomicU64::new(0);
+static RANDOM_READS: AtomicU64 = AtomicU64::new(0);
+static PAGES_QUEUED: AtomicU64 = AtomicU64::new(0);
+static PAGES_READ: AtomicU64 = AtomicU64::new(0);
+
+static QUEUE: Mutex<VecDeque<(CachedFile, Range<u32>)>> = Mutex::new(VecDeque::new());
+static WORKER: Once<()> = Once::new();
+
+lazy_static! {
+    /// Woken whenever a request is queued.
+    static ref QUEUED: PollSet = PollSet::new();
+}
+
+/// Queues the `pages` of `cache` to be read in by the `readahead` task.
+fn queue(cache: &CachedFile, pages: Range<u32>) {
+    if pages.is_empty() {
+        return;
+    }
+    WORKER.call_once(|| {
+        axtask::spawn(worker, "readahead".into());
+        // Pending requests keep the caches they read into alive.
+        register_shrinker(|| QUEUE.lock().clear());
+    });
+    let mut queue = QUEUE.lock();
+    if queue.len() >= MAX_QUEUED || under_pressure() {
+        return;
+    }
+    PAGES_QUEUED.fetch_add(pages.len() as u64, Ordering::Relaxed);
+    queue.push_back((cache.clone(), pages));
+    drop(queue);
+    QUEUED.wake();
+}
+
+fn worker() {
+    loop {
+        let (cache, pages) = block_on(poll_fn(|cx| {
+            if let Some(request) = QUEUE.lock().pop_front() {
+                return Poll::Ready(request);
+            }
+            QUEUED.register(cx.waker());
+            match QUEUE.lock().pop_front() {
+                Some(request) => Poll::Ready(request),
+                None => Poll::Pending,
+            }
+        }));
+        for page in pages {
+            // Bringing the page in is all there is to do.
+            if cache.with_page(page, |_| ()).is_err() {
+                break;
+            }
+            PAGES_READ.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the number of pages covering `len` bytes of a file.
+fn pages_of(len: u64) -> u32 {
+    len.div_ceil(PAGE_SIZE_4K as u64).min(u32::MAX as u64) as u32
+}
+
+/// The read-ahead state of an open file.
+#[derive(Default)]
+pub struct ReadAhead(Mutex<State>);
+
+#[derive(Default)]
+struct State {
+    /// The `POSIX_FADV_*` advice given for the file.
+    advice: u32,
+    /// Where the last read ended.
+    next: u64,
+    /// The current window, in pages.
+    window: u32,
+    /// The first page not queued for read-ahead yet.
+    queued_until: u32,
+}
+
+impl ReadAhead {
+    /// Notes a read of `len` bytes at `offset` of `cache`, which is `size`
+    /// bytes long, reading ahead if it is sequential.
+    pub fn on_read(&self, cache: &CachedFile, offset: u64, len: usize, size: u64) {
+        if len == 0 {
+            return;
+        }
+        let mut state = self.0.lock();
+        let end = offset + len as u64;
+        let sequential = offset == state.next;
+        state.next = end;
+        if state.advice == POSIX_FADV_RANDOM {
+            return;
+        }
+        if !sequential {
+            RANDOM_READS.fetch_add(1, Ordering::Relaxed);
+            state.window = 0;
+            state.queued_until = 0;
+            return;
+        }
+        SEQUENTIAL_READS.fetch_add(1, Ordering::Relaxed);
+
+        let mut max = pages_of(READ_AHEAD_KB.get() as u64 * 1024);
+        if state.advice == POSIX_FADV_SEQUENTIAL {
+            max = max.saturating_mul(2);
+        }
+        state.window = if state.window == 0 {
+            (max / 4).max(1)
+        } else {
+            state.window.saturating_mul(2)
+        }
+        .min(max);
+
+        let next_page = (end / PAGE_SIZE_4K as u64).min(u32::MAX as u64) as u32;
+        let start = state.queued_until.max(next_page);
+        let stop = next_page.saturating_add(state.window).min(pages_of(size));
+        if start < stop {
+            state.queued_until = stop;
+            drop(state);
+            queue(cache, start..stop);
+        }
+    }
+
+    /// Takes the advice of `posix_fadvise` for `len` bytes at `offset` of
+    /// `cache`, which is `size` bytes long. A `len` of 0 means up to the end
+    /// of the file.
+    pub fn advise(&self, cache: &CachedFile, advice: u32, offset: u64, len: u64, size: u64) {
+        if advice == POSIX_FADV_WILLNEED {
+            let end = if len == 0 {
+                size
+            } else {
+                offset.saturating_add(len).min(size)
+            };
+            let start = (offset / PAGE_SIZE_4K as u64).min(u32::MAX as u64) as u32;
+            queue(cache, start..pages_of(end));
+            return;
+        }
+        // `POSIX_FADV_DONTNEED` and `POSIX_FADV_NOREUSE` are only hints
+        // about caching, which the page cache decides on its own.
+        if matches!(
+            advice,
+            POSIX_FADV_NORMAL | POSIX_FADV_RANDOM | POSIX_FADV_SEQUENTIAL
+        ) {
+            let mut state = self.0.lock();
+            state.advice = advice;
+            state.window = 0;
+        }
+    }
+}
+
+/// Renders the counters of `/proc/readahead`.
+pub fn stats() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "read_ahead_kb {}", READ_AHEAD_KB.get());
+    for (name, value) in [
+        ("sequential_reads", &SEQUENTIAL_READS),
+        ("random_reads", &RANDOM_READS),
+        ("pages_queued", &PAGES_QUEUED),
+        ("pages_read", &PAGES_READ),
+    ] {
+        let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+    }
+    let _ = writeln!(out, "queued_requests {}", QUEUE.lock().len());
+    out
+}
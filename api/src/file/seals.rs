@@ -0,0 +1,151 @@
+//! Memfd seals, as set with `fcntl(F_ADD_SEALS)`.
+//!
+//! Seals belong to the memfd's inode rather than to the file it was created
+//! as: they also hold when the memfd is opened again by path, and for writes
+//! that don't go through [`FileLike::write`](super::FileLike::write), such as
+//! `sendfile` or `truncate`.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::Location;
+use axhal::paging::MappingFlags;
+use axmm::backend::Backend;
+use spin::Mutex;
+use starry_core::task::processes;
+
+use super::FileSeals;
+
+/// The seals of a memfd, shared by every file opened on it.
+pub struct MemfdSeals {
+    seals: AtomicU32,
+    /// Identifies the memfd's page cache in shared mappings of it.
+    mapping: Mutex<Weak<()>>,
+}
+
+impl MemfdSeals {
+    /// Returns the seals (`F_GET_SEALS`).
+    pub fn get(&self) -> FileSeals {
+        FileSeals::from_bits_retain(self.seals.load(Ordering::Acquire))
+    }
+
+    /// Adds seals (`F_ADD_SEALS`).
+    ///
+    /// `F_SEAL_WRITE` can't be added while the memfd is mapped shared and
+    /// writable.
+    pub fn add(&self, new: FileSeals) -> AxResult<()> {
+        if new.contains(FileSeals::WRITE)
+            && !self.get().contains(FileSeals::SEAL)
+            && self.mapped_writable()
+        {
+            return Err(AxError::ResourceBusy);
+        }
+        self.seals
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |old| {
+                let old = FileSeals::from_bits_retain(old);
+                (!old.contains(FileSeals::SEAL)).then(|| (old | new).bits())
+            })
+            .map_err(|_| AxError::OperationNotPermitted)?;
+        Ok(())
+    }
+
+    /// Remembers that the memfd is about to be mapped shared through
+    /// `backend`, for [`Self::mapped_writable`] to find such mappings later.
+    pub fn note_mapping(&self, backend: &Backend) {
+        if let Backend::File(file) = backend {
+            *self.mapping.lock() = file.futex_handle();
+        }
+    }
+
+    /// Whether any process maps the memfd shared and writable.
+    fn mapped_writable(&self) -> bool {
+        let mapping = self.mapping.lock().clone();
+        if mapping.strong_count() == 0 {
+            return false;
+        }
+        processes().iter().any(|proc_data| {
+            proc_data.aspace.lock().areas().any(|area| {
+                area.flags().contains(MappingFlags::WRITE)
+                    && matches!(area.backend(), Backend::File(file)
+                        if Weak::ptr_eq(&file.futex_handle(), &mapping))
+            })
+        })
+    }
+}
+
+struct Sealed {
+    dev: u64,
+    ino: u64,
+    seals: Weak<MemfdSeals>,
+}
+
+/// Every memfd that is still open, with its seals.
+static SEALED: Mutex<Vec<Sealed>> = Mutex::new(Vec::new());
+
+fn inode_of(loc: &Location) -> AxResult<(u64, u64)> {
+    Ok((loc.mountpoint().device() as u64, loc.metadata()?.inode))
+}
+
+/// Makes `loc` a memfd starting with `seals`. The seals last as long as the
+/// returned handle.
+pub fn register(loc: &Location, seals: FileSeals) -> AxResult<Arc<MemfdSeals>> {
+    let (dev, ino) = inode_of(loc)?;
+    let new = Arc::new(MemfdSeals {
+        seals: AtomicU32::new(seals.bits()),
+        mapping: Mutex::new(Weak::new()),
+    });
+    let mut sealed = SEALED.lock();
+    sealed.retain(|it| it.seals.strong_count() > 0 && (it.dev, it.ino) != (dev, ino));
+    sealed.push(Sealed {
+        dev,
+        ino,
+        seals: Arc::downgrade(&new),
+    });
+    Ok(new)
+}
+
+/// Returns the seals of `loc` if it is an open memfd.
+pub fn lookup(loc: &Location) -> Option<Arc<MemfdSeals>> {
+    let sealed = SEALED.lock();
+    if sealed.is_empty() {
+        return None;
+    }
+    let (dev, ino) = inode_of(loc).ok()?;
+    sealed
+        .iter()
+        .find(|it| it.dev == dev && it.ino == ino)
+        .and_then(|it| it.seals.upgrade())
+}
+
+/// Checks that writing to `loc` up to offset `end` is allowed by the seals.
+pub fn check_write(loc: &Location, end: u64) -> AxResult<()> {
+    let Some(seals) = lookup(loc) else {
+        return Ok(());
+    };
+    let seals = seals.get();
+    if seals.intersects(FileSeals::WRITE | FileSeals::FUTURE_WRITE)
+        || (seals.contains(FileSeals::GROW) && end > loc.len()?)
+    {
+        return Err(AxError::OperationNotPermitted);
+    }
+    Ok(())
+}
+
+/// Checks that resizing `loc` to `len` is allowed by the seals.
+pub fn check_set_len(loc: &Location, len: u64) -> AxResult<()> {
+    let Some(seals) = lookup(loc) else {
+        return Ok(());
+    };
+    let seals = seals.get();
+    let old = loc.len()?;
+    if (seals.contains(FileSeals::SHRINK) && len < old)
+        || (seals.contains(FileSeals::GROW) && len > old)
+    {
+        return Err(AxError::OperationNotPermitted);
+    }
+    Ok(())
+}
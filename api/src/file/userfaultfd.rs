@@ -0,0 +1,547 @@
+use alloc::{
+    borrow::Cow,
+    collections::vec_deque::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    future::poll_fn,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::paging::MappingFlags;
+use axio::{BufMut, Write};
+use axmm::AddrSpace;
+use axpoll::{IoEvents, PollSet, Pollable};
+use axsync::Mutex as AxMutex;
+use axtask::{
+    current,
+    future::{Poller, block_on, interruptible},
+};
+use bitflags::bitflags;
+use bytemuck::AnyBitPattern;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr};
+use spin::Mutex;
+use starry_core::task::AsThread;
+use starry_vm::{VmMutPtr, VmPtr, vm_load};
+
+use crate::file::{FileLike, Kstat, SealedBuf, SealedBufMut};
+
+const UFFD_API: u64 = 0xaa;
+
+const UFFDIO_API: u32 = 0xc018_aa3f;
+const UFFDIO_REGISTER: u32 = 0xc020_aa00;
+const UFFDIO_UNREGISTER: u32 = 0x8010_aa01;
+const UFFDIO_WAKE: u32 = 0x8010_aa02;
+const UFFDIO_COPY: u32 = 0xc028_aa03;
+const UFFDIO_ZEROPAGE: u32 = 0xc020_aa04;
+
+/// The ioctls available on the fd, as bits of their command numbers.
+const UFFD_API_IOCTLS: u64 = 1 << 0x3f | 1 << 0x00 | 1 << 0x01;
+/// The ioctls available on a registered range.
+const UFFD_API_RANGE_IOCTLS: u64 = 1 << 0x02 | 1 << 0x03 | 1 << 0x04;
+
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_PAGEFAULT_FLAG_WRITE: u64 = 1 << 0;
+
+const UFFD_FEATURE_THREAD_ID: u64 = 1 << 8;
+
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+const UFFDIO_COPY_MODE_DONTWAKE: u64 = 1 << 0;
+const UFFDIO_ZEROPAGE_MODE_DONTWAKE: u64 = 1 << 0;
+
+/// Size of a `struct uffd_msg`.
+const UFFD_MSG_LEN: usize = 32;
+
+bitflags! {
+    /// Flags for the `userfaultfd` syscall.
+    #[derive(Debug, Clone, Copy)]
+    pub struct UserFaultFdFlags: u32 {
+        /// Only handle faults from user space.
+        const USER_MODE_ONLY = 1;
+        /// Create a non-blocking fd.
+        const NONBLOCK = 0o4000;
+        /// Close the fd on `exec`.
+        const CLOEXEC = 0o2000000;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+impl UffdioRange {
+    /// Checks that the range is page aligned and returns it.
+    fn to_range(self) -> AxResult<Range<usize>> {
+        let start = self.start as usize;
+        let end = start
+            .checked_add(self.len as usize)
+            .ok_or(AxError::InvalidInput)?;
+        if self.len == 0 || !start.is_aligned_4k() || !end.is_aligned_4k() {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(start..end)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+struct FaultMsg {
+    address: usize,
+    write: bool,
+    tid: u32,
+}
+
+impl FaultMsg {
+    fn to_bytes(&self, features: u64) -> [u8; UFFD_MSG_LEN] {
+        let mut msg = [0; UFFD_MSG_LEN];
+        msg[0] = UFFD_EVENT_PAGEFAULT;
+        let flags = if self.write {
+            UFFD_PAGEFAULT_FLAG_WRITE
+        } else {
+            0
+        };
+        msg[8..16].copy_from_slice(&flags.to_ne_bytes());
+        msg[16..24].copy_from_slice(&(self.address as u64).to_ne_bytes());
+        if features & UFFD_FEATURE_THREAD_ID != 0 {
+            msg[24..28].copy_from_slice(&self.tid.to_ne_bytes());
+        }
+        msg
+    }
+}
+
+/// A userfaultfd, which hands missing-page faults in its registered ranges
+/// over to user space.
+pub struct UserFaultFd {
+    aspace: Weak<AxMutex<AddrSpace>>,
+    features: Mutex<Option<u64>>,
+    ranges: Mutex<Vec<Range<usize>>>,
+    messages: Mutex<VecDeque<FaultMsg>>,
+    /// Faulting threads and the page they wait on.
+    waiters: Mutex<Vec<(usize, Waker)>>,
+    non_blocking: AtomicBool,
+    poll_rx: PollSet,
+}
+
+static CONTEXTS: Mutex<Vec<Weak<UserFaultFd>>> = Mutex::new(Vec::new());
+
+impl UserFaultFd {
+    pub fn new(flags: UserFaultFdFlags) -> Arc<Self> {
+        let uffd = Arc::new(Self {
+            aspace: Arc::downgrade(&current().as_thread().proc_data.aspace),
+            features: Mutex::new(None),
+            ranges: Mutex::new(Vec::new()),
+            messages: Mutex::new(VecDeque::new()),
+            waiters: Mutex::new(Vec::new()),
+            non_blocking: AtomicBool::new(flags.contains(UserFaultFdFlags::NONBLOCK)),
+            poll_rx: PollSet::new(),
+        });
+        CONTEXTS.lock().push(Arc::downgrade(&uffd));
+        uffd
+    }
+
+    fn covers(&self, range: &Range<usize>) -> bool {
+        let mut start = range.start;
+        let mut ranges = self.ranges.lock().clone();
+        ranges.sort_by_key(|r| r.start);
+        for r in ranges {
+            if r.start <= start && start < r.end {
+                start = r.end;
+            }
+        }
+        start >= range.end
+    }
+
+    fn register(&self, arg: usize) -> AxResult<usize> {
+        let ptr = arg as *mut UffdioRegister;
+        let mut reg = ptr.vm_read()?;
+        if reg.mode & !UFFDIO_REGISTER_MODE_MISSING != 0 || reg.mode == 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let range = reg.range.to_range()?;
+
+        let aspace = self.aspace.upgrade().ok_or(AxError::InvalidInput)?;
+        let aspace = aspace.lock();
+        // Every page of the range must be mapped.
+        let mut addr = range.start;
+        while addr < range.end {
+            let area = aspace
+                .find_area(VirtAddr::from(addr))
+                .ok_or(AxError::InvalidInput)?;
+            addr = area.end().as_usize();
+        }
+        drop(aspace);
+
+        let mut ranges = self.ranges.lock();
+        if ranges
+            .iter()
+            .any(|r| r.start < range.end && range.start < r.end)
+        {
+            return Err(AxError::ResourceBusy);
+        }
+        ranges.push(range);
+        drop(ranges);
+
+        reg.ioctls = UFFD_API_RANGE_IOCTLS;
+        ptr.vm_write(reg)?;
+        Ok(0)
+    }
+
+    fn unregister(&self, arg: usize) -> AxResult<usize> {
+        let range = (arg as *const UffdioRange).vm_read()?.to_range()?;
+        let mut ranges = self.ranges.lock();
+        let mut remaining = Vec::new();
+        for r in ranges.drain(..) {
+            if r.start < range.start {
+                remaining.push(r.start..r.end.min(range.start));
+            }
+            if r.end > range.end {
+                remaining.push(r.start.max(range.end)..r.end);
+            }
+        }
+        *ranges = remaining;
+        drop(ranges);
+        // Faults in the range are no longer ours to resolve.
+        self.wake(&range);
+        Ok(0)
+    }
+
+    /// Resolves the missing pages in `range` with `fill`, returning how many
+    /// bytes were filled.
+    fn resolve(
+        &self,
+        range: Range<usize>,
+        fill: impl Fn(&mut AddrSpace, VirtAddr) -> AxResult<()>,
+    ) -> AxResult<usize> {
+        if !self.covers(&range) {
+            return Err(AxError::NotFound);
+        }
+        let aspace = self.aspace.upgrade().ok_or(AxError::NotFound)?;
+        let mut aspace = aspace.lock();
+        let mut filled = 0;
+        for addr in range.step_by(PAGE_SIZE_4K) {
+            let addr = VirtAddr::from(addr);
+            if aspace.page_table().query(addr).is_ok() {
+                if filled == 0 {
+                    return Err(AxError::AlreadyExists);
+                }
+                break;
+            }
+            let area = aspace.find_area(addr).ok_or(AxError::NotFound)?;
+            let access = area.flags() & (MappingFlags::READ | MappingFlags::WRITE);
+            aspace.populate_area(addr, PAGE_SIZE_4K, access)?;
+            fill(&mut aspace, addr)?;
+            filled += PAGE_SIZE_4K;
+        }
+        Ok(filled)
+    }
+
+    fn copy(&self, arg: usize) -> AxResult<usize> {
+        let ptr = arg as *mut UffdioCopy;
+        let mut copy = ptr.vm_read()?;
+        if copy.mode & !UFFDIO_COPY_MODE_DONTWAKE != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let range = UffdioRange {
+            start: copy.dst,
+            len: copy.len,
+        }
+        .to_range()?;
+        let data = vm_load(copy.src as *const u8, copy.len as usize)?;
+
+        let result = self.resolve(range.clone(), |aspace, addr| {
+            let offset = addr.as_usize() - range.start;
+            aspace.write(addr, &data[offset..offset + PAGE_SIZE_4K])
+        });
+        copy.copy = match &result {
+            Ok(filled) => *filled as i64,
+            Err(err) => -(LinuxError::from(*err).code() as i64),
+        };
+        ptr.vm_write(copy)?;
+        let filled = result?;
+
+        if copy.mode & UFFDIO_COPY_MODE_DONTWAKE == 0 {
+            self.wake(&(range.start..range.start + filled));
+        }
+        Ok(0)
+    }
+
+    fn zeropage(&self, arg: usize) -> AxResult<usize> {
+        let ptr = arg as *mut UffdioZeropage;
+        let mut zeropage = ptr.vm_read()?;
+        if zeropage.mode & !UFFDIO_ZEROPAGE_MODE_DONTWAKE != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let range = zeropage.range.to_range()?;
+
+        // Freshly populated anonymous pages are already zeroed.
+        let result = self.resolve(range.clone(), |_, _| Ok(()));
+        zeropage.zeropage = match &result {
+            Ok(filled) => *filled as i64,
+            Err(err) => -(LinuxError::from(*err).code() as i64),
+        };
+        ptr.vm_write(zeropage)?;
+        let filled = result?;
+
+        if zeropage.mode & UFFDIO_ZEROPAGE_MODE_DONTWAKE == 0 {
+            self.wake(&(range.start..range.start + filled));
+        }
+        Ok(0)
+    }
+
+    /// Wakes the threads waiting on faults in `range`.
+    fn wake(&self, range: &Range<usize>) {
+        self.waiters.lock().retain(|(page, waker)| {
+            if range.contains(page) {
+                waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn is_waiting(&self, waker: &Waker) -> bool {
+        self.waiters.lock().iter().any(|(_, w)| w.will_wake(waker))
+    }
+}
+
+impl FileLike for UserFaultFd {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        if dst.remaining_mut() < UFFD_MSG_LEN {
+            return Err(AxError::InvalidInput);
+        }
+        let features = self.features.lock().ok_or(AxError::InvalidInput)?;
+
+        Poller::new(self, IoEvents::IN)
+            .non_blocking(self.nonblocking())
+            .poll(|| {
+                let mut messages = self.messages.lock();
+                if messages.is_empty() {
+                    return Err(AxError::WouldBlock);
+                }
+                let mut read = 0;
+                while dst.remaining_mut() >= UFFD_MSG_LEN
+                    && let Some(msg) = messages.pop_front()
+                {
+                    dst.write(&msg.to_bytes(features))?;
+                    read += UFFD_MSG_LEN;
+                }
+                Ok(read)
+            })
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        if cmd == UFFDIO_API {
+            let ptr = arg as *mut UffdioApi;
+            let mut api = ptr.vm_read()?;
+            let mut features = self.features.lock();
+            if features.is_some() {
+                return Err(AxError::InvalidInput);
+            }
+            if api.api != UFFD_API || api.features & !UFFD_FEATURE_THREAD_ID != 0 {
+                api.features = 0;
+                api.ioctls = 0;
+                ptr.vm_write(api)?;
+                return Err(AxError::InvalidInput);
+            }
+            *features = Some(api.features);
+            api.features = UFFD_FEATURE_THREAD_ID;
+            api.ioctls = UFFD_API_IOCTLS;
+            ptr.vm_write(api)?;
+            return Ok(0);
+        }
+
+        // Everything else needs the API handshake first.
+        if self.features.lock().is_none() {
+            return Err(AxError::InvalidInput);
+        }
+        match cmd {
+            UFFDIO_REGISTER => self.register(arg),
+            UFFDIO_UNREGISTER => self.unregister(arg),
+            UFFDIO_WAKE => {
+                let range = (arg as *const UffdioRange).vm_read()?.to_range()?;
+                self.wake(&range);
+                Ok(0)
+            }
+            UFFDIO_COPY => self.copy(arg),
+            UFFDIO_ZEROPAGE => self.zeropage(arg),
+            _ => Err(AxError::BadIoctl),
+        }
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<str> {
+        "anon_inode:[userfaultfd]".into()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl Pollable for UserFaultFd {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, !self.messages.lock().is_empty());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
+
+impl Drop for UserFaultFd {
+    fn drop(&mut self) {
+        // Once the fd is gone, faults are handled by the kernel again.
+        for (_, waker) in self.waiters.get_mut().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Finds the userfaultfd a fault at `addr` should be reported to: one with a
+/// range covering `addr`, if the fault is for a missing page.
+fn find_context(
+    aspace: &Arc<AxMutex<AddrSpace>>,
+    addr: VirtAddr,
+    flags: MappingFlags,
+) -> Option<Arc<UserFaultFd>> {
+    let page = addr.align_down_4k().as_usize();
+    let uffd = {
+        let mut contexts = CONTEXTS.lock();
+        contexts.retain(|c| c.strong_count() > 0);
+        contexts.iter().filter_map(Weak::upgrade).find(|c| {
+            c.aspace.ptr_eq(&Arc::downgrade(aspace))
+                && c.ranges.lock().iter().any(|r| r.contains(&page))
+        })
+    }?;
+
+    // Only missing pages are reported; other faults, such as copy-on-write,
+    // are resolved as usual.
+    let aspace = aspace.lock();
+    let permitted = aspace
+        .find_area(addr)
+        .is_some_and(|area| area.flags().contains(flags));
+    if !permitted || aspace.page_table().query(addr).is_ok() {
+        return None;
+    }
+    Some(uffd)
+}
+
+/// Whether a fault at `addr` hits a missing page in a range registered with
+/// a userfaultfd.
+///
+/// The kernel can't wait for user space in the middle of accessing user
+/// memory, so such faults fail the access with `EFAULT` instead of being
+/// served with a zero page behind the back of the userfaultfd.
+pub fn is_registered(
+    aspace: &Arc<AxMutex<AddrSpace>>,
+    addr: VirtAddr,
+    flags: MappingFlags,
+) -> bool {
+    find_context(aspace, addr, flags).is_some()
+}
+
+/// Hands a page fault at `addr` over to user space if it hits a missing
+/// page in a range registered with a userfaultfd.
+///
+/// Returns `true` once user space has been told about the fault and the
+/// access should be retried, or `false` if the kernel should handle it.
+pub fn handle_fault(aspace: &Arc<AxMutex<AddrSpace>>, addr: VirtAddr, flags: MappingFlags) -> bool {
+    let page = addr.align_down_4k().as_usize();
+    let Some(uffd) = find_context(aspace, addr, flags) else {
+        return false;
+    };
+
+    // Don't keep the fd alive while waiting, or closing it would never let
+    // us go.
+    let weak = Arc::downgrade(&uffd);
+    drop(uffd);
+    let mut queued = None;
+    let result = block_on(interruptible(poll_fn(|cx| {
+        let Some(uffd) = weak.upgrade() else {
+            return Poll::Ready(());
+        };
+        match &queued {
+            None => {
+                uffd.waiters.lock().push((page, cx.waker().clone()));
+                uffd.messages.lock().push_back(FaultMsg {
+                    address: page,
+                    write: flags.contains(MappingFlags::WRITE),
+                    tid: current().id().as_u64() as u32,
+                });
+                uffd.poll_rx.wake();
+                queued = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Some(waker) if uffd.is_waiting(waker) => Poll::Pending,
+            Some(_) => Poll::Ready(()),
+        }
+    })));
+    if result.is_err()
+        && let (Some(uffd), Some(waker)) = (weak.upgrade(), &queued)
+    {
+        // A signal arrived first; the access is retried after handling it.
+        uffd.waiters.lock().retain(|(_, w)| !w.will_wake(waker));
+    }
+    true
+}
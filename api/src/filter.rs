@@ -0,0 +1,387 @@
+//! Classic BPF socket filters.
+//!
+//! Programs attached with `SO_ATTACH_FILTER` are checked once, the same way
+//! Linux's `bpf_check_classic` does, and then interpreted for every packet
+//! the socket receives. The program returns how many bytes of the packet to
+//! keep, 0 dropping it.
+//!
+//! The network stack hands sockets their payload only, so that is what the
+//! program sees: offset 0 is the first byte of the UDP payload or Unix
+//! datagram, not a link or IP header. Ancillary loads (`SKF_AD_*`) are not
+//! supported and read as out of bounds.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axerrno::{AxError, AxResult};
+use bytemuck::AnyBitPattern;
+use linux_raw_sys::net::socklen_t;
+use spin::RwLock;
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+/// `SO_ATTACH_FILTER`, also `SO_GET_FILTER`.
+const SO_ATTACH_FILTER: u32 = 26;
+/// `SO_DETACH_FILTER`
+const SO_DETACH_FILTER: u32 = 27;
+/// `SO_LOCK_FILTER`
+const SO_LOCK_FILTER: u32 = 44;
+
+/// The longest program accepted.
+const BPF_MAXINSNS: usize = 4096;
+/// The number of scratch memory words.
+const BPF_MEMWORDS: u32 = 16;
+
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// `struct sock_filter`, one instruction.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+#[allow(non_camel_case_types)]
+pub struct sock_filter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// `struct sock_fprog`, as passed to `SO_ATTACH_FILTER`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub struct sock_fprog {
+    pub len: u16,
+    pub filter: *const sock_filter,
+}
+
+/// A checked classic BPF program.
+pub struct Filter {
+    insns: Vec<sock_filter>,
+}
+
+impl Filter {
+    /// Checks `insns` and builds a filter from them.
+    ///
+    /// Fails with `EINVAL` on unknown opcodes, jumps out of the program,
+    /// scratch memory accesses out of range, division by a zero constant, or
+    /// a program that can run past its end.
+    pub fn new(insns: Vec<sock_filter>) -> AxResult<Self> {
+        let len = insns.len();
+        if len == 0 || len > BPF_MAXINSNS {
+            return Err(AxError::InvalidInput);
+        }
+        for (pc, insn) in insns.iter().enumerate() {
+            let code = insn.code;
+            let valid = match code & 0x07 {
+                BPF_LD => match code & !0x07 {
+                    it if it == BPF_W | BPF_ABS
+                        || it == BPF_H | BPF_ABS
+                        || it == BPF_B | BPF_ABS
+                        || it == BPF_W | BPF_IND
+                        || it == BPF_H | BPF_IND
+                        || it == BPF_B | BPF_IND
+                        || it == BPF_W | BPF_IMM
+                        || it == BPF_W | BPF_LEN =>
+                    {
+                        true
+                    }
+                    it if it == BPF_W | BPF_MEM => insn.k < BPF_MEMWORDS,
+                    _ => false,
+                },
+                BPF_LDX => match code & !0x07 {
+                    it if it == BPF_W | BPF_IMM
+                        || it == BPF_W | BPF_LEN
+                        || it == BPF_B | BPF_MSH =>
+                    {
+                        true
+                    }
+                    it if it == BPF_W | BPF_MEM => insn.k < BPF_MEMWORDS,
+                    _ => false,
+                },
+                BPF_ST | BPF_STX => code & !0x07 == 0 && insn.k < BPF_MEMWORDS,
+                BPF_ALU => {
+                    let op = code & 0xf0;
+                    let src = code & BPF_X;
+                    if code & !(0xf0 | BPF_X | 0x07) != 0 {
+                        false
+                    } else {
+                        match op {
+                            BPF_NEG => src == BPF_K,
+                            BPF_DIV | BPF_MOD => src == BPF_X || insn.k != 0,
+                            BPF_LSH | BPF_RSH => src == BPF_X || insn.k < 32,
+                            BPF_ADD | BPF_SUB | BPF_MUL | BPF_OR | BPF_AND | BPF_XOR => true,
+                            _ => false,
+                        }
+                    }
+                }
+                BPF_JMP => {
+                    let remaining = len - pc - 1;
+                    if code & !(0xf0 | BPF_X | 0x07) != 0 {
+                        false
+                    } else {
+                        match code & 0xf0 {
+                            BPF_JA => code & BPF_X == 0 && (insn.k as usize) < remaining,
+                            BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET => {
+                                (insn.jt as usize) < remaining && (insn.jf as usize) < remaining
+                            }
+                            _ => false,
+                        }
+                    }
+                }
+                BPF_RET => code == BPF_RET | BPF_K || code == BPF_RET | BPF_A,
+                BPF_MISC => code == BPF_MISC | BPF_TAX || code == BPF_MISC | BPF_TXA,
+                _ => false,
+            };
+            if !valid {
+                return Err(AxError::InvalidInput);
+            }
+        }
+        // Every path ends in a return as jumps only go forward, as long as
+        // the last instruction is one.
+        if insns[len - 1].code & 0x07 != BPF_RET {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(Self { insns })
+    }
+
+    /// Returns the instructions of the program.
+    pub fn insns(&self) -> &[sock_filter] {
+        &self.insns
+    }
+
+    /// Runs the program over `packet`, returning how many bytes to keep.
+    pub fn run(&self, packet: &[u8]) -> usize {
+        let load = |offset: u32, size: usize| -> Option<u32> {
+            let offset = offset as usize;
+            let bytes = packet.get(offset..offset.checked_add(size)?)?;
+            Some(bytes.iter().fold(0, |acc, &b| acc << 8 | b as u32))
+        };
+        let size = |code: u16| match code & 0x18 {
+            BPF_W => 4,
+            BPF_H => 2,
+            _ => 1,
+        };
+
+        let (mut a, mut x) = (0u32, 0u32);
+        let mut mem = [0u32; BPF_MEMWORDS as usize];
+        let mut pc = 0;
+        loop {
+            let insn = &self.insns[pc];
+            let k = insn.k;
+            pc += 1;
+            match insn.code & 0x07 {
+                BPF_LD => {
+                    a = match insn.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_MEM => mem[k as usize],
+                        BPF_LEN => packet.len() as u32,
+                        BPF_ABS => match load(k, size(insn.code)) {
+                            Some(val) => val,
+                            None => return 0,
+                        },
+                        _ => match load(x.wrapping_add(k), size(insn.code)) {
+                            Some(val) => val,
+                            None => return 0,
+                        },
+                    }
+                }
+                BPF_LDX => {
+                    x = match insn.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_MEM => mem[k as usize],
+                        BPF_LEN => packet.len() as u32,
+                        // The IP header length, `4 * (P[k] & 0xf)`.
+                        _ => match load(k, 1) {
+                            Some(val) => (val & 0xf) << 2,
+                            None => return 0,
+                        },
+                    }
+                }
+                BPF_ST => mem[k as usize] = a,
+                BPF_STX => mem[k as usize] = x,
+                BPF_ALU => {
+                    let src = if insn.code & BPF_X != 0 { x } else { k };
+                    a = match insn.code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(src),
+                        BPF_SUB => a.wrapping_sub(src),
+                        BPF_MUL => a.wrapping_mul(src),
+                        BPF_DIV => match a.checked_div(src) {
+                            Some(val) => val,
+                            None => return 0,
+                        },
+                        BPF_MOD => match a.checked_rem(src) {
+                            Some(val) => val,
+                            None => return 0,
+                        },
+                        BPF_OR => a | src,
+                        BPF_AND => a & src,
+                        BPF_LSH => a.checked_shl(src).unwrap_or(0),
+                        BPF_RSH => a.checked_shr(src).unwrap_or(0),
+                        BPF_NEG => a.wrapping_neg(),
+                        _ => a ^ src,
+                    }
+                }
+                BPF_JMP => {
+                    let src = if insn.code & BPF_X != 0 { x } else { k };
+                    let taken = match insn.code & 0xf0 {
+                        BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == src,
+                        BPF_JGT => a > src,
+                        BPF_JGE => a >= src,
+                        _ => a & src != 0,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET => {
+                    return if insn.code & BPF_A != 0 { a } else { k } as usize;
+                }
+                _ => {
+                    if insn.code & BPF_TXA != 0 {
+                        a = x;
+                    } else {
+                        x = a;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The filter attached to a socket, managed through the `SOL_SOCKET`
+/// options.
+#[derive(Default)]
+pub struct SocketFilter {
+    filter: RwLock<Option<Arc<Filter>>>,
+    /// `SO_LOCK_FILTER`: whether the filter can no longer be changed.
+    locked: AtomicBool,
+}
+
+impl SocketFilter {
+    /// Returns the attached filter.
+    pub fn get(&self) -> Option<Arc<Filter>> {
+        self.filter.read().clone()
+    }
+
+    /// Handles `setsockopt(SOL_SOCKET, optname)`, returning whether
+    /// `optname` is one of the filter options.
+    pub fn set_option(&self, optname: u32, optval: *const u8, optlen: socklen_t) -> AxResult<bool> {
+        let read_int = || -> AxResult<i32> {
+            if optlen as usize != size_of::<i32>() {
+                return Err(AxError::InvalidInput);
+            }
+            Ok(optval.cast::<i32>().vm_read()?)
+        };
+        match optname {
+            SO_ATTACH_FILTER | SO_DETACH_FILTER if self.locked.load(Ordering::Acquire) => {
+                return Err(AxError::OperationNotPermitted);
+            }
+            SO_ATTACH_FILTER => {
+                if optlen as usize != size_of::<sock_fprog>() {
+                    return Err(AxError::InvalidInput);
+                }
+                let prog = unsafe { optval.cast::<sock_fprog>().vm_read_uninit()?.assume_init() };
+                if prog.len == 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                let insns = vm_load(prog.filter, prog.len as usize)?;
+                *self.filter.write() = Some(Arc::new(Filter::new(insns)?));
+            }
+            SO_DETACH_FILTER => {
+                self.filter.write().take().ok_or(AxError::NotFound)?;
+            }
+            SO_LOCK_FILTER => {
+                let locked = read_int()? != 0;
+                // The lock can't be released once taken.
+                if !locked && self.locked.load(Ordering::Acquire) {
+                    return Err(AxError::OperationNotPermitted);
+                }
+                self.locked.store(locked, Ordering::Release);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Handles `getsockopt(SOL_SOCKET, optname)`, returning whether
+    /// `optname` is one of the filter options.
+    pub fn get_option(
+        &self,
+        optname: u32,
+        optval: *mut u8,
+        optlen: &mut socklen_t,
+    ) -> AxResult<bool> {
+        match optname {
+            // `SO_GET_FILTER`, where the length counts instructions.
+            SO_ATTACH_FILTER => {
+                let Some(filter) = self.get() else {
+                    *optlen = 0;
+                    return Ok(true);
+                };
+                let insns = filter.insns();
+                if *optlen != 0 {
+                    if (*optlen as usize) < insns.len() {
+                        return Err(AxError::InvalidInput);
+                    }
+                    vm_write_slice(optval.cast::<sock_filter>(), insns)?;
+                }
+                *optlen = insns.len() as _;
+            }
+            SO_LOCK_FILTER => {
+                if (*optlen as usize) < size_of::<i32>() {
+                    return Err(AxError::InvalidInput);
+                }
+                *optlen = size_of::<i32>() as _;
+                optval
+                    .cast::<i32>()
+                    .vm_write(self.locked.load(Ordering::Acquire) as _)?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
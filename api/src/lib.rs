@@ -10,14 +10,23 @@ extern crate axlog;
 
 extern crate alloc;
 
+pub mod cpufreq;
+pub mod crypto;
 pub mod file;
+pub mod filter;
 pub mod io;
 pub mod mm;
+pub mod net;
+pub mod oom;
+pub mod panic;
+pub mod pci;
+pub mod power;
 pub mod signal;
 pub mod socket;
 pub mod syscall;
 pub mod task;
 pub mod terminal;
+pub mod thermal;
 pub mod time;
 pub mod vfs;
 
@@ -26,14 +35,34 @@ pub fn init() {
     if axconfig::plat::CPU_NUM > 1 {
         panic!("SMP is not supported");
     }
+    starry_core::cmdline::init();
+    starry_core::cpu::init(power::stop_this_cpu);
+
+    info!("Initialize platform devices...");
+    pci::init();
+    vfs::dev::gpio::probe();
+    vfs::dev::i2c::probe();
+    vfs::dev::spi::probe();
+    vfs::dev::uio::probe();
+    vfs::dev::usb::probe();
+    vfs::dev::hv::probe();
+
+    info!("Initialize CPU frequency scaling and thermal management...");
+    cpufreq::init();
+    thermal::init();
+
     info!("Initialize VFS...");
     vfs::mount_all().expect("Failed to mount vfs");
 
-    info!("Initialize /proc/interrupts...");
+    info!("Initialize static network config...");
+    net::apply_static_config();
+
+    info!("Initialize tick accounting...");
     axtask::register_timer_callback(|_| {
         time::inc_irq_cnt();
+        cpufreq::account_tick();
     });
 
-    info!("Initialize alarm...");
-    starry_core::time::spawn_alarm_task();
+    info!("Initialize ksoftirqd...");
+    starry_core::timer::spawn_ksoftirqd();
 }
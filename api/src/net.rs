@@ -0,0 +1,134 @@
+//! Static network configuration.
+//!
+//! Boards without a DHCP server configure their interfaces from
+//! [`STATIC_CONFIG_PATH`], which uses the familiar `ifupdown` syntax:
+//!
+//! ```text
+//! iface eth0 inet static
+//!     address 192.168.1.10
+//!     netmask 255.255.255.0
+//!     gateway 192.168.1.1
+//! ```
+//!
+//! Only `inet static` stanzas are understood; everything else is ignored.
+
+use alloc::{string::String, vec::Vec};
+use core::net::Ipv4Addr;
+
+use axerrno::{AxError, AxResult};
+use axfs_ng::FS_CONTEXT;
+use axnet::device::{interface_by_name, set_default_gateway};
+
+/// Path of the static network configuration file.
+pub const STATIC_CONFIG_PATH: &str = "/etc/network/interfaces";
+
+#[derive(Default)]
+struct Stanza {
+    name: String,
+    address: Option<Ipv4Addr>,
+    prefix: Option<u8>,
+    gateway: Option<Ipv4Addr>,
+}
+
+impl Stanza {
+    fn apply(self) -> AxResult<()> {
+        let iface = interface_by_name(&self.name).ok_or(AxError::NotFound)?;
+        if let Some(address) = self.address {
+            iface.set_ipv4(address, self.prefix.unwrap_or(24))?;
+        }
+        iface.set_up(true)?;
+        if let Some(gateway) = self.gateway {
+            set_default_gateway(gateway)?;
+        }
+        info!(
+            "net: {} configured as {:?}/{:?}, gateway {:?}",
+            self.name, self.address, self.prefix, self.gateway
+        );
+        Ok(())
+    }
+}
+
+fn parse_netmask(mask: &str) -> Option<u8> {
+    if let Ok(prefix) = mask.parse::<u8>() {
+        return (prefix <= 32).then_some(prefix);
+    }
+    let mask = mask.parse::<Ipv4Addr>().ok()?.to_bits();
+    (mask.leading_ones() + mask.trailing_zeros() == 32).then_some(mask.leading_ones() as u8)
+}
+
+fn parse(config: &str) -> AxResult<Vec<Stanza>> {
+    let mut stanzas = Vec::new();
+    let mut current: Option<Stanza> = None;
+    for (lineno, line) in config.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut words = line.split_whitespace();
+        let Some(key) = words.next() else {
+            continue;
+        };
+        let value = words.next();
+        let invalid = || {
+            warn!(
+                "{STATIC_CONFIG_PATH}:{}: invalid line {:?}",
+                lineno + 1,
+                line
+            );
+            AxError::InvalidInput
+        };
+        match key {
+            "iface" => {
+                stanzas.extend(current.take());
+                let name = value.ok_or_else(invalid)?;
+                if words.next() == Some("inet") && words.next() == Some("static") {
+                    current = Some(Stanza {
+                        name: name.into(),
+                        ..Default::default()
+                    });
+                }
+            }
+            "auto" | "allow-hotplug" => {}
+            _ => {
+                let Some(stanza) = current.as_mut() else {
+                    continue;
+                };
+                let value = value.ok_or_else(invalid)?;
+                match key {
+                    "address" => {
+                        let (addr, prefix) = match value.split_once('/') {
+                            Some((addr, prefix)) => (addr, Some(prefix)),
+                            None => (value, None),
+                        };
+                        stanza.address = Some(addr.parse().map_err(|_| invalid())?);
+                        if let Some(prefix) = prefix {
+                            stanza.prefix = Some(parse_netmask(prefix).ok_or_else(invalid)?);
+                        }
+                    }
+                    "netmask" => stanza.prefix = Some(parse_netmask(value).ok_or_else(invalid)?),
+                    "gateway" => stanza.gateway = Some(value.parse().map_err(|_| invalid())?),
+                    _ => {}
+                }
+            }
+        }
+    }
+    stanzas.extend(current);
+    Ok(stanzas)
+}
+
+/// Applies [`STATIC_CONFIG_PATH`] to the network interfaces, if it exists.
+pub fn apply_static_config() {
+    let Ok(config) = FS_CONTEXT.lock().read_to_string(STATIC_CONFIG_PATH) else {
+        return;
+    };
+    let stanzas = match parse(&config) {
+        Ok(stanzas) => stanzas,
+        Err(err) => {
+            warn!("Failed to parse {STATIC_CONFIG_PATH}: {err:?}");
+            return;
+        }
+    };
+    for stanza in stanzas {
+        let name = stanza.name.clone();
+        if let Err(err) = stanza.apply() {
+            warn!("Failed to configure {name}: {err:?}");
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Out-of-memory handling.
+//!
+//! When a user page fault can't get a frame, the kernel caches are emptied
+//! first. If that doesn't free anything, a process is picked based on how
+//! much memory it uses and its `oom_score_adj`, and killed with `SIGKILL` to
+//! make room.
+//!
+//! Only user page faults get here: running out of kernel heap still ends in
+//! the allocator's failure handler.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use memory_addr::PAGE_SIZE_4K;
+use starry_core::{
+    mm::shrink_caches,
+    sysctl::{MIN_FREE_KBYTES, PANIC_ON_OOM},
+    task::{AsThread, ProcessData, get_process_data, get_task, processes, send_signal_to_process},
+};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
+
+/// An `oom_score_adj` that exempts the process from the OOM killer.
+pub const OOM_SCORE_ADJ_MIN: i32 = -1000;
+/// The largest `oom_score_adj`.
+pub const OOM_SCORE_ADJ_MAX: i32 = 1000;
+
+/// The last process killed, so that we wait for it to exit rather than
+/// killing another one for the same shortage.
+static OOM_VICTIM: AtomicU32 = AtomicU32::new(0);
+
+/// Whether free memory has dropped below `vm.min_free_kbytes`.
+pub fn under_pressure() -> bool {
+    let free = axalloc::global_allocator().available_pages() * (PAGE_SIZE_4K / 1024);
+    free < MIN_FREE_KBYTES.get()
+}
+
+/// Returns the badness of `proc_data`, as reported by
+/// `/proc/[pid]/oom_score`, or `None` if it must not be killed.
+///
+/// The score is the share of memory the process uses, in thousandths,
+/// shifted by its `oom_score_adj`.
+pub fn oom_score(proc_data: &ProcessData) -> Option<u32> {
+    let pid = proc_data.proc.pid();
+    // Killing init would take down the whole system.
+    if pid == 1 || proc_data.proc.is_zombie() {
+        return None;
+    }
+    let adj = get_task(pid).map_or(0, |task| task.as_thread().oom_score_adj());
+    if adj <= OOM_SCORE_ADJ_MIN {
+        return None;
+    }
+
+    let allocator = axalloc::global_allocator();
+    let total = (allocator.used_pages() + allocator.available_pages()).max(1);
+    let usage = (proc_data.rss_pages() * 1000 / total) as i64;
+    Some((usage + adj as i64).clamp(1, 2000) as u32)
+}
+
+/// Tries to free memory, first by emptying the kernel caches and then by
+/// killing the process with the highest badness.
+///
+/// Returns `false` if nothing could be freed.
+pub fn out_of_memory() -> bool {
+    let freed = shrink_caches();
+    if freed > 0 {
+        info!(
+            "Low on memory: reclaimed {} kB from caches",
+            freed * PAGE_SIZE_4K / 1024
+        );
+        return true;
+    }
+
+    // Give the previous victim time to exit and release its memory.
+    let victim = OOM_VICTIM.load(Ordering::Acquire);
+    if victim != 0
+        && let Ok(proc_data) = get_process_data(victim)
+        && !proc_data.proc.is_zombie()
+    {
+        return true;
+    }
+
+    if PANIC_ON_OOM.get() != 0 {
+        panic!("Out of memory: panic_on_oom is enabled");
+    }
+
+    let Some((score, proc_data)) = processes()
+        .into_iter()
+        .filter_map(|proc_data| Some((oom_score(&proc_data)?, proc_data)))
+        .max_by_key(|(score, _)| *score)
+    else {
+        warn!("Out of memory and no killable processes");
+        return false;
+    };
+
+    let pid: Pid = proc_data.proc.pid();
+    warn!(
+        "Out of memory: killed process {} ({}), rss: {} kB, oom_score: {}",
+        pid,
+        proc_data.exe_path.read(),
+        proc_data.rss_pages() * PAGE_SIZE_4K / 1024,
+        score
+    );
+    OOM_VICTIM.store(pid, Ordering::Release);
+    send_signal_to_process(pid, Some(SignalInfo::new_kernel(Signo::SIGKILL))).is_ok()
+}
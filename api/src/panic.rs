@@ -0,0 +1,60 @@
+//! Kernel panic reports.
+//!
+//! Besides the message, a report tells which task was running and where it
+//! was in user space, unwinds the kernel stack through the frame pointers,
+//! symbolizing it from the DWARF info when the kernel is built with it, and
+//! dumps the last trace entries if `kernel/panic_print` has `0x10` set.
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use axbacktrace::Backtrace;
+use axhal::percpu::this_cpu_id;
+use starry_core::{sysctl::PANIC_PRINT, task::AsThread, trace};
+
+/// The `kernel/panic_print` bit that dumps the trace buffers.
+const PANIC_PRINT_FTRACE: usize = 0x10;
+
+/// The number of trace entries dumped for each CPU.
+const TRACE_ENTRIES: usize = 32;
+
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Prints the report of a panic. Registered as the panic hook of the runtime.
+pub fn report(info: &PanicInfo) {
+    ax_println!("{info}");
+    // A panic while reporting only gets its message.
+    if REPORTING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    match axtask::current_may_uninit() {
+        Some(curr) => {
+            ax_println!(
+                "CPU {} running task {:?} (tid {})",
+                this_cpu_id(),
+                curr.name(),
+                curr.id().as_u64()
+            );
+            if let Some(thr) = curr.try_as_thread() {
+                let proc_data = &thr.proc_data;
+                let (pc, sp) = thr.user_context();
+                match proc_data.exe_path.try_read() {
+                    Some(exe) => ax_println!("  process {} ({})", proc_data.proc.pid(), *exe),
+                    None => ax_println!("  process {}", proc_data.proc.pid()),
+                }
+                ax_println!("  user pc={pc:#x} sp={sp:#x}");
+            }
+        }
+        None => ax_println!("CPU {} running no task", this_cpu_id()),
+    }
+
+    ax_println!("{}", Backtrace::capture());
+
+    if PANIC_PRINT.get() & PANIC_PRINT_FTRACE != 0 {
+        ax_println!("Last trace entries:");
+        trace::for_each_recent(TRACE_ENTRIES, |entry| ax_print!("{entry}"));
+    }
+}
@@ -0,0 +1,684 @@
+//! PCI devices, as found behind the host bridges registered by the platform.
+//!
+//! A host bridge only provides configuration space access; the buses behind
+//! it are scanned when it is registered, sizing the BARs of each function
+//! found. The ECAM window of the platform configuration, which axdriver
+//! probes its own PCI devices through, is registered by [`init`], along with
+//! the MSI controller of the platform: the GICv2m frames in the device tree
+//! on Arm, or the local APIC on x86. The devices are exposed in
+//! `/sys/bus/pci/devices` and `/proc/bus/pci`, where privileged processes may
+//! write the configuration space and map the memory BARs.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{fmt, ops::RangeInclusive};
+
+use axerrno::{AxError, AxResult};
+use axhal::mem::phys_to_virt;
+use axtask::current;
+use kspin::SpinNoIrq;
+use memory_addr::PhysAddr;
+use spin::{Once, RwLock};
+use starry_core::task::AsThread;
+
+mod msi;
+
+/// The size of the configuration space of conventional PCI functions.
+pub const PCI_CFG_SPACE_SIZE: usize = 256;
+/// The size of the configuration space of PCI Express functions.
+pub const PCI_CFG_SPACE_EXP_SIZE: usize = 4096;
+/// How much of the configuration space unprivileged processes may read,
+/// the standard header.
+pub const PCI_CFG_SPACE_PUBLIC: usize = 64;
+
+const PCI_VENDOR_ID: u16 = 0x00;
+const PCI_COMMAND: u16 = 0x04;
+const PCI_STATUS: u16 = 0x06;
+const PCI_CLASS_REVISION: u16 = 0x08;
+const PCI_HEADER_TYPE: u16 = 0x0e;
+const PCI_BASE_ADDRESS_0: u16 = 0x10;
+const PCI_SUBSYSTEM_VENDOR_ID: u16 = 0x2c;
+const PCI_CAPABILITY_LIST: u16 = 0x34;
+const PCI_INTERRUPT_LINE: u16 = 0x3c;
+
+const PCI_COMMAND_IO: u16 = 0x1;
+const PCI_COMMAND_MEMORY: u16 = 0x2;
+const PCI_COMMAND_MASTER: u16 = 0x4;
+const PCI_COMMAND_INTX_DISABLE: u16 = 0x400;
+const PCI_STATUS_INTERRUPT: u16 = 0x8;
+const PCI_STATUS_CAP_LIST: u16 = 0x10;
+const PCI_HEADER_TYPE_MASK: u8 = 0x7f;
+const PCI_HEADER_TYPE_NORMAL: u8 = 0;
+const PCI_HEADER_TYPE_BRIDGE: u8 = 1;
+const PCI_HEADER_MULTI_FUNCTION: u8 = 0x80;
+const PCI_CAP_ID_MSI: u8 = 0x05;
+const PCI_CAP_ID_EXP: u8 = 0x10;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+const PCI_MSI_FLAGS_ENABLE: u16 = 0x1;
+const PCI_MSI_FLAGS_QSIZE: u16 = 0x70;
+const PCI_MSI_FLAGS_64BIT: u16 = 0x80;
+const PCI_MSIX_FLAGS_MASKALL: u16 = 0x4000;
+const PCI_MSIX_FLAGS_ENABLE: u16 = 0x8000;
+const PCI_MSIX_TABLE_BIR: u32 = 0x7;
+
+const PCI_BASE_ADDRESS_SPACE_IO: u32 = 0x1;
+const PCI_BASE_ADDRESS_MEM_TYPE_64: u32 = 0x4;
+const PCI_BASE_ADDRESS_MEM_PREFETCH: u32 = 0x8;
+
+/// `IORESOURCE_*`, the flags reported for each resource.
+pub const IORESOURCE_IO: u64 = 0x0000_0100;
+pub const IORESOURCE_MEM: u64 = 0x0000_0200;
+pub const IORESOURCE_PREFETCH: u64 = 0x0000_2000;
+pub const IORESOURCE_MEM_64: u64 = 0x0010_0000;
+
+/// Access to the configuration space of the functions behind a host bridge,
+/// e.g. through ECAM.
+pub trait PciHostBridge: Send + Sync {
+    /// Returns the PCI segment (domain) of the bridge.
+    fn segment(&self) -> u16 {
+        0
+    }
+
+    /// Returns the buses behind the bridge.
+    fn bus_range(&self) -> RangeInclusive<u8>;
+
+    /// Returns whether the extended configuration space of PCI Express
+    /// functions, past the first 256 bytes, is reachable.
+    fn extended_config(&self) -> bool;
+
+    /// Reads the dword at `offset`, which is 4-byte aligned. Functions that
+    /// don't exist read as all ones.
+    fn read_config(&self, address: PciAddress, offset: u16) -> u32;
+
+    /// Writes the dword at `offset`, which is 4-byte aligned.
+    fn write_config(&self, address: PciAddress, offset: u16, value: u32);
+
+    /// Translates a PCI bus address to a CPU physical address, for bridges
+    /// whose memory windows aren't identity-mapped.
+    fn bus_to_phys(&self, address: u64) -> u64 {
+        address
+    }
+}
+
+/// The message a function writes to raise an MSI.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// The part of the interrupt controller that receives MSIs, such as the
+/// GICv3 ITS or the local APICs.
+pub trait MsiController: Send + Sync {
+    /// Allocates an interrupt for `address`, returning its number, as
+    /// handlers are registered for with `axhal::irq::register`, and the
+    /// message that raises it.
+    fn alloc(&self, address: PciAddress) -> AxResult<(usize, MsiMessage)>;
+}
+
+static MSI_CONTROLLER: Once<Arc<dyn MsiController>> = Once::new();
+
+/// Sets the controller MSIs are allocated from.
+pub fn register_msi_controller(controller: Arc<dyn MsiController>) {
+    MSI_CONTROLLER.call_once(|| controller);
+}
+
+/// The address of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Returns the device and function number packed as in `devfn`.
+    pub fn devfn(&self) -> u8 {
+        self.device << 3 | self.function
+    }
+}
+
+impl fmt::Display for PciAddress {
+    /// Formats the address as Linux names devices, e.g. `0000:00:1f.3`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A BAR, or the region it decodes once sized.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PciResource {
+    /// The CPU physical address of the region.
+    pub start: u64,
+    pub size: u64,
+    /// `IORESOURCE_*` flags, 0 for BARs that aren't implemented.
+    pub flags: u64,
+}
+
+impl PciResource {
+    pub fn is_mem(&self) -> bool {
+        self.flags & IORESOURCE_MEM != 0
+    }
+
+    /// Returns the last address of the region, as printed in `resource`.
+    pub fn end(&self) -> u64 {
+        if self.size == 0 {
+            0
+        } else {
+            self.start + self.size - 1
+        }
+    }
+}
+
+/// A PCI function.
+pub struct PciDevice {
+    host: Arc<dyn PciHostBridge>,
+    address: PciAddress,
+    vendor: u16,
+    device: u16,
+    /// The class code, revision ID excluded.
+    class: u32,
+    revision: u8,
+    header_type: u8,
+    subsystem_vendor: u16,
+    subsystem_device: u16,
+    irq: u8,
+    config_size: usize,
+    resources: Vec<PciResource>,
+    /// Serializes the read-modify-write of partial dwords, also done by
+    /// interrupt handlers.
+    config_lock: SpinNoIrq<()>,
+}
+
+impl PciDevice {
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    pub fn vendor(&self) -> u16 {
+        self.vendor
+    }
+
+    pub fn device(&self) -> u16 {
+        self.device
+    }
+
+    pub fn class(&self) -> u32 {
+        self.class
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    pub fn subsystem_vendor(&self) -> u16 {
+        self.subsystem_vendor
+    }
+
+    pub fn subsystem_device(&self) -> u16 {
+        self.subsystem_device
+    }
+
+    /// Returns the legacy interrupt line, as routed by the firmware.
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type & PCI_HEADER_TYPE_MASK == PCI_HEADER_TYPE_BRIDGE
+    }
+
+    /// Returns the size of the configuration space, 256 or 4096 bytes.
+    pub fn config_size(&self) -> usize {
+        self.config_size
+    }
+
+    /// Returns the BARs, 6 for devices and 2 for bridges.
+    pub fn resources(&self) -> &[PciResource] {
+        &self.resources
+    }
+
+    /// Returns where capability `id` is in the configuration space.
+    pub fn capability(&self, id: u8) -> Option<u16> {
+        let _guard = self.config_lock.lock();
+        find_capability(&*self.host, self.address, id)
+    }
+
+    fn modify_command(&self, f: impl FnOnce(u16) -> u16) {
+        let _guard = self.config_lock.lock();
+        let command = read16(&*self.host, self.address, PCI_COMMAND);
+        write16(&*self.host, self.address, PCI_COMMAND, f(command));
+    }
+
+    /// Returns whether the function asserts its legacy interrupt.
+    pub fn intx_pending(&self) -> bool {
+        let _guard = self.config_lock.lock();
+        read16(&*self.host, self.address, PCI_STATUS) & PCI_STATUS_INTERRUPT != 0
+    }
+
+    /// Keeps the function from asserting its legacy interrupt, or lets it
+    /// again.
+    pub fn set_intx_disabled(&self, disabled: bool) {
+        self.modify_command(|command| {
+            if disabled {
+                command | PCI_COMMAND_INTX_DISABLE
+            } else {
+                command & !PCI_COMMAND_INTX_DISABLE
+            }
+        });
+    }
+
+    /// Enables the memory BARs of the function and lets it access memory
+    /// itself, for kernel drivers doing DMA.
+    pub fn set_master(&self) {
+        self.modify_command(|command| command | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
+    }
+
+    /// Switches the function to a single MSI-X or MSI vector, returning the
+    /// interrupt it raises.
+    ///
+    /// The MSI-X table is written through the kernel mapping of the BAR
+    /// holding it, which must be among the device memory the platform maps.
+    pub fn enable_msi(&self) -> AxResult<usize> {
+        let controller = MSI_CONTROLLER.get().ok_or(AxError::Unsupported)?;
+        let msix = self.capability(PCI_CAP_ID_MSIX);
+        let msi = self.capability(PCI_CAP_ID_MSI);
+        if msix.is_none() && msi.is_none() {
+            return Err(AxError::Unsupported);
+        }
+        let (irq, message) = controller.alloc(self.address)?;
+        // MSIs are memory writes by the function.
+        self.modify_command(|command| {
+            command | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE
+        });
+
+        let host = &*self.host;
+        let _guard = self.config_lock.lock();
+        if let Some(cap) = msix {
+            let table = host.read_config(self.address, cap + 4);
+            let bar = self
+                .resources
+                .get((table & PCI_MSIX_TABLE_BIR) as usize)
+                .filter(|bar| bar.is_mem())
+                .ok_or(AxError::InvalidData)?;
+            let entry = bar.start + (table & !PCI_MSIX_TABLE_BIR) as u64;
+            let flags = read16(host, self.address, cap + 2);
+            write16(
+                host,
+                self.address,
+                cap + 2,
+                flags | PCI_MSIX_FLAGS_ENABLE | PCI_MSIX_FLAGS_MASKALL,
+            );
+            let entry = phys_to_virt(PhysAddr::from(entry as usize))
+                .as_mut_ptr()
+                .cast::<u32>();
+            // SAFETY: the entry is in the MSI-X table of the function, which
+            // only the function itself decodes.
+            unsafe {
+                entry.write_volatile(message.address as u32);
+                entry.add(1).write_volatile((message.address >> 32) as u32);
+                entry.add(2).write_volatile(message.data);
+                // Unmask the vector, the others stay masked.
+                entry.add(3).write_volatile(0);
+            }
+            write16(
+                host,
+                self.address,
+                cap + 2,
+                (flags | PCI_MSIX_FLAGS_ENABLE) & !PCI_MSIX_FLAGS_MASKALL,
+            );
+        } else if let Some(cap) = msi {
+            let flags = read16(host, self.address, cap + 2);
+            host.write_config(self.address, cap + 4, message.address as u32);
+            let data = if flags & PCI_MSI_FLAGS_64BIT != 0 {
+                host.write_config(self.address, cap + 8, (message.address >> 32) as u32);
+                cap + 12
+            } else if message.address >> 32 != 0 {
+                return Err(AxError::InvalidInput);
+            } else {
+                cap + 8
+            };
+            write16(host, self.address, data, message.data as u16);
+            write16(
+                host,
+                self.address,
+                cap + 2,
+                (flags & !PCI_MSI_FLAGS_QSIZE) | PCI_MSI_FLAGS_ENABLE,
+            );
+        }
+        info!("pci: {} raises MSI {irq}", self.address);
+        Ok(irq)
+    }
+
+    /// Reads the configuration space at `offset` into `buf`, returning how
+    /// much was read.
+    pub fn read_config(&self, buf: &mut [u8], offset: usize) -> usize {
+        let len = buf.len().min(self.config_size.saturating_sub(offset));
+        let _guard = self.config_lock.lock();
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            let pos = offset + i;
+            let dword = self.host.read_config(self.address, (pos & !3) as u16);
+            *byte = (dword >> ((pos & 3) * 8)) as u8;
+        }
+        len
+    }
+
+    /// Writes `buf` to the configuration space at `offset`, returning how
+    /// much was written.
+    ///
+    /// Partial dwords are read back and merged, as the host bridge only
+    /// writes whole dwords.
+    pub fn write_config(&self, buf: &[u8], offset: usize) -> usize {
+        let len = buf.len().min(self.config_size.saturating_sub(offset));
+        let _guard = self.config_lock.lock();
+        let mut pos = offset;
+        while pos < offset + len {
+            let aligned = pos & !3;
+            let end = (aligned + 4).min(offset + len);
+            let mut bytes = if pos == aligned && end == aligned + 4 {
+                [0; 4]
+            } else {
+                self.host
+                    .read_config(self.address, aligned as u16)
+                    .to_le_bytes()
+            };
+            bytes[pos - aligned..end - aligned].copy_from_slice(&buf[pos - offset..end - offset]);
+            self.host
+                .write_config(self.address, aligned as u16, u32::from_le_bytes(bytes));
+            pos = end;
+        }
+        len
+    }
+}
+
+fn read16(host: &dyn PciHostBridge, address: PciAddress, offset: u16) -> u16 {
+    (host.read_config(address, offset & !3) >> ((offset & 2) * 8)) as u16
+}
+
+fn write16(host: &dyn PciHostBridge, address: PciAddress, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let dword = host.read_config(address, offset & !3);
+    let dword = dword & !(0xffff << shift) | (value as u32) << shift;
+    host.write_config(address, offset & !3, dword);
+}
+
+fn read8(host: &dyn PciHostBridge, address: PciAddress, offset: u16) -> u8 {
+    (host.read_config(address, offset & !3) >> ((offset & 3) * 8)) as u8
+}
+
+/// Sizes the BARs, with decoding turned off meanwhile so that the function
+/// doesn't claim the all-ones addresses written to them.
+fn size_bars(host: &dyn PciHostBridge, address: PciAddress, count: usize) -> Vec<PciResource> {
+    let command = read16(host, address, PCI_COMMAND);
+    write16(
+        host,
+        address,
+        PCI_COMMAND,
+        command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY),
+    );
+
+    let mut resources = Vec::with_capacity(count);
+    let mut index = 0;
+    while index < count {
+        let offset = PCI_BASE_ADDRESS_0 + index as u16 * 4;
+        let low = host.read_config(address, offset);
+        host.write_config(address, offset, !0);
+        let low_mask = host.read_config(address, offset);
+        host.write_config(address, offset, low);
+
+        let mut resource = PciResource::default();
+        if low & PCI_BASE_ADDRESS_SPACE_IO != 0 {
+            let size = (!(low_mask & !0x3) as u16).wrapping_add(1) as u64;
+            if low_mask != 0 && size != 0 {
+                resource = PciResource {
+                    start: (low & !0x3) as u64,
+                    size,
+                    flags: IORESOURCE_IO,
+                };
+            }
+        } else {
+            let is_64 = low & 0x6 == PCI_BASE_ADDRESS_MEM_TYPE_64 && index + 1 < count;
+            let (base, mask) = if is_64 {
+                let high = host.read_config(address, offset + 4);
+                host.write_config(address, offset + 4, !0);
+                let high_mask = host.read_config(address, offset + 4);
+                host.write_config(address, offset + 4, high);
+                (
+                    (high as u64) << 32 | (low & !0xf) as u64,
+                    (high_mask as u64) << 32 | (low_mask & !0xf) as u64,
+                )
+            } else {
+                ((low & !0xf) as u64, (low_mask & !0xf) as u64 | !0u64 << 32)
+            };
+            if low_mask & !0xf != 0 || (is_64 && mask >> 32 != 0) {
+                let mut flags = IORESOURCE_MEM;
+                if low & PCI_BASE_ADDRESS_MEM_PREFETCH != 0 {
+                    flags |= IORESOURCE_PREFETCH;
+                }
+                if is_64 {
+                    flags |= IORESOURCE_MEM_64;
+                }
+                resource = PciResource {
+                    start: host.bus_to_phys(base),
+                    size: (!mask).wrapping_add(1),
+                    flags,
+                };
+            }
+            if is_64 {
+                resources.push(resource);
+                resource = PciResource::default();
+                index += 1;
+            }
+        }
+        resources.push(resource);
+        index += 1;
+    }
+
+    write16(host, address, PCI_COMMAND, command);
+    resources
+}
+
+/// Returns where capability `id` of the function is.
+fn find_capability(host: &dyn PciHostBridge, address: PciAddress, id: u8) -> Option<u16> {
+    if read16(host, address, PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+        return None;
+    }
+    let mut pos = read8(host, address, PCI_CAPABILITY_LIST) & !3;
+    // The list lives in the standard configuration space, so it can't be
+    // longer than this unless it loops.
+    for _ in 0..48 {
+        if pos < 0x40 {
+            break;
+        }
+        if read8(host, address, pos as u16) == id {
+            return Some(pos as u16);
+        }
+        pos = read8(host, address, pos as u16 + 1) & !3;
+    }
+    None
+}
+
+fn probe(host: &Arc<dyn PciHostBridge>, address: PciAddress) -> Option<PciDevice> {
+    let id = host.read_config(address, PCI_VENDOR_ID);
+    let vendor = id as u16;
+    if vendor == 0xffff || vendor == 0 {
+        return None;
+    }
+    let class_revision = host.read_config(address, PCI_CLASS_REVISION);
+    let header_type = read8(&**host, address, PCI_HEADER_TYPE);
+    let (bars, subsystem) = match header_type & PCI_HEADER_TYPE_MASK {
+        PCI_HEADER_TYPE_NORMAL => (6, host.read_config(address, PCI_SUBSYSTEM_VENDOR_ID)),
+        PCI_HEADER_TYPE_BRIDGE => (2, 0),
+        // CardBus bridges
+        _ => (0, 0),
+    };
+    let express = find_capability(&**host, address, PCI_CAP_ID_EXP).is_some();
+    let config_size = if host.extended_config() && express {
+        PCI_CFG_SPACE_EXP_SIZE
+    } else {
+        PCI_CFG_SPACE_SIZE
+    };
+    Some(PciDevice {
+        host: host.clone(),
+        address,
+        vendor,
+        device: (id >> 16) as u16,
+        class: class_revision >> 8,
+        revision: class_revision as u8,
+        header_type,
+        subsystem_vendor: subsystem as u16,
+        subsystem_device: (subsystem >> 16) as u16,
+        irq: read8(&**host, address, PCI_INTERRUPT_LINE),
+        config_size,
+        resources: size_bars(&**host, address, bars),
+        config_lock: SpinNoIrq::new(()),
+    })
+}
+
+static DEVICES: RwLock<Vec<Arc<PciDevice>>> = RwLock::new(Vec::new());
+
+/// Adds a host bridge, scanning the buses behind it.
+///
+/// Host bridges must be registered before sysfs is mounted.
+pub fn register_host(host: Arc<dyn PciHostBridge>) {
+    let segment = host.segment();
+    let mut found = Vec::new();
+    for bus in host.bus_range() {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = PciAddress {
+                    segment,
+                    bus,
+                    device,
+                    function,
+                };
+                let Some(dev) = probe(&host, address) else {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                };
+                let multi_function = dev.header_type & PCI_HEADER_MULTI_FUNCTION != 0;
+                info!(
+                    "pci: {address} [{:04x}:{:04x}] class {:06x}",
+                    dev.vendor, dev.device, dev.class
+                );
+                found.push(Arc::new(dev));
+                if function == 0 && !multi_function {
+                    break;
+                }
+            }
+        }
+    }
+    let mut devices = DEVICES.write();
+    devices.extend(found);
+    devices.sort_by_key(|dev| dev.address);
+}
+
+/// The memory-mapped configuration space of the platform configuration.
+struct Ecam {
+    base: usize,
+    buses: RangeInclusive<u8>,
+}
+
+impl Ecam {
+    fn config_addr(&self, address: PciAddress, offset: u16) -> usize {
+        self.base
+            + ((address.bus as usize) << 20
+                | (address.device as usize) << 15
+                | (address.function as usize) << 12
+                | offset as usize)
+    }
+}
+
+impl PciHostBridge for Ecam {
+    fn bus_range(&self) -> RangeInclusive<u8> {
+        self.buses.clone()
+    }
+
+    fn extended_config(&self) -> bool {
+        true
+    }
+
+    fn read_config(&self, address: PciAddress, offset: u16) -> u32 {
+        // SAFETY: the window is mapped by the platform, as axdriver uses it.
+        unsafe { (self.config_addr(address, offset) as *const u32).read_volatile() }
+    }
+
+    fn write_config(&self, address: PciAddress, offset: u16, value: u32) {
+        // SAFETY: the window is mapped by the platform, as axdriver uses it.
+        unsafe { (self.config_addr(address, offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+/// Registers the MSI controller and the host bridge of the platform, if it
+/// has PCI.
+///
+/// BARs are those assigned by the firmware or by axdriver, so this must run
+/// after the drivers have been probed.
+pub fn init() {
+    if let Some(controller) = msi::probe() {
+        register_msi_controller(controller);
+    }
+    let base = axconfig::devices::PCI_ECAM_BASE;
+    if base == 0 {
+        return;
+    }
+    register_host(Arc::new(Ecam {
+        base: phys_to_virt(PhysAddr::from(base)).as_usize(),
+        buses: 0..=axconfig::devices::PCI_BUS_END as u8,
+    }));
+}
+
+/// Returns all PCI functions, in address order.
+pub fn devices() -> Vec<Arc<PciDevice>> {
+    DEVICES.read().clone()
+}
+
+/// Formats a line of `/proc/bus/pci/devices`.
+pub fn proc_devices_line(dev: &PciDevice) -> String {
+    let mut line = format!(
+        "{:02x}{:02x}\t{:04x}{:04x}\t{:x}",
+        dev.address.bus,
+        dev.address.devfn(),
+        dev.vendor,
+        dev.device,
+        dev.irq
+    );
+    // The BARs and the expansion ROM, which isn't sized.
+    let mut resources = dev.resources.clone();
+    resources.resize(7, PciResource::default());
+    for res in &resources {
+        // The low bits of the BAR, as pciutils decodes them.
+        let mut low = 0;
+        if res.flags & IORESOURCE_IO != 0 {
+            low |= PCI_BASE_ADDRESS_SPACE_IO;
+        }
+        if res.flags & IORESOURCE_MEM_64 != 0 {
+            low |= PCI_BASE_ADDRESS_MEM_TYPE_64;
+        }
+        if res.flags & IORESOURCE_PREFETCH != 0 {
+            low |= PCI_BASE_ADDRESS_MEM_PREFETCH;
+        }
+        line += &format!("\t{:016x}", res.start | low as u64);
+    }
+    for res in &resources {
+        line += &format!("\t{:016x}", res.size);
+    }
+    line.push('\n');
+    line
+}
+
+/// Fails unless the current process may touch hardware directly.
+pub fn check_privileged() -> AxResult {
+    if current().as_thread().proc_data.cred.read().is_privileged() {
+        Ok(())
+    } else {
+        Err(AxError::OperationNotPermitted)
+    }
+}
@@ -0,0 +1,165 @@
+//! The MSI controllers of the platforms: the GICv2m frames on Arm, which
+//! turn MSIs into SPIs, and the local APIC on x86, which takes them as
+//! interrupt vectors directly.
+
+use alloc::sync::Arc;
+
+use super::MsiController;
+
+#[cfg(not(target_arch = "x86_64"))]
+mod gicv2m {
+    use alloc::{sync::Arc, vec::Vec};
+
+    use axerrno::{AxError, AxResult};
+    use kspin::SpinNoIrq;
+    use memory_addr::PhysAddr;
+    use starry_core::boot::{self, FdtDevice};
+
+    use crate::pci::{MsiController, MsiMessage, PciAddress};
+
+    /// `MSI_TYPER`, with the interrupt ID of the first SPI of the frame,
+    /// which is what axhal numbers interrupts by, and the number of them.
+    const V2M_MSI_TYPER: usize = 0x008;
+    /// `MSI_SETSPI_NS`, which devices write the SPI number to.
+    const V2M_MSI_SETSPI_NS: usize = 0x040;
+
+    struct Frame {
+        /// The physical address devices write to.
+        doorbell: u64,
+        first_spi: usize,
+        num_spis: usize,
+    }
+
+    /// The GICv2m frames, handing out the SPIs they cover in turn.
+    pub struct Gicv2m {
+        frames: Vec<Frame>,
+        /// The frame and the SPI in it to hand out next.
+        next: SpinNoIrq<(usize, usize)>,
+    }
+
+    impl MsiController for Gicv2m {
+        fn alloc(&self, _address: PciAddress) -> AxResult<(usize, MsiMessage)> {
+            let mut next = self.next.lock();
+            let (frame, spi) = &mut *next;
+            while *frame < self.frames.len() && *spi >= self.frames[*frame].num_spis {
+                *frame += 1;
+                *spi = 0;
+            }
+            let frame = self.frames.get(*frame).ok_or(AxError::NoMemory)?;
+            let irq = frame.first_spi + *spi;
+            *spi += 1;
+            Ok((
+                irq,
+                MsiMessage {
+                    address: frame.doorbell,
+                    data: irq as u32,
+                },
+            ))
+        }
+    }
+
+    fn add(dev: &FdtDevice) -> AxResult<Frame> {
+        let &(paddr, size) = dev.regs().first().ok_or(AxError::InvalidData)?;
+        let base = starry_core::mm::ioremap(PhysAddr::from(paddr as usize), size as usize)?;
+        // SAFETY: the frame was just mapped.
+        let typer = unsafe { ((base.as_usize() + V2M_MSI_TYPER) as *const u32).read_volatile() };
+        // These override `MSI_TYPER`, which is wrong on some implementations.
+        let first_spi = dev
+            .node
+            .property_u32("arm,msi-base-spi")
+            .unwrap_or((typer >> 16) & 0x3ff) as usize;
+        let num_spis = dev
+            .node
+            .property_u32("arm,msi-num-spis")
+            .unwrap_or(typer & 0x3ff) as usize;
+        info!(
+            "pci: MSIs through {} raise SPIs {first_spi}..{}",
+            dev.node.name,
+            first_spi + num_spis
+        );
+        Ok(Frame {
+            doorbell: paddr + V2M_MSI_SETSPI_NS as u64,
+            first_spi,
+            num_spis,
+        })
+    }
+
+    /// Finds the frames in the device tree.
+    pub fn probe() -> Option<Gicv2m> {
+        let mut frames = Vec::new();
+        for dev in boot::find_compatible(&["arm,gic-v2m-frame"]) {
+            match add(&dev) {
+                Ok(frame) => frames.push(frame),
+                Err(err) => warn!("pci: failed to set up {}: {err:?}", dev.node.name),
+            }
+        }
+        (!frames.is_empty()).then(|| Gicv2m {
+            frames,
+            next: SpinNoIrq::new((0, 0)),
+        })
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod lapic {
+    use axerrno::{AxError, AxResult};
+    use kspin::SpinNoIrq;
+
+    use crate::pci::{MsiController, MsiMessage, PciAddress};
+
+    /// The vectors handed out for MSIs, clear of those of the I/O APIC and
+    /// of the local APIC's own interrupts at the top. axhal numbers
+    /// interrupts by their vectors.
+    const MSI_VECTORS: core::ops::Range<usize> = 0x40..0xf0;
+
+    /// The local APIC of the boot CPU, which all MSIs are sent to.
+    pub struct LocalApic {
+        apic_id: u8,
+        next: SpinNoIrq<usize>,
+    }
+
+    impl LocalApic {
+        pub fn new() -> Self {
+            // Interrupts aren't balanced across CPUs, so they all go to the
+            // boot CPU, which this runs on.
+            let apic_id = (unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24) as u8;
+            Self {
+                apic_id,
+                next: SpinNoIrq::new(MSI_VECTORS.start),
+            }
+        }
+    }
+
+    impl MsiController for LocalApic {
+        fn alloc(&self, _address: PciAddress) -> AxResult<(usize, MsiMessage)> {
+            let mut next = self.next.lock();
+            if !MSI_VECTORS.contains(&*next) {
+                return Err(AxError::NoMemory);
+            }
+            let vector = *next;
+            *next += 1;
+            Ok((
+                vector,
+                MsiMessage {
+                    // Physical destination mode, to the one CPU.
+                    address: 0xfee0_0000 | (self.apic_id as u64) << 12,
+                    // Fixed delivery, edge-triggered.
+                    data: vector as u32,
+                },
+            ))
+        }
+    }
+}
+
+/// Finds the MSI controller of the platform.
+pub fn probe() -> Option<Arc<dyn MsiController>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        Some(Arc::new(lapic::LocalApic::new()))
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        gicv2m::probe().map(|it| Arc::new(it) as _)
+    }
+}
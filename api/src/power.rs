@@ -0,0 +1,175 @@
+//! Shutting down, restarting and soft rebooting the system.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use axfs_ng::FS_CONTEXT;
+use axtask::current;
+use starry_core::task::{AsThread, processes, send_signal_to_process};
+use starry_signal::{SignalInfo, Signo};
+
+use crate::vfs;
+
+/// How long processes get to exit after `SIGTERM` before they are killed.
+const TERM_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// What to do once the system is shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    /// Reset the machine.
+    Restart,
+    /// Stop the CPU without powering off.
+    Halt,
+    /// Power the machine off.
+    PowerOff,
+}
+
+static SOFT_REBOOT: AtomicBool = AtomicBool::new(false);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether a soft reboot was requested, clearing the request.
+///
+/// The kernel then starts the init process again once the current one has
+/// exited.
+pub fn take_soft_reboot() -> bool {
+    SOFT_REBOOT.swap(false, Ordering::AcqRel)
+}
+
+/// Sends `signo` to every process but the caller, and waits for up to
+/// `timeout` for them to exit.
+fn signal_all(signo: Signo, timeout: Duration) {
+    let self_pid = current()
+        .try_as_thread()
+        .map(|thr| thr.proc_data.proc.pid());
+    let others = || {
+        processes()
+            .into_iter()
+            .filter(|proc_data| Some(proc_data.proc.pid()) != self_pid)
+            .filter(|proc_data| !proc_data.proc.is_zombie())
+    };
+    for proc_data in others() {
+        let _ = send_signal_to_process(proc_data.proc.pid(), Some(SignalInfo::new_kernel(signo)));
+    }
+
+    let deadline = axhal::time::monotonic_time() + timeout;
+    while others().next().is_some() && axhal::time::monotonic_time() < deadline {
+        axtask::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Stops all other processes and writes back the filesystems.
+fn prepare_shutdown() {
+    info!("Sending SIGTERM to all processes");
+    signal_all(Signo::SIGTERM, TERM_GRACE_PERIOD);
+    info!("Sending SIGKILL to all processes");
+    signal_all(Signo::SIGKILL, TERM_GRACE_PERIOD);
+
+    if let Err(err) = vfs::sync_all() {
+        warn!("Failed to sync filesystems: {err:?}");
+    }
+}
+
+/// Unmounts every filesystem, and writes back the boot filesystem, which
+/// stays mounted as the root.
+fn unmount_all() {
+    if let Err(err) = FS_CONTEXT.lock().root_dir().unmount_all() {
+        warn!("Failed to unmount all filesystems: {err:?}");
+    }
+    if let Err(err) = vfs::boot_root().filesystem().flush() {
+        warn!("Failed to flush rootfs: {err:?}");
+    }
+}
+
+/// Kills every process and has the kernel start the init process again,
+/// without going through the firmware.
+pub fn soft_reboot() {
+    // Set before killing init, which the kernel waits on.
+    SOFT_REBOOT.store(true, Ordering::Release);
+    prepare_shutdown();
+}
+
+/// Resets the machine right away, without stopping processes or writing
+/// back filesystems, e.g. when a watchdog expires.
+///
+/// Powers off if the platform cannot be reset.
+pub fn emergency_restart() -> ! {
+    #[cfg(target_arch = "riscv64")]
+    sbi_rt::system_reset(sbi_rt::ColdReboot, sbi_rt::NoReason);
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let smc = starry_core::boot::fdt_property("psci", "method")
+            .is_some_and(|method| method.starts_with(b"smc"));
+        let _ = if smc {
+            smccc::psci::system_reset::<smccc::Smc>()
+        } else {
+            smccc::psci::system_reset::<smccc::Hvc>()
+        };
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    // Pulse the reset line through the keyboard controller.
+    unsafe {
+        x86::io::outb(0x64, 0xfe)
+    };
+
+    warn!("Restart failed or is not supported, powering off");
+    axhal::power::system_off()
+}
+
+/// Hands the calling CPU back to the firmware for good, or parks it where
+/// that isn't possible.
+pub fn stop_this_cpu() -> ! {
+    axhal::asm::disable_irqs();
+
+    #[cfg(target_arch = "riscv64")]
+    let _ = sbi_rt::hart_stop();
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let smc = starry_core::boot::fdt_property("psci", "method")
+            .is_some_and(|method| method.starts_with(b"smc"));
+        let _ = if smc {
+            smccc::psci::cpu_off::<smccc::Smc>()
+        } else {
+            smccc::psci::cpu_off::<smccc::Hvc>()
+        };
+    }
+
+    loop {
+        axhal::asm::halt();
+    }
+}
+
+/// Stops all processes, writes back and unmounts the filesystems, and then
+/// performs `action`.
+///
+/// Only the first caller goes through; later ones, such as the kernel seeing
+/// the init process killed on the way, just wait for the end.
+pub fn shutdown(action: PowerAction) -> ! {
+    if SHUTTING_DOWN.swap(true, Ordering::AcqRel) {
+        loop {
+            axtask::sleep(Duration::from_secs(1));
+        }
+    }
+    prepare_shutdown();
+    unmount_all();
+
+    match action {
+        PowerAction::Restart => {
+            info!("Restarting system");
+            emergency_restart();
+        }
+        PowerAction::Halt => {
+            info!("System halted");
+            axhal::asm::disable_irqs();
+            loop {
+                axhal::asm::halt();
+            }
+        }
+        PowerAction::PowerOff => info!("Power down"),
+    }
+    axhal::power::system_off()
+}
@@ -3,21 +3,82 @@ use core::sync::atomic::{AtomicBool, Ordering};
 use axerrno::AxResult;
 use axhal::uspace::UserContext;
 use axtask::current;
+use linux_raw_sys::general::{SA_RESTART, SIG_IGN, SS_AUTODISARM, SS_DISABLE, kernel_sigaction};
 use starry_core::task::{AsThread, Thread};
-use starry_signal::{SignalOSAction, SignalSet};
+use starry_signal::{SignalOSAction, SignalSet, SignalStack, Signo};
 
 use crate::task::do_exit;
 
+/// A disabled alternate signal stack.
+pub const DISABLED_STACK: SignalStack = SignalStack {
+    sp: 0,
+    flags: SS_DISABLE as _,
+    size: 0,
+};
+
+/// Checks whether `sp` lies on the alternate signal stack `stack`.
+pub fn on_alt_stack(stack: &SignalStack, sp: usize) -> bool {
+    stack.flags as u32 & SS_DISABLE == 0 && sp > stack.sp && sp - stack.sp <= stack.size
+}
+
+/// Re-arms the alternate stack disarmed by `SS_AUTODISARM` once the handler
+/// that ran on it has returned, i.e. `sp` has left it.
+pub fn rearm_alt_stack(thr: &Thread, sp: usize) {
+    let mut disarmed = thr.disarmed_stack.lock();
+    if disarmed
+        .as_ref()
+        .is_some_and(|stack| !on_alt_stack(stack, sp))
+    {
+        thr.signal.set_stack(disarmed.take().unwrap());
+    }
+}
+
+/// Discards the signals in `set` queued to `thr` or its process.
+pub fn flush_signals(thr: &Thread, set: &SignalSet) {
+    while let Some(sig) = thr.signal.dequeue_signal(set) {
+        thr.proc_data.uncharge_signal(sig.signo());
+    }
+}
+
 pub fn check_signals(
     thr: &Thread,
     uctx: &mut UserContext,
     restore_blocked: Option<SignalSet>,
 ) -> bool {
-    let Some((sig, os_action)) = thr.signal.check_signals(uctx, restore_blocked) else {
+    if thr.proc_data.group_exiting() {
+        // No handler may run once the process is going away.
+        if !thr.pending_exit() {
+            do_exit(Signo::SIGKILL as i32, true);
+        }
+        return false;
+    }
+    let stack = thr.signal.stack();
+    let on_stack = on_alt_stack(&stack, uctx.sp());
+    // A handler interrupted on the alternate stack must not have its frame
+    // overwritten from the top of the stack: push the new frame right below
+    // the current one instead.
+    if on_stack {
+        thr.signal.set_stack(DISABLED_STACK);
+    }
+    let result = thr.signal.check_signals(uctx, restore_blocked);
+    if on_stack {
+        thr.signal.set_stack(stack);
+    } else if stack.flags as u32 & SS_AUTODISARM != 0
+        && result.is_some()
+        && on_alt_stack(&stack, uctx.sp())
+    {
+        // The handler now runs on the alternate stack; let it install
+        // another one (e.g. with swapcontext) until it returns.
+        thr.signal.set_stack(DISABLED_STACK);
+        *thr.disarmed_stack.lock() = Some(stack);
+    }
+
+    let Some((sig, os_action)) = result else {
         return false;
     };
 
     let signo = sig.signo();
+    thr.proc_data.uncharge_signal(signo);
     match os_action {
         SignalOSAction::Terminate => {
             do_exit(signo as i32, true);
@@ -40,6 +101,53 @@ pub fn check_signals(
     true
 }
 
+/// How a syscall interrupted by a signal is resumed, after Linux's
+/// `ERESTART*` codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// Always fail with `EINTR`.
+    Never,
+    /// Restart unless a handler without `SA_RESTART` runs (`ERESTARTSYS`).
+    Sys,
+    /// Restart only if no handler runs at all (`ERESTARTNOHAND`).
+    NoHandler,
+}
+
+/// Length of the instruction that traps into a syscall, used to rewind the
+/// user PC so the syscall is executed again.
+#[cfg(target_arch = "x86_64")]
+pub const SYSCALL_INSN_LEN: usize = 2;
+#[cfg(not(target_arch = "x86_64"))]
+pub const SYSCALL_INSN_LEN: usize = 4;
+
+/// Decides whether a syscall interrupted with `restart` semantics should be
+/// restarted, based on the signal about to be delivered to `thr`.
+pub fn should_restart(thr: &Thread, restart: Restart) -> bool {
+    if restart == Restart::Never {
+        return false;
+    }
+
+    // Signals are delivered lowest number first, whether they were sent to
+    // the thread or to the whole process.
+    let pending = thr.signal.pending();
+    let shared = thr.proc_data.signal.pending();
+    let blocked = thr.signal.blocked();
+    let Some(signo) = (1..=64)
+        .filter_map(Signo::from_repr)
+        .find(|&signo| (pending.has(signo) || shared.has(signo)) && !blocked.has(signo))
+    else {
+        // Nothing to deliver (e.g. it was ignored), so just carry on.
+        return true;
+    };
+
+    let action: kernel_sigaction = thr.proc_data.signal.actions.lock()[signo].clone().into();
+    let handler = action.sa_handler_kernel.map_or(0, |f| f as usize);
+    if handler == 0 || handler == SIG_IGN as usize {
+        return true;
+    }
+    restart == Restart::Sys && action.sa_flags as u32 & SA_RESTART != 0
+}
+
 static BLOCK_NEXT_SIGNAL_CHECK: AtomicBool = AtomicBool::new(false);
 
 pub fn block_next_signal() {
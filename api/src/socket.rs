@@ -13,45 +13,53 @@ use linux_raw_sys::net::{
     __kernel_sa_family_t, AF_INET, AF_INET6, AF_UNIX, in_addr, in6_addr, sockaddr, sockaddr_in,
     sockaddr_in6, socklen_t,
 };
-
-use crate::mm::{UserConstPtr, UserPtr};
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
 
 /// Trait to extend [`SocketAddr`] and its variants with methods for reading
 /// from and writing to user space.
 pub trait SocketAddrExt: Sized {
     /// This method attempts to interpret the data pointed to by `addr` with the
     /// given `addrlen` as a valid socket address of the implementing type.
-    fn read_from_user(addr: UserConstPtr<sockaddr>, addrlen: socklen_t) -> AxResult<Self>;
+    fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self>;
 
     /// This method serializes the current socket address instance into the
     /// [`sockaddr`] structure pointed to by `addr` in user space.
-    fn write_to_user(&self, addr: UserPtr<sockaddr>, addrlen: &mut socklen_t) -> AxResult<()>;
+    fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult<()>;
 
     /// Gets the address family of the socket address.
     fn family(&self) -> u16;
 }
 
-fn read_family(addr: UserConstPtr<sockaddr>, addrlen: socklen_t) -> AxResult<u16> {
+fn read_family(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<u16> {
     if size_of::<__kernel_sa_family_t>() > addrlen as usize {
         return Err(AxError::InvalidInput);
     }
-    let family = *addr.cast::<__kernel_sa_family_t>().get_as_ref()?;
-    Ok(family)
+    Ok(addr.cast::<__kernel_sa_family_t>().vm_read()?)
 }
 unsafe fn cast_to_slice<T>(value: &T) -> &[u8] {
     unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
 }
-fn fill_addr(addr: UserPtr<sockaddr>, addrlen: &mut socklen_t, data: &[u8]) -> AxResult<()> {
+pub(crate) fn fill_addr(addr: *mut sockaddr, addrlen: &mut socklen_t, data: &[u8]) -> AxResult<()> {
     let len = (*addrlen as usize).min(data.len());
-    addr.cast::<u8>()
-        .get_as_mut_slice(len)?
-        .copy_from_slice(&data[..len]);
+    vm_write_slice(addr.cast::<u8>(), &data[..len])?;
     *addrlen = data.len() as _;
     Ok(())
 }
 
+/// Calls `write` with the length of the user buffer at `addrlen` and stores
+/// the length it leaves back there, as `getsockname` and friends do.
+pub(crate) fn with_addrlen(
+    addrlen: *mut socklen_t,
+    write: impl FnOnce(&mut socklen_t) -> AxResult<()>,
+) -> AxResult<()> {
+    let mut len = addrlen.vm_read()?;
+    write(&mut len)?;
+    addrlen.vm_write(len)?;
+    Ok(())
+}
+
 impl SocketAddrExt for SocketAddr {
-    fn read_from_user(addr: UserConstPtr<sockaddr>, addrlen: socklen_t) -> AxResult<Self> {
+    fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self> {
         match read_family(addr, addrlen)? as u32 {
             AF_INET => SocketAddrV4::read_from_user(addr, addrlen).map(Self::V4),
             AF_INET6 => SocketAddrV6::read_from_user(addr, addrlen).map(Self::V6),
@@ -59,7 +67,7 @@ impl SocketAddrExt for SocketAddr {
         }
     }
 
-    fn write_to_user(&self, addr: UserPtr<sockaddr>, addrlen: &mut socklen_t) -> AxResult<()> {
+    fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult<()> {
         match self {
             SocketAddr::V4(v4) => v4.write_to_user(addr, addrlen),
             SocketAddr::V6(v6) => v6.write_to_user(addr, addrlen),
@@ -75,11 +83,11 @@ impl SocketAddrExt for SocketAddr {
 }
 
 impl SocketAddrExt for SocketAddrV4 {
-    fn read_from_user(addr: UserConstPtr<sockaddr>, addrlen: socklen_t) -> AxResult<Self> {
+    fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self> {
         if addrlen != size_of::<sockaddr_in>() as socklen_t {
             return Err(AxError::InvalidInput);
         }
-        let addr_in = addr.cast::<sockaddr_in>().get_as_ref()?;
+        let addr_in = unsafe { addr.cast::<sockaddr_in>().vm_read_uninit()?.assume_init() };
         if addr_in.sin_family as u32 != AF_INET {
             return Err(AxError::Other(LinuxError::EAFNOSUPPORT));
         }
@@ -90,7 +98,7 @@ impl SocketAddrExt for SocketAddrV4 {
         ))
     }
 
-    fn write_to_user(&self, addr: UserPtr<sockaddr>, addrlen: &mut socklen_t) -> AxResult<()> {
+    fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult<()> {
         let sockin_addr = sockaddr_in {
             sin_family: AF_INET as _,
             sin_port: self.port().to_be(),
@@ -108,11 +116,11 @@ impl SocketAddrExt for SocketAddrV4 {
 }
 
 impl SocketAddrExt for SocketAddrV6 {
-    fn read_from_user(addr: UserConstPtr<sockaddr>, addrlen: socklen_t) -> AxResult<Self> {
+    fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self> {
         if addrlen != size_of::<sockaddr_in6>() as socklen_t {
             return Err(AxError::InvalidInput);
         }
-        let addr_in6 = addr.cast::<sockaddr_in6>().get_as_ref()?;
+        let addr_in6 = unsafe { addr.cast::<sockaddr_in6>().vm_read_uninit()?.assume_init() };
         if addr_in6.sin6_family as u32 != AF_INET6 {
             return Err(AxError::Other(LinuxError::EAFNOSUPPORT));
         }
@@ -125,7 +133,7 @@ impl SocketAddrExt for SocketAddrV6 {
         ))
     }
 
-    fn write_to_user(&self, addr: UserPtr<sockaddr>, addrlen: &mut socklen_t) -> AxResult<()> {
+    fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult<()> {
         let sockin_addr = sockaddr_in6 {
             sin6_family: AF_INET6 as _,
             sin6_port: self.port().to_be(),
@@ -146,13 +154,15 @@ impl SocketAddrExt for SocketAddrV6 {
 }
 
 impl SocketAddrExt for UnixSocketAddr {
-    fn read_from_user(addr: UserConstPtr<sockaddr>, addrlen: socklen_t) -> AxResult<Self> {
+    fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self> {
         if read_family(addr, addrlen)? as u32 != AF_UNIX {
             return Err(AxError::Other(LinuxError::EAFNOSUPPORT));
         }
         let offset = size_of::<__kernel_sa_family_t>();
-        let ptr = UserConstPtr::<u8>::from(addr.address().as_usize() + offset);
-        let data = ptr.get_as_slice(addrlen as usize - offset)?;
+        let data = vm_load(
+            addr.cast::<u8>().wrapping_add(offset),
+            addrlen as usize - offset,
+        )?;
         Ok(if data.is_empty() {
             Self::Unnamed
         } else if data[0] == 0 {
@@ -167,7 +177,7 @@ impl SocketAddrExt for UnixSocketAddr {
         })
     }
 
-    fn write_to_user(&self, addr: UserPtr<sockaddr>, addrlen: &mut socklen_t) -> AxResult<()> {
+    fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult<()> {
         let data_len = match self {
             UnixSocketAddr::Unnamed => 0,
             UnixSocketAddr::Abstract(name) => name.len() + 1,
@@ -196,7 +206,7 @@ impl SocketAddrExt for UnixSocketAddr {
 }
 
 impl SocketAddrExt for SocketAddrEx {
-    fn read_from_user(addr: UserConstPtr<sockaddr>, addrlen: socklen_t) -> AxResult<Self> {
+    fn read_from_user(addr: *const sockaddr, addrlen: socklen_t) -> AxResult<Self> {
         match read_family(addr, addrlen)? as u32 {
             AF_INET | AF_INET6 => SocketAddr::read_from_user(addr, addrlen).map(Self::Ip),
             AF_UNIX => UnixSocketAddr::read_from_user(addr, addrlen).map(Self::Unix),
@@ -204,7 +214,7 @@ impl SocketAddrExt for SocketAddrEx {
         }
     }
 
-    fn write_to_user(&self, addr: UserPtr<sockaddr>, addrlen: &mut socklen_t) -> AxResult<()> {
+    fn write_to_user(&self, addr: *mut sockaddr, addrlen: &mut socklen_t) -> AxResult<()> {
         match self {
             SocketAddrEx::Ip(ip_addr) => ip_addr.write_to_user(addr, addrlen),
             SocketAddrEx::Unix(unix_addr) => unix_addr.write_to_user(addr, addrlen),
@@ -212,6 +222,9 @@ impl SocketAddrExt for SocketAddrEx {
     }
 
     fn family(&self) -> u16 {
-        AF_INET as u16
+        match self {
+            SocketAddrEx::Ip(ip_addr) => ip_addr.family(),
+            SocketAddrEx::Unix(unix_addr) => unix_addr.family(),
+        }
     }
 }
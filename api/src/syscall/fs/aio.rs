@@ -0,0 +1,334 @@
+//! Linux native AIO: `io_setup`, `io_submit`, `io_getevents` and friends.
+//!
+//! Requests are carried out when they are submitted, through the same paths
+//! as `pread`, `pwrite` and `fsync`, and their completions queue up on the
+//! context until `io_getevents` reaps them. The context id handed to user
+//! space is the address of a ring page, as on Linux, but its header never
+//! carries the ring magic: libaio then always reaps through the syscall
+//! rather than from the ring.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    ffi::c_int,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::paging::{MappingFlags, PageSize};
+use axmm::backend::Backend;
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::current;
+use bytemuck::{AnyBitPattern, NoUninit};
+use linux_raw_sys::general::timespec;
+use memory_addr::{PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
+use spin::Mutex;
+use starry_core::{sysctl::AIO_MAX_NR, task::AsThread};
+use starry_vm::{VmMutPtr, VmPtr, vm_write_slice};
+
+use super::{sys_fdatasync, sys_fsync, sys_pread64, sys_preadv, sys_pwrite64, sys_pwritev};
+use crate::{
+    file::{FileLike, event::EventFd, get_file_like},
+    syscall::io_mpx::poll_timeout,
+    time::TimeValueLike,
+};
+
+const IOCB_CMD_PREAD: u16 = 0;
+const IOCB_CMD_PWRITE: u16 = 1;
+const IOCB_CMD_FSYNC: u16 = 2;
+const IOCB_CMD_FDSYNC: u16 = 3;
+const IOCB_CMD_PREADV: u16 = 7;
+const IOCB_CMD_PWRITEV: u16 = 8;
+
+/// Signal the eventfd in `aio_resfd` on completion.
+const IOCB_FLAG_RESFD: u32 = 1;
+/// Take the I/O priority in `aio_reqprio`.
+const IOCB_FLAG_IOPRIO: u32 = 2;
+
+/// `struct iocb`, for little-endian targets.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+pub struct Iocb {
+    aio_data: u64,
+    aio_key: u32,
+    aio_rw_flags: u32,
+    aio_lio_opcode: u16,
+    aio_reqprio: i16,
+    aio_fildes: u32,
+    aio_buf: u64,
+    aio_nbytes: u64,
+    aio_offset: i64,
+    aio_reserved2: u64,
+    aio_flags: u32,
+    aio_resfd: u32,
+}
+
+/// `struct io_event`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, NoUninit)]
+pub struct IoEvent {
+    data: u64,
+    obj: u64,
+    res: i64,
+    res2: i64,
+}
+
+/// The number of events the contexts of the system hold, against
+/// `fs.aio-max-nr`.
+static AIO_NR: AtomicUsize = AtomicUsize::new(0);
+
+/// An AIO context.
+struct AioContext {
+    /// The most completions that may wait to be reaped.
+    max_events: usize,
+    completed: Mutex<VecDeque<IoEvent>>,
+    poll: PollSet,
+}
+
+impl Drop for AioContext {
+    fn drop(&mut self) {
+        AIO_NR.fetch_sub(self.max_events, Ordering::Relaxed);
+    }
+}
+
+impl Pollable for AioContext {
+    fn poll(&self) -> IoEvents {
+        if self.completed.lock().is_empty() {
+            IoEvents::empty()
+        } else {
+            IoEvents::IN
+        }
+    }
+
+    fn register(&self, context: &mut Context<'_>, _events: IoEvents) {
+        self.poll.register(context.waker());
+    }
+}
+
+scope_local::scope_local! {
+    /// The AIO contexts of the current process, by id.
+    static AIO_CONTEXTS: Arc<Mutex<BTreeMap<u64, Arc<AioContext>>>> = Arc::default();
+}
+
+fn get_context(ctx_id: u64) -> AxResult<Arc<AioContext>> {
+    AIO_CONTEXTS
+        .lock()
+        .get(&ctx_id)
+        .cloned()
+        .ok_or(AxError::InvalidInput)
+}
+
+pub fn sys_io_setup(nr_events: u32, ctxp: *mut u64) -> AxResult<isize> {
+    debug!("sys_io_setup <= nr_events: {}", nr_events);
+
+    if ctxp.vm_read()? != 0 || nr_events == 0 || nr_events > i32::MAX as u32 {
+        return Err(AxError::InvalidInput);
+    }
+    let max_events = nr_events as usize;
+    AIO_NR
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |nr| {
+            nr.checked_add(max_events)
+                .filter(|&it| it <= AIO_MAX_NR.get())
+        })
+        .map_err(|_| AxError::WouldBlock)?;
+    let ctx = Arc::new(AioContext {
+        max_events,
+        completed: Mutex::new(VecDeque::new()),
+        poll: PollSet::new(),
+    });
+
+    // The id is the address of a page of the caller, which libaio reads the
+    // ring header from.
+    let curr = current();
+    let mut aspace = curr.as_thread().proc_data.aspace.lock();
+    let start = aspace
+        .find_free_area(
+            aspace.base(),
+            PAGE_SIZE_4K,
+            VirtAddrRange::new(aspace.base(), aspace.end()),
+        )
+        .ok_or(AxError::NoMemory)?;
+    aspace.map(
+        start,
+        PAGE_SIZE_4K,
+        MappingFlags::USER | MappingFlags::READ | MappingFlags::WRITE,
+        false,
+        Backend::new_alloc(start, PageSize::Size4K),
+    )?;
+    drop(aspace);
+
+    let ctx_id = start.as_usize() as u64;
+    AIO_CONTEXTS.lock().insert(ctx_id, ctx);
+    if let Err(err) = ctxp.vm_write(ctx_id) {
+        let _ = sys_io_destroy(ctx_id);
+        return Err(err);
+    }
+    Ok(0)
+}
+
+pub fn sys_io_destroy(ctx_id: u64) -> AxResult<isize> {
+    debug!("sys_io_destroy <= ctx: {:#x}", ctx_id);
+
+    let ctx = AIO_CONTEXTS
+        .lock()
+        .remove(&ctx_id)
+        .ok_or(AxError::InvalidInput)?;
+    // Nothing is ever in flight, but those waiting for events must give up.
+    ctx.poll.wake();
+    current()
+        .as_thread()
+        .proc_data
+        .aspace
+        .lock()
+        .unmap(VirtAddr::from(ctx_id as usize), PAGE_SIZE_4K)?;
+    Ok(0)
+}
+
+/// Carries out the request `iocb`, returning the result of its completion
+/// event.
+fn run_request(iocb: &Iocb) -> isize {
+    let fd = iocb.aio_fildes as c_int;
+    let (buf, len, offset) = (
+        iocb.aio_buf as usize,
+        iocb.aio_nbytes as usize,
+        iocb.aio_offset,
+    );
+    let result = match iocb.aio_lio_opcode {
+        IOCB_CMD_PREAD => sys_pread64(fd, buf as _, len, offset),
+        IOCB_CMD_PWRITE => sys_pwrite64(fd, buf as _, len, offset),
+        IOCB_CMD_PREADV => sys_preadv(fd, buf as _, len, offset),
+        IOCB_CMD_PWRITEV => sys_pwritev(fd, buf as _, len, offset),
+        IOCB_CMD_FSYNC => sys_fsync(fd),
+        IOCB_CMD_FDSYNC => sys_fdatasync(fd),
+        _ => unreachable!(),
+    };
+    result.unwrap_or_else(|err| -LinuxError::from(err).code() as isize)
+}
+
+/// Submits the request at `user_iocb`.
+fn submit_one(ctx: &AioContext, user_iocb: *const Iocb) -> AxResult<()> {
+    let iocb = user_iocb.vm_read()?;
+    if iocb.aio_reserved2 != 0 || iocb.aio_flags & !(IOCB_FLAG_RESFD | IOCB_FLAG_IOPRIO) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if !matches!(
+        iocb.aio_lio_opcode,
+        IOCB_CMD_PREAD
+            | IOCB_CMD_PWRITE
+            | IOCB_CMD_PREADV
+            | IOCB_CMD_PWRITEV
+            | IOCB_CMD_FSYNC
+            | IOCB_CMD_FDSYNC
+    ) {
+        return Err(AxError::InvalidInput);
+    }
+    // Bad descriptors fail the submission rather than the request.
+    get_file_like(iocb.aio_fildes as c_int)?;
+    let resfd = if iocb.aio_flags & IOCB_FLAG_RESFD != 0 {
+        Some(EventFd::from_fd(iocb.aio_resfd as c_int).map_err(|_| AxError::InvalidInput)?)
+    } else {
+        None
+    };
+    if ctx.completed.lock().len() >= ctx.max_events {
+        return Err(AxError::WouldBlock);
+    }
+
+    let res = run_request(&iocb);
+    ctx.completed.lock().push_back(IoEvent {
+        data: iocb.aio_data,
+        obj: user_iocb as u64,
+        res: res as i64,
+        res2: 0,
+    });
+    ctx.poll.wake();
+    if let Some(eventfd) = resfd {
+        eventfd.signal(1);
+    }
+    Ok(())
+}
+
+pub fn sys_io_submit(ctx_id: u64, nr: isize, iocbpp: *const *const Iocb) -> AxResult<isize> {
+    debug!("sys_io_submit <= ctx: {:#x}, nr: {}", ctx_id, nr);
+
+    if nr < 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let ctx = get_context(ctx_id)?;
+    let mut submitted = 0;
+    while submitted < nr as usize {
+        let result = iocbpp
+            .wrapping_add(submitted)
+            .vm_read()
+            .and_then(|iocb| submit_one(&ctx, iocb));
+        match result {
+            Ok(()) => submitted += 1,
+            // What was submitted before the failure counts.
+            Err(_) if submitted > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(submitted as isize)
+}
+
+pub fn sys_io_cancel(ctx_id: u64, _iocb: *const Iocb, _result: *mut IoEvent) -> AxResult<isize> {
+    debug!("sys_io_cancel <= ctx: {:#x}", ctx_id);
+
+    get_context(ctx_id)?;
+    // Every request completes as it is submitted, so none can be cancelled.
+    Err(AxError::InvalidInput)
+}
+
+pub fn sys_io_getevents(
+    ctx_id: u64,
+    min_nr: isize,
+    nr: isize,
+    events: *mut IoEvent,
+    timeout: *const timespec,
+) -> AxResult<isize> {
+    debug!(
+        "sys_io_getevents <= ctx: {:#x}, min_nr: {}, nr: {}",
+        ctx_id, min_nr, nr
+    );
+
+    if min_nr < 0 || nr < 0 || min_nr > nr {
+        return Err(AxError::InvalidInput);
+    }
+    let ctx = get_context(ctx_id)?;
+    let timeout = match timeout.nullable() {
+        Some(ts) => Some(unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?),
+        None => None,
+    };
+
+    let (min_nr, nr) = (min_nr as usize, nr as usize);
+    let ready = || {
+        let completed = ctx.completed.lock().len();
+        if completed >= min_nr || !AIO_CONTEXTS.lock().contains_key(&ctx_id) {
+            Ok(())
+        } else {
+            Err(AxError::WouldBlock)
+        }
+    };
+    match poll_timeout(ctx.as_ref(), IoEvents::IN, timeout, ready) {
+        // Whatever completed in time is returned.
+        Ok(()) | Err(AxError::TimedOut) => {}
+        Err(err) => return Err(err),
+    }
+
+    let mut completed = ctx.completed.lock();
+    let count = completed.len().min(nr);
+    let reaped = completed.drain(..count).collect::<Vec<_>>();
+    drop(completed);
+    if let Err(err) = vm_write_slice(events, &reaped) {
+        // Put them back for the next try.
+        let mut completed = ctx.completed.lock();
+        for event in reaped.into_iter().rev() {
+            completed.push_front(event);
+        }
+        return Err(err);
+    }
+    Ok(count as isize)
+}
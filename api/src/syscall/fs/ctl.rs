@@ -1,4 +1,4 @@
-use alloc::{ffi::CString, vec, vec::Vec};
+use alloc::{ffi::CString, format, vec, vec::Vec};
 use core::{
     ffi::{c_char, c_int},
     mem::offset_of,
@@ -14,13 +14,20 @@ use linux_raw_sys::{
     general::*,
     ioctl::{FIONBIO, TIOCGWINSZ},
 };
-use starry_core::{task::AsThread, vfs::Device as VfsDevice};
+use starry_core::{
+    task::AsThread,
+    vfs::{Device as VfsDevice, check_mount_writable},
+};
 use starry_vm::{VmPtr, vm_write_slice};
 
 use crate::{
-    file::{Directory, FileLike, get_file_like, resolve_at, with_fs},
+    file::{
+        Directory, FileLike, ResolveAtResult, fscrypt, get_file_like, path_from_root, resolve_at,
+        with_fs,
+    },
     mm::vm_load_string,
     time::TimeValueLike,
+    vfs::{self, set_device_id},
 };
 
 /// The ioctl() system call manipulates the underlying device parameters
@@ -77,11 +84,17 @@ pub fn sys_chroot(path: *const c_char) -> AxResult<isize> {
     let path = vm_load_string(path)?;
     debug!("sys_chroot <= path: {}", path);
 
+    if !current().as_thread().proc_data.cred.read().is_privileged() {
+        return Err(AxError::OperationNotPermitted);
+    }
+
     let mut fs = FS_CONTEXT.lock();
     let loc = fs.resolve(path)?;
     if loc.node_type() != NodeType::Directory {
         return Err(AxError::NotADirectory);
     }
+    // Unlike Linux, the working directory moves into the new root too, so
+    // that it cannot be used to escape from it.
     *fs = FsContext::new(loc);
     Ok(0)
 }
@@ -97,7 +110,9 @@ pub fn sys_mkdirat(dirfd: i32, path: *const c_char, mode: u32) -> AxResult<isize
     let mode = NodePermission::from_bits_truncate(mode as u16);
 
     with_fs(dirfd, |fs| {
-        fs.create_dir(path, mode)?;
+        check_mount_writable(&fs.resolve_nonexistent(Path::new(&path))?.0)?;
+        fs.create_dir(&path, mode)?;
+        fscrypt::inherit(&fs.resolve(&path)?)?;
         Ok(0)
     })
 }
@@ -126,6 +141,7 @@ pub fn sys_mknodat(dirfd: i32, path: *const c_char, mode: u32, dev: u64) -> Resu
 
     let res = with_fs(dirfd, |fs| {
         let (dir, name) = fs.resolve_nonexistent(Path::new(&path))?;
+        check_mount_writable(&dir)?;
         let loc = dir.create(
             name,
             node_type,
@@ -141,7 +157,7 @@ pub fn sys_mknodat(dirfd: i32, path: *const c_char, mode: u32, dev: u64) -> Resu
             // (this works for in-kernel SimpleFs device nodes).
             if let Ok(dev_node) = loc.entry().downcast::<VfsDevice>() {
                 dev_node.set_device_id(DeviceId::new(major, minor));
-            } else {
+            } else if !set_device_id(loc.entry(), DeviceId::new(major, minor)) {
                 // If downcast fails, we can't set rdev through MetadataUpdate
                 // (not supported), so just ignore and continue.
                 warn!("not a device node, cannot set rdev");
@@ -260,8 +276,11 @@ pub fn sys_linkat(
     if old.is_dir() {
         return Err(AxError::OperationNotPermitted);
     }
+    fscrypt::check_path(old.name())?;
+    fscrypt::check_path(&new_path)?;
     let (new_dir, new_name) =
         with_fs(new_dirfd, |fs| fs.resolve_nonexistent(Path::new(&new_path)))?;
+    check_mount_writable(&new_dir)?;
 
     new_dir.link(new_name, &old)?;
     Ok(0)
@@ -286,9 +305,12 @@ pub fn sys_unlinkat(dirfd: i32, path: *const c_char, flags: usize) -> AxResult<i
     );
 
     with_fs(dirfd, |fs| {
+        check_mount_writable(&fs.resolve_parent(Path::new(&path))?.0)?;
         if flags == AT_REMOVEDIR as _ {
+            fscrypt::before_remove_dir(&fs.resolve(&path)?)?;
             fs.remove_dir(path)?;
         } else {
+            fscrypt::check_path(&path)?;
             fs.remove_file(path)?;
         }
         Ok(0)
@@ -311,10 +333,18 @@ pub fn sys_getcwd(buf: *mut u8, size: isize) -> AxResult<isize> {
         return Ok(0);
     }
 
-    let cwd = FS_CONTEXT.lock().current_dir().absolute_path()?;
+    // The path is relative to the root of the process, and like Linux, a
+    // working directory outside of it is reported as unreachable.
+    let cwd = {
+        let fs = FS_CONTEXT.lock();
+        match path_from_root(fs.root_dir(), fs.current_dir())? {
+            Some(cwd) => cwd,
+            None => format!("(unreachable){}", fs.current_dir().absolute_path()?),
+        }
+    };
     debug!("sys_getcwd => cwd: {}", cwd);
 
-    let cwd = CString::new(cwd.as_str()).map_err(|_| AxError::InvalidInput)?;
+    let cwd = CString::new(cwd).map_err(|_| AxError::InvalidInput)?;
     let cwd = cwd.as_bytes_with_nul();
 
     if cwd.len() <= size {
@@ -344,6 +374,7 @@ pub fn sys_symlinkat(
     );
 
     with_fs(new_dirfd, |fs| {
+        check_mount_writable(&fs.resolve_nonexistent(Path::new(&linkpath))?.0)?;
         fs.symlink(target, linkpath)?;
         Ok(0)
     })
@@ -399,8 +430,22 @@ pub fn sys_fchownat(
     let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
         .ok_or(AxError::BadFileDescriptor)?;
+    check_mount_writable(&loc)?;
     let meta = loc.metadata()?;
 
+    // Only a privileged process may give a file away. The owner may change
+    // its group to one it is in.
+    let cred = current().as_thread().proc_data.cred.read().clone();
+    if !cred.is_privileged() {
+        let owner = cred.fsuid == meta.uid;
+        if uid != -1 && !(owner && uid as u32 == meta.uid) {
+            return Err(AxError::OperationNotPermitted);
+        }
+        if gid != -1 && !(owner && (gid as u32 == meta.gid || cred.in_group(gid as u32))) {
+            return Err(AxError::OperationNotPermitted);
+        }
+    }
+
     let mut mode = meta.mode;
     // chown always clears the setuid bits
     mode.remove(NodePermission::SET_UID);
@@ -430,13 +475,27 @@ pub fn sys_fchmod(fd: i32, mode: u32) -> AxResult<isize> {
 
 pub fn sys_fchmodat(dirfd: i32, path: *const c_char, mode: u32, flags: u32) -> AxResult<isize> {
     let path = path.nullable().map(vm_load_string).transpose()?;
-    resolve_at(dirfd, path.as_deref(), flags)?
+    let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
-        .ok_or(AxError::BadFileDescriptor)?
-        .update_metadata(MetadataUpdate {
-            mode: Some(NodePermission::from_bits_truncate(mode as u16)),
-            ..Default::default()
-        })?;
+        .ok_or(AxError::BadFileDescriptor)?;
+    check_mount_writable(&loc)?;
+    let meta = loc.metadata()?;
+
+    let mut mode = NodePermission::from_bits_truncate(mode as u16);
+    let cred = current().as_thread().proc_data.cred.read().clone();
+    if !cred.is_privileged() {
+        if cred.fsuid != meta.uid {
+            return Err(AxError::OperationNotPermitted);
+        }
+        // The setgid bit would hand out a group the caller isn't in.
+        if !cred.in_group(meta.gid) {
+            mode.remove(NodePermission::SET_GID);
+        }
+    }
+    loc.update_metadata(MetadataUpdate {
+        mode: Some(mode),
+        ..Default::default()
+    })?;
     Ok(0)
 }
 
@@ -448,14 +507,15 @@ fn update_times(
     flags: u32,
 ) -> AxResult<()> {
     let path = path.nullable().map(vm_load_string).transpose()?;
-    resolve_at(dirfd, path.as_deref(), flags)?
+    let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
-        .ok_or(AxError::BadFileDescriptor)?
-        .update_metadata(MetadataUpdate {
-            atime,
-            mtime,
-            ..Default::default()
-        })?;
+        .ok_or(AxError::BadFileDescriptor)?;
+    check_mount_writable(&loc)?;
+    loc.update_metadata(MetadataUpdate {
+        atime,
+        mtime,
+        ..Default::default()
+    })?;
     Ok(())
 }
 
@@ -565,20 +625,29 @@ pub fn sys_renameat2(
         old_dirfd, old_path, new_dirfd, new_path, flags
     );
 
+    fscrypt::check_path(&old_path)?;
+    fscrypt::check_path(&new_path)?;
     let (old_dir, old_name) = with_fs(old_dirfd, |fs| fs.resolve_parent(Path::new(&old_path)))?;
     let (new_dir, new_name) =
         with_fs(new_dirfd, |fs| fs.resolve_nonexistent(Path::new(&new_path)))?;
+    check_mount_writable(&old_dir)?;
+    check_mount_writable(&new_dir)?;
 
     old_dir.rename(&old_name, &new_dir, new_name)?;
     Ok(0)
 }
 
 pub fn sys_sync() -> AxResult<isize> {
-    warn!("dummy sys_sync");
+    vfs::sync_all()?;
     Ok(0)
 }
 
-pub fn sys_syncfs(_fd: i32) -> AxResult<isize> {
-    warn!("dummy sys_syncfs");
+pub fn sys_syncfs(fd: i32) -> AxResult<isize> {
+    debug!("sys_syncfs <= fd: {}", fd);
+    let loc = match resolve_at(fd, None, AT_EMPTY_PATH)? {
+        ResolveAtResult::File(loc) => loc,
+        ResolveAtResult::Other(_) => return Ok(0),
+    };
+    loc.filesystem().flush()?;
     Ok(0)
 }
@@ -0,0 +1,83 @@
+use core::ffi::{c_char, c_int};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::NodeType;
+use axtask::current;
+use linux_raw_sys::general::{AT_EMPTY_PATH, AT_SYMLINK_NOFOLLOW, O_CLOEXEC, O_LARGEFILE};
+use starry_core::task::AsThread;
+
+use crate::{
+    file::{
+        FileLike, add_file_like,
+        fanotify::{FanEvents, FanInitFlags, FanMarkFlags, Fanotify},
+        resolve_at,
+    },
+    mm::vm_load_string,
+};
+
+pub fn sys_fanotify_init(flags: u32, event_f_flags: u32) -> AxResult<isize> {
+    debug!(
+        "sys_fanotify_init <= flags: {:#x}, event_f_flags: {:#x}",
+        flags, event_f_flags
+    );
+
+    let flags = FanInitFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    if flags.contains(FanInitFlags::CLASS_CONTENT | FanInitFlags::CLASS_PRE_CONTENT) {
+        return Err(AxError::InvalidInput);
+    }
+    // Listening to every access on the system takes CAP_SYS_ADMIN.
+    if !current().as_thread().proc_data.cred.read().is_privileged() {
+        return Err(AxError::OperationNotPermitted);
+    }
+
+    let group = Fanotify::new(flags, event_f_flags & !(O_CLOEXEC | O_LARGEFILE));
+    add_file_like(group as _, flags.contains(FanInitFlags::CLOEXEC)).map(|fd| fd as _)
+}
+
+pub fn sys_fanotify_mark(
+    fd: c_int,
+    flags: u32,
+    mask: u64,
+    dirfd: c_int,
+    path: *const c_char,
+) -> AxResult<isize> {
+    let path = (!path.is_null())
+        .then(|| vm_load_string(path))
+        .transpose()?;
+    debug!(
+        "sys_fanotify_mark <= fd: {}, flags: {:#x}, mask: {:#x}, dirfd: {}, path: {:?}",
+        fd, flags, mask, dirfd, path
+    );
+
+    let flags = FanMarkFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    let mask = FanEvents::from_bits(mask).ok_or(AxError::InvalidInput)?;
+    let action = flags & (FanMarkFlags::ADD | FanMarkFlags::REMOVE | FanMarkFlags::FLUSH);
+    if action.bits().count_ones() != 1
+        || flags.contains(FanMarkFlags::MOUNT | FanMarkFlags::FILESYSTEM)
+    {
+        return Err(AxError::InvalidInput);
+    }
+    let group = Fanotify::from_fd(fd)?;
+
+    if flags.contains(FanMarkFlags::FLUSH) {
+        group.update_mark(None, flags, mask)?;
+        return Ok(0);
+    }
+    if mask.is_empty() {
+        return Err(AxError::InvalidInput);
+    }
+
+    let mut resolve_flags = AT_EMPTY_PATH;
+    if flags.contains(FanMarkFlags::DONT_FOLLOW) {
+        resolve_flags |= AT_SYMLINK_NOFOLLOW;
+    }
+    let loc = resolve_at(dirfd, path.as_deref(), resolve_flags)?
+        .into_file()
+        .ok_or(AxError::InvalidInput)?;
+    if flags.contains(FanMarkFlags::ONLYDIR) && loc.metadata()?.node_type != NodeType::Directory {
+        return Err(AxError::NotADirectory);
+    }
+
+    group.update_mark(Some(&loc), flags, mask)?;
+    Ok(0)
+}
@@ -6,25 +6,36 @@ use core::{
 };
 
 use axerrno::{AxError, AxResult};
-use axfs_ng::{FS_CONTEXT, FileBackend, OpenOptions, OpenResult};
-use axfs_ng_vfs::{DirEntry, FileNode, Location, NodePermission, NodeType, Reference};
+use axfs_ng::{FS_CONTEXT, FileBackend, FileFlags, FsContext, OpenOptions, OpenResult};
+use axfs_ng_vfs::{DirEntry, FileNode, Location, NodePermission, NodeType, Reference, path::Path};
 use axtask::current;
 use bitflags::bitflags;
 use linux_raw_sys::general::*;
-use starry_core::{task::AsThread, vfs::Device};
+use starry_core::{
+    mm::deny_write_access,
+    task::AsThread,
+    vfs::{Device, MountFlags, check_mount_writable, mount_flags},
+};
+use starry_signal::Signo;
+use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
     file::{
-        Directory, FD_TABLE, File, FileLike, Pipe, add_file_like, close_file_like, get_file_like,
-        with_fs,
+        Directory, FD_TABLE, File, FileLike, FileSeals, Pipe, add_file_like, close_file_like,
+        fanotify::{has_listeners, notify_open, notify_open_perm},
+        fscrypt, get_file_like, lease, with_fs,
     },
-    mm::{UserPtr, vm_load_string},
+    mm::vm_load_string,
     syscall::sys::{sys_getegid, sys_geteuid},
-    vfs::dev::tty,
+    vfs::dev::{i2c, tty},
 };
 
 /// Convert open flags to [`OpenOptions`].
-fn flags_to_options(flags: c_int, mode: __kernel_mode_t, (uid, gid): (u32, u32)) -> OpenOptions {
+pub(super) fn flags_to_options(
+    flags: c_int,
+    mode: __kernel_mode_t,
+    (uid, gid): (u32, u32),
+) -> OpenOptions {
     let flags = flags as u32;
     let mut options = OpenOptions::new();
     options.mode(mode).user(uid, gid);
@@ -60,11 +71,26 @@ fn flags_to_options(flags: c_int, mode: __kernel_mode_t, (uid, gid): (u32, u32))
     options
 }
 
-fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
+/// Whether opening `path` with `flags` would modify the filesystem, which a
+/// read-only mount forbids.
+fn open_writes(fs: &FsContext, path: &str, flags: u32) -> bool {
+    if flags & O_PATH != 0 {
+        return false;
+    }
+    flags & 0b11 != O_RDONLY
+        || flags & O_TRUNC != 0
+        || (flags & O_CREAT != 0 && fs.resolve(path).is_err())
+}
+
+pub(super) fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
     let f: Arc<dyn FileLike> = match result {
         OpenResult::File(mut file) => {
+            let mut opened = None;
             // /dev/xx handling
             if let Ok(device) = file.location().entry().downcast::<Device>() {
+                if mount_flags(file.location()).contains(MountFlags::NODEV) {
+                    return Err(AxError::PermissionDenied);
+                }
                 let inner = device.inner().as_any();
                 if let Some(ptmx) = inner.downcast_ref::<tty::Ptmx>() {
                     // Opening /dev/ptmx creates a new pseudo-terminal
@@ -87,8 +113,8 @@ fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
                         .session()
                         .terminal()
                         .ok_or(AxError::NotFound)?;
-                    let path = if term.is::<tty::NTtyDriver>() {
-                        "/dev/console".to_string()
+                    let path = if let Some(console) = term.downcast_ref::<tty::NTtyDriver>() {
+                        format!("/dev/{}", console.backend().name())
                     } else if let Some(pts) = term.downcast_ref::<tty::PtyDriver>() {
                         format!("/dev/pts/{}", pts.pty_number())
                     } else {
@@ -96,9 +122,25 @@ fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
                     };
                     let loc = FS_CONTEXT.lock().resolve(&path)?;
                     file = axfs_ng::File::new(FileBackend::Direct(loc), file.flags());
+                } else if let Some(adapter) = inner.downcast_ref::<i2c::I2cDevice>() {
+                    // Each file opened on an I2C adapter is a client of its own
+                    let client = Arc::new(adapter.open_client(File::new(file)));
+                    if flags & O_NONBLOCK != 0 {
+                        client.set_nonblocking(true)?;
+                    }
+                    return add_file_like(client, flags & O_CLOEXEC != 0);
+                } else {
+                    opened = Some(device.inner().clone());
                 }
             }
-            Arc::new(File::new(file))
+            let mut file = File::new(fscrypt::open(file)?);
+            if flags & O_DIRECT != 0 {
+                file = file.into_direct()?;
+            }
+            match opened {
+                Some(device) => Arc::new(file.open_device(device)?),
+                None => Arc::new(file),
+            }
         }
         OpenResult::Dir(dir) => Arc::new(Directory::new(dir)),
     };
@@ -129,9 +171,49 @@ pub fn sys_openat(
     let mode = mode & !current().as_thread().proc_data.umask();
 
     let options = flags_to_options(flags, mode, (sys_geteuid()? as _, sys_getegid()? as _));
-    with_fs(dirfd, |fs| options.open(fs, path))
-        .and_then(|it| add_to_fd(it, flags as _))
-        .map(|fd| fd as isize)
+    // Ask for permission before the open can truncate the file. Files the
+    // open creates are only asked about once they exist, as on Linux.
+    let existing = if flags as u32 & O_PATH == 0 && has_listeners() {
+        with_fs(dirfd, |fs| {
+            if flags as u32 & O_NOFOLLOW != 0 {
+                fs.resolve_no_follow(&path)
+            } else {
+                fs.resolve(&path)
+            }
+        })
+        .ok()
+    } else {
+        None
+    };
+    if let Some(loc) = &existing {
+        notify_open_perm(loc)?;
+    }
+    with_fs(dirfd, |fs| {
+        if open_writes(fs, &path, flags as _) {
+            fscrypt::check_path(&path)?;
+            if let Ok((dir, _)) = fs.resolve_parent(Path::new(&path)) {
+                check_mount_writable(&dir)?;
+            }
+            if let Ok(loc) = fs.resolve(&path) {
+                deny_write_access(&loc)?;
+            }
+        }
+        options.open(fs, path)
+    })
+    .and_then(|it| {
+        if flags as u32 & O_PATH == 0 {
+            if let OpenResult::File(file) = &it {
+                lease::break_lease(
+                    file.location(),
+                    file.flags().contains(FileFlags::WRITE) || flags as u32 & O_TRUNC != 0,
+                    flags as u32 & O_NONBLOCK != 0,
+                )?;
+            }
+            notify_open(&it, existing.is_some())?;
+        }
+        add_to_fd(it, flags as _)
+    })
+    .map(|fd| fd as isize)
 }
 
 /// Open a file by `filename` and insert it into the file descriptor table.
@@ -254,8 +336,10 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
         F_SETLK | F_SETLKW => Ok(0),
         F_OFD_SETLK | F_OFD_SETLKW => Ok(0),
         F_GETLK | F_OFD_GETLK => {
-            let arg = UserPtr::<flock64>::from(arg);
-            arg.get_as_mut()?.l_type = F_UNLCK as _;
+            let arg = arg as *mut flock64;
+            let mut lock = unsafe { arg.vm_read_uninit()?.assume_init() };
+            lock.l_type = F_UNLCK as _;
+            arg.vm_write(lock)?;
             Ok(0)
         }
         F_SETFL => {
@@ -269,6 +353,13 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
             if f.nonblocking() {
                 ret |= O_NONBLOCK;
             }
+            if f.clone()
+                .into_any()
+                .downcast_ref::<File>()
+                .is_some_and(File::direct)
+            {
+                ret |= O_DIRECT;
+            }
 
             let perm = NodePermission::from_bits_truncate(f.stat()?.mode as _);
             if perm.contains(NodePermission::OWNER_WRITE) {
@@ -298,14 +389,37 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
                 .cloexec = cloexec;
             Ok(0)
         }
+        F_ADD_SEALS => {
+            let seals = FileSeals::from_bits(arg as u32).ok_or(AxError::InvalidInput)?;
+            File::from_fd(fd)
+                .map_err(|_| AxError::InvalidInput)?
+                .add_seals(seals)?;
+            Ok(0)
+        }
+        F_GET_SEALS => {
+            let file = File::from_fd(fd).map_err(|_| AxError::InvalidInput)?;
+            Ok(file.seals()?.bits() as _)
+        }
+        F_SETSIG => {
+            if arg != 0 && u8::try_from(arg).ok().and_then(Signo::from_repr).is_none() {
+                return Err(AxError::InvalidInput);
+            }
+            File::from_fd(fd)?.set_signal(arg as u32);
+            Ok(0)
+        }
+        F_GETSIG => Ok(File::from_fd(fd)?.signal() as _),
+        F_SETLEASE => {
+            lease::set_lease(&File::from_fd(fd)?, arg as u32)?;
+            Ok(0)
+        }
+        F_GETLEASE => Ok(lease::get_lease(&File::from_fd(fd)?) as _),
         F_GETPIPE_SZ => {
             let pipe = Pipe::from_fd(fd)?;
             Ok(pipe.capacity() as _)
         }
         F_SETPIPE_SZ => {
             let pipe = Pipe::from_fd(fd)?;
-            pipe.resize(arg)?;
-            Ok(0)
+            Ok(pipe.resize(arg)? as _)
         }
         _ => {
             warn!("unsupported fcntl parameters: cmd: {}", cmd);
@@ -0,0 +1,121 @@
+use alloc::string::ToString;
+use core::ffi::{c_char, c_int};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::OpenResult;
+use axtask::current;
+use linux_raw_sys::general::{AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_FOLLOW, AT_SYMLINK_NOFOLLOW};
+use starry_core::{
+    task::AsThread,
+    vfs::{FILE_HANDLE_SIZE, FileHandle, export_handle, lookup_handle},
+};
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use super::fd_ops::{add_to_fd, flags_to_options};
+use crate::{
+    file::{resolve_at, with_fs},
+    mm::vm_load_string,
+    syscall::sys::{sys_getegid, sys_geteuid},
+    vfs::inode_generation,
+};
+
+/// The largest `handle_bytes` user space may pass in.
+const MAX_HANDLE_SZ: u32 = 128;
+
+/// The `handle_type` of every handle we hand out.
+const FILEID_INO64_GEN: c_int = 0x81;
+
+/// Reads the `handle_bytes` field of a user `struct file_handle`.
+fn handle_bytes(handle: *mut u32) -> AxResult<u32> {
+    let bytes = handle.vm_read()?;
+    if bytes > MAX_HANDLE_SZ {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(bytes)
+}
+
+pub fn sys_name_to_handle_at(
+    dirfd: c_int,
+    path: *const c_char,
+    handle: *mut u32,
+    mount_id: *mut c_int,
+    flags: u32,
+) -> AxResult<isize> {
+    let path = vm_load_string(path)?;
+    debug!(
+        "sys_name_to_handle_at <= dirfd: {}, path: {:?}, flags: {:#x}",
+        dirfd, path, flags
+    );
+
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_FOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let mut resolve_flags = flags & AT_EMPTY_PATH;
+    if flags & AT_SYMLINK_FOLLOW == 0 {
+        resolve_flags |= AT_SYMLINK_NOFOLLOW;
+    }
+    let loc = resolve_at(dirfd, Some(path.as_str()), resolve_flags)?
+        .into_file()
+        .ok_or(AxError::OperationNotSupported)?;
+
+    // Report the required size if the buffer is too small, so that the
+    // caller can retry.
+    if (handle_bytes(handle)? as usize) < FILE_HANDLE_SIZE {
+        handle.vm_write(FILE_HANDLE_SIZE as u32)?;
+        return Err(AxError::Other(LinuxError::EOVERFLOW));
+    }
+
+    let fh = export_handle(&loc, inode_generation(loc.entry()))?;
+    handle.vm_write(FILE_HANDLE_SIZE as u32)?;
+    (handle.wrapping_add(1) as *mut c_int).vm_write(FILEID_INO64_GEN)?;
+    vm_write_slice(handle.wrapping_add(2) as *mut u8, &fh.to_bytes())?;
+    mount_id.vm_write(starry_core::vfs::mount_id(&loc) as c_int)?;
+    Ok(0)
+}
+
+pub fn sys_open_by_handle_at(mount_fd: c_int, handle: *mut u32, flags: i32) -> AxResult<isize> {
+    debug!(
+        "sys_open_by_handle_at <= mount_fd: {}, flags: {:#x}",
+        mount_fd, flags
+    );
+
+    // Opening by handle bypasses the permission checks of the directories
+    // along the path, so it needs CAP_DAC_READ_SEARCH.
+    if !current().as_thread().proc_data.cred.read().is_privileged() {
+        return Err(AxError::OperationNotPermitted);
+    }
+
+    let bytes = handle_bytes(handle)?;
+    if bytes == 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let handle_type = (handle.wrapping_add(1) as *mut c_int).vm_read()?;
+    let data = vm_load(handle.wrapping_add(2) as *const u8, bytes as usize)?;
+    let fh = FileHandle::from_bytes(&data)
+        .filter(|_| handle_type == FILEID_INO64_GEN)
+        .ok_or(AxError::Other(LinuxError::ESTALE))?;
+
+    if mount_fd != AT_FDCWD {
+        let mount = resolve_at(mount_fd, None, AT_EMPTY_PATH)?
+            .into_file()
+            .ok_or(AxError::BadFileDescriptor)?;
+        if mount.mountpoint().device() as u64 != fh.fsid {
+            return Err(AxError::Other(LinuxError::ESTALE));
+        }
+    }
+
+    let loc = lookup_handle(&fh, |loc| inode_generation(loc.entry()))?;
+    let path = loc.absolute_path()?.to_string();
+    let options = flags_to_options(flags, 0, (sys_geteuid()? as _, sys_getegid()? as _));
+    let result = with_fs(AT_FDCWD, |fs| options.open(fs, path))?;
+
+    // The file may have been renamed over since the handle was made.
+    let opened = match &result {
+        OpenResult::File(file) => file.location(),
+        OpenResult::Dir(dir) => dir,
+    };
+    if opened.metadata()?.inode != fh.ino {
+        return Err(AxError::Other(LinuxError::ESTALE));
+    }
+    add_to_fd(result, flags as _).map(|fd| fd as isize)
+}
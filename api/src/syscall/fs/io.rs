@@ -4,19 +4,27 @@ use core::{
     task::Context,
 };
 
-use axerrno::{AxError, AxResult};
-use axfs_ng::{FS_CONTEXT, FileFlags, OpenOptions};
-use axio::{Seek, SeekFrom};
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::{CachedFile, FS_CONTEXT, FileFlags, OpenOptions};
+use axio::{Buf, BufMut, Read, Seek, SeekFrom, Write};
+use axnet::SendOptions;
 use axpoll::{IoEvents, Pollable};
 use axtask::current;
-use linux_raw_sys::general::__kernel_off_t;
+use linux_raw_sys::general::{
+    __kernel_off_t, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE,
+    SPLICE_F_NONBLOCK,
+};
+use memory_addr::PAGE_SIZE_4K;
+use starry_core::{mm::deny_write_access, vfs::check_mount_writable};
 use starry_vm::{VmMutPtr, VmPtr};
 use syscalls::Sysno;
 
 use crate::{
-    file::{File, FileLike, Pipe, SealedBuf, SealedBufMut, get_file_like},
+    file::{
+        Directory, File, FileLike, Pipe, SealedBuf, SealedBufMut, Socket, get_file_like, seals,
+    },
     io::{IoVec, IoVectorBuf},
-    mm::{UserConstPtr, VmBytes, VmBytesMut},
+    mm::{VmBytes, VmBytesMut, vm_load_string},
 };
 
 struct DummyFd;
@@ -97,20 +105,43 @@ pub fn sys_lseek(fd: c_int, offset: __kernel_off_t, whence: c_int) -> AxResult<i
         2 => SeekFrom::End(offset as _),
         _ => return Err(AxError::InvalidInput),
     };
+    if let Ok(dir) = Directory::from_fd(fd) {
+        return seek_dir(&dir, pos);
+    }
     let off = File::from_fd(fd)?.inner().seek(pos)?;
     Ok(off as _)
 }
 
-pub fn sys_truncate(path: UserConstPtr<c_char>, length: __kernel_off_t) -> AxResult<isize> {
-    let path = path.get_as_str()?;
+/// Moves the position `getdents64` lists a directory from.
+///
+/// Offsets are the `d_off` of the entries listed before, so seeking to one
+/// resumes the listing after that entry.
+fn seek_dir(dir: &Directory, pos: SeekFrom) -> AxResult<isize> {
+    let mut offset = dir.offset.lock();
+    let new = match pos {
+        SeekFrom::Start(off) => Some(off),
+        SeekFrom::Current(off) => offset.checked_add_signed(off),
+        SeekFrom::End(_) => None,
+    };
+    *offset = new
+        .filter(|&off| off <= i64::MAX as u64)
+        .ok_or(AxError::InvalidInput)?;
+    Ok(*offset as _)
+}
+
+pub fn sys_truncate(path: *const c_char, length: __kernel_off_t) -> AxResult<isize> {
+    let path = vm_load_string(path)?;
     debug!("sys_truncate <= {:?} {}", path, length);
     if length < 0 {
         return Err(AxError::InvalidInput);
     }
     let file = OpenOptions::new()
         .write(true)
-        .open(&FS_CONTEXT.lock(), path)?
+        .open(&FS_CONTEXT.lock(), &path)?
         .into_file()?;
+    check_mount_writable(file.location())?;
+    deny_write_access(file.location())?;
+    seals::check_set_len(file.location(), length as _)?;
     file.access(FileFlags::WRITE)?.set_len(length as _)?;
     Ok(0)
 }
@@ -118,10 +149,26 @@ pub fn sys_truncate(path: UserConstPtr<c_char>, length: __kernel_off_t) -> AxRes
 pub fn sys_ftruncate(fd: c_int, length: __kernel_off_t) -> AxResult<isize> {
     debug!("sys_ftruncate <= {} {}", fd, length);
     let f = File::from_fd(fd)?;
+    check_mount_writable(f.inner().location())?;
+    deny_write_access(f.inner().location())?;
+    f.check_set_len(length as _)?;
     f.inner().access(FileFlags::WRITE)?.set_len(length as _)?;
     Ok(0)
 }
 
+/// Overwrites `len` bytes of `file` from `offset` with zeros.
+fn write_zeros(file: &axfs_ng::File, offset: u64, len: u64) -> AxResult<()> {
+    let zeros = [0u8; PAGE_SIZE_4K];
+    let end = offset + len;
+    let mut pos = offset;
+    while pos < end {
+        // Stay within a page, so that whole pages are written at once.
+        let chunk = (PAGE_SIZE_4K - pos as usize % PAGE_SIZE_4K).min((end - pos) as usize);
+        pos += file.write_at(&mut &zeros[..chunk], pos)? as u64;
+    }
+    Ok(())
+}
+
 pub fn sys_fallocate(
     fd: c_int,
     mode: u32,
@@ -132,13 +179,47 @@ pub fn sys_fallocate(
         "sys_fallocate <= fd: {}, mode: {}, offset: {}, len: {}",
         fd, mode, offset, len
     );
-    if mode != 0 {
+    if offset < 0 || len <= 0 {
         return Err(AxError::InvalidInput);
     }
+    if mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+        return Err(AxError::OperationNotSupported);
+    }
+    // The page cache has no way to drop pages in the middle of a file, so no
+    // hole can be punched: callers fall back to writing zeros themselves.
+    if mode & FALLOC_FL_PUNCH_HOLE != 0 {
+        return Err(AxError::OperationNotSupported);
+    }
+    let (offset, len) = (offset as u64, len as u64);
+    let end = offset + len;
+    if end > i64::MAX as u64 {
+        return Err(AxError::Other(LinuxError::EFBIG));
+    }
+
     let f = File::from_fd(fd)?;
     let inner = f.inner();
     let file = inner.access(FileFlags::WRITE)?;
-    file.set_len(file.location().len()?.max(offset as u64 + len as u64))?;
+    check_mount_writable(file.location())?;
+    let old_len = file.location().len()?;
+    let new_len = if mode & FALLOC_FL_KEEP_SIZE != 0 {
+        old_len
+    } else {
+        old_len.max(end)
+    };
+
+    if mode & FALLOC_FL_ZERO_RANGE != 0 {
+        f.check_write(new_len)?;
+        // The range is zeroed in place. Nothing past the end of the file is
+        // written, which would grow it.
+        let zero_end = end.min(old_len);
+        if offset < zero_end {
+            write_zeros(inner, offset, zero_end - offset)?;
+        }
+    }
+    if new_len != old_len {
+        f.check_set_len(new_len)?;
+        file.set_len(new_len)?;
+    }
     Ok(0)
 }
 
@@ -169,9 +250,12 @@ pub fn sys_fadvise64(
     if Pipe::from_fd(fd).is_ok() {
         return Err(AxError::BrokenPipe);
     }
-    if advice > 5 {
+    if advice > 5 || len < 0 {
         return Err(AxError::InvalidInput);
     }
+    if let Ok(f) = File::from_fd(fd) {
+        f.advise(advice, offset.max(0) as u64, len as u64);
+    }
     Ok(0)
 }
 
@@ -180,9 +264,11 @@ pub fn sys_pread64(fd: c_int, buf: *mut u8, len: usize, offset: __kernel_off_t)
     if offset < 0 {
         return Err(AxError::InvalidInput);
     }
+    f.begin_direct_io(buf as usize, len, offset as u64)?;
     let read = f
         .inner()
         .read_at(&mut VmBytesMut::new(buf, len), offset as _)?;
+    f.note_read(offset as u64, read);
     Ok(read as _)
 }
 
@@ -196,12 +282,35 @@ pub fn sys_pwrite64(
         return Ok(0);
     }
     let f = File::from_fd(fd)?;
+    f.check_write(offset as u64 + len as u64)?;
+    f.begin_direct_io(buf as usize, len, offset as u64)?;
     let write = f
         .inner()
         .write_at(&mut VmBytes::new(buf, len), offset as _)?;
+    f.end_direct_write(offset as u64, write)?;
     Ok(write as _)
 }
 
+/// Prepares an `O_DIRECT` transfer through the vector `iov`, each of whose
+/// buffers must be aligned.
+fn begin_direct_iov(
+    f: &File,
+    iov: *const IoVec,
+    iovcnt: usize,
+    offset: __kernel_off_t,
+) -> AxResult<()> {
+    if !f.direct() {
+        return Ok(());
+    }
+    // Any misaligned buffer leaves low bits set in the union.
+    let mut bits = 0;
+    for i in 0..iovcnt.min(1024) {
+        let iov = iov.wrapping_add(i).vm_read()?;
+        bits |= iov.iov_base as usize | iov.iov_len as usize;
+    }
+    f.begin_direct_io(bits, 0, offset as u64)
+}
+
 pub fn sys_preadv(
     fd: c_int,
     iov: *const IoVec,
@@ -232,6 +341,7 @@ pub fn sys_preadv2(
         fd, iovcnt, offset, _flags
     );
     let f = File::from_fd(fd)?;
+    begin_direct_iov(&f, iov, iovcnt, offset)?;
     f.inner()
         .read_at(&mut IoVectorBuf::new(iov, iovcnt)?.into_io(), offset as _)
         .map(|n| n as _)
@@ -249,9 +359,12 @@ pub fn sys_pwritev2(
         fd, iovcnt, offset, _flags
     );
     let f = File::from_fd(fd)?;
-    f.inner()
-        .read_at(&mut IoVectorBuf::new(iov, iovcnt)?.into_io(), offset as _)
-        .map(|n| n as _)
+    begin_direct_iov(&f, iov, iovcnt, offset)?;
+    let mut buf = IoVectorBuf::new(iov, iovcnt)?.into_io();
+    f.check_write(offset as u64 + buf.remaining() as u64)?;
+    let written = f.inner().write_at(&mut buf, offset as _)?;
+    f.end_direct_write(offset as u64, written)?;
+    Ok(written as _)
 }
 
 enum SendFile {
@@ -268,7 +381,15 @@ impl SendFile {
         .contains(IoEvents::IN)
     }
 
-    fn read(&mut self, mut buf: &mut [u8]) -> AxResult<usize> {
+    /// Returns the file to wait on when a transfer with it would block.
+    fn pollable(&self) -> &dyn Pollable {
+        match self {
+            SendFile::Direct(file) => &**file,
+            SendFile::Offset(file, ..) => &**file,
+        }
+    }
+
+    fn read(&self, mut buf: &mut [u8]) -> AxResult<usize> {
         match self {
             SendFile::Direct(file) => file.read(&mut buf.into()),
             SendFile::Offset(file, offset) => {
@@ -280,11 +401,12 @@ impl SendFile {
         }
     }
 
-    fn write(&mut self, mut buf: &[u8]) -> AxResult<usize> {
+    fn write(&self, mut buf: &[u8]) -> AxResult<usize> {
         match self {
             SendFile::Direct(file) => file.write(&mut buf.into()),
             SendFile::Offset(file, offset) => {
                 let off = offset.vm_read()?;
+                file.check_write(off + buf.len() as u64)?;
                 let bytes_written = file.inner().write_at(&mut buf, off)?;
                 offset.vm_write(off + bytes_written as u64)?;
                 Ok(bytes_written)
@@ -293,7 +415,110 @@ impl SendFile {
     }
 }
 
-fn do_send(mut src: SendFile, mut dst: SendFile, len: usize) -> AxResult<usize> {
+/// A range of a file's page cache.
+///
+/// [`Buf::consume`] hands out slices of the cached pages themselves, letting
+/// the network stack gather them into its own buffers without the bounce
+/// buffer of [`do_send`].
+struct CachedRange<'a> {
+    cache: &'a CachedFile,
+    offset: u64,
+    len: usize,
+}
+
+impl Read for CachedRange<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> AxResult<usize> {
+        let len = buf.len().min(self.len);
+        let read = self.cache.read_at(&mut &mut buf[..len], self.offset)?;
+        self.offset += read as u64;
+        self.len -= read;
+        Ok(read)
+    }
+}
+
+impl Buf for CachedRange<'_> {
+    fn remaining(&self) -> usize {
+        self.len
+    }
+
+    fn consume(&mut self, mut f: impl FnMut(&[u8]) -> AxResult<usize>) -> AxResult<usize> {
+        let mut consumed = 0;
+        while self.len > 0 {
+            let in_page = self.offset as usize % PAGE_SIZE_4K;
+            let chunk = (PAGE_SIZE_4K - in_page).min(self.len);
+            let page = (self.offset / PAGE_SIZE_4K as u64) as u32;
+            let taken = match self
+                .cache
+                .with_page(page, |data| f(&data[in_page..in_page + chunk]))
+                .and_then(|it| it)
+            {
+                Ok(taken) => taken,
+                Err(_) if consumed > 0 => break,
+                Err(err) => return Err(err),
+            };
+            self.offset += taken as u64;
+            self.len -= taken;
+            consumed += taken;
+            if taken < chunk {
+                break;
+            }
+        }
+        Ok(consumed)
+    }
+}
+
+/// Sends from the page cache of a file straight to a socket.
+///
+/// Returns `None` when the copying path must be taken instead: the
+/// destination is not a socket, the source is not in the page cache (e.g. a
+/// device) or the transfer does not start on a page boundary.
+fn send_cached(src: &SendFile, dst: &SendFile, len: usize) -> Option<AxResult<usize>> {
+    let SendFile::Direct(dst) = dst else {
+        return None;
+    };
+    let socket = dst.clone().into_any().downcast::<Socket>().ok()?;
+    let (file, offset) = match src {
+        SendFile::Direct(file) => (file.clone().into_any().downcast::<File>().ok()?, None),
+        SendFile::Offset(file, offset) => (file.clone(), Some(*offset)),
+    };
+    let start = match offset {
+        Some(offset) => offset.vm_read(),
+        None => file.inner().seek(SeekFrom::Current(0)),
+    };
+    let start = match start {
+        Ok(start) if start % PAGE_SIZE_4K as u64 == 0 => start,
+        Ok(_) => return None,
+        Err(err) => return Some(Err(err)),
+    };
+
+    file.with_page_cache(|cache| {
+        let len = cache
+            .location()
+            .len()?
+            .saturating_sub(start)
+            .min(len as u64) as usize;
+        let mut range = CachedRange {
+            cache,
+            offset: start,
+            len,
+        };
+        let sent = socket.send_checked(&mut range, SendOptions::default(), false)?;
+        let end = start + sent as u64;
+        match offset {
+            Some(offset) => offset.vm_write(end)?,
+            None => {
+                file.inner().seek(SeekFrom::Start(end))?;
+            }
+        }
+        Ok(sent)
+    })
+}
+
+fn do_send(src: SendFile, dst: SendFile, len: usize) -> AxResult<usize> {
+    if let Some(sent) = send_cached(&src, &dst, len) {
+        return sent;
+    }
+
     let mut buf = vec![0; 0x1000];
     let mut total_written = 0;
     let mut remaining = len;
@@ -390,7 +615,7 @@ pub fn sys_splice(
     fd_out: c_int,
     off_out: *mut i64,
     len: usize,
-    _flags: u32,
+    flags: u32,
 ) -> AxResult<isize> {
     debug!(
         "sys_splice <= fd_in: {}, off_in: {}, fd_out: {}, off_out: {}, len: {}, flags: {}",
@@ -399,7 +624,7 @@ pub fn sys_splice(
         fd_out,
         !off_out.is_null(),
         len,
-        _flags
+        flags
     );
 
     let mut has_pipe = false;
@@ -454,5 +679,57 @@ pub fn sys_splice(
         return Err(AxError::InvalidInput);
     }
 
-    do_send(src, dst, len).map(|n| n as _)
+    do_splice(src, dst, len, flags & SPLICE_F_NONBLOCK != 0).map(|n| n as _)
+}
+
+/// Like [`do_send`], but moves data straight between the pipe's pages and
+/// the other file instead of bouncing it through a temporary buffer.
+fn do_splice(src: SendFile, dst: SendFile, len: usize, non_blocking: bool) -> AxResult<usize> {
+    let as_pipe = |file: &SendFile| match file {
+        SendFile::Direct(file) => file.clone().into_any().downcast::<Pipe>().ok(),
+        SendFile::Offset(..) => None,
+    };
+    if let Some(pipe) = as_pipe(&src)
+        && as_pipe(&dst).is_none()
+    {
+        return pipe.splice_out(len, non_blocking, Some(dst.pollable()), |chunk| {
+            dst.write(chunk)
+        });
+    }
+    if let Some(pipe) = as_pipe(&dst)
+        && as_pipe(&src).is_none()
+    {
+        return pipe.splice_in(len, non_blocking, Some(src.pollable()), |buf| src.read(buf));
+    }
+    do_send(src, dst, len)
+}
+
+pub fn sys_tee(fd_in: c_int, fd_out: c_int, len: usize, flags: u32) -> AxResult<isize> {
+    debug!(
+        "sys_tee <= fd_in: {}, fd_out: {}, len: {}, flags: {}",
+        fd_in, fd_out, len, flags
+    );
+    let src = Pipe::from_fd(fd_in).map_err(|_| AxError::InvalidInput)?;
+    let dst = Pipe::from_fd(fd_out).map_err(|_| AxError::InvalidInput)?;
+    src.tee(&dst, len, flags & SPLICE_F_NONBLOCK != 0)
+        .map(|n| n as _)
+}
+
+pub fn sys_vmsplice(fd: c_int, iov: *const IoVec, nr_segs: usize, flags: u32) -> AxResult<isize> {
+    debug!(
+        "sys_vmsplice <= fd: {}, nr_segs: {}, flags: {}",
+        fd, nr_segs, flags
+    );
+    let pipe = Pipe::from_fd(fd).map_err(|_| AxError::BadFileDescriptor)?;
+    let non_blocking = flags & SPLICE_F_NONBLOCK != 0;
+    // The user pages are copied into the pipe; `SPLICE_F_GIFT` is only a hint.
+    let mut io = IoVectorBuf::new(iov, nr_segs)?.into_io();
+    if pipe.is_write() {
+        let len = io.remaining();
+        pipe.splice_in(len, non_blocking, None, |buf| io.read(buf))
+    } else {
+        let len = io.remaining_mut();
+        pipe.splice_out(len, non_blocking, None, |chunk| io.write(chunk))
+    }
+    .map(|n| n as _)
 }
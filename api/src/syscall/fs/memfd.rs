@@ -3,16 +3,13 @@ use core::ffi::c_char;
 
 use axerrno::{AxError, AxResult};
 use axfs_ng::{FS_CONTEXT, OpenOptions};
-use linux_raw_sys::general::MFD_CLOEXEC;
+use linux_raw_sys::general::{MFD_ALLOW_SEALING, MFD_CLOEXEC};
 
-use crate::{
-    file::{File, FileLike},
-    mm::UserConstPtr,
-};
+use crate::file::{File, FileLike};
 
 // TODO: correct memfd implementation
 
-pub fn sys_memfd_create(_name: UserConstPtr<c_char>, flags: u32) -> AxResult<isize> {
+pub fn sys_memfd_create(_name: *const c_char, flags: u32) -> AxResult<isize> {
     // This is cursed
     for id in 0..0xffff {
         let name = format!("/tmp/memfd-{id:04x}");
@@ -25,7 +22,10 @@ pub fn sys_memfd_create(_name: UserConstPtr<c_char>, flags: u32) -> AxResult<isi
                 .open(&fs, &name)?
                 .into_file()?;
             let cloexec = flags & MFD_CLOEXEC != 0;
-            return File::new(file).add_to_fd_table(cloexec).map(|fd| fd as _);
+            let allow_sealing = flags & MFD_ALLOW_SEALING != 0;
+            return File::new_memfd(file, allow_sealing)?
+                .add_to_fd_table(cloexec)
+                .map(|fd| fd as _);
         }
     }
     Err(AxError::TooManyOpenFiles)
@@ -1,13 +1,20 @@
+mod aio;
 mod ctl;
 mod event;
+mod fanotify;
 mod fd_ops;
+mod handle;
 mod io;
 mod memfd;
 mod mount;
+mod perf;
 mod pidfd;
 mod pipe;
+mod quota;
 mod stat;
+mod userfaultfd;
 
 pub use self::{
-    ctl::*, event::*, fd_ops::*, io::*, memfd::*, mount::*, pidfd::*, pipe::*, stat::*,
+    aio::*, ctl::*, event::*, fanotify::*, fd_ops::*, handle::*, io::*, memfd::*, mount::*,
+    perf::*, pidfd::*, pipe::*, quota::*, stat::*, userfaultfd::*,
 };
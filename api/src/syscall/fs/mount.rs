@@ -1,41 +1,524 @@
-use core::ffi::{c_char, c_void};
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+};
+use core::ffi::{c_char, c_int, c_void};
 
 use axerrno::{AxError, AxResult};
-use axfs_ng::FS_CONTEXT;
+use axfs_ng::{FS_CONTEXT, FsContext};
+use axfs_ng_vfs::{Filesystem, Location, NodeType};
+use axtask::current;
+use linux_raw_sys::general::{
+    AT_EMPTY_PATH, AT_SYMLINK_NOFOLLOW, FSMOUNT_CLOEXEC, FSOPEN_CLOEXEC, FSPICK_CLOEXEC,
+    FSPICK_EMPTY_PATH, FSPICK_NO_AUTOMOUNT, FSPICK_SYMLINK_NOFOLLOW, MOUNT_ATTR__ATIME,
+    MOUNT_ATTR_NODEV, MOUNT_ATTR_NODIRATIME, MOUNT_ATTR_NOEXEC, MOUNT_ATTR_NOSUID,
+    MOUNT_ATTR_RDONLY, MOVE_MOUNT_F_AUTOMOUNTS, MOVE_MOUNT_F_EMPTY_PATH, MOVE_MOUNT_F_SYMLINKS,
+    MOVE_MOUNT_T_AUTOMOUNTS, MOVE_MOUNT_T_EMPTY_PATH, MOVE_MOUNT_T_SYMLINKS, MS_MOVE, MS_REMOUNT,
+};
+use starry_core::{
+    task::{AsThread, processes},
+    vfs::{
+        MountFlags, add_mount, mount_flags, mount_users, move_mount, remove_mount, set_mount_flags,
+    },
+};
+use starry_vm::VmPtr;
 
-use crate::{mm::vm_load_string, vfs::MemoryFs};
+use crate::{
+    file::{
+        FileLike, ResolveAtResult,
+        fsmount::{DetachedMount, FsConfig, FsContextFile, FsContextPhase, MountFile, MountState},
+        path_from_root, resolve_at,
+    },
+    mm::vm_load_string,
+    vfs::{MemoryFs, new_mqueuefs},
+};
+
+/// Flags of `umount2`, which only `<sys/mount.h>` has.
+const MNT_FORCE: i32 = 1;
+const MNT_DETACH: i32 = 2;
+const UMOUNT_NOFOLLOW: i32 = 8;
+
+/// Commands of `fsconfig`, from `enum fsconfig_command`.
+const FSCONFIG_SET_FLAG: u32 = 0;
+const FSCONFIG_SET_STRING: u32 = 1;
+const FSCONFIG_SET_BINARY: u32 = 2;
+const FSCONFIG_SET_PATH: u32 = 3;
+const FSCONFIG_SET_PATH_EMPTY: u32 = 4;
+const FSCONFIG_SET_FD: u32 = 5;
+const FSCONFIG_CMD_CREATE: u32 = 6;
+const FSCONFIG_CMD_RECONFIGURE: u32 = 7;
+const FSCONFIG_CMD_CREATE_EXCL: u32 = 8;
+
+/// Fails with `EPERM` unless the caller may change the mount tree.
+fn check_privileged() -> AxResult<()> {
+    if !current().as_thread().proc_data.cred.read().is_privileged() {
+        return Err(AxError::OperationNotPermitted);
+    }
+    Ok(())
+}
+
+fn is_mount_root(loc: &Location) -> bool {
+    loc.ptr_eq(&loc.mountpoint().root_location())
+}
+
+/// Creates a filesystem of type `fs_type`.
+fn new_fs(fs_type: &str) -> AxResult<Filesystem> {
+    match fs_type {
+        "tmpfs" => Ok(MemoryFs::new()),
+        "mqueue" => Ok(new_mqueuefs()),
+        _ => Err(AxError::NoSuchDevice),
+    }
+}
+
+/// Mounts `fs` on `loc` and records it, returning the root of the new mount.
+fn attach_mount(
+    loc: &Location,
+    fs: &Filesystem,
+    source: String,
+    fs_type: String,
+    flags: MountFlags,
+) -> AxResult<Location> {
+    loc.mount(fs)?;
+    let target = loc.absolute_path()?.to_string();
+    let root = FS_CONTEXT.lock().resolve(target.as_str())?;
+    add_mount(root.clone(), source, target, fs_type, flags);
+    Ok(root)
+}
+
+/// Moves the mount whose root is `from` onto `to`, returning its new root.
+fn move_mount_to(from: &Location, to: &Location) -> AxResult<Location> {
+    if !is_mount_root(from) || from.ptr_eq(&FS_CONTEXT.lock().root_dir()) {
+        return Err(AxError::InvalidInput);
+    }
+    if to.node_type() != NodeType::Directory {
+        return Err(AxError::NotADirectory);
+    }
+    let fs = from.filesystem().clone();
+    from.unmount()?;
+    to.mount(&fs)?;
+    let target = to.absolute_path()?.to_string();
+    let root = FS_CONTEXT.lock().resolve(target.as_str())?;
+    move_mount(from, root.clone(), target);
+    Ok(root)
+}
 
 pub fn sys_mount(
     source: *const c_char,
     target: *const c_char,
     fs_type: *const c_char,
-    _flags: i32,
+    flags: i32,
     _data: *const c_void,
 ) -> AxResult<isize> {
-    let source = vm_load_string(source)?;
+    check_privileged()?;
+
     let target = vm_load_string(target)?;
+    let flags = flags as u32;
+    let mount_flags = MountFlags::from_bits_truncate(flags);
+
+    if flags & MS_REMOUNT != 0 {
+        debug!(
+            "sys_mount <= remount target: {:?}, flags: {:?}",
+            target, mount_flags
+        );
+        let root = FS_CONTEXT.lock().resolve(target)?;
+        if !is_mount_root(&root) {
+            return Err(AxError::InvalidInput);
+        }
+        set_mount_flags(&root, mount_flags)?;
+        return Ok(0);
+    }
+
+    let source = vm_load_string(source)?;
+    if flags & MS_MOVE != 0 {
+        debug!(
+            "sys_mount <= move source: {:?}, target: {:?}",
+            source, target
+        );
+        let fs_ctx = FS_CONTEXT.lock();
+        let from = fs_ctx.resolve(source)?;
+        let to = fs_ctx.resolve(target.as_str())?;
+        drop(fs_ctx);
+        move_mount_to(&from, &to)?;
+        return Ok(0);
+    }
+
     let fs_type = vm_load_string(fs_type)?;
     debug!(
-        "sys_mount <= source: {:?}, target: {:?}, fs_type: {:?}",
-        source, target, fs_type
+        "sys_mount <= source: {:?}, target: {:?}, fs_type: {:?}, flags: {:?}",
+        source, target, fs_type, mount_flags
     );
 
-    if fs_type != "tmpfs" {
+    let fs = new_fs(&fs_type)?;
+    let loc = FS_CONTEXT.lock().resolve(target.as_str())?;
+    attach_mount(&loc, &fs, source, fs_type, mount_flags)?;
+    Ok(0)
+}
+
+/// Unmounts the filesystem at `target`.
+///
+/// A mount still in use fails with `EBUSY`, unless `MNT_DETACH` is given. It
+/// is then taken out of the namespace at once, while the open files and
+/// working directories on it keep the filesystem alive until they are gone.
+/// `MNT_FORCE` is accepted but changes nothing, and `MNT_EXPIRE` is not
+/// supported.
+pub fn sys_umount2(target: *const c_char, flags: i32) -> AxResult<isize> {
+    let target = vm_load_string(target)?;
+    debug!("sys_umount2 <= target: {:?}, flags: {:#x}", target, flags);
+
+    if flags & !(MNT_FORCE | MNT_DETACH | UMOUNT_NOFOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    check_privileged()?;
+    let target = if flags & UMOUNT_NOFOLLOW != 0 {
+        FS_CONTEXT.lock().resolve_no_follow(target)?
+    } else {
+        FS_CONTEXT.lock().resolve(target)?
+    };
+    if !is_mount_root(&target) {
+        return Err(AxError::InvalidInput);
+    }
+    if flags & MNT_DETACH == 0 && mount_users(&target) > 0 {
+        return Err(AxError::ResourceBusy);
+    }
+    target.unmount()?;
+    remove_mount(&target);
+    Ok(0)
+}
+
+/// Moves `fs` to `new` if its root is `old`, and its working directory too
+/// if it is there.
+fn replace_root(fs: &mut FsContext, old: &Location, new: &Location) -> AxResult<()> {
+    if !fs.root_dir().ptr_eq(old) {
+        return Ok(());
+    }
+    let cwd = fs.current_dir().clone();
+    let mut new_fs = FsContext::new(new.clone());
+    if !cwd.ptr_eq(old) {
+        new_fs = new_fs.with_current_dir(cwd)?;
+    }
+    *fs = new_fs;
+    Ok(())
+}
+
+/// Makes the mount at `new_root` the root, and mounts the old root at
+/// `put_old`, which must be below `new_root`.
+///
+/// As in Linux, every process whose root or working directory is the old
+/// root is moved. Mounts below the old root are not carried over to
+/// `put_old`, so they have to be moved to the new root first.
+pub fn sys_pivot_root(new_root: *const c_char, put_old: *const c_char) -> AxResult<isize> {
+    let new_root = vm_load_string(new_root)?;
+    let put_old = vm_load_string(put_old)?;
+    debug!(
+        "sys_pivot_root <= new_root: {:?}, put_old: {:?}",
+        new_root, put_old
+    );
+
+    check_privileged()?;
+
+    let fs_ctx = FS_CONTEXT.lock();
+    let new = fs_ctx.resolve(new_root.as_str())?;
+    let old = fs_ctx.resolve(put_old.as_str())?;
+    if new.node_type() != NodeType::Directory || old.node_type() != NodeType::Directory {
+        return Err(AxError::NotADirectory);
+    }
+    let root = fs_ctx.root_dir().clone();
+    if !is_mount_root(&new) || new.ptr_eq(&root) || path_from_root(&new, &old)?.is_none() {
+        return Err(AxError::InvalidInput);
+    }
+    drop(fs_ctx);
+
+    old.mount(root.filesystem())?;
+    let old_root = FS_CONTEXT.lock().resolve(put_old.as_str())?;
+    let target = old_root.absolute_path()?.to_string();
+    add_mount(
+        old_root,
+        "rootfs".into(),
+        target,
+        root.filesystem().name().into(),
+        MountFlags::empty(),
+    );
+
+    for proc_data in processes() {
+        let scope = proc_data.scope.read();
+        replace_root(&mut FS_CONTEXT.scope(&scope).lock(), &root, &new)?;
+    }
+    Ok(0)
+}
+
+pub fn sys_fsopen(fs_name: *const c_char, flags: u32) -> AxResult<isize> {
+    let fs_type = vm_load_string(fs_name)?;
+    debug!("sys_fsopen <= fs_name: {:?}, flags: {:#x}", fs_type, flags);
+
+    check_privileged()?;
+    if flags & !FSOPEN_CLOEXEC != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if !matches!(fs_type.as_str(), "tmpfs" | "mqueue") {
         return Err(AxError::NoSuchDevice);
     }
+    FsContextFile::new(FsConfig {
+        fs_type,
+        source: None,
+        flags: MountFlags::empty(),
+        phase: FsContextPhase::Creating,
+    })
+    .add_to_fd_table(flags & FSOPEN_CLOEXEC != 0)
+    .map(|fd| fd as isize)
+}
 
-    let fs = MemoryFs::new();
+pub fn sys_fspick(dirfd: c_int, path: *const c_char, flags: u32) -> AxResult<isize> {
+    let path = path.nullable().map(vm_load_string).transpose()?;
+    debug!(
+        "sys_fspick <= dirfd: {}, path: {:?}, flags: {:#x}",
+        dirfd, path, flags
+    );
 
-    let target = FS_CONTEXT.lock().resolve(target)?;
-    target.mount(&fs)?;
+    check_privileged()?;
+    if flags & !(FSPICK_CLOEXEC | FSPICK_SYMLINK_NOFOLLOW | FSPICK_NO_AUTOMOUNT | FSPICK_EMPTY_PATH)
+        != 0
+    {
+        return Err(AxError::InvalidInput);
+    }
+    let mut resolve_flags = 0;
+    if flags & FSPICK_SYMLINK_NOFOLLOW != 0 {
+        resolve_flags |= AT_SYMLINK_NOFOLLOW;
+    }
+    if flags & FSPICK_EMPTY_PATH != 0 {
+        resolve_flags |= AT_EMPTY_PATH;
+    }
+    let root = resolve_at(dirfd, path.as_deref(), resolve_flags)?
+        .into_file()
+        .ok_or(AxError::InvalidInput)?;
+    if !is_mount_root(&root) {
+        return Err(AxError::InvalidInput);
+    }
+    FsContextFile::new(FsConfig {
+        fs_type: root.filesystem().name().into(),
+        source: None,
+        flags: mount_flags(&root),
+        phase: FsContextPhase::Reconfiguring(root),
+    })
+    .add_to_fd_table(flags & FSPICK_CLOEXEC != 0)
+    .map(|fd| fd as isize)
+}
+
+/// Sets the flag parameter `key` of `fsconfig`. Of the superblock flags, only
+/// `ro` makes a difference here.
+fn set_flag(config: &mut FsConfig, key: &str) -> AxResult<()> {
+    match key {
+        "ro" => config.flags.insert(MountFlags::RDONLY),
+        "rw" => config.flags.remove(MountFlags::RDONLY),
+        "sync" | "dirsync" | "lazytime" | "silent" => {}
+        _ => return Err(AxError::InvalidInput),
+    }
+    Ok(())
+}
+
+pub fn sys_fsconfig(
+    fd: c_int,
+    cmd: u32,
+    key: *const c_char,
+    value: *const c_void,
+    aux: c_int,
+) -> AxResult<isize> {
+    let file = FsContextFile::from_fd(fd)?;
+    let key = key.nullable().map(vm_load_string).transpose()?;
+    debug!(
+        "sys_fsconfig <= fd: {}, cmd: {}, key: {:?}, aux: {}",
+        fd, cmd, key, aux
+    );
 
+    check_privileged()?;
+    let mut config = file.config().lock();
+    match cmd {
+        FSCONFIG_SET_FLAG => {
+            if !value.is_null() || aux != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            set_flag(&mut config, key.as_deref().ok_or(AxError::InvalidInput)?)?;
+        }
+        FSCONFIG_SET_STRING => {
+            let key = key.ok_or(AxError::InvalidInput)?;
+            if value.is_null() || aux != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            let value = vm_load_string(value.cast::<c_char>())?;
+            // Like the data of `mount`, other options such as the size and
+            // mode of a tmpfs are not taken into account.
+            if key == "source" {
+                if config.source.is_some() {
+                    return Err(AxError::InvalidInput);
+                }
+                config.source = Some(value);
+            }
+        }
+        FSCONFIG_SET_BINARY | FSCONFIG_SET_PATH | FSCONFIG_SET_PATH_EMPTY | FSCONFIG_SET_FD => {
+            return Err(AxError::InvalidInput);
+        }
+        FSCONFIG_CMD_CREATE | FSCONFIG_CMD_CREATE_EXCL => {
+            if key.is_some() || !value.is_null() || aux != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            if !matches!(config.phase, FsContextPhase::Creating) {
+                return Err(AxError::ResourceBusy);
+            }
+            // Every filesystem we create is a fresh one, so there is never an
+            // existing superblock for `FSCONFIG_CMD_CREATE_EXCL` to refuse.
+            config.phase = FsContextPhase::Created(new_fs(&config.fs_type)?);
+        }
+        FSCONFIG_CMD_RECONFIGURE => {
+            if key.is_some() || !value.is_null() || aux != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            let FsContextPhase::Reconfiguring(root) = &config.phase else {
+                return Err(AxError::ResourceBusy);
+            };
+            set_mount_flags(root, config.flags)?;
+        }
+        _ => return Err(AxError::OperationNotSupported),
+    }
     Ok(0)
 }
 
-pub fn sys_umount2(target: *const c_char, _flags: i32) -> AxResult<isize> {
-    let target = vm_load_string(target)?;
-    debug!("sys_umount2 <= target: {:?}", target);
-    let target = FS_CONTEXT.lock().resolve(target)?;
-    target.unmount()?;
+pub fn sys_fsmount(fs_fd: c_int, flags: u32, attr_flags: u32) -> AxResult<isize> {
+    debug!(
+        "sys_fsmount <= fs_fd: {}, flags: {:#x}, attr_flags: {:#x}",
+        fs_fd, flags, attr_flags
+    );
+
+    check_privileged()?;
+    if flags & !FSMOUNT_CLOEXEC != 0
+        || attr_flags
+            & !(MOUNT_ATTR_RDONLY
+                | MOUNT_ATTR_NOSUID
+                | MOUNT_ATTR_NODEV
+                | MOUNT_ATTR_NOEXEC
+                | MOUNT_ATTR__ATIME
+                | MOUNT_ATTR_NODIRATIME)
+            != 0
+    {
+        return Err(AxError::InvalidInput);
+    }
+    let file = FsContextFile::from_fd(fs_fd)?;
+    let mut config = file.config().lock();
+    let fs = match core::mem::replace(&mut config.phase, FsContextPhase::Mounted) {
+        FsContextPhase::Created(fs) => fs,
+        phase => {
+            config.phase = phase;
+            return Err(AxError::InvalidInput);
+        }
+    };
+
+    let mut mount_flags = config.flags;
+    for (attr, flag) in [
+        (MOUNT_ATTR_RDONLY, MountFlags::RDONLY),
+        (MOUNT_ATTR_NOSUID, MountFlags::NOSUID),
+        (MOUNT_ATTR_NODEV, MountFlags::NODEV),
+        (MOUNT_ATTR_NOEXEC, MountFlags::NOEXEC),
+    ] {
+        if attr_flags & attr != 0 {
+            mount_flags.insert(flag);
+        }
+    }
+    MountFile::new(DetachedMount {
+        fs,
+        source: config.source.clone().unwrap_or_else(|| "none".into()),
+        fs_type: config.fs_type.clone(),
+        flags: mount_flags,
+    })
+    .add_to_fd_table(flags & FSMOUNT_CLOEXEC != 0)
+    .map(|fd| fd as isize)
+}
+
+/// Resolves one end of `move_mount`, following symlinks and taking an empty
+/// path only if `move_mount` was told to.
+fn resolve_move_mount_end(
+    dirfd: c_int,
+    path: *const c_char,
+    symlinks: bool,
+    empty_path: bool,
+) -> AxResult<ResolveAtResult> {
+    let path = path.nullable().map(vm_load_string).transpose()?;
+    let mut flags = 0;
+    if !symlinks {
+        flags |= AT_SYMLINK_NOFOLLOW;
+    }
+    if empty_path {
+        flags |= AT_EMPTY_PATH;
+    }
+    resolve_at(dirfd, path.as_deref(), flags)
+}
+
+/// Attaches a mount from `fsmount` to the filesystem tree, or moves an
+/// attached one.
+///
+/// Detached mounts can't be looked into before they are attached, as there
+/// is nothing to resolve paths against until then.
+pub fn sys_move_mount(
+    from_dirfd: c_int,
+    from_path: *const c_char,
+    to_dirfd: c_int,
+    to_path: *const c_char,
+    flags: u32,
+) -> AxResult<isize> {
+    debug!(
+        "sys_move_mount <= from_dirfd: {}, to_dirfd: {}, flags: {:#x}",
+        from_dirfd, to_dirfd, flags
+    );
+
+    check_privileged()?;
+    if flags
+        & !(MOVE_MOUNT_F_SYMLINKS
+            | MOVE_MOUNT_F_AUTOMOUNTS
+            | MOVE_MOUNT_F_EMPTY_PATH
+            | MOVE_MOUNT_T_SYMLINKS
+            | MOVE_MOUNT_T_AUTOMOUNTS
+            | MOVE_MOUNT_T_EMPTY_PATH)
+        != 0
+    {
+        return Err(AxError::InvalidInput);
+    }
+    let from = resolve_move_mount_end(
+        from_dirfd,
+        from_path,
+        flags & MOVE_MOUNT_F_SYMLINKS != 0,
+        flags & MOVE_MOUNT_F_EMPTY_PATH != 0,
+    )?;
+    let to = resolve_move_mount_end(
+        to_dirfd,
+        to_path,
+        flags & MOVE_MOUNT_T_SYMLINKS != 0,
+        flags & MOVE_MOUNT_T_EMPTY_PATH != 0,
+    )?
+    .into_file()
+    .ok_or(AxError::InvalidInput)?;
+    if to.node_type() != NodeType::Directory {
+        return Err(AxError::NotADirectory);
+    }
+
+    match from {
+        ResolveAtResult::File(from) => {
+            move_mount_to(&from, &to)?;
+        }
+        ResolveAtResult::Other(file) => {
+            let file: Arc<MountFile> = file
+                .into_any()
+                .downcast()
+                .map_err(|_| AxError::InvalidInput)?;
+            let mut state = file.state().lock();
+            let root = match &*state {
+                MountState::Detached(mount) => attach_mount(
+                    &to,
+                    &mount.fs,
+                    mount.source.clone(),
+                    mount.fs_type.clone(),
+                    mount.flags,
+                )?,
+                MountState::Attached(root) => move_mount_to(root, &to)?,
+            };
+            *state = MountState::Attached(root);
+        }
+    }
     Ok(0)
 }
@@ -0,0 +1,67 @@
+use axconfig::plat::CPU_NUM;
+use axerrno::{AxError, AxResult};
+use axtask::current;
+use starry_core::task::{AsThread, get_task};
+use starry_vm::VmPtr;
+
+use crate::file::{
+    add_file_like,
+    perf::{PerfEvent, PerfEventAttr},
+};
+
+const PERF_FLAG_FD_NO_GROUP: u32 = 1 << 0;
+const PERF_FLAG_FD_OUTPUT: u32 = 1 << 1;
+const PERF_FLAG_FD_CLOEXEC: u32 = 1 << 3;
+
+/// `PERF_ATTR_SIZE_VER0`, the size of the first published
+/// `struct perf_event_attr`.
+const PERF_ATTR_SIZE_VER0: u32 = 64;
+
+pub fn sys_perf_event_open(
+    attr: *const PerfEventAttr,
+    pid: i32,
+    cpu: i32,
+    group_fd: i32,
+    flags: u32,
+) -> AxResult<isize> {
+    let attr = attr.vm_read()?;
+    debug!(
+        "sys_perf_event_open <= type: {}, config: {}, pid: {}, cpu: {}, group_fd: {}, flags: {:#x}",
+        attr.ty, attr.config, pid, cpu, group_fd, flags
+    );
+
+    if flags & !(PERF_FLAG_FD_NO_GROUP | PERF_FLAG_FD_OUTPUT | PERF_FLAG_FD_CLOEXEC) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if attr.size != 0 && attr.size < PERF_ATTR_SIZE_VER0 {
+        return Err(AxError::InvalidInput);
+    }
+    // Groups are not supported; every event is read on its own.
+    if group_fd != -1 {
+        return Err(AxError::InvalidInput);
+    }
+    if cpu < -1 || cpu >= CPU_NUM as i32 {
+        return Err(AxError::InvalidInput);
+    }
+    // System-wide events would need per-CPU counters.
+    if pid == -1 {
+        return Err(AxError::OperationNotSupported);
+    }
+
+    let curr = current();
+    let task = if pid == 0 {
+        curr.clone()
+    } else {
+        let task = get_task(pid as _)?;
+        let cred = curr.as_thread().proc_data.cred.read();
+        let target = task.as_thread().proc_data.cred.read();
+        if !cred.is_privileged() && (target.uid != cred.euid || target.euid != cred.euid) {
+            return Err(AxError::PermissionDenied);
+        }
+        drop(target);
+        task
+    };
+
+    let event = PerfEvent::new(&attr, task)?;
+    add_file_like(event as _, flags & PERF_FLAG_FD_CLOEXEC != 0).map(|fd| fd as _)
+}
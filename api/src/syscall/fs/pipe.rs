@@ -2,7 +2,7 @@ use core::ffi::c_int;
 
 use axerrno::AxResult;
 use bitflags::bitflags;
-use linux_raw_sys::general::{O_CLOEXEC, O_NONBLOCK};
+use linux_raw_sys::general::{O_CLOEXEC, O_DIRECT, O_NONBLOCK};
 use starry_vm::VmMutPtr;
 
 use crate::file::{FileLike, Pipe, close_file_like};
@@ -15,6 +15,8 @@ bitflags! {
         const CLOEXEC = O_CLOEXEC;
         /// Create a non-blocking pipe.
         const NONBLOCK = O_NONBLOCK;
+        /// Create a pipe that performs I/O in "packet" mode.
+        const DIRECT = O_DIRECT;
     }
 }
 
@@ -28,7 +30,7 @@ pub fn sys_pipe2(fds: *mut [c_int; 2], flags: u32) -> AxResult<isize> {
     };
 
     let cloexec = flags.contains(PipeFlags::CLOEXEC);
-    let (read_end, write_end) = Pipe::new();
+    let (read_end, write_end) = Pipe::new(flags.contains(PipeFlags::DIRECT));
     if flags.contains(PipeFlags::NONBLOCK) {
         read_end.set_nonblocking(true)?;
         write_end.set_nonblocking(true)?;
@@ -0,0 +1,237 @@
+use alloc::sync::Arc;
+use core::ffi::{c_char, c_int};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::FS_CONTEXT;
+use axfs_ng_vfs::Location;
+use axtask::current;
+use bytemuck::{AnyBitPattern, NoUninit};
+use linux_raw_sys::general::AT_EMPTY_PATH;
+use starry_core::{
+    task::AsThread,
+    vfs::{DiskQuota, Dquot, QuotaType, mount_by_source},
+};
+use starry_vm::{VmMutPtr, VmPtr};
+
+use crate::{file::resolve_at, mm::vm_load_string, vfs::disk_quota};
+
+const Q_SYNC: u32 = 0x80_0001;
+const Q_QUOTAON: u32 = 0x80_0002;
+const Q_QUOTAOFF: u32 = 0x80_0003;
+const Q_GETFMT: u32 = 0x80_0004;
+const Q_GETINFO: u32 = 0x80_0005;
+const Q_SETINFO: u32 = 0x80_0006;
+const Q_GETQUOTA: u32 = 0x80_0007;
+const Q_SETQUOTA: u32 = 0x80_0008;
+const Q_GETNEXTQUOTA: u32 = 0x80_0009;
+
+const QFMT_VFS_V0: u32 = 2;
+const QFMT_VFS_V1: u32 = 4;
+
+/// The unit of the space limits, in bytes.
+const QIF_DQBLKSIZE: u64 = 1024;
+
+const QIF_BLIMITS: u32 = 1;
+const QIF_SPACE: u32 = 2;
+const QIF_ILIMITS: u32 = 4;
+const QIF_INODES: u32 = 8;
+const QIF_BTIME: u32 = 16;
+const QIF_ITIME: u32 = 32;
+const QIF_ALL: u32 = 0x3f;
+
+const IIF_BGRACE: u32 = 1;
+const IIF_IGRACE: u32 = 2;
+const IIF_ALL: u32 = 7;
+
+/// `struct if_nextdqblk`, which is `struct if_dqblk` with the id in place of
+/// the padding at its end.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, AnyBitPattern, NoUninit)]
+struct IfDqblk {
+    bhardlimit: u64,
+    bsoftlimit: u64,
+    curspace: u64,
+    ihardlimit: u64,
+    isoftlimit: u64,
+    curinodes: u64,
+    btime: u64,
+    itime: u64,
+    valid: u32,
+    id: u32,
+}
+
+impl From<Dquot> for IfDqblk {
+    fn from(dquot: Dquot) -> Self {
+        Self {
+            bhardlimit: dquot.space_hard / QIF_DQBLKSIZE,
+            bsoftlimit: dquot.space_soft / QIF_DQBLKSIZE,
+            curspace: dquot.space,
+            ihardlimit: dquot.inodes_hard,
+            isoftlimit: dquot.inodes_soft,
+            curinodes: dquot.inodes,
+            btime: dquot.space_deadline,
+            itime: dquot.inodes_deadline,
+            valid: QIF_ALL,
+            id: 0,
+        }
+    }
+}
+
+/// `struct if_dqinfo`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern, NoUninit)]
+struct IfDqinfo {
+    bgrace: u64,
+    igrace: u64,
+    flags: u32,
+    valid: u32,
+}
+
+/// Returns the quotas of the filesystem `special` names: a mount source,
+/// such as the block device a filesystem is mounted from, or any path on the
+/// filesystem.
+fn lookup(special: *const c_char) -> AxResult<Arc<DiskQuota>> {
+    let special = vm_load_string(special)?;
+    let root = match mount_by_source(&special) {
+        Some(root) => root,
+        None => FS_CONTEXT
+            .lock()
+            .resolve(&special)?
+            .mountpoint()
+            .root_location(),
+    };
+    quota_of(&root)
+}
+
+fn quota_of(loc: &Location) -> AxResult<Arc<DiskQuota>> {
+    disk_quota(loc.mountpoint().root_location().entry()).ok_or(AxError::Other(LinuxError::ENOSYS))
+}
+
+/// Checks that the caller may run `cmd` for user or group `id`: anyone may
+/// look at their own quota, everything else is for root.
+fn check_permission(cmd: u32, ty: QuotaType, id: u32) -> AxResult<()> {
+    let curr = current();
+    let cred = curr.as_thread().proc_data.cred.read();
+    let own = match ty {
+        QuotaType::User => cred.euid == id,
+        QuotaType::Group => cred.egid == id,
+    };
+    match cmd {
+        Q_SYNC | Q_GETFMT | Q_GETINFO => Ok(()),
+        Q_GETQUOTA if own => Ok(()),
+        _ if cred.is_privileged() => Ok(()),
+        _ => Err(AxError::OperationNotPermitted),
+    }
+}
+
+fn quotactl(quota: &DiskQuota, cmd: u32, ty: QuotaType, id: u32, addr: usize) -> AxResult<isize> {
+    check_permission(cmd, ty, id)?;
+    let no_quota = AxError::Other(LinuxError::ESRCH);
+    match cmd {
+        // Usage lives in memory, there is no quota file to write back.
+        Q_SYNC => Ok(0),
+        Q_QUOTAON => {
+            // `addr` names the quota file, which isn't needed as usage is
+            // always tracked.
+            if id != QFMT_VFS_V0 && id != QFMT_VFS_V1 {
+                return Err(no_quota);
+            }
+            quota.set_on(ty, true)?;
+            Ok(0)
+        }
+        Q_QUOTAOFF => {
+            quota.set_on(ty, false)?;
+            Ok(0)
+        }
+        _ if !quota.is_on(ty) => Err(no_quota),
+        Q_GETFMT => {
+            (addr as *mut u32).vm_write(QFMT_VFS_V1)?;
+            Ok(0)
+        }
+        Q_GETINFO => {
+            let info = quota.info(ty);
+            (addr as *mut IfDqinfo).vm_write(IfDqinfo {
+                bgrace: info.space_grace,
+                igrace: info.inodes_grace,
+                flags: 0,
+                valid: IIF_ALL,
+            })?;
+            Ok(0)
+        }
+        Q_SETINFO => {
+            let new = (addr as *const IfDqinfo).vm_read()?;
+            let mut info = quota.info(ty);
+            if new.valid & IIF_BGRACE != 0 {
+                info.space_grace = new.bgrace;
+            }
+            if new.valid & IIF_IGRACE != 0 {
+                info.inodes_grace = new.igrace;
+            }
+            quota.set_info(ty, info);
+            Ok(0)
+        }
+        Q_GETQUOTA => {
+            (addr as *mut IfDqblk).vm_write(quota.get(ty, id).into())?;
+            Ok(0)
+        }
+        Q_GETNEXTQUOTA => {
+            let (id, dquot) = quota.next(ty, id).ok_or(AxError::NotFound)?;
+            (addr as *mut IfDqblk).vm_write(IfDqblk { id, ..dquot.into() })?;
+            Ok(0)
+        }
+        Q_SETQUOTA => {
+            let new = (addr as *const IfDqblk).vm_read()?;
+            quota.update(ty, id, |dquot| {
+                if new.valid & QIF_BLIMITS != 0 {
+                    dquot.space_hard = new.bhardlimit.saturating_mul(QIF_DQBLKSIZE);
+                    dquot.space_soft = new.bsoftlimit.saturating_mul(QIF_DQBLKSIZE);
+                }
+                if new.valid & QIF_SPACE != 0 {
+                    dquot.space = new.curspace;
+                }
+                if new.valid & QIF_ILIMITS != 0 {
+                    dquot.inodes_hard = new.ihardlimit;
+                    dquot.inodes_soft = new.isoftlimit;
+                }
+                if new.valid & QIF_INODES != 0 {
+                    dquot.inodes = new.curinodes;
+                }
+                if new.valid & QIF_BTIME != 0 {
+                    dquot.space_deadline = new.btime;
+                }
+                if new.valid & QIF_ITIME != 0 {
+                    dquot.inodes_deadline = new.itime;
+                }
+            });
+            Ok(0)
+        }
+        _ => Err(AxError::InvalidInput),
+    }
+}
+
+pub fn sys_quotactl(cmd: u32, special: *const c_char, id: u32, addr: usize) -> AxResult<isize> {
+    debug!(
+        "sys_quotactl <= cmd: {:#x}, special: {:?}, id: {}, addr: {:#x}",
+        cmd, special, id, addr
+    );
+
+    let (cmd, ty) = (cmd >> 8, QuotaType::from_raw(cmd & 0xff)?);
+    // Syncing every filesystem needs no device.
+    if cmd == Q_SYNC && special.is_null() {
+        return Ok(0);
+    }
+    quotactl(&lookup(special)?, cmd, ty, id, addr)
+}
+
+pub fn sys_quotactl_fd(fd: c_int, cmd: u32, id: u32, addr: usize) -> AxResult<isize> {
+    debug!(
+        "sys_quotactl_fd <= fd: {}, cmd: {:#x}, id: {}, addr: {:#x}",
+        fd, cmd, id, addr
+    );
+
+    let (cmd, ty) = (cmd >> 8, QuotaType::from_raw(cmd & 0xff)?);
+    let loc = resolve_at(fd, None, AT_EMPTY_PATH)?
+        .into_file()
+        .ok_or(AxError::InvalidInput)?;
+    quotactl(&quota_of(&loc)?, cmd, ty, id, addr)
+}
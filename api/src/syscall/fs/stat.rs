@@ -6,6 +6,7 @@ use axfs_ng_vfs::{Location, NodePermission};
 use linux_raw_sys::general::{
     __kernel_fsid_t, AT_EMPTY_PATH, R_OK, W_OK, X_OK, stat, statfs, statx,
 };
+use starry_core::vfs::mount_flags;
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
@@ -34,7 +35,7 @@ pub fn sys_fstat(fd: i32, statbuf: *mut stat) -> AxResult<isize> {
 ///
 /// Return 0 if success.
 #[cfg(target_arch = "x86_64")]
-pub fn sys_lstat(path: *const c_char, statbuf: UserPtr<stat>) -> AxResult<isize> {
+pub fn sys_lstat(path: *const c_char, statbuf: *mut stat) -> AxResult<isize> {
     use linux_raw_sys::general::{AT_FDCWD, AT_SYMLINK_FOLLOW};
 
     sys_fstatat(AT_FDCWD, path, statbuf, AT_SYMLINK_FOLLOW)
@@ -159,6 +160,7 @@ fn statfs(loc: &Location) -> AxResult<statfs> {
     result.f_namelen = stat.name_length as _;
     result.f_frsize = stat.fragment_size as _;
     result.f_flags = stat.mount_flags as _;
+    result.f_flags |= mount_flags(loc).bits() as _;
     Ok(result)
 }
 
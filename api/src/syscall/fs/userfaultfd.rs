@@ -0,0 +1,14 @@
+use axerrno::{AxError, AxResult};
+
+use crate::file::{
+    add_file_like,
+    userfaultfd::{UserFaultFd, UserFaultFdFlags},
+};
+
+pub fn sys_userfaultfd(flags: u32) -> AxResult<isize> {
+    debug!("sys_userfaultfd <= flags: {:#x}", flags);
+
+    let flags = UserFaultFdFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    let uffd = UserFaultFd::new(flags);
+    add_file_like(uffd as _, flags.contains(UserFaultFdFlags::CLOEXEC)).map(|fd| fd as _)
+}
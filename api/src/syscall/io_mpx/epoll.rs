@@ -1,25 +1,29 @@
+use alloc::vec;
 use core::time::Duration;
 
 use axerrno::{AxError, AxResult};
 use axpoll::IoEvents;
-use axtask::future::Poller;
 use bitflags::bitflags;
 use linux_raw_sys::general::{
     EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD, epoll_event, timespec,
 };
 use starry_signal::SignalSet;
+use starry_vm::{VmPtr, vm_write_slice};
 
+use super::busy_poll_timeout;
 use crate::{
     file::{
         FileLike,
         epoll::{Epoll, EpollEvent, EpollFlags},
     },
-    mm::{UserConstPtr, UserPtr, nullable},
     signal::with_replacen_blocked,
     syscall::signal::check_sigset_size,
     time::TimeValueLike,
 };
 
+/// The most events returned by a single `epoll_wait`.
+const MAX_EVENTS: usize = 1024;
+
 bitflags! {
     /// Flags for the `epoll_create` syscall.
     #[derive(Debug, Clone, Copy, Default)]
@@ -36,17 +40,12 @@ pub fn sys_epoll_create1(flags: u32) -> AxResult<isize> {
         .map(|fd| fd as isize)
 }
 
-pub fn sys_epoll_ctl(
-    epfd: i32,
-    op: u32,
-    fd: i32,
-    event: UserConstPtr<epoll_event>,
-) -> AxResult<isize> {
+pub fn sys_epoll_ctl(epfd: i32, op: u32, fd: i32, event: *const epoll_event) -> AxResult<isize> {
     let epoll = Epoll::from_fd(epfd)?;
     debug!("sys_epoll_ctl <= epfd: {}, op: {}, fd: {}", epfd, op, fd);
 
     let parse_event = || -> AxResult<(EpollEvent, EpollFlags)> {
-        let event = event.get_as_ref()?;
+        let event = unsafe { event.vm_read_uninit()?.assume_init() };
         let events = IoEvents::from_bits_truncate(event.events);
         let flags =
             EpollFlags::from_bits(event.events & !events.bits()).ok_or(AxError::InvalidInput)?;
@@ -77,10 +76,10 @@ pub fn sys_epoll_ctl(
 
 fn do_epoll_wait(
     epfd: i32,
-    events: UserPtr<epoll_event>,
+    events: *mut epoll_event,
     maxevents: i32,
     timeout: Option<Duration>,
-    sigmask: UserConstPtr<SignalSet>,
+    sigmask: *const SignalSet,
     sigsetsize: usize,
 ) -> AxResult<isize> {
     check_sigset_size(sigsetsize)?;
@@ -94,27 +93,33 @@ fn do_epoll_wait(
     if maxevents <= 0 {
         return Err(AxError::InvalidInput);
     }
-    let events = events.get_as_mut_slice(maxevents as usize)?;
+    let sigmask = match sigmask.nullable() {
+        Some(set) => Some(unsafe { set.vm_read_uninit()?.assume_init() }),
+        None => None,
+    };
+    // Events are collected in a kernel buffer and copied out afterwards; any
+    // events beyond its size stay ready for the next call.
+    let mut buf = vec![unsafe { core::mem::zeroed() }; (maxevents as usize).min(MAX_EVENTS)];
 
-    with_replacen_blocked(
-        nullable!(sigmask.get_as_ref())?.copied(),
-        || match Poller::new(epoll.as_ref(), IoEvents::IN)
-            .timeout(timeout)
-            .poll(|| epoll.poll_events(events))
-        {
-            Ok(n) => Ok(n as isize),
+    let n = with_replacen_blocked(sigmask, || {
+        match busy_poll_timeout(epoll.as_ref(), IoEvents::IN, timeout, || {
+            epoll.poll_events(&mut buf)
+        }) {
+            Ok(n) => Ok(n),
             Err(AxError::TimedOut) => Ok(0),
             Err(e) => Err(e),
-        },
-    )
+        }
+    })?;
+    vm_write_slice(events, &buf[..n])?;
+    Ok(n as isize)
 }
 
 pub fn sys_epoll_pwait(
     epfd: i32,
-    events: UserPtr<epoll_event>,
+    events: *mut epoll_event,
     maxevents: i32,
     timeout: i32,
-    sigmask: UserConstPtr<SignalSet>,
+    sigmask: *const SignalSet,
     sigsetsize: usize,
 ) -> AxResult<isize> {
     let timeout = match timeout {
@@ -127,14 +132,15 @@ pub fn sys_epoll_pwait(
 
 pub fn sys_epoll_pwait2(
     epfd: i32,
-    events: UserPtr<epoll_event>,
+    events: *mut epoll_event,
     maxevents: i32,
-    timeout: UserConstPtr<timespec>,
-    sigmask: UserConstPtr<SignalSet>,
+    timeout: *const timespec,
+    sigmask: *const SignalSet,
     sigsetsize: usize,
 ) -> AxResult<isize> {
-    let timeout = nullable!(timeout.get_as_ref())?
-        .map(|ts| ts.try_into_time_value())
-        .transpose()?;
+    let timeout = match timeout.nullable() {
+        Some(ts) => Some(unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?),
+        None => None,
+    };
     do_epoll_wait(epfd, events, maxevents, timeout, sigmask, sigsetsize)
 }
@@ -3,9 +3,20 @@ mod poll;
 mod select;
 
 use alloc::{sync::Arc, vec::Vec};
-use core::task::Context;
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+    time::Duration,
+};
 
+use axerrno::{AxError, AxResult};
+use axhal::time::monotonic_time;
 use axpoll::{IoEvents, Pollable};
+use axtask::{
+    future::{block_on, interruptible},
+    yield_now,
+};
+use starry_core::{sysctl::BUSY_POLL, timer};
 
 pub use self::{epoll::*, poll::*, select::*};
 use crate::file::FileLike;
@@ -22,3 +33,60 @@ impl Pollable for FdPollSet {
         }
     }
 }
+
+/// Calls `f` until it stops failing with [`AxError::WouldBlock`], sleeping
+/// on `pollable` in between, for at most `timeout`.
+pub(crate) fn poll_timeout<T>(
+    pollable: &(impl Pollable + ?Sized),
+    events: IoEvents,
+    timeout: Option<Duration>,
+    mut f: impl FnMut() -> AxResult<T>,
+) -> AxResult<T> {
+    block_on(interruptible(timer::timeout(
+        timeout,
+        poll_fn(|cx| match f() {
+            Err(AxError::WouldBlock) => {
+                pollable.register(cx, events);
+                // Check again in case an event arrived before we registered.
+                match f() {
+                    Err(AxError::WouldBlock) => Poll::Pending,
+                    result => Poll::Ready(result),
+                }
+            }
+            result => Poll::Ready(result),
+        }),
+    )))??
+}
+
+/// Calls `f` until it stops failing with [`AxError::WouldBlock`], yielding
+/// the CPU in between rather than sleeping, for at most `budget`.
+///
+/// This is the busy polling of `SO_BUSY_POLL` and `net.core.busy_poll`,
+/// which trades CPU time for the latency of a wakeup.
+pub(crate) fn busy_poll<T>(budget: Duration, mut f: impl FnMut() -> AxResult<T>) -> AxResult<T> {
+    let deadline = monotonic_time() + budget;
+    loop {
+        match f() {
+            Err(AxError::WouldBlock) if monotonic_time() < deadline => yield_now(),
+            result => return result,
+        }
+    }
+}
+
+/// Like [`poll_timeout`], but busy polls for up to `net.core.busy_poll`
+/// microseconds before going to sleep.
+fn busy_poll_timeout<T>(
+    pollable: &(impl Pollable + ?Sized),
+    events: IoEvents,
+    timeout: Option<Duration>,
+    mut f: impl FnMut() -> AxResult<T>,
+) -> AxResult<T> {
+    let start = monotonic_time();
+    let budget = Duration::from_micros(BUSY_POLL.get() as _);
+    match busy_poll(timeout.map_or(budget, |t| t.min(budget)), &mut f) {
+        Err(AxError::WouldBlock) => {}
+        result => return result,
+    }
+    let timeout = timeout.map(|t| t.saturating_sub(monotonic_time() - start));
+    poll_timeout(pollable, events, timeout, f)
+}
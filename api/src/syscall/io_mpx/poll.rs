@@ -3,20 +3,35 @@ use alloc::vec::Vec;
 use axerrno::{AxError, AxResult};
 use axhal::time::TimeValue;
 use axpoll::IoEvents;
-use axtask::future::Poller;
-use linux_raw_sys::general::{POLLNVAL, pollfd, timespec};
+use axtask::current;
+use linux_raw_sys::general::{POLLNVAL, RLIMIT_NOFILE, pollfd, timespec};
+use starry_core::task::AsThread;
 use starry_signal::SignalSet;
+use starry_vm::{VmPtr, vm_write_slice};
 
-use super::FdPollSet;
+use super::{FdPollSet, busy_poll_timeout};
 use crate::{
-    file::get_file_like,
-    mm::{UserConstPtr, UserPtr, nullable},
-    signal::with_replacen_blocked,
-    syscall::signal::check_sigset_size,
-    time::TimeValueLike,
+    file::get_file_like, mm::vm_load_plain, signal::with_replacen_blocked,
+    syscall::signal::check_sigset_size, time::TimeValueLike,
 };
 
 fn do_poll(
+    fds: *mut pollfd,
+    nfds: usize,
+    timeout: Option<TimeValue>,
+    sigmask: Option<SignalSet>,
+) -> AxResult<isize> {
+    let max_nofile = current().as_thread().proc_data.rlim.read()[RLIMIT_NOFILE].current;
+    if nfds as u64 > max_nofile {
+        return Err(AxError::InvalidInput);
+    }
+    let mut poll_fds = unsafe { vm_load_plain(fds, nfds)? };
+    let res = poll_fds_impl(&mut poll_fds, timeout, sigmask);
+    vm_write_slice(fds, &poll_fds)?;
+    res
+}
+
+fn poll_fds_impl(
     poll_fds: &mut [pollfd],
     timeout: Option<TimeValue>,
     sigmask: Option<SignalSet>,
@@ -53,31 +68,29 @@ fn do_poll(
     let fds = FdPollSet(fds);
 
     with_replacen_blocked(sigmask, || {
-        match Poller::new(&fds, IoEvents::empty())
-            .timeout(timeout)
-            .poll(|| {
-                let mut res = 0usize;
-                for ((fd, events), revents) in fds.0.iter().zip(revents.iter_mut()) {
-                    let mut result = fd.poll();
-                    if result.contains(IoEvents::IN) {
-                        result |= IoEvents::RDNORM;
-                    }
-                    if result.contains(IoEvents::OUT) {
-                        result |= IoEvents::WRNORM;
-                    }
-                    result &= *events;
-
-                    **revents = result.bits() as _;
-                    if **revents != 0 {
-                        res += 1;
-                    }
+        match busy_poll_timeout(&fds, IoEvents::empty(), timeout, || {
+            let mut res = 0usize;
+            for ((fd, events), revents) in fds.0.iter().zip(revents.iter_mut()) {
+                let mut result = fd.poll();
+                if result.contains(IoEvents::IN) {
+                    result |= IoEvents::RDNORM;
                 }
-                if res > 0 {
-                    Ok(res as _)
-                } else {
-                    Err(AxError::WouldBlock)
+                if result.contains(IoEvents::OUT) {
+                    result |= IoEvents::WRNORM;
                 }
-            }) {
+                result &= *events;
+
+                **revents = result.bits() as _;
+                if **revents != 0 {
+                    res += 1;
+                }
+            }
+            if res > 0 {
+                Ok(res as _)
+            } else {
+                Err(AxError::WouldBlock)
+            }
+        }) {
             Err(AxError::TimedOut) => Ok(0),
             other => other,
         }
@@ -85,28 +98,32 @@ fn do_poll(
 }
 
 #[cfg(target_arch = "x86_64")]
-pub fn sys_poll(fds: UserPtr<pollfd>, nfds: u32, timeout: i32) -> AxResult<isize> {
-    let fds = fds.get_as_mut_slice(nfds as usize)?;
+pub fn sys_poll(fds: *mut pollfd, nfds: u32, timeout: i32) -> AxResult<isize> {
     let timeout = if timeout < 0 {
         None
     } else {
         Some(TimeValue::from_millis(timeout as u64))
     };
-    do_poll(fds, timeout, None)
+    do_poll(fds, nfds as usize, timeout, None)
 }
 
 pub fn sys_ppoll(
-    fds: UserPtr<pollfd>,
+    fds: *mut pollfd,
     nfds: i32,
-    timeout: UserConstPtr<timespec>,
-    sigmask: UserConstPtr<SignalSet>,
+    timeout: *const timespec,
+    sigmask: *const SignalSet,
     sigsetsize: usize,
 ) -> AxResult<isize> {
     check_sigset_size(sigsetsize)?;
-    let fds = fds.get_as_mut_slice(nfds.try_into().map_err(|_| AxError::InvalidInput)?)?;
-    let timeout = nullable!(timeout.get_as_ref())?
-        .map(|ts| ts.try_into_time_value())
-        .transpose()?;
+    let nfds = nfds.try_into().map_err(|_| AxError::InvalidInput)?;
+    let timeout = match timeout.nullable() {
+        Some(ts) => Some(unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?),
+        None => None,
+    };
+    let sigmask = match sigmask.nullable() {
+        Some(set) => Some(unsafe { set.vm_read_uninit()?.assume_init() }),
+        None => None,
+    };
     // TODO: handle signal
-    do_poll(fds, timeout, nullable!(sigmask.get_as_ref())?.copied())
+    do_poll(fds, nfds, timeout, sigmask)
 }
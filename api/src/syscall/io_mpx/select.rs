@@ -3,20 +3,17 @@ use core::{fmt, time::Duration};
 
 use axerrno::{AxError, AxResult};
 use axpoll::IoEvents;
-use axtask::future::Poller;
 use bitmaps::Bitmap;
 use linux_raw_sys::{
     general::*,
     select_macros::{FD_ISSET, FD_SET, FD_ZERO},
 };
 use starry_signal::SignalSet;
+use starry_vm::{VmMutPtr, VmPtr};
 
-use super::FdPollSet;
+use super::{FdPollSet, busy_poll_timeout};
 use crate::{
-    file::FD_TABLE,
-    mm::{UserConstPtr, UserPtr, nullable},
-    signal::with_replacen_blocked,
-    syscall::signal::check_sigset_size,
+    file::FD_TABLE, signal::with_replacen_blocked, syscall::signal::check_sigset_size,
     time::TimeValueLike,
 };
 
@@ -42,32 +39,50 @@ impl fmt::Debug for FdSet {
     }
 }
 
+fn load_fd_set(fds: *mut __kernel_fd_set) -> AxResult<Option<__kernel_fd_set>> {
+    match fds.nullable() {
+        Some(fds) => Ok(Some(unsafe { fds.vm_read_uninit()?.assume_init() })),
+        None => Ok(None),
+    }
+}
+
+fn store_fd_set(ptr: *mut __kernel_fd_set, fds: Option<__kernel_fd_set>) -> AxResult<()> {
+    if let Some(fds) = fds {
+        ptr.vm_write(fds)?;
+    }
+    Ok(())
+}
+
 fn do_select(
     nfds: u32,
-    readfds: UserPtr<__kernel_fd_set>,
-    writefds: UserPtr<__kernel_fd_set>,
-    exceptfds: UserPtr<__kernel_fd_set>,
+    readfds_ptr: *mut __kernel_fd_set,
+    writefds_ptr: *mut __kernel_fd_set,
+    exceptfds_ptr: *mut __kernel_fd_set,
     timeout: Option<Duration>,
-    sigmask: UserConstPtr<SignalSetWithSize>,
+    sigmask: *const SignalSetWithSize,
 ) -> AxResult<isize> {
     if nfds > __FD_SETSIZE {
         return Err(AxError::InvalidInput);
     }
-    let sigmask = if let Some(sigmask) = nullable!(sigmask.get_as_ref())? {
-        check_sigset_size(sigmask.sigsetsize)?;
-        let set = sigmask.set;
-        nullable!(set.get_as_ref())?
-    } else {
-        None
+    let sigmask = match sigmask.nullable() {
+        Some(sigmask) => {
+            let sigmask = unsafe { sigmask.vm_read_uninit()?.assume_init() };
+            check_sigset_size(sigmask.sigsetsize)?;
+            match sigmask.set.nullable() {
+                Some(set) => Some(unsafe { set.vm_read_uninit()?.assume_init() }),
+                None => None,
+            }
+        }
+        None => None,
     };
 
-    let mut readfds = nullable!(readfds.get_as_mut())?;
-    let mut writefds = nullable!(writefds.get_as_mut())?;
-    let mut exceptfds = nullable!(exceptfds.get_as_mut())?;
+    let mut readfds = load_fd_set(readfds_ptr)?;
+    let mut writefds = load_fd_set(writefds_ptr)?;
+    let mut exceptfds = load_fd_set(exceptfds_ptr)?;
 
-    let read_set = FdSet::new(nfds as _, readfds.as_deref());
-    let write_set = FdSet::new(nfds as _, writefds.as_deref());
-    let except_set = FdSet::new(nfds as _, exceptfds.as_deref());
+    let read_set = FdSet::new(nfds as _, readfds.as_ref());
+    let write_set = FdSet::new(nfds as _, writefds.as_ref());
+    let except_set = FdSet::new(nfds as _, exceptfds.as_ref());
 
     debug!(
         "sys_select <= nfds: {} sets: [read: {:?}, write: {:?}, except: {:?}] timeout: {:?}",
@@ -98,96 +113,96 @@ fn do_select(
     drop(fd_table);
     let fds = FdPollSet(fds);
 
-    if let Some(readfds) = readfds.as_deref_mut() {
+    if let Some(readfds) = readfds.as_mut() {
         unsafe { FD_ZERO(readfds) };
     }
-    if let Some(writefds) = writefds.as_deref_mut() {
+    if let Some(writefds) = writefds.as_mut() {
         unsafe { FD_ZERO(writefds) };
     }
-    if let Some(exceptfds) = exceptfds.as_deref_mut() {
+    if let Some(exceptfds) = exceptfds.as_mut() {
         unsafe { FD_ZERO(exceptfds) };
     }
-    with_replacen_blocked(sigmask.copied(), || {
-        match Poller::new(&fds, IoEvents::empty())
-            .timeout(timeout)
-            .poll(|| {
-                let mut res = 0usize;
-                for ((fd, interested), index) in fds.0.iter().zip(fd_indices.iter().copied()) {
-                    let events = fd.poll() & *interested;
-                    if events.contains(IoEvents::IN)
-                        && let Some(set) = readfds.as_deref_mut()
-                    {
-                        res += 1;
-                        unsafe { FD_SET(index as _, set) };
-                    }
-                    if events.contains(IoEvents::OUT)
-                        && let Some(set) = writefds.as_deref_mut()
-                    {
-                        res += 1;
-                        unsafe { FD_SET(index as _, set) };
-                    }
-                    if events.contains(IoEvents::ERR)
-                        && let Some(set) = exceptfds.as_deref_mut()
-                    {
-                        res += 1;
-                        unsafe { FD_SET(index as _, set) };
-                    }
+    let res = with_replacen_blocked(sigmask, || {
+        match busy_poll_timeout(&fds, IoEvents::empty(), timeout, || {
+            let mut res = 0usize;
+            for ((fd, interested), index) in fds.0.iter().zip(fd_indices.iter().copied()) {
+                let events = fd.poll() & *interested;
+                if events.contains(IoEvents::IN)
+                    && let Some(set) = readfds.as_mut()
+                {
+                    res += 1;
+                    unsafe { FD_SET(index as _, set) };
                 }
-                if res > 0 {
-                    return Ok(res as _);
+                if events.contains(IoEvents::OUT)
+                    && let Some(set) = writefds.as_mut()
+                {
+                    res += 1;
+                    unsafe { FD_SET(index as _, set) };
                 }
+                if events.contains(IoEvents::ERR)
+                    && let Some(set) = exceptfds.as_mut()
+                {
+                    res += 1;
+                    unsafe { FD_SET(index as _, set) };
+                }
+            }
+            if res > 0 {
+                return Ok(res as _);
+            }
 
-                Err(AxError::WouldBlock)
-            }) {
+            Err(AxError::WouldBlock)
+        }) {
             Err(AxError::TimedOut) => Ok(0),
             other => other,
         }
-    })
+    })?;
+
+    store_fd_set(readfds_ptr, readfds)?;
+    store_fd_set(writefds_ptr, writefds)?;
+    store_fd_set(exceptfds_ptr, exceptfds)?;
+    Ok(res)
 }
 
 #[cfg(target_arch = "x86_64")]
 pub fn sys_select(
     nfds: u32,
-    readfds: UserPtr<__kernel_fd_set>,
-    writefds: UserPtr<__kernel_fd_set>,
-    exceptfds: UserPtr<__kernel_fd_set>,
-    timeout: UserConstPtr<timeval>,
+    readfds: *mut __kernel_fd_set,
+    writefds: *mut __kernel_fd_set,
+    exceptfds: *mut __kernel_fd_set,
+    timeout: *const timeval,
 ) -> AxResult<isize> {
+    let timeout = match timeout.nullable() {
+        Some(tv) => Some(unsafe { tv.vm_read_uninit()?.assume_init() }.try_into_time_value()?),
+        None => None,
+    };
     do_select(
         nfds,
         readfds,
         writefds,
         exceptfds,
-        nullable!(timeout.get_as_ref())?
-            .map(|it| it.try_into_time_value())
-            .transpose()?,
-        0.into(),
+        timeout,
+        core::ptr::null(),
     )
 }
 
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct SignalSetWithSize {
-    set: UserConstPtr<SignalSet>,
+    set: *const SignalSet,
     sigsetsize: usize,
 }
 
 pub fn sys_pselect6(
     nfds: u32,
-    readfds: UserPtr<__kernel_fd_set>,
-    writefds: UserPtr<__kernel_fd_set>,
-    exceptfds: UserPtr<__kernel_fd_set>,
-    timeout: UserConstPtr<timespec>,
-    sigmask: UserConstPtr<SignalSetWithSize>,
+    readfds: *mut __kernel_fd_set,
+    writefds: *mut __kernel_fd_set,
+    exceptfds: *mut __kernel_fd_set,
+    timeout: *const timespec,
+    sigmask: *const SignalSetWithSize,
 ) -> AxResult<isize> {
-    do_select(
-        nfds,
-        readfds,
-        writefds,
-        exceptfds,
-        nullable!(timeout.get_as_ref())?
-            .map(|ts| ts.try_into_time_value())
-            .transpose()?,
-        sigmask,
-    )
+    let timeout = match timeout.nullable() {
+        Some(ts) => Some(unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?),
+        None => None,
+    };
+    do_select(nfds, readfds, writefds, exceptfds, timeout, sigmask)
 }
@@ -6,6 +6,7 @@ fn next_ipc_id() -> i32 {
     IPC_ID.fetch_add(1, Ordering::Relaxed)
 }
 
+mod mqueue;
 mod shm;
 
-pub use self::shm::*;
+pub use self::{mqueue::*, shm::*};
@@ -0,0 +1,292 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{ffi::c_char, time::Duration};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::wall_time;
+use axpoll::IoEvents;
+use axtask::current;
+use bytemuck::{AnyBitPattern, NoUninit};
+use linux_raw_sys::general::{
+    O_ACCMODE, O_CREAT, O_EXCL, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY, timespec,
+};
+use starry_core::{
+    sysctl::{MQ_MSG_DEFAULT, MQ_MSG_MAX, MQ_MSGSIZE_DEFAULT, MQ_MSGSIZE_MAX},
+    task::AsThread,
+};
+use starry_signal::Signo;
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use crate::{
+    file::{
+        FileLike, add_file_like,
+        mqueue::{self, MQ_PRIO_MAX, MessageQueue, MqueueFile, NOTIFY_COOKIE_LEN, Notify},
+        netlink::NetlinkSocket,
+    },
+    mm::vm_load_string,
+    syscall::io_mpx::poll_timeout,
+    time::TimeValueLike,
+};
+
+/// The most messages a privileged process may give a queue.
+const HARD_MSGMAX: usize = 65536;
+/// The largest messages a privileged process may let a queue take.
+const HARD_MSGSIZEMAX: usize = 16 * 1024 * 1024;
+const NAME_MAX: usize = 255;
+
+const SIGEV_SIGNAL: i32 = 0;
+const SIGEV_NONE: i32 = 1;
+const SIGEV_THREAD: i32 = 2;
+
+/// `struct mq_attr`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, AnyBitPattern, NoUninit)]
+pub struct MqAttr {
+    mq_flags: i64,
+    mq_maxmsg: i64,
+    mq_msgsize: i64,
+    mq_curmsgs: i64,
+    reserved: [i64; 4],
+}
+
+/// `struct sigevent`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+pub struct SigEvent {
+    sigev_value: usize,
+    sigev_signo: i32,
+    sigev_notify: i32,
+    sigev_tid: i32,
+    pad: [i32; 11],
+}
+
+/// Checks the name of a queue, which libc passes without its leading `/`.
+fn load_name(name: *const c_char) -> AxResult<String> {
+    let name = vm_load_string(name)?;
+    if name.is_empty() {
+        return Err(AxError::NotFound);
+    }
+    if name.len() > NAME_MAX {
+        return Err(AxError::NameTooLong);
+    }
+    if name.contains('/') {
+        return Err(AxError::PermissionDenied);
+    }
+    Ok(name)
+}
+
+/// Returns the size of a new queue, from `attr` or the defaults.
+fn queue_size(attr: *const MqAttr) -> AxResult<(usize, usize)> {
+    if attr.is_null() {
+        return Ok((
+            MQ_MSG_DEFAULT.get().min(MQ_MSG_MAX.get()),
+            MQ_MSGSIZE_DEFAULT.get().min(MQ_MSGSIZE_MAX.get()),
+        ));
+    }
+    let attr = attr.vm_read()?;
+    if attr.mq_maxmsg <= 0 || attr.mq_msgsize <= 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let (max_msg, msg_size) = (attr.mq_maxmsg as usize, attr.mq_msgsize as usize);
+    let (msg_limit, size_limit) = if current().as_thread().proc_data.cred.read().is_privileged() {
+        (HARD_MSGMAX, HARD_MSGSIZEMAX)
+    } else {
+        (MQ_MSG_MAX.get(), MQ_MSGSIZE_MAX.get())
+    };
+    if max_msg > msg_limit || msg_size > size_limit {
+        return Err(AxError::InvalidInput);
+    }
+    Ok((max_msg, msg_size))
+}
+
+/// Turns an absolute `CLOCK_REALTIME` timeout into the time left.
+fn load_timeout(abs_timeout: *const timespec) -> AxResult<Option<Duration>> {
+    let Some(ts) = abs_timeout.nullable() else {
+        return Ok(None);
+    };
+    let deadline = unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
+    Ok(Some(deadline.saturating_sub(wall_time())))
+}
+
+pub fn sys_mq_open(
+    name: *const c_char,
+    oflag: u32,
+    mode: u32,
+    attr: *const MqAttr,
+) -> AxResult<isize> {
+    let name = load_name(name)?;
+    debug!(
+        "sys_mq_open <= name: {:?}, oflag: {:#o}, mode: {:#o}",
+        name, oflag, mode
+    );
+
+    let (read, write) = match oflag & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(AxError::InvalidInput),
+    };
+    let (queue, created) = if oflag & O_CREAT != 0 {
+        mqueue::open_or_create(&name, oflag & O_EXCL != 0, || {
+            let (max_msg, msg_size) = queue_size(attr)?;
+            let umask = current().as_thread().proc_data.umask();
+            Ok(MessageQueue::new(&name, max_msg, msg_size, mode & !umask))
+        })?
+    } else {
+        (mqueue::lookup(&name).ok_or(AxError::NotFound)?, false)
+    };
+    if !created {
+        queue.check_access(read, write)?;
+    }
+    let file = MqueueFile::new(queue, read, write, oflag & O_NONBLOCK != 0);
+    add_file_like(Arc::new(file), true).map(|fd| fd as isize)
+}
+
+pub fn sys_mq_unlink(name: *const c_char) -> AxResult<isize> {
+    let name = load_name(name)?;
+    debug!("sys_mq_unlink <= name: {:?}", name);
+
+    mqueue::unlink(&name)?;
+    Ok(0)
+}
+
+pub fn sys_mq_timedsend(
+    mqd: i32,
+    msg_ptr: *const u8,
+    msg_len: usize,
+    msg_prio: u32,
+    abs_timeout: *const timespec,
+) -> AxResult<isize> {
+    debug!(
+        "sys_mq_timedsend <= mqd: {}, msg_len: {}, msg_prio: {}",
+        mqd, msg_len, msg_prio
+    );
+
+    if msg_prio >= MQ_PRIO_MAX {
+        return Err(AxError::InvalidInput);
+    }
+    let file = MqueueFile::from_fd(mqd)?;
+    if !file.writable {
+        return Err(AxError::BadFileDescriptor);
+    }
+    let queue = &file.queue;
+    if msg_len > queue.msg_size {
+        return Err(AxError::Other(LinuxError::EMSGSIZE));
+    }
+    let data = if msg_len == 0 {
+        Vec::new()
+    } else {
+        vm_load(msg_ptr, msg_len)?
+    };
+    if file.nonblocking() {
+        queue.try_send(&data, msg_prio)?;
+    } else {
+        let timeout = load_timeout(abs_timeout)?;
+        poll_timeout(queue.as_ref(), IoEvents::OUT, timeout, || {
+            queue.try_send(&data, msg_prio)
+        })?;
+    }
+    Ok(0)
+}
+
+pub fn sys_mq_timedreceive(
+    mqd: i32,
+    msg_ptr: *mut u8,
+    msg_len: usize,
+    msg_prio: *mut u32,
+    abs_timeout: *const timespec,
+) -> AxResult<isize> {
+    debug!("sys_mq_timedreceive <= mqd: {}, msg_len: {}", mqd, msg_len);
+
+    let file = MqueueFile::from_fd(mqd)?;
+    if !file.readable {
+        return Err(AxError::BadFileDescriptor);
+    }
+    let queue = &file.queue;
+    if msg_len < queue.msg_size {
+        return Err(AxError::Other(LinuxError::EMSGSIZE));
+    }
+    let (data, prio) = if file.nonblocking() {
+        queue.try_receive()?
+    } else {
+        let timeout = load_timeout(abs_timeout)?;
+        let _waiting = queue.wait_receive();
+        poll_timeout(queue.as_ref(), IoEvents::IN, timeout, || {
+            queue.try_receive()
+        })?
+    };
+    if !data.is_empty() {
+        vm_write_slice(msg_ptr, &data)?;
+    }
+    if !msg_prio.is_null() {
+        msg_prio.vm_write(prio)?;
+    }
+    Ok(data.len() as isize)
+}
+
+pub fn sys_mq_notify(mqd: i32, sevp: *const SigEvent) -> AxResult<isize> {
+    debug!("sys_mq_notify <= mqd: {}, sevp: {:?}", mqd, sevp);
+
+    let file = MqueueFile::from_fd(mqd)?;
+    if sevp.is_null() {
+        file.queue.clear_notify();
+        return Ok(0);
+    }
+    let sev = sevp.vm_read()?;
+    let notify = match sev.sigev_notify {
+        SIGEV_NONE => Notify::None,
+        SIGEV_SIGNAL => Notify::Signal {
+            signo: u8::try_from(sev.sigev_signo)
+                .ok()
+                .and_then(Signo::from_repr)
+                .ok_or(AxError::InvalidInput)?,
+            value: sev.sigev_value,
+        },
+        // libc passes the netlink socket to notify in `sigev_signo` and the
+        // cookie to send it in `sigev_value`.
+        SIGEV_THREAD => {
+            let socket = NetlinkSocket::from_fd(sev.sigev_signo)?;
+            let cookie = vm_load(sev.sigev_value as *const u8, NOTIFY_COOKIE_LEN)?;
+            Notify::Netlink {
+                socket: Arc::downgrade(&socket),
+                cookie: cookie.try_into().unwrap(),
+            }
+        }
+        _ => return Err(AxError::InvalidInput),
+    };
+    file.queue.set_notify(notify)?;
+    Ok(0)
+}
+
+pub fn sys_mq_getsetattr(
+    mqd: i32,
+    new_attr: *const MqAttr,
+    old_attr: *mut MqAttr,
+) -> AxResult<isize> {
+    debug!("sys_mq_getsetattr <= mqd: {}", mqd);
+
+    let file = MqueueFile::from_fd(mqd)?;
+    let queue = &file.queue;
+    let attr = MqAttr {
+        mq_flags: if file.nonblocking() {
+            O_NONBLOCK as _
+        } else {
+            0
+        },
+        mq_maxmsg: queue.max_msg as _,
+        mq_msgsize: queue.msg_size as _,
+        mq_curmsgs: queue.len() as _,
+        ..Default::default()
+    };
+    if !new_attr.is_null() {
+        let new_attr = new_attr.vm_read()?;
+        // Only `O_NONBLOCK` may be changed.
+        if new_attr.mq_flags & !(O_NONBLOCK as i64) != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        file.set_nonblocking(new_attr.mq_flags != 0)?;
+    }
+    if !old_attr.is_null() {
+        old_attr.vm_write(attr)?;
+    }
+    Ok(0)
+}
@@ -0,0 +1,224 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::ffi::c_char;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use starry_core::keys::{
+    self, KEY_LINK, KEY_READ, KEY_SEARCH, KEY_SETATTR, KEY_VIEW, KEY_WRITE, Key, KeyType, Requester,
+};
+use starry_vm::{vm_load, vm_write_slice};
+
+use crate::mm::vm_load_string;
+
+const KEYCTL_GET_KEYRING_ID: u32 = 0;
+const KEYCTL_JOIN_SESSION_KEYRING: u32 = 1;
+const KEYCTL_UPDATE: u32 = 2;
+const KEYCTL_REVOKE: u32 = 3;
+const KEYCTL_CHOWN: u32 = 4;
+const KEYCTL_SETPERM: u32 = 5;
+const KEYCTL_DESCRIBE: u32 = 6;
+const KEYCTL_CLEAR: u32 = 7;
+const KEYCTL_LINK: u32 = 8;
+const KEYCTL_UNLINK: u32 = 9;
+const KEYCTL_SEARCH: u32 = 10;
+const KEYCTL_READ: u32 = 11;
+const KEYCTL_INVALIDATE: u32 = 21;
+
+fn load_payload(payload: *const u8, len: usize) -> AxResult<Vec<u8>> {
+    if len > keys::MAX_PAYLOAD {
+        return Err(AxError::InvalidInput);
+    }
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    Ok(vm_load(payload, len)?)
+}
+
+/// Copies `data` to the user buffer `buf` if it fits, returning the length
+/// of `data` either way.
+fn copy_out(data: &[u8], buf: *mut u8, buflen: usize) -> AxResult<isize> {
+    if !buf.is_null() && buflen > 0 {
+        vm_write_slice(buf, &data[..data.len().min(buflen)])?;
+    }
+    Ok(data.len() as isize)
+}
+
+/// Looks up the keyring `id`, creating it if it is a missing special
+/// keyring, and checks that `key` may be linked into it.
+fn link_into(id: i32, key: &Arc<Key>, req: &Requester) -> AxResult<()> {
+    let keyring = keys::lookup(id, true)?;
+    keyring.check(req, KEY_WRITE)?;
+    key.check(req, KEY_LINK)?;
+    keyring.link(key.clone())
+}
+
+pub fn sys_add_key(
+    ty: *const c_char,
+    description: *const c_char,
+    payload: *const u8,
+    plen: usize,
+    keyring_id: i32,
+) -> AxResult<isize> {
+    let ty = vm_load_string(ty)?;
+    let description = vm_load_string(description)?;
+    debug!(
+        "sys_add_key <= type: {:?}, description: {:?}, plen: {}, keyring: {}",
+        ty, description, plen, keyring_id
+    );
+
+    let ty = KeyType::from_name(&ty)?;
+    let payload = load_payload(payload, plen)?;
+    let keyring = keys::lookup(keyring_id, true)?;
+    let req = Requester::current();
+    Ok(keys::add(&keyring, ty, &description, &payload, &req)?.serial as isize)
+}
+
+pub fn sys_request_key(
+    ty: *const c_char,
+    description: *const c_char,
+    callout_info: *const c_char,
+    dest_keyring: i32,
+) -> AxResult<isize> {
+    let ty = vm_load_string(ty)?;
+    let description = vm_load_string(description)?;
+    debug!(
+        "sys_request_key <= type: {:?}, description: {:?}, dest: {}",
+        ty, description, dest_keyring
+    );
+
+    let ty = KeyType::from_name(&ty)?;
+    let req = Requester::current();
+    // There is no `/sbin/request-key` upcall to construct a missing key, so
+    // the callout info is of no use.
+    let key = req.search(ty, &description).inspect_err(|_| {
+        if !callout_info.is_null() {
+            debug!("sys_request_key: cannot construct key {:?}", description);
+        }
+    })?;
+    if dest_keyring != 0 {
+        link_into(dest_keyring, &key, &req)?;
+    }
+    Ok(key.serial as isize)
+}
+
+pub fn sys_keyctl(
+    option: u32,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+) -> AxResult<isize> {
+    debug!(
+        "sys_keyctl <= option: {}, args: {:#x}, {:#x}, {:#x}, {:#x}",
+        option, arg2, arg3, arg4, arg5
+    );
+
+    let id = arg2 as i32;
+    match option {
+        KEYCTL_GET_KEYRING_ID => Ok(keys::lookup(id, arg3 != 0)?.serial as isize),
+        KEYCTL_JOIN_SESSION_KEYRING => {
+            let name = (arg2 != 0).then(|| vm_load_string(arg2 as _)).transpose()?;
+            Ok(keys::join_session(name.as_deref())?.serial as isize)
+        }
+        KEYCTL_UPDATE => {
+            let payload = load_payload(arg3 as _, arg4)?;
+            let key = keys::lookup(id, false)?;
+            key.check(&Requester::current(), KEY_WRITE)?;
+            key.update(&payload)?;
+            Ok(0)
+        }
+        KEYCTL_REVOKE => {
+            let key = keys::lookup(id, false)?;
+            let req = Requester::current();
+            key.check(&req, KEY_WRITE)
+                .or_else(|_| key.check(&req, KEY_SETATTR))?;
+            key.revoke();
+            Ok(0)
+        }
+        KEYCTL_CHOWN => {
+            let key = keys::lookup(id, false)?;
+            let req = Requester::current();
+            key.check(&req, KEY_SETATTR)?;
+            let (owner, group) = key.owner();
+            let uid = Some(arg3 as u32).filter(|&uid| uid != u32::MAX && uid != owner);
+            let gid = Some(arg4 as u32).filter(|&gid| gid != u32::MAX && gid != group);
+            // Only root may give keys away, or to groups it is not in.
+            if req.uid != 0 && (uid.is_some() || gid.is_some_and(|gid| gid != req.gid)) {
+                return Err(AxError::PermissionDenied);
+            }
+            key.chown(uid, gid);
+            Ok(0)
+        }
+        KEYCTL_SETPERM => {
+            let key = keys::lookup(id, false)?;
+            let req = Requester::current();
+            key.check(&req, KEY_SETATTR)?;
+            if req.uid != 0 && req.uid != key.owner().0 {
+                return Err(AxError::PermissionDenied);
+            }
+            key.set_perm(arg3 as u32)?;
+            Ok(0)
+        }
+        KEYCTL_DESCRIBE => {
+            let key = keys::lookup(id, false)?;
+            key.check(&Requester::current(), KEY_VIEW)?;
+            let mut desc = key.describe();
+            desc.push('\0');
+            copy_out(desc.as_bytes(), arg3 as _, arg4)
+        }
+        KEYCTL_CLEAR => {
+            let keyring = keys::lookup(id, true)?;
+            keyring.check(&Requester::current(), KEY_WRITE)?;
+            keyring.clear()?;
+            Ok(0)
+        }
+        KEYCTL_LINK => {
+            let key = keys::lookup(id, false)?;
+            link_into(arg3 as i32, &key, &Requester::current())?;
+            Ok(0)
+        }
+        KEYCTL_UNLINK => {
+            let key = keys::lookup(id, false)?;
+            let keyring = keys::lookup(arg3 as i32, false)?;
+            keyring.check(&Requester::current(), KEY_WRITE)?;
+            keyring.unlink(&key)?;
+            Ok(0)
+        }
+        KEYCTL_SEARCH => {
+            let keyring = keys::lookup(id, false)?;
+            let ty = KeyType::from_name(&vm_load_string(arg3 as _)?)?;
+            let description = vm_load_string(arg4 as _)?;
+            let req = Requester::current();
+            let key = keyring.search(ty, &description, &req)?;
+            if arg5 != 0 {
+                link_into(arg5 as i32, &key, &req)?;
+            }
+            Ok(key.serial as isize)
+        }
+        KEYCTL_READ => {
+            let key = keys::lookup(id, false)?;
+            key.check(&Requester::current(), KEY_READ)?;
+            match key.ty {
+                KeyType::Keyring => {
+                    let serials = key
+                        .links()?
+                        .iter()
+                        .map(|key| key.serial)
+                        .collect::<Vec<_>>();
+                    copy_out(bytemuck::cast_slice(&serials), arg3 as _, arg4)
+                }
+                KeyType::Logon => Err(AxError::Other(LinuxError::EOPNOTSUPP)),
+                KeyType::User => key.with_payload(|payload| copy_out(payload, arg3 as _, arg4))?,
+            }
+        }
+        KEYCTL_INVALIDATE => {
+            let key = keys::lookup(id, false)?;
+            key.check(&Requester::current(), KEY_SEARCH)?;
+            key.invalidate();
+            Ok(0)
+        }
+        _ => {
+            warn!("sys_keyctl: unsupported option {}", option);
+            Err(AxError::Other(LinuxError::EOPNOTSUPP))
+        }
+    }
+}
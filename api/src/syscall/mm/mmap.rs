@@ -1,19 +1,25 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::Range;
 
 use axerrno::{AxError, AxResult};
 use axfs_ng::FileBackend;
 use axhal::paging::{MappingFlags, PageSize};
-use axmm::backend::{Backend, SharedPages};
+use axmm::{
+    AddrSpace,
+    backend::{Backend, SharedPages},
+};
 use axtask::current;
 use linux_raw_sys::general::*;
-use memory_addr::{MemoryAddr, VirtAddr, VirtAddrRange, align_up_4k};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange, align_up_4k};
 use starry_core::{
-    task::AsThread,
-    vfs::{Device, DeviceMmap},
+    mm::{RangeMap, privatize_shared_text, resident_pages},
+    sysctl::{ENFORCE_WX, OVERCOMMIT_MEMORY},
+    task::{AsThread, ProcessData, READ_IMPLIES_EXEC},
+    vfs::{Device, DeviceMmap, MountFlags, mount_flags},
 };
 use starry_vm::{vm_load, vm_write_slice};
 
-use crate::file::{File, FileLike};
+use crate::file::{File, FileLike, FileSeals, fscrypt, perf::PerfEvent};
 
 bitflags::bitflags! {
     /// `PROT_*` flags for use with [`sys_mmap`].
@@ -71,6 +77,8 @@ bitflags::bitflags! {
         const ANONYMOUS = MAP_ANONYMOUS;
         /// Populate the mapping.
         const POPULATE = MAP_POPULATE;
+        /// Lock the pages of the mapping into memory.
+        const LOCKED = MAP_LOCKED;
         /// Don't check for reservations.
         const NORESERVE = MAP_NORESERVE;
         /// Allocation is for a stack.
@@ -87,6 +95,113 @@ bitflags::bitflags! {
     }
 }
 
+/// Checks whether a mapping that may need `length` bytes of fresh memory is
+/// allowed by `vm.overcommit_memory`.
+fn check_overcommit(length: usize, map_flags: MmapFlags) -> AxResult<()> {
+    let allocator = axalloc::global_allocator();
+    let available = allocator.available_pages() * PAGE_SIZE_4K;
+    let limit = match OVERCOMMIT_MEMORY.get() {
+        1 => return Ok(()),
+        // The heuristic mode only refuses what could never fit in memory.
+        0 if map_flags.contains(MmapFlags::NORESERVE) => return Ok(()),
+        0 => available + allocator.used_pages() * PAGE_SIZE_4K,
+        _ => available,
+    };
+    if length > limit {
+        return Err(AxError::NoMemory);
+    }
+    Ok(())
+}
+
+/// Checks `range` being mapped with `prot` against `vm/enforce_wx`, which
+/// keeps memory from being writable and executable at once, or executable
+/// after having been writable, so that code only comes from files.
+fn check_wx(proc_data: &ProcessData, range: Range<usize>, prot: MmapProt) -> AxResult<()> {
+    let mode = ENFORCE_WX.get();
+    if mode == 0 || !prot.contains(MmapProt::EXEC) {
+        return Ok(());
+    }
+    let reason = if prot.contains(MmapProt::WRITE) {
+        "writable and executable"
+    } else if proc_data.written_ranges.lock().overlaps(range.clone()) {
+        "executable after being writable"
+    } else {
+        return Ok(());
+    };
+    warn!(
+        "audit: W^X violation: pid={} comm={:?} range={:#x}-{:#x}: {}{}",
+        proc_data.proc.pid(),
+        current().name(),
+        range.start,
+        range.end,
+        reason,
+        if mode == 2 { ", denied" } else { "" }
+    );
+    if mode == 2 {
+        return Err(AxError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Returns the locked ranges as they would be with `ranges` locked too,
+/// checking them against `RLIMIT_MEMLOCK`.
+fn with_locked(
+    proc_data: &ProcessData,
+    ranges: impl IntoIterator<Item = Range<usize>>,
+) -> AxResult<RangeMap<()>> {
+    let mut locked = proc_data.locked_ranges.lock().clone();
+    for range in ranges {
+        locked.set(range, Some(()));
+    }
+    let limit = proc_data.rlim.read()[RLIMIT_MEMLOCK].current;
+    if locked.total_len() as u64 > limit && !proc_data.cred.read().is_privileged() {
+        return Err(if limit == 0 {
+            AxError::OperationNotPermitted
+        } else {
+            AxError::NoMemory
+        });
+    }
+    Ok(locked)
+}
+
+/// Splits `range` along the areas mapping it, failing if part of it isn't
+/// mapped.
+fn mapped_parts(
+    aspace: &AddrSpace,
+    range: Range<usize>,
+) -> AxResult<Vec<(Range<usize>, MappingFlags)>> {
+    let mut parts = Vec::new();
+    let mut addr = range.start;
+    while addr < range.end {
+        let area = aspace
+            .find_area(VirtAddr::from(addr))
+            .ok_or(AxError::NoMemory)?;
+        let end = area.end().as_usize().min(range.end);
+        parts.push((addr..end, area.flags()));
+        addr = end;
+    }
+    Ok(parts)
+}
+
+/// Faults in the pages of `parts` with the access their areas allow, so
+/// that touching them later doesn't fault.
+fn populate_parts(
+    proc_data: &ProcessData,
+    aspace: &mut AddrSpace,
+    parts: &[(Range<usize>, MappingFlags)],
+) -> AxResult<()> {
+    parts.iter().try_for_each(|(range, flags)| {
+        let access = *flags & (MappingFlags::READ | MappingFlags::WRITE);
+        if access.is_empty() {
+            return Ok(());
+        }
+        let resident = resident_pages(aspace, range.clone());
+        let result = aspace.populate_area(VirtAddr::from(range.start), range.len(), access);
+        proc_data.add_rss(resident_pages(aspace, range.clone()) as isize - resident as isize);
+        result
+    })
+}
+
 pub fn sys_mmap(
     addr: usize,
     length: usize,
@@ -101,7 +216,7 @@ pub fn sys_mmap(
 
     let curr = current();
     let mut aspace = curr.as_thread().proc_data.aspace.lock();
-    let permission_flags = MmapProt::from_bits_truncate(prot);
+    let mut permission_flags = MmapProt::from_bits_truncate(prot);
     // TODO: check illegal flags for mmap
     let map_flags = match MmapFlags::from_bits(flags) {
         Some(flags) => flags,
@@ -151,7 +266,23 @@ pub fn sys_mmap(
     let start = if map_flags.intersects(MmapFlags::FIXED | MmapFlags::FIXED_NOREPLACE) {
         let dst_addr = VirtAddr::from(start);
         if !map_flags.contains(MmapFlags::FIXED_NOREPLACE) {
+            let proc_data = &curr.as_thread().proc_data;
+            let resident = resident_pages(&aspace, start..start + length);
             aspace.unmap(dst_addr, length)?;
+            proc_data.add_rss(-(resident as isize));
+            // The new mapping doesn't keep the state of the old one.
+            proc_data
+                .range_policies
+                .lock()
+                .set(start..start + length, None);
+            proc_data
+                .written_ranges
+                .lock()
+                .set(start..start + length, None);
+            proc_data
+                .locked_ranges
+                .lock()
+                .set(start..start + length, None);
         }
         dst_addr
     } else {
@@ -169,27 +300,89 @@ pub fn sys_mmap(
             .ok_or(AxError::NoMemory)?
     };
 
+    if fd > 0
+        && let Ok(event) = PerfEvent::from_fd(fd)
+    {
+        // The ring buffer of a perf event, which is always shared.
+        if map_type == MmapFlags::PRIVATE || offset != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let pages = event.ring_buffer(length)?;
+        aspace.map(
+            start,
+            length,
+            permission_flags.into(),
+            false,
+            Backend::new_shared(start, pages),
+        )?;
+        return Ok(start.as_usize() as _);
+    }
+
     let file = if fd > 0 {
         Some(File::from_fd(fd)?)
     } else {
         None
     };
+    // Encrypted files are only decrypted by reads and writes.
+    if file
+        .as_ref()
+        .is_some_and(|file| fscrypt::is_encrypted(file.inner().location()))
+    {
+        return Err(AxError::NoSuchDevice);
+    }
+    // Like Linux, `READ_IMPLIES_EXEC` leaves files on `noexec` mounts alone.
+    let noexec = file
+        .as_ref()
+        .is_some_and(|file| mount_flags(file.inner().location()).contains(MountFlags::NOEXEC));
+    if permission_flags.contains(MmapProt::READ)
+        && !noexec
+        && curr.as_thread().proc_data.personality() & READ_IMPLIES_EXEC != 0
+    {
+        permission_flags |= MmapProt::EXEC;
+    }
+    if noexec && permission_flags.contains(MmapProt::EXEC) {
+        return Err(AxError::OperationNotPermitted);
+    }
+    check_wx(
+        &curr.as_thread().proc_data,
+        start.as_usize()..start.as_usize() + length,
+        permission_flags,
+    )?;
+
+    // Anonymous memory and private copies of written file pages are what
+    // actually consume memory.
+    if file.is_none()
+        || (map_type == MmapFlags::PRIVATE && permission_flags.contains(MmapProt::WRITE))
+    {
+        check_overcommit(length, map_flags)?;
+    }
 
     let backend = match map_type {
         MmapFlags::SHARED | MmapFlags::SHARED_VALIDATE => {
             if let Some(file) = file {
+                if permission_flags.contains(MmapProt::WRITE)
+                    && let Ok(seals) = file.seals()
+                    && seals.intersects(FileSeals::WRITE | FileSeals::FUTURE_WRITE)
+                {
+                    return Err(AxError::OperationNotPermitted);
+                }
+                let seals = file.memfd_seals();
+                let backend = file.mmap_backend()?;
                 let file = file.inner();
-                let backend = file.backend()?.clone();
-                match file.backend()?.clone() {
+                match backend.clone() {
                     FileBackend::Cached(cache) => {
                         // TODO(mivik): file mmap page size
-                        Backend::new_file(
+                        let backend = Backend::new_file(
                             start,
                             cache,
                             file.flags(),
                             offset,
                             &curr.as_thread().proc_data.aspace,
-                        )
+                        );
+                        if let Some(seals) = seals {
+                            seals.note_mapping(&backend);
+                        }
+                        backend
                     }
                     FileBackend::Direct(loc) => {
                         let device = loc
@@ -197,15 +390,14 @@ pub fn sys_mmap(
                             .downcast::<Device>()
                             .map_err(|_| AxError::NoSuchDevice)?;
 
-                        match device.mmap() {
+                        match device.mmap_at(offset) {
                             DeviceMmap::None => {
                                 return Err(AxError::NoSuchDevice);
                             }
                             DeviceMmap::ReadOnly => {
                                 Backend::new_cow(start, page_size, backend, offset as u64, None)
                             }
-                            DeviceMmap::Physical(mut range) => {
-                                range.start += offset;
+                            DeviceMmap::Physical(range) => {
                                 if range.is_empty() {
                                     return Err(AxError::InvalidInput);
                                 }
@@ -231,7 +423,7 @@ pub fn sys_mmap(
         MmapFlags::PRIVATE => {
             if let Some(file) = file {
                 // Private mapping from a file
-                let backend = file.inner().backend()?.clone();
+                let backend = file.mmap_backend()?;
                 Backend::new_cow(start, page_size, backend, offset as u64, None)
             } else {
                 Backend::new_alloc(start, page_size)
@@ -240,8 +432,25 @@ pub fn sys_mmap(
         _ => return Err(AxError::InvalidInput),
     };
 
-    let populate = map_flags.contains(MmapFlags::POPULATE);
+    // Mappings made after `mlockall(MCL_FUTURE)` are locked too.
+    let proc_data = &curr.as_thread().proc_data;
+    let mlockall = proc_data.mlockall_flags();
+    let range = start.as_usize()..start.as_usize() + length;
+    let locked = if map_flags.contains(MmapFlags::LOCKED) || mlockall & MCL_FUTURE != 0 {
+        Some(with_locked(proc_data, [range.clone()]).map_err(|_| AxError::WouldBlock)?)
+    } else {
+        None
+    };
+    let populate = map_flags.intersects(MmapFlags::POPULATE | MmapFlags::LOCKED)
+        || (locked.is_some() && mlockall & MCL_ONFAULT == 0);
     aspace.map(start, length, permission_flags.into(), populate, backend)?;
+    proc_data.add_rss(resident_pages(&aspace, range.clone()) as isize);
+    if permission_flags.contains(MmapProt::WRITE) {
+        proc_data.written_ranges.lock().set(range, Some(()));
+    }
+    if let Some(locked) = locked {
+        *proc_data.locked_ranges.lock() = locked;
+    }
 
     Ok(start.as_usize() as _)
 }
@@ -252,13 +461,28 @@ pub fn sys_munmap(addr: usize, length: usize) -> AxResult<isize> {
     let mut aspace = curr.as_thread().proc_data.aspace.lock();
     let length = align_up_4k(length);
     let start_addr = VirtAddr::from(addr);
+    let resident = resident_pages(&aspace, addr..addr + length);
     aspace.unmap(start_addr, length)?;
+    let proc_data = &curr.as_thread().proc_data;
+    proc_data.add_rss(-(resident as isize));
+    proc_data
+        .range_policies
+        .lock()
+        .set(addr..addr + length, None);
+    proc_data
+        .written_ranges
+        .lock()
+        .set(addr..addr + length, None);
+    proc_data
+        .locked_ranges
+        .lock()
+        .set(addr..addr + length, None);
     Ok(0)
 }
 
 pub fn sys_mprotect(addr: usize, length: usize, prot: u32) -> AxResult<isize> {
     // TODO: implement PROT_GROWSUP & PROT_GROWSDOWN
-    let Some(permission_flags) = MmapProt::from_bits(prot) else {
+    let Some(mut permission_flags) = MmapProt::from_bits(prot) else {
         return Err(AxError::InvalidInput);
     };
     debug!(
@@ -271,10 +495,26 @@ pub fn sys_mprotect(addr: usize, length: usize, prot: u32) -> AxResult<isize> {
     }
 
     let curr = current();
-    let mut aspace = curr.as_thread().proc_data.aspace.lock();
+    if permission_flags.contains(MmapProt::READ)
+        && curr.as_thread().proc_data.personality() & READ_IMPLIES_EXEC != 0
+    {
+        permission_flags |= MmapProt::EXEC;
+    }
+    let proc_data = &curr.as_thread().proc_data;
+    let mut aspace = proc_data.aspace.lock();
     let length = align_up_4k(length);
     let start_addr = VirtAddr::from(addr);
+    check_wx(proc_data, addr..addr + length, permission_flags)?;
+    if permission_flags.contains(MmapProt::WRITE) {
+        privatize_shared_text(&mut aspace, addr..addr + length)?;
+    }
     aspace.protect(start_addr, length, permission_flags.into())?;
+    if permission_flags.contains(MmapProt::WRITE) {
+        proc_data
+            .written_ranges
+            .lock()
+            .set(addr..addr + length, Some(()));
+    }
 
     Ok(0)
 }
@@ -299,6 +539,12 @@ pub fn sys_mremap(addr: usize, old_size: usize, new_size: usize, flags: u32) ->
 
     let flags = aspace.find_area(addr).ok_or(AxError::NoMemory)?.flags();
     drop(aspace);
+    let was_locked = curr
+        .as_thread()
+        .proc_data
+        .locked_ranges
+        .lock()
+        .overlaps(addr.as_usize()..addr.as_usize() + old_size);
     let new_addr = sys_mmap(
         addr.as_usize(),
         new_size,
@@ -313,6 +559,9 @@ pub fn sys_mremap(addr: usize, old_size: usize, new_size: usize, flags: u32) ->
     vm_write_slice(new_addr as *mut u8, &data)?;
 
     sys_munmap(addr.as_usize(), old_size)?;
+    if was_locked {
+        sys_mlock(new_addr, new_size)?;
+    }
 
     Ok(new_addr as isize)
 }
@@ -338,6 +587,77 @@ pub fn sys_mlock(addr: usize, length: usize) -> AxResult<isize> {
     sys_mlock2(addr, length, 0)
 }
 
-pub fn sys_mlock2(_addr: usize, _length: usize, _flags: u32) -> AxResult<isize> {
+pub fn sys_mlock2(addr: usize, length: usize, flags: u32) -> AxResult<isize> {
+    debug!(
+        "sys_mlock2 <= addr: {:#x}, length: {:x}, flags: {:#x}",
+        addr, length, flags
+    );
+    if flags & !MLOCK_ONFAULT != 0 {
+        return Err(AxError::InvalidInput);
+    }
+
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let mut aspace = proc_data.aspace.lock();
+    let range = addr.align_down_4k()..align_up_4k(addr + length);
+    let parts = mapped_parts(&aspace, range.clone())?;
+    let locked = with_locked(proc_data, [range])?;
+    if flags & MLOCK_ONFAULT == 0 {
+        populate_parts(proc_data, &mut aspace, &parts)?;
+    }
+    *proc_data.locked_ranges.lock() = locked;
+    Ok(0)
+}
+
+pub fn sys_munlock(addr: usize, length: usize) -> AxResult<isize> {
+    debug!("sys_munlock <= addr: {:#x}, length: {:x}", addr, length);
+
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let aspace = proc_data.aspace.lock();
+    let range = addr.align_down_4k()..align_up_4k(addr + length);
+    mapped_parts(&aspace, range.clone())?;
+    proc_data.locked_ranges.lock().set(range, None);
+    Ok(0)
+}
+
+pub fn sys_mlockall(flags: u32) -> AxResult<isize> {
+    debug!("sys_mlockall <= flags: {:#x}", flags);
+    if flags & !(MCL_CURRENT | MCL_FUTURE | MCL_ONFAULT) != 0
+        || flags & (MCL_CURRENT | MCL_FUTURE) == 0
+    {
+        return Err(AxError::InvalidInput);
+    }
+
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let mut aspace = proc_data.aspace.lock();
+    if flags & MCL_CURRENT != 0 {
+        let parts: Vec<_> = aspace
+            .areas()
+            .map(|area| (area.start().as_usize()..area.end().as_usize(), area.flags()))
+            .collect();
+        let locked = with_locked(proc_data, parts.iter().map(|(range, _)| range.clone()))?;
+        if flags & MCL_ONFAULT == 0 {
+            populate_parts(proc_data, &mut aspace, &parts)?;
+        }
+        *proc_data.locked_ranges.lock() = locked;
+    }
+    proc_data.set_mlockall_flags(if flags & MCL_FUTURE != 0 {
+        flags & (MCL_FUTURE | MCL_ONFAULT)
+    } else {
+        0
+    });
+    Ok(0)
+}
+
+pub fn sys_munlockall() -> AxResult<isize> {
+    debug!("sys_munlockall");
+
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let _aspace = proc_data.aspace.lock();
+    proc_data.locked_ranges.lock().clear();
+    proc_data.set_mlockall_flags(0);
     Ok(0)
 }
@@ -1,4 +1,5 @@
 mod brk;
 mod mmap;
+mod numa;
 
-pub use self::{brk::*, mmap::*};
+pub use self::{brk::*, mmap::*, numa::*};
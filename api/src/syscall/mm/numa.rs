@@ -0,0 +1,371 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::paging::MappingFlags;
+use axmm::AddrSpace;
+use axtask::current;
+use memory_addr::{PAGE_SIZE_4K, VirtAddr, align_up_4k, is_aligned_4k};
+use starry_core::{
+    numa::{self, MAX_NUMNODES, MemPolicy, PolicyMode},
+    task::{AsThread, ProcessData, get_process_data},
+};
+use starry_vm::{VmMutPtr, vm_load, vm_write_slice};
+
+const MPOL_F_NODE: usize = 1 << 0;
+const MPOL_F_ADDR: usize = 1 << 1;
+const MPOL_F_MEMS_ALLOWED: usize = 1 << 2;
+
+/// The mode flags of `set_mempolicy` and `mbind`, which change how the node
+/// mask follows cpusets. There are no cpusets, so they are ignored.
+const MPOL_MODE_FLAGS: u32 = 1 << 15 | 1 << 14 | 1 << 13;
+
+const MPOL_MF_STRICT: u32 = 1 << 0;
+const MPOL_MF_MOVE: u32 = 1 << 1;
+const MPOL_MF_MOVE_ALL: u32 = 1 << 2;
+
+/// Reads a node mask of `maxnode` bits, of which only the first
+/// `maxnode - 1` count, as on Linux.
+fn load_nodemask(nodemask: *const usize, maxnode: usize) -> AxResult<u64> {
+    let bits = maxnode.saturating_sub(1);
+    if nodemask.is_null() || bits == 0 {
+        return Ok(0);
+    }
+    let words = vm_load(nodemask, bits.div_ceil(usize::BITS as usize))?;
+    let mut mask = 0;
+    for (index, word) in words.into_iter().enumerate() {
+        let remaining = bits - index * usize::BITS as usize;
+        let word = if remaining < usize::BITS as usize {
+            word & ((1 << remaining) - 1)
+        } else {
+            word
+        };
+        if index == 0 {
+            mask = word as u64;
+        } else if word != 0 {
+            return Err(AxError::InvalidInput);
+        }
+    }
+    if mask & !numa::online_nodes() != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(mask)
+}
+
+fn store_nodemask(nodemask: *mut usize, maxnode: usize, mask: u64) -> AxResult<()> {
+    if nodemask.is_null() {
+        return Ok(());
+    }
+    if maxnode < numa::num_nodes() {
+        return Err(AxError::InvalidInput);
+    }
+    let mut words = vec![0; maxnode.div_ceil(usize::BITS as usize)];
+    words[0] = mask as usize;
+    vm_write_slice(nodemask, &words)?;
+    Ok(())
+}
+
+fn policy_from_user(mode: u32, nodemask: *const usize, maxnode: usize) -> AxResult<MemPolicy> {
+    MemPolicy::new(mode & !MPOL_MODE_FLAGS, load_nodemask(nodemask, maxnode)?)
+}
+
+/// Returns the process `pid` refers to, which the caller must be allowed
+/// to move the pages of.
+fn target_process(pid: i32) -> AxResult<Arc<ProcessData>> {
+    let curr = current();
+    if pid == 0 {
+        return Ok(curr.as_thread().proc_data.clone());
+    }
+    let proc_data = get_process_data(pid as _)?;
+    let cred = curr.as_thread().proc_data.cred.read();
+    let target = proc_data.cred.read();
+    if !cred.is_privileged() && (target.uid != cred.euid || target.euid != cred.euid) {
+        return Err(AxError::OperationNotPermitted);
+    }
+    drop(target);
+    Ok(proc_data)
+}
+
+/// Returns the node of the page at `addr`, or `None` if it isn't
+/// populated.
+fn page_node(aspace: &AddrSpace, addr: VirtAddr) -> Option<usize> {
+    let (paddr, ..) = aspace.page_table().query(addr).ok()?;
+    Some(numa::node_of_paddr(paddr))
+}
+
+/// Moves the page at `addr` to `node`.
+fn move_page(aspace: &mut AddrSpace, addr: VirtAddr, node: usize) -> AxResult<()> {
+    if page_node(aspace, addr) == Some(node) {
+        return Ok(());
+    }
+    let curr = current();
+    let thr = curr.as_thread();
+    let target = MemPolicy {
+        mode: PolicyMode::Bind,
+        nodes: 1 << node,
+    };
+    thr.set_fault_policy(Some((target, 0)));
+    let result = aspace.migrate_page(addr);
+    thr.set_fault_policy(None);
+    result
+}
+
+pub fn sys_set_mempolicy(mode: i32, nodemask: *const usize, maxnode: usize) -> AxResult<isize> {
+    debug!(
+        "sys_set_mempolicy <= mode: {}, nodemask: {:?}, maxnode: {}",
+        mode, nodemask, maxnode
+    );
+    let policy = policy_from_user(mode as u32, nodemask, maxnode)?;
+    current().as_thread().set_mempolicy(policy);
+    Ok(0)
+}
+
+pub fn sys_get_mempolicy(
+    policy: *mut i32,
+    nodemask: *mut usize,
+    maxnode: usize,
+    addr: usize,
+    flags: usize,
+) -> AxResult<isize> {
+    debug!(
+        "sys_get_mempolicy <= nodemask: {:?}, maxnode: {}, addr: {:#x}, flags: {:#x}",
+        nodemask, maxnode, addr, flags
+    );
+    if flags & !(MPOL_F_NODE | MPOL_F_ADDR | MPOL_F_MEMS_ALLOWED) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if flags & MPOL_F_MEMS_ALLOWED != 0 {
+        if flags & (MPOL_F_NODE | MPOL_F_ADDR) != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        if let Some(policy) = policy.nullable() {
+            policy.vm_write(0)?;
+        }
+        store_nodemask(nodemask, maxnode, numa::online_nodes())?;
+        return Ok(0);
+    }
+
+    let curr = current();
+    let thr = curr.as_thread();
+    let (pol, node) = if flags & MPOL_F_ADDR != 0 {
+        let addr = VirtAddr::from(addr);
+        let mut aspace = thr.proc_data.aspace.lock();
+        if aspace.find_area(addr).is_none() {
+            return Err(AxError::BadAddress);
+        }
+        let pol = thr
+            .proc_data
+            .range_policy(addr.as_usize())
+            .map(|(pol, _)| pol)
+            .unwrap_or_default();
+        let node = if flags & MPOL_F_NODE != 0 {
+            // The page is faulted in to tell where it lives.
+            let page = addr.align_down_4k();
+            if page_node(&aspace, page).is_none() {
+                aspace.populate_area(page, PAGE_SIZE_4K, MappingFlags::READ)?;
+            }
+            page_node(&aspace, page)
+        } else {
+            None
+        };
+        (pol, node)
+    } else {
+        if addr != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let pol = thr.mempolicy();
+        let node = if flags & MPOL_F_NODE != 0 {
+            // The node the next page will come from.
+            if pol.mode != PolicyMode::Interleave {
+                return Err(AxError::InvalidInput);
+            }
+            Some(thr.next_alloc_node())
+        } else {
+            None
+        };
+        (pol, node)
+    };
+
+    if let Some(policy) = policy.nullable() {
+        let value = match node {
+            Some(node) => node as i32,
+            None => pol.mode as i32,
+        };
+        policy.vm_write(value)?;
+    }
+    store_nodemask(nodemask, maxnode, pol.nodes)?;
+    Ok(0)
+}
+
+pub fn sys_mbind(
+    start: usize,
+    len: usize,
+    mode: u32,
+    nodemask: *const usize,
+    maxnode: usize,
+    flags: u32,
+) -> AxResult<isize> {
+    debug!(
+        "sys_mbind <= start: {:#x}, len: {:#x}, mode: {}, nodemask: {:?}, maxnode: {}, flags: \
+         {:#x}",
+        start, len, mode, nodemask, maxnode, flags
+    );
+    if !is_aligned_4k(start) || flags & !(MPOL_MF_STRICT | MPOL_MF_MOVE | MPOL_MF_MOVE_ALL) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    if flags & MPOL_MF_MOVE_ALL != 0 && !proc_data.cred.read().is_privileged() {
+        return Err(AxError::OperationNotPermitted);
+    }
+    let policy = policy_from_user(mode, nodemask, maxnode)?;
+    let range = start..start + align_up_4k(len);
+    if range.is_empty() {
+        return Ok(0);
+    }
+
+    let mut aspace = proc_data.aspace.lock();
+    // The whole range must be mapped.
+    let mut addr = range.start;
+    while addr < range.end {
+        let area = aspace
+            .find_area(VirtAddr::from(addr))
+            .ok_or(AxError::Other(LinuxError::EFAULT))?;
+        addr = area.end().as_usize();
+    }
+    let stored = (policy.mode != PolicyMode::Default).then_some(policy);
+    proc_data.range_policies.lock().set(range.clone(), stored);
+
+    if flags & (MPOL_MF_STRICT | MPOL_MF_MOVE | MPOL_MF_MOVE_ALL) == 0 {
+        return Ok(0);
+    }
+    // Pages already there that the policy doesn't allow are moved, or
+    // reported if they may not be.
+    let mut misplaced = false;
+    for (index, addr) in range.step_by(PAGE_SIZE_4K).enumerate() {
+        let addr = VirtAddr::from(addr);
+        let Some(node) = page_node(&aspace, addr) else {
+            continue;
+        };
+        if policy.allows(node) {
+            continue;
+        }
+        let moved = flags & (MPOL_MF_MOVE | MPOL_MF_MOVE_ALL) != 0
+            && move_page(&mut aspace, addr, policy.node(index)).is_ok();
+        misplaced |= !moved;
+    }
+    if misplaced && flags & MPOL_MF_STRICT != 0 {
+        return Err(AxError::Io);
+    }
+    Ok(0)
+}
+
+pub fn sys_move_pages(
+    pid: i32,
+    count: usize,
+    pages: *const usize,
+    nodes: *const i32,
+    status: *mut i32,
+    flags: u32,
+) -> AxResult<isize> {
+    debug!(
+        "sys_move_pages <= pid: {}, count: {}, pages: {:?}, nodes: {:?}, flags: {:#x}",
+        pid, count, pages, nodes, flags
+    );
+    if flags & !(MPOL_MF_MOVE | MPOL_MF_MOVE_ALL) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let proc_data = target_process(pid)?;
+    if flags & MPOL_MF_MOVE_ALL != 0 && !current().as_thread().proc_data.cred.read().is_privileged()
+    {
+        return Err(AxError::OperationNotPermitted);
+    }
+    let addrs = vm_load(pages, count)?;
+    let targets = if nodes.is_null() {
+        None
+    } else {
+        let targets = vm_load(nodes, count)?;
+        if targets
+            .iter()
+            .any(|&node| node < 0 || node as usize >= numa::num_nodes())
+        {
+            return Err(AxError::NoSuchDevice);
+        }
+        Some(targets)
+    };
+
+    let mut aspace = proc_data.aspace.lock();
+    let mut result = Vec::with_capacity(count);
+    for (index, &addr) in addrs.iter().enumerate() {
+        let page = VirtAddr::from(addr).align_down_4k();
+        let status = if aspace.find_area(page).is_none() {
+            -(LinuxError::EFAULT.code())
+        } else if let Some(targets) = &targets {
+            let node = targets[index] as usize;
+            match page_node(&aspace, page) {
+                None => -(LinuxError::ENOENT.code()),
+                Some(_) => match move_page(&mut aspace, page, node) {
+                    Ok(()) => node as i32,
+                    Err(err) => -(LinuxError::from(err).code()),
+                },
+            }
+        } else {
+            match page_node(&aspace, page) {
+                Some(node) => node as i32,
+                None => -(LinuxError::ENOENT.code()),
+            }
+        };
+        result.push(status);
+    }
+    drop(aspace);
+    vm_write_slice(status, &result)?;
+    Ok(0)
+}
+
+pub fn sys_migrate_pages(
+    pid: i32,
+    maxnode: usize,
+    old_nodes: *const usize,
+    new_nodes: *const usize,
+) -> AxResult<isize> {
+    debug!(
+        "sys_migrate_pages <= pid: {}, maxnode: {}, old_nodes: {:?}, new_nodes: {:?}",
+        pid, maxnode, old_nodes, new_nodes
+    );
+    let old = load_nodemask(old_nodes, maxnode)?;
+    let new = load_nodemask(new_nodes, maxnode)?;
+    let proc_data = target_process(pid)?;
+    if old == 0 || new == 0 {
+        return Ok(0);
+    }
+
+    // The nth node of `old` moves to the nth node of `new`, wrapping around,
+    // unless it is in `new` too.
+    let nth = |mask: u64, n: usize| {
+        (0..MAX_NUMNODES)
+            .filter(|node| mask & (1 << node) != 0)
+            .nth(n % mask.count_ones() as usize)
+            .unwrap()
+    };
+    let mut remap = [None; MAX_NUMNODES];
+    for (n, node) in (0..MAX_NUMNODES)
+        .filter(|node| old & (1 << node) != 0)
+        .enumerate()
+    {
+        if new & (1 << node) == 0 {
+            remap[node] = Some(nth(new, n));
+        }
+    }
+
+    let mut aspace = proc_data.aspace.lock();
+    let moves: Vec<_> = aspace
+        .areas()
+        .flat_map(|area| (area.start().as_usize()..area.end().as_usize()).step_by(PAGE_SIZE_4K))
+        .map(VirtAddr::from)
+        .filter_map(|addr| Some((addr, remap[page_node(&aspace, addr)?]?)))
+        .collect();
+    let failed = moves
+        .into_iter()
+        .filter(|&(addr, node)| move_page(&mut aspace, addr, node).is_err())
+        .count();
+    Ok(failed as _)
+}
@@ -1,10 +1,12 @@
 mod fs;
 mod io_mpx;
 mod ipc;
+mod keys;
 mod mm;
 mod net;
 mod resources;
 mod signal;
+pub mod stats;
 mod sync;
 mod sys;
 mod task;
@@ -12,22 +14,79 @@ mod time;
 
 use axerrno::{AxError, LinuxError};
 use axhal::uspace::UserContext;
+use axtask::current;
+use linux_raw_sys::general::{FUTEX_CMD_MASK, FUTEX_WAIT, FUTEX_WAIT_BITSET, TIMER_ABSTIME};
+use starry_core::{task::AsThread, trace};
 use syscalls::Sysno;
 
 use self::{
-    fs::*, io_mpx::*, ipc::*, mm::*, net::*, resources::*, signal::*, sync::*, sys::*, task::*,
-    time::*,
+    fs::*, io_mpx::*, ipc::*, keys::*, mm::*, net::*, resources::*, signal::*, sync::*, sys::*,
+    task::*, time::*,
 };
+use crate::signal::{Restart, SYSCALL_INSN_LEN, should_restart};
+
+/// How `sysno`, called with the arguments in `uctx`, behaves when
+/// interrupted by a signal.
+fn restart_kind(sysno: Sysno, uctx: &UserContext) -> Restart {
+    let futex_wait = || {
+        matches!(
+            uctx.arg1() as u32 & FUTEX_CMD_MASK,
+            FUTEX_WAIT | FUTEX_WAIT_BITSET
+        )
+    };
+    match sysno {
+        // These report the interruption to the caller even if no handler
+        // runs, as on Linux.
+        Sysno::rt_sigsuspend
+        | Sysno::rt_sigtimedwait
+        | Sysno::epoll_pwait
+        | Sysno::epoll_pwait2 => Restart::Never,
+        #[cfg(target_arch = "x86_64")]
+        Sysno::epoll_wait | Sysno::pause => Restart::Never,
+        // Restarting with the same arguments would start a relative timeout
+        // over, so these report the interruption instead. `nanosleep` and
+        // `clock_nanosleep` hand back the time left.
+        Sysno::nanosleep => Restart::Never,
+        Sysno::clock_nanosleep if uctx.arg1() as u32 & TIMER_ABSTIME == 0 => Restart::Never,
+        Sysno::futex if futex_wait() && uctx.arg3() != 0 => Restart::Never,
+        Sysno::ppoll if uctx.arg2() != 0 => Restart::Never,
+        Sysno::pselect6 if uctx.arg4() != 0 => Restart::Never,
+        #[cfg(target_arch = "x86_64")]
+        Sysno::poll if (uctx.arg2() as i32) >= 0 => Restart::Never,
+        #[cfg(target_arch = "x86_64")]
+        Sysno::select if uctx.arg4() != 0 => Restart::Never,
+        #[cfg(target_arch = "x86_64")]
+        Sysno::poll | Sysno::select => Restart::NoHandler,
+        Sysno::ppoll | Sysno::pselect6 | Sysno::clock_nanosleep | Sysno::futex => {
+            Restart::NoHandler
+        }
+        _ => Restart::Sys,
+    }
+}
 
 pub fn handle_syscall(uctx: &mut UserContext) {
-    let Some(sysno) = Sysno::new(uctx.sysno()) else {
-        warn!("Invalid syscall number: {}", uctx.sysno());
+    let nr = uctx.sysno();
+    trace::sys_enter(
+        nr,
+        [
+            uctx.arg0(),
+            uctx.arg1(),
+            uctx.arg2(),
+            uctx.arg3(),
+            uctx.arg4(),
+            uctx.arg5(),
+        ],
+    );
+    let Some(sysno) = Sysno::new(nr) else {
+        warn!("Invalid syscall number: {}", nr);
+        trace::sys_exit(nr, -LinuxError::ENOSYS.code() as _);
         uctx.set_retval(-LinuxError::ENOSYS.code() as _);
         return;
     };
 
     trace!("Syscall {:?}", sysno);
 
+    let start = stats::start();
     let result = match sysno {
         // fs ctl
         Sysno::ioctl => sys_ioctl(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
@@ -126,6 +185,16 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg2() as _,
             uctx.arg3() as _,
         ),
+        Sysno::name_to_handle_at => sys_name_to_handle_at(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+        Sysno::open_by_handle_at => {
+            sys_open_by_handle_at(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _)
+        }
         Sysno::close => sys_close(uctx.arg0() as _),
         Sysno::close_range => sys_close_range(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::dup => sys_dup(uctx.arg0() as _),
@@ -141,7 +210,7 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         Sysno::write => sys_write(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::writev => sys_writev(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::lseek => sys_lseek(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
-        Sysno::truncate => sys_truncate(uctx.arg0().into(), uctx.arg1() as _),
+        Sysno::truncate => sys_truncate(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::ftruncate => sys_ftruncate(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::fallocate => sys_fallocate(
             uctx.arg0() as _,
@@ -217,54 +286,66 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg4() as _,
             uctx.arg5() as _,
         ),
+        Sysno::tee => sys_tee(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
+        Sysno::vmsplice => sys_vmsplice(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
 
         // io mpx
         #[cfg(target_arch = "x86_64")]
-        Sysno::poll => sys_poll(uctx.arg0().into(), uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::poll => sys_poll(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::ppoll => sys_ppoll(
-            uctx.arg0().into(),
+            uctx.arg0() as _,
             uctx.arg1() as _,
-            uctx.arg2().into(),
-            uctx.arg3().into(),
+            uctx.arg2() as _,
+            uctx.arg3() as _,
             uctx.arg4() as _,
         ),
         #[cfg(target_arch = "x86_64")]
         Sysno::select => sys_select(
             uctx.arg0() as _,
-            uctx.arg1().into(),
-            uctx.arg2().into(),
-            uctx.arg3().into(),
-            uctx.arg4().into(),
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
         ),
         Sysno::pselect6 => sys_pselect6(
             uctx.arg0() as _,
-            uctx.arg1().into(),
-            uctx.arg2().into(),
-            uctx.arg3().into(),
-            uctx.arg4().into(),
-            uctx.arg5().into(),
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+            uctx.arg5() as _,
         ),
         Sysno::epoll_create1 => sys_epoll_create1(uctx.arg0() as _),
         Sysno::epoll_ctl => sys_epoll_ctl(
             uctx.arg0() as _,
             uctx.arg1() as _,
             uctx.arg2() as _,
-            uctx.arg3().into(),
+            uctx.arg3() as _,
         ),
         Sysno::epoll_pwait => sys_epoll_pwait(
             uctx.arg0() as _,
-            uctx.arg1().into(),
+            uctx.arg1() as _,
             uctx.arg2() as _,
             uctx.arg3() as _,
-            uctx.arg4().into(),
+            uctx.arg4() as _,
             uctx.arg5() as _,
         ),
         Sysno::epoll_pwait2 => sys_epoll_pwait2(
             uctx.arg0() as _,
-            uctx.arg1().into(),
+            uctx.arg1() as _,
             uctx.arg2() as _,
-            uctx.arg3().into(),
-            uctx.arg4().into(),
+            uctx.arg3() as _,
+            uctx.arg4() as _,
             uctx.arg5() as _,
         ),
 
@@ -277,6 +358,36 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg4() as _,
         ) as _,
         Sysno::umount2 => sys_umount2(uctx.arg0() as _, uctx.arg1() as _) as _,
+        Sysno::quotactl => sys_quotactl(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
+        Sysno::quotactl_fd => sys_quotactl_fd(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
+        Sysno::pivot_root => sys_pivot_root(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::fsopen => sys_fsopen(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::fspick => sys_fspick(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::fsconfig => sys_fsconfig(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+        Sysno::fsmount => sys_fsmount(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::move_mount => sys_move_mount(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
 
         // pipe
         Sysno::pipe2 => sys_pipe2(uctx.arg0() as _, uctx.arg1() as _),
@@ -284,8 +395,32 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         Sysno::pipe => sys_pipe2(uctx.arg0() as _, 0),
 
         // event
+        #[cfg(target_arch = "x86_64")]
+        Sysno::eventfd => sys_eventfd2(uctx.arg0() as _, 0),
         Sysno::eventfd2 => sys_eventfd2(uctx.arg0() as _, uctx.arg1() as _),
 
+        // fanotify
+        Sysno::fanotify_init => sys_fanotify_init(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::fanotify_mark => sys_fanotify_mark(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+
+        // userfaultfd
+        Sysno::userfaultfd => sys_userfaultfd(uctx.arg0() as _),
+
+        // perf
+        Sysno::perf_event_open => sys_perf_event_open(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+
         // pidfd
         Sysno::pidfd_open => sys_pidfd_open(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::pidfd_getfd => sys_pidfd_getfd(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
@@ -297,7 +432,7 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         ),
 
         // memfd
-        Sysno::memfd_create => sys_memfd_create(uctx.arg0().into(), uctx.arg1() as _),
+        Sysno::memfd_create => sys_memfd_create(uctx.arg0() as _, uctx.arg1() as _),
 
         // fs stat
         #[cfg(target_arch = "x86_64")]
@@ -359,6 +494,41 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         Sysno::msync => sys_msync(uctx.arg0(), uctx.arg1() as _, uctx.arg2() as _),
         Sysno::mlock => sys_mlock(uctx.arg0(), uctx.arg1() as _),
         Sysno::mlock2 => sys_mlock2(uctx.arg0(), uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::munlock => sys_munlock(uctx.arg0(), uctx.arg1() as _),
+        Sysno::mlockall => sys_mlockall(uctx.arg0() as _),
+        Sysno::munlockall => sys_munlockall(),
+        Sysno::set_mempolicy => {
+            sys_set_mempolicy(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _)
+        }
+        Sysno::get_mempolicy => sys_get_mempolicy(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+        Sysno::mbind => sys_mbind(
+            uctx.arg0(),
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+            uctx.arg5() as _,
+        ),
+        Sysno::move_pages => sys_move_pages(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+            uctx.arg5() as _,
+        ),
+        Sysno::migrate_pages => sys_migrate_pages(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
 
         // task info
         Sysno::getpid => sys_getpid(),
@@ -381,15 +551,31 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         Sysno::sched_setaffinity => {
             sys_sched_setaffinity(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _)
         }
+        Sysno::getcpu => sys_getcpu(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::sched_getscheduler => sys_sched_getscheduler(uctx.arg0() as _),
         Sysno::sched_setscheduler => {
             sys_sched_setscheduler(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _)
         }
         Sysno::sched_getparam => sys_sched_getparam(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::sched_get_priority_max => sys_sched_get_priority_max(uctx.arg0() as _),
+        Sysno::sched_get_priority_min => sys_sched_get_priority_min(uctx.arg0() as _),
+        Sysno::sched_rr_get_interval => {
+            sys_sched_rr_get_interval(uctx.arg0() as _, uctx.arg1() as _)
+        }
         Sysno::getpriority => sys_getpriority(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::ioprio_set => sys_ioprio_set(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::ioprio_get => sys_ioprio_get(uctx.arg0() as _, uctx.arg1() as _),
 
         // task ops
         Sysno::execve => sys_execve(uctx, uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::execveat => sys_execveat(
+            uctx,
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
         Sysno::set_tid_address => sys_set_tid_address(uctx.arg0()),
         #[cfg(target_arch = "x86_64")]
         Sysno::arch_prctl => sys_arch_prctl(uctx, uctx.arg0() as _, uctx.arg1() as _),
@@ -400,6 +586,7 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg3() as _,
             uctx.arg4() as _,
         ),
+        Sysno::personality => sys_personality(uctx.arg0() as _),
         Sysno::prlimit64 => sys_prlimit64(
             uctx.arg0() as _,
             uctx.arg1() as _,
@@ -410,15 +597,9 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         Sysno::capset => sys_capset(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::umask => sys_umask(uctx.arg0() as _),
         Sysno::setreuid => sys_setreuid(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::setregid => sys_setregid(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::setresuid => sys_setresuid(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::setresgid => sys_setresgid(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
-        Sysno::get_mempolicy => sys_get_mempolicy(
-            uctx.arg0() as _,
-            uctx.arg1() as _,
-            uctx.arg2() as _,
-            uctx.arg3() as _,
-            uctx.arg4() as _,
-        ),
 
         // task management
         Sysno::clone => sys_clone(
@@ -429,11 +610,23 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg3(),
             uctx.arg4(),
         ),
+        Sysno::clone3 => sys_clone3(uctx, uctx.arg0() as _, uctx.arg1() as _),
         #[cfg(target_arch = "x86_64")]
         Sysno::fork => sys_fork(uctx),
         Sysno::exit => sys_exit(uctx.arg0() as _),
         Sysno::exit_group => sys_exit_group(uctx.arg0() as _),
-        Sysno::wait4 => sys_waitpid(uctx, uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::wait4 => sys_waitpid(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
+        Sysno::waitid => sys_waitid(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
         Sysno::getsid => sys_getsid(uctx.arg0() as _),
         Sysno::setsid => sys_setsid(),
         Sysno::getpgid => sys_getpgid(uctx.arg0() as _),
@@ -478,7 +671,7 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg3() as _,
             uctx.arg4() as _,
         ),
-        Sysno::sigaltstack => sys_sigaltstack(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::sigaltstack => sys_sigaltstack(uctx, uctx.arg0() as _, uctx.arg1() as _),
         Sysno::futex => sys_futex(
             uctx.arg0() as _,
             uctx.arg1() as _,
@@ -487,6 +680,13 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg4() as _,
             uctx.arg5() as _,
         ),
+        Sysno::futex_waitv => sys_futex_waitv(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
         Sysno::get_robust_list => {
             sys_get_robust_list(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _)
         }
@@ -497,15 +697,43 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         Sysno::geteuid => sys_geteuid(),
         Sysno::getgid => sys_getgid(),
         Sysno::getegid => sys_getegid(),
+        Sysno::getresuid => sys_getresuid(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::getresgid => sys_getresgid(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::setuid => sys_setuid(uctx.arg0() as _),
         Sysno::setgid => sys_setgid(uctx.arg0() as _),
         Sysno::getgroups => sys_getgroups(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::setgroups => sys_setgroups(uctx.arg0() as _, uctx.arg1() as _),
         Sysno::uname => sys_uname(uctx.arg0() as _),
         Sysno::sysinfo => sys_sysinfo(uctx.arg0() as _),
+        Sysno::reboot => sys_reboot(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
         Sysno::syslog => sys_syslog(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::getrandom => sys_getrandom(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::seccomp => sys_seccomp(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::add_key => sys_add_key(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+        Sysno::request_key => sys_request_key(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
+        Sysno::keyctl => sys_keyctl(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
         #[cfg(target_arch = "riscv64")]
         Sysno::riscv_flush_icache => sys_riscv_flush_icache(),
 
@@ -529,8 +757,33 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         // shm
         Sysno::shmget => sys_shmget(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::shmat => sys_shmat(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
-        Sysno::shmctl => sys_shmctl(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2().into()),
+        Sysno::shmctl => sys_shmctl(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::shmdt => sys_shmdt(uctx.arg0() as _),
+        Sysno::mq_open => sys_mq_open(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
+        Sysno::mq_unlink => sys_mq_unlink(uctx.arg0() as _),
+        Sysno::mq_timedsend => sys_mq_timedsend(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+        Sysno::mq_timedreceive => sys_mq_timedreceive(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+        Sysno::mq_notify => sys_mq_notify(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::mq_getsetattr => {
+            sys_mq_getsetattr(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _)
+        }
 
         // net
         Sysno::socket => sys_socket(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
@@ -538,22 +791,18 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg0() as _,
             uctx.arg1() as _,
             uctx.arg2() as _,
-            uctx.arg3().into(),
+            uctx.arg3() as _,
         ),
-        Sysno::bind => sys_bind(uctx.arg0() as _, uctx.arg1().into(), uctx.arg2() as _),
-        Sysno::connect => sys_connect(uctx.arg0() as _, uctx.arg1().into(), uctx.arg2() as _),
-        Sysno::getsockname => {
-            sys_getsockname(uctx.arg0() as _, uctx.arg1().into(), uctx.arg2().into())
-        }
-        Sysno::getpeername => {
-            sys_getpeername(uctx.arg0() as _, uctx.arg1().into(), uctx.arg2().into())
-        }
+        Sysno::bind => sys_bind(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::connect => sys_connect(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::getsockname => sys_getsockname(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::getpeername => sys_getpeername(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::listen => sys_listen(uctx.arg0() as _, uctx.arg1() as _),
-        Sysno::accept => sys_accept(uctx.arg0() as _, uctx.arg1().into(), uctx.arg2().into()),
+        Sysno::accept => sys_accept(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
         Sysno::accept4 => sys_accept4(
             uctx.arg0() as _,
-            uctx.arg1().into(),
-            uctx.arg2().into(),
+            uctx.arg1() as _,
+            uctx.arg2() as _,
             uctx.arg3() as _,
         ),
         Sysno::shutdown => sys_shutdown(uctx.arg0() as _, uctx.arg1() as _),
@@ -562,7 +811,7 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg1() as _,
             uctx.arg2() as _,
             uctx.arg3() as _,
-            uctx.arg4().into(),
+            uctx.arg4() as _,
             uctx.arg5() as _,
         ),
         Sysno::recvfrom => sys_recvfrom(
@@ -570,37 +819,58 @@ pub fn handle_syscall(uctx: &mut UserContext) {
             uctx.arg1() as _,
             uctx.arg2() as _,
             uctx.arg3() as _,
-            uctx.arg4().into(),
-            uctx.arg5().into(),
+            uctx.arg4() as _,
+            uctx.arg5() as _,
+        ),
+        Sysno::sendmsg => sys_sendmsg(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::recvmsg => sys_recvmsg(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::sendmmsg => sys_sendmmsg(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+        ),
+        Sysno::recvmmsg => sys_recvmmsg(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
+            uctx.arg4() as _,
         ),
-        Sysno::sendmsg => sys_sendmsg(uctx.arg0() as _, uctx.arg1().into(), uctx.arg2() as _),
-        Sysno::recvmsg => sys_recvmsg(uctx.arg0() as _, uctx.arg1().into(), uctx.arg2() as _),
         Sysno::getsockopt => sys_getsockopt(
             uctx.arg0() as _,
             uctx.arg1() as _,
             uctx.arg2() as _,
-            uctx.arg3().into(),
-            uctx.arg4().into(),
+            uctx.arg3() as _,
+            uctx.arg4() as _,
         ),
         Sysno::setsockopt => sys_setsockopt(
             uctx.arg0() as _,
             uctx.arg1() as _,
             uctx.arg2() as _,
-            uctx.arg3().into(),
+            uctx.arg3() as _,
+            uctx.arg4() as _,
+        ),
+
+        // native aio
+        Sysno::io_setup => sys_io_setup(uctx.arg0() as _, uctx.arg1() as _),
+        Sysno::io_destroy => sys_io_destroy(uctx.arg0() as _),
+        Sysno::io_submit => sys_io_submit(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::io_cancel => sys_io_cancel(uctx.arg0() as _, uctx.arg1() as _, uctx.arg2() as _),
+        Sysno::io_getevents => sys_io_getevents(
+            uctx.arg0() as _,
+            uctx.arg1() as _,
+            uctx.arg2() as _,
+            uctx.arg3() as _,
             uctx.arg4() as _,
         ),
 
         // dummy fds
         Sysno::signalfd4
         | Sysno::timerfd_create
-        | Sysno::fanotify_init
         | Sysno::inotify_init1
-        | Sysno::userfaultfd
-        | Sysno::perf_event_open
         | Sysno::io_uring_setup
         | Sysno::bpf
-        | Sysno::fsopen
-        | Sysno::fspick
         | Sysno::open_tree
         | Sysno::memfd_secret => sys_dummy_fd(sysno),
 
@@ -612,6 +882,21 @@ pub fn handle_syscall(uctx: &mut UserContext) {
         }
     };
     debug!("Syscall {} return {:?}", sysno, result);
+    if let Some(start) = start {
+        stats::record(nr, start, result.is_err());
+    }
+
+    if matches!(result, Err(AxError::Interrupted))
+        && should_restart(current().as_thread(), restart_kind(sysno, uctx))
+    {
+        // Leave the arguments untouched and trap into the syscall again once
+        // the signal has been handled.
+        debug!("Syscall {} restarted", sysno);
+        uctx.set_ip(uctx.ip() - SYSCALL_INSN_LEN);
+        return;
+    }
 
-    uctx.set_retval(result.unwrap_or_else(|err| -LinuxError::from(err).code() as _) as _);
+    let ret = result.unwrap_or_else(|err| -LinuxError::from(err).code() as _);
+    trace::sys_exit(nr, ret);
+    uctx.set_retval(ret as _);
 }
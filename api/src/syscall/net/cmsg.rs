@@ -1,25 +1,39 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{sync::Arc, vec, vec::Vec};
 
 use axerrno::{AxError, AxResult};
 use linux_raw_sys::net::{SCM_RIGHTS, SOL_SOCKET, cmsghdr};
+use starry_vm::{VmMutPtr, vm_load, vm_write_slice};
 
-use crate::{
-    file::{FileLike, get_file_like},
-    mm::{UserConstPtr, UserPtr},
+use crate::file::{
+    FileLike,
+    alg::{ALG_SET_AEAD_ASSOCLEN, ALG_SET_IV, ALG_SET_OP, SOL_ALG},
+    get_file_like,
 };
 
+/// The most data a control message built for `recvmsg` may carry, well
+/// above what `SCM_RIGHTS` and `SCM_CREDENTIALS` need.
+const MAX_CMSG_BODY: usize = 4096;
+
 pub enum CMsg {
-    Rights { fds: Vec<Arc<dyn FileLike>> },
+    Rights {
+        fds: Vec<Arc<dyn FileLike>>,
+    },
+    /// `ALG_SET_OP`: whether an `AF_ALG` request encrypts or decrypts.
+    AlgOp(u32),
+    /// `ALG_SET_IV`: the IV of an `AF_ALG` request.
+    AlgIv(Vec<u8>),
+    /// `ALG_SET_AEAD_ASSOCLEN`: the length of the associated data.
+    AlgAssocLen(u32),
 }
 impl CMsg {
-    pub fn parse(hdr: &cmsghdr) -> AxResult<Self> {
+    /// Parses a control message `hdr` read from the user, whose data
+    /// follows it at `data`.
+    pub fn parse(hdr: &cmsghdr, data: *const u8) -> AxResult<Self> {
         if hdr.cmsg_len < size_of::<cmsghdr>() {
             return Err(AxError::InvalidInput);
         }
 
-        let data =
-            UserConstPtr::<u8>::from((hdr as *const cmsghdr as usize) + size_of::<cmsghdr>())
-                .get_as_slice(hdr.cmsg_len - size_of::<cmsghdr>())?;
+        let data = vm_load(data, hdr.cmsg_len - size_of::<cmsghdr>())?;
         Ok(match (hdr.cmsg_level as u32, hdr.cmsg_type as u32) {
             (SOL_SOCKET, SCM_RIGHTS) => {
                 if data.len() % size_of::<i32>() != 0 {
@@ -36,6 +50,14 @@ impl CMsg {
                 }
                 Self::Rights { fds }
             }
+            (SOL_ALG, ALG_SET_OP) => Self::AlgOp(read_u32(&data)?),
+            (SOL_ALG, ALG_SET_IV) => {
+                // `struct af_alg_iv`: the length followed by the IV itself.
+                let len = read_u32(&data)? as usize;
+                let iv = data.get(4..4 + len).ok_or(AxError::InvalidInput)?;
+                Self::AlgIv(iv.to_vec())
+            }
+            (SOL_ALG, ALG_SET_AEAD_ASSOCLEN) => Self::AlgAssocLen(read_u32(&data)?),
             _ => {
                 return Err(AxError::InvalidInput);
             }
@@ -43,13 +65,19 @@ impl CMsg {
     }
 }
 
+fn read_u32(data: &[u8]) -> AxResult<u32> {
+    data.get(..size_of::<u32>())
+        .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+        .ok_or(AxError::InvalidInput)
+}
+
 pub struct CMsgBuilder<'a> {
-    hdr: UserPtr<cmsghdr>,
+    hdr: *mut cmsghdr,
     len: &'a mut usize,
     capacity: usize,
 }
 impl<'a> CMsgBuilder<'a> {
-    pub fn new(msg: UserPtr<cmsghdr>, len: &'a mut usize) -> Self {
+    pub fn new(msg: *mut cmsghdr, len: &'a mut usize) -> Self {
         let capacity = *len;
         *len = 0;
         Self {
@@ -70,17 +98,21 @@ impl<'a> CMsgBuilder<'a> {
             return Ok(false);
         };
 
-        let hdr = self.hdr.get_as_mut()?;
+        let mut data = vec![0; body_capacity.min(MAX_CMSG_BODY)];
+        let body_len = body(&mut data)?;
+        let cmsg_len = size_of::<cmsghdr>() + body_len;
+
+        let mut hdr: cmsghdr = unsafe { core::mem::zeroed() };
+        hdr.cmsg_len = cmsg_len;
         hdr.cmsg_level = level as _;
         hdr.cmsg_type = ty as _;
+        self.hdr.vm_write(hdr)?;
+        vm_write_slice(
+            self.hdr.cast::<u8>().wrapping_add(size_of::<cmsghdr>()),
+            &data[..body_len],
+        )?;
 
-        let data = UserPtr::<u8>::from(self.hdr.address().as_usize() + size_of::<cmsghdr>())
-            .get_as_mut_slice(body_capacity)?;
-        let body_len = body(data)?;
-
-        let cmsg_len = size_of::<cmsghdr>() + body_len;
-        hdr.cmsg_len = cmsg_len;
-        self.hdr = UserPtr::from(hdr as *const _ as usize + cmsg_len);
+        self.hdr = self.hdr.cast::<u8>().wrapping_add(cmsg_len).cast();
         *self.len += cmsg_len;
         Ok(true)
     }
@@ -1,29 +1,77 @@
 use alloc::{boxed::Box, vec::Vec};
-use core::net::Ipv4Addr;
+use core::{mem::offset_of, net::Ipv4Addr};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::monotonic_time;
 use axio::{Buf, BufMut};
 use axnet::{CMsgData, RecvFlags, RecvOptions, SendFlags, SendOptions, SocketAddrEx, SocketOps};
-use linux_raw_sys::net::{
-    MSG_PEEK, MSG_TRUNC, SCM_RIGHTS, SOL_SOCKET, cmsghdr, msghdr, sockaddr, socklen_t,
+use axpoll::{IoEvents, Pollable};
+use linux_raw_sys::{
+    general::timespec,
+    net::{
+        MSG_DONTWAIT, MSG_FASTOPEN, MSG_MORE, MSG_NOSIGNAL, MSG_PEEK, MSG_TRUNC, MSG_WAITFORONE,
+        SCM_CREDENTIALS, SCM_RIGHTS, SOL_SOCKET, cmsghdr, mmsghdr, msghdr, sockaddr, socklen_t,
+        ucred,
+    },
 };
+use starry_core::sysctl::{TCP_FASTOPEN, TFO_CLIENT_ENABLE};
+use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
-    file::{FileLike, Socket, add_file_like},
+    file::{
+        FileLike, Socket, add_file_like,
+        alg::{AlgControl, AlgOpSocket},
+        netlink::{NetlinkSocket, SockAddrNl},
+        packet::{PacketSocket, SockAddrLl},
+    },
     io::{IoVec, IoVectorBuf},
-    mm::{UserConstPtr, UserPtr, VmBytes, VmBytesMut},
+    mm::{VmBytes, VmBytesMut},
     socket::SocketAddrExt,
-    syscall::net::{CMsg, CMsgBuilder},
+    syscall::{
+        io_mpx::busy_poll,
+        net::{CMsg, CMsgBuilder},
+    },
+    time::TimeValueLike,
 };
 
+/// Maximum number of messages handled by a single `sendmmsg`/`recvmmsg`
+/// call, same as Linux's `UIO_MAXIOV`.
+const MMSG_MAX: u32 = 1024;
+
 fn send_impl(
     fd: i32,
     mut src: impl Buf,
     flags: u32,
-    addr: UserConstPtr<sockaddr>,
+    addr: *const sockaddr,
     addrlen: socklen_t,
     cmsg: Vec<CMsgData>,
 ) -> AxResult<isize> {
+    if let Ok(socket) = AlgOpSocket::from_fd(fd) {
+        let mut control = AlgControl::default();
+        for cmsg in cmsg {
+            match cmsg.downcast::<CMsg>().map(|cmsg| *cmsg) {
+                Ok(CMsg::AlgOp(op)) => control.op = Some(op),
+                Ok(CMsg::AlgIv(iv)) => control.iv = Some(iv),
+                Ok(CMsg::AlgAssocLen(len)) => control.assoc_len = Some(len),
+                _ => return Err(AxError::InvalidInput),
+            }
+        }
+        let sent = socket.send(&mut src, flags & MSG_MORE != 0, control)?;
+        return Ok(sent as isize);
+    }
+    if let Ok(socket) = PacketSocket::from_fd(fd) {
+        let addr = (!addr.is_null() && addrlen != 0)
+            .then(|| SockAddrLl::read_from_user(addr, addrlen))
+            .transpose()?;
+        return socket.send(&mut src, addr).map(|sent| sent as isize);
+    }
+    if let Ok(socket) = NetlinkSocket::from_fd(fd) {
+        let addr = (!addr.is_null() && addrlen != 0)
+            .then(|| SockAddrNl::read_from_user(addr, addrlen))
+            .transpose()?;
+        return socket.send(&mut src, addr).map(|sent| sent as isize);
+    }
+
     let addr = if addr.is_null() || addrlen == 0 {
         None
     } else {
@@ -33,13 +81,27 @@ fn send_impl(
     debug!("sys_send <= fd: {}, flags: {}, addr: {:?}", fd, flags, addr);
 
     let socket = Socket::from_fd(fd)?;
-    let sent = socket.send(
+    let mut addr = addr.map(|addr| socket.addr_from_user(addr)).transpose()?;
+    if flags & MSG_FASTOPEN != 0 && socket.is_tcp() {
+        if TCP_FASTOPEN.get() & TFO_CLIENT_ENABLE == 0 {
+            return Err(AxError::OperationNotSupported);
+        }
+        // Without Fast Open cookies the data can't ride on the SYN, so this
+        // connects first and sends afterwards, as Linux does for a peer it
+        // has no cookie for.
+        let addr = addr
+            .take()
+            .ok_or(AxError::Other(LinuxError::EDESTADDRREQ))?;
+        socket.start_connect(addr)?;
+    }
+    let sent = socket.send_checked(
         &mut src,
         SendOptions {
             to: addr,
             flags: SendFlags::default(),
             cmsg,
         },
+        flags & MSG_NOSIGNAL != 0,
     )?;
 
     Ok(sent as isize)
@@ -50,24 +112,25 @@ pub fn sys_sendto(
     buf: *const u8,
     len: usize,
     flags: u32,
-    addr: UserConstPtr<sockaddr>,
+    addr: *const sockaddr,
     addrlen: socklen_t,
 ) -> AxResult<isize> {
     send_impl(fd, VmBytes::new(buf, len), flags, addr, addrlen, Vec::new())
 }
 
-pub fn sys_sendmsg(fd: i32, msg: UserConstPtr<msghdr>, flags: u32) -> AxResult<isize> {
-    let msg = msg.get_as_ref()?;
+pub fn sys_sendmsg(fd: i32, msg: *const msghdr, flags: u32) -> AxResult<isize> {
+    let msg = unsafe { msg.vm_read_uninit()?.assume_init() };
     let mut cmsg = Vec::new();
     if !msg.msg_control.is_null() {
         let mut ptr = msg.msg_control as usize;
         let ptr_end = ptr + msg.msg_controllen;
         while ptr + size_of::<cmsghdr>() <= ptr_end {
-            let hdr = UserConstPtr::<cmsghdr>::from(ptr).get_as_ref()?;
+            let hdr = unsafe { (ptr as *const cmsghdr).vm_read_uninit()?.assume_init() };
             if ptr_end - ptr < hdr.cmsg_len {
                 return Err(AxError::InvalidInput);
             }
-            cmsg.push(Box::new(CMsg::parse(hdr)?) as CMsgData);
+            let data = (ptr + size_of::<cmsghdr>()) as *const u8;
+            cmsg.push(Box::new(CMsg::parse(&hdr, data)?) as CMsgData);
             ptr += hdr.cmsg_len;
         }
     }
@@ -75,23 +138,109 @@ pub fn sys_sendmsg(fd: i32, msg: UserConstPtr<msghdr>, flags: u32) -> AxResult<i
         fd,
         IoVectorBuf::new(msg.msg_iov as *const IoVec, msg.msg_iovlen)?.into_io(),
         flags,
-        UserConstPtr::from(msg.msg_name as usize),
+        msg.msg_name as *const sockaddr,
         msg.msg_namelen as socklen_t,
         cmsg,
     )
 }
 
+/// Returns where `recvmmsg` and `sendmmsg` store the length of the message
+/// at `mmsg`.
+fn mmsg_len(mmsg: *mut mmsghdr) -> *mut u32 {
+    mmsg.cast::<u8>()
+        .wrapping_add(offset_of!(mmsghdr, msg_len))
+        .cast()
+}
+
+pub fn sys_sendmmsg(fd: i32, msgvec: *mut mmsghdr, vlen: u32, flags: u32) -> AxResult<isize> {
+    debug!(
+        "sys_sendmmsg <= fd: {}, vlen: {}, flags: {}",
+        fd, vlen, flags
+    );
+
+    let mut sent = 0;
+    for i in 0..vlen.min(MMSG_MAX) as usize {
+        let mmsg = msgvec.wrapping_add(i);
+        // `msg_hdr` is the first field of `mmsghdr`.
+        match sys_sendmsg(fd, mmsg.cast::<msghdr>(), flags) {
+            Ok(len) => mmsg_len(mmsg).vm_write(len as _)?,
+            // Errors are only reported if no message has been sent yet; the
+            // caller is expected to retry from the failing message.
+            Err(err) if sent == 0 => return Err(err),
+            Err(_) => break,
+        }
+        sent += 1;
+    }
+    Ok(sent)
+}
+
 fn recv_impl(
     fd: i32,
     mut dst: impl BufMut,
     flags: u32,
-    addr: UserPtr<sockaddr>,
-    addrlen: UserPtr<socklen_t>,
+    addr: *mut sockaddr,
+    addrlen: &mut socklen_t,
     cmsg_builder: Option<CMsgBuilder>,
 ) -> AxResult<isize> {
     debug!("sys_recv <= fd: {}, flags: {}", fd, flags);
 
+    if let Ok(socket) = AlgOpSocket::from_fd(fd) {
+        let recv = socket.recv(&mut dst, flags & MSG_DONTWAIT != 0)?;
+        return Ok(recv as isize);
+    }
+    if let Ok(socket) = PacketSocket::from_fd(fd) {
+        let (recv, from) = socket.recv(
+            &mut dst,
+            flags & MSG_DONTWAIT != 0,
+            flags & MSG_PEEK != 0,
+            flags & MSG_TRUNC != 0,
+        )?;
+        if !addr.is_null() {
+            from.write_to_user(addr, addrlen)?;
+        }
+        return Ok(recv as isize);
+    }
+    if let Ok(socket) = NetlinkSocket::from_fd(fd) {
+        let (recv, from, cred) = socket.recv(
+            &mut dst,
+            flags & MSG_DONTWAIT != 0,
+            flags & MSG_PEEK != 0,
+            flags & MSG_TRUNC != 0,
+        )?;
+        if !addr.is_null() {
+            from.write_to_user(addr, addrlen)?;
+        }
+        // udev drops uevents that don't come with the credentials of root.
+        if let Some(mut builder) = cmsg_builder
+            && socket.pass_cred()
+        {
+            builder.push(SOL_SOCKET, SCM_CREDENTIALS, |data| {
+                let mut cred = [cred.pid as u32, cred.uid, cred.gid]
+                    .into_iter()
+                    .flat_map(u32::to_ne_bytes);
+                let len = size_of::<ucred>().min(data.len());
+                data[..len].fill_with(|| cred.next().unwrap());
+                Ok(len)
+            })?;
+        }
+        return Ok(recv as isize);
+    }
+
     let socket = Socket::from_fd(fd)?;
+    let ready = || {
+        if socket.poll().contains(IoEvents::IN) {
+            Ok(())
+        } else {
+            Err(AxError::WouldBlock)
+        }
+    };
+    if flags & MSG_DONTWAIT != 0 {
+        ready()?;
+    } else if !socket.nonblocking() {
+        // Spin for a while before the network stack puts us to sleep; if
+        // nothing turns up, the receive below blocks as usual.
+        let _ = busy_poll(socket.busy_poll(), ready);
+    }
     let mut recv_flags = RecvFlags::empty();
     if flags & MSG_PEEK != 0 {
         recv_flags |= RecvFlags::PEEK;
@@ -104,7 +253,7 @@ fn recv_impl(
 
     let mut remote_addr =
         (!addr.is_null()).then(|| SocketAddrEx::Ip((Ipv4Addr::UNSPECIFIED, 0).into()));
-    let recv = socket.recv(
+    let recv = socket.recv_filtered(
         &mut dst,
         RecvOptions {
             from: remote_addr.as_mut(),
@@ -114,7 +263,9 @@ fn recv_impl(
     )?;
 
     if let Some(remote_addr) = remote_addr {
-        remote_addr.write_to_user(addr, addrlen.get_as_mut()?)?;
+        socket
+            .addr_to_user(remote_addr)
+            .write_to_user(addr, addrlen)?;
     }
 
     if let Some(mut builder) = cmsg_builder {
@@ -134,6 +285,8 @@ fn recv_impl(
                     }
                     Ok(written)
                 })?,
+                // Only ever sent to `AF_ALG` sockets.
+                CMsg::AlgOp(_) | CMsg::AlgIv(_) | CMsg::AlgAssocLen(_) => continue,
             };
             if !pushed {
                 break;
@@ -150,25 +303,81 @@ pub fn sys_recvfrom(
     buf: *mut u8,
     len: usize,
     flags: u32,
-    addr: UserPtr<sockaddr>,
-    addrlen: UserPtr<socklen_t>,
+    addr: *mut sockaddr,
+    addrlen: *mut socklen_t,
 ) -> AxResult<isize> {
-    recv_impl(fd, VmBytesMut::new(buf, len), flags, addr, addrlen, None)
+    let dst = VmBytesMut::new(buf, len);
+    if addr.is_null() {
+        return recv_impl(fd, dst, flags, addr, &mut 0, None);
+    }
+    let mut namelen = addrlen.vm_read()?;
+    let recv = recv_impl(fd, dst, flags, addr, &mut namelen, None)?;
+    addrlen.vm_write(namelen)?;
+    Ok(recv)
 }
 
-pub fn sys_recvmsg(fd: i32, msg: UserPtr<msghdr>, flags: u32) -> AxResult<isize> {
-    let msg = msg.get_as_mut()?;
-    recv_impl(
+pub fn sys_recvmsg(fd: i32, msg_ptr: *mut msghdr, flags: u32) -> AxResult<isize> {
+    let mut msg = unsafe { msg_ptr.vm_read_uninit()?.assume_init() };
+    let mut namelen = msg.msg_namelen as socklen_t;
+    let recv = recv_impl(
         fd,
         IoVectorBuf::new(msg.msg_iov as *mut IoVec, msg.msg_iovlen)?.into_io(),
         flags,
-        UserPtr::from(msg.msg_name as usize),
-        UserPtr::from(&mut msg.msg_namelen as *mut _ as *mut socklen_t),
-        (!msg.msg_control.is_null()).then(|| {
-            CMsgBuilder::new(
-                UserPtr::from(msg.msg_control as *mut cmsghdr),
-                &mut msg.msg_controllen,
-            )
-        }),
-    )
+        msg.msg_name as *mut sockaddr,
+        &mut namelen,
+        (!msg.msg_control.is_null())
+            .then(|| CMsgBuilder::new(msg.msg_control as *mut cmsghdr, &mut msg.msg_controllen)),
+    )?;
+    msg.msg_namelen = namelen as _;
+    msg_ptr.vm_write(msg)?;
+    Ok(recv)
+}
+
+pub fn sys_recvmmsg(
+    fd: i32,
+    msgvec: *mut mmsghdr,
+    vlen: u32,
+    flags: u32,
+    timeout: *mut timespec,
+) -> AxResult<isize> {
+    debug!(
+        "sys_recvmmsg <= fd: {}, vlen: {}, flags: {}",
+        fd, vlen, flags
+    );
+
+    let deadline = match timeout.nullable() {
+        Some(ts) => {
+            let dur = unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
+            Some(monotonic_time() + dur)
+        }
+        None => None,
+    };
+
+    let mut flags = flags;
+    let mut received = 0;
+    for i in 0..vlen.min(MMSG_MAX) as usize {
+        let mmsg = msgvec.wrapping_add(i);
+        match sys_recvmsg(fd, mmsg.cast::<msghdr>(), flags) {
+            Ok(len) => mmsg_len(mmsg).vm_write(len as _)?,
+            Err(err) if received == 0 => return Err(err),
+            Err(_) => break,
+        }
+        received += 1;
+
+        if flags & MSG_WAITFORONE != 0 {
+            flags |= MSG_DONTWAIT;
+        }
+        // Like Linux, the timeout is only checked after each datagram is
+        // received, so a blocking receive may still overrun it.
+        if deadline.is_some_and(|deadline| monotonic_time() >= deadline) {
+            break;
+        }
+    }
+
+    if let Some(deadline) = deadline {
+        timeout.vm_write(timespec::from_time_value(
+            deadline.saturating_sub(monotonic_time()),
+        ))?;
+    }
+    Ok(received)
 }
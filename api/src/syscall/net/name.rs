@@ -1,35 +1,42 @@
-use axerrno::AxResult;
+use axerrno::{AxError, AxResult};
 use axnet::SocketOps;
 use linux_raw_sys::net::{sockaddr, socklen_t};
 
 use crate::{
-    file::{FileLike, Socket},
-    mm::UserPtr,
-    socket::SocketAddrExt,
+    file::{FileLike, Socket, netlink::NetlinkSocket, packet::PacketSocket},
+    socket::{SocketAddrExt, with_addrlen},
 };
 
-pub fn sys_getsockname(
-    fd: i32,
-    addr: UserPtr<sockaddr>,
-    addrlen: UserPtr<socklen_t>,
-) -> AxResult<isize> {
+pub fn sys_getsockname(fd: i32, addr: *mut sockaddr, addrlen: *mut socklen_t) -> AxResult<isize> {
+    if let Ok(socket) = PacketSocket::from_fd(fd) {
+        let local_addr = socket.local_addr()?;
+        with_addrlen(addrlen, |len| local_addr.write_to_user(addr, len))?;
+        return Ok(0);
+    }
+    if let Ok(socket) = NetlinkSocket::from_fd(fd) {
+        let local_addr = socket.local_addr();
+        with_addrlen(addrlen, |len| local_addr.write_to_user(addr, len))?;
+        return Ok(0);
+    }
+
     let socket = Socket::from_fd(fd)?;
-    let local_addr = socket.local_addr()?;
+    let local_addr = socket.addr_to_user(socket.local_addr()?);
     debug!("sys_getsockname <= fd: {}, addr: {:?}", fd, local_addr);
 
-    local_addr.write_to_user(addr, addrlen.get_as_mut()?)?;
+    with_addrlen(addrlen, |len| local_addr.write_to_user(addr, len))?;
     Ok(0)
 }
 
-pub fn sys_getpeername(
-    fd: i32,
-    addr: UserPtr<sockaddr>,
-    addrlen: UserPtr<socklen_t>,
-) -> AxResult<isize> {
+pub fn sys_getpeername(fd: i32, addr: *mut sockaddr, addrlen: *mut socklen_t) -> AxResult<isize> {
+    // Packet and netlink sockets are never connected.
+    if PacketSocket::from_fd(fd).is_ok() || NetlinkSocket::from_fd(fd).is_ok() {
+        return Err(AxError::NotConnected);
+    }
+
     let socket = Socket::from_fd(fd)?;
-    let peer_addr = socket.peer_addr()?;
+    let peer_addr = socket.addr_to_user(socket.peer_addr()?);
     debug!("sys_getpeername <= fd: {}, addr: {:?}", fd, peer_addr);
 
-    peer_addr.write_to_user(addr, addrlen.get_as_mut()?)?;
+    with_addrlen(addrlen, |len| peer_addr.write_to_user(addr, len))?;
     Ok(0)
 }
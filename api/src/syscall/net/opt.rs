@@ -1,16 +1,23 @@
 use axerrno::{AxError, AxResult, LinuxError};
 use axnet::options::{Configurable, GetSocketOption, SetSocketOption};
-use linux_raw_sys::net::socklen_t;
-
-use crate::{
-    file::{FileLike, Socket},
-    mm::{UserConstPtr, UserPtr},
+use axtask::current;
+use linux_raw_sys::net::{
+    IPV6_V6ONLY, SO_BUSY_POLL, SOL_SOCKET, TCP_FASTOPEN, TCP_FASTOPEN_CONNECT, socklen_t,
+};
+use starry_core::{
+    sysctl::{self, TFO_CLIENT_ENABLE},
+    task::AsThread,
 };
+use starry_vm::{VmMutPtr, VmPtr};
+
+use crate::file::{FileLike, Socket, alg::AlgSocket, netlink::NetlinkSocket, packet::PacketSocket};
 
 const PROTO_TCP: u32 = linux_raw_sys::net::IPPROTO_TCP as u32;
 
 const PROTO_IP: u32 = linux_raw_sys::net::IPPROTO_IP as u32;
 
+const PROTO_IPV6: u32 = linux_raw_sys::net::IPPROTO_IPV6 as u32;
+
 mod conv {
     use axerrno::{AxError, AxResult};
     use axnet::options::UnixCredentials;
@@ -98,6 +105,8 @@ macro_rules! call_dispatch {
             (PROTO_TCP, TCP_INFO) => TcpInfo,
 
             (PROTO_IP, IP_TTL) => Ttl as Int<u8>,
+
+            (PROTO_IPV6, IPV6_UNICAST_HOPS) => Ttl as Int<u8>,
         }
     }};
     ($dispatch:ident, $in:expr, $($pat:pat => $which:ident $(as $conv:ty)?),* $(,)?) => {
@@ -116,73 +125,158 @@ pub fn sys_getsockopt(
     fd: i32,
     level: u32,
     optname: u32,
-    optval: UserPtr<u8>,
-    optlen: UserPtr<socklen_t>,
+    optval: *mut u8,
+    optlen: *mut socklen_t,
 ) -> AxResult<isize> {
-    let optlen = optlen.get_as_mut()?;
+    let mut len = optlen.vm_read()?;
     debug!(
         "sys_getsockopt <= fd: {}, level: {}, optname: {}, optval: {:?}, optlen: {}",
-        fd,
-        level,
-        optname,
-        optval.address(),
-        optlen,
+        fd, level, optname, optval, len,
     );
 
-    fn get<'a, T: 'static>(val: UserPtr<u8>, len: &mut socklen_t) -> AxResult<&'a mut T> {
+    get_option(fd, level, optname, optval, &mut len)?;
+    optlen.vm_write(len)?;
+    Ok(0)
+}
+
+fn get_option(
+    fd: i32,
+    level: u32,
+    optname: u32,
+    optval: *mut u8,
+    optlen: &mut socklen_t,
+) -> AxResult<()> {
+    fn put<T>(val: *mut u8, len: &mut socklen_t, value: T) -> AxResult<()> {
         if (*len as usize) < size_of::<T>() {
             return Err(AxError::InvalidInput);
         }
         *len = size_of::<T>() as socklen_t;
-        val.cast().get_as_mut()
+        val.cast::<T>().vm_write(value)?;
+        Ok(())
+    }
+
+    if let Ok(socket) = PacketSocket::from_fd(fd) {
+        return socket.get_option(level, optname, optval, optlen);
+    }
+    if let Ok(socket) = NetlinkSocket::from_fd(fd) {
+        return socket.get_option(level, optname, optval, optlen);
     }
 
     let socket = Socket::from_fd(fd)?;
+    // Address family semantics are handled here rather than by the network
+    // stack, see `Socket::addr_from_user`.
+    if (level, optname) == (PROTO_IPV6, IPV6_V6ONLY) {
+        if socket.domain() != linux_raw_sys::net::AF_INET6 {
+            return Err(AxError::Other(LinuxError::ENOPROTOOPT));
+        }
+        return put::<i32>(optval, optlen, socket.v6only() as _);
+    }
+    if (level, optname) == (SOL_SOCKET, SO_BUSY_POLL) {
+        return put::<i32>(optval, optlen, socket.busy_poll().as_micros() as _);
+    }
+    if (level, optname) == (PROTO_TCP, TCP_FASTOPEN) && socket.is_tcp() {
+        return put::<i32>(optval, optlen, socket.fastopen_qlen() as _);
+    }
+    if (level, optname) == (PROTO_TCP, TCP_FASTOPEN_CONNECT) && socket.is_tcp() {
+        return put::<i32>(optval, optlen, socket.fastopen_connect() as _);
+    }
+    if level == SOL_SOCKET && socket.filter().get_option(optname, optval, optlen)? {
+        return Ok(());
+    }
     macro_rules! dispatch {
         ($which:ident) => {
-            socket.get_option(GetSocketOption::$which(get(optval, optlen)?))?;
+            // The options without a conversion are plain C structs.
+            let mut val = unsafe { core::mem::zeroed() };
+            socket.get_option(GetSocketOption::$which(&mut val))?;
+            put(optval, optlen, val)?;
         };
         ($which:ident as $conv:ty) => {
             let mut val = Default::default();
             socket.get_option(GetSocketOption::$which(&mut val))?;
-            *get(optval, optlen)? = <$conv>::rust_to_sys(val)?;
+            put(optval, optlen, <$conv>::rust_to_sys(val)?)?;
         };
     }
     call_dispatch!(dispatch, (level, optname));
 
-    Ok(0)
+    Ok(())
 }
 
 pub fn sys_setsockopt(
     fd: i32,
     level: u32,
     optname: u32,
-    optval: UserConstPtr<u8>,
+    optval: *const u8,
     optlen: socklen_t,
 ) -> AxResult<isize> {
     debug!(
         "sys_setsockopt <= fd: {}, level: {}, optname: {}, optval: {:?}, optlen: {}",
-        fd,
-        level,
-        optname,
-        optval.address(),
-        optlen
+        fd, level, optname, optval, optlen
     );
 
-    fn get<'a, T: 'static>(val: UserConstPtr<u8>, len: socklen_t) -> AxResult<&'a T> {
+    fn get<T>(val: *const u8, len: socklen_t) -> AxResult<T> {
         if len as usize != size_of::<T>() {
             return Err(AxError::InvalidInput);
         }
-        val.cast().get_as_ref()
+        // SAFETY: The options are integers and plain C structs.
+        Ok(unsafe { val.cast::<T>().vm_read_uninit()?.assume_init() })
+    }
+
+    if let Ok(socket) = AlgSocket::from_fd(fd) {
+        socket.set_option(level, optname, optval, optlen)?;
+        return Ok(0);
+    }
+    if let Ok(socket) = PacketSocket::from_fd(fd) {
+        socket.set_option(level, optname, optval, optlen)?;
+        return Ok(0);
+    }
+    if let Ok(socket) = NetlinkSocket::from_fd(fd) {
+        socket.set_option(level, optname, optval, optlen)?;
+        return Ok(0);
     }
 
     let socket = Socket::from_fd(fd)?;
+    if (level, optname) == (PROTO_IPV6, IPV6_V6ONLY) {
+        socket.set_v6only(get::<i32>(optval, optlen)? != 0)?;
+        return Ok(0);
+    }
+    if (level, optname) == (SOL_SOCKET, SO_BUSY_POLL) {
+        let usecs =
+            u32::try_from(get::<i32>(optval, optlen)?).map_err(|_| AxError::InvalidInput)?;
+        // Spinning longer takes CPU time from everyone else.
+        if usecs as u128 > socket.busy_poll().as_micros()
+            && !current().as_thread().proc_data.cred.read().is_privileged()
+        {
+            return Err(AxError::OperationNotPermitted);
+        }
+        socket.set_busy_poll(usecs);
+        return Ok(0);
+    }
+    if (level, optname) == (PROTO_TCP, TCP_FASTOPEN) && socket.is_tcp() {
+        let qlen = u32::try_from(get::<i32>(optval, optlen)?).map_err(|_| AxError::InvalidInput)?;
+        socket.set_fastopen_qlen(qlen);
+        return Ok(0);
+    }
+    if (level, optname) == (PROTO_TCP, TCP_FASTOPEN_CONNECT) && socket.is_tcp() {
+        let enabled = match get::<i32>(optval, optlen)? {
+            0 => false,
+            1 => true,
+            _ => return Err(AxError::InvalidInput),
+        };
+        if enabled && sysctl::TCP_FASTOPEN.get() & TFO_CLIENT_ENABLE == 0 {
+            return Err(AxError::OperationNotSupported);
+        }
+        socket.set_fastopen_connect(enabled);
+        return Ok(0);
+    }
+    if level == SOL_SOCKET && socket.filter().set_option(optname, optval, optlen)? {
+        return Ok(0);
+    }
     macro_rules! dispatch {
         ($which:ident) => {
-            socket.set_option(SetSocketOption::$which(get(optval, optlen)?))?;
+            socket.set_option(SetSocketOption::$which(&get(optval, optlen)?))?;
         };
         ($which:ident as $conv:ty) => {
-            let mut val = <$conv>::sys_to_rust(*get(optval, optlen)?)?;
+            let mut val = <$conv>::sys_to_rust(get(optval, optlen)?)?;
             socket.set_option(SetSocketOption::$which(&mut val))?;
         };
     }
@@ -1,24 +1,32 @@
 use axerrno::{AxError, AxResult, LinuxError};
 use axnet::{
     Shutdown, SocketAddrEx, SocketOps,
+    options::{Configurable, GetSocketOption},
     tcp::TcpSocket,
     udp::UdpSocket,
     unix::{DgramTransport, StreamTransport, UnixSocket},
 };
+use axpoll::{IoEvents, Pollable};
 use axtask::current;
 use linux_raw_sys::{
     general::{O_CLOEXEC, O_NONBLOCK},
     net::{
-        AF_INET, AF_UNIX, IPPROTO_TCP, IPPROTO_UDP, SHUT_RD, SHUT_RDWR, SHUT_WR, SOCK_DGRAM,
-        SOCK_SEQPACKET, SOCK_STREAM, sockaddr, socklen_t,
+        AF_ALG, AF_INET, AF_INET6, AF_NETLINK, AF_PACKET, AF_UNIX, IPPROTO_TCP, IPPROTO_UDP,
+        SHUT_RD, SHUT_RDWR, SHUT_WR, SOCK_DGRAM, SOCK_RAW, SOCK_SEQPACKET, SOCK_STREAM, sockaddr,
+        socklen_t,
     },
 };
 use starry_core::task::AsThread;
+use starry_vm::VmMutPtr;
 
 use crate::{
-    file::{FileLike, Socket},
-    mm::{UserConstPtr, UserPtr},
-    socket::SocketAddrExt,
+    file::{
+        FileLike, Socket, add_file_like,
+        alg::{AlgSocket, SockAddrAlg},
+        netlink::{NetlinkSocket, SockAddrNl},
+        packet::{PacketSocket, SockAddrLl},
+    },
+    socket::{SocketAddrExt, with_addrlen},
 };
 
 pub fn sys_socket(domain: u32, raw_ty: u32, proto: u32) -> AxResult<isize> {
@@ -30,13 +38,13 @@ pub fn sys_socket(domain: u32, raw_ty: u32, proto: u32) -> AxResult<isize> {
 
     let pid = current().as_thread().proc_data.proc.pid();
     let socket = match (domain, ty) {
-        (AF_INET, SOCK_STREAM) => {
+        (AF_INET | AF_INET6, SOCK_STREAM) => {
             if proto != 0 && proto != IPPROTO_TCP as _ {
                 return Err(AxError::Other(LinuxError::EPROTONOSUPPORT));
             }
             axnet::Socket::Tcp(TcpSocket::new())
         }
-        (AF_INET, SOCK_DGRAM) => {
+        (AF_INET | AF_INET6, SOCK_DGRAM) => {
             if proto != 0 && proto != IPPROTO_UDP as _ {
                 return Err(AxError::Other(LinuxError::EPROTONOSUPPORT));
             }
@@ -44,7 +52,35 @@ pub fn sys_socket(domain: u32, raw_ty: u32, proto: u32) -> AxResult<isize> {
         }
         (AF_UNIX, SOCK_STREAM) => axnet::Socket::Unix(UnixSocket::new(StreamTransport::new(pid))),
         (AF_UNIX, SOCK_DGRAM) => axnet::Socket::Unix(UnixSocket::new(DgramTransport::new(pid))),
-        (AF_INET, _) | (AF_UNIX, _) => {
+        (AF_ALG, SOCK_SEQPACKET) => {
+            if proto != 0 {
+                return Err(AxError::Other(LinuxError::EPROTONOSUPPORT));
+            }
+            let cloexec = raw_ty & O_CLOEXEC != 0;
+            return AlgSocket::default()
+                .add_to_fd_table(cloexec)
+                .map(|fd| fd as isize);
+        }
+        (AF_PACKET, SOCK_RAW | SOCK_DGRAM) => {
+            if !current().as_thread().proc_data.cred.read().is_privileged() {
+                return Err(AxError::OperationNotPermitted);
+            }
+            let socket = PacketSocket::new(ty == SOCK_DGRAM, proto as u16);
+            if raw_ty & O_NONBLOCK != 0 {
+                socket.set_nonblocking(true)?;
+            }
+            let cloexec = raw_ty & O_CLOEXEC != 0;
+            return add_file_like(socket, cloexec).map(|fd| fd as isize);
+        }
+        (AF_NETLINK, SOCK_RAW | SOCK_DGRAM) => {
+            let socket = NetlinkSocket::new(proto)?;
+            if raw_ty & O_NONBLOCK != 0 {
+                socket.set_nonblocking(true)?;
+            }
+            let cloexec = raw_ty & O_CLOEXEC != 0;
+            return add_file_like(socket, cloexec).map(|fd| fd as isize);
+        }
+        (AF_INET | AF_INET6 | AF_UNIX | AF_ALG | AF_PACKET | AF_NETLINK, _) => {
             warn!("Unsupported socket type: domain: {}, ty: {}", domain, ty);
             return Err(AxError::Other(LinuxError::ESOCKTNOSUPPORT));
         }
@@ -52,7 +88,7 @@ pub fn sys_socket(domain: u32, raw_ty: u32, proto: u32) -> AxResult<isize> {
             return Err(AxError::Other(LinuxError::EAFNOSUPPORT));
         }
     };
-    let socket = Socket(socket);
+    let socket = Socket::new(socket, domain);
 
     if raw_ty & O_NONBLOCK != 0 {
         socket.set_nonblocking(true)?;
@@ -62,26 +98,58 @@ pub fn sys_socket(domain: u32, raw_ty: u32, proto: u32) -> AxResult<isize> {
     socket.add_to_fd_table(cloexec).map(|fd| fd as isize)
 }
 
-pub fn sys_bind(fd: i32, addr: UserConstPtr<sockaddr>, addrlen: u32) -> AxResult<isize> {
+pub fn sys_bind(fd: i32, addr: *const sockaddr, addrlen: u32) -> AxResult<isize> {
+    if let Ok(socket) = AlgSocket::from_fd(fd) {
+        socket.bind(addr.cast::<SockAddrAlg>(), addrlen)?;
+        return Ok(0);
+    }
+    if let Ok(socket) = PacketSocket::from_fd(fd) {
+        socket.bind(SockAddrLl::read_from_user(addr, addrlen)?)?;
+        return Ok(0);
+    }
+    if let Ok(socket) = NetlinkSocket::from_fd(fd) {
+        socket.bind(SockAddrNl::read_from_user(addr, addrlen)?)?;
+        return Ok(0);
+    }
+
     let addr = SocketAddrEx::read_from_user(addr, addrlen)?;
     debug!("sys_bind <= fd: {}, addr: {:?}", fd, addr);
 
-    Socket::from_fd(fd)?.bind(addr)?;
+    let socket = Socket::from_fd(fd)?;
+    socket.bind(socket.addr_from_user(addr)?)?;
 
     Ok(0)
 }
 
-pub fn sys_connect(fd: i32, addr: UserConstPtr<sockaddr>, addrlen: u32) -> AxResult<isize> {
+pub fn sys_connect(fd: i32, addr: *const sockaddr, addrlen: u32) -> AxResult<isize> {
     let addr = SocketAddrEx::read_from_user(addr, addrlen)?;
     debug!("sys_connect <= fd: {}, addr: {:?}", fd, addr);
 
-    Socket::from_fd(fd)?.connect(addr).map_err(|e| {
-        if e == AxError::WouldBlock {
-            AxError::InProgress
-        } else {
-            e
+    let socket = Socket::from_fd(fd)?;
+    if socket.connecting() {
+        // A previous non-blocking connect is still pending: report its
+        // outcome once it completes, like Linux does.
+        if !socket
+            .poll()
+            .intersects(IoEvents::OUT | IoEvents::ERR | IoEvents::HUP)
+        {
+            return Err(AxError::Other(LinuxError::EALREADY));
         }
-    })?;
+        socket.set_connecting(false);
+        let mut err = 0;
+        socket.get_option(GetSocketOption::Error(&mut err))?;
+        return if err == 0 {
+            Ok(0)
+        } else {
+            Err(AxError::Other(
+                LinuxError::try_from(err).unwrap_or(LinuxError::ECONNREFUSED),
+            ))
+        };
+    }
+
+    // Fast Open has no cookies to send data with the SYN, so
+    // `TCP_FASTOPEN_CONNECT` falls back to the regular handshake here.
+    socket.start_connect(socket.addr_from_user(addr)?)?;
 
     Ok(0)
 }
@@ -98,36 +166,40 @@ pub fn sys_listen(fd: i32, backlog: i32) -> AxResult<isize> {
     Ok(0)
 }
 
-pub fn sys_accept(
-    fd: i32,
-    addr: UserPtr<sockaddr>,
-    addrlen: UserPtr<socklen_t>,
-) -> AxResult<isize> {
+pub fn sys_accept(fd: i32, addr: *mut sockaddr, addrlen: *mut socklen_t) -> AxResult<isize> {
     sys_accept4(fd, addr, addrlen, 0)
 }
 
 pub fn sys_accept4(
     fd: i32,
-    addr: UserPtr<sockaddr>,
-    addrlen: UserPtr<socklen_t>,
+    addr: *mut sockaddr,
+    addrlen: *mut socklen_t,
     flags: u32,
 ) -> AxResult<isize> {
     debug!("sys_accept <= fd: {}, flags: {}", fd, flags);
 
     let cloexec = flags & O_CLOEXEC != 0;
 
-    let socket = Socket::from_fd(fd)?;
-    let socket = Socket(socket.accept()?);
+    if let Ok(socket) = AlgSocket::from_fd(fd) {
+        let socket = socket.accept()?;
+        if flags & O_NONBLOCK != 0 {
+            socket.set_nonblocking(true)?;
+        }
+        return socket.add_to_fd_table(cloexec).map(|fd| fd as isize);
+    }
+
+    let listener = Socket::from_fd(fd)?;
+    let socket = Socket::new(listener.accept()?, listener.domain());
     if flags & O_NONBLOCK != 0 {
         socket.set_nonblocking(true)?;
     }
 
-    let remote_addr = socket.local_addr()?;
+    let remote_addr = socket.addr_to_user(socket.peer_addr()?);
     let fd = socket.add_to_fd_table(cloexec).map(|fd| fd as isize)?;
     debug!("sys_accept => fd: {}, addr: {:?}", fd, remote_addr);
 
     if !addr.is_null() {
-        remote_addr.write_to_user(addr, addrlen.get_as_mut()?)?;
+        with_addrlen(addrlen, |len| remote_addr.write_to_user(addr, len))?;
     }
 
     Ok(fd)
@@ -146,12 +218,7 @@ pub fn sys_shutdown(fd: i32, how: u32) -> AxResult<isize> {
     socket.shutdown(how).map(|_| 0)
 }
 
-pub fn sys_socketpair(
-    domain: u32,
-    raw_ty: u32,
-    proto: u32,
-    fds: UserPtr<[i32; 2]>,
-) -> AxResult<isize> {
+pub fn sys_socketpair(domain: u32, raw_ty: u32, proto: u32, fds: *mut [i32; 2]) -> AxResult<isize> {
     debug!(
         "sys_socketpair <= domain: {}, ty: {}, proto: {}",
         domain, raw_ty, proto
@@ -177,8 +244,8 @@ pub fn sys_socketpair(
             return Err(AxError::Other(LinuxError::ESOCKTNOSUPPORT));
         }
     };
-    let sock1 = Socket(axnet::Socket::Unix(sock1));
-    let sock2 = Socket(axnet::Socket::Unix(sock2));
+    let sock1 = Socket::new(axnet::Socket::Unix(sock1), AF_UNIX);
+    let sock2 = Socket::new(axnet::Socket::Unix(sock2), AF_UNIX);
 
     if raw_ty & O_NONBLOCK != 0 {
         sock1.set_nonblocking(true)?;
@@ -186,9 +253,9 @@ pub fn sys_socketpair(
     }
     let cloexec = raw_ty & O_CLOEXEC != 0;
 
-    *fds.get_as_mut()? = [
+    fds.vm_write([
         sock1.add_to_fd_table(cloexec)?,
         sock2.add_to_fd_table(cloexec)?,
-    ];
+    ])?;
     Ok(0)
 }
@@ -1,8 +1,8 @@
 use axerrno::{AxError, AxResult};
-use axhal::time::TimeValue;
 use axtask::current;
 use linux_raw_sys::general::{__kernel_old_timeval, RLIM_NLIMITS, rlimit64, rusage};
-use starry_core::task::{AsThread, Thread, get_process_data, get_task};
+use memory_addr::PAGE_SIZE_4K;
+use starry_core::task::{AsThread, Rusage, get_process_data};
 use starry_process::Pid;
 use starry_vm::{VmMutPtr, VmPtr};
 
@@ -49,33 +49,16 @@ pub fn sys_prlimit64(
     Ok(0)
 }
 
-#[derive(Default)]
-struct Rusage {
-    utime: TimeValue,
-    stime: TimeValue,
-}
-
-impl Rusage {
-    fn from_thread(thread: &Thread) -> Self {
-        let (utime, stime) = thread.time.borrow().output();
-        Self { utime, stime }
-    }
-
-    fn collate(mut self, other: Rusage) -> Self {
-        self.utime += other.utime;
-        self.stime += other.stime;
-        self
-    }
-}
-
-impl From<Rusage> for rusage {
-    fn from(value: Rusage) -> Self {
-        // FIXME: Zeroable
-        let mut usage: rusage = unsafe { core::mem::zeroed() };
-        usage.ru_utime = __kernel_old_timeval::from_time_value(value.utime);
-        usage.ru_stime = __kernel_old_timeval::from_time_value(value.stime);
-        usage
-    }
+/// Converts a [`Rusage`] to the user-space `struct rusage`.
+pub(crate) fn to_user_rusage(usage: Rusage) -> rusage {
+    // FIXME: Zeroable
+    let mut result: rusage = unsafe { core::mem::zeroed() };
+    result.ru_utime = __kernel_old_timeval::from_time_value(usage.utime);
+    result.ru_stime = __kernel_old_timeval::from_time_value(usage.stime);
+    result.ru_maxrss = (usage.maxrss * PAGE_SIZE_4K / 1024) as _;
+    result.ru_minflt = usage.minflt as _;
+    result.ru_majflt = usage.majflt as _;
+    result
 }
 
 pub fn sys_getrusage(who: i32, usage: *mut rusage) -> AxResult<isize> {
@@ -87,38 +70,12 @@ pub fn sys_getrusage(who: i32, usage: *mut rusage) -> AxResult<isize> {
     let thr = curr.as_thread();
 
     let result = match who {
-        RUSAGE_SELF => {
-            thr.proc_data
-                .proc
-                .threads()
-                .into_iter()
-                .fold(Rusage::default(), |acc, tid| {
-                    if let Ok(task) = get_task(tid) {
-                        acc.collate(Rusage::from_thread(task.as_thread()))
-                    } else {
-                        acc
-                    }
-                })
-        }
-        RUSAGE_CHILDREN => {
-            thr.proc_data
-                .proc
-                .threads()
-                .into_iter()
-                .fold(Rusage::default(), |acc, child| {
-                    if let Ok(task) = get_task(child)
-                        && !curr.ptr_eq(&task)
-                    {
-                        acc.collate(Rusage::from_thread(task.as_thread()))
-                    } else {
-                        acc
-                    }
-                })
-        }
-        RUSAGE_THREAD => Rusage::from_thread(thr),
+        RUSAGE_SELF => thr.proc_data.rusage(),
+        RUSAGE_CHILDREN => thr.proc_data.children_rusage(),
+        RUSAGE_THREAD => thr.rusage(),
         _ => return Err(AxError::InvalidInput),
     };
-    usage.vm_write(result.into())?;
+    usage.vm_write(to_user_rusage(result))?;
 
     Ok(0)
 }
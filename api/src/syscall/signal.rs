@@ -2,24 +2,24 @@ use core::{future::poll_fn, task::Poll};
 
 use axerrno::{AxError, AxResult, LinuxError};
 use axhal::uspace::UserContext;
-use axtask::{
-    current,
-    future::{self, block_on},
-};
+use axtask::{current, future::block_on};
 use linux_raw_sys::general::{
-    MINSIGSTKSZ, SI_TKILL, SI_USER, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK, kernel_sigaction, siginfo,
-    timespec,
+    MINSIGSTKSZ, SI_TKILL, SI_USER, SIG_BLOCK, SIG_IGN, SIG_SETMASK, SIG_UNBLOCK, SS_AUTODISARM,
+    SS_DISABLE, SS_ONSTACK, kernel_sigaction, siginfo, timespec,
 };
-use starry_core::task::{
-    AsThread, processes, send_signal_to_process, send_signal_to_process_group,
-    send_signal_to_thread,
+use starry_core::{
+    task::{
+        AsThread, get_task, processes, send_signal_to_process, send_signal_to_process_group,
+        send_signal_to_thread,
+    },
+    timer,
 };
 use starry_process::Pid;
 use starry_signal::{SignalInfo, SignalSet, SignalStack, Signo};
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
-    signal::{block_next_signal, check_signals},
+    signal::{block_next_signal, check_signals, flush_signals, on_alt_stack, rearm_alt_stack},
     time::TimeValueLike,
 };
 
@@ -86,9 +86,28 @@ pub fn sys_rt_sigaction(
         oldact.vm_write(actions[signo].clone().into())?;
     }
     if let Some(act) = act.nullable() {
-        let act = unsafe { act.vm_read_uninit()?.assume_init() }.into();
-        debug!("sys_rt_sigaction <= signo: {:?}, act: {:?}", signo, act);
-        actions[signo] = act;
+        let act = unsafe { act.vm_read_uninit()?.assume_init() };
+        let ignored = act.sa_handler_kernel.map_or(0, |f| f as usize) == SIG_IGN as usize;
+        actions[signo] = act.into();
+        debug!(
+            "sys_rt_sigaction <= signo: {:?}, act: {:?}",
+            signo, actions[signo]
+        );
+        drop(actions);
+
+        // Like on Linux, the pending instances of a signal that becomes
+        // ignored are discarded.
+        if ignored {
+            let mut set = SignalSet::default();
+            set.add(signo);
+            for tid in curr.as_thread().proc_data.proc.threads() {
+                if let Ok(task) = get_task(tid)
+                    && let Some(thr) = task.try_as_thread()
+                {
+                    flush_signals(thr, &set);
+                }
+            }
+        }
     }
     Ok(0)
 }
@@ -207,7 +226,10 @@ pub fn sys_rt_tgsigqueueinfo(
 
 pub fn sys_rt_sigreturn(uctx: &mut UserContext) -> AxResult<isize> {
     block_next_signal();
-    current().as_thread().signal.restore(uctx);
+    let curr = current();
+    let thr = curr.as_thread();
+    thr.signal.restore(uctx);
+    rearm_alt_stack(thr, uctx.sp());
     Ok(uctx.retval() as isize)
 }
 
@@ -244,6 +266,7 @@ pub fn sys_rt_sigtimedwait(
     uctx.set_retval(-LinuxError::EINTR.code() as usize);
     let fut = poll_fn(|context| {
         if let Some(sig) = signal.dequeue_signal(&set) {
+            thr.proc_data.uncharge_signal(sig.signo());
             signal.set_blocked(old_blocked);
             Poll::Ready(Some(sig))
         } else if check_signals(thr, uctx, Some(old_blocked)) {
@@ -254,7 +277,7 @@ pub fn sys_rt_sigtimedwait(
         }
     });
 
-    let Ok(sig) = block_on(future::timeout(timeout, fut)) else {
+    let Ok(sig) = block_on(timer::timeout(timeout, fut)) else {
         // Timeout
         signal.set_blocked(old_blocked);
         return Err(AxError::WouldBlock);
@@ -297,19 +320,46 @@ pub fn sys_rt_sigsuspend(
     Ok(0)
 }
 
-pub fn sys_sigaltstack(ss: *const SignalStack, old_ss: *mut SignalStack) -> AxResult<isize> {
+pub fn sys_sigaltstack(
+    uctx: &UserContext,
+    ss: *const SignalStack,
+    old_ss: *mut SignalStack,
+) -> AxResult<isize> {
     let curr = current();
-    let sig = &curr.as_thread().signal;
+    let thr = curr.as_thread();
+    let sig = &thr.signal;
+
+    let stack = sig.stack();
+    let on_stack = on_alt_stack(&stack, uctx.sp());
 
     if let Some(old_ss) = old_ss.nullable() {
-        old_ss.vm_write(sig.stack())?;
+        let mut old = stack.clone();
+        if on_stack {
+            old.flags = (old.flags as u32 | SS_ONSTACK) as _;
+        }
+        old_ss.vm_write(old)?;
     }
 
     if let Some(ss) = ss.nullable() {
-        let ss = unsafe { ss.vm_read_uninit()?.assume_init() };
-        if ss.size <= MINSIGSTKSZ as usize {
-            return Err(AxError::NoMemory);
+        let mut ss = unsafe { ss.vm_read_uninit()?.assume_init() };
+        if on_stack {
+            return Err(AxError::OperationNotPermitted);
+        }
+        match ss.flags as u32 & !SS_AUTODISARM {
+            SS_DISABLE => {
+                ss.sp = 0;
+                ss.size = 0;
+            }
+            // SS_ONSTACK is accepted for compatibility and means enabled.
+            0 | SS_ONSTACK => {
+                if ss.size < MINSIGSTKSZ as usize {
+                    return Err(AxError::NoMemory);
+                }
+                ss.flags = (ss.flags as u32 & SS_AUTODISARM) as _;
+            }
+            _ => return Err(AxError::InvalidInput),
         }
+        thr.disarmed_stack.lock().take();
         sig.set_stack(ss);
     }
     Ok(0)
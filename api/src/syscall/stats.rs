@@ -0,0 +1,124 @@
+//! Per-syscall statistics, exposed in `/proc/syscalls`.
+//!
+//! While `kernel.syscall_stats` is set, every syscall is counted along with
+//! its failures and how long it took, in a histogram of power-of-two
+//! microsecond buckets. Writing to `/proc/syscalls` clears the counters.
+
+use alloc::{boxed::Box, string::String};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axhal::time::monotonic_time_nanos;
+use spin::Once;
+use starry_core::sysctl::SYSCALL_STATS;
+use syscalls::Sysno;
+
+/// Syscall numbers at or above this aren't recorded.
+const MAX_SYSNO: usize = 512;
+/// The number of latency buckets: below 1 µs, then one per power of two up
+/// to the last, which takes everything from 2^(`BUCKETS` - 2) µs on.
+const BUCKETS: usize = 20;
+
+#[derive(Default)]
+struct Stat {
+    count: AtomicU64,
+    errors: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Stat {
+    fn clear(&self) {
+        for counter in [&self.count, &self.errors, &self.total_ns, &self.max_ns]
+            .into_iter()
+            .chain(&self.buckets)
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Allocated the first time a syscall is recorded.
+static STATS: Once<Box<[Stat]>> = Once::new();
+
+fn stats() -> &'static [Stat] {
+    STATS.call_once(|| (0..MAX_SYSNO).map(|_| Stat::default()).collect())
+}
+
+/// Returns the bucket of a syscall that took `ns` nanoseconds.
+fn bucket_of(ns: u64) -> usize {
+    let us = ns / 1000;
+    if us == 0 {
+        0
+    } else {
+        (us.ilog2() as usize + 1).min(BUCKETS - 1)
+    }
+}
+
+/// Returns when a syscall starts, or `None` if syscalls aren't recorded.
+pub fn start() -> Option<u64> {
+    (SYSCALL_STATS.get() != 0).then(monotonic_time_nanos)
+}
+
+/// Records that syscall `nr`, which started at `start`, is done.
+pub fn record(nr: usize, start: u64, failed: bool) {
+    let Some(stat) = stats().get(nr) else {
+        return;
+    };
+    let ns = monotonic_time_nanos().saturating_sub(start);
+    stat.count.fetch_add(1, Ordering::Relaxed);
+    if failed {
+        stat.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    stat.total_ns.fetch_add(ns, Ordering::Relaxed);
+    stat.max_ns.fetch_max(ns, Ordering::Relaxed);
+    stat.buckets[bucket_of(ns)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Clears the counters.
+pub fn clear() {
+    if let Some(stats) = STATS.get() {
+        stats.iter().for_each(Stat::clear);
+    }
+}
+
+/// Renders `/proc/syscalls`: a header naming the columns, then a line for
+/// each syscall made, with its counters and latency buckets. Bucket columns
+/// are named by their upper bound in microseconds.
+pub fn render() -> String {
+    let mut out = String::from("name nr count errors total_ns max_ns");
+    for bucket in 0..BUCKETS - 1 {
+        let _ = write!(out, " lt{}us", 1u64 << bucket);
+    }
+    out.push_str(" inf\n");
+
+    let Some(stats) = STATS.get() else {
+        return out;
+    };
+    for (nr, stat) in stats.iter().enumerate() {
+        let count = stat.count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+        let _ = match Sysno::new(nr) {
+            Some(sysno) => write!(out, "{} {}", sysno, nr),
+            None => write!(out, "unknown {}", nr),
+        };
+        let _ = write!(
+            out,
+            " {} {} {} {}",
+            count,
+            stat.errors.load(Ordering::Relaxed),
+            stat.total_ns.load(Ordering::Relaxed),
+            stat.max_ns.load(Ordering::Relaxed)
+        );
+        for bucket in &stat.buckets {
+            let _ = write!(out, " {}", bucket.load(Ordering::Relaxed));
+        }
+        out.push('\n');
+    }
+    out
+}
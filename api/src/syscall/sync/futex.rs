@@ -1,18 +1,108 @@
+use alloc::vec::Vec;
 use core::sync::atomic::Ordering;
 
 use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::TimeValue;
 use axtask::current;
+use bytemuck::AnyBitPattern;
 use linux_raw_sys::general::{
-    FUTEX_CMD_MASK, FUTEX_CMP_REQUEUE, FUTEX_REQUEUE, FUTEX_WAIT, FUTEX_WAIT_BITSET, FUTEX_WAKE,
-    FUTEX_WAKE_BITSET, robust_list_head, timespec,
+    CLOCK_MONOTONIC, CLOCK_REALTIME, FUTEX_CLOCK_REALTIME, FUTEX_CMD_MASK, FUTEX_CMP_REQUEUE,
+    FUTEX_CMP_REQUEUE_PI, FUTEX_LOCK_PI, FUTEX_LOCK_PI2, FUTEX_OWNER_DIED, FUTEX_PRIVATE_FLAG,
+    FUTEX_REQUEUE, FUTEX_TID_MASK, FUTEX_TRYLOCK_PI, FUTEX_UNLOCK_PI, FUTEX_WAIT,
+    FUTEX_WAIT_BITSET, FUTEX_WAIT_REQUEUE_PI, FUTEX_WAITERS, FUTEX_WAKE, FUTEX_WAKE_BITSET,
+    robust_list_head, timespec,
 };
 use starry_core::{
-    futex::FutexKey,
+    futex::{FutexEntry, FutexKey, WaitQueue},
     task::{AsThread, get_task},
 };
 use starry_vm::{VmMutPtr, VmPtr};
 
-use crate::time::TimeValueLike;
+use crate::{task::futex_cmpxchg, time::TimeValueLike};
+
+/// Acquires the PI futex at `uaddr` on behalf of the current thread.
+///
+/// The futex word holds the owner's TID, plus `FUTEX_WAITERS` while someone
+/// sleeps in the kernel and `FUTEX_OWNER_DIED` once the owner exited while
+/// holding it. The latter is preserved on take-over so that user space can
+/// report `EOWNERDEAD`.
+///
+/// There is no priority inheritance: `axtask` schedules every thread
+/// round-robin at a single priority, so the owner has no priority that a
+/// waiter could boost. A contended lock simply blocks until the owner
+/// unlocks it.
+fn futex_lock_pi(
+    uaddr: *const u32,
+    futex: &FutexEntry,
+    deadline: Option<(TimeValue, fn() -> TimeValue)>,
+    trylock: bool,
+) -> AxResult<isize> {
+    let tid = current().id().as_u64() as u32;
+    loop {
+        let word = uaddr.vm_read()?;
+        let owner = word & FUTEX_TID_MASK;
+        let owner_alive = owner != 0 && get_task(owner).is_ok();
+
+        if !owner_alive && (owner == 0 || word & FUTEX_OWNER_DIED != 0) {
+            let mut new = tid | (word & FUTEX_OWNER_DIED);
+            if !futex.wq.is_empty() {
+                new |= FUTEX_WAITERS;
+            }
+            if futex_cmpxchg(uaddr, word, new)?.is_ok() {
+                return Ok(0);
+            }
+            continue;
+        }
+        if owner == tid {
+            return Err(AxError::Other(LinuxError::EDEADLK));
+        }
+        if !owner_alive {
+            return Err(AxError::NoSuchProcess);
+        }
+        if trylock {
+            return Err(AxError::WouldBlock);
+        }
+
+        let word = if word & FUTEX_WAITERS == 0 {
+            match futex_cmpxchg(uaddr, word, word | FUTEX_WAITERS)? {
+                Ok(_) => word | FUTEX_WAITERS,
+                Err(_) => continue,
+            }
+        } else {
+            word
+        };
+
+        let timeout = match deadline {
+            Some((deadline, clock)) => match deadline.checked_sub(clock()) {
+                Some(timeout) => Some(timeout),
+                None => return Err(AxError::TimedOut),
+            },
+            None => None,
+        };
+        futex
+            .wq
+            .wait_if(u32::MAX, timeout, || uaddr.vm_read() == Ok(word))?;
+    }
+}
+
+/// Releases the PI futex at `uaddr`, which must be owned by the current
+/// thread, and wakes one waiter to compete for it.
+fn futex_unlock_pi(uaddr: *const u32, futex: Option<&FutexEntry>) -> AxResult<isize> {
+    let tid = current().id().as_u64() as u32;
+    loop {
+        let word = uaddr.vm_read()?;
+        if word & FUTEX_TID_MASK != tid {
+            return Err(AxError::OperationNotPermitted);
+        }
+        if futex_cmpxchg(uaddr, word, 0)?.is_ok() {
+            break;
+        }
+    }
+    if let Some(futex) = futex {
+        futex.wq.wake(1, u32::MAX);
+    }
+    Ok(0)
+}
 
 fn assert_unsigned(value: u32) -> AxResult<u32> {
     if (value as i32) < 0 {
@@ -22,6 +112,16 @@ fn assert_unsigned(value: u32) -> AxResult<u32> {
     }
 }
 
+/// Returns the key of the futex at `uaddr`, which processes sharing the
+/// memory agree on unless it is `private`.
+fn futex_key(uaddr: usize, private: bool) -> AxResult<FutexKey> {
+    if private {
+        Ok(FutexKey::new_private(uaddr))
+    } else {
+        FutexKey::new_current(uaddr)
+    }
+}
+
 pub fn sys_futex(
     uaddr: *const u32,
     futex_op: u32,
@@ -35,7 +135,8 @@ pub fn sys_futex(
         uaddr, futex_op, value, uaddr2, value3,
     );
 
-    let key = FutexKey::new_current(uaddr.addr());
+    let private = futex_op & FUTEX_PRIVATE_FLAG != 0;
+    let key = futex_key(uaddr.addr(), private)?;
 
     let curr = current();
     let thr = curr.as_thread();
@@ -101,7 +202,7 @@ pub fn sys_futex(
             let value2 = assert_unsigned(timeout.addr() as u32)?;
 
             let futex = futex_table.get(&key);
-            let key2 = FutexKey::new_current(uaddr2.addr());
+            let key2 = futex_key(uaddr2.addr(), private)?;
             let table2 = proc_data.futex_table_for(&key2);
             let futex2 = table2.get_or_insert(&key2);
 
@@ -114,10 +215,195 @@ pub fn sys_futex(
             }
             Ok(count as _)
         }
+        FUTEX_WAIT_REQUEUE_PI => {
+            if uaddr.addr() == uaddr2.addr() {
+                return Err(AxError::InvalidInput);
+            }
+            if uaddr.vm_read()? != value {
+                return Err(AxError::WouldBlock);
+            }
+            let clock: fn() -> TimeValue = if futex_op & FUTEX_CLOCK_REALTIME != 0 {
+                axhal::time::wall_time
+            } else {
+                axhal::time::monotonic_time
+            };
+            let deadline = if let Some(ts) = timeout.nullable() {
+                // FIXME: AnyBitPattern
+                let ts = unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
+                Some((ts, clock))
+            } else {
+                None
+            };
+
+            // Wait on the condition variable first. We are either woken
+            // there or requeued onto the PI futex and woken by its unlock;
+            // either way, finish by acquiring the PI futex.
+            {
+                let futex = futex_table.get_or_insert(&key);
+                let timeout = match deadline {
+                    Some((deadline, clock)) => {
+                        Some(deadline.checked_sub(clock()).ok_or(AxError::TimedOut)?)
+                    }
+                    None => None,
+                };
+                if !futex
+                    .wq
+                    .wait_if(u32::MAX, timeout, || uaddr.vm_read() == Ok(value))?
+                {
+                    return Err(AxError::WouldBlock);
+                }
+            }
+
+            let key2 = futex_key(uaddr2.addr(), private)?;
+            let table2 = proc_data.futex_table_for(&key2);
+            let futex2 = table2.get_or_insert(&key2);
+            futex_lock_pi(uaddr2, &futex2, deadline, false)
+        }
+        FUTEX_CMP_REQUEUE_PI => {
+            // Only one waiter may be woken to take the PI futex; the others
+            // must queue up behind it.
+            if value != 1 || uaddr.addr() == uaddr2.addr() {
+                return Err(AxError::InvalidInput);
+            }
+            let value2 = assert_unsigned(timeout.addr() as u32)?;
+            if uaddr.vm_read()? != value3 {
+                return Err(AxError::WouldBlock);
+            }
+
+            let key2 = futex_key(uaddr2.addr(), private)?;
+            let table2 = proc_data.futex_table_for(&key2);
+            let futex2 = table2.get_or_insert(&key2);
+
+            let Some(futex) = futex_table.get(&key) else {
+                return Ok(0);
+            };
+            let woken = futex.wq.wake(1, u32::MAX);
+            let requeued = futex.wq.requeue(value2 as _, &futex2.wq);
+            if requeued > 0 {
+                // Make sure the owner goes through FUTEX_UNLOCK_PI so that
+                // the requeued waiters get woken.
+                loop {
+                    let word = uaddr2.vm_read()?;
+                    if word & FUTEX_TID_MASK == 0 || word & FUTEX_WAITERS != 0 {
+                        break;
+                    }
+                    if futex_cmpxchg(uaddr2, word, word | FUTEX_WAITERS)?.is_ok() {
+                        break;
+                    }
+                }
+                if uaddr2.vm_read()? & FUTEX_TID_MASK == 0 {
+                    futex2.wq.wake(1, u32::MAX);
+                }
+            }
+            Ok((woken + requeued) as _)
+        }
+        FUTEX_LOCK_PI | FUTEX_LOCK_PI2 | FUTEX_TRYLOCK_PI => {
+            // FUTEX_LOCK_PI always uses CLOCK_REALTIME for its absolute
+            // timeout, FUTEX_LOCK_PI2 honors FUTEX_CLOCK_REALTIME.
+            let clock: fn() -> TimeValue =
+                if command == FUTEX_LOCK_PI || futex_op & FUTEX_CLOCK_REALTIME != 0 {
+                    axhal::time::wall_time
+                } else {
+                    axhal::time::monotonic_time
+                };
+            let deadline = if command != FUTEX_TRYLOCK_PI
+                && let Some(ts) = timeout.nullable()
+            {
+                // FIXME: AnyBitPattern
+                let ts = unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
+                Some((ts, clock))
+            } else {
+                None
+            };
+
+            let futex = futex_table.get_or_insert(&key);
+            futex_lock_pi(uaddr, &futex, deadline, command == FUTEX_TRYLOCK_PI)
+        }
+        FUTEX_UNLOCK_PI => {
+            let futex = futex_table.get(&key);
+            futex_unlock_pi(uaddr, futex.as_deref().map(|f| &**f))
+        }
         _ => Err(AxError::Unsupported),
     }
 }
 
+const FUTEX2_SIZE_U32: u32 = 0x02;
+const FUTEX2_SIZE_MASK: u32 = 0x03;
+const FUTEX2_PRIVATE: u32 = 128;
+const FUTEX_WAITV_MAX: u32 = 128;
+
+/// `struct futex_waitv`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+pub struct FutexWaitv {
+    val: u64,
+    uaddr: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+pub fn sys_futex_waitv(
+    waiters: *const FutexWaitv,
+    nr_futexes: u32,
+    flags: u32,
+    timeout: *const timespec,
+    clockid: u32,
+) -> AxResult<isize> {
+    debug!(
+        "sys_futex_waitv <= waiters: {:?}, nr_futexes: {}, flags: {}, clockid: {}",
+        waiters, nr_futexes, flags, clockid
+    );
+
+    if flags != 0 || nr_futexes == 0 || nr_futexes > FUTEX_WAITV_MAX {
+        return Err(AxError::InvalidInput);
+    }
+    let deadline = if let Some(ts) = timeout.nullable() {
+        let clock: fn() -> TimeValue = match clockid {
+            CLOCK_MONOTONIC => axhal::time::monotonic_time,
+            CLOCK_REALTIME => axhal::time::wall_time,
+            _ => return Err(AxError::InvalidInput),
+        };
+        // FIXME: AnyBitPattern
+        let ts = unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
+        Some(ts.saturating_sub(clock()))
+    } else {
+        None
+    };
+
+    let mut waits = Vec::with_capacity(nr_futexes as usize);
+    for i in 0..nr_futexes as usize {
+        let waiter = waiters.wrapping_add(i).vm_read()?;
+        if waiter.reserved != 0
+            || waiter.flags & !(FUTEX2_SIZE_MASK | FUTEX2_PRIVATE) != 0
+            || waiter.flags & FUTEX2_SIZE_MASK != FUTEX2_SIZE_U32
+            || waiter.val > u32::MAX as u64
+        {
+            return Err(AxError::InvalidInput);
+        }
+        let uaddr = waiter.uaddr as usize as *const u32;
+        if !uaddr.addr().is_multiple_of(align_of::<u32>()) {
+            return Err(AxError::InvalidInput);
+        }
+        let key = futex_key(uaddr.addr(), waiter.flags & FUTEX2_PRIVATE != 0)?;
+        let table = current().as_thread().proc_data.futex_table_for(&key);
+        waits.push((uaddr, waiter.val as u32, table, key));
+    }
+
+    let futexes = waits
+        .iter()
+        .map(|(_, _, table, key)| table.get_or_insert(key))
+        .collect::<Vec<_>>();
+    let queues = futexes.iter().map(|f| &f.wq).collect::<Vec<&WaitQueue>>();
+
+    match WaitQueue::wait_any(&queues, deadline, |i| {
+        let (uaddr, value, ..) = waits[i];
+        uaddr.vm_read() == Ok(value)
+    })? {
+        Some(index) => Ok(index as _),
+        None => Err(AxError::WouldBlock),
+    }
+}
+
 pub fn sys_get_robust_list(
     tid: u32,
     head: *mut *const robust_list_head,
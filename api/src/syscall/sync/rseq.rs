@@ -1,46 +1,69 @@
-use axerrno::AxError;
+use axerrno::{AxError, AxResult};
 use axtask::current;
 use starry_core::task::AsThread;
-use starry_vm::VmPtr;
 
-/// Minimal implementation of the rseq syscall registration.
+use crate::task::{RSEQ_AREA_SIZE, rseq_update_cpu};
+
+const RSEQ_FLAG_UNREGISTER: u32 = 1;
+
+/// Registers or unregisters the calling thread's restartable sequence area.
 ///
-/// This implementation only supports registration/unregistration via the
-/// first argument (addr) and the flags argument. It stores the user pointer
-/// in the current thread structure so kernel-side users can inspect it.
+/// Once registered, the kernel keeps `cpu_id_start`/`cpu_id` up to date on
+/// every return to user space and aborts a critical section that was
+/// interrupted (see [`crate::task::rseq_resume`]).
 ///
 /// C prototype (simplified):
-/// long rseq(void *addr, uint32_t len, int flags, uint32_t sig);
-pub fn sys_rseq(addr: *mut u8, len: usize, flags: u32, sig: u32) -> Result<isize, AxError> {
+/// long rseq(struct rseq *rseq, uint32_t len, int flags, uint32_t sig);
+pub fn sys_rseq(addr: *mut u8, len: u32, flags: u32, sig: u32) -> AxResult<isize> {
     debug!(
-        "sys_rseq <= addr: {:?}, len: {}, flags: {}, sig: {}",
+        "sys_rseq <= addr: {:?}, len: {}, flags: {}, sig: {:#x}",
         addr, len, flags, sig
     );
 
-    // According to Linux, addr == NULL and len == 0 unregisters.
-    // Validate inputs: len should be either 0 (unregister) or match expected header
-    // size. For simplicity accept any non-zero len up to a reasonable limit.
-    if addr.is_null() {
-        if len != 0 {
+    let curr = current();
+    let thr = curr.as_thread();
+    let registered = thr.rseq_area();
+
+    if flags & RSEQ_FLAG_UNREGISTER != 0 {
+        if flags & !RSEQ_FLAG_UNREGISTER != 0 {
             return Err(AxError::InvalidInput);
         }
-        // unregister
-        current().as_thread().set_rseq_area(0);
+        if registered != addr.addr() || len < RSEQ_AREA_SIZE {
+            return Err(AxError::InvalidInput);
+        }
+        if thr.rseq_sig() != sig {
+            return Err(AxError::OperationNotPermitted);
+        }
+        thr.set_rseq_area(0);
+        thr.set_rseq_sig(0);
         return Ok(0);
     }
-
-    if len == 0 {
+    if flags != 0 {
         return Err(AxError::InvalidInput);
     }
 
-    // Check that the user pointer is readable/writable (we only need the address).
-    // Try to read one byte to ensure the area is valid.
-    if addr.vm_read().is_err() {
+    if registered != 0 {
+        // Registering the same area twice is reported as busy so that
+        // libraries can tell it apart from a conflicting registration.
+        if registered != addr.addr() {
+            return Err(AxError::InvalidInput);
+        }
+        if thr.rseq_sig() != sig {
+            return Err(AxError::OperationNotPermitted);
+        }
+        return Err(AxError::ResourceBusy);
+    }
+
+    if addr.is_null() || !addr.addr().is_multiple_of(RSEQ_AREA_SIZE as usize) {
+        return Err(AxError::InvalidInput);
+    }
+    if len < RSEQ_AREA_SIZE {
         return Err(AxError::InvalidInput);
     }
 
-    // Store the user address in the thread.
-    current().as_thread().set_rseq_area(addr.addr());
+    rseq_update_cpu(addr.addr())?;
+    thr.set_rseq_sig(sig);
+    thr.set_rseq_area(addr.addr());
 
     Ok(0)
 }
@@ -1,41 +1,101 @@
 use alloc::vec;
-use core::ffi::c_char;
+use core::ffi::{c_char, c_void};
 
 use axerrno::{AxError, AxResult};
 use axfs_ng::FS_CONTEXT;
+use axtask::current;
 use linux_raw_sys::{
     general::{GRND_INSECURE, GRND_NONBLOCK, GRND_RANDOM},
     system::{new_utsname, sysinfo},
 };
-use starry_core::task::processes;
+use starry_core::{
+    cred::Credentials,
+    task::{AsThread, processes},
+};
 use starry_vm::{VmMutPtr, vm_write_slice};
 
+use crate::{
+    power::{PowerAction, shutdown, soft_reboot},
+    task::do_exit,
+};
+
+const LINUX_REBOOT_MAGIC1: u32 = 0xfee1_dead;
+const LINUX_REBOOT_MAGIC2: [u32; 4] = [672274793, 85072278, 369367448, 537993216];
+
+const LINUX_REBOOT_CMD_CAD_OFF: u32 = 0;
+const LINUX_REBOOT_CMD_CAD_ON: u32 = 0x89ab_cdef;
+const LINUX_REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+const LINUX_REBOOT_CMD_RESTART2: u32 = 0xa1b2_c3d4;
+const LINUX_REBOOT_CMD_HALT: u32 = 0xcdef_0123;
+const LINUX_REBOOT_CMD_POWER_OFF: u32 = 0x4321_fedc;
+const LINUX_REBOOT_CMD_KEXEC: u32 = 0x4558_4543;
+
+fn cred() -> Credentials {
+    current().as_thread().proc_data.cred.read().clone()
+}
+
 pub fn sys_getuid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().uid as _)
 }
 
 pub fn sys_geteuid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().euid as _)
 }
 
 pub fn sys_getgid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().gid as _)
 }
 
 pub fn sys_getegid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().egid as _)
 }
 
-pub fn sys_setuid(_uid: u32) -> AxResult<isize> {
-    debug!("sys_setuid <= uid: {}", _uid);
+pub fn sys_getresuid(ruid: *mut u32, euid: *mut u32, suid: *mut u32) -> AxResult<isize> {
+    let cred = cred();
+    ruid.vm_write(cred.uid)?;
+    euid.vm_write(cred.euid)?;
+    suid.vm_write(cred.suid)?;
     Ok(0)
 }
 
-pub fn sys_setgid(_gid: u32) -> AxResult<isize> {
-    debug!("sys_setgid <= gid: {}", _gid);
+pub fn sys_getresgid(rgid: *mut u32, egid: *mut u32, sgid: *mut u32) -> AxResult<isize> {
+    let cred = cred();
+    rgid.vm_write(cred.gid)?;
+    egid.vm_write(cred.egid)?;
+    sgid.vm_write(cred.sgid)?;
     Ok(0)
 }
 
+pub fn sys_setuid(uid: u32) -> AxResult<isize> {
+    debug!("sys_setuid <= uid: {}", uid);
+    current().as_thread().proc_data.update_cred(|cred| {
+        if cred.is_privileged() {
+            cred.uid = uid;
+            cred.suid = uid;
+        } else if uid != cred.uid && uid != cred.suid {
+            return Err(AxError::OperationNotPermitted);
+        }
+        cred.euid = uid;
+        cred.fsuid = uid;
+        Ok(0)
+    })
+}
+
+pub fn sys_setgid(gid: u32) -> AxResult<isize> {
+    debug!("sys_setgid <= gid: {}", gid);
+    current().as_thread().proc_data.update_cred(|cred| {
+        if cred.is_privileged() {
+            cred.gid = gid;
+            cred.sgid = gid;
+        } else if gid != cred.gid && gid != cred.sgid {
+            return Err(AxError::OperationNotPermitted);
+        }
+        cred.egid = gid;
+        cred.fsgid = gid;
+        Ok(0)
+    })
+}
+
 pub fn sys_getgroups(size: usize, list: *mut u32) -> AxResult<isize> {
     debug!("sys_getgroups <= size: {}", size);
     if size < 1 {
@@ -131,3 +191,31 @@ pub fn sys_riscv_flush_icache() -> AxResult<isize> {
     riscv::asm::fence_i();
     Ok(0)
 }
+
+pub fn sys_reboot(magic1: u32, magic2: u32, cmd: u32, _arg: *const c_void) -> AxResult<isize> {
+    debug!(
+        "sys_reboot <= magic1: {:#x}, magic2: {:#x}, cmd: {:#x}",
+        magic1, magic2, cmd
+    );
+    if !cred().is_privileged() {
+        return Err(AxError::OperationNotPermitted);
+    }
+    if magic1 != LINUX_REBOOT_MAGIC1 || !LINUX_REBOOT_MAGIC2.contains(&magic2) {
+        return Err(AxError::InvalidInput);
+    }
+
+    match cmd {
+        // There is no Ctrl-Alt-Del to handle.
+        LINUX_REBOOT_CMD_CAD_ON | LINUX_REBOOT_CMD_CAD_OFF => Ok(0),
+        LINUX_REBOOT_CMD_RESTART | LINUX_REBOOT_CMD_RESTART2 => shutdown(PowerAction::Restart),
+        LINUX_REBOOT_CMD_HALT => shutdown(PowerAction::Halt),
+        LINUX_REBOOT_CMD_POWER_OFF => shutdown(PowerAction::PowerOff),
+        // Without kexec images, this restarts userspace on the running kernel.
+        LINUX_REBOOT_CMD_KEXEC => {
+            soft_reboot();
+            do_exit(0, true);
+            Ok(0)
+        }
+        _ => Err(AxError::InvalidInput),
+    }
+}
@@ -1,22 +1,30 @@
 use alloc::sync::Arc;
+use core::{future::poll_fn, task::Poll};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axfs_ng::FS_CONTEXT;
 use axhal::uspace::UserContext;
-use axtask::{TaskExtProxy, current, spawn_task};
+use axtask::{
+    TaskExtProxy, current,
+    future::{block_on, interruptible},
+    spawn_task,
+};
 use bitflags::bitflags;
 use kspin::SpinNoIrq;
 use linux_raw_sys::general::*;
 use starry_core::{
-    mm::copy_from_kernel,
+    config::{USER_SPACE_BASE, USER_SPACE_SIZE},
+    cpu,
+    mm::{copy_from_kernel, resident_pages},
+    sysctl::PID_MAX,
     task::{AsThread, ProcessData, Thread, add_task_to_table},
 };
 use starry_process::Pid;
-use starry_signal::Signo;
+use starry_signal::{Signo, api::SignalActions};
+use starry_vm::{VmMutPtr, vm_load};
 
 use crate::{
     file::{FD_TABLE, FileLike, PidFd},
-    mm::UserPtr,
     task::new_user_task,
 };
 
@@ -85,6 +93,20 @@ bitflags! {
     }
 }
 
+/// Arguments shared by `clone` and `clone3`, see [`do_clone`].
+struct CloneArgs {
+    flags: CloneFlags,
+    exit_signal: u32,
+    stack: usize,
+    tls: usize,
+    parent_tid: usize,
+    child_tid: usize,
+    /// Where to store the pidfd if `CLONE_PIDFD` is set.
+    pidfd: usize,
+    /// `CLONE_CLEAR_SIGHAND` from `clone3`.
+    clear_sighand: bool,
+}
+
 pub fn sys_clone(
     uctx: &UserContext,
     flags: u32,
@@ -96,11 +118,7 @@ pub fn sys_clone(
 ) -> AxResult<isize> {
     const FLAG_MASK: u32 = 0xff;
     let exit_signal = flags & FLAG_MASK;
-    let mut flags = CloneFlags::from_bits_truncate(flags & !FLAG_MASK);
-    if flags.contains(CloneFlags::VFORK) {
-        debug!("sys_clone: CLONE_VFORK slow path");
-        flags.remove(CloneFlags::VM);
-    }
+    let flags = CloneFlags::from_bits_truncate(flags & !FLAG_MASK);
 
     debug!(
         "sys_clone <= flags: {:?}, exit_signal: {}, stack: {:#x}, ptid: {:#x}, ctid: {:#x}, tls: \
@@ -108,16 +126,174 @@ pub fn sys_clone(
         flags, exit_signal, stack, parent_tid, child_tid, tls
     );
 
-    if exit_signal != 0 && flags.contains(CloneFlags::THREAD | CloneFlags::PARENT) {
+    // `clone` reuses `parent_tid` to return the pidfd.
+    if flags.contains(CloneFlags::PIDFD | CloneFlags::PARENT_SETTID) {
         return Err(AxError::InvalidInput);
     }
-    if flags.contains(CloneFlags::THREAD) && !flags.contains(CloneFlags::VM | CloneFlags::SIGHAND) {
+
+    do_clone(
+        uctx,
+        CloneArgs {
+            flags,
+            exit_signal,
+            stack,
+            tls,
+            parent_tid,
+            child_tid,
+            pidfd: parent_tid,
+            clear_sighand: false,
+        },
+    )
+}
+
+pub fn sys_clone3(uctx: &UserContext, args: *const clone_args, size: usize) -> AxResult<isize> {
+    // Older, smaller versions of `struct clone_args` are zero-extended; newer
+    // ones must not use fields we don't know about.
+    if size < CLONE_ARGS_SIZE_VER0 as usize {
         return Err(AxError::InvalidInput);
     }
-    if flags.contains(CloneFlags::PIDFD | CloneFlags::PARENT_SETTID) {
+    let mut raw = [0u8; size_of::<clone_args>()];
+    let user = vm_load(args.cast::<u8>(), size)?;
+    let (known, extra) = user.split_at(size.min(raw.len()));
+    if extra.iter().any(|&b| b != 0) {
+        return Err(AxError::Other(LinuxError::E2BIG));
+    }
+    raw[..known.len()].copy_from_slice(known);
+    let args = unsafe { (raw.as_ptr() as *const clone_args).read_unaligned() };
+
+    debug!(
+        "sys_clone3 <= flags: {:#x}, exit_signal: {}, stack: {:#x}+{:#x}, ptid: {:#x}, ctid: \
+         {:#x}, pidfd: {:#x}, tls: {:#x}, set_tid_size: {}",
+        args.flags,
+        args.exit_signal,
+        args.stack,
+        args.stack_size,
+        args.parent_tid,
+        args.child_tid,
+        args.pidfd,
+        args.tls,
+        args.set_tid_size
+    );
+
+    // The exit signal has its own field; the low byte of `flags` is reserved.
+    if args.flags & 0xff != 0
+        || (args.exit_signal != 0
+            && u8::try_from(args.exit_signal)
+                .ok()
+                .and_then(Signo::from_repr)
+                .is_none())
+    {
         return Err(AxError::InvalidInput);
     }
-    let exit_signal = Signo::from_repr(exit_signal as u8);
+    // Threads and siblings don't signal the parent with their own signal,
+    // which `clone3` won't let the caller think they do.
+    if args.exit_signal != 0 && args.flags & (CLONE_THREAD | CLONE_PARENT) as u64 != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let clear_sighand = args.flags & CLONE_CLEAR_SIGHAND as u64 != 0;
+    if clear_sighand && args.flags & CLONE_SIGHAND as u64 != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if args.flags & CLONE_INTO_CGROUP as u64 != 0 {
+        return Err(AxError::Unsupported);
+    }
+    let flags = CloneFlags::from_bits(
+        (args.flags & !(CLONE_CLEAR_SIGHAND as u64))
+            .try_into()
+            .map_err(|_| AxError::InvalidInput)?,
+    )
+    .ok_or(AxError::InvalidInput)?;
+
+    if (args.stack == 0) != (args.stack_size == 0) {
+        return Err(AxError::InvalidInput);
+    }
+    match args.set_tid_size {
+        0 if args.set_tid == 0 => {}
+        // There is only a single pid namespace, and thread ids are handed out
+        // by the scheduler, so a specific tid can't be honoured. Linux reports
+        // missing privileges the same way.
+        1 => return Err(AxError::OperationNotPermitted),
+        _ => return Err(AxError::InvalidInput),
+    }
+
+    do_clone(
+        uctx,
+        CloneArgs {
+            flags,
+            exit_signal: args.exit_signal as _,
+            // Unlike `clone`, `clone3` gets the lowest address of the stack.
+            stack: (args.stack + args.stack_size) as _,
+            tls: args.tls as _,
+            parent_tid: args.parent_tid as _,
+            child_tid: args.child_tid as _,
+            pidfd: args.pidfd as _,
+            clear_sighand,
+        },
+    )
+}
+
+/// Copies `actions` for `CLONE_CLEAR_SIGHAND`: signals that are caught go
+/// back to their default action, while ignored ones stay ignored.
+fn clear_handlers(actions: &SignalActions) -> SignalActions {
+    let mut actions = actions.clone();
+    for signo in (1..=64).filter_map(Signo::from_repr) {
+        let action: kernel_sigaction = actions[signo].clone().into();
+        let handler = action.sa_handler_kernel.map_or(0, |f| f as usize);
+        if handler != 0 && handler != SIG_IGN as usize {
+            // SAFETY: an all-zero `kernel_sigaction` is `SIG_DFL` with no
+            // flags and an empty mask.
+            actions[signo] = unsafe { core::mem::zeroed::<kernel_sigaction>() }.into();
+        }
+    }
+    actions
+}
+
+fn do_clone(uctx: &UserContext, args: CloneArgs) -> AxResult<isize> {
+    let CloneArgs {
+        mut flags,
+        exit_signal,
+        stack,
+        tls,
+        parent_tid,
+        child_tid,
+        pidfd,
+        clear_sighand,
+    } = args;
+    // A thread shares the signal handlers of its process, and shared signal
+    // handlers have to live in a shared address space.
+    if flags.contains(CloneFlags::THREAD) && !flags.contains(CloneFlags::SIGHAND) {
+        return Err(AxError::InvalidInput);
+    }
+    if flags.contains(CloneFlags::SIGHAND) && !flags.contains(CloneFlags::VM) {
+        return Err(AxError::InvalidInput);
+    }
+    // A new mount or user namespace can't go with a shared root and cwd.
+    if flags.intersects(CloneFlags::NEWNS | CloneFlags::NEWUSER) && flags.contains(CloneFlags::FS) {
+        return Err(AxError::InvalidInput);
+    }
+    if flags.contains(CloneFlags::PIDFD | CloneFlags::THREAD) {
+        return Err(AxError::InvalidInput);
+    }
+    if flags.contains(CloneFlags::SETTLS) {
+        check_tls(tls)?;
+    }
+
+    let vfork = flags.contains(CloneFlags::VFORK);
+    if vfork {
+        debug!("sys_clone: CLONE_VFORK slow path");
+        flags.remove(CloneFlags::VM);
+    }
+
+    let curr = current();
+    let old_proc_data = &curr.as_thread().proc_data;
+
+    // A sibling exits with the signal its parent exits with. A thread shares
+    // the process, so it has none of its own to use.
+    let exit_signal = if flags.contains(CloneFlags::PARENT) {
+        old_proc_data.exit_signal
+    } else {
+        Signo::from_repr(exit_signal as u8)
+    };
 
     let mut new_uctx = *uctx;
     if stack != 0 {
@@ -129,19 +305,21 @@ pub fn sys_clone(
     new_uctx.set_retval(0);
 
     let set_child_tid = if flags.contains(CloneFlags::CHILD_SETTID) {
-        Some(UserPtr::<u32>::from(child_tid).get_as_mut()?)
+        Some(child_tid)
     } else {
         None
     };
 
-    let curr = current();
-    let old_proc_data = &curr.as_thread().proc_data;
-
     let mut new_task = new_user_task(&curr.name(), new_uctx, set_child_tid);
 
     let tid = new_task.id().as_u64() as Pid;
+    // Task ids are never reused, so once they reach `pid_max` no more tasks
+    // can be created.
+    if tid as usize >= PID_MAX.get() {
+        return Err(AxError::WouldBlock);
+    }
     if flags.contains(CloneFlags::PARENT_SETTID) {
-        *UserPtr::<Pid>::from(parent_tid).get_as_mut()? = tid;
+        (parent_tid as *mut Pid).vm_write(tid)?;
     }
 
     let new_proc_data = if flags.contains(CloneFlags::THREAD) {
@@ -171,18 +349,32 @@ pub fn sys_clone(
 
         let signal_actions = if flags.contains(CloneFlags::SIGHAND) {
             old_proc_data.signal.actions.clone()
+        } else if clear_sighand {
+            Arc::new(SpinNoIrq::new(clear_handlers(
+                &old_proc_data.signal.actions.lock(),
+            )))
         } else {
             Arc::new(SpinNoIrq::new(old_proc_data.signal.actions.lock().clone()))
         };
         let proc_data = ProcessData::new(
             proc,
             old_proc_data.exe_path.read().clone(),
+            old_proc_data.exe.read().clone(),
             old_proc_data.cmdline.read().clone(),
+            old_proc_data.environ.read().clone(),
             aspace,
             signal_actions,
             exit_signal,
         );
         proc_data.set_umask(old_proc_data.umask());
+        proc_data.replace_personality(old_proc_data.personality());
+        *proc_data.cred.write() = old_proc_data.cred.read().clone();
+        proc_data.set_dumpable(old_proc_data.dumpable());
+        *proc_data.range_policies.lock() = old_proc_data.range_policies.lock().clone();
+        *proc_data.written_ranges.lock() = old_proc_data.written_ranges.lock().clone();
+        // The child shares every page of the parent until it writes to it,
+        // and each of them counts towards the resident set of both.
+        proc_data.add_rss(resident_pages(&proc_data.aspace.lock(), 0..usize::MAX) as isize);
 
         {
             let mut scope = proc_data.scope.write();
@@ -209,24 +401,63 @@ pub fn sys_clone(
     };
 
     new_proc_data.proc.add_thread(tid);
+    if vfork {
+        new_proc_data.set_vfork_pending();
+    }
 
     if flags.contains(CloneFlags::PIDFD) {
-        let pidfd = PidFd::new(&new_proc_data);
-        *UserPtr::<i32>::from(parent_tid).get_as_mut()? = pidfd.add_to_fd_table(true)?;
+        // The fd is installed before the child can run, so the caller never
+        // observes the child without its pidfd.
+        (pidfd as *mut i32).vm_write(PidFd::new(&new_proc_data).add_to_fd_table(true)?)?;
     }
 
+    let child_data = new_proc_data.clone();
     let thr = Thread::new(tid, new_proc_data);
+    thr.set_mempolicy(curr.as_thread().mempolicy());
+    thr.set_ioprio(curr.as_thread().ioprio());
     if flags.contains(CloneFlags::CHILD_CLEARTID) {
         thr.set_clear_child_tid(child_tid);
     }
     *new_task.task_ext_mut() = Some(unsafe { TaskExtProxy::from_impl(thr) });
 
+    // The child inherits the affinity of its parent.
+    new_task.set_cpumask(cpu::restrict(&curr.cpumask()));
     let task = spawn_task(new_task);
     add_task_to_table(&task);
 
+    if vfork {
+        // The child runs on its own copy of the address space, but the
+        // caller still expects it to be done with it before going on. A
+        // signal cuts the wait short, as it does on Linux.
+        let _ = block_on(interruptible(poll_fn(|cx| {
+            if !child_data.vfork_pending() {
+                return Poll::Ready(());
+            }
+            child_data.vfork_done.register(cx.waker());
+            if child_data.vfork_pending() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })));
+    }
+
     Ok(tid as _)
 }
 
+/// Checks the TLS pointer a thread is given, by `CLONE_SETTLS` or
+/// `arch_prctl(ARCH_SET_FS)`.
+///
+/// The thread pointer is an ordinary register on riscv64, loongarch64 and
+/// aarch64 (`tp` and `TPIDR_EL0`), which takes any value. On x86_64 it is the
+/// FS base, and loading a non-canonical one faults in the kernel.
+pub(super) fn check_tls(tls: usize) -> AxResult<()> {
+    if cfg!(target_arch = "x86_64") && tls >= USER_SPACE_BASE + USER_SPACE_SIZE {
+        return Err(AxError::OperationNotPermitted);
+    }
+    Ok(())
+}
+
 #[cfg(target_arch = "x86_64")]
 pub fn sys_fork(uctx: &UserContext) -> AxResult<isize> {
     sys_clone(uctx, SIGCHLD, 0, 0, 0, 0)
@@ -4,6 +4,7 @@ use axerrno::{AxError, AxResult};
 use axtask::current;
 use linux_raw_sys::general::{__user_cap_data_struct, __user_cap_header_struct};
 use starry_core::task::{AsThread, get_process_data};
+use starry_signal::Signo;
 use starry_vm::{VmMutPtr, VmPtr, vm_write_slice};
 
 use crate::mm::vm_load_string;
@@ -51,27 +52,125 @@ pub fn sys_umask(mask: u32) -> AxResult<isize> {
     Ok(old as isize)
 }
 
-pub fn sys_setreuid(_ruid: u32, _euid: u32) -> AxResult<isize> {
-    Ok(0)
+pub fn sys_personality(persona: u32) -> AxResult<isize> {
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    // 0xffffffff only queries the current personality.
+    let old = if persona == u32::MAX {
+        proc_data.personality()
+    } else {
+        proc_data.replace_personality(persona)
+    };
+    Ok(old as isize)
 }
 
-pub fn sys_setresuid(_ruid: u32, _euid: u32, _suid: u32) -> AxResult<isize> {
-    Ok(0)
+/// The id argument value that leaves an id unchanged.
+const ID_UNCHANGED: u32 = u32::MAX;
+
+pub fn sys_setreuid(ruid: u32, euid: u32) -> AxResult<isize> {
+    debug!("sys_setreuid <= ruid: {}, euid: {}", ruid, euid);
+    current().as_thread().proc_data.update_cred(|cred| {
+        let privileged = cred.is_privileged();
+        let old_ruid = cred.uid;
+        if ruid != ID_UNCHANGED {
+            if !privileged && ruid != cred.uid && ruid != cred.euid {
+                return Err(AxError::OperationNotPermitted);
+            }
+            cred.uid = ruid;
+        }
+        if euid != ID_UNCHANGED {
+            if !privileged && !cred.has_uid(euid) {
+                return Err(AxError::OperationNotPermitted);
+            }
+            cred.euid = euid;
+        }
+        // The saved id follows the effective one whenever the real id is set
+        // or the effective id moves away from the old real id.
+        if ruid != ID_UNCHANGED || (euid != ID_UNCHANGED && euid != old_ruid) {
+            cred.suid = cred.euid;
+        }
+        cred.fsuid = cred.euid;
+        Ok(0)
+    })
 }
 
-pub fn sys_setresgid(_rgid: u32, _egid: u32, _sgid: u32) -> AxResult<isize> {
-    Ok(0)
+pub fn sys_setregid(rgid: u32, egid: u32) -> AxResult<isize> {
+    debug!("sys_setregid <= rgid: {}, egid: {}", rgid, egid);
+    current().as_thread().proc_data.update_cred(|cred| {
+        let privileged = cred.is_privileged();
+        let old_rgid = cred.gid;
+        if rgid != ID_UNCHANGED {
+            if !privileged && rgid != cred.gid && rgid != cred.egid {
+                return Err(AxError::OperationNotPermitted);
+            }
+            cred.gid = rgid;
+        }
+        if egid != ID_UNCHANGED {
+            if !privileged && !cred.has_gid(egid) {
+                return Err(AxError::OperationNotPermitted);
+            }
+            cred.egid = egid;
+        }
+        if rgid != ID_UNCHANGED || (egid != ID_UNCHANGED && egid != old_rgid) {
+            cred.sgid = cred.egid;
+        }
+        cred.fsgid = cred.egid;
+        Ok(0)
+    })
 }
 
-pub fn sys_get_mempolicy(
-    _policy: *mut i32,
-    _nodemask: *mut usize,
-    _maxnode: usize,
-    _addr: usize,
-    _flags: usize,
-) -> AxResult<isize> {
-    warn!("Dummy get_mempolicy called");
-    Ok(0)
+pub fn sys_setresuid(ruid: u32, euid: u32, suid: u32) -> AxResult<isize> {
+    debug!(
+        "sys_setresuid <= ruid: {}, euid: {}, suid: {}",
+        ruid, euid, suid
+    );
+    current().as_thread().proc_data.update_cred(|cred| {
+        let ids = [ruid, euid, suid];
+        if !cred.is_privileged()
+            && ids
+                .iter()
+                .any(|&id| id != ID_UNCHANGED && !cred.has_uid(id))
+        {
+            return Err(AxError::OperationNotPermitted);
+        }
+        for (slot, id) in [&mut cred.uid, &mut cred.euid, &mut cred.suid]
+            .into_iter()
+            .zip(ids)
+        {
+            if id != ID_UNCHANGED {
+                *slot = id;
+            }
+        }
+        cred.fsuid = cred.euid;
+        Ok(0)
+    })
+}
+
+pub fn sys_setresgid(rgid: u32, egid: u32, sgid: u32) -> AxResult<isize> {
+    debug!(
+        "sys_setresgid <= rgid: {}, egid: {}, sgid: {}",
+        rgid, egid, sgid
+    );
+    current().as_thread().proc_data.update_cred(|cred| {
+        let ids = [rgid, egid, sgid];
+        if !cred.is_privileged()
+            && ids
+                .iter()
+                .any(|&id| id != ID_UNCHANGED && !cred.has_gid(id))
+        {
+            return Err(AxError::OperationNotPermitted);
+        }
+        for (slot, id) in [&mut cred.gid, &mut cred.egid, &mut cred.sgid]
+            .into_iter()
+            .zip(ids)
+        {
+            if id != ID_UNCHANGED {
+                *slot = id;
+            }
+        }
+        cred.fsgid = cred.egid;
+        Ok(0)
+    })
 }
 
 pub fn sys_prctl(
@@ -91,7 +190,12 @@ pub fn sys_prctl(
     match option {
         PR_SET_NAME => {
             let s = vm_load_string(arg2 as *const c_char)?;
-            current().set_name(&s);
+            // Like the comm of Linux, names are cut to 15 bytes.
+            let mut len = s.len().min(15);
+            while !s.is_char_boundary(len) {
+                len -= 1;
+            }
+            current().set_name(&s[..len]);
         }
         PR_GET_NAME => {
             let name = current().name();
@@ -100,6 +204,49 @@ pub fn sys_prctl(
             buf[..len].copy_from_slice(&name.as_bytes()[..len]);
             vm_write_slice(arg2 as _, &buf)?;
         }
+        PR_GET_DUMPABLE => {
+            return Ok(current().as_thread().proc_data.dumpable() as isize);
+        }
+        PR_SET_DUMPABLE => {
+            if arg2 > 1 {
+                return Err(AxError::InvalidInput);
+            }
+            current().as_thread().proc_data.set_dumpable(arg2 == 1);
+        }
+        PR_SET_PDEATHSIG => {
+            let signo = match arg2 {
+                0 => None,
+                _ => Some(Signo::from_repr(arg2 as u8).ok_or(AxError::InvalidInput)?),
+            };
+            current().as_thread().proc_data.set_pdeath_signal(signo);
+        }
+        PR_GET_PDEATHSIG => {
+            let signo = current().as_thread().proc_data.pdeath_signal();
+            (arg2 as *mut i32).vm_write(signo.map_or(0, |signo| signo as i32))?;
+        }
+        PR_SET_CHILD_SUBREAPER => {
+            current()
+                .as_thread()
+                .proc_data
+                .proc
+                .set_child_subreaper(arg2 != 0);
+        }
+        PR_GET_CHILD_SUBREAPER => {
+            let subreaper = current().as_thread().proc_data.proc.is_child_subreaper();
+            (arg2 as *mut i32).vm_write(subreaper as i32)?;
+        }
+        PR_SET_NO_NEW_PRIVS => {
+            if arg2 != 1 || arg3 != 0 || arg4 != 0 || arg5 != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            current().as_thread().proc_data.cred.write().no_new_privs = true;
+        }
+        PR_GET_NO_NEW_PRIVS => {
+            if arg2 != 0 || arg3 != 0 || arg4 != 0 || arg5 != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            return Ok(current().as_thread().proc_data.cred.read().no_new_privs as isize);
+        }
         PR_SET_SECCOMP => {}
         PR_MCE_KILL => {}
         PR_SET_MM_START_CODE
@@ -1,23 +1,32 @@
-use alloc::{string::ToString, sync::Arc, vec::Vec};
-use core::ffi::c_char;
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::ffi::{c_char, c_int};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axfs_ng::FS_CONTEXT;
+use axfs_ng_vfs::{NodePermission, NodeType};
 use axhal::uspace::UserContext;
 use axtask::current;
-use starry_core::{mm::load_user_app, task::AsThread};
+use linux_raw_sys::general::{AT_EMPTY_PATH, AT_SYMLINK_NOFOLLOW};
+use starry_core::{
+    mm::{load_user_app, resident_pages},
+    task::{AsThread, PER_CLEAR_ON_SETID},
+    vfs::{MountFlags, mount_flags},
+};
 use starry_vm::vm_load_until_nul;
 
-use crate::{file::FD_TABLE, mm::vm_load_string};
+use crate::{
+    file::{FD_TABLE, fscrypt, perf, resolve_at},
+    mm::vm_load_string,
+};
 
-pub fn sys_execve(
-    uctx: &mut UserContext,
-    path: *const c_char,
+fn load_args(
     argv: *const *const c_char,
     envp: *const *const c_char,
-) -> AxResult<isize> {
-    let path = vm_load_string(path)?;
-
+) -> AxResult<(Vec<String>, Vec<String>)> {
     let args = vm_load_until_nul(argv)?
         .into_iter()
         .map(vm_load_string)
@@ -28,11 +37,68 @@ pub fn sys_execve(
         .map(vm_load_string)
         .collect::<Result<Vec<_>, _>>()?;
 
+    Ok((args, envs))
+}
+
+pub fn sys_execve(
+    uctx: &mut UserContext,
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> AxResult<isize> {
+    let path = vm_load_string(path)?;
+    let (args, envs) = load_args(argv, envp)?;
+
     debug!(
         "sys_execve <= path: {:?}, args: {:?}, envs: {:?}",
         path, args, envs
     );
 
+    do_execve(uctx, path, args, envs)
+}
+
+pub fn sys_execveat(
+    uctx: &mut UserContext,
+    dirfd: c_int,
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    flags: u32,
+) -> AxResult<isize> {
+    let path = (!path.is_null())
+        .then(|| vm_load_string(path))
+        .transpose()?;
+    let (args, envs) = load_args(argv, envp)?;
+
+    debug!(
+        "sys_execveat <= dirfd: {}, path: {:?}, args: {:?}, envs: {:?}, flags: {:#x}",
+        dirfd, path, args, envs, flags
+    );
+
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    // With `AT_EMPTY_PATH` this execs `dirfd` itself, which is how fexecve(3)
+    // is implemented; memfd files live in the tmpfs so they resolve as well.
+    let loc = resolve_at(dirfd, path.as_deref(), flags)?
+        .into_file()
+        .ok_or(AxError::PermissionDenied)?;
+    match loc.metadata()?.node_type {
+        NodeType::RegularFile => {}
+        NodeType::Symlink => return Err(AxError::Other(LinuxError::ELOOP)),
+        _ => return Err(AxError::PermissionDenied),
+    }
+    let path = loc.absolute_path()?.to_string();
+
+    do_execve(uctx, path, args, envs)
+}
+
+fn do_execve(
+    uctx: &mut UserContext,
+    path: String,
+    args: Vec<String>,
+    envs: Vec<String>,
+) -> AxResult<isize> {
     let curr = current();
     let proc_data = &curr.as_thread().proc_data;
 
@@ -42,16 +108,64 @@ pub fn sys_execve(
         return Err(AxError::WouldBlock);
     }
 
-    let mut aspace = proc_data.aspace.lock();
+    let loc = FS_CONTEXT.lock().resolve(&path)?;
+    let mount_flags = mount_flags(&loc);
+    if mount_flags.contains(MountFlags::NOEXEC) {
+        return Err(AxError::PermissionDenied);
+    }
+    fscrypt::check_exec(&loc)?;
+
+    // Honor the set-user-id and set-group-id bits, unless the file lives on
+    // a `nosuid` mount or the process has no_new_privs set. A set-group-id file
+    // without group execute permission marks mandatory locking instead.
+    let meta = loc.metadata()?;
+    let mut setuid = meta
+        .mode
+        .contains(NodePermission::SET_UID)
+        .then_some(meta.uid);
+    let mut setgid = meta
+        .mode
+        .contains(NodePermission::SET_GID | NodePermission::GROUP_EXEC)
+        .then_some(meta.gid);
+    if mount_flags.contains(MountFlags::NOSUID) || proc_data.cred.read().no_new_privs {
+        setuid = None;
+        setgid = None;
+    }
+    let mut cred = proc_data.cred.read().clone();
+    cred.apply_exec(setuid, setgid);
+    let privileged_exec =
+        setuid.is_some_and(|uid| uid != cred.uid) || setgid.is_some_and(|gid| gid != cred.gid);
+
     let (entry_point, user_stack_base) =
-        load_user_app(&mut aspace, Some(path.as_str()), &args, &envs)?;
-    drop(aspace);
+        load_user_app(&proc_data.aspace, Some(path.as_str()), &args, &envs, &cred)?;
 
-    let loc = FS_CONTEXT.lock().resolve(&path)?;
+    proc_data.reset_rss();
+    proc_data.add_rss(resident_pages(&proc_data.aspace.lock(), 0..usize::MAX) as isize);
+    proc_data.range_policies.lock().clear();
+    proc_data.written_ranges.lock().clear();
+    proc_data.locked_ranges.lock().clear();
+    proc_data.set_mlockall_flags(0);
+    // The old image is gone, and with it the words the C library asked to be
+    // cleared and the robust futexes it registered.
+    curr.as_thread().set_clear_child_tid(0);
+    curr.as_thread().set_robust_list_head(0);
+    // The thread and process keyrings don't survive `execve`.
+    *curr.as_thread().keyring.lock() = None;
+    *proc_data.keyring.lock() = None;
+    if privileged_exec {
+        // Don't let the caller weaken a privileged program.
+        proc_data.replace_personality(proc_data.personality() & !PER_CLEAR_ON_SETID);
+    }
     curr.set_name(loc.name());
+    proc_data.update_cred(|old| {
+        *old = cred;
+        Ok(())
+    })?;
 
     *proc_data.exe_path.write() = loc.absolute_path()?.to_string();
+    *proc_data.exe.write() = loc;
     *proc_data.cmdline.write() = Arc::new(args);
+    *proc_data.environ.write() = Arc::new(envs);
 
     *proc_data.signal.actions.lock() = Default::default();
 
@@ -66,6 +180,11 @@ pub fn sys_execve(
     }
     drop(fd_table);
 
+    perf::on_exec();
+
+    // A `vfork` parent may go on now that the child has a new image.
+    proc_data.release_vfork();
+
     uctx.set_ip(entry_point.as_usize());
     uctx.set_sp(user_stack_base.as_usize());
     Ok(0)
@@ -1,14 +1,20 @@
+use alloc::{vec, vec::Vec};
+
 use axerrno::{AxError, AxResult};
 use axhal::time::TimeValue;
 use axtask::{
-    AxCpuMask, current,
+    AxCpuMask, AxTaskRef, current,
     future::{block_on, interruptible, sleep},
 };
 use linux_raw_sys::general::{
     __kernel_clockid_t, CLOCK_MONOTONIC, CLOCK_REALTIME, PRIO_PGRP, PRIO_PROCESS, PRIO_USER,
-    SCHED_RR, TIMER_ABSTIME, timespec,
+    SCHED_BATCH, SCHED_DEADLINE, SCHED_FIFO, SCHED_IDLE, SCHED_NORMAL, SCHED_RR, TIMER_ABSTIME,
+    timespec,
+};
+use starry_core::{
+    cpu, numa,
+    task::{AsThread, get_process_data, get_process_group, get_task, tasks},
 };
-use starry_core::task::{get_process_data, get_process_group};
 use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
 
 use crate::time::TimeValueLike;
@@ -90,16 +96,16 @@ pub fn sys_clock_nanosleep(
 }
 
 pub fn sys_sched_getaffinity(pid: i32, cpusetsize: usize, user_mask: *mut u8) -> AxResult<isize> {
-    if cpusetsize * 8 < axconfig::plat::CPU_NUM {
+    debug!(
+        "sys_sched_getaffinity <= pid: {}, cpusetsize: {}",
+        pid, cpusetsize
+    );
+    if cpusetsize * 8 < axconfig::plat::CPU_NUM || cpusetsize % size_of::<usize>() != 0 {
         return Err(AxError::InvalidInput);
     }
 
-    // TODO: support other threads
-    if pid != 0 {
-        return Err(AxError::OperationNotPermitted);
-    }
-
-    let mask = current().cpumask();
+    let task = get_task(pid as _)?;
+    let mask = task.cpumask();
     let mask_bytes = mask.as_bytes();
 
     vm_write_slice(user_mask, mask_bytes)?;
@@ -107,27 +113,52 @@ pub fn sys_sched_getaffinity(pid: i32, cpusetsize: usize, user_mask: *mut u8) ->
     Ok(mask_bytes.len() as _)
 }
 
-pub fn sys_sched_setaffinity(
-    _pid: i32,
-    cpusetsize: usize,
-    user_mask: *const u8,
-) -> AxResult<isize> {
+pub fn sys_sched_setaffinity(pid: i32, cpusetsize: usize, user_mask: *const u8) -> AxResult<isize> {
+    debug!(
+        "sys_sched_setaffinity <= pid: {}, cpusetsize: {}",
+        pid, cpusetsize
+    );
     let size = cpusetsize.min(axconfig::plat::CPU_NUM.div_ceil(8));
     let user_mask = vm_load(user_mask, size)?;
     let mut cpu_mask = AxCpuMask::new();
 
+    // Bits for CPUs that don't exist are silently dropped, but at least one
+    // online CPU must remain.
     for i in 0..(size * 8).min(axconfig::plat::CPU_NUM) {
-        if user_mask[i / 8] & (1 << (i % 8)) != 0 {
+        if user_mask[i / 8] & (1 << (i % 8)) != 0 && cpu::is_online(i) {
             cpu_mask.set(i, true);
         }
     }
+    if cpu_mask.is_empty() {
+        return Err(AxError::InvalidInput);
+    }
 
-    // TODO: support other threads
-    axtask::set_current_affinity(cpu_mask);
+    let task = get_task(pid as _)?;
+    if task.id() == current().id() {
+        // Migrates right away if the current CPU is no longer allowed.
+        axtask::set_current_affinity(cpu_mask);
+    } else {
+        // The mask is only recorded: the run queues live in axtask, which
+        // doesn't migrate queued tasks, so a task already queued on a CPU
+        // that is no longer allowed keeps running there until it sleeps.
+        // Its next wakeup picks an allowed CPU.
+        task.set_cpumask(cpu_mask);
+    }
 
     Ok(0)
 }
 
+pub fn sys_getcpu(cpu: *mut u32, node: *mut u32) -> AxResult<isize> {
+    let this_cpu = axhal::percpu::this_cpu_id();
+    if let Some(cpu) = cpu.nullable() {
+        cpu.vm_write(this_cpu as u32)?;
+    }
+    if let Some(node) = node.nullable() {
+        node.vm_write(numa::node_of_cpu(this_cpu) as u32)?;
+    }
+    Ok(0)
+}
+
 pub fn sys_sched_getscheduler(_pid: i32) -> AxResult<isize> {
     Ok(SCHED_RR as _)
 }
@@ -140,6 +171,45 @@ pub fn sys_sched_getparam(_pid: i32, _param: *mut ()) -> AxResult<isize> {
     Ok(0)
 }
 
+/// The highest static priority of the real-time policies.
+const MAX_RT_PRIO: u32 = 99;
+/// The ticks a task runs for before the scheduler rotates it out, as set by
+/// the round-robin scheduler of `axtask`.
+const RR_TIME_SLICE_TICKS: u64 = 5;
+
+/// Returns the range of static priorities of `policy`.
+fn priority_range(policy: i32) -> AxResult<(u32, u32)> {
+    match policy as u32 {
+        SCHED_FIFO | SCHED_RR => Ok((1, MAX_RT_PRIO)),
+        SCHED_NORMAL | SCHED_BATCH | SCHED_IDLE | SCHED_DEADLINE => Ok((0, 0)),
+        _ => Err(AxError::InvalidInput),
+    }
+}
+
+pub fn sys_sched_get_priority_max(policy: i32) -> AxResult<isize> {
+    priority_range(policy).map(|(_, max)| max as isize)
+}
+
+pub fn sys_sched_get_priority_min(policy: i32) -> AxResult<isize> {
+    priority_range(policy).map(|(min, _)| min as isize)
+}
+
+pub fn sys_sched_rr_get_interval(pid: i32, interval: *mut timespec) -> AxResult<isize> {
+    debug!("sys_sched_rr_get_interval <= pid: {}", pid);
+
+    if pid < 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if pid != 0 {
+        get_task(pid as _)?;
+    }
+    // Every task is scheduled round-robin, with the same time slice.
+    let slice =
+        TimeValue::from_nanos(RR_TIME_SLICE_TICKS * 1_000_000_000 / axconfig::TICKS_PER_SEC as u64);
+    interval.vm_write(timespec::from_time_value(slice))?;
+    Ok(0)
+}
+
 pub fn sys_getpriority(which: u32, who: u32) -> AxResult<isize> {
     debug!("sys_getpriority <= which: {}, who: {}", which, who);
 
@@ -166,3 +236,111 @@ pub fn sys_getpriority(which: u32, who: u32) -> AxResult<isize> {
         _ => Err(AxError::InvalidInput),
     }
 }
+
+const IOPRIO_WHO_PROCESS: u32 = 1;
+const IOPRIO_WHO_PGRP: u32 = 2;
+const IOPRIO_WHO_USER: u32 = 3;
+
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_CLASS_NONE: u32 = 0;
+const IOPRIO_CLASS_RT: u32 = 1;
+const IOPRIO_CLASS_BE: u32 = 2;
+const IOPRIO_CLASS_IDLE: u32 = 3;
+/// The number of levels of the real-time and best-effort classes.
+const IOPRIO_NR_LEVELS: u32 = 8;
+/// The priority of tasks that never set one: best effort at the level
+/// Linux derives from a nice value of 0.
+const IOPRIO_DEFAULT: u32 = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 4;
+
+/// Returns the threads `which` and `who` select for `ioprio_set` and
+/// `ioprio_get`.
+fn ioprio_targets(which: u32, who: u32) -> AxResult<Vec<AxTaskRef>> {
+    let curr = current();
+    let targets = match which {
+        IOPRIO_WHO_PROCESS => vec![if who == 0 {
+            curr.clone()
+        } else {
+            get_task(who)?
+        }],
+        IOPRIO_WHO_PGRP => {
+            let pg = if who == 0 {
+                curr.as_thread().proc_data.proc.group()
+            } else {
+                get_process_group(who)?
+            };
+            pg.processes()
+                .iter()
+                .flat_map(|proc| proc.threads())
+                .filter_map(|tid| get_task(tid).ok())
+                .collect()
+        }
+        IOPRIO_WHO_USER => {
+            let uid = if who == 0 {
+                curr.as_thread().proc_data.cred.read().uid
+            } else {
+                who
+            };
+            tasks()
+                .into_iter()
+                .filter(|task| {
+                    task.try_as_thread()
+                        .is_some_and(|thr| thr.proc_data.cred.read().uid == uid)
+                })
+                .collect()
+        }
+        _ => return Err(AxError::InvalidInput),
+    };
+    if targets.is_empty() {
+        return Err(AxError::NoSuchProcess);
+    }
+    Ok(targets)
+}
+
+pub fn sys_ioprio_set(which: u32, who: u32, ioprio: u32) -> AxResult<isize> {
+    debug!(
+        "sys_ioprio_set <= which: {}, who: {}, ioprio: {:#x}",
+        which, who, ioprio
+    );
+
+    let (class, level) = (
+        ioprio >> IOPRIO_CLASS_SHIFT,
+        ioprio & ((1 << IOPRIO_CLASS_SHIFT) - 1),
+    );
+    let cred = current().as_thread().proc_data.cred.read().clone();
+    match class {
+        IOPRIO_CLASS_RT if !cred.is_privileged() => return Err(AxError::OperationNotPermitted),
+        IOPRIO_CLASS_RT | IOPRIO_CLASS_BE if level >= IOPRIO_NR_LEVELS => {
+            return Err(AxError::InvalidInput);
+        }
+        IOPRIO_CLASS_RT | IOPRIO_CLASS_BE | IOPRIO_CLASS_IDLE => {}
+        IOPRIO_CLASS_NONE if level == 0 => {}
+        _ => return Err(AxError::InvalidInput),
+    }
+
+    for task in ioprio_targets(which, who)? {
+        let thr = task.as_thread();
+        let target = thr.proc_data.cred.read();
+        if !cred.is_privileged() && cred.euid != target.uid && cred.euid != target.euid {
+            return Err(AxError::OperationNotPermitted);
+        }
+        drop(target);
+        thr.set_ioprio(ioprio);
+    }
+    Ok(0)
+}
+
+pub fn sys_ioprio_get(which: u32, who: u32) -> AxResult<isize> {
+    debug!("sys_ioprio_get <= which: {}, who: {}", which, who);
+
+    // Of several threads, report the highest priority, which is the lowest
+    // class and then the lowest level.
+    let best = ioprio_targets(which, who)?
+        .iter()
+        .map(|task| match task.as_thread().ioprio() {
+            0 => IOPRIO_DEFAULT,
+            ioprio => ioprio,
+        })
+        .min()
+        .unwrap_or(IOPRIO_DEFAULT);
+    Ok(best as isize)
+}
@@ -64,13 +64,12 @@ pub fn sys_arch_prctl(
     debug!("sys_arch_prctl: code = {:?}, addr = {:#x}", code, addr);
 
     match code {
-        // According to Linux implementation, SetFs & SetGs does not return
-        // error at all
         ArchPrctlCode::GetFs => {
             (addr as *mut usize).vm_write(tf.tls())?;
             Ok(0)
         }
         ArchPrctlCode::SetFs => {
+            super::clone::check_tls(addr)?;
             tf.set_tls(addr);
             Ok(0)
         }
@@ -80,6 +79,7 @@ pub fn sys_arch_prctl(
             Ok(0)
         }
         ArchPrctlCode::SetGs => {
+            super::clone::check_tls(addr)?;
             unsafe {
                 x86::msr::wrmsr(x86::msr::IA32_KERNEL_GSBASE, addr as _);
             }
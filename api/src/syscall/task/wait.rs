@@ -2,20 +2,24 @@ use alloc::vec::Vec;
 use core::{future::poll_fn, task::Poll};
 
 use axerrno::{AxError, AxResult, LinuxError};
-use axhal::uspace::UserContext;
 use axtask::{
     current,
     future::{block_on, interruptible},
 };
 use bitflags::bitflags;
 use linux_raw_sys::general::{
-    __WALL, __WCLONE, __WNOTHREAD, WCONTINUED, WEXITED, WNOHANG, WNOWAIT, WUNTRACED,
+    __WALL, __WCLONE, __WNOTHREAD, CLD_DUMPED, CLD_EXITED, CLD_KILLED, P_ALL, P_PGID, P_PID,
+    P_PIDFD, WCONTINUED, WEXITED, WNOHANG, WNOWAIT, WUNTRACED, rusage,
 };
-use starry_core::task::AsThread;
+use starry_core::task::{AsThread, Rusage};
 use starry_process::{Pid, Process};
+use starry_signal::Signo;
 use starry_vm::{VmMutPtr, VmPtr};
 
-use crate::signal::check_signals;
+use crate::{
+    file::{FileLike, PidFd},
+    syscall::resources::to_user_rusage,
+};
 
 bitflags! {
     #[derive(Debug)]
@@ -62,29 +66,18 @@ impl WaitPid {
     }
 }
 
-pub fn sys_waitpid(
-    uctx: &mut UserContext,
-    pid: i32,
-    exit_code: *mut i32,
-    options: u32,
+/// Waits for a child selected by `pid` to change state, calling `report` with
+/// the child and its resource usage once it does. Returns the pid of the child,
+/// or 0 if `WNOHANG` is given and no child is ready.
+fn wait_child(
+    pid: WaitPid,
+    options: WaitOptions,
+    report: impl Fn(&Process, Rusage) -> AxResult<()>,
 ) -> AxResult<isize> {
-    let options = WaitOptions::from_bits_truncate(options);
-    info!("sys_waitpid <= pid: {:?}, options: {:?}", pid, options);
-
     let curr = current();
     let proc_data = &curr.as_thread().proc_data;
     let proc = &proc_data.proc;
 
-    let pid = if pid == -1 {
-        WaitPid::Any
-    } else if pid == 0 {
-        WaitPid::Pgid(proc.group().pgid())
-    } else if pid > 0 {
-        WaitPid::Pid(pid as _)
-    } else {
-        WaitPid::Pgid(-pid as _)
-    };
-
     // FIXME: add back support for WALL & WCLONE, since ProcessData may drop before
     // Process now.
     let children = proc
@@ -98,12 +91,11 @@ pub fn sys_waitpid(
 
     let check_children = || {
         if let Some(child) = children.iter().find(|child| child.is_zombie()) {
-            if !options.contains(WaitOptions::WNOWAIT) {
+            let reap = !options.contains(WaitOptions::WNOWAIT);
+            if reap {
                 child.free();
             }
-            if let Some(exit_code) = exit_code.nullable() {
-                exit_code.vm_write(child.exit_code())?;
-            }
+            report(child, proc_data.child_rusage(child.pid(), reap))?;
             Ok(Some(child.pid() as _))
         } else if options.contains(WaitOptions::WNOHANG) {
             Ok(Some(0))
@@ -121,14 +113,111 @@ pub fn sys_waitpid(
             }
         }
     })));
-    match result {
-        Ok(r) => r,
-        Err(_) => {
-            // FIXME: more general syscall RESTART
-            let ip = uctx.ip() - 4;
-            uctx.set_ip(ip);
-            while check_signals(curr.as_thread(), uctx, None) {}
-            Ok(0)
+    // An interruption is restarted or reported by the syscall dispatcher.
+    result?
+}
+
+pub fn sys_waitpid(
+    pid: i32,
+    exit_code: *mut i32,
+    options: u32,
+    usage: *mut rusage,
+) -> AxResult<isize> {
+    let options = WaitOptions::from_bits_truncate(options);
+    info!("sys_waitpid <= pid: {:?}, options: {:?}", pid, options);
+
+    let pid = if pid == -1 {
+        WaitPid::Any
+    } else if pid == 0 {
+        WaitPid::Pgid(current().as_thread().proc_data.proc.group().pgid())
+    } else if pid > 0 {
+        WaitPid::Pid(pid as _)
+    } else {
+        WaitPid::Pgid(-pid as _)
+    };
+
+    wait_child(pid, options, |child, child_usage| {
+        if let Some(exit_code) = exit_code.nullable() {
+            exit_code.vm_write(child.exit_code())?;
+        }
+        if let Some(usage) = usage.nullable() {
+            usage.vm_write(to_user_rusage(child_usage))?;
+        }
+        Ok(())
+    })
+}
+
+/// The part of `siginfo_t` filled in by `waitid`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct WaitIdInfo {
+    signo: i32,
+    errno: i32,
+    code: i32,
+    _pad: i32,
+    pid: i32,
+    uid: u32,
+    status: i32,
+    _pad2: i32,
+    utime: i64,
+    stime: i64,
+    _rest: [u64; 10],
+}
+
+impl WaitIdInfo {
+    /// Decodes a wait status as stored in [`Process::exit_code`].
+    fn new(pid: Pid, wstatus: i32) -> Self {
+        let (code, status) = match wstatus & 0x7f {
+            0 => (CLD_EXITED, (wstatus >> 8) & 0xff),
+            sig if wstatus & 0x80 != 0 => (CLD_DUMPED, sig),
+            sig => (CLD_KILLED, sig),
+        };
+        Self {
+            signo: Signo::SIGCHLD as _,
+            code: code as _,
+            pid: pid as _,
+            status,
+            ..Default::default()
+        }
+    }
+}
+
+pub fn sys_waitid(idtype: u32, id: u32, infop: *mut WaitIdInfo, options: u32) -> AxResult<isize> {
+    let options = WaitOptions::from_bits_truncate(options);
+    info!(
+        "sys_waitid <= idtype: {}, id: {}, options: {:?}",
+        idtype, id, options
+    );
+
+    if !options.intersects(WaitOptions::WEXITED | WaitOptions::WUNTRACED | WaitOptions::WCONTINUED)
+    {
+        return Err(AxError::InvalidInput);
+    }
+
+    let pid = match idtype {
+        P_ALL => WaitPid::Any,
+        P_PID => WaitPid::Pid(id as _),
+        P_PGID if id == 0 => WaitPid::Pgid(current().as_thread().proc_data.proc.group().pgid()),
+        P_PGID => WaitPid::Pgid(id as _),
+        P_PIDFD => WaitPid::Pid(PidFd::from_fd(id as _)?.process().pid()),
+        _ => return Err(AxError::InvalidInput),
+    };
+
+    let pid = wait_child(pid, options, |child, usage| {
+        if let Some(infop) = infop.nullable() {
+            let mut info = WaitIdInfo::new(child.pid(), child.exit_code());
+            // Times are reported in clock ticks of 1/100 s.
+            info.utime = (usage.utime.as_millis() / 10) as _;
+            info.stime = (usage.stime.as_millis() / 10) as _;
+            infop.vm_write(info)?;
         }
+        Ok(())
+    })?;
+    // With `WNOHANG` and no child ready, `si_pid` must read back as zero.
+    if pid == 0
+        && let Some(infop) = infop.nullable()
+    {
+        infop.vm_write(WaitIdInfo::default())?;
     }
+    Ok(0)
 }
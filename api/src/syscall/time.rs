@@ -6,7 +6,10 @@ use linux_raw_sys::general::{
     CLOCK_MONOTONIC_RAW, CLOCK_PROCESS_CPUTIME_ID, CLOCK_REALTIME, CLOCK_REALTIME_COARSE,
     CLOCK_THREAD_CPUTIME_ID, itimerval, timespec, timeval,
 };
-use starry_core::{task::AsThread, time::ITimerType};
+use starry_core::{
+    task::AsThread,
+    time::{ITimerType, ITimers},
+};
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::time::TimeValueLike;
@@ -76,7 +79,7 @@ pub fn sys_times(tms: *mut Tms) -> AxResult<isize> {
 
 pub fn sys_getitimer(which: i32, value: *mut itimerval) -> AxResult<isize> {
     let ty = ITimerType::from_repr(which).ok_or(AxError::InvalidInput)?;
-    let (it_interval, it_value) = current().as_thread().time.borrow().get_itimer(ty);
+    let (it_interval, it_value) = current().as_thread().proc_data.itimers.lock().get(ty);
 
     value.vm_write(itimerval {
         it_interval: timeval::from_time_value(it_interval),
@@ -98,11 +101,11 @@ pub fn sys_setitimer(
             // FIXME: AnyBitPattern
             let new_value = unsafe { new_value.vm_read_uninit()?.assume_init() };
             (
-                new_value.it_interval.try_into_time_value()?.as_nanos() as usize,
-                new_value.it_value.try_into_time_value()?.as_nanos() as usize,
+                new_value.it_interval.try_into_time_value()?,
+                new_value.it_value.try_into_time_value()?,
             )
         }
-        None => (0, 0),
+        None => (TimeValue::ZERO, TimeValue::ZERO),
     };
 
     debug!(
@@ -110,11 +113,7 @@ pub fn sys_setitimer(
         ty, interval, remained
     );
 
-    let old = curr
-        .as_thread()
-        .time
-        .borrow_mut()
-        .set_itimer(ty, interval, remained);
+    let old = ITimers::set(&curr.as_thread().proc_data, ty, interval, remained);
 
     if let Some(old_value) = old_value.nullable() {
         old_value.vm_write(itimerval {
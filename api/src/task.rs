@@ -1,82 +1,151 @@
-use core::{ffi::c_long, sync::atomic::Ordering};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    ffi::c_long,
+    future::poll_fn,
+    sync::atomic::{AtomicU32, Ordering},
+    task::Poll,
+};
 
 use axerrno::{AxError, AxResult};
-use axhal::uspace::{ExceptionKind, ReturnReason, UserContext};
-use axtask::{TaskInner, current};
+use axhal::{
+    paging::MappingFlags,
+    uspace::{ExceptionKind, ReturnReason, UserContext},
+};
+use axmm::AddrSpace;
+use axtask::{TaskInner, current, future::block_on};
 use bytemuck::AnyBitPattern;
-use linux_raw_sys::general::ROBUST_LIST_LIMIT;
+use kspin::SpinNoIrq;
+use linux_raw_sys::general::{
+    BUS_ADRALN, BUS_ADRERR, FUTEX_OWNER_DIED, FUTEX_TID_MASK, FUTEX_WAITERS, ILL_ILLOPC,
+    ROBUST_LIST_LIMIT, SEGV_ACCERR, SEGV_MAPERR, SIG_IGN, TRAP_BRKPT, kernel_sigaction,
+};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr};
 use starry_core::{
     futex::FutexKey,
     mm::access_user_memory,
     shm::SHM_MANAGER,
     task::{
-        AsThread, get_process_data, get_task, send_signal_to_process, send_signal_to_thread,
-        set_timer_state,
+        AsThread, ProcessData, Thread, get_process_data, get_task, send_signal_to_process,
+        send_signal_to_thread, set_timer_state,
     },
     time::TimerState,
+    timer, trace,
 };
-use starry_process::Pid;
+use starry_process::{Pid, Process};
 use starry_signal::{SignalInfo, Signo};
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
-    signal::{check_signals, unblock_next_signal},
+    file::{perf, userfaultfd},
+    signal::{check_signals, flush_signals, unblock_next_signal},
     syscall::handle_syscall,
 };
 
 /// Create a new user task.
-pub fn new_user_task(
-    name: &str,
-    mut uctx: UserContext,
-    set_child_tid: Option<&'static mut Pid>,
-) -> TaskInner {
+pub fn new_user_task(name: &str, mut uctx: UserContext, set_child_tid: Option<usize>) -> TaskInner {
     TaskInner::new(
         move || {
             let curr = axtask::current();
-            access_user_memory(|| {
-                if let Some(tid) = set_child_tid {
-                    *tid = curr.id().as_u64() as Pid;
-                }
-            });
+            if let Some(tid) = set_child_tid {
+                // Like Linux, a bad address is silently ignored.
+                let _ = (tid as *mut Pid).vm_write(curr.id().as_u64() as Pid);
+            }
 
             info!("Enter user space: ip={:#x}, sp={:#x}", uctx.ip(), uctx.sp());
 
             let thr = curr.as_thread();
             while !thr.pending_exit() {
                 let reason = uctx.run();
+                thr.set_user_context(uctx.ip(), uctx.sp());
 
                 set_timer_state(&curr, TimerState::Kernel);
 
+                // Anything but a syscall may have preempted the thread.
+                let preempted = !matches!(reason, ReturnReason::Syscall);
+                let fault_addr = match reason {
+                    ReturnReason::PageFault(addr, _) => Some(addr.as_usize()),
+                    _ => None,
+                };
+
                 match reason {
+                    // The process is being torn down: don't start anything
+                    // new, the pending SIGKILL ends the thread below.
+                    ReturnReason::Syscall if thr.proc_data.group_exiting() => {}
                     ReturnReason::Syscall => handle_syscall(&mut uctx),
-                    ReturnReason::PageFault(addr, flags) => {
-                        if !thr.proc_data.aspace.lock().handle_page_fault(addr, flags) {
+                    ReturnReason::PageFault(addr, flags) => 'fault: {
+                        trace::page_fault_user(addr.as_usize(), uctx.ip(), flags.bits());
+                        if userfaultfd::handle_fault(&thr.proc_data.aspace, addr, flags) {
+                            // User space resolves the fault; retry the access.
+                            break 'fault;
+                        }
+                        // Pages of ranges given a policy with `mbind` come
+                        // from its nodes.
+                        let range_policy = thr.proc_data.range_policy(addr.as_usize());
+                        let mut aspace = thr.proc_data.aspace.lock();
+                        // Only a page newly mapped here adds to the resident
+                        // set, not one copied on write in place of another.
+                        let mapped = |aspace: &AddrSpace| {
+                            aspace
+                                .page_table()
+                                .query(addr)
+                                .map_or(0, |(_, _, size)| (size as usize / PAGE_SIZE_4K) as isize)
+                        };
+                        let resident = mapped(&aspace);
+                        thr.set_fault_policy(range_policy);
+                        let handled = aspace.handle_page_fault(addr, flags);
+                        thr.set_fault_policy(None);
+                        thr.proc_data.add_rss(mapped(&aspace) - resident);
+                        if handled {
+                            thr.record_page_fault(false);
+                        }
+                        // A permitted access that still couldn't be served
+                        // means we ran out of frames: free some memory and
+                        // let the access fault again.
+                        if !handled
+                            && aspace
+                                .find_area(addr)
+                                .is_some_and(|area| area.flags().contains(flags))
+                        {
+                            drop(aspace);
+                            if crate::oom::out_of_memory() {
+                                axtask::yield_now();
+                            } else {
+                                raise_fault_signal(Signo::SIGBUS, BUS_ADRERR, addr.as_usize());
+                            }
+                        } else if !handled {
                             info!(
                                 "{:?}: segmentation fault at {:#x} {:?}",
                                 thr.proc_data.proc, addr, flags
                             );
-                            raise_signal_fatal(SignalInfo::new_kernel(Signo::SIGSEGV))
-                                .expect("Failed to send SIGSEGV");
+                            // A mapped area means the access itself wasn't
+                            // allowed.
+                            let code = if aspace.find_area(addr).is_some() {
+                                SEGV_ACCERR
+                            } else {
+                                SEGV_MAPERR
+                            };
+                            drop(aspace);
+                            raise_fault_signal(Signo::SIGSEGV, code, addr.as_usize());
                         }
                     }
                     ReturnReason::Interrupt => {}
                     #[allow(unused_labels)]
                     ReturnReason::Exception(exc_info) => 'exc: {
-                        // TODO: detailed handling
-                        let signo = match exc_info.kind() {
+                        // The faulting data address isn't reported for
+                        // exceptions, so `si_addr` is the faulting instruction.
+                        let (signo, code) = match exc_info.kind() {
                             ExceptionKind::Misaligned => {
                                 #[cfg(target_arch = "loongarch64")]
                                 if unsafe { uctx.emulate_unaligned() }.is_ok() {
                                     break 'exc;
                                 }
-                                Signo::SIGBUS
+                                (Signo::SIGBUS, BUS_ADRALN)
                             }
-                            ExceptionKind::Breakpoint => Signo::SIGTRAP,
-                            ExceptionKind::IllegalInstruction => Signo::SIGILL,
-                            _ => Signo::SIGTRAP,
+                            ExceptionKind::Breakpoint => (Signo::SIGTRAP, TRAP_BRKPT),
+                            ExceptionKind::IllegalInstruction => (Signo::SIGILL, ILL_ILLOPC),
+                            _ => (Signo::SIGTRAP, TRAP_BRKPT),
                         };
-                        raise_signal_fatal(SignalInfo::new_kernel(signo))
-                            .expect("Failed to send SIGTRAP");
+                        raise_fault_signal(signo, code, uctx.ip());
                     }
                     r => {
                         warn!("Unexpected return reason: {:?}", r);
@@ -85,6 +154,16 @@ pub fn new_user_task(
                     }
                 }
 
+                if preempted {
+                    perf::sample(uctx.ip(), fault_addr);
+                }
+
+                if let Err(err) = rseq_resume(thr, &mut uctx, preempted) {
+                    info!("{:?}: invalid rseq area: {:?}", thr.proc_data.proc, err);
+                    raise_signal_fatal(SignalInfo::new_kernel(Signo::SIGSEGV))
+                        .expect("Failed to send SIGSEGV");
+                }
+
                 if !unblock_next_signal() {
                     while check_signals(thr, &mut uctx, None) {}
                 }
@@ -113,13 +192,74 @@ pub struct RobustListHead {
     pub list_op_pending: *mut RobustList,
 }
 
-fn handle_futex_death(entry: *mut RobustList, offset: i64) -> AxResult<()> {
-    let address = (entry as u64)
+/// Atomically replaces the futex word at `uaddr` with `new` if it still holds
+/// `old`, returning the previous value on failure.
+pub fn futex_cmpxchg(uaddr: *const u32, old: u32, new: u32) -> AxResult<Result<u32, u32>> {
+    if !uaddr.is_aligned() {
+        return Err(AxError::InvalidInput);
+    }
+    let addr = VirtAddr::from_ptr_of(uaddr);
+    let access = MappingFlags::READ | MappingFlags::WRITE;
+    let curr = current();
+    // The word is faulted in and then updated with the address space locked,
+    // so that it can't be unmapped in between.
+    let mut aspace = curr.as_thread().proc_data.aspace.lock();
+    if !aspace.can_access_range(addr, size_of::<u32>(), access) {
+        return Err(AxError::BadAddress);
+    }
+    aspace.populate_area(addr.align_down_4k(), PAGE_SIZE_4K, access)?;
+    Ok(access_user_memory(|| {
+        unsafe { AtomicU32::from_ptr(uaddr as *mut u32) }.compare_exchange(
+            old,
+            new,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+    }))
+}
+
+/// Releases a robust futex held by the exiting thread `tid`.
+///
+/// If the futex word still names `tid` as the owner, it is marked with
+/// `FUTEX_OWNER_DIED` and one waiter is woken so that it can take over with
+/// `EOWNERDEAD`. For the `list_op_pending` entry the thread may also have
+/// died right after acquiring the lock but before storing its TID, in which
+/// case a waiter is woken as well.
+fn handle_futex_death(
+    entry: *mut RobustList,
+    offset: i64,
+    tid: u32,
+    pending: bool,
+) -> AxResult<()> {
+    // Bit 0 of an entry marks a PI futex.
+    let pi = entry.addr() & 1 != 0;
+    let address = ((entry.addr() & !1) as u64)
         .checked_add_signed(offset)
         .ok_or(AxError::InvalidInput)?;
     let address: usize = address.try_into().map_err(|_| AxError::InvalidInput)?;
-    let key = FutexKey::new_current(address);
+    if !address.is_multiple_of(align_of::<u32>()) {
+        return Err(AxError::InvalidInput);
+    }
+    let uaddr = address as *const u32;
 
+    let wake = loop {
+        let word = uaddr.vm_read()?;
+        if pending && !pi && word == 0 {
+            break true;
+        }
+        if word & FUTEX_TID_MASK != tid {
+            return Ok(());
+        }
+        let new = (word & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        if futex_cmpxchg(uaddr, word, new)?.is_ok() {
+            break word & FUTEX_WAITERS != 0;
+        }
+    };
+    if !wake {
+        return Ok(());
+    }
+
+    let key = FutexKey::new_current(address)?;
     let curr = current();
     let futex_table = curr.as_thread().proc_data.futex_table_for(&key);
 
@@ -131,7 +271,7 @@ fn handle_futex_death(entry: *mut RobustList, offset: i64) -> AxResult<()> {
     Ok(())
 }
 
-pub fn exit_robust_list(head: *const RobustListHead) -> AxResult<()> {
+pub fn exit_robust_list(head: *const RobustListHead, tid: u32) -> AxResult<()> {
     // Reference: https://elixir.bootlin.com/linux/v6.13.6/source/kernel/futex/core.c#L777
 
     let mut limit = ROBUST_LIST_LIMIT;
@@ -143,9 +283,10 @@ pub fn exit_robust_list(head: *const RobustListHead) -> AxResult<()> {
     let pending = head.list_op_pending;
 
     while !core::ptr::eq(entry, end_ptr) {
-        let next_entry = entry.vm_read()?.next;
+        let next_entry = (entry.map_addr(|addr| addr & !1)).vm_read()?.next;
+        // The pending entry is handled last, on its own.
         if entry != pending {
-            handle_futex_death(entry, offset)?;
+            handle_futex_death(entry, offset, tid, false)?;
         }
         entry = next_entry;
 
@@ -153,60 +294,282 @@ pub fn exit_robust_list(head: *const RobustListHead) -> AxResult<()> {
         if limit == 0 {
             return Err(AxError::FilesystemLoop);
         }
-        axtask::yield_now();
     }
+    if !pending.is_null() {
+        handle_futex_death(pending, offset, tid, true)?;
+    }
+
+    Ok(())
+}
+
+/// Minimum size of `struct rseq`, which is also its required alignment.
+pub const RSEQ_AREA_SIZE: u32 = 32;
+
+/// Offsets of the fields of `struct rseq` the kernel updates.
+const RSEQ_CPU_ID_START: usize = 0;
+const RSEQ_CPU_ID: usize = 4;
+const RSEQ_CS: usize = 8;
+
+/// `struct rseq_cs`, describing a restartable critical section.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, AnyBitPattern)]
+struct RseqCs {
+    version: u32,
+    flags: u32,
+    start_ip: u64,
+    post_commit_offset: u64,
+    abort_ip: u64,
+}
 
+/// Publishes the current CPU in the rseq area at `area`.
+pub fn rseq_update_cpu(area: usize) -> AxResult<()> {
+    let cpu = axhal::percpu::this_cpu_id() as u32;
+    ((area + RSEQ_CPU_ID_START) as *mut u32).vm_write(cpu)?;
+    ((area + RSEQ_CPU_ID) as *mut u32).vm_write(cpu)?;
     Ok(())
 }
 
+/// Handles the registered rseq area of `thr` before returning to user space.
+///
+/// If the thread was `preempted` inside a critical section, it is redirected
+/// to the section's abort handler. The CPU id fields are refreshed in any
+/// case, since the thread may have migrated.
+pub fn rseq_resume(thr: &Thread, uctx: &mut UserContext, preempted: bool) -> AxResult<()> {
+    let area = thr.rseq_area();
+    if area == 0 {
+        return Ok(());
+    }
+
+    let cs_ptr = (area + RSEQ_CS) as *mut u64;
+    let cs = cs_ptr.vm_read()?;
+    if cs != 0 {
+        let cs = (cs as usize as *const RseqCs).vm_read()?;
+        if cs.version != 0
+            || cs.start_ip.checked_add(cs.post_commit_offset).is_none()
+            || cs.abort_ip.wrapping_sub(cs.start_ip) < cs.post_commit_offset
+        {
+            return Err(AxError::InvalidInput);
+        }
+        let ip = uctx.ip() as u64;
+        if ip.wrapping_sub(cs.start_ip) >= cs.post_commit_offset {
+            // Outside of the critical section, so the descriptor is stale.
+            cs_ptr.vm_write(0)?;
+        } else if preempted {
+            let sig_ptr = cs.abort_ip.checked_sub(4).ok_or(AxError::InvalidInput)?;
+            if (sig_ptr as usize as *const u32).vm_read()? != thr.rseq_sig() {
+                return Err(AxError::InvalidInput);
+            }
+            cs_ptr.vm_write(0)?;
+            uctx.set_ip(cs.abort_ip as usize);
+        }
+    }
+
+    rseq_update_cpu(area)
+}
+
 pub fn do_exit(exit_code: i32, group_exit: bool) {
     let curr = current();
     let thr = curr.as_thread();
+    let process = &thr.proc_data.proc;
+    let tid = curr.id().as_u64() as Pid;
 
     info!("{} exit with code: {}", curr.id_name(), exit_code);
 
+    let initiator = group_exit && thr.proc_data.start_group_exit();
+    if initiator {
+        // Stop the other threads before anything is torn down: they die at
+        // their next syscall or return to user space, and are waited for so
+        // that none of them still uses what the process releases below.
+        let sig = SignalInfo::new_kernel(Signo::SIGKILL);
+        for other in process.threads() {
+            if other != tid {
+                let _ = send_signal_to_thread(None, other, Some(sig.clone()));
+            }
+        }
+        block_on(poll_fn(|cx| {
+            if process.threads().len() <= 1 {
+                return Poll::Ready(());
+            }
+            thr.proc_data.thread_exit_event.register(cx.waker());
+            if process.threads().len() <= 1 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }));
+    }
+
     let clear_child_tid = thr.clear_child_tid() as *mut u32;
     if clear_child_tid.vm_write(0).is_ok() {
-        let key = FutexKey::new_current(clear_child_tid as usize);
-        let table = thr.proc_data.futex_table_for(&key);
-        let guard = table.get(&key);
-        if let Some(futex) = guard {
-            futex.wq.wake(1, u32::MAX);
+        if let Ok(key) = FutexKey::new_current(clear_child_tid as usize) {
+            let table = thr.proc_data.futex_table_for(&key);
+            if let Some(futex) = table.get(&key) {
+                futex.wq.wake(1, u32::MAX);
+            }
         }
         axtask::yield_now();
     }
     let head = thr.robust_list_head() as *const RobustListHead;
     if !head.is_null()
-        && let Err(err) = exit_robust_list(head)
+        && let Err(err) = exit_robust_list(head, curr.id().as_u64() as u32)
     {
         warn!("exit robust list failed: {:?}", err);
     }
 
-    let process = &thr.proc_data.proc;
-    if process.exit_thread(curr.id().as_u64() as Pid, exit_code) {
+    let last_thread = process.exit_thread(tid, exit_code);
+    if !last_thread {
+        // Signals sent to this thread alone die with it.
+        flush_signals(
+            thr,
+            &(thr.signal.pending() & !thr.proc_data.signal.pending()),
+        );
+    }
+    thr.proc_data.thread_exit_event.wake();
+    thr.proc_data.release_vfork();
+    thr.proc_data.account_thread_exit(thr);
+    if initiator {
+        // The whole group exited, with the code of this thread.
+        process.group_exit();
+    }
+    if last_thread {
+        let children = process.children();
         process.exit();
+        orphan_children(&thr.proc_data, children);
         if let Some(parent) = process.parent() {
+            let parent_data = get_process_data(parent.pid());
+            // Hand our final usage to the parent before it can wait for us.
+            if let Ok(data) = &parent_data {
+                let usage = thr.proc_data.rusage();
+                data.add_zombie_rusage(
+                    process.pid(),
+                    usage.collate(thr.proc_data.children_rusage()),
+                );
+            }
             if let Some(signo) = thr.proc_data.exit_signal {
                 let _ = send_signal_to_process(parent.pid(), Some(SignalInfo::new_kernel(signo)));
             }
-            if let Ok(data) = get_process_data(parent.pid()) {
+            if let Ok(data) = parent_data {
                 data.child_exit_event.wake();
             }
         }
         thr.proc_data.exit_event.wake();
+        if !ORPHANAGES.lock().is_empty() {
+            timer::defer(reap_orphans);
+        }
 
         SHM_MANAGER.lock().clear_proc_shm(process.pid());
-    }
-    if group_exit && !process.is_group_exited() {
-        process.group_exit();
-        let sig = SignalInfo::new_kernel(Signo::SIGKILL);
-        for tid in process.threads() {
-            let _ = send_signal_to_thread(None, tid, Some(sig.clone()));
+        // Release the memory now instead of when the zombie is reaped, so
+        // that killing a process actually relieves memory pressure.
+        if Arc::strong_count(&thr.proc_data.aspace) == 1 {
+            thr.proc_data.aspace.lock().clear();
+            thr.proc_data.reset_rss();
         }
     }
     thr.set_exit();
 }
 
+/// Tells the former children of an exiting process that it is gone, once
+/// they have been handed to the nearest child subreaper or init.
+///
+/// The new reaper is woken for the children that are zombies already, and
+/// the others get their parent-death signal.
+fn orphan_children(proc_data: &ProcessData, children: Vec<Arc<Process>>) {
+    for child in children {
+        if child.is_zombie() {
+            let usage = proc_data.child_rusage(child.pid(), false);
+            if let Some(reaper) = child.parent()
+                && let Ok(data) = get_process_data(reaper.pid())
+            {
+                data.add_zombie_rusage(child.pid(), usage);
+                data.child_exit_event.wake();
+            }
+        } else if let Ok(data) = get_process_data(child.pid())
+            && let Some(signo) = data.pdeath_signal()
+        {
+            let _ = send_signal_to_process(child.pid(), Some(SignalInfo::new_kernel(signo)));
+        }
+    }
+}
+
+/// Init processes that have exited, whose children, orphaned processes
+/// included, the kernel reaps in their place.
+static ORPHANAGES: SpinNoIrq<Vec<Arc<Process>>> = SpinNoIrq::new(Vec::new());
+
+/// Makes the kernel the reaper of the children of `init`, which has exited.
+///
+/// Orphans are handed to the init process, so once it is gone nobody waits
+/// for them: they are reaped as soon as they exit instead, so that they do
+/// not linger as zombies through a shutdown or soft reboot. An init process
+/// that left no children behind is not kept at all, and one is dropped once
+/// its last child is reaped.
+pub fn adopt_orphans(init: Arc<Process>) {
+    if init.children().is_empty() {
+        return;
+    }
+    {
+        let mut orphanages = ORPHANAGES.lock();
+        if orphanages.iter().any(|it| Arc::ptr_eq(it, &init)) {
+            return;
+        }
+        orphanages.push(init);
+    }
+    reap_orphans();
+}
+
+fn reap_orphans() {
+    let orphanages = ORPHANAGES.lock().clone();
+    for init in &orphanages {
+        for child in init.children() {
+            if child.is_zombie() {
+                debug!("Reaping orphan {}", child.pid());
+                child.free();
+                if let Ok(data) = get_process_data(init.pid()) {
+                    data.child_rusage(child.pid(), true);
+                }
+            }
+        }
+    }
+    ORPHANAGES.lock().retain(|init| !init.children().is_empty());
+}
+
+/// Sends the signal for a synchronous fault at `addr` to the current thread,
+/// filling in `si_code` and `si_addr` for `SA_SIGINFO` handlers.
+///
+/// Like Linux's `force_sig_fault`, the signal can be neither blocked nor
+/// ignored, since returning to user space would just fault again: it is
+/// unblocked and its action reset to the default if needed.
+pub fn raise_fault_signal(signo: Signo, code: u32, addr: usize) {
+    let curr = current();
+    let thr = curr.as_thread();
+
+    let mut sig = SignalInfo::new_kernel(signo);
+    // SAFETY: every member of the siginfo unions is plain old data.
+    unsafe {
+        let info = &mut sig.0.__bindgen_anon_1.__bindgen_anon_1;
+        info.si_code = code as _;
+        info._sifields._sigfault._addr = addr as _;
+    }
+
+    let mut actions = thr.proc_data.signal.actions.lock();
+    let action: kernel_sigaction = actions[signo].clone().into();
+    let mut blocked = thr.signal.blocked();
+    if blocked.has(signo) || action.sa_handler_kernel.map_or(0, |f| f as usize) == SIG_IGN as usize
+    {
+        actions[signo] = Default::default();
+        blocked.remove(signo);
+        thr.signal.set_blocked(blocked);
+    }
+    drop(actions);
+
+    info!(
+        "Send fault signal {:?} at {:#x} to the current thread",
+        signo, addr
+    );
+    if send_signal_to_thread(None, curr.id().as_u64() as Pid, Some(sig)).is_err() {
+        do_exit(signo as i32, true);
+    }
+}
+
 /// Sends a fatal signal to the current process.
 pub fn raise_signal_fatal(sig: SignalInfo) -> AxResult<()> {
     let curr = current();
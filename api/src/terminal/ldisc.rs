@@ -10,7 +10,8 @@ use axerrno::{AxError, AxResult};
 use axpoll::{IoEvents, PollSet, Pollable};
 use axtask::future::{Poller, block_on};
 use linux_raw_sys::general::{
-    ECHOCTL, ECHOK, ICRNL, IGNCR, ISIG, VEOF, VERASE, VKILL, VMIN, VTIME,
+    ECHOCTL, ECHOK, ICRNL, IGNCR, ISIG, IXANY, IXOFF, IXON, VEOF, VERASE, VKILL, VMIN, VSTART,
+    VSTOP, VTIME,
 };
 use ringbuf::{
     CachingCons, CachingProd,
@@ -55,6 +56,10 @@ pub struct TtyConfig<R, W> {
 
 pub trait TtyRead: Send + Sync + 'static {
     fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Sets the characters sent to pause and resume the sender when input
+    /// backs up, or `None` if `IXOFF` is off.
+    fn set_input_flow(&mut self, _chars: Option<(u8, u8)>) {}
 }
 pub trait TtyWrite: Send + Sync + 'static {
     fn write(&self, buf: &[u8]);
@@ -79,11 +84,19 @@ impl<R: TtyRead, W: TtyWrite> InputReader<R, W> {
         if self.clear_line_buf.swap(false, Ordering::Relaxed) {
             self.line_buf.clear();
         }
+        let term = self.terminal.load_termios();
+        self.reader.set_input_flow(
+            term.has_iflag(IXOFF)
+                .then(|| (term.special_char(VSTOP), term.special_char(VSTART))),
+        );
         if self.read_range.is_empty() {
             let read = self.reader.read(&mut self.read_buf);
+            self.terminal
+                .counters
+                .rx
+                .fetch_add(read as u32, Ordering::Relaxed);
             self.read_range = 0..read;
         }
-        let term = self.terminal.load_termios();
         let mut sent = 0;
         loop {
             if let Some(offset) = &mut self.line_read {
@@ -113,6 +126,10 @@ impl<R: TtyRead, W: TtyWrite> InputReader<R, W> {
                 }
             }
 
+            if term.has_iflag(IXON) && self.check_output_flow(&term, ch) {
+                continue;
+            }
+
             self.check_send_signal(&term, ch);
 
             if term.echo() {
@@ -153,6 +170,24 @@ impl<R: TtyRead, W: TtyWrite> InputReader<R, W> {
         sent > 0
     }
 
+    /// Handles `VSTOP` and `VSTART`, returning whether `ch` was one of them.
+    fn check_output_flow(&self, term: &Termios2, ch: u8) -> bool {
+        // A special character of 0 is disabled.
+        let is = |index| ch != 0 && ch == term.special_char(index);
+        if is(VSTOP) {
+            self.terminal.set_output_stopped(true);
+            return true;
+        }
+        if is(VSTART) {
+            self.terminal.set_output_stopped(false);
+            return true;
+        }
+        if term.has_iflag(IXANY) {
+            self.terminal.set_output_stopped(false);
+        }
+        false
+    }
+
     fn check_send_signal(&self, term: &Termios2, ch: u8) {
         if !term.canonical() || !term.has_lflag(ISIG) {
             return;
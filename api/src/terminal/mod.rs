@@ -1,8 +1,9 @@
 //! Terminal module.
 
 use alloc::sync::Arc;
-use core::sync::atomic::AtomicU32;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
+use axpoll::PollSet;
 use bytemuck::AnyBitPattern;
 use kspin::SpinNoPreempt;
 
@@ -19,11 +20,24 @@ pub struct WindowSize {
     pub ws_ypixel: u16,
 }
 
+/// Input and output counts, as reported by `TIOCGICOUNT`.
+#[derive(Default)]
+pub struct IoCounters {
+    pub rx: AtomicU32,
+    pub tx: AtomicU32,
+    /// Characters lost because the input buffer was full.
+    pub buf_overrun: AtomicU32,
+}
+
 pub struct Terminal {
     pub job_control: job::JobControl,
     pub window_size: SpinNoPreempt<WindowSize>,
     pub termios: SpinNoPreempt<Arc<termios::Termios2>>,
     pub pty_number: AtomicU32,
+    pub counters: IoCounters,
+    /// Whether output was stopped with `VSTOP`.
+    output_stopped: AtomicBool,
+    poll_output: PollSet,
 }
 impl Default for Terminal {
     fn default() -> Self {
@@ -37,6 +51,9 @@ impl Default for Terminal {
             }),
             termios: SpinNoPreempt::new(Arc::new(termios::Termios2::default())),
             pty_number: AtomicU32::new(0),
+            counters: IoCounters::default(),
+            output_stopped: AtomicBool::new(false),
+            poll_output: PollSet::new(),
         }
     }
 }
@@ -44,4 +61,21 @@ impl Terminal {
     pub fn load_termios(&self) -> Arc<termios::Termios2> {
         self.termios.lock().clone()
     }
+
+    pub fn output_stopped(&self) -> bool {
+        self.output_stopped.load(Ordering::Acquire)
+    }
+
+    /// Stops or restarts output, for software flow control (`IXON`).
+    pub fn set_output_stopped(&self, stopped: bool) {
+        let was_stopped = self.output_stopped.swap(stopped, Ordering::AcqRel);
+        if was_stopped && !stopped {
+            self.poll_output.wake();
+        }
+    }
+
+    /// Returns the writers waiting for output to be restarted.
+    pub fn poll_output(&self) -> &PollSet {
+        &self.poll_output
+    }
 }
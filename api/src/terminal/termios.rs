@@ -4,7 +4,7 @@ use bytemuck::AnyBitPattern;
 use linux_raw_sys::general::{
     B38400, CREAD, CS8, ECHO, ECHOCTL, ECHOE, ECHOK, ECHOKE, ICANON, ICRNL, IEXTEN, ISIG, IXON,
     ONLCR, OPOST, VDISCARD, VEOF, VEOL, VEOL2, VERASE, VINTR, VKILL, VLNEXT, VQUIT, VREPRINT,
-    VWERASE, speed_t, tcflag_t,
+    VSTART, VSTOP, VWERASE, speed_t, tcflag_t,
 };
 use starry_signal::Signo;
 
@@ -45,6 +45,8 @@ impl Default for Termios {
             (VWERASE, ctl(b'W')),
             (VLNEXT, ctl(b'V')),
             (VEOL2, b'\0'),
+            (VSTART, ctl(b'Q')),
+            (VSTOP, ctl(b'S')),
         ] {
             result.c_cc[i as usize] = ch;
         }
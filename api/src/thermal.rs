@@ -0,0 +1,368 @@
+//! Thermal management.
+//!
+//! Platform drivers register their temperature sensors as thermal zones,
+//! each with trip points, and the devices that can be slowed down to shed
+//! heat as cooling devices bound to those trip points. The zones are polled
+//! by a kernel task running the step-wise governor: while a zone is above a
+//! trip point and not cooling down, each bound device is throttled one more
+//! step, and once it is below the trip point by the hysteresis, released one
+//! step. Reaching a critical trip point shuts the system down.
+//!
+//! [`init`] registers a cooling device for each cpufreq policy, and the
+//! digital thermal sensor of Intel CPUs as a zone throttling them.
+//!
+//! Everything is exposed under `/sys/class/thermal` and `/sys/class/hwmon`.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use spin::{Once, RwLock};
+
+use crate::{
+    cpufreq,
+    power::{self, PowerAction},
+};
+
+#[cfg(target_arch = "x86_64")]
+mod coretemp;
+mod cpufreq_cooling;
+
+/// How often thermal zones are polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A temperature sensor.
+pub trait ThermalSensor: Send + Sync {
+    /// Returns the type of the zone, e.g. `soc-thermal`.
+    fn name(&self) -> &str;
+
+    /// Reads the temperature, in millidegrees Celsius.
+    fn temperature(&self) -> AxResult<i32>;
+}
+
+/// A device that can be throttled to reduce heat, such as a CPU or NPU
+/// limited to lower frequencies.
+pub trait CoolingDevice: Send + Sync {
+    /// Returns the type of the device, e.g. `cpufreq-cpu0`.
+    fn name(&self) -> &str;
+
+    /// Returns the highest cooling state, the most throttled one.
+    fn max_state(&self) -> u32;
+
+    /// Returns the current cooling state, 0 being not throttled.
+    fn cur_state(&self) -> u32;
+
+    /// Sets the cooling state.
+    fn set_state(&self, state: u32) -> AxResult<()>;
+}
+
+/// What happens when a trip point is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TripType {
+    /// Bound cooling devices, such as fans, are turned up.
+    Active,
+    /// Bound devices are throttled.
+    Passive,
+    /// A warning is logged.
+    Hot,
+    /// The system is shut down.
+    Critical,
+}
+
+impl TripType {
+    /// Returns the name used in `trip_point_*_type`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TripType::Active => "active",
+            TripType::Passive => "passive",
+            TripType::Hot => "hot",
+            TripType::Critical => "critical",
+        }
+    }
+}
+
+/// A temperature, in millidegrees Celsius, at which a zone needs cooling.
+pub struct TripPoint {
+    ty: TripType,
+    temp: AtomicI32,
+    hyst: AtomicI32,
+}
+
+impl TripPoint {
+    /// Creates a trip point at `temp`, cleared once the zone cools down to
+    /// `temp - hyst`.
+    pub fn new(ty: TripType, temp: i32, hyst: i32) -> Self {
+        Self {
+            ty,
+            temp: AtomicI32::new(temp),
+            hyst: AtomicI32::new(hyst),
+        }
+    }
+
+    /// Returns the trip type.
+    pub fn ty(&self) -> TripType {
+        self.ty
+    }
+
+    /// Returns the trip temperature.
+    pub fn temp(&self) -> i32 {
+        self.temp.load(Ordering::Relaxed)
+    }
+
+    /// Sets the trip temperature.
+    pub fn set_temp(&self, temp: i32) {
+        self.temp.store(temp, Ordering::Relaxed);
+    }
+
+    /// Returns the hysteresis.
+    pub fn hyst(&self) -> i32 {
+        self.hyst.load(Ordering::Relaxed)
+    }
+
+    /// Sets the hysteresis.
+    pub fn set_hyst(&self, hyst: i32) -> AxResult<()> {
+        if hyst < 0 {
+            return Err(AxError::InvalidInput);
+        }
+        self.hyst.store(hyst, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// A cooling device bound to a trip point of a zone.
+#[derive(Debug, Clone, Copy)]
+pub struct CoolingBinding {
+    /// The index of the trip point.
+    pub trip: usize,
+    /// The ID of the cooling device.
+    pub cdev: usize,
+}
+
+/// A thermal zone, one sensor with its trip points.
+pub struct ThermalZone {
+    sensor: Arc<dyn ThermalSensor>,
+    trips: Vec<TripPoint>,
+    bindings: RwLock<Vec<CoolingBinding>>,
+    enabled: AtomicBool,
+    user_space: AtomicBool,
+    last_temp: AtomicI32,
+}
+
+/// The governors a zone can use.
+pub const POLICIES: &[&str] = &["step_wise", "user_space"];
+
+impl ThermalZone {
+    /// Returns the sensor of the zone.
+    pub fn sensor(&self) -> &Arc<dyn ThermalSensor> {
+        &self.sensor
+    }
+
+    /// Returns the trip points of the zone.
+    pub fn trips(&self) -> &[TripPoint] {
+        &self.trips
+    }
+
+    /// Returns the cooling devices bound to the zone.
+    pub fn bindings(&self) -> Vec<CoolingBinding> {
+        self.bindings.read().clone()
+    }
+
+    /// Returns whether the zone is polled.
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables polling of the zone.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns the governor of the zone.
+    pub fn policy(&self) -> &'static str {
+        POLICIES[self.user_space.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Sets the governor of the zone, one of [`POLICIES`].
+    ///
+    /// With `user_space`, cooling devices are left to userspace.
+    pub fn set_policy(&self, policy: &str) -> AxResult<()> {
+        let index = POLICIES
+            .iter()
+            .position(|it| *it == policy)
+            .ok_or(AxError::InvalidInput)?;
+        self.user_space.store(index == 1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+static ZONES: RwLock<Vec<Arc<ThermalZone>>> = RwLock::new(Vec::new());
+static COOLING_DEVICES: RwLock<Vec<Arc<dyn CoolingDevice>>> = RwLock::new(Vec::new());
+static POLLER: Once = Once::new();
+
+/// Returns all thermal zones, indexed by ID.
+pub fn zones() -> Vec<Arc<ThermalZone>> {
+    ZONES.read().clone()
+}
+
+/// Returns all cooling devices, indexed by ID.
+pub fn cooling_devices() -> Vec<Arc<dyn CoolingDevice>> {
+    COOLING_DEVICES.read().clone()
+}
+
+/// Registers a thermal zone, returning its ID.
+pub fn register_zone(sensor: Arc<dyn ThermalSensor>, trips: Vec<TripPoint>) -> usize {
+    let zone = Arc::new(ThermalZone {
+        sensor,
+        trips,
+        bindings: RwLock::new(Vec::new()),
+        enabled: AtomicBool::new(true),
+        user_space: AtomicBool::new(false),
+        last_temp: AtomicI32::new(0),
+    });
+    let mut zones = ZONES.write();
+    info!(
+        "thermal: registered zone {} ({})",
+        zones.len(),
+        zone.sensor.name()
+    );
+    zones.push(zone);
+    let id = zones.len() - 1;
+    drop(zones);
+
+    POLLER.call_once(|| {
+        axtask::spawn(
+            || loop {
+                poll();
+                axtask::sleep(POLL_INTERVAL);
+            },
+            "thermal".into(),
+        );
+    });
+    id
+}
+
+/// Registers a cooling device, returning its ID.
+pub fn register_cooling_device(device: Arc<dyn CoolingDevice>) -> usize {
+    let mut devices = COOLING_DEVICES.write();
+    info!(
+        "thermal: registered cooling device {} ({})",
+        devices.len(),
+        device.name()
+    );
+    devices.push(device);
+    devices.len() - 1
+}
+
+/// Binds cooling device `cdev` to trip point `trip` of zone `zone`.
+pub fn bind(zone: usize, trip: usize, cdev: usize) -> AxResult<()> {
+    let zone = ZONES.read().get(zone).cloned().ok_or(AxError::NotFound)?;
+    if trip >= zone.trips.len() || cdev >= COOLING_DEVICES.read().len() {
+        return Err(AxError::NotFound);
+    }
+    zone.bindings.write().push(CoolingBinding { trip, cdev });
+    Ok(())
+}
+
+/// Registers the cooling devices and temperature sensors of the platform,
+/// binding the devices to the passive trip points. The cpufreq drivers must
+/// have been registered first.
+pub fn init() {
+    let cdevs: Vec<_> = cpufreq::policies()
+        .into_iter()
+        .map(|policy| {
+            register_cooling_device(Arc::new(cpufreq_cooling::CpufreqCooling::new(policy)))
+        })
+        .collect();
+
+    #[cfg(target_arch = "x86_64")]
+    let sensors = coretemp::probe();
+    #[cfg(not(target_arch = "x86_64"))]
+    let sensors: Option<(Arc<dyn ThermalSensor>, Vec<TripPoint>)> = None;
+
+    if let Some((sensor, trips)) = sensors {
+        let passive: Vec<_> = trips
+            .iter()
+            .enumerate()
+            .filter(|(_, trip)| trip.ty == TripType::Passive)
+            .map(|(index, _)| index)
+            .collect();
+        let zone = register_zone(sensor, trips);
+        for &trip in &passive {
+            for &cdev in &cdevs {
+                bind(zone, trip, cdev).expect("trip point and cooling device exist");
+            }
+        }
+    }
+}
+
+/// Reads every zone and runs the step-wise governor.
+fn poll() {
+    let devices = cooling_devices();
+    // A device bound to several zones or trip points gets the highest state
+    // any of them asks for.
+    let mut targets: Vec<Option<u32>> = alloc::vec![None; devices.len()];
+
+    for (id, zone) in zones().iter().enumerate() {
+        if !zone.enabled() {
+            continue;
+        }
+        let temp = match zone.sensor.temperature() {
+            Ok(temp) => temp,
+            Err(err) => {
+                warn!("thermal: failed to read zone {id}: {err:?}");
+                continue;
+            }
+        };
+        let last = zone.last_temp.swap(temp, Ordering::Relaxed);
+
+        for trip in &zone.trips {
+            match trip.ty {
+                TripType::Critical if temp >= trip.temp() => {
+                    error!("thermal: zone {id} reached critical temperature {temp}, shutting down");
+                    power::shutdown(PowerAction::PowerOff);
+                }
+                TripType::Hot if temp >= trip.temp() => {
+                    warn!("thermal: zone {id} is hot ({temp})");
+                }
+                _ => {}
+            }
+        }
+
+        if zone.user_space.load(Ordering::Relaxed) {
+            continue;
+        }
+        for binding in zone.bindings.read().iter() {
+            let trip = &zone.trips[binding.trip];
+            let device = &devices[binding.cdev];
+            let cur = device.cur_state();
+            let target = if temp >= trip.temp() {
+                if temp >= last {
+                    (cur + 1).min(device.max_state())
+                } else {
+                    cur
+                }
+            } else if temp < trip.temp() - trip.hyst() {
+                cur.saturating_sub(1)
+            } else {
+                cur
+            };
+            let slot = &mut targets[binding.cdev];
+            *slot = Some(slot.map_or(target, |it| it.max(target)));
+        }
+    }
+
+    for (device, target) in devices.iter().zip(targets) {
+        if let Some(target) = target
+            && target != device.cur_state()
+            && let Err(err) = device.set_state(target)
+        {
+            warn!(
+                "thermal: failed to set {} to {target}: {err:?}",
+                device.name()
+            );
+        }
+    }
+}
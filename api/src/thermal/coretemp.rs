@@ -0,0 +1,77 @@
+//! The digital thermal sensor of Intel CPUs, which reads how many degrees
+//! the core is below TjMax, the temperature at which it starts throttling
+//! itself.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::arch::x86_64::__cpuid;
+
+use axerrno::{AxError, AxResult};
+use x86::msr::{IA32_THERM_STATUS, MSR_TEMPERATURE_TARGET, rdmsr};
+
+use super::{ThermalSensor, TripPoint, TripType};
+
+/// TjMax of the CPUs that don't report it, as Linux assumes.
+const DEFAULT_TJMAX: i32 = 100_000;
+/// How far below TjMax the CPUs are slowed down, before they do it
+/// themselves.
+const PASSIVE_MARGIN: i32 = 15_000;
+const PASSIVE_HYST: i32 = 5_000;
+
+/// Whether the reading in `IA32_THERM_STATUS` is valid.
+const THERM_STATUS_VALID: u64 = 1 << 31;
+
+struct CoreTemp {
+    tjmax: i32,
+}
+
+impl ThermalSensor for CoreTemp {
+    fn name(&self) -> &str {
+        "x86_core_temp"
+    }
+
+    fn temperature(&self) -> AxResult<i32> {
+        // SAFETY: the MSR exists on CPUs with a digital thermal sensor.
+        let status = unsafe { rdmsr(IA32_THERM_STATUS) };
+        if status & THERM_STATUS_VALID == 0 {
+            return Err(AxError::WouldBlock);
+        }
+        let below = ((status >> 16) & 0x7f) as i32;
+        Ok(self.tjmax - below * 1000)
+    }
+}
+
+/// Reads TjMax from `MSR_TEMPERATURE_TARGET`, which CPUs since Nehalem
+/// have.
+fn tjmax() -> i32 {
+    let signature = unsafe { __cpuid(1) }.eax;
+    let family = (signature >> 8) & 0xf;
+    let model = (signature >> 12) & 0xf0 | (signature >> 4) & 0xf;
+    if family != 6 || model < 0x1a {
+        return DEFAULT_TJMAX;
+    }
+    // SAFETY: the MSR exists on these CPUs.
+    let target = (unsafe { rdmsr(MSR_TEMPERATURE_TARGET) } >> 16) & 0xff;
+    if target == 0 {
+        DEFAULT_TJMAX
+    } else {
+        target as i32 * 1000
+    }
+}
+
+/// Finds the sensor of the boot CPU, with a passive trip point a margin
+/// below TjMax and a hot one at it. Reaching TjMax isn't critical, as the
+/// CPU protects itself from there.
+pub fn probe() -> Option<(Arc<dyn ThermalSensor>, Vec<TripPoint>)> {
+    let vendor = unsafe { __cpuid(0) };
+    // "GenuineIntel"; other vendors report temperatures elsewhere.
+    let intel = [vendor.ebx, vendor.edx, vendor.ecx] == [0x756e_6547, 0x4965_6e69, 0x6c65_746e];
+    if !intel || vendor.eax < 6 || unsafe { __cpuid(6) }.eax & 1 == 0 {
+        return None;
+    }
+    let tjmax = tjmax();
+    let trips = vec![
+        TripPoint::new(TripType::Passive, tjmax - PASSIVE_MARGIN, PASSIVE_HYST),
+        TripPoint::new(TripType::Hot, tjmax, 0),
+    ];
+    Some((Arc::new(CoreTemp { tjmax }), trips))
+}
@@ -0,0 +1,52 @@
+//! Cooling by capping the frequency of a cpufreq policy, each state one
+//! supported frequency below the last.
+
+use alloc::{format, string::String, sync::Arc};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axerrno::{AxError, AxResult};
+
+use super::CoolingDevice;
+use crate::cpufreq::Policy;
+
+pub struct CpufreqCooling {
+    name: String,
+    policy: Arc<Policy>,
+    state: AtomicU32,
+}
+
+impl CpufreqCooling {
+    pub fn new(policy: Arc<Policy>) -> Self {
+        Self {
+            name: format!("cpufreq-cpu{}", policy.cpus().start),
+            policy,
+            state: AtomicU32::new(0),
+        }
+    }
+}
+
+impl CoolingDevice for CpufreqCooling {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn max_state(&self) -> u32 {
+        self.policy.driver().frequencies().len().saturating_sub(1) as u32
+    }
+
+    fn cur_state(&self) -> u32 {
+        self.state.load(Ordering::Relaxed)
+    }
+
+    fn set_state(&self, state: u32) -> AxResult<()> {
+        let frequencies = self.policy.driver().frequencies();
+        let khz = (frequencies.len() as u32)
+            .checked_sub(state + 1)
+            .map(|index| frequencies[index as usize])
+            .ok_or(AxError::InvalidInput)?;
+        // The minimum set through sysfs wins over the cap.
+        self.policy.set_max(khz.max(self.policy.min()))?;
+        self.state.store(state, Ordering::Relaxed);
+        Ok(())
+    }
+}
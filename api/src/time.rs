@@ -140,3 +140,11 @@ pub(crate) fn inc_irq_cnt() {
 pub(crate) fn irq_cnt() -> usize {
     IRQ_CNT.load(Ordering::Relaxed)
 }
+
+/// Busy-waits for `us` microseconds, for drivers timing signals by hand.
+pub fn udelay(us: u64) {
+    let end = axhal::time::monotonic_time() + core::time::Duration::from_micros(us);
+    while axhal::time::monotonic_time() < end {
+        core::hint::spin_loop();
+    }
+}
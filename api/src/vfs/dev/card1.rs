@@ -1,12 +1,11 @@
-use core::{any::Any, convert::TryFrom, mem};
+use core::{any::Any, convert::TryFrom, mem::MaybeUninit, slice};
 
 use axfs_ng_vfs::{DeviceId, NodeFlags, VfsError, VfsResult};
-use axhal::asm::user_copy;
 use rknpu::{
     RknpuAction,
     ioctrl::{RknpuMemCreate, RknpuSubmit},
 };
-use starry_vm::VmMutPtr;
+use starry_core::mm;
 
 use crate::vfs::DeviceOps;
 
@@ -54,9 +53,6 @@ impl DeviceOps for Card1 {
             warn!("[rknpu]: ioctl received null arg pointer");
             return Err(VfsError::InvalidData);
         }
-        let flag = arg as *mut RknpuUserAction;
-        let flag_val = unsafe { &*flag }.flag();
-        info!("flag_val is {:?}", flag_val);
 
         npu_power_on().expect("Failed to power on NPU.");
 
@@ -69,11 +65,9 @@ impl DeviceOps for Card1 {
                         _value: 0,
                     };
 
-                    copy_from_user(
-                        &mut action_args as *mut _ as *mut u8,
-                        flag as *const u8,
-                        mem::size_of::<RknpuUserAction>(),
-                    )?;
+                    copy_from_user(&mut action_args, arg)?;
+                    let flag_val = action_args.flag();
+                    info!("flag_val is {:?}", flag_val);
 
                     if let Err(e) = with_npu(|rknpu_dev| {
                         rknpu_dev
@@ -83,21 +77,13 @@ impl DeviceOps for Card1 {
                         warn!("rknpu action ioctl failed: {:?}", e);
                     }
 
-                    copy_to_user(
-                        flag as *mut u8,
-                        &action_args as *const _ as *const u8,
-                        mem::size_of::<RknpuUserAction>(),
-                    )?;
+                    copy_to_user(arg, &action_args)?;
                 }
                 RknpuCmd::Submit => {
                     info!("rknpu submit ioctl");
                     let mut submit_args = RknpuSubmit::default();
 
-                    copy_from_user(
-                        &mut submit_args as *mut _ as *mut u8,
-                        arg as *const u8,
-                        mem::size_of::<RknpuSubmit>(),
-                    )?;
+                    copy_from_user(&mut submit_args, arg)?;
 
                     if let Err(e) = with_npu(|rknpu_dev| {
                         rknpu_dev
@@ -107,21 +93,13 @@ impl DeviceOps for Card1 {
                         warn!("rknpu submit ioctl failed: {:?}", e);
                     }
 
-                    copy_to_user(
-                        arg as *mut u8,
-                        &submit_args as *const _ as *const u8,
-                        mem::size_of::<RknpuSubmit>(),
-                    )?;
+                    copy_to_user(arg, &submit_args)?;
                 }
                 RknpuCmd::MemCreate => {
                     info!("rknpu mem_create ioctl");
                     let mut mem_create_args = RknpuMemCreate::default();
 
-                    copy_from_user(
-                        &mut mem_create_args as *mut _ as *mut u8,
-                        arg as *const u8,
-                        mem::size_of::<RknpuMemCreate>(),
-                    )?;
+                    copy_from_user(&mut mem_create_args, arg)?;
 
                     if let Err(e) = with_npu(|rknpu_dev| {
                         rknpu_dev
@@ -131,11 +109,7 @@ impl DeviceOps for Card1 {
                         warn!("rknpu mem_create ioctl failed: {:?}", e);
                     }
 
-                    copy_to_user(
-                        arg as *mut u8,
-                        &mem_create_args as *const _ as *const u8,
-                        mem::size_of::<RknpuMemCreate>(),
-                    )?;
+                    copy_to_user(arg, &mem_create_args)?;
                 }
                 _ => {
                     warn!("not implemented yet");
@@ -187,24 +161,32 @@ fn npu_power_off() -> Result<(), VfsError> {
     Ok(())
 }
 
-fn copy_from_user(dst: *mut u8, src: *const u8, size: usize) -> Result<(), axio::Error> {
-    let ret = unsafe { user_copy(dst, src, size) };
-
-    if ret != 0 {
-        warn!("[rknpu]: copy_to_user failed, ret={}", ret);
-        return Err(VfsError::InvalidData);
-    }
-    Ok(())
+fn copy_from_user<T>(dst: &mut T, src: usize) -> VfsResult<()> {
+    // SAFETY: `dst` is valid for `size_of::<T>()` bytes.
+    let buf = unsafe {
+        slice::from_raw_parts_mut((dst as *mut T).cast::<MaybeUninit<u8>>(), size_of::<T>())
+    };
+    mm::copy_from_user(buf, src).map_err(|fault| {
+        warn!(
+            "[card1]: copy_from_user faulted after {} of {} bytes",
+            fault.copied,
+            size_of::<T>()
+        );
+        fault.into()
+    })
 }
 
-fn copy_to_user(dst: *mut u8, src: *const u8, size: usize) -> Result<(), axio::Error> {
-    let ret = unsafe { user_copy(dst, src, size) };
-
-    if ret != 0 {
-        warn!("[rknpu]: copy_to_user failed, ret={}", ret);
-        return Err(VfsError::InvalidData);
-    }
-    Ok(())
+fn copy_to_user<T>(dst: usize, src: &T) -> VfsResult<()> {
+    // SAFETY: `src` is valid for `size_of::<T>()` bytes.
+    let buf = unsafe { slice::from_raw_parts((src as *const T).cast::<u8>(), size_of::<T>()) };
+    mm::copy_to_user(dst, buf).map_err(|fault| {
+        warn!(
+            "[card1]: copy_to_user faulted after {} of {} bytes",
+            fault.copied,
+            size_of::<T>()
+        );
+        fault.into()
+    })
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -0,0 +1,51 @@
+use alloc::{format, string::String};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng::FileBackend;
+use axfs_ng_vfs::VfsResult;
+
+use super::{SECTOR_SIZE, Target, open_device};
+
+/// The `linear` target, which maps onto a range of another device.
+///
+/// Parameters: `<device> <start sector>`.
+pub struct Linear {
+    path: String,
+    dev: FileBackend,
+    start: u64,
+}
+
+impl Linear {
+    pub fn new(params: &str) -> AxResult<Self> {
+        let mut args = params.split_whitespace();
+        let (Some(path), Some(start), None) = (args.next(), args.next(), args.next()) else {
+            return Err(AxError::InvalidInput);
+        };
+        let start = start.parse().map_err(|_| AxError::InvalidInput)?;
+        Ok(Self {
+            path: path.into(),
+            dev: open_device(path, true)?,
+            start,
+        })
+    }
+}
+
+impl Target for Linear {
+    fn type_name(&self) -> &'static str {
+        "linear"
+    }
+
+    fn read_at(&self, mut buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        self.dev
+            .read_at(&mut buf, self.start * SECTOR_SIZE + offset)
+    }
+
+    fn write_at(&self, mut buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.dev
+            .write_at(&mut buf, self.start * SECTOR_SIZE + offset)
+    }
+
+    fn table(&self) -> String {
+        format!("{} {}", self.path, self.start)
+    }
+}
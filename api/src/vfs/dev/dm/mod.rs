@@ -0,0 +1,551 @@
+//! Device mapper: virtual block devices stacked over other block devices.
+//!
+//! Mapped devices are managed through the `/dev/mapper/control` ioctl
+//! interface of Linux, the subset used by `dmsetup` and `veritysetup`, and
+//! show up as `/dev/mapper/<name>`. A table maps the whole device to a
+//! single target, either `linear` or `verity`.
+
+mod linear;
+mod verity;
+
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    ffi::CStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::{FS_CONTEXT, FileBackend, OpenOptions};
+use axfs_ng_vfs::{DeviceId, NodeFlags, NodeType, VfsResult};
+use linux_raw_sys::ioctl::{BLKGETSIZE, BLKGETSIZE64, BLKROGET};
+use spin::{Mutex, RwLock};
+use starry_core::vfs::{Device, DeviceOps, NodeOpsMux, SimpleDirOps, SimpleFs};
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+/// The device ID of `/dev/mapper/control`.
+pub const DM_CONTROL_DEVICE_ID: DeviceId = DeviceId::new(10, 236);
+/// The major number of mapped devices.
+const DM_MAJOR: u32 = 253;
+
+const DM_VERSION_MAJOR: u32 = 4;
+const DM_VERSION_MINOR: u32 = 48;
+const DM_VERSION_PATCHLEVEL: u32 = 0;
+
+const DM_IOCTL: u32 = 0xfd;
+
+const DM_VERSION_CMD: u32 = 0;
+const DM_REMOVE_ALL_CMD: u32 = 1;
+const DM_LIST_DEVICES_CMD: u32 = 2;
+const DM_DEV_CREATE_CMD: u32 = 3;
+const DM_DEV_REMOVE_CMD: u32 = 4;
+const DM_DEV_SUSPEND_CMD: u32 = 6;
+const DM_DEV_STATUS_CMD: u32 = 7;
+const DM_TABLE_LOAD_CMD: u32 = 9;
+const DM_TABLE_CLEAR_CMD: u32 = 10;
+const DM_TABLE_STATUS_CMD: u32 = 12;
+
+const DM_READONLY_FLAG: u32 = 1 << 0;
+const DM_SUSPEND_FLAG: u32 = 1 << 1;
+const DM_STATUS_TABLE_FLAG: u32 = 1 << 4;
+const DM_ACTIVE_PRESENT_FLAG: u32 = 1 << 5;
+const DM_INACTIVE_PRESENT_FLAG: u32 = 1 << 6;
+const DM_BUFFER_FULL_FLAG: u32 = 1 << 8;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// `struct dm_ioctl`, the header of every device mapper ioctl.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DmIoctl {
+    version: [u32; 3],
+    /// The size of the whole buffer, including this header.
+    data_size: u32,
+    /// Where the payload starts, relative to the header.
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; 128],
+    uuid: [u8; 129],
+    data: [u8; 7],
+}
+
+/// `struct dm_target_spec`, followed by the target parameters.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DmTargetSpec {
+    sector_start: u64,
+    length: u64,
+    status: i32,
+    next: u32,
+    target_type: [u8; 16],
+}
+
+fn c_str(bytes: &[u8]) -> AxResult<&str> {
+    CStr::from_bytes_until_nul(bytes)
+        .ok()
+        .and_then(|s| s.to_str().ok())
+        .ok_or(AxError::InvalidInput)
+}
+
+fn copy_c_str(dst: &mut [u8], src: &str) {
+    let len = src.len().min(dst.len() - 1);
+    dst[..len].copy_from_slice(&src.as_bytes()[..len]);
+    dst[len..].fill(0);
+}
+
+/// Opens the block device underlying a target.
+fn open_device(path: &str, write: bool) -> AxResult<FileBackend> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(&FS_CONTEXT.lock(), path)?
+        .into_file()?;
+    Ok(file.backend()?.clone())
+}
+
+/// Reads exactly `buf.len()` bytes at `offset`, failing with `EIO` on a
+/// short read.
+fn read_exact(dev: &FileBackend, mut buf: &mut [u8], offset: u64) -> VfsResult<()> {
+    let len = buf.len();
+    if dev.read_at(&mut buf, offset)? != len {
+        return Err(AxError::Other(LinuxError::EIO));
+    }
+    Ok(())
+}
+
+/// What a mapped device forwards its I/O to.
+trait Target: Send + Sync {
+    /// The target type, as named in tables.
+    fn type_name(&self) -> &'static str;
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize>;
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize>;
+
+    /// The parameters the target was created with.
+    fn table(&self) -> String;
+
+    /// The current state of the target.
+    fn status(&self) -> String {
+        String::new()
+    }
+}
+
+fn new_target(ty: &str, length: u64, params: &str) -> AxResult<Arc<dyn Target>> {
+    Ok(match ty {
+        "linear" => Arc::new(linear::Linear::new(params)?),
+        "verity" => Arc::new(verity::Verity::new(length, params)?),
+        _ => {
+            warn!("dm: unsupported target type {ty}");
+            return Err(AxError::InvalidInput);
+        }
+    })
+}
+
+struct Table {
+    /// The size of the device, in sectors.
+    length: u64,
+    target: Arc<dyn Target>,
+}
+
+/// A mapped device, `/dev/mapper/<name>`.
+pub struct MappedDevice {
+    name: String,
+    uuid: String,
+    minor: u32,
+    live: RwLock<Option<Table>>,
+    /// The table loaded by `DM_TABLE_LOAD`, which becomes live on resume.
+    inactive: Mutex<Option<Table>>,
+    suspended: AtomicBool,
+    read_only: AtomicBool,
+}
+
+impl MappedDevice {
+    fn device_id(&self) -> DeviceId {
+        DeviceId::new(DM_MAJOR, self.minor)
+    }
+
+    fn size(&self) -> u64 {
+        self.live
+            .read()
+            .as_ref()
+            .map_or(0, |table| table.length * SECTOR_SIZE)
+    }
+
+    /// Fills in the state of the device, like `__dev_status` in Linux.
+    fn fill_status(&self, hdr: &mut DmIoctl) {
+        hdr.flags &= !(DM_SUSPEND_FLAG
+            | DM_READONLY_FLAG
+            | DM_ACTIVE_PRESENT_FLAG
+            | DM_INACTIVE_PRESENT_FLAG);
+        if self.suspended.load(Ordering::Acquire) {
+            hdr.flags |= DM_SUSPEND_FLAG;
+        }
+        if self.read_only.load(Ordering::Acquire) {
+            hdr.flags |= DM_READONLY_FLAG;
+        }
+        let live = self.live.read().is_some();
+        if live {
+            hdr.flags |= DM_ACTIVE_PRESENT_FLAG;
+        }
+        if self.inactive.lock().is_some() {
+            hdr.flags |= DM_INACTIVE_PRESENT_FLAG;
+        }
+        hdr.dev = self.device_id().0;
+        hdr.target_count = live as u32;
+        hdr.open_count = 0;
+        hdr.event_nr = 0;
+        copy_c_str(&mut hdr.name, &self.name);
+        copy_c_str(&mut hdr.uuid, &self.uuid);
+    }
+}
+
+impl DeviceOps for MappedDevice {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let live = self.live.read();
+        let table = live.as_ref().ok_or(AxError::Other(LinuxError::ENXIO))?;
+        let size = table.length * SECTOR_SIZE;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        table.target.read_at(&mut buf[..len], offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        if self.read_only.load(Ordering::Acquire) {
+            return Err(AxError::ReadOnlyFilesystem);
+        }
+        let live = self.live.read();
+        let table = live.as_ref().ok_or(AxError::Other(LinuxError::ENXIO))?;
+        let size = table.length * SECTOR_SIZE;
+        if offset >= size {
+            return Err(AxError::StorageFull);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        table.target.write_at(&buf[..len], offset)
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        match cmd {
+            BLKGETSIZE => (arg as *mut u32).vm_write((self.size() / SECTOR_SIZE) as _)?,
+            BLKGETSIZE64 => (arg as *mut u64).vm_write(self.size())?,
+            BLKROGET => (arg as *mut u32).vm_write(self.read_only.load(Ordering::Acquire) as u32)?,
+            _ => return Err(AxError::BadIoctl),
+        }
+        Ok(0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+struct MappedEntry {
+    dev: Arc<MappedDevice>,
+    node: Arc<Device>,
+}
+
+static MAPPED_DEVICES: Mutex<Vec<MappedEntry>> = Mutex::new(Vec::new());
+
+/// Finds the device an ioctl refers to: by name, by UUID, or by device
+/// number, whichever is given first.
+fn lookup(hdr: &DmIoctl) -> AxResult<Arc<MappedDevice>> {
+    let name = c_str(&hdr.name)?;
+    let uuid = c_str(&hdr.uuid)?;
+    MAPPED_DEVICES
+        .lock()
+        .iter()
+        .find(|entry| {
+            if !name.is_empty() {
+                entry.dev.name == name
+            } else if !uuid.is_empty() {
+                entry.dev.uuid == uuid
+            } else {
+                entry.dev.device_id().0 == hdr.dev
+            }
+        })
+        .map(|entry| entry.dev.clone())
+        .ok_or(AxError::Other(LinuxError::ENXIO))
+}
+
+fn create_device(fs: &Arc<SimpleFs>, hdr: &mut DmIoctl) -> AxResult {
+    let name = c_str(&hdr.name)?;
+    let uuid = c_str(&hdr.uuid)?;
+    if name.is_empty() || name.contains('/') {
+        return Err(AxError::InvalidInput);
+    }
+
+    let mut devices = MAPPED_DEVICES.lock();
+    if devices
+        .iter()
+        .any(|entry| entry.dev.name == name || (!uuid.is_empty() && entry.dev.uuid == uuid))
+    {
+        return Err(AxError::ResourceBusy);
+    }
+    let minor = (0..)
+        .find(|minor| devices.iter().all(|entry| entry.dev.minor != *minor))
+        .unwrap();
+    let dev = Arc::new(MappedDevice {
+        name: name.to_string(),
+        uuid: uuid.to_string(),
+        minor,
+        live: RwLock::new(None),
+        inactive: Mutex::new(None),
+        suspended: AtomicBool::new(false),
+        read_only: AtomicBool::new(hdr.flags & DM_READONLY_FLAG != 0),
+    });
+    let node = Device::new(
+        fs.clone(),
+        NodeType::BlockDevice,
+        dev.device_id(),
+        dev.clone(),
+    );
+    dev.fill_status(hdr);
+    devices.push(MappedEntry { dev, node });
+    Ok(())
+}
+
+fn load_table(dev: &MappedDevice, hdr: &DmIoctl, payload: &[u8]) -> AxResult {
+    // Tables spanning several targets are not supported.
+    if hdr.target_count != 1 || payload.len() < size_of::<DmTargetSpec>() {
+        return Err(AxError::InvalidInput);
+    }
+    // SAFETY: every bit pattern is a valid `DmTargetSpec`.
+    let spec = unsafe { (payload.as_ptr() as *const DmTargetSpec).read_unaligned() };
+    if spec.sector_start != 0 || spec.length == 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let ty = c_str(&spec.target_type)?;
+    let params = c_str(&payload[size_of::<DmTargetSpec>()..])?;
+    debug!(
+        "dm: loading table for {}: 0 {} {} {}",
+        dev.name, spec.length, ty, params
+    );
+
+    let target = new_target(ty, spec.length, params)?;
+    *dev.inactive.lock() = Some(Table {
+        length: spec.length,
+        target,
+    });
+    Ok(())
+}
+
+/// Builds the `DM_TABLE_STATUS` payload: a target spec followed by the
+/// table or status line of the target.
+fn table_status(dev: &MappedDevice, table: bool) -> Vec<u8> {
+    let live = dev.live.read();
+    let Some(live) = live.as_ref() else {
+        return Vec::new();
+    };
+    let line = if table {
+        live.target.table()
+    } else {
+        live.target.status()
+    };
+    let mut spec = DmTargetSpec {
+        sector_start: 0,
+        length: live.length,
+        status: 0,
+        next: 0,
+        target_type: [0; 16],
+    };
+    copy_c_str(&mut spec.target_type, live.target.type_name());
+    let len = (size_of::<DmTargetSpec>() + line.len() + 1).next_multiple_of(8);
+    spec.next = len as u32;
+
+    let mut payload = vec![0; len];
+    // SAFETY: `DmTargetSpec` is plain old data.
+    unsafe { (payload.as_mut_ptr() as *mut DmTargetSpec).write_unaligned(spec) };
+    payload[size_of::<DmTargetSpec>()..][..line.len()].copy_from_slice(line.as_bytes());
+    payload
+}
+
+/// Builds the `DM_LIST_DEVICES` payload, a chain of `struct dm_name_list`.
+fn list_devices() -> Vec<u8> {
+    let devices = MAPPED_DEVICES.lock();
+    let mut payload = Vec::new();
+    for (i, entry) in devices.iter().enumerate() {
+        let start = payload.len();
+        let len = (12 + entry.dev.name.len() + 1).next_multiple_of(8);
+        payload.resize(start + len, 0);
+        let item = &mut payload[start..];
+        item[..8].copy_from_slice(&entry.dev.device_id().0.to_ne_bytes());
+        let next = if i + 1 < devices.len() { len as u32 } else { 0 };
+        item[8..12].copy_from_slice(&next.to_ne_bytes());
+        item[12..][..entry.dev.name.len()].copy_from_slice(entry.dev.name.as_bytes());
+    }
+    payload
+}
+
+/// `/dev/mapper/control`
+pub struct MapperControl {
+    fs: Arc<SimpleFs>,
+}
+
+impl MapperControl {
+    fn handle(&self, nr: u32, hdr: &mut DmIoctl, payload: &[u8]) -> AxResult<Vec<u8>> {
+        match nr {
+            DM_VERSION_CMD => {}
+            DM_REMOVE_ALL_CMD => MAPPED_DEVICES.lock().clear(),
+            DM_LIST_DEVICES_CMD => return Ok(list_devices()),
+            DM_DEV_CREATE_CMD => create_device(&self.fs, hdr)?,
+            DM_DEV_REMOVE_CMD => {
+                let dev = lookup(hdr)?;
+                MAPPED_DEVICES
+                    .lock()
+                    .retain(|entry| !Arc::ptr_eq(&entry.dev, &dev));
+            }
+            DM_DEV_SUSPEND_CMD => {
+                let dev = lookup(hdr)?;
+                if hdr.flags & DM_SUSPEND_FLAG != 0 {
+                    dev.suspended.store(true, Ordering::Release);
+                } else {
+                    // Resuming swaps in the table loaded last, if any.
+                    if let Some(table) = dev.inactive.lock().take() {
+                        *dev.live.write() = Some(table);
+                    }
+                    dev.suspended.store(false, Ordering::Release);
+                }
+                dev.fill_status(hdr);
+            }
+            DM_DEV_STATUS_CMD => lookup(hdr)?.fill_status(hdr),
+            DM_TABLE_LOAD_CMD => {
+                let dev = lookup(hdr)?;
+                load_table(&dev, hdr, payload)?;
+                dev.fill_status(hdr);
+            }
+            DM_TABLE_CLEAR_CMD => {
+                let dev = lookup(hdr)?;
+                dev.inactive.lock().take();
+                dev.fill_status(hdr);
+            }
+            DM_TABLE_STATUS_CMD => {
+                let dev = lookup(hdr)?;
+                dev.fill_status(hdr);
+                return Ok(table_status(&dev, hdr.flags & DM_STATUS_TABLE_FLAG != 0));
+            }
+            _ => {
+                warn!("dm: unsupported ioctl command {nr}");
+                return Err(AxError::BadIoctl);
+            }
+        }
+        Ok(Vec::new())
+    }
+}
+
+impl DeviceOps for MapperControl {
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        if (cmd >> 8) & 0xff != DM_IOCTL {
+            return Err(AxError::BadIoctl);
+        }
+        let ptr = arg as *mut DmIoctl;
+        // FIXME: AnyBitPattern
+        let mut hdr = unsafe { ptr.vm_read_uninit()?.assume_init() };
+        let version = [DM_VERSION_MAJOR, DM_VERSION_MINOR, DM_VERSION_PATCHLEVEL];
+        if hdr.version[0] != DM_VERSION_MAJOR {
+            hdr.version = version;
+            ptr.vm_write(hdr)?;
+            return Err(AxError::InvalidInput);
+        }
+        hdr.version = version;
+
+        let data_size = hdr.data_size as usize;
+        let data_start = hdr.data_start as usize;
+        if data_size < size_of::<DmIoctl>() || data_start > data_size {
+            return Err(AxError::InvalidInput);
+        }
+        let payload = vm_load((arg + data_start) as *const u8, data_size - data_start)?;
+
+        hdr.flags &= !DM_BUFFER_FULL_FLAG;
+        let out = self.handle(cmd & 0xff, &mut hdr, &payload)?;
+        // Results go right after the header, if they fit.
+        let out_start = size_of::<DmIoctl>().next_multiple_of(8);
+        hdr.data_start = out_start as u32;
+        if out_start + out.len() > data_size {
+            hdr.flags |= DM_BUFFER_FULL_FLAG;
+            hdr.data_size = out_start as u32;
+        } else {
+            vm_write_slice((arg + out_start) as *mut u8, &out)?;
+            hdr.data_size = (out_start + out.len()) as u32;
+        }
+        ptr.vm_write(hdr)?;
+        Ok(0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+/// `/dev/mapper`
+pub struct MapperDir {
+    control: Arc<Device>,
+}
+
+impl MapperDir {
+    pub fn new(fs: Arc<SimpleFs>) -> Self {
+        let control = Device::new(
+            fs.clone(),
+            NodeType::CharacterDevice,
+            DM_CONTROL_DEVICE_ID,
+            Arc::new(MapperControl { fs }),
+        );
+        Self { control }
+    }
+}
+
+impl SimpleDirOps for MapperDir {
+    fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let names = MAPPED_DEVICES
+            .lock()
+            .iter()
+            .map(|entry| Cow::Owned(entry.dev.name.clone()))
+            .collect::<Vec<_>>();
+        Box::new(core::iter::once(Cow::Borrowed("control")).chain(names))
+    }
+
+    fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        if name == "control" {
+            return Ok(NodeOpsMux::File(self.control.clone()));
+        }
+        MAPPED_DEVICES
+            .lock()
+            .iter()
+            .find(|entry| entry.dev.name == name)
+            .map(|entry| NodeOpsMux::File(entry.node.clone()))
+            .ok_or(AxError::NotFound)
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+}
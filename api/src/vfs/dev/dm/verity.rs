@@ -0,0 +1,338 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::FileBackend;
+use axfs_ng_vfs::VfsResult;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use super::{SECTOR_SIZE, Target, open_device, read_exact};
+use crate::crypto::{self, AlgorithmKind, Hash};
+
+/// How many verified hash blocks are kept around.
+const HASH_CACHE_BLOCKS: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CorruptionMode {
+    /// Fail reads of corrupted blocks with `EIO`.
+    Eio,
+    /// Log corrupted blocks but return them anyway.
+    Ignore,
+    /// Panic, so that a corrupted system never keeps running.
+    Panic,
+}
+
+fn hex_decode(s: &str) -> AxResult<Vec<u8>> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    if s.len() % 2 != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| AxError::InvalidInput))
+        .collect()
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_string();
+    }
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(s, "{byte:02x}");
+    }
+    s
+}
+
+fn parse_block_size(s: &str) -> AxResult<usize> {
+    let size = s.parse::<usize>().map_err(|_| AxError::InvalidInput)?;
+    if !size.is_power_of_two() || !(SECTOR_SIZE as usize..=PAGE_SIZE_4K).contains(&size) {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(size)
+}
+
+/// The `verity` target, which checks every block read from the data device
+/// against a Merkle tree of hashes stored on the hash device.
+///
+/// Parameters, as in Linux: `<version> <data device> <hash device>
+/// <data block size> <hash block size> <number of data blocks> <hash start
+/// block> <algorithm> <root digest> <salt> [<#opt params> <opt params>]`.
+pub struct Verity {
+    data_path: String,
+    hash_path: String,
+    data_dev: FileBackend,
+    hash_dev: FileBackend,
+
+    version: u32,
+    data_block_size: usize,
+    hash_block_size: usize,
+    data_blocks: u64,
+    hash_start: u64,
+    algorithm: String,
+    new_hash: fn() -> Box<dyn Hash>,
+    digest_size: usize,
+    root_digest: Vec<u8>,
+    salt: Vec<u8>,
+    mode: CorruptionMode,
+
+    /// log2 of the number of hashes in a hash block.
+    hash_per_block_bits: u32,
+    /// The first hash block of each level of the tree, from the bottom.
+    level_start: Vec<u64>,
+
+    /// Hash blocks that have already been verified, by block number.
+    verified: Mutex<BTreeMap<u64, Arc<[u8]>>>,
+    corrupted: AtomicBool,
+}
+
+impl Verity {
+    pub fn new(length: u64, params: &str) -> AxResult<Self> {
+        let args = params.split_whitespace().collect::<Vec<_>>();
+        if args.len() < 10 {
+            return Err(AxError::InvalidInput);
+        }
+        let parse = |s: &str| s.parse::<u64>().map_err(|_| AxError::InvalidInput);
+
+        let version = parse(args[0])? as u32;
+        if version > 1 {
+            return Err(AxError::InvalidInput);
+        }
+        let data_block_size = parse_block_size(args[3])?;
+        let hash_block_size = parse_block_size(args[4])?;
+        let data_blocks = parse(args[5])?;
+        let hash_start = parse(args[6])?;
+        if data_blocks == 0 || length > data_blocks * (data_block_size as u64 / SECTOR_SIZE) {
+            warn!("dm-verity: data device is too small");
+            return Err(AxError::InvalidInput);
+        }
+
+        let algorithm = args[7];
+        let Some(AlgorithmKind::Hash { new: new_hash }) =
+            crypto::find("hash", algorithm).map(|alg| alg.kind)
+        else {
+            warn!("dm-verity: unknown hash algorithm {algorithm}");
+            return Err(AxError::InvalidInput);
+        };
+        let digest_size = new_hash().finalize().len();
+        let root_digest = hex_decode(args[8])?;
+        if root_digest.len() != digest_size {
+            return Err(AxError::InvalidInput);
+        }
+        let salt = hex_decode(args[9])?;
+
+        let mut mode = CorruptionMode::Eio;
+        if let Some(count) = args.get(10) {
+            let count = parse(count)? as usize;
+            if args.len() != 11 + count {
+                return Err(AxError::InvalidInput);
+            }
+            for arg in &args[11..] {
+                mode = match *arg {
+                    "ignore_corruption" => CorruptionMode::Ignore,
+                    "panic_on_corruption" | "restart_on_corruption" => CorruptionMode::Panic,
+                    _ => {
+                        warn!("dm-verity: unsupported option {arg}");
+                        return Err(AxError::InvalidInput);
+                    }
+                };
+            }
+        }
+
+        // Lay out the tree the way `veritysetup` does: the top level comes
+        // first on the hash device.
+        let hash_per_block_bits = (hash_block_size / digest_size).ilog2();
+        let mut levels = 0;
+        while hash_per_block_bits * levels < 64
+            && (data_blocks - 1) >> (hash_per_block_bits * levels) != 0
+        {
+            levels += 1;
+        }
+        let mut level_start = vec![0; levels as usize];
+        let mut position = hash_start;
+        for level in (0..levels).rev() {
+            level_start[level as usize] = position;
+            let shift = (level + 1) * hash_per_block_bits;
+            position += if shift >= 64 {
+                1
+            } else {
+                (data_blocks + (1 << shift) - 1) >> shift
+            };
+        }
+
+        Ok(Self {
+            data_path: args[1].into(),
+            hash_path: args[2].into(),
+            data_dev: open_device(args[1], false)?,
+            hash_dev: open_device(args[2], false)?,
+            version,
+            data_block_size,
+            hash_block_size,
+            data_blocks,
+            hash_start,
+            algorithm: algorithm.into(),
+            new_hash,
+            digest_size,
+            root_digest,
+            salt,
+            mode,
+            hash_per_block_bits,
+            level_start,
+            verified: Mutex::new(BTreeMap::new()),
+            corrupted: AtomicBool::new(false),
+        })
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = (self.new_hash)();
+        // Version 0 appends the salt, version 1 prepends it.
+        if self.version >= 1 {
+            hasher.update(&self.salt);
+        }
+        hasher.update(data);
+        if self.version == 0 {
+            hasher.update(&self.salt);
+        }
+        hasher.finalize()
+    }
+
+    /// Returns the hash block holding the hash of data block `block` at
+    /// `level`, and the offset of the hash in it.
+    fn hash_position(&self, block: u64, level: usize) -> (u64, usize) {
+        let bits = self.hash_per_block_bits;
+        let position = block >> (level as u32 * bits);
+        let hash_block = self.level_start[level] + (position >> bits);
+        let index = (position & ((1 << bits) - 1)) as usize;
+        // Version 1 pads each hash to a power of two.
+        let offset = if self.version == 0 {
+            index * self.digest_size
+        } else {
+            index << (self.hash_block_size.ilog2() - bits)
+        };
+        (hash_block, offset)
+    }
+
+    fn corruption(&self, what: &str, block: u64) -> VfsResult<()> {
+        self.corrupted.store(true, Ordering::Release);
+        warn!("dm-verity: {} block {} is corrupted", what, block);
+        match self.mode {
+            CorruptionMode::Eio => Err(AxError::Other(LinuxError::EIO)),
+            CorruptionMode::Ignore => Ok(()),
+            CorruptionMode::Panic => panic!("dm-verity: {what} block {block} is corrupted"),
+        }
+    }
+
+    /// Reads hash block `block` and checks it against `digest`.
+    fn read_hash_block(&self, block: u64, digest: &[u8]) -> VfsResult<Arc<[u8]>> {
+        if let Some(data) = self.verified.lock().get(&block) {
+            return Ok(data.clone());
+        }
+        let mut data = vec![0; self.hash_block_size];
+        read_exact(
+            &self.hash_dev,
+            &mut data,
+            block * self.hash_block_size as u64,
+        )?;
+        let data = Arc::<[u8]>::from(data);
+        if self.hash(&data) != digest {
+            self.corruption("metadata", block)?;
+        } else {
+            let mut verified = self.verified.lock();
+            if verified.len() >= HASH_CACHE_BLOCKS {
+                verified.pop_first();
+            }
+            verified.insert(block, data.clone());
+        }
+        Ok(data)
+    }
+
+    /// Checks data block `block` against the hash tree, walking down from
+    /// the root.
+    fn verify_block(&self, block: u64, data: &[u8]) -> VfsResult<()> {
+        let mut digest = self.root_digest.clone();
+        for level in (0..self.level_start.len()).rev() {
+            let (hash_block, offset) = self.hash_position(block, level);
+            let hashes = self.read_hash_block(hash_block, &digest)?;
+            digest = hashes[offset..][..self.digest_size].to_vec();
+        }
+        if self.hash(data) != digest {
+            self.corruption("data", block)?;
+        }
+        Ok(())
+    }
+}
+
+impl Target for Verity {
+    fn type_name(&self) -> &'static str {
+        "verity"
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let block_size = self.data_block_size as u64;
+        let mut data = vec![0; self.data_block_size];
+        let mut read = 0;
+        while read < buf.len() {
+            let pos = offset + read as u64;
+            let block = pos / block_size;
+            if block >= self.data_blocks {
+                break;
+            }
+            read_exact(&self.data_dev, &mut data, block * block_size)?;
+            self.verify_block(block, &data)?;
+
+            let start = (pos % block_size) as usize;
+            let len = (self.data_block_size - start).min(buf.len() - read);
+            buf[read..][..len].copy_from_slice(&data[start..][..len]);
+            read += len;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(AxError::ReadOnlyFilesystem)
+    }
+
+    fn table(&self) -> String {
+        let mut table = format!(
+            "{} {} {} {} {} {} {} {} {} {}",
+            self.version,
+            self.data_path,
+            self.hash_path,
+            self.data_block_size,
+            self.hash_block_size,
+            self.data_blocks,
+            self.hash_start,
+            self.algorithm,
+            hex_encode(&self.root_digest),
+            hex_encode(&self.salt)
+        );
+        match self.mode {
+            CorruptionMode::Eio => {}
+            CorruptionMode::Ignore => table.push_str(" 1 ignore_corruption"),
+            CorruptionMode::Panic => table.push_str(" 1 panic_on_corruption"),
+        }
+        table
+    }
+
+    fn status(&self) -> String {
+        if self.corrupted.load(Ordering::Acquire) {
+            "C".into()
+        } else {
+            "V".into()
+        }
+    }
+}
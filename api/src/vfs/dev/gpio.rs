@@ -0,0 +1,871 @@
+//! GPIO character devices, `/dev/gpiochipN`.
+//!
+//! This is the v2 uAPI of Linux, as used by libgpiod: `GPIO_GET_CHIPINFO_IOCTL`
+//! and `GPIO_V2_GET_LINEINFO_IOCTL` describe the chip and its lines, and
+//! `GPIO_V2_GET_LINE_IOCTL` requests a set of lines, returning a file
+//! descriptor to get and set their values through. If edge detection is
+//! enabled, edges are read from that file descriptor as
+//! `struct gpio_v2_line_event`s, and polling it reports `POLLIN` when there
+//! are some.
+//!
+//! Platform GPIO drivers register their controllers with [`register`]; the
+//! controllers in the device tree that have a driver here are registered by
+//! [`probe`].
+
+use alloc::{borrow::Cow, collections::VecDeque, format, string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::{DeviceId, NodeFlags, VfsError, VfsResult};
+use axhal::time::{monotonic_time_nanos, wall_time};
+use axio::BufMut;
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::future::Poller;
+use bytemuck::{AnyBitPattern, NoUninit, Zeroable};
+use kspin::SpinNoIrq;
+use spin::{Mutex, RwLock};
+use starry_core::boot::{self, FdtNode};
+use starry_vm::{VmMutPtr, VmPtr};
+
+use crate::{
+    file::{FileLike, Kstat, SealedBuf, SealedBufMut, add_file_like},
+    vfs::DeviceOps,
+};
+
+mod pl061;
+
+/// The major number of `/dev/gpiochipN`.
+pub const GPIO_MAJOR: u32 = 254;
+
+const GPIO_GET_CHIPINFO_IOCTL: u32 = 0x8044_b401;
+const GPIO_V2_GET_LINEINFO_IOCTL: u32 = 0xc100_b405;
+const GPIO_V2_GET_LINE_IOCTL: u32 = 0xc250_b407;
+const GPIO_V2_LINE_SET_CONFIG_IOCTL: u32 = 0xc110_b40d;
+const GPIO_V2_LINE_GET_VALUES_IOCTL: u32 = 0xc010_b40e;
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u32 = 0xc010_b40f;
+
+/// The most lines a request may have.
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+
+const GPIO_V2_LINE_FLAG_USED: u64 = 1 << 0;
+const GPIO_V2_LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+const GPIO_V2_LINE_FLAG_OPEN_DRAIN: u64 = 1 << 6;
+const GPIO_V2_LINE_FLAG_OPEN_SOURCE: u64 = 1 << 7;
+const GPIO_V2_LINE_FLAG_BIAS_PULL_UP: u64 = 1 << 8;
+const GPIO_V2_LINE_FLAG_BIAS_PULL_DOWN: u64 = 1 << 9;
+const GPIO_V2_LINE_FLAG_BIAS_DISABLED: u64 = 1 << 10;
+const GPIO_V2_LINE_FLAG_EVENT_CLOCK_REALTIME: u64 = 1 << 11;
+const GPIO_V2_LINE_FLAG_EVENT_CLOCK_HTE: u64 = 1 << 12;
+
+const GPIO_V2_LINE_EDGE_FLAGS: u64 = GPIO_V2_LINE_FLAG_EDGE_RISING | GPIO_V2_LINE_FLAG_EDGE_FALLING;
+const GPIO_V2_LINE_DRIVE_FLAGS: u64 = GPIO_V2_LINE_FLAG_OPEN_DRAIN | GPIO_V2_LINE_FLAG_OPEN_SOURCE;
+const GPIO_V2_LINE_BIAS_FLAGS: u64 = GPIO_V2_LINE_FLAG_BIAS_PULL_UP
+    | GPIO_V2_LINE_FLAG_BIAS_PULL_DOWN
+    | GPIO_V2_LINE_FLAG_BIAS_DISABLED;
+/// The flags a request may set, everything but `USED`.
+const GPIO_V2_LINE_VALID_FLAGS: u64 = (GPIO_V2_LINE_FLAG_EVENT_CLOCK_HTE << 1) - 2;
+
+const GPIO_V2_LINE_ATTR_ID_FLAGS: u32 = 1;
+const GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+const GPIO_V2_LINE_ATTR_ID_DEBOUNCE: u32 = 3;
+
+const GPIO_V2_LINE_EVENT_RISING_EDGE: u32 = 1;
+const GPIO_V2_LINE_EVENT_FALLING_EDGE: u32 = 2;
+
+/// The events buffered for each line of a request, unless it asks otherwise.
+const EVENTS_PER_LINE: usize = 16;
+
+/// `struct gpiochip_info`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct GpioChipInfo {
+    name: [u8; 32],
+    label: [u8; 32],
+    lines: u32,
+}
+
+/// `struct gpio_v2_line_attribute`. `value` holds the flags, the output
+/// values or the debounce period in microseconds, depending on `id`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct GpioLineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+/// `struct gpio_v2_line_config_attribute`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct GpioLineConfigAttribute {
+    attr: GpioLineAttribute,
+    /// The lines of the request the attribute applies to.
+    mask: u64,
+}
+
+/// `struct gpio_v2_line_config`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct GpioLineConfig {
+    /// The flags of the lines no `GPIO_V2_LINE_ATTR_ID_FLAGS` attribute
+    /// applies to.
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [GpioLineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+/// `struct gpio_v2_line_request`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct GpioLineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; 32],
+    config: GpioLineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+/// `struct gpio_v2_line_info`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct GpioLineInfo {
+    name: [u8; 32],
+    consumer: [u8; 32],
+    offset: u32,
+    num_attrs: u32,
+    flags: u64,
+    attrs: [GpioLineAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+    padding: [u32; 4],
+}
+
+/// `struct gpio_v2_line_values`, with bits indexing the lines of a request.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct GpioLineValues {
+    bits: u64,
+    mask: u64,
+}
+
+/// `struct gpio_v2_line_event`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern, NoUninit)]
+struct GpioLineEvent {
+    timestamp_ns: u64,
+    id: u32,
+    offset: u32,
+    seqno: u32,
+    line_seqno: u32,
+    padding: [u32; 6],
+}
+
+/// Copies `s` into a NUL-terminated C string, truncating it if needed.
+fn fill_str(dst: &mut [u8; 32], s: &str) {
+    let len = s.len().min(dst.len() - 1);
+    dst[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+fn parse_str(src: &[u8; 32]) -> String {
+    let len = src.iter().position(|&b| b == 0).unwrap_or(src.len());
+    String::from_utf8_lossy(&src[..len]).into_owned()
+}
+
+/// A signal edge, in terms of the physical level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+impl Edge {
+    fn invert(self) -> Self {
+        match self {
+            Self::Rising => Self::Falling,
+            Self::Falling => Self::Rising,
+        }
+    }
+}
+
+/// Called by a GPIO driver, usually from its interrupt handler, when an
+/// enabled edge is seen on a line.
+pub type EdgeHandler = Arc<dyn Fn(Edge) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    PushPull,
+    OpenDrain,
+    OpenSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bias {
+    /// Leave the bias as it is.
+    AsIs,
+    PullUp,
+    PullDown,
+    Disabled,
+}
+
+/// The setup of a line, in terms of physical levels.
+#[derive(Debug, Clone, Copy)]
+pub struct LineSettings {
+    pub direction: Direction,
+    pub drive: Drive,
+    pub bias: Bias,
+    /// The debounce period in microseconds, 0 for none.
+    pub debounce_us: u32,
+    /// Whether rising edges are reported to the edge handler.
+    pub rising: bool,
+    /// Whether falling edges are reported to the edge handler.
+    pub falling: bool,
+}
+
+/// A GPIO controller.
+pub trait GpioChip: Send + Sync {
+    /// Returns the label reported by `GPIO_GET_CHIPINFO_IOCTL`, e.g. the
+    /// name of the controller.
+    fn label(&self) -> &str;
+
+    fn num_lines(&self) -> u32;
+
+    /// Returns the name of a line, e.g. from the board's device tree.
+    fn line_name(&self, _offset: u32) -> Option<&str> {
+        None
+    }
+
+    /// Sets up a line. `value` is the level an output starts with.
+    fn configure(&self, offset: u32, settings: &LineSettings, value: bool) -> AxResult;
+
+    /// Reads the level of a line.
+    fn get(&self, offset: u32) -> bool;
+
+    /// Sets the level of an output.
+    fn set(&self, offset: u32, value: bool);
+
+    /// Sets the function called on the edges enabled by
+    /// [`configure`](Self::configure), or removes it.
+    fn set_edge_handler(&self, _offset: u32, _handler: Option<EdgeHandler>) -> AxResult {
+        Err(AxError::Unsupported)
+    }
+}
+
+/// What `GPIO_V2_GET_LINEINFO_IOCTL` reports about a line.
+struct LineStatus {
+    /// The consumer of the request holding the line, if any.
+    consumer: Option<String>,
+    flags: u64,
+    debounce_us: u32,
+}
+
+/// `/dev/gpiochipN`.
+pub struct GpioChipDevice {
+    index: u32,
+    chip: Arc<dyn GpioChip>,
+    lines: Mutex<Vec<LineStatus>>,
+}
+
+static CHIPS: RwLock<Vec<Arc<GpioChipDevice>>> = RwLock::new(Vec::new());
+
+/// Adds a GPIO controller, which shows up as `/dev/gpiochipN`.
+///
+/// Controllers must be registered before devfs is mounted.
+pub fn register(chip: Arc<dyn GpioChip>) {
+    let mut chips = CHIPS.write();
+    let index = chips.len() as u32;
+    info!(
+        "gpio: gpiochip{index} is {} with {} lines",
+        chip.label(),
+        chip.num_lines()
+    );
+    let lines = (0..chip.num_lines())
+        .map(|_| LineStatus {
+            consumer: None,
+            flags: GPIO_V2_LINE_FLAG_INPUT,
+            debounce_us: 0,
+        })
+        .collect();
+    chips.push(Arc::new(GpioChipDevice {
+        index,
+        chip,
+        lines: Mutex::new(lines),
+    }));
+}
+
+/// The controllers registered by [`probe`], with the device tree nodes
+/// describing them, for the `*-gpios` properties of other devices.
+static DT_CHIPS: RwLock<Vec<(&'static FdtNode, Arc<dyn GpioChip>)>> = RwLock::new(Vec::new());
+
+/// Registers the controllers described by the device tree.
+pub fn probe() {
+    for (node, chip) in pl061::probe() {
+        DT_CHIPS.write().push((node, chip.clone()));
+        register(chip);
+    }
+}
+
+/// A line named by a `*-gpios` property of the device tree.
+pub struct DtLine {
+    pub chip: Arc<dyn GpioChip>,
+    pub offset: u32,
+    pub active_low: bool,
+    pub drive: Drive,
+}
+
+/// Returns the line entry `index` of property `name` of `node` names, if
+/// its controller has been registered by [`probe`].
+pub fn dt_line(node: &FdtNode, name: &str, index: usize) -> Option<DtLine> {
+    const GPIO_ACTIVE_LOW: u32 = 0x1;
+    const GPIO_SINGLE_ENDED: u32 = 0x2;
+    const GPIO_LINE_OPEN_DRAIN: u32 = 0x4;
+
+    let root = boot::device_tree()?;
+    let cells: Vec<u32> = node.property_cells(name).collect();
+    let mut pos = 0;
+    for _ in 0..index {
+        // A zero phandle leaves an entry empty.
+        let controller = match cells.get(pos)? {
+            0 => None,
+            &phandle => Some(root.find_phandle(phandle)?),
+        };
+        pos += 1 + controller.map_or(Some(0), |it| it.property_u32("#gpio-cells"))? as usize;
+    }
+
+    let controller = root.find_phandle(*cells.get(pos)?)?;
+    let args = &cells[pos + 1..];
+    let chip = DT_CHIPS
+        .read()
+        .iter()
+        .find(|(node, _)| core::ptr::eq(*node, controller))?
+        .1
+        .clone();
+    let flags = args.get(1).copied().unwrap_or(0);
+    Some(DtLine {
+        chip,
+        offset: *args.first()?,
+        active_low: flags & GPIO_ACTIVE_LOW != 0,
+        drive: if flags & GPIO_SINGLE_ENDED == 0 {
+            Drive::PushPull
+        } else if flags & GPIO_LINE_OPEN_DRAIN != 0 {
+            Drive::OpenDrain
+        } else {
+            Drive::OpenSource
+        },
+    })
+}
+
+/// Returns all GPIO controllers.
+pub fn chips() -> Vec<Arc<GpioChipDevice>> {
+    CHIPS.read().clone()
+}
+
+impl GpioChipDevice {
+    pub fn name(&self) -> String {
+        format!("gpiochip{}", self.index)
+    }
+
+    pub fn device_id(&self) -> DeviceId {
+        DeviceId::new(GPIO_MAJOR, self.index)
+    }
+
+    fn line_info(&self, offset: u32) -> AxResult<GpioLineInfo> {
+        let lines = self.lines.lock();
+        let status = lines.get(offset as usize).ok_or(AxError::InvalidInput)?;
+        let mut info = GpioLineInfo::zeroed();
+        if let Some(name) = self.chip.line_name(offset) {
+            fill_str(&mut info.name, name);
+        }
+        info.offset = offset;
+        info.flags = status.flags;
+        if let Some(consumer) = &status.consumer {
+            fill_str(&mut info.consumer, consumer);
+            info.flags |= GPIO_V2_LINE_FLAG_USED;
+        }
+        if status.debounce_us != 0 {
+            info.attrs[0].id = GPIO_V2_LINE_ATTR_ID_DEBOUNCE;
+            info.attrs[0].value = status.debounce_us as u64;
+            info.num_attrs = 1;
+        }
+        Ok(info)
+    }
+
+    fn request_lines(self: &Arc<Self>, args: &GpioLineRequest) -> AxResult<Arc<LineRequest>> {
+        let num_lines = args.num_lines as usize;
+        if num_lines == 0 || num_lines > GPIO_V2_LINES_MAX || args.padding != [0; 5] {
+            return Err(AxError::InvalidInput);
+        }
+        let offsets = args.offsets[..num_lines].to_vec();
+        for (i, &offset) in offsets.iter().enumerate() {
+            if offset >= self.chip.num_lines() || offsets[..i].contains(&offset) {
+                return Err(AxError::InvalidInput);
+            }
+        }
+        let configs = parse_config(&args.config, num_lines)?;
+        let consumer = parse_str(&args.consumer);
+
+        {
+            let mut lines = self.lines.lock();
+            if offsets
+                .iter()
+                .any(|&offset| lines[offset as usize].consumer.is_some())
+            {
+                return Err(AxError::ResourceBusy);
+            }
+            for &offset in &offsets {
+                lines[offset as usize].consumer = Some(consumer.clone());
+            }
+        }
+
+        let capacity = match args.event_buffer_size as usize {
+            0 => num_lines * EVENTS_PER_LINE,
+            size => size,
+        }
+        .min(GPIO_V2_LINES_MAX * EVENTS_PER_LINE);
+        let request = Arc::new(LineRequest {
+            device: self.clone(),
+            events: Arc::new(EventQueue {
+                state: SpinNoIrq::new(EventState {
+                    lines: offsets
+                        .iter()
+                        .map(|&offset| EdgeConfig {
+                            offset,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    events: VecDeque::new(),
+                    seqno: 0,
+                }),
+                capacity,
+                poll_rx: PollSet::new(),
+            }),
+            configs: Mutex::new(vec![LineConfig::default(); num_lines]),
+            offsets,
+            non_blocking: AtomicBool::new(false),
+        });
+        // On failure, dropping the request releases the lines again.
+        request.apply(&configs)?;
+        Ok(request)
+    }
+}
+
+impl DeviceOps for GpioChipDevice {
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        match cmd {
+            GPIO_GET_CHIPINFO_IOCTL => {
+                let mut info = GpioChipInfo::zeroed();
+                fill_str(&mut info.name, &self.name());
+                fill_str(&mut info.label, self.chip.label());
+                info.lines = self.chip.num_lines();
+                (arg as *mut GpioChipInfo).vm_write(info)?;
+            }
+            GPIO_V2_GET_LINEINFO_IOCTL => {
+                let info = (arg as *const GpioLineInfo).vm_read()?;
+                if info.padding != [0; 4] {
+                    return Err(VfsError::InvalidInput);
+                }
+                (arg as *mut GpioLineInfo).vm_write(self.line_info(info.offset)?)?;
+            }
+            GPIO_V2_GET_LINE_IOCTL => {
+                let mut args = (arg as *const GpioLineRequest).vm_read()?;
+                // Line requests hold on to the chip.
+                let this = CHIPS.read()[self.index as usize].clone();
+                let request = this.request_lines(&args)?;
+                args.fd = add_file_like(request, true)?;
+                (arg as *mut GpioLineRequest).vm_write(args)?;
+            }
+            _ => return Err(VfsError::BadIoctl),
+        }
+        Ok(0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+/// The configuration of one line of a request, with logical values.
+#[derive(Debug, Clone, Copy, Default)]
+struct LineConfig {
+    flags: u64,
+    /// The value of an output.
+    value: bool,
+    debounce_us: u32,
+}
+
+impl LineConfig {
+    fn active_low(&self) -> bool {
+        self.flags & GPIO_V2_LINE_FLAG_ACTIVE_LOW != 0
+    }
+
+    fn is_output(&self) -> bool {
+        self.flags & GPIO_V2_LINE_FLAG_OUTPUT != 0
+    }
+
+    fn settings(&self) -> LineSettings {
+        let flags = self.flags;
+        let (mut rising, mut falling) = (
+            flags & GPIO_V2_LINE_FLAG_EDGE_RISING != 0,
+            flags & GPIO_V2_LINE_FLAG_EDGE_FALLING != 0,
+        );
+        if self.active_low() {
+            (rising, falling) = (falling, rising);
+        }
+        LineSettings {
+            direction: if self.is_output() {
+                Direction::Output
+            } else {
+                Direction::Input
+            },
+            drive: if flags & GPIO_V2_LINE_FLAG_OPEN_DRAIN != 0 {
+                Drive::OpenDrain
+            } else if flags & GPIO_V2_LINE_FLAG_OPEN_SOURCE != 0 {
+                Drive::OpenSource
+            } else {
+                Drive::PushPull
+            },
+            bias: if flags & GPIO_V2_LINE_FLAG_BIAS_PULL_UP != 0 {
+                Bias::PullUp
+            } else if flags & GPIO_V2_LINE_FLAG_BIAS_PULL_DOWN != 0 {
+                Bias::PullDown
+            } else if flags & GPIO_V2_LINE_FLAG_BIAS_DISABLED != 0 {
+                Bias::Disabled
+            } else {
+                Bias::AsIs
+            },
+            debounce_us: self.debounce_us,
+            rising,
+            falling,
+        }
+    }
+}
+
+/// Checks that the line flags don't contradict each other.
+fn validate_flags(flags: u64) -> AxResult {
+    let input = flags & GPIO_V2_LINE_FLAG_INPUT != 0;
+    let output = flags & GPIO_V2_LINE_FLAG_OUTPUT != 0;
+    let invalid = flags & !GPIO_V2_LINE_VALID_FLAGS != 0
+        || (input && output)
+        // Edge detection needs an input, and drive modes an output.
+        || (flags & GPIO_V2_LINE_EDGE_FLAGS != 0 && !input)
+        || (flags & GPIO_V2_LINE_DRIVE_FLAGS != 0 && !output)
+        || (flags & GPIO_V2_LINE_DRIVE_FLAGS).count_ones() > 1
+        // A bias needs a direction.
+        || (flags & GPIO_V2_LINE_BIAS_FLAGS != 0 && !input && !output)
+        || (flags & GPIO_V2_LINE_BIAS_FLAGS).count_ones() > 1;
+    if invalid {
+        return Err(AxError::InvalidInput);
+    }
+    if flags & GPIO_V2_LINE_FLAG_EVENT_CLOCK_HTE != 0 {
+        // There are no hardware timestamping engines.
+        return Err(AxError::Unsupported);
+    }
+    Ok(())
+}
+
+/// Works out the configuration of each of the `num_lines` lines.
+fn parse_config(config: &GpioLineConfig, num_lines: usize) -> AxResult<Vec<LineConfig>> {
+    let num_attrs = config.num_attrs as usize;
+    if num_attrs > GPIO_V2_LINE_NUM_ATTRS_MAX || config.padding != [0; 5] {
+        return Err(AxError::InvalidInput);
+    }
+    let attrs = &config.attrs[..num_attrs];
+    (0..num_lines)
+        .map(|i| {
+            let mut line = LineConfig {
+                flags: config.flags,
+                ..Default::default()
+            };
+            // The first attribute of each kind that covers the line wins.
+            let find = |id| {
+                attrs
+                    .iter()
+                    .find(|it| it.attr.id == id && it.mask & (1 << i) != 0)
+                    .map(|it| it.attr.value)
+            };
+            if let Some(flags) = find(GPIO_V2_LINE_ATTR_ID_FLAGS) {
+                line.flags = flags;
+            }
+            if let Some(values) = find(GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES) {
+                line.value = values & (1 << i) != 0;
+            }
+            if let Some(period) = find(GPIO_V2_LINE_ATTR_ID_DEBOUNCE) {
+                line.debounce_us = period as u32;
+            }
+            validate_flags(line.flags)?;
+            Ok(line)
+        })
+        .collect()
+}
+
+/// The edges reported for a line of a request.
+#[derive(Default)]
+struct EdgeConfig {
+    offset: u32,
+    active_low: bool,
+    rising: bool,
+    falling: bool,
+    realtime: bool,
+    /// The number of edges seen on the line.
+    seqno: u32,
+}
+
+struct EventState {
+    lines: Vec<EdgeConfig>,
+    events: VecDeque<GpioLineEvent>,
+    /// The number of edges seen on all lines of the request.
+    seqno: u32,
+}
+
+/// The edges detected on the lines of a request, filled by the edge handlers
+/// of the driver.
+struct EventQueue {
+    state: SpinNoIrq<EventState>,
+    capacity: usize,
+    poll_rx: PollSet,
+}
+
+impl EventQueue {
+    /// Queues an edge seen on line `index` of the request, dropping the
+    /// oldest event if the queue is full.
+    fn push(&self, index: usize, edge: Edge) {
+        let mut state = self.state.lock();
+        let line = &mut state.lines[index];
+        let edge = if line.active_low { edge.invert() } else { edge };
+        let id = match edge {
+            Edge::Rising if line.rising => GPIO_V2_LINE_EVENT_RISING_EDGE,
+            Edge::Falling if line.falling => GPIO_V2_LINE_EVENT_FALLING_EDGE,
+            _ => return,
+        };
+        let timestamp_ns = if line.realtime {
+            wall_time().as_nanos() as u64
+        } else {
+            monotonic_time_nanos()
+        };
+        line.seqno = line.seqno.wrapping_add(1);
+        let mut event = GpioLineEvent::zeroed();
+        event.timestamp_ns = timestamp_ns;
+        event.id = id;
+        event.offset = line.offset;
+        event.line_seqno = line.seqno;
+        state.seqno = state.seqno.wrapping_add(1);
+        event.seqno = state.seqno;
+        if state.events.len() >= self.capacity {
+            state.events.pop_front();
+        }
+        state.events.push_back(event);
+        drop(state);
+        self.poll_rx.wake();
+    }
+}
+
+/// A line request file descriptor, as returned by `GPIO_V2_GET_LINE_IOCTL`.
+struct LineRequest {
+    device: Arc<GpioChipDevice>,
+    offsets: Vec<u32>,
+    configs: Mutex<Vec<LineConfig>>,
+    events: Arc<EventQueue>,
+    non_blocking: AtomicBool,
+}
+
+impl LineRequest {
+    /// Applies a new configuration to the lines.
+    fn apply(&self, new: &[LineConfig]) -> AxResult {
+        let chip = &self.device.chip;
+        let mut configs = self.configs.lock();
+        for (index, (&offset, config)) in self.offsets.iter().zip(new).enumerate() {
+            let old = &configs[index];
+            let had_edges = old.flags & GPIO_V2_LINE_EDGE_FLAGS != 0;
+            let has_edges = config.flags & GPIO_V2_LINE_EDGE_FLAGS != 0;
+            if had_edges && !has_edges {
+                chip.set_edge_handler(offset, None)?;
+            }
+            chip.configure(
+                offset,
+                &config.settings(),
+                config.value ^ config.active_low(),
+            )?;
+            if has_edges && !had_edges {
+                let events = self.events.clone();
+                let handler: EdgeHandler = Arc::new(move |edge| events.push(index, edge));
+                chip.set_edge_handler(offset, Some(handler))?;
+            }
+
+            let mut state = self.events.state.lock();
+            let line = &mut state.lines[index];
+            line.active_low = config.active_low();
+            line.rising = config.flags & GPIO_V2_LINE_FLAG_EDGE_RISING != 0;
+            line.falling = config.flags & GPIO_V2_LINE_FLAG_EDGE_FALLING != 0;
+            line.realtime = config.flags & GPIO_V2_LINE_FLAG_EVENT_CLOCK_REALTIME != 0;
+            drop(state);
+
+            let status = &mut self.device.lines.lock()[offset as usize];
+            status.flags = config.flags;
+            status.debounce_us = config.debounce_us;
+            configs[index] = *config;
+        }
+        Ok(())
+    }
+
+    fn get_values(&self, mask: u64) -> u64 {
+        let configs = self.configs.lock();
+        let mut bits = 0;
+        for (index, (&offset, config)) in self.offsets.iter().zip(configs.iter()).enumerate() {
+            if mask & (1 << index) != 0 && self.device.chip.get(offset) ^ config.active_low() {
+                bits |= 1 << index;
+            }
+        }
+        bits
+    }
+
+    fn set_values(&self, values: &GpioLineValues) -> AxResult {
+        let mut configs = self.configs.lock();
+        let selected = || (0..self.offsets.len()).filter(|index| values.mask & (1 << index) != 0);
+        if selected().any(|index| !configs[index].is_output()) {
+            return Err(AxError::OperationNotPermitted);
+        }
+        for index in selected() {
+            let config = &mut configs[index];
+            config.value = values.bits & (1 << index) != 0;
+            self.device
+                .chip
+                .set(self.offsets[index], config.value ^ config.active_low());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LineRequest {
+    fn drop(&mut self) {
+        let chip = &self.device.chip;
+        for (&offset, config) in self.offsets.iter().zip(self.configs.get_mut().iter()) {
+            if config.flags & GPIO_V2_LINE_EDGE_FLAGS != 0 {
+                let _ = chip.set_edge_handler(offset, None);
+            }
+        }
+        let mut lines = self.device.lines.lock();
+        for &offset in &self.offsets {
+            let status = &mut lines[offset as usize];
+            status.consumer = None;
+            status.flags &= !(GPIO_V2_LINE_EDGE_FLAGS | GPIO_V2_LINE_FLAG_EVENT_CLOCK_REALTIME);
+        }
+    }
+}
+
+impl FileLike for LineRequest {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        const EVENT_LEN: usize = size_of::<GpioLineEvent>();
+        if dst.remaining_mut() < EVENT_LEN {
+            return Err(AxError::InvalidInput);
+        }
+
+        Poller::new(self, IoEvents::IN)
+            .non_blocking(self.nonblocking())
+            .poll(|| {
+                let mut state = self.events.state.lock();
+                if state.events.is_empty() {
+                    return Err(AxError::WouldBlock);
+                }
+                let mut read = 0;
+                while dst.remaining_mut() >= EVENT_LEN
+                    && let Some(event) = state.events.pop_front()
+                {
+                    dst.write(bytemuck::bytes_of(&event))?;
+                    read += EVENT_LEN;
+                }
+                Ok(read)
+            })
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<str> {
+        "anon_inode:gpio-line".into()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        match cmd {
+            GPIO_V2_LINE_GET_VALUES_IOCTL => {
+                let mut values = (arg as *const GpioLineValues).vm_read()?;
+                if values.mask == 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                values.bits = self.get_values(values.mask);
+                (arg as *mut GpioLineValues).vm_write(values)?;
+            }
+            GPIO_V2_LINE_SET_VALUES_IOCTL => {
+                let values = (arg as *const GpioLineValues).vm_read()?;
+                if values.mask == 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                self.set_values(&values)?;
+            }
+            GPIO_V2_LINE_SET_CONFIG_IOCTL => {
+                let config = (arg as *const GpioLineConfig).vm_read()?;
+                self.apply(&parse_config(&config, self.offsets.len())?)?;
+            }
+            _ => return Err(AxError::BadIoctl),
+        }
+        Ok(0)
+    }
+}
+
+impl Pollable for LineRequest {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, !self.events.state.lock().events.is_empty());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.events.poll_rx.register(context.waker());
+        }
+    }
+}
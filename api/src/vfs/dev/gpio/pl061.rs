@@ -0,0 +1,233 @@
+//! The Arm PrimeCell PL061 GPIO controller, as found on the QEMU `virt`
+//! machine.
+//!
+//! It has 8 lines and no pull or drive strength control of its own; open
+//! drain and open source outputs are emulated by releasing the line as an
+//! input.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use axerrno::{AxError, AxResult};
+use kspin::SpinNoIrq;
+use memory_addr::PhysAddr;
+use starry_core::boot::{self, FdtDevice, FdtNode};
+
+use super::{Bias, Direction, Drive, Edge, EdgeHandler, GpioChip, LineSettings};
+
+const NUM_LINES: u32 = 8;
+
+/// The data register, of which address bits 9:2 mask the lines accessed.
+const GPIODATA: usize = 0x000;
+/// Direction, 1 for output.
+const GPIODIR: usize = 0x400;
+/// Interrupt sense, 1 for level.
+const GPIOIS: usize = 0x404;
+/// Interrupt on both edges.
+const GPIOIBE: usize = 0x408;
+/// Interrupt event, 1 for rising edges.
+const GPIOIEV: usize = 0x40c;
+/// Interrupt mask, 1 to enable.
+const GPIOIE: usize = 0x410;
+/// Masked interrupt status.
+const GPIOMIS: usize = 0x418;
+/// Interrupt clear.
+const GPIOIC: usize = 0x41c;
+
+struct State {
+    drive: [Drive; NUM_LINES as usize],
+    handlers: [Option<EdgeHandler>; NUM_LINES as usize],
+}
+
+pub struct Pl061 {
+    base: usize,
+    label: String,
+    names: Vec<String>,
+    /// Serializes the read-modify-write of the registers, also done by the
+    /// interrupt handler.
+    state: SpinNoIrq<State>,
+}
+
+impl Pl061 {
+    fn read(&self, reg: usize) -> u32 {
+        // SAFETY: the registers are mapped by `probe`.
+        unsafe { ((self.base + reg) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        // SAFETY: the registers are mapped by `probe`.
+        unsafe { ((self.base + reg) as *mut u32).write_volatile(value) }
+    }
+
+    fn modify(&self, reg: usize, bit: u32, set: bool) {
+        let value = self.read(reg);
+        self.write(reg, if set { value | bit } else { value & !bit });
+    }
+
+    /// Drives a line, or releases it as an input for the levels an open
+    /// drain or open source output doesn't drive.
+    fn drive(&self, drive: Drive, offset: u32, value: bool) {
+        let bit = 1 << offset;
+        let release = match drive {
+            Drive::PushPull => false,
+            Drive::OpenDrain => value,
+            Drive::OpenSource => !value,
+        };
+        if release {
+            self.modify(GPIODIR, bit, false);
+        } else {
+            self.write(
+                GPIODATA + ((bit as usize) << 2),
+                if value { bit } else { 0 },
+            );
+            self.modify(GPIODIR, bit, true);
+        }
+    }
+
+    /// Reports and acknowledges the pending edges.
+    fn handle_irq(&self) {
+        let mut pending = Vec::new();
+        {
+            let state = self.state.lock();
+            let status = self.read(GPIOMIS);
+            self.write(GPIOIC, status);
+            let both = self.read(GPIOIBE);
+            let rising = self.read(GPIOIEV);
+            let level = self.read(GPIODATA + (0xff << 2));
+            for offset in 0..NUM_LINES {
+                let bit = 1 << offset;
+                if status & bit == 0 {
+                    continue;
+                }
+                // The hardware doesn't tell which edge it saw when both are
+                // enabled, so go by the level now.
+                let rose = if both & bit != 0 {
+                    level & bit != 0
+                } else {
+                    rising & bit != 0
+                };
+                let edge = if rose { Edge::Rising } else { Edge::Falling };
+                if let Some(handler) = &state.handlers[offset as usize] {
+                    pending.push((handler.clone(), edge));
+                }
+            }
+        }
+        for (handler, edge) in pending {
+            handler(edge);
+        }
+    }
+}
+
+impl GpioChip for Pl061 {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn num_lines(&self) -> u32 {
+        NUM_LINES
+    }
+
+    fn line_name(&self, offset: u32) -> Option<&str> {
+        self.names
+            .get(offset as usize)
+            .map(String::as_str)
+            .filter(|name| !name.is_empty())
+    }
+
+    fn configure(&self, offset: u32, settings: &LineSettings, value: bool) -> AxResult {
+        if offset >= NUM_LINES {
+            return Err(AxError::InvalidInput);
+        }
+        if settings.bias != Bias::AsIs || settings.debounce_us != 0 {
+            return Err(AxError::Unsupported);
+        }
+        let bit = 1 << offset;
+        let mut state = self.state.lock();
+        self.modify(GPIOIE, bit, false);
+        match settings.direction {
+            Direction::Input => self.modify(GPIODIR, bit, false),
+            Direction::Output => self.drive(settings.drive, offset, value),
+        }
+        state.drive[offset as usize] = settings.drive;
+
+        if settings.rising || settings.falling {
+            self.modify(GPIOIS, bit, false);
+            self.modify(GPIOIBE, bit, settings.rising && settings.falling);
+            self.modify(GPIOIEV, bit, settings.rising);
+            // Changing the sense may have latched a spurious edge.
+            self.write(GPIOIC, bit);
+            self.modify(GPIOIE, bit, true);
+        }
+        Ok(())
+    }
+
+    fn get(&self, offset: u32) -> bool {
+        let bit = 1 << offset;
+        self.read(GPIODATA + ((bit as usize) << 2)) & bit != 0
+    }
+
+    fn set(&self, offset: u32, value: bool) {
+        let state = self.state.lock();
+        self.drive(state.drive[offset as usize], offset, value);
+    }
+
+    fn set_edge_handler(&self, offset: u32, handler: Option<EdgeHandler>) -> AxResult {
+        let mut state = self.state.lock();
+        let slot = state
+            .handlers
+            .get_mut(offset as usize)
+            .ok_or(AxError::InvalidInput)?;
+        *slot = handler;
+        Ok(())
+    }
+}
+
+static CHIPS: SpinNoIrq<Vec<Arc<Pl061>>> = SpinNoIrq::new(Vec::new());
+
+fn handle_irq() {
+    // Handlers aren't told which interrupt fired, and the controllers may
+    // share one, so ask all of them.
+    let chips = CHIPS.lock().clone();
+    for chip in chips {
+        chip.handle_irq();
+    }
+}
+
+fn add(dev: &FdtDevice) -> AxResult<Arc<Pl061>> {
+    let &(paddr, size) = dev.regs().first().ok_or(AxError::InvalidData)?;
+    let base = starry_core::mm::ioremap(PhysAddr::from(paddr as usize), size as usize)?;
+    let chip = Arc::new(Pl061 {
+        base: base.as_usize(),
+        label: dev.node.name.clone(),
+        names: dev
+            .node
+            .strings("gpio-line-names")
+            .map(String::from)
+            .collect(),
+        state: SpinNoIrq::new(State {
+            drive: [Drive::PushPull; NUM_LINES as usize],
+            handlers: Default::default(),
+        }),
+    });
+    // Start out with all interrupts masked and cleared.
+    chip.write(GPIOIE, 0);
+    chip.write(GPIOIC, 0xff);
+    CHIPS.lock().push(chip.clone());
+    if let Some(irq) = dev.irq(0)
+        && !axhal::irq::register(irq, handle_irq)
+    {
+        warn!("gpio: failed to register handler for IRQ {irq}, which may be shared");
+    }
+    Ok(chip)
+}
+
+/// Finds the controllers in the device tree.
+pub fn probe() -> Vec<(&'static FdtNode, Arc<dyn GpioChip>)> {
+    let mut chips = Vec::new();
+    for dev in boot::find_compatible(&["arm,pl061"]) {
+        match add(&dev) {
+            Ok(chip) => chips.push((dev.node, chip as _)),
+            Err(err) => warn!("gpio: failed to set up {}: {err:?}", dev.node.name),
+        }
+    }
+    chips
+}
@@ -0,0 +1,554 @@
+//! The hypervisor control device, `/dev/starry-hv`.
+//!
+//! A userspace VMM drives guests through ioctls modelled after KVM's:
+//! `HV_CREATE_VM` on the device returns a VM file descriptor, which takes the
+//! guest memory layout through `HV_SET_MEMORY_REGION` and creates vCPU file
+//! descriptors with `HV_CREATE_VCPU`. `HV_RUN` on a vCPU runs the guest until
+//! it needs the VMM, e.g. for an MMIO or port access, and reports why in a
+//! `struct hv_run`.
+//!
+//! Guest memory comes from shared anonymous mappings of the VMM: the pages
+//! backing a region are faulted in and mapped into the guest when the region
+//! is set, and the region holds on to them until it is deleted. Unlike KVM,
+//! changes the VMM makes to its mapping afterwards, such as unmapping it, are
+//! not seen by the guest.
+//!
+//! Running guests needs hardware virtualization, which platform code provides
+//! by registering a [`HvBackend`] with [`register`]. [`probe`] registers the
+//! RISC-V hypervisor extension when the harts have it. Without a backend,
+//! the device only answers `HV_GET_API_VERSION`.
+
+use alloc::{borrow::Cow, boxed::Box, format, sync::Arc, vec::Vec};
+use core::{any::Any, task::Context};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng_vfs::{DeviceId, NodeFlags, VfsError, VfsResult};
+use axhal::{mem::PhysAddr, paging::MappingFlags};
+use axmm::{
+    AddrSpace,
+    backend::{Backend, SharedPages},
+};
+use axpoll::{IoEvents, Pollable};
+use axtask::current;
+use bytemuck::AnyBitPattern;
+use memory_addr::{PAGE_SIZE_4K, VirtAddr, is_aligned_4k};
+use spin::{Mutex, RwLock};
+use starry_core::task::AsThread;
+use starry_signal::Signo;
+use starry_vm::{VmMutPtr, VmPtr};
+
+use crate::{
+    file::{FileLike, Kstat, SealedBuf, SealedBufMut, add_file_like},
+    vfs::DeviceOps,
+};
+
+#[cfg(target_arch = "riscv64")]
+mod riscv;
+
+/// The device ID of `/dev/starry-hv`, the misc minor of `/dev/kvm`.
+pub const HV_DEVICE_ID: DeviceId = DeviceId::new(10, 232);
+
+/// The version of the ioctl interface.
+const HV_API_VERSION: usize = 1;
+
+const HV_GET_API_VERSION: u32 = 0x0000_b500;
+const HV_CREATE_VM: u32 = 0x0000_b501;
+const HV_CREATE_VCPU: u32 = 0x0000_b541;
+const HV_SET_MEMORY_REGION: u32 = 0x4020_b546;
+const HV_RUN: u32 = 0xc058_b580;
+const HV_GET_REGS: u32 = 0x8108_b581;
+const HV_SET_REGS: u32 = 0x4108_b582;
+
+/// The region is mapped read-only into the guest; writes exit as MMIO.
+const HV_MEM_READONLY: u32 = 1 << 1;
+
+const HV_EXIT_UNKNOWN: u32 = 0;
+const HV_EXIT_IO: u32 = 2;
+const HV_EXIT_HYPERCALL: u32 = 3;
+const HV_EXIT_HLT: u32 = 5;
+const HV_EXIT_MMIO: u32 = 6;
+const HV_EXIT_SHUTDOWN: u32 = 8;
+const HV_EXIT_FAIL_ENTRY: u32 = 9;
+const HV_EXIT_INTR: u32 = 10;
+
+/// The most memory slots a VM has.
+const MAX_MEMORY_SLOTS: usize = 32;
+/// The most vCPUs a VM has.
+const MAX_VCPUS: u32 = 64;
+
+/// `struct hv_memory_region`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AnyBitPattern)]
+struct HvMemoryRegion {
+    slot: u32,
+    flags: u32,
+    guest_phys_addr: u64,
+    /// The size of the region, 0 to delete the slot.
+    memory_size: u64,
+    userspace_addr: u64,
+}
+
+/// `struct hv_regs`, the general purpose registers of a vCPU.
+///
+/// The registers are numbered as in the architecture's own encoding: `x0`
+/// to `x30` on AArch64, `x1` to `x31` on RISC-V (with `gprs[0]` being
+/// `x1`), and `rax`, `rcx`, `rdx`, `rbx`, `rsp`, `rbp`, `rsi`, `rdi`, `r8`
+/// to `r15` on x86_64.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, AnyBitPattern)]
+pub struct HvRegs {
+    pub pc: u64,
+    pub flags: u64,
+    pub gprs: [u64; 31],
+}
+
+/// `struct hv_run`, where `HV_RUN` reports why the guest stopped.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, AnyBitPattern)]
+struct HvRun {
+    exit_reason: u32,
+    /// The access width in bytes, for MMIO and port I/O.
+    size: u32,
+    /// The guest physical address, the port or the hypercall number.
+    addr: u64,
+    /// The data written by the guest. For reads, the VMM stores the result
+    /// here before running the vCPU again.
+    data: u64,
+    is_write: u8,
+    _pad: [u8; 7],
+    /// The hypercall arguments, or the hardware entry failure reason in
+    /// `args[0]`.
+    args: [u64; 6],
+    /// The hypercall result, set by the VMM.
+    ret: u64,
+}
+
+/// Why a vCPU stopped running the guest.
+#[derive(Debug, Clone, Copy)]
+pub enum VcpuExit {
+    /// The guest accessed guest physical memory not backed by a region, or
+    /// wrote to a read-only region.
+    Mmio {
+        addr: u64,
+        size: u8,
+        /// The value written, or `None` for a read.
+        write: Option<u64>,
+    },
+    /// The guest accessed an I/O port.
+    Io {
+        port: u16,
+        size: u8,
+        write: Option<u32>,
+    },
+    /// The guest made a hypercall to the VMM.
+    Hypercall { nr: u64, args: [u64; 6] },
+    /// The guest halted until the next interrupt.
+    Halt,
+    /// The guest powered off or reset.
+    Shutdown,
+    /// The host took an interrupt; the guest can be resumed right away.
+    Preempted,
+    /// The hardware refused to enter the guest.
+    FailEntry(u64),
+    /// Anything else.
+    Unknown,
+}
+
+/// A virtual CPU.
+pub trait GuestVcpu: Send {
+    fn regs(&self) -> HvRegs;
+
+    fn set_regs(&mut self, regs: &HvRegs);
+
+    /// Runs the guest until it exits.
+    fn run(&mut self) -> AxResult<VcpuExit>;
+
+    /// Completes the MMIO or port read, or the hypercall, of the last exit
+    /// with `value`.
+    fn complete(&mut self, value: u64);
+}
+
+/// A virtual machine: the guest physical address space and its vCPUs.
+pub trait GuestVm: Send {
+    /// Maps `size` bytes of guest physical memory at `gpa` to host
+    /// physical memory at `hpa`.
+    fn map(&mut self, gpa: u64, hpa: PhysAddr, size: usize, writable: bool) -> AxResult;
+
+    /// Unmaps `size` bytes of guest physical memory at `gpa`.
+    fn unmap(&mut self, gpa: u64, size: usize) -> AxResult;
+
+    fn create_vcpu(&mut self, id: u32) -> AxResult<Box<dyn GuestVcpu>>;
+}
+
+/// Hardware virtualization support.
+pub trait HvBackend: Send + Sync {
+    /// Returns the name of the virtualization extension, for logging.
+    fn name(&self) -> &str;
+
+    fn create_vm(&self) -> AxResult<Box<dyn GuestVm>>;
+}
+
+static BACKEND: RwLock<Option<Arc<dyn HvBackend>>> = RwLock::new(None);
+
+/// Registers the hardware virtualization support to back `/dev/starry-hv`.
+pub fn register(backend: Arc<dyn HvBackend>) {
+    info!("hv: using {}", backend.name());
+    *BACKEND.write() = Some(backend);
+}
+
+/// Registers the hardware virtualization support of the platform, if any.
+pub fn probe() {
+    #[cfg(target_arch = "riscv64")]
+    if let Some(backend) = riscv::probe() {
+        register(backend);
+    }
+}
+
+/// Returns whether the current thread has a signal to handle, which makes
+/// `HV_RUN` return to the VMM.
+fn signal_pending() -> bool {
+    let curr = current();
+    let signal = &curr.as_thread().signal;
+    let pending = signal.pending();
+    let blocked = signal.blocked();
+    (1..=64)
+        .filter_map(Signo::from_repr)
+        .any(|signo| pending.has(signo) && !blocked.has(signo))
+}
+
+/// `/dev/starry-hv`.
+pub struct HvDevice;
+
+impl DeviceOps for HvDevice {
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        match cmd {
+            HV_GET_API_VERSION => Ok(HV_API_VERSION),
+            HV_CREATE_VM => {
+                if arg != 0 {
+                    return Err(VfsError::InvalidInput);
+                }
+                let backend = BACKEND
+                    .read()
+                    .clone()
+                    .ok_or(AxError::Other(LinuxError::ENXIO))?;
+                let vm = Arc::new(Vm {
+                    inner: Mutex::new(backend.create_vm()?),
+                    regions: Mutex::new(Vec::new()),
+                    vcpus: Mutex::new(Vec::new()),
+                });
+                Ok(add_file_like(vm, true)? as usize)
+            }
+            _ => Err(VfsError::BadIoctl),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+struct Region {
+    slot: u32,
+    gpa: u64,
+    size: usize,
+    /// The pages backing the region, kept allocated for the guest.
+    _pages: Vec<Arc<SharedPages>>,
+}
+
+/// Returns the pages backing `size` bytes of the VMM's memory at `uva`.
+///
+/// Only shared anonymous memory can back a region: the frames of other
+/// mappings can be freed or replaced behind the guest's back, e.g. by
+/// copy-on-write or by the page cache dropping them.
+fn backing_pages(
+    aspace: &AddrSpace,
+    uva: VirtAddr,
+    size: usize,
+) -> AxResult<Vec<Arc<SharedPages>>> {
+    let mut pages = Vec::new();
+    let mut addr = uva;
+    while addr < uva + size {
+        let area = aspace.find_area(addr).ok_or(AxError::BadAddress)?;
+        let Backend::Shared(backend) = area.backend() else {
+            return Err(AxError::InvalidInput);
+        };
+        pages.push(backend.pages().clone());
+        addr = area.end();
+    }
+    Ok(pages)
+}
+
+/// A VM file descriptor.
+struct Vm {
+    inner: Mutex<Box<dyn GuestVm>>,
+    regions: Mutex<Vec<Region>>,
+    vcpus: Mutex<Vec<u32>>,
+}
+
+impl Vm {
+    fn set_memory_region(&self, region: &HvMemoryRegion) -> AxResult {
+        if region.slot as usize >= MAX_MEMORY_SLOTS || region.flags & !HV_MEM_READONLY != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let size = region.memory_size as usize;
+        let gpa = region.guest_phys_addr;
+        let uva = VirtAddr::from(region.userspace_addr as usize);
+        if !is_aligned_4k(gpa as usize) || !is_aligned_4k(size) || !uva.is_aligned_4k() {
+            return Err(AxError::InvalidInput);
+        }
+
+        let mut vm = self.inner.lock();
+        let mut regions = self.regions.lock();
+        if let Some(index) = regions.iter().position(|it| it.slot == region.slot) {
+            // Like KVM, a slot is moved or resized by deleting it first.
+            if size != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            let old = regions.swap_remove(index);
+            return vm.unmap(old.gpa, old.size);
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        let end = gpa.checked_add(size as u64).ok_or(AxError::InvalidInput)?;
+        if regions
+            .iter()
+            .any(|it| gpa < it.gpa + it.size as u64 && it.gpa < end)
+        {
+            return Err(AxError::AlreadyExists);
+        }
+
+        let writable = region.flags & HV_MEM_READONLY == 0;
+        let mut access = MappingFlags::READ;
+        if writable {
+            access |= MappingFlags::WRITE;
+        }
+        let curr = current();
+        let mut aspace = curr.as_thread().proc_data.aspace.lock();
+        if !aspace.can_access_range(uva, size, access) {
+            return Err(AxError::BadAddress);
+        }
+        let pages = backing_pages(&aspace, uva, size)?;
+        aspace.populate_area(uva, size, access)?;
+        for offset in (0..size).step_by(PAGE_SIZE_4K) {
+            let mapped = aspace
+                .page_table()
+                .query(uva + offset)
+                .map_err(|_| AxError::BadAddress)
+                .and_then(|(hpa, ..)| vm.map(gpa + offset as u64, hpa, PAGE_SIZE_4K, writable));
+            if let Err(err) = mapped {
+                if offset > 0 {
+                    let _ = vm.unmap(gpa, offset);
+                }
+                return Err(err);
+            }
+        }
+        regions.push(Region {
+            slot: region.slot,
+            gpa,
+            size,
+            _pages: pages,
+        });
+        Ok(())
+    }
+
+    fn create_vcpu(&self, id: u32) -> AxResult<usize> {
+        if id >= MAX_VCPUS {
+            return Err(AxError::InvalidInput);
+        }
+        let mut vcpus = self.vcpus.lock();
+        if vcpus.contains(&id) {
+            return Err(AxError::AlreadyExists);
+        }
+        let vcpu = Arc::new(Vcpu {
+            inner: Mutex::new(self.inner.lock().create_vcpu(id)?),
+            pending: Mutex::new(None),
+        });
+        let fd = add_file_like(vcpu, true)?;
+        vcpus.push(id);
+        Ok(fd as usize)
+    }
+}
+
+impl FileLike for Vm {
+    fn read(&self, _dst: &mut SealedBufMut) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        "anon_inode:hv-vm".into()
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        match cmd {
+            HV_SET_MEMORY_REGION => {
+                let region = (arg as *const HvMemoryRegion).vm_read()?;
+                self.set_memory_region(&region)?;
+                Ok(0)
+            }
+            HV_CREATE_VCPU => self.create_vcpu(arg as u32),
+            _ => Err(AxError::BadIoctl),
+        }
+    }
+}
+
+impl Pollable for Vm {
+    fn poll(&self) -> IoEvents {
+        IoEvents::empty()
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+/// What the VMM has to answer before the vCPU runs again.
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    /// An MMIO or port read, answered in `data`.
+    Read,
+    /// A hypercall, answered in `ret`.
+    Hypercall,
+}
+
+/// A vCPU file descriptor.
+struct Vcpu {
+    inner: Mutex<Box<dyn GuestVcpu>>,
+    pending: Mutex<Option<Pending>>,
+}
+
+impl Vcpu {
+    fn run(&self, ptr: *mut HvRun) -> AxResult {
+        let mut run = ptr.vm_read()?;
+        // Another thread running the same vCPU gets `EBUSY`, as with KVM.
+        let mut vcpu = self.inner.try_lock().ok_or(AxError::ResourceBusy)?;
+        match self.pending.lock().take() {
+            Some(Pending::Read) => vcpu.complete(run.data),
+            Some(Pending::Hypercall) => vcpu.complete(run.ret),
+            None => {}
+        }
+
+        let exit = loop {
+            if signal_pending() {
+                run = HvRun {
+                    exit_reason: HV_EXIT_INTR,
+                    ..Default::default()
+                };
+                ptr.vm_write(run)?;
+                return Err(AxError::Interrupted);
+            }
+            match vcpu.run()? {
+                VcpuExit::Preempted => axtask::yield_now(),
+                exit => break exit,
+            }
+        };
+
+        run = HvRun::default();
+        let mut pending = None;
+        match exit {
+            VcpuExit::Mmio { addr, size, write } => {
+                run.exit_reason = HV_EXIT_MMIO;
+                run.addr = addr;
+                run.size = size as _;
+                run.is_write = write.is_some() as _;
+                run.data = write.unwrap_or(0);
+                pending = write.is_none().then_some(Pending::Read);
+            }
+            VcpuExit::Io { port, size, write } => {
+                run.exit_reason = HV_EXIT_IO;
+                run.addr = port as _;
+                run.size = size as _;
+                run.is_write = write.is_some() as _;
+                run.data = write.unwrap_or(0) as _;
+                pending = write.is_none().then_some(Pending::Read);
+            }
+            VcpuExit::Hypercall { nr, args } => {
+                run.exit_reason = HV_EXIT_HYPERCALL;
+                run.addr = nr;
+                run.args = args;
+                pending = Some(Pending::Hypercall);
+            }
+            VcpuExit::Halt => run.exit_reason = HV_EXIT_HLT,
+            VcpuExit::Shutdown => run.exit_reason = HV_EXIT_SHUTDOWN,
+            VcpuExit::FailEntry(reason) => {
+                run.exit_reason = HV_EXIT_FAIL_ENTRY;
+                run.args[0] = reason;
+            }
+            VcpuExit::Preempted | VcpuExit::Unknown => run.exit_reason = HV_EXIT_UNKNOWN,
+        }
+        *self.pending.lock() = pending;
+        ptr.vm_write(run)?;
+        Ok(())
+    }
+}
+
+impl FileLike for Vcpu {
+    fn read(&self, _dst: &mut SealedBufMut) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        format!("anon_inode:hv-vcpu:{}", self as *const _ as usize).into()
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        match cmd {
+            HV_RUN => self.run(arg as *mut HvRun)?,
+            HV_GET_REGS => {
+                let regs = self.inner.try_lock().ok_or(AxError::ResourceBusy)?.regs();
+                (arg as *mut HvRegs).vm_write(regs)?;
+            }
+            HV_SET_REGS => {
+                let regs = (arg as *const HvRegs).vm_read()?;
+                self.inner
+                    .try_lock()
+                    .ok_or(AxError::ResourceBusy)?
+                    .set_regs(&regs);
+            }
+            _ => return Err(AxError::BadIoctl),
+        }
+        Ok(0)
+    }
+}
+
+impl Pollable for Vcpu {
+    fn poll(&self) -> IoEvents {
+        IoEvents::empty()
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
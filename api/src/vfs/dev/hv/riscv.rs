@@ -0,0 +1,760 @@
+//! The RISC-V hypervisor extension.
+//!
+//! Guests run in VS-mode, with their guest physical memory translated by an
+//! Sv39x4 G-stage table. While a guest runs, the traps of the hart go to a
+//! vector of our own, which stores the guest registers and returns to where
+//! the guest was entered, so any trap ends a run: host interrupts make the
+//! vCPU yield, and everything else is handled here or reported to the VMM.
+//!
+//! The VMM provides the SBI of the guest, whose calls exit as hypercalls,
+//! except for the timer, which is emulated here with `hvip`, and system
+//! reset, which shuts the guest down.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{arch::global_asm, mem::offset_of, time::Duration};
+
+use axerrno::{AxError, AxResult};
+use axhal::{
+    mem::{PhysAddr, phys_to_virt, virt_to_phys},
+    time::{current_ticks, ticks_to_nanos},
+};
+use memory_addr::PAGE_SIZE_4K;
+use spin::RwLock;
+use starry_core::boot;
+
+use super::{GuestVcpu, GuestVm, HvBackend, HvRegs, VcpuExit};
+
+macro_rules! csr_read {
+    ($csr:literal) => {{
+        let value: usize;
+        unsafe { core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! csr_write {
+    ($csr:literal, $value:expr) => {
+        unsafe { core::arch::asm!(concat!("csrw ", $csr, ", {}"), in(reg) $value) }
+    };
+}
+
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+const SSTATUS_FS_DIRTY: usize = 3 << 13;
+
+const HSTATUS_SPV: usize = 1 << 7;
+const HSTATUS_SPVP: usize = 1 << 8;
+const HSTATUS_VTW: usize = 1 << 21;
+
+/// The exceptions the guest handles itself: misaligned fetches, illegal
+/// instructions, breakpoints, misaligned loads and stores, `ecall`s from
+/// VU-mode and page faults.
+const HEDELEG: usize =
+    1 << 0 | 1 << 2 | 1 << 3 | 1 << 4 | 1 << 6 | 1 << 8 | 1 << 12 | 1 << 13 | 1 << 15;
+/// The VS-level software, timer and external interrupts.
+const HIDELEG: usize = 1 << 2 | 1 << 6 | 1 << 10;
+/// The guest reads `cycle`, `time` and `instret` directly.
+const HCOUNTEREN: usize = 0b111;
+const HVIP_VSTIP: usize = 1 << 6;
+
+const HGATP_MODE_SHIFT: usize = 60;
+const HGATP_MODE_SV39X4: usize = 8;
+/// The guest physical address bits Sv39x4 translates.
+const GPA_BITS: u32 = 41;
+
+const SCAUSE_INTERRUPT: usize = 1 << 63;
+const EXC_VS_ECALL: usize = 10;
+const EXC_LOAD_GUEST_PAGE_FAULT: usize = 21;
+const EXC_VIRTUAL_INSTRUCTION: usize = 22;
+const EXC_STORE_GUEST_PAGE_FAULT: usize = 23;
+
+const INSN_WFI: u32 = 0x1050_0073;
+
+const SBI_EXT_LEGACY_SET_TIMER: usize = 0x00;
+const SBI_EXT_LEGACY_SHUTDOWN: usize = 0x08;
+/// The first extension ID of the SBI calling convention, below which calls
+/// return their value in `a0` alone.
+const SBI_EXT_BASE: usize = 0x10;
+const SBI_EXT_TIME: usize = 0x5449_4d45;
+const SBI_EXT_SRST: usize = 0x5352_5354;
+const SBI_TIME_SET_TIMER: usize = 0;
+const SBI_SRST_SYSTEM_RESET: usize = 0;
+const SBI_SUCCESS: usize = 0;
+
+/// How long a halted vCPU waiting for its timer sleeps at most, before going
+/// back to `HV_RUN` to look for signals.
+const HALT_SLICE: Duration = Duration::from_millis(10);
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+/// Set on all G-stage leaves, which are checked as if accessed from U-mode.
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// The registers switched by `_hv_enter_guest` and `_hv_exit_guest`.
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    /// The host registers, of which only the callee-saved ones are kept.
+    host: [usize; 32],
+    /// The guest registers, `x0` included to keep the numbering.
+    guest: [usize; 32],
+    host_sstatus: usize,
+    host_hstatus: usize,
+    host_stvec: usize,
+    host_sscratch: usize,
+    guest_sstatus: usize,
+    guest_hstatus: usize,
+    guest_sepc: usize,
+}
+
+global_asm!(
+    r"
+    .section .text
+    .balign 4
+    .global _hv_enter_guest
+_hv_enter_guest:
+    .irp n, 1, 2, 3, 4, 8, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27
+    sd x\n, ({host} + \n * 8)(a0)
+    .endr
+
+    csrr t0, sstatus
+    sd t0, {host_sstatus}(a0)
+    ld t0, {guest_sstatus}(a0)
+    csrw sstatus, t0
+    csrr t0, hstatus
+    sd t0, {host_hstatus}(a0)
+    ld t0, {guest_hstatus}(a0)
+    csrw hstatus, t0
+    ld t0, {guest_sepc}(a0)
+    csrw sepc, t0
+    csrr t0, stvec
+    sd t0, {host_stvec}(a0)
+    la t0, _hv_exit_guest
+    csrw stvec, t0
+    csrr t0, sscratch
+    sd t0, {host_sscratch}(a0)
+    csrw sscratch, a0
+
+    .irp n, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    ld x\n, ({guest} + \n * 8)(a0)
+    .endr
+    ld a0, ({guest} + 10 * 8)(a0)
+    sret
+
+    .balign 4
+_hv_exit_guest:
+    csrrw a0, sscratch, a0
+    .irp n, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    sd x\n, ({guest} + \n * 8)(a0)
+    .endr
+    csrr t0, sscratch
+    sd t0, ({guest} + 10 * 8)(a0)
+    csrr t0, sepc
+    sd t0, {guest_sepc}(a0)
+
+    ld t0, {host_sstatus}(a0)
+    csrw sstatus, t0
+    ld t0, {host_hstatus}(a0)
+    csrw hstatus, t0
+    ld t0, {host_stvec}(a0)
+    csrw stvec, t0
+    ld t0, {host_sscratch}(a0)
+    csrw sscratch, t0
+    .irp n, 1, 2, 3, 4, 8, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27
+    ld x\n, ({host} + \n * 8)(a0)
+    .endr
+    ret
+",
+    host = const offset_of!(Context, host),
+    guest = const offset_of!(Context, guest),
+    host_sstatus = const offset_of!(Context, host_sstatus),
+    host_hstatus = const offset_of!(Context, host_hstatus),
+    host_stvec = const offset_of!(Context, host_stvec),
+    host_sscratch = const offset_of!(Context, host_sscratch),
+    guest_sstatus = const offset_of!(Context, guest_sstatus),
+    guest_hstatus = const offset_of!(Context, guest_hstatus),
+    guest_sepc = const offset_of!(Context, guest_sepc),
+);
+
+unsafe extern "C" {
+    /// Runs the guest of `ctx` until it traps. Interrupts must be disabled.
+    fn _hv_enter_guest(ctx: *mut Context);
+}
+
+/// Flushes the G-stage translations of all VMs.
+fn hfence_gvma() {
+    // `hfence.gvma zero, zero`, spelled out for assemblers without `+h`.
+    unsafe { core::arch::asm!(".insn r 0x73, 0x0, 0x31, x0, x0, x0") };
+}
+
+/// The floating-point registers, which the guest uses as its own.
+#[repr(C)]
+#[derive(Default)]
+struct FpRegs {
+    f: [u64; 32],
+    fcsr: usize,
+}
+
+impl FpRegs {
+    /// Saves the registers of the hart. `sstatus.FS` must not be off.
+    fn save(&mut self) {
+        unsafe {
+            core::arch::asm!(
+                r"
+                .irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+                fsd f\n, (\n * 8)({f})
+                .endr
+                frcsr {fcsr}
+                ",
+                f = in(reg) self.f.as_mut_ptr(),
+                fcsr = out(reg) self.fcsr,
+            )
+        }
+    }
+
+    /// Loads the registers into the hart. `sstatus.FS` must not be off.
+    fn restore(&self) {
+        unsafe {
+            core::arch::asm!(
+                r"
+                .irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+                fld f\n, (\n * 8)({f})
+                .endr
+                fscsr {fcsr}
+                ",
+                f = in(reg) self.f.as_ptr(),
+                fcsr = in(reg) self.fcsr,
+            )
+        }
+    }
+}
+
+/// The VS-level CSRs, which hold the supervisor state of the guest.
+#[derive(Default)]
+struct VsCsrs {
+    vsstatus: usize,
+    vsie: usize,
+    vstvec: usize,
+    vsscratch: usize,
+    vsepc: usize,
+    vscause: usize,
+    vstval: usize,
+    vsatp: usize,
+}
+
+impl VsCsrs {
+    fn save(&mut self) {
+        self.vsstatus = csr_read!("vsstatus");
+        self.vsie = csr_read!("vsie");
+        self.vstvec = csr_read!("vstvec");
+        self.vsscratch = csr_read!("vsscratch");
+        self.vsepc = csr_read!("vsepc");
+        self.vscause = csr_read!("vscause");
+        self.vstval = csr_read!("vstval");
+        self.vsatp = csr_read!("vsatp");
+    }
+
+    fn restore(&self) {
+        csr_write!("vsstatus", self.vsstatus);
+        csr_write!("vsie", self.vsie);
+        csr_write!("vstvec", self.vstvec);
+        csr_write!("vsscratch", self.vsscratch);
+        csr_write!("vsepc", self.vsepc);
+        csr_write!("vscause", self.vscause);
+        csr_write!("vstval", self.vstval);
+        csr_write!("vsatp", self.vsatp);
+    }
+}
+
+/// Allocates a zeroed page table of `pages` pages, aligned to its size.
+fn alloc_table(pages: usize) -> AxResult<usize> {
+    let vaddr = axalloc::global_allocator()
+        .alloc_pages(pages, pages * PAGE_SIZE_4K)
+        .map_err(|_| AxError::NoMemory)?;
+    // SAFETY: the pages were just allocated.
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, pages * PAGE_SIZE_4K) };
+    Ok(vaddr)
+}
+
+/// The Sv39x4 G-stage page table of a VM, mapping 4K pages.
+struct GStage {
+    /// The 16K root table.
+    root: usize,
+    /// The tables below the root.
+    tables: Vec<usize>,
+}
+
+impl GStage {
+    fn new() -> AxResult<Self> {
+        Ok(Self {
+            root: alloc_table(4)?,
+            tables: Vec::new(),
+        })
+    }
+
+    fn hgatp(&self) -> usize {
+        HGATP_MODE_SV39X4 << HGATP_MODE_SHIFT | virt_to_phys(self.root.into()).as_usize() >> 12
+    }
+
+    /// Returns the leaf entry for `gpa`, allocating the tables down to it if
+    /// `alloc` is set.
+    fn leaf(&mut self, gpa: u64, alloc: bool) -> AxResult<Option<&mut u64>> {
+        if gpa >> GPA_BITS != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let mut table = self.root;
+        // The root index has two more bits than the others.
+        for (shift, mask) in [(30, 0x7ff), (21, 0x1ff)] {
+            // SAFETY: the index is within the table.
+            let pte = unsafe { &mut *(table as *mut u64).add((gpa >> shift) as usize & mask) };
+            if *pte & PTE_V == 0 {
+                if !alloc {
+                    return Ok(None);
+                }
+                let next = alloc_table(1)?;
+                self.tables.push(next);
+                *pte = (virt_to_phys(next.into()).as_usize() as u64 >> 12) << 10 | PTE_V;
+            }
+            table = phys_to_virt(PhysAddr::from(((*pte >> 10) << 12) as usize)).as_usize();
+        }
+        // SAFETY: as above.
+        Ok(Some(unsafe {
+            &mut *(table as *mut u64).add((gpa >> 12) as usize & 0x1ff)
+        }))
+    }
+}
+
+impl Drop for GStage {
+    fn drop(&mut self) {
+        let allocator = axalloc::global_allocator();
+        for &table in &self.tables {
+            allocator.dealloc_pages(table, 1);
+        }
+        allocator.dealloc_pages(self.root, 4);
+    }
+}
+
+/// A VM, whose table its vCPUs share.
+///
+/// vCPUs hold the table for reading while in the guest, and it is only
+/// changed under the write lock, so no guest runs on stale translations
+/// after a change: each entry into a guest flushes them.
+struct RiscvVm {
+    table: Arc<RwLock<GStage>>,
+}
+
+impl GuestVm for RiscvVm {
+    fn map(&mut self, gpa: u64, hpa: PhysAddr, size: usize, writable: bool) -> AxResult {
+        let mut table = self.table.write();
+        let mut flags = PTE_V | PTE_R | PTE_X | PTE_U | PTE_A | PTE_D;
+        if writable {
+            flags |= PTE_W;
+        }
+        for offset in (0..size).step_by(PAGE_SIZE_4K) {
+            let pte = table.leaf(gpa + offset as u64, true)?.unwrap();
+            if *pte & PTE_V != 0 {
+                return Err(AxError::AlreadyExists);
+            }
+            *pte = ((hpa.as_usize() + offset) as u64 >> 12) << 10 | flags;
+        }
+        Ok(())
+    }
+
+    fn unmap(&mut self, gpa: u64, size: usize) -> AxResult {
+        let mut table = self.table.write();
+        for offset in (0..size).step_by(PAGE_SIZE_4K) {
+            if let Some(pte) = table.leaf(gpa + offset as u64, false)? {
+                *pte = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn create_vcpu(&mut self, _id: u32) -> AxResult<Box<dyn GuestVcpu>> {
+        Ok(Box::new(RiscvVcpu {
+            ctx: Context::default(),
+            vs: VsCsrs::default(),
+            guest_fp: FpRegs::default(),
+            host_fp: FpRegs::default(),
+            hvip: 0,
+            timer: None,
+            pending: None,
+            table: self.table.clone(),
+        }))
+    }
+}
+
+/// The trap that ended a run of the guest.
+struct Trap {
+    scause: usize,
+    stval: usize,
+    htval: usize,
+    htinst: usize,
+}
+
+/// A load or store of the guest to emulate.
+struct Access {
+    /// The register loaded or stored.
+    reg: usize,
+    size: u8,
+    signed: bool,
+    store: bool,
+}
+
+/// Decodes the loads and stores, compressed or not, that MMIO is done with.
+fn decode(insn: u32) -> Option<Access> {
+    let access = |reg, size, signed, store| {
+        Some(Access {
+            reg: reg as usize,
+            size,
+            signed,
+            store,
+        })
+    };
+    if insn & 3 == 3 {
+        let funct3 = insn >> 12 & 7;
+        let (rd, rs2) = (insn >> 7 & 0x1f, insn >> 20 & 0x1f);
+        return match (insn & 0x7f, funct3) {
+            (0x03, 0..=3) => access(rd, 1 << funct3, funct3 != 3, false),
+            (0x03, 4..=6) => access(rd, 1 << (funct3 - 4), false, false),
+            (0x23, 0..=3) => access(rs2, 1 << funct3, false, true),
+            _ => None,
+        };
+    }
+    let rd_rs2 = (insn >> 2 & 7) + 8;
+    match (insn & 3, insn >> 13 & 7) {
+        // C.LW, C.LD, C.SW and C.SD.
+        (0, 2) => access(rd_rs2, 4, true, false),
+        (0, 3) => access(rd_rs2, 8, false, false),
+        (0, 6) => access(rd_rs2, 4, false, true),
+        (0, 7) => access(rd_rs2, 8, false, true),
+        // C.LWSP, C.LDSP, C.SWSP and C.SDSP.
+        (2, 2) => access(insn >> 7 & 0x1f, 4, true, false),
+        (2, 3) => access(insn >> 7 & 0x1f, 8, false, false),
+        (2, 6) => access(insn >> 2 & 0x1f, 4, false, true),
+        (2, 7) => access(insn >> 2 & 0x1f, 8, false, true),
+        _ => None,
+    }
+}
+
+/// What the VMM has to answer before the vCPU runs again.
+enum Pending {
+    Load { reg: usize, size: u8, signed: bool },
+    Sbi { legacy: bool },
+}
+
+struct RiscvVcpu {
+    ctx: Context,
+    vs: VsCsrs,
+    guest_fp: FpRegs,
+    host_fp: FpRegs,
+    /// The interrupts injected into the guest.
+    hvip: usize,
+    /// When the guest timer fires, in ticks of `time`.
+    timer: Option<u64>,
+    pending: Option<Pending>,
+    table: Arc<RwLock<GStage>>,
+}
+
+impl RiscvVcpu {
+    fn gpr(&self, reg: usize) -> usize {
+        self.ctx.guest[reg]
+    }
+
+    fn set_gpr(&mut self, reg: usize, value: usize) {
+        if reg != 0 {
+            self.ctx.guest[reg] = value;
+        }
+    }
+
+    /// Enters the guest and returns the trap that brought us back.
+    fn enter(&mut self) -> Trap {
+        if let Some(deadline) = self.timer
+            && current_ticks() >= deadline
+        {
+            self.hvip |= HVIP_VSTIP;
+            self.timer = None;
+        }
+
+        let table = self.table.read();
+        let irqs = axhal::asm::irqs_enabled();
+        axhal::asm::disable_irqs();
+        csr_write!("hgatp", table.hgatp());
+        hfence_gvma();
+        csr_write!("hedeleg", HEDELEG);
+        csr_write!("hideleg", HIDELEG);
+        csr_write!("hcounteren", HCOUNTEREN);
+        csr_write!("hvip", self.hvip);
+        self.vs.restore();
+        unsafe { core::arch::asm!("csrs sstatus, {}", in(reg) SSTATUS_FS_DIRTY) };
+        self.host_fp.save();
+        self.guest_fp.restore();
+
+        self.ctx.guest_sstatus = (csr_read!("sstatus") | SSTATUS_SPP) & !SSTATUS_SPIE;
+        self.ctx.guest_hstatus = csr_read!("hstatus") | HSTATUS_SPV | HSTATUS_SPVP | HSTATUS_VTW;
+        // SAFETY: interrupts are disabled, and the context outlives the run.
+        unsafe { _hv_enter_guest(&mut self.ctx) };
+        let trap = Trap {
+            scause: csr_read!("scause"),
+            stval: csr_read!("stval"),
+            htval: csr_read!("htval"),
+            htinst: csr_read!("htinst"),
+        };
+
+        self.guest_fp.save();
+        self.host_fp.restore();
+        self.vs.save();
+        self.hvip = csr_read!("hvip");
+        drop(table);
+        if irqs {
+            axhal::asm::enable_irqs();
+        }
+        trap
+    }
+
+    /// Reads the instruction that trapped and its length.
+    fn instruction(&self, trap: &Trap) -> (u32, usize) {
+        // A transformed instruction, with bit 1 cleared if it was compressed.
+        if trap.htinst & 1 != 0 {
+            let len = if trap.htinst & 2 != 0 { 4 } else { 2 };
+            return (trap.htinst as u32 | 2, len);
+        }
+        let fetch = |addr: usize| {
+            let value: usize;
+            // `hlvx.hu`, with the guest's privilege in `hstatus.SPVP`. The
+            // guest just ran from there, so the page is mapped.
+            unsafe {
+                core::arch::asm!(
+                    "csrs hstatus, {spvp}",
+                    ".insn r 0x73, 0x4, 0x32, {value}, {addr}, x3",
+                    "csrc hstatus, {spvp}",
+                    spvp = in(reg) HSTATUS_SPVP,
+                    value = out(reg) value,
+                    addr = in(reg) addr,
+                )
+            };
+            value as u32
+        };
+        let sepc = self.ctx.guest_sepc;
+        let low = fetch(sepc);
+        if low & 3 != 3 {
+            return (low, 2);
+        }
+        (low | fetch(sepc + 2) << 16, 4)
+    }
+
+    fn mmio(&mut self, trap: &Trap) -> VcpuExit {
+        let addr = (trap.htval << 2 | trap.stval & 3) as u64;
+        let (insn, len) = self.instruction(trap);
+        let Some(access) = decode(insn) else {
+            warn!("hv: can't emulate {insn:#x} accessing {addr:#x}");
+            return VcpuExit::Unknown;
+        };
+        self.ctx.guest_sepc += len;
+        let Access {
+            reg,
+            size,
+            signed,
+            store,
+        } = access;
+        if store {
+            let value = self.gpr(reg) as u64 & (u64::MAX >> (64 - 8 * size as u32));
+            VcpuExit::Mmio {
+                addr,
+                size,
+                write: Some(value),
+            }
+        } else {
+            self.pending = Some(Pending::Load { reg, size, signed });
+            VcpuExit::Mmio {
+                addr,
+                size,
+                write: None,
+            }
+        }
+    }
+
+    fn set_timer(&mut self, deadline: usize) {
+        self.timer = Some(deadline as u64);
+        self.hvip &= !HVIP_VSTIP;
+    }
+
+    /// Handles an SBI call, or passes it to the VMM.
+    fn sbi(&mut self) -> Option<VcpuExit> {
+        let (eid, fid) = (self.gpr(17), self.gpr(16));
+        match (eid, fid) {
+            (SBI_EXT_LEGACY_SET_TIMER, _) => self.set_timer(self.gpr(10)),
+            (SBI_EXT_TIME, SBI_TIME_SET_TIMER) => {
+                self.set_timer(self.gpr(10));
+                self.set_gpr(10, SBI_SUCCESS);
+                self.set_gpr(11, 0);
+            }
+            (SBI_EXT_LEGACY_SHUTDOWN, _) | (SBI_EXT_SRST, SBI_SRST_SYSTEM_RESET) => {
+                return Some(VcpuExit::Shutdown);
+            }
+            _ => {
+                self.pending = Some(Pending::Sbi {
+                    legacy: eid < SBI_EXT_BASE,
+                });
+                let mut args = [0; 6];
+                for (i, arg) in args.iter_mut().enumerate() {
+                    *arg = self.gpr(10 + i) as u64;
+                }
+                return Some(VcpuExit::Hypercall {
+                    nr: (eid | fid << 32) as u64,
+                    args,
+                });
+            }
+        }
+        None
+    }
+
+    /// Handles `wfi`, sleeping here while only the timer can wake the guest.
+    fn halt(&mut self) -> Option<VcpuExit> {
+        // `vsie` has the enables at the bits of the S-level interrupts.
+        if self.hvip & self.vs.vsie << 1 != 0 {
+            return None;
+        }
+        let Some(deadline) = self.timer else {
+            return Some(VcpuExit::Halt);
+        };
+        let ticks = deadline.saturating_sub(current_ticks());
+        axtask::sleep(Duration::from_nanos(ticks_to_nanos(ticks)).min(HALT_SLICE));
+        Some(VcpuExit::Preempted)
+    }
+
+    /// Handles the trap that ended a run, returning the exit to report.
+    fn handle(&mut self, trap: Trap) -> Option<VcpuExit> {
+        if trap.scause & SCAUSE_INTERRUPT != 0 {
+            return Some(VcpuExit::Preempted);
+        }
+        match trap.scause {
+            EXC_VS_ECALL => {
+                self.ctx.guest_sepc += 4;
+                self.sbi()
+            }
+            EXC_LOAD_GUEST_PAGE_FAULT | EXC_STORE_GUEST_PAGE_FAULT => Some(self.mmio(&trap)),
+            EXC_VIRTUAL_INSTRUCTION if self.instruction(&trap).0 == INSN_WFI => {
+                self.ctx.guest_sepc += 4;
+                self.halt()
+            }
+            _ => {
+                warn!(
+                    "hv: unhandled guest trap {:#x} at {:#x}",
+                    trap.scause, self.ctx.guest_sepc
+                );
+                Some(VcpuExit::Unknown)
+            }
+        }
+    }
+}
+
+impl GuestVcpu for RiscvVcpu {
+    fn regs(&self) -> HvRegs {
+        let mut regs = HvRegs {
+            pc: self.ctx.guest_sepc as u64,
+            ..Default::default()
+        };
+        for (gpr, &value) in regs.gprs.iter_mut().zip(&self.ctx.guest[1..]) {
+            *gpr = value as u64;
+        }
+        regs
+    }
+
+    fn set_regs(&mut self, regs: &HvRegs) {
+        self.ctx.guest_sepc = regs.pc as usize;
+        for (value, &gpr) in self.ctx.guest[1..].iter_mut().zip(&regs.gprs) {
+            *value = gpr as usize;
+        }
+    }
+
+    fn run(&mut self) -> AxResult<VcpuExit> {
+        loop {
+            let trap = self.enter();
+            if let Some(exit) = self.handle(trap) {
+                return Ok(exit);
+            }
+        }
+    }
+
+    fn complete(&mut self, value: u64) {
+        match self.pending.take() {
+            Some(Pending::Load { reg, size, signed }) => {
+                let shift = 64 - 8 * size as u32;
+                let value = if signed {
+                    ((value << shift) as i64 >> shift) as u64
+                } else {
+                    value << shift >> shift
+                };
+                self.set_gpr(reg, value as usize);
+            }
+            // The calls of the SBI calling convention return an error in
+            // `a0` and a value in `a1`, which a negative result is taken as.
+            Some(Pending::Sbi { legacy: false }) if (value as i64) >= 0 => {
+                self.set_gpr(10, SBI_SUCCESS);
+                self.set_gpr(11, value as usize);
+            }
+            Some(Pending::Sbi { .. }) => self.set_gpr(10, value as usize),
+            None => {}
+        }
+    }
+}
+
+/// The hypervisor extension of the harts.
+struct RiscvH;
+
+impl HvBackend for RiscvH {
+    fn name(&self) -> &str {
+        "RISC-V hypervisor extension"
+    }
+
+    fn create_vm(&self) -> AxResult<Box<dyn GuestVm>> {
+        Ok(Box::new(RiscvVm {
+            table: Arc::new(RwLock::new(GStage::new()?)),
+        }))
+    }
+}
+
+/// Returns whether the device tree lists the hypervisor extension for all
+/// the harts.
+fn harts_have_h() -> bool {
+    let Some(cpus) =
+        boot::device_tree().and_then(|root| root.children.iter().find(|node| node.name == "cpus"))
+    else {
+        return false;
+    };
+    let mut harts = cpus
+        .children
+        .iter()
+        .filter(|node| node.strings("device_type").next() == Some("cpu"))
+        .peekable();
+    harts.peek().is_some()
+        && harts.all(|hart| {
+            hart.strings("riscv,isa-extensions").any(|ext| ext == "h")
+                || hart
+                    .strings("riscv,isa")
+                    .next()
+                    .and_then(|isa| isa.split('_').next())
+                    .is_some_and(|base| base.contains('h'))
+        })
+}
+
+/// Finds the hypervisor extension.
+pub fn probe() -> Option<Arc<dyn HvBackend>> {
+    if !harts_have_h() {
+        return None;
+    }
+    // `hgatp` is WARL: a translation mode the hart lacks reads back as bare.
+    csr_write!("hgatp", HGATP_MODE_SV39X4 << HGATP_MODE_SHIFT);
+    let mode = csr_read!("hgatp") >> HGATP_MODE_SHIFT;
+    csr_write!("hgatp", 0);
+    if mode != HGATP_MODE_SV39X4 {
+        warn!("hv: the hypervisor extension lacks Sv39x4");
+        return None;
+    }
+    Some(Arc::new(RiscvH))
+}
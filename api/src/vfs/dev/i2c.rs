@@ -0,0 +1,615 @@
+//! I2C adapter devices, `/dev/i2c-N`, as used by i2c-tools.
+//!
+//! Each open file is a client of its own, which talks to the address set
+//! with `I2C_SLAVE`: plain reads and writes become single I2C messages,
+//! `I2C_SMBUS` runs an SMBus transaction, and `I2C_RDWR` runs a combined
+//! transaction of several messages with repeated starts in between. SMBus
+//! transactions are emulated with I2C messages, including packet error
+//! checking if the client enables it with `I2C_PEC`.
+//!
+//! Platform I2C drivers register their controllers with [`register`]; the
+//! buses in the device tree that have a driver here are registered by
+//! [`probe`].
+
+use alloc::{borrow::Cow, format, string::String, sync::Arc, vec, vec::Vec};
+use core::{any::Any, task::Context, time::Duration};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng_vfs::{DeviceId, NodeFlags, VfsError, VfsResult};
+use axhal::time::monotonic_time;
+use axio::{Buf, BufMut, Read, Write};
+use axpoll::{IoEvents, Pollable};
+use bytemuck::AnyBitPattern;
+use spin::{Mutex, RwLock};
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use crate::{
+    file::{File, FileLike, Kstat, SealedBuf, SealedBufMut},
+    vfs::DeviceOps,
+};
+
+mod bitbang;
+
+/// The major number of `/dev/i2c-N`.
+pub const I2C_MAJOR: u32 = 89;
+
+const I2C_RETRIES: u32 = 0x0701;
+const I2C_TIMEOUT: u32 = 0x0702;
+const I2C_SLAVE: u32 = 0x0703;
+const I2C_TENBIT: u32 = 0x0704;
+const I2C_FUNCS: u32 = 0x0705;
+const I2C_SLAVE_FORCE: u32 = 0x0706;
+const I2C_RDWR: u32 = 0x0707;
+const I2C_PEC: u32 = 0x0708;
+const I2C_SMBUS: u32 = 0x0720;
+
+/// Read from the target rather than write to it.
+pub const I2C_M_RD: u16 = 0x0001;
+/// The address is a 10-bit one.
+pub const I2C_M_TEN: u16 = 0x0010;
+/// The first byte read is the number of bytes that follow it, as in SMBus
+/// block reads.
+pub const I2C_M_RECV_LEN: u16 = 0x0400;
+pub const I2C_M_NO_RD_ACK: u16 = 0x0800;
+pub const I2C_M_IGNORE_NAK: u16 = 0x1000;
+pub const I2C_M_REV_DIR_ADDR: u16 = 0x2000;
+pub const I2C_M_NOSTART: u16 = 0x4000;
+pub const I2C_M_STOP: u16 = 0x8000;
+
+pub const I2C_FUNC_I2C: u32 = 0x0000_0001;
+pub const I2C_FUNC_10BIT_ADDR: u32 = 0x0000_0002;
+pub const I2C_FUNC_PROTOCOL_MANGLING: u32 = 0x0000_0004;
+pub const I2C_FUNC_SMBUS_PEC: u32 = 0x0000_0008;
+pub const I2C_FUNC_NOSTART: u32 = 0x0000_0010;
+pub const I2C_FUNC_SMBUS_BLOCK_PROC_CALL: u32 = 0x0000_8000;
+pub const I2C_FUNC_SMBUS_QUICK: u32 = 0x0001_0000;
+pub const I2C_FUNC_SMBUS_BYTE: u32 = 0x0006_0000;
+pub const I2C_FUNC_SMBUS_BYTE_DATA: u32 = 0x0018_0000;
+pub const I2C_FUNC_SMBUS_WORD_DATA: u32 = 0x0060_0000;
+pub const I2C_FUNC_SMBUS_PROC_CALL: u32 = 0x0080_0000;
+pub const I2C_FUNC_SMBUS_READ_BLOCK_DATA: u32 = 0x0100_0000;
+pub const I2C_FUNC_SMBUS_WRITE_BLOCK_DATA: u32 = 0x0200_0000;
+pub const I2C_FUNC_SMBUS_I2C_BLOCK: u32 = 0x0c00_0000;
+
+/// The SMBus transactions emulated for adapters that do plain I2C.
+const I2C_FUNC_SMBUS_EMUL: u32 = I2C_FUNC_SMBUS_QUICK
+    | I2C_FUNC_SMBUS_BYTE
+    | I2C_FUNC_SMBUS_BYTE_DATA
+    | I2C_FUNC_SMBUS_WORD_DATA
+    | I2C_FUNC_SMBUS_PROC_CALL
+    | I2C_FUNC_SMBUS_WRITE_BLOCK_DATA
+    | I2C_FUNC_SMBUS_I2C_BLOCK
+    | I2C_FUNC_SMBUS_PEC;
+/// The SMBus transactions that are emulated with `I2C_M_RECV_LEN`.
+const I2C_FUNC_SMBUS_EMUL_RECV_LEN: u32 =
+    I2C_FUNC_SMBUS_READ_BLOCK_DATA | I2C_FUNC_SMBUS_BLOCK_PROC_CALL;
+
+const I2C_SMBUS_WRITE: u8 = 0;
+const I2C_SMBUS_READ: u8 = 1;
+
+const I2C_SMBUS_QUICK: u32 = 0;
+const I2C_SMBUS_BYTE: u32 = 1;
+const I2C_SMBUS_BYTE_DATA: u32 = 2;
+const I2C_SMBUS_WORD_DATA: u32 = 3;
+const I2C_SMBUS_PROC_CALL: u32 = 4;
+const I2C_SMBUS_BLOCK_DATA: u32 = 5;
+const I2C_SMBUS_I2C_BLOCK_BROKEN: u32 = 6;
+const I2C_SMBUS_BLOCK_PROC_CALL: u32 = 7;
+const I2C_SMBUS_I2C_BLOCK_DATA: u32 = 8;
+
+/// The longest SMBus block.
+const I2C_SMBUS_BLOCK_MAX: usize = 32;
+/// The size of `union i2c_smbus_data`: a length byte, the block and a PEC
+/// byte.
+const I2C_SMBUS_DATA_LEN: usize = I2C_SMBUS_BLOCK_MAX + 2;
+
+/// The most messages `I2C_RDWR` takes.
+const I2C_RDWR_IOCTL_MAX_MSGS: usize = 42;
+/// The longest message from userspace.
+const MAX_MSG_LEN: usize = 8192;
+
+/// `struct i2c_msg`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct I2cMsgUser {
+    addr: u16,
+    flags: u16,
+    len: u16,
+    _pad: u16,
+    buf: usize,
+}
+
+/// `struct i2c_rdwr_ioctl_data`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct I2cRdwrData {
+    msgs: usize,
+    nmsgs: u32,
+}
+
+/// `struct i2c_smbus_ioctl_data`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct I2cSmbusData {
+    read_write: u8,
+    command: u8,
+    size: u32,
+    data: usize,
+}
+
+/// A message of an I2C transaction.
+#[derive(Debug, Clone)]
+pub struct I2cMsg {
+    pub addr: u16,
+    /// `I2C_M_*` flags.
+    pub flags: u16,
+    /// The data to write, or the buffer to read into. With
+    /// [`I2C_M_RECV_LEN`], the driver appends as many bytes as the first one
+    /// read says.
+    pub buf: Vec<u8>,
+}
+
+/// An I2C controller.
+pub trait I2cAdapter: Send + Sync {
+    /// Returns the name of the adapter, e.g. the name of the controller.
+    fn name(&self) -> &str;
+
+    /// Returns the `I2C_FUNC_*` flags of what the adapter can do. The SMBus
+    /// transactions are emulated on adapters that do [`I2C_FUNC_I2C`].
+    fn functionality(&self) -> u32 {
+        I2C_FUNC_I2C
+    }
+
+    /// Runs a transaction, with a repeated start between the messages,
+    /// returning the number of messages transferred. Failing with
+    /// [`AxError::WouldBlock`], e.g. on lost arbitration, makes the
+    /// transaction be retried.
+    fn transfer(&self, msgs: &mut [I2cMsg]) -> AxResult<usize>;
+}
+
+/// `/dev/i2c-N`.
+pub struct I2cDevice {
+    index: u32,
+    adapter: Arc<dyn I2cAdapter>,
+    /// How many times a transaction is retried.
+    retries: Mutex<u32>,
+    /// How long retries go on for.
+    timeout: Mutex<Duration>,
+}
+
+static ADAPTERS: RwLock<Vec<Arc<I2cDevice>>> = RwLock::new(Vec::new());
+
+/// Adds an I2C controller, which shows up as `/dev/i2c-N`.
+///
+/// Controllers must be registered before devfs is mounted.
+pub fn register(adapter: Arc<dyn I2cAdapter>) {
+    let mut adapters = ADAPTERS.write();
+    let index = adapters.len() as u32;
+    info!("i2c: i2c-{index} is {}", adapter.name());
+    adapters.push(Arc::new(I2cDevice {
+        index,
+        adapter,
+        retries: Mutex::new(0),
+        timeout: Mutex::new(Duration::from_secs(1)),
+    }));
+}
+
+/// Registers the buses described by the device tree.
+pub fn probe() {
+    for adapter in bitbang::probe() {
+        register(adapter);
+    }
+}
+
+/// Returns all I2C controllers.
+pub fn adapters() -> Vec<Arc<I2cDevice>> {
+    ADAPTERS.read().clone()
+}
+
+impl I2cDevice {
+    pub fn name(&self) -> String {
+        format!("i2c-{}", self.index)
+    }
+
+    pub fn device_id(&self) -> DeviceId {
+        DeviceId::new(I2C_MAJOR, self.index)
+    }
+
+    /// Opens a client of the adapter, for a file opened on the device.
+    pub fn open_client(&self, file: File) -> I2cClient {
+        I2cClient {
+            file,
+            device: ADAPTERS.read()[self.index as usize].clone(),
+            state: Mutex::new(ClientState::default()),
+        }
+    }
+
+    fn functionality(&self) -> u32 {
+        let funcs = self.adapter.functionality();
+        if funcs & I2C_FUNC_I2C != 0 {
+            funcs | I2C_FUNC_SMBUS_EMUL
+        } else {
+            funcs
+        }
+    }
+
+    fn transfer(&self, msgs: &mut [I2cMsg]) -> AxResult<usize> {
+        if msgs.iter().any(|msg| msg.flags & I2C_M_TEN != 0)
+            && self.adapter.functionality() & I2C_FUNC_10BIT_ADDR == 0
+        {
+            return Err(AxError::Unsupported);
+        }
+        let retries = *self.retries.lock();
+        let deadline = monotonic_time() + *self.timeout.lock();
+        let mut tries = 0;
+        loop {
+            match self.adapter.transfer(msgs) {
+                Err(AxError::WouldBlock) if tries < retries && monotonic_time() < deadline => {
+                    tries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs an SMBus transaction as I2C messages. `data` is
+    /// `union i2c_smbus_data`.
+    fn smbus_xfer(
+        &self,
+        client: &ClientState,
+        mut read: bool,
+        command: u8,
+        size: u32,
+        data: &mut [u8; I2C_SMBUS_DATA_LEN],
+    ) -> AxResult {
+        let flags = if client.tenbit { I2C_M_TEN } else { 0 };
+        let msg = |flags, buf| I2cMsg {
+            addr: client.addr,
+            flags,
+            buf,
+        };
+        let block_len = || match data[0] as usize {
+            len @ 1..=I2C_SMBUS_BLOCK_MAX => Ok(len),
+            _ => Err(AxError::InvalidInput),
+        };
+
+        let mut msgs = vec![msg(flags, vec![command])];
+        match size {
+            I2C_SMBUS_QUICK => {
+                msgs[0].buf.clear();
+                if read {
+                    msgs[0].flags |= I2C_M_RD;
+                }
+            }
+            I2C_SMBUS_BYTE => {
+                if read {
+                    msgs[0] = msg(flags | I2C_M_RD, vec![0]);
+                }
+            }
+            I2C_SMBUS_BYTE_DATA if read => msgs.push(msg(flags | I2C_M_RD, vec![0])),
+            I2C_SMBUS_BYTE_DATA => msgs[0].buf.push(data[0]),
+            I2C_SMBUS_WORD_DATA if read => msgs.push(msg(flags | I2C_M_RD, vec![0; 2])),
+            I2C_SMBUS_WORD_DATA => msgs[0].buf.extend_from_slice(&data[..2]),
+            I2C_SMBUS_PROC_CALL => {
+                msgs[0].buf.extend_from_slice(&data[..2]);
+                msgs.push(msg(flags | I2C_M_RD, vec![0; 2]));
+                read = true;
+            }
+            I2C_SMBUS_BLOCK_DATA if read => {
+                msgs.push(msg(flags | I2C_M_RD | I2C_M_RECV_LEN, vec![0]));
+            }
+            I2C_SMBUS_BLOCK_DATA => {
+                let len = block_len()?;
+                msgs[0].buf.extend_from_slice(&data[..=len]);
+            }
+            I2C_SMBUS_BLOCK_PROC_CALL => {
+                let len = block_len()?;
+                msgs[0].buf.extend_from_slice(&data[..=len]);
+                msgs.push(msg(flags | I2C_M_RD | I2C_M_RECV_LEN, vec![0]));
+                read = true;
+            }
+            I2C_SMBUS_I2C_BLOCK_DATA if read => {
+                msgs.push(msg(flags | I2C_M_RD, vec![0; block_len()?]));
+            }
+            I2C_SMBUS_I2C_BLOCK_DATA => {
+                let len = block_len()?;
+                msgs[0].buf.extend_from_slice(&data[1..=len]);
+            }
+            _ => return Err(AxError::InvalidInput),
+        }
+        if msgs.last().unwrap().flags & I2C_M_RECV_LEN != 0
+            && self.adapter.functionality() & I2C_FUNC_SMBUS_EMUL_RECV_LEN == 0
+        {
+            return Err(AxError::Unsupported);
+        }
+
+        // Quick commands have no data to check, and I2C block transfers
+        // aren't SMBus.
+        let pec = client.pec && size != I2C_SMBUS_QUICK && size != I2C_SMBUS_I2C_BLOCK_DATA;
+        let mut partial_pec = 0;
+        if pec {
+            if msgs.len() == 1 && msgs[0].flags & I2C_M_RD == 0 {
+                let crc = msg_pec(0, &msgs[0]);
+                msgs[0].buf.push(crc);
+            } else if msgs.len() == 2 {
+                partial_pec = msg_pec(0, &msgs[0]);
+            }
+            let last = msgs.last_mut().unwrap();
+            if last.flags & I2C_M_RD != 0 {
+                last.buf.push(0);
+            }
+        }
+
+        self.transfer(&mut msgs)?;
+
+        let last = msgs.last_mut().unwrap();
+        if pec && last.flags & I2C_M_RD != 0 {
+            let received = last.buf.pop().unwrap_or(0);
+            if received != msg_pec(partial_pec, last) {
+                return Err(AxError::Other(LinuxError::EBADMSG));
+            }
+        }
+        if !read {
+            return Ok(());
+        }
+        let buf = &last.buf;
+        match size {
+            I2C_SMBUS_BYTE | I2C_SMBUS_BYTE_DATA => data[0] = buf[0],
+            I2C_SMBUS_WORD_DATA | I2C_SMBUS_PROC_CALL => data[..2].copy_from_slice(&buf[..2]),
+            I2C_SMBUS_I2C_BLOCK_DATA => data[1..=buf.len()].copy_from_slice(buf),
+            I2C_SMBUS_BLOCK_DATA | I2C_SMBUS_BLOCK_PROC_CALL => {
+                let len = buf[0] as usize;
+                if len > I2C_SMBUS_BLOCK_MAX || buf.len() < len + 1 {
+                    return Err(AxError::Other(LinuxError::EPROTO));
+                }
+                data[..=len].copy_from_slice(&buf[..=len]);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl DeviceOps for I2cDevice {
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+/// The CRC-8 of SMBus packet error checking.
+fn crc8(mut crc: u8, data: &[u8]) -> u8 {
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Adds a message, with the address byte that starts it, to a PEC.
+fn msg_pec(crc: u8, msg: &I2cMsg) -> u8 {
+    let addr = ((msg.addr << 1) as u8) | (msg.flags & I2C_M_RD) as u8;
+    crc8(crc8(crc, &[addr]), &msg.buf)
+}
+
+#[derive(Default, Clone, Copy)]
+struct ClientState {
+    addr: u16,
+    tenbit: bool,
+    pec: bool,
+}
+
+/// A file opened on `/dev/i2c-N`.
+pub struct I2cClient {
+    file: File,
+    device: Arc<I2cDevice>,
+    state: Mutex<ClientState>,
+}
+
+impl I2cClient {
+    fn rdwr(&self, arg: &I2cRdwrData) -> AxResult<usize> {
+        let nmsgs = arg.nmsgs as usize;
+        if nmsgs == 0 || nmsgs > I2C_RDWR_IOCTL_MAX_MSGS {
+            return Err(AxError::InvalidInput);
+        }
+        let user_msgs = vm_load(arg.msgs as *const I2cMsgUser, nmsgs)?;
+        let mut msgs = Vec::with_capacity(nmsgs);
+        for user in &user_msgs {
+            let len = user.len as usize;
+            if len > MAX_MSG_LEN {
+                return Err(AxError::InvalidInput);
+            }
+            let mut buf = if user.flags & I2C_M_RD != 0 {
+                vec![0; len]
+            } else {
+                vm_load(user.buf as *const u8, len)?
+            };
+            if user.flags & I2C_M_RECV_LEN != 0 {
+                // The first byte is the number of bytes received besides the
+                // block, e.g. 2 for the length byte and a PEC byte.
+                let extra = (user.buf as *const u8).vm_read()? as usize;
+                if user.flags & I2C_M_RD == 0 || extra == 0 || len < extra + I2C_SMBUS_BLOCK_MAX {
+                    return Err(AxError::InvalidInput);
+                }
+                buf.truncate(extra);
+            }
+            msgs.push(I2cMsg {
+                addr: user.addr,
+                flags: user.flags,
+                buf,
+            });
+        }
+
+        let transferred = self.device.transfer(&mut msgs)?;
+
+        for (i, (user, msg)) in user_msgs.iter().zip(&msgs).enumerate() {
+            if msg.flags & I2C_M_RD == 0 {
+                continue;
+            }
+            vm_write_slice(
+                user.buf as *mut u8,
+                &msg.buf[..msg.buf.len().min(user.len as usize)],
+            )?;
+            if msg.flags & I2C_M_RECV_LEN != 0 {
+                let len_ptr = arg.msgs + i * size_of::<I2cMsgUser>() + 4;
+                (len_ptr as *mut u16).vm_write(msg.buf.len() as u16)?;
+            }
+        }
+        Ok(transferred)
+    }
+
+    fn smbus(&self, arg: &I2cSmbusData) -> AxResult {
+        let read = match arg.read_write {
+            I2C_SMBUS_READ => true,
+            I2C_SMBUS_WRITE => false,
+            _ => return Err(AxError::InvalidInput),
+        };
+        let mut size = arg.size;
+        if size > I2C_SMBUS_I2C_BLOCK_DATA {
+            return Err(AxError::InvalidInput);
+        }
+        let state = *self.state.lock();
+        let mut data = [0; I2C_SMBUS_DATA_LEN];
+        if size == I2C_SMBUS_QUICK || (size == I2C_SMBUS_BYTE && !read) {
+            // These have no data.
+            return self
+                .device
+                .smbus_xfer(&state, read, arg.command, size, &mut data);
+        }
+        if arg.data == 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let data_len = match size {
+            I2C_SMBUS_BYTE | I2C_SMBUS_BYTE_DATA => 1,
+            I2C_SMBUS_WORD_DATA | I2C_SMBUS_PROC_CALL => 2,
+            _ => I2C_SMBUS_DATA_LEN,
+        };
+        let data_ptr = arg.data as *mut u8;
+        if !read
+            || matches!(
+                size,
+                I2C_SMBUS_PROC_CALL | I2C_SMBUS_BLOCK_PROC_CALL | I2C_SMBUS_I2C_BLOCK_DATA
+            )
+        {
+            data[..data_len].copy_from_slice(&vm_load(data_ptr, data_len)?);
+        }
+        if size == I2C_SMBUS_I2C_BLOCK_BROKEN {
+            // The old I2C block read, which always reads a whole block.
+            size = I2C_SMBUS_I2C_BLOCK_DATA;
+            if read {
+                data[0] = I2C_SMBUS_BLOCK_MAX as u8;
+            }
+        }
+        self.device
+            .smbus_xfer(&state, read, arg.command, size, &mut data)?;
+        if read {
+            vm_write_slice(data_ptr, &data[..data_len])?;
+        }
+        Ok(())
+    }
+
+    fn msg(&self, flags: u16, buf: Vec<u8>) -> I2cMsg {
+        let state = self.state.lock();
+        I2cMsg {
+            addr: state.addr,
+            flags: flags | if state.tenbit { I2C_M_TEN } else { 0 },
+            buf,
+        }
+    }
+}
+
+impl FileLike for I2cClient {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        let len = dst.remaining_mut().min(MAX_MSG_LEN);
+        let mut msgs = [self.msg(I2C_M_RD, vec![0; len])];
+        self.device.transfer(&mut msgs)?;
+        dst.write(&msgs[0].buf)
+    }
+
+    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+        let mut buf = vec![0; src.remaining().min(MAX_MSG_LEN)];
+        let len = src.read(&mut buf)?;
+        buf.truncate(len);
+        let mut msgs = [self.msg(0, buf)];
+        self.device.transfer(&mut msgs)?;
+        Ok(len)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        self.file.stat()
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.file.nonblocking()
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.file.set_nonblocking(non_blocking)
+    }
+
+    fn path(&self) -> Cow<str> {
+        self.file.path()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        match cmd {
+            I2C_SLAVE | I2C_SLAVE_FORCE => {
+                // No kernel drivers bind to I2C targets, so no address is
+                // busy.
+                let mut state = self.state.lock();
+                let max = if state.tenbit { 0x3ff } else { 0x7f };
+                if arg > max {
+                    return Err(AxError::InvalidInput);
+                }
+                state.addr = arg as u16;
+            }
+            I2C_TENBIT => self.state.lock().tenbit = arg != 0,
+            I2C_PEC => self.state.lock().pec = arg != 0,
+            I2C_FUNCS => (arg as *mut usize).vm_write(self.device.functionality() as usize)?,
+            I2C_RDWR => {
+                let data = (arg as *const I2cRdwrData).vm_read()?;
+                return self.rdwr(&data);
+            }
+            I2C_SMBUS => {
+                let data = (arg as *const I2cSmbusData).vm_read()?;
+                self.smbus(&data)?;
+            }
+            I2C_RETRIES => *self.device.retries.lock() = arg as u32,
+            // In units of 10 ms.
+            I2C_TIMEOUT => {
+                *self.device.timeout.lock() = Duration::from_millis(arg as u64 * 10);
+            }
+            _ => return Err(AxError::BadIoctl),
+        }
+        Ok(0)
+    }
+}
+
+impl Pollable for I2cClient {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
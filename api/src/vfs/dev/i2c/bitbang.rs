@@ -0,0 +1,305 @@
+//! I2C buses bit-banged on two GPIO lines, described in the device tree as
+//! `i2c-gpio`.
+//!
+//! Both lines are driven open drain, so the bus is only ever pulled low;
+//! the clock is released until it reads high, letting targets stretch it,
+//! unless the board says it can't be read back.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::time::Duration;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::monotonic_time;
+use spin::Mutex;
+use starry_core::boot::{self, FdtDevice};
+
+use super::{
+    I2C_FUNC_10BIT_ADDR, I2C_FUNC_I2C, I2C_FUNC_NOSTART, I2C_FUNC_PROTOCOL_MANGLING,
+    I2C_FUNC_SMBUS_BLOCK_PROC_CALL, I2C_FUNC_SMBUS_READ_BLOCK_DATA, I2C_M_IGNORE_NAK,
+    I2C_M_NO_RD_ACK, I2C_M_NOSTART, I2C_M_RD, I2C_M_RECV_LEN, I2C_M_REV_DIR_ADDR, I2C_M_STOP,
+    I2C_M_TEN, I2C_SMBUS_BLOCK_MAX, I2cAdapter, I2cMsg,
+};
+use crate::{
+    time::udelay,
+    vfs::dev::gpio::{self, Bias, Direction, Drive, DtLine, LineSettings},
+};
+
+/// The half clock period if the board doesn't give one, for 100 kHz.
+const DEFAULT_DELAY_US: u64 = 5;
+/// How long targets may stretch the clock if the board doesn't say.
+const DEFAULT_TIMEOUT_MS: u64 = 100;
+
+pub struct GpioI2c {
+    name: String,
+    sda: DtLine,
+    scl: DtLine,
+    /// Whether SCL can be read back, to wait for targets stretching it.
+    scl_readable: bool,
+    delay_us: u64,
+    timeout: Duration,
+    /// Serializes transactions.
+    bus: Mutex<()>,
+}
+
+impl GpioI2c {
+    fn setup(line: &DtLine) -> AxResult {
+        let settings = LineSettings {
+            direction: Direction::Output,
+            drive: Drive::OpenDrain,
+            bias: Bias::AsIs,
+            debounce_us: 0,
+            rising: false,
+            falling: false,
+        };
+        line.chip.configure(line.offset, &settings, true)
+    }
+
+    fn set_sda(&self, high: bool) {
+        self.sda.chip.set(self.sda.offset, high);
+    }
+
+    fn sda(&self) -> bool {
+        self.sda.chip.get(self.sda.offset)
+    }
+
+    fn scl_low(&self) {
+        self.scl.chip.set(self.scl.offset, false);
+    }
+
+    /// Releases SCL, waiting for targets holding it low.
+    fn scl_high(&self) -> AxResult {
+        self.scl.chip.set(self.scl.offset, true);
+        if !self.scl_readable {
+            udelay(self.delay_us);
+            return Ok(());
+        }
+        let deadline = monotonic_time() + self.timeout;
+        while !self.scl.chip.get(self.scl.offset) {
+            if monotonic_time() >= deadline {
+                // Another master may be holding the clock.
+                return Err(AxError::WouldBlock);
+            }
+            core::hint::spin_loop();
+        }
+        udelay(self.delay_us);
+        Ok(())
+    }
+
+    fn start(&self) {
+        self.set_sda(false);
+        udelay(self.delay_us);
+        self.scl_low();
+    }
+
+    fn repeated_start(&self) -> AxResult {
+        self.set_sda(true);
+        self.scl_high()?;
+        self.start();
+        Ok(())
+    }
+
+    fn stop(&self) -> AxResult {
+        self.set_sda(false);
+        udelay(self.delay_us);
+        self.scl_high()?;
+        self.set_sda(true);
+        udelay(self.delay_us);
+        Ok(())
+    }
+
+    /// Clocks out a byte, returning whether the target acknowledged it.
+    fn write_byte(&self, byte: u8) -> AxResult<bool> {
+        for bit in (0..8).rev() {
+            self.set_sda(byte & (1 << bit) != 0);
+            udelay(self.delay_us.div_ceil(2));
+            self.scl_high()?;
+            self.scl_low();
+        }
+        self.set_sda(true);
+        udelay(self.delay_us.div_ceil(2));
+        self.scl_high()?;
+        let ack = !self.sda();
+        self.scl_low();
+        Ok(ack)
+    }
+
+    /// Clocks in a byte, acknowledging it unless `ack` is `None`, which
+    /// leaves the acknowledge bit out altogether.
+    fn read_byte(&self, ack: Option<bool>) -> AxResult<u8> {
+        let mut byte = 0;
+        self.set_sda(true);
+        for _ in 0..8 {
+            udelay(self.delay_us.div_ceil(2));
+            self.scl_high()?;
+            byte = byte << 1 | self.sda() as u8;
+            self.scl_low();
+        }
+        if let Some(ack) = ack {
+            self.set_sda(!ack);
+            udelay(self.delay_us.div_ceil(2));
+            self.scl_high()?;
+            self.scl_low();
+            self.set_sda(true);
+        }
+        Ok(byte)
+    }
+
+    fn expect_ack(&self, msg: &I2cMsg, byte: u8) -> AxResult {
+        if self.write_byte(byte)? || msg.flags & I2C_M_IGNORE_NAK != 0 {
+            Ok(())
+        } else {
+            Err(AxError::Other(LinuxError::ENXIO))
+        }
+    }
+
+    /// Sends the address of `msg`, after the start condition.
+    fn address(&self, msg: &I2cMsg) -> AxResult {
+        let mut read = msg.flags & I2C_M_RD != 0;
+        if msg.flags & I2C_M_REV_DIR_ADDR != 0 {
+            read = !read;
+        }
+        if msg.flags & I2C_M_TEN != 0 {
+            let high = 0xf0 | ((msg.addr >> 7) & 0x06) as u8;
+            self.expect_ack(msg, high)?;
+            self.expect_ack(msg, msg.addr as u8)?;
+            if read {
+                self.repeated_start()?;
+                self.expect_ack(msg, high | 1)?;
+            }
+        } else {
+            self.expect_ack(msg, (msg.addr << 1) as u8 | read as u8)?;
+        }
+        Ok(())
+    }
+
+    fn read_msg(&self, msg: &mut I2cMsg) -> AxResult {
+        let no_ack = msg.flags & I2C_M_NO_RD_ACK != 0;
+        let ack = |last: bool| (!no_ack).then_some(!last);
+        let mut pos = 0;
+        if msg.flags & I2C_M_RECV_LEN != 0 {
+            let extra = msg.buf.len();
+            let len = self.read_byte(ack(false))? as usize;
+            if len == 0 || len > I2C_SMBUS_BLOCK_MAX {
+                // Finish the transfer the target expects before giving up.
+                let _ = self.read_byte(ack(true));
+                return Err(AxError::Other(LinuxError::EPROTO));
+            }
+            msg.buf[0] = len as u8;
+            msg.buf.resize(extra + len, 0);
+            pos = 1;
+        }
+        let len = msg.buf.len();
+        for (i, byte) in msg.buf.iter_mut().enumerate().skip(pos) {
+            *byte = self.read_byte(ack(i + 1 == len))?;
+        }
+        Ok(())
+    }
+
+    fn write_msg(&self, msg: &I2cMsg) -> AxResult {
+        for &byte in &msg.buf {
+            if !self.write_byte(byte)? && msg.flags & I2C_M_IGNORE_NAK == 0 {
+                return Err(AxError::Other(LinuxError::EIO));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the messages, keeping track of whether the bus is `busy`, as a
+    /// stop condition is only due then.
+    fn run(&self, msgs: &mut [I2cMsg], busy: &mut bool) -> AxResult {
+        for (i, msg) in msgs.iter_mut().enumerate() {
+            if i == 0 || msg.flags & I2C_M_NOSTART == 0 {
+                if *busy {
+                    self.repeated_start()?;
+                } else {
+                    self.start();
+                    *busy = true;
+                }
+                self.address(msg)?;
+            }
+            if msg.flags & I2C_M_RD != 0 {
+                self.read_msg(msg)?;
+            } else {
+                self.write_msg(msg)?;
+            }
+            if msg.flags & I2C_M_STOP != 0 {
+                self.stop()?;
+                *busy = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl I2cAdapter for GpioI2c {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn functionality(&self) -> u32 {
+        I2C_FUNC_I2C
+            | I2C_FUNC_10BIT_ADDR
+            | I2C_FUNC_NOSTART
+            | I2C_FUNC_PROTOCOL_MANGLING
+            | I2C_FUNC_SMBUS_READ_BLOCK_DATA
+            | I2C_FUNC_SMBUS_BLOCK_PROC_CALL
+    }
+
+    fn transfer(&self, msgs: &mut [I2cMsg]) -> AxResult<usize> {
+        let _bus = self.bus.lock();
+        let mut busy = false;
+        let result = self.run(msgs, &mut busy);
+        // Leave the bus idle whatever happened.
+        if busy {
+            self.stop()?;
+        }
+        result?;
+        Ok(msgs.len())
+    }
+}
+
+fn add(dev: &FdtDevice) -> AxResult<Arc<GpioI2c>> {
+    let node = dev.node;
+    // The deprecated binding lists both lines in `gpios`.
+    let (sda, scl) = match gpio::dt_line(node, "sda-gpios", 0) {
+        Some(sda) => (Some(sda), gpio::dt_line(node, "scl-gpios", 0)),
+        None => (
+            gpio::dt_line(node, "gpios", 0),
+            gpio::dt_line(node, "gpios", 1),
+        ),
+    };
+    let (Some(sda), Some(scl)) = (sda, scl) else {
+        // The lines are missing, or their controller has no driver.
+        return Err(AxError::NoSuchDevice);
+    };
+    let delay_us = node
+        .property_u32("i2c-gpio,delay-us")
+        .map_or(DEFAULT_DELAY_US, |it| it.max(1) as u64);
+    let timeout_ms = node
+        .property_u32("i2c-gpio,timeout-ms")
+        .map_or(DEFAULT_TIMEOUT_MS, |it| it as u64);
+    GpioI2c::setup(&sda)?;
+    GpioI2c::setup(&scl)?;
+    Ok(Arc::new(GpioI2c {
+        name: node.name.clone(),
+        sda,
+        scl,
+        scl_readable: node.property("i2c-gpio,scl-output-only").is_none(),
+        delay_us,
+        timeout: Duration::from_millis(timeout_ms),
+        bus: Mutex::new(()),
+    }))
+}
+
+/// Finds the buses in the device tree. The GPIO controllers must have been
+/// probed first.
+pub fn probe() -> Vec<Arc<dyn I2cAdapter>> {
+    let mut adapters = Vec::new();
+    for dev in boot::find_compatible(&["i2c-gpio"]) {
+        match add(&dev) {
+            Ok(adapter) => adapters.push(adapter as _),
+            Err(err) => warn!("i2c: failed to set up {}: {err:?}", dev.node.name),
+        }
+    }
+    adapters
+}
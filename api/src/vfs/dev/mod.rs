@@ -1,8 +1,12 @@
 //! Special devices
 
+mod dm;
 #[cfg(feature = "input")]
 mod event;
 mod fb;
+pub mod gpio;
+pub mod hv;
+pub mod i2c;
 #[cfg(feature = "dev-log")]
 mod log;
 mod r#loop;
@@ -13,7 +17,11 @@ mod dma_heap;
 pub mod card0;
 pub mod card1;
 mod rtc;
+pub mod spi;
 pub mod tty;
+pub mod uio;
+pub mod usb;
+pub mod watchdog;
 
 use alloc::{format, sync::Arc};
 use core::any::Any;
@@ -24,7 +32,9 @@ use axsync::Mutex;
 #[cfg(feature = "dev-log")]
 pub use log::bind_dev_log;
 use rand::{RngCore, SeedableRng, rngs::SmallRng};
-use starry_core::vfs::{Device, DeviceOps, DirMaker, DirMapping, SimpleDir, SimpleFs};
+use starry_core::vfs::{
+    Device, DeviceOps, DirMaker, DirMapping, SimpleDir, SimpleDirOps, SimpleFs,
+};
 
 const RANDOM_SEED: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
 
@@ -201,6 +211,68 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             Arc::new(rtc::Rtc),
         ),
     );
+    root.add(
+        "starry-hv",
+        Device::new(
+            fs.clone(),
+            NodeType::CharacterDevice,
+            hv::HV_DEVICE_ID,
+            Arc::new(hv::HvDevice),
+        ),
+    );
+    root.add(
+        "watchdog",
+        Device::new(
+            fs.clone(),
+            NodeType::CharacterDevice,
+            watchdog::WATCHDOG_DEVICE_ID,
+            Arc::new(watchdog::Watchdog::new()),
+        ),
+    );
+    for adapter in i2c::adapters() {
+        root.add(
+            adapter.name(),
+            Device::new(
+                fs.clone(),
+                NodeType::CharacterDevice,
+                adapter.device_id(),
+                adapter.clone(),
+            ),
+        );
+    }
+    for spidev in spi::devices() {
+        root.add(
+            spidev.name(),
+            Device::new(
+                fs.clone(),
+                NodeType::CharacterDevice,
+                spidev.device_id(),
+                spidev.clone(),
+            ),
+        );
+    }
+    for dev in uio::devices() {
+        root.add(
+            dev.name(),
+            Device::new(
+                fs.clone(),
+                NodeType::CharacterDevice,
+                dev.device_id(),
+                dev.clone(),
+            ),
+        );
+    }
+    for chip in gpio::chips() {
+        root.add(
+            chip.name(),
+            Device::new(
+                fs.clone(),
+                NodeType::CharacterDevice,
+                chip.device_id(),
+                chip.clone(),
+            ),
+        );
+    }
     if axdisplay::has_display() {
         root.add(
             "fb0",
@@ -241,6 +313,18 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             tty::N_TTY.clone(),
         ),
     );
+    for console in tty::consoles() {
+        let backend = console.backend();
+        root.add(
+            backend.name(),
+            Device::new(
+                fs.clone(),
+                NodeType::CharacterDevice,
+                backend.device_id(),
+                console.clone(),
+            ),
+        );
+    }
 
     root.add(
         "ptmx",
@@ -287,6 +371,11 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
         "shm",
         SimpleDir::new_maker(fs.clone(), Arc::new(DirMapping::new())),
     );
+    // And this to the mqueue filesystem in `mount_all`
+    root.add(
+        "mqueue",
+        SimpleDir::new_maker(fs.clone(), Arc::new(DirMapping::new())),
+    );
 
     // DMA heap devices
     let mut dma_heap_dir = DirMapping::new();
@@ -343,6 +432,12 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
         );
     }
 
+    // Device mapper
+    root.add(
+        "mapper",
+        SimpleDir::new_maker(fs.clone(), Arc::new(dm::MapperDir::new(fs.clone()))),
+    );
+
     // Input devices
     #[cfg(feature = "input")]
     root.add(
@@ -350,5 +445,7 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
         SimpleDir::new_maker(fs.clone(), Arc::new(event::input_devices(fs.clone()))),
     );
 
-    SimpleDir::new_maker(fs, Arc::new(root))
+    // USB devices come and go
+    let usb = usb::UsbDir::new(fs.clone());
+    SimpleDir::new_maker(fs, Arc::new(root.chain(usb)))
 }
\ No newline at end of file
@@ -0,0 +1,421 @@
+//! SPI devices, `/dev/spidevB.C`, for talking to SPI peripherals from
+//! userspace.
+//!
+//! There is a device for each chip select `C` of each controller `B`. The
+//! SPI mode, word size and clock rate are set with the `SPI_IOC_WR_*`
+//! ioctls, and stay in effect for everyone using the device. Reads and
+//! writes are half-duplex transfers; `SPI_IOC_MESSAGE(N)` runs a message of
+//! `N` full-duplex transfers, with the chip select asserted throughout
+//! unless a transfer asks for it to be toggled.
+//!
+//! Platform SPI drivers register their controllers with [`register`]; the
+//! buses in the device tree that have a driver here are registered by
+//! [`probe`].
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::any::Any;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng_vfs::{DeviceId, NodeFlags, VfsError, VfsResult};
+use bytemuck::AnyBitPattern;
+use spin::{Mutex, RwLock};
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use crate::vfs::DeviceOps;
+
+mod bitbang;
+
+/// The major number of `/dev/spidevB.C`.
+pub const SPIDEV_MAJOR: u32 = 153;
+
+const SPI_IOC_RD_MODE: u32 = 0x8001_6b01;
+const SPI_IOC_WR_MODE: u32 = 0x4001_6b01;
+const SPI_IOC_RD_LSB_FIRST: u32 = 0x8001_6b02;
+const SPI_IOC_WR_LSB_FIRST: u32 = 0x4001_6b02;
+const SPI_IOC_RD_BITS_PER_WORD: u32 = 0x8001_6b03;
+const SPI_IOC_WR_BITS_PER_WORD: u32 = 0x4001_6b03;
+const SPI_IOC_RD_MAX_SPEED_HZ: u32 = 0x8004_6b04;
+const SPI_IOC_WR_MAX_SPEED_HZ: u32 = 0x4004_6b04;
+const SPI_IOC_RD_MODE32: u32 = 0x8004_6b05;
+const SPI_IOC_WR_MODE32: u32 = 0x4004_6b05;
+/// `SPI_IOC_MESSAGE(N)`, with the size of `N` transfers in bits 16 to 29.
+const SPI_IOC_MESSAGE_BASE: u32 = 0x4000_6b00;
+const IOC_SIZE_MASK: u32 = 0x3fff << 16;
+
+pub const SPI_CPHA: u32 = 0x0001;
+pub const SPI_CPOL: u32 = 0x0002;
+pub const SPI_CS_HIGH: u32 = 0x0004;
+pub const SPI_LSB_FIRST: u32 = 0x0008;
+pub const SPI_3WIRE: u32 = 0x0010;
+pub const SPI_LOOP: u32 = 0x0020;
+pub const SPI_NO_CS: u32 = 0x0040;
+pub const SPI_READY: u32 = 0x0080;
+pub const SPI_TX_DUAL: u32 = 0x0100;
+pub const SPI_TX_QUAD: u32 = 0x0200;
+pub const SPI_RX_DUAL: u32 = 0x0400;
+pub const SPI_RX_QUAD: u32 = 0x0800;
+pub const SPI_CS_WORD: u32 = 0x1000;
+pub const SPI_TX_OCTAL: u32 = 0x2000;
+pub const SPI_RX_OCTAL: u32 = 0x4000;
+pub const SPI_3WIRE_HIZ: u32 = 0x8000;
+
+/// The most data a read, a write or a message may move each way.
+const BUFSIZ: usize = 4096;
+const DEFAULT_SPEED_HZ: u32 = 500_000;
+
+/// `struct spi_ioc_transfer`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+struct SpiIocTransfer {
+    tx_buf: u64,
+    rx_buf: u64,
+    len: u32,
+    speed_hz: u32,
+    delay_usecs: u16,
+    word_delay_usecs: u8,
+    bits_per_word: u8,
+    tx_nbits: u8,
+    rx_nbits: u8,
+    cs_change: u8,
+    pad: u8,
+}
+
+/// A transfer of an SPI message.
+#[derive(Debug, Clone)]
+pub struct SpiTransfer {
+    /// The data to send, or `None` to send zeroes.
+    pub tx: Option<Vec<u8>>,
+    /// The buffer to receive into, or `None` to discard what is received.
+    pub rx: Option<Vec<u8>>,
+    /// The length of the transfer in bytes.
+    pub len: usize,
+    pub speed_hz: u32,
+    pub bits_per_word: u8,
+    /// The number of data lines used to send, 1, 2, 4 or 8.
+    pub tx_nbits: u8,
+    /// The number of data lines used to receive, 1, 2, 4 or 8.
+    pub rx_nbits: u8,
+    /// How long to wait after the transfer, in microseconds.
+    pub delay_us: u16,
+    /// How long to wait between words, in microseconds.
+    pub word_delay_us: u8,
+    /// Whether to deassert the chip select after the transfer. After the
+    /// last transfer, whether to leave it asserted instead.
+    pub cs_change: bool,
+}
+
+/// An SPI controller.
+pub trait SpiController: Send + Sync {
+    /// Returns the name of the controller.
+    fn name(&self) -> &str;
+
+    fn num_chip_selects(&self) -> u8;
+
+    /// Returns the fastest clock rate the controller can do.
+    fn max_speed_hz(&self) -> u32;
+
+    /// Returns the `SPI_*` mode bits the controller supports.
+    fn mode_bits(&self) -> u32 {
+        SPI_CPHA | SPI_CPOL | SPI_CS_HIGH
+    }
+
+    /// Returns the word sizes the controller supports, with bit `n - 1`
+    /// standing for `n` bits.
+    fn bits_per_word_mask(&self) -> u32 {
+        1 << 7
+    }
+
+    /// Runs a message on chip select `cs` in SPI mode `mode`, asserting the
+    /// chip select until the last transfer is done.
+    fn transfer(&self, cs: u8, mode: u32, transfers: &mut [SpiTransfer]) -> AxResult;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SpiSettings {
+    mode: u32,
+    bits_per_word: u8,
+    max_speed_hz: u32,
+}
+
+/// `/dev/spidevB.C`.
+pub struct SpiDevice {
+    bus: u32,
+    cs: u8,
+    minor: u32,
+    controller: Arc<dyn SpiController>,
+    settings: Mutex<SpiSettings>,
+}
+
+static DEVICES: RwLock<Vec<Arc<SpiDevice>>> = RwLock::new(Vec::new());
+static NUM_BUSES: Mutex<u32> = Mutex::new(0);
+
+/// Adds an SPI controller, which shows up as a `/dev/spidevB.C` for each of
+/// its chip selects.
+///
+/// Controllers must be registered before devfs is mounted.
+pub fn register(controller: Arc<dyn SpiController>) {
+    let mut num_buses = NUM_BUSES.lock();
+    let bus = *num_buses;
+    *num_buses += 1;
+    info!(
+        "spi: spi{bus} is {} with {} chip selects",
+        controller.name(),
+        controller.num_chip_selects()
+    );
+    let mut devices = DEVICES.write();
+    for cs in 0..controller.num_chip_selects() {
+        let minor = devices.len() as u32;
+        devices.push(Arc::new(SpiDevice {
+            bus,
+            cs,
+            minor,
+            settings: Mutex::new(SpiSettings {
+                mode: 0,
+                bits_per_word: 8,
+                max_speed_hz: DEFAULT_SPEED_HZ.min(controller.max_speed_hz()),
+            }),
+            controller: controller.clone(),
+        }));
+    }
+}
+
+/// Registers the buses described by the device tree.
+pub fn probe() {
+    for controller in bitbang::probe() {
+        register(controller);
+    }
+}
+
+/// Returns all SPI devices.
+pub fn devices() -> Vec<Arc<SpiDevice>> {
+    DEVICES.read().clone()
+}
+
+/// Returns the number of bytes a word of `bits` bits takes.
+fn word_size(bits: u8) -> usize {
+    match bits {
+        0..=8 => 1,
+        9..=16 => 2,
+        _ => 4,
+    }
+}
+
+fn nbits_valid(nbits: u8, mode: u32, dual: u32, quad: u32, octal: u32) -> bool {
+    match nbits {
+        1 => true,
+        2 => mode & (dual | quad | octal) != 0,
+        4 => mode & (quad | octal) != 0,
+        8 => mode & octal != 0,
+        _ => false,
+    }
+}
+
+impl SpiDevice {
+    pub fn name(&self) -> String {
+        format!("spidev{}.{}", self.bus, self.cs)
+    }
+
+    pub fn device_id(&self) -> DeviceId {
+        DeviceId::new(SPIDEV_MAJOR, self.minor)
+    }
+
+    fn set_mode(&self, mode: u32) -> VfsResult<()> {
+        if mode & !self.controller.mode_bits() != 0 {
+            return Err(VfsError::InvalidInput);
+        }
+        self.settings.lock().mode = mode;
+        Ok(())
+    }
+
+    fn bits_supported(&self, bits: u8) -> bool {
+        (1..=32).contains(&bits) && self.controller.bits_per_word_mask() & (1 << (bits - 1)) != 0
+    }
+
+    /// Fills in the defaults of a transfer and checks it against the
+    /// device.
+    fn validate(&self, settings: &SpiSettings, xfer: &mut SpiTransfer) -> AxResult {
+        if xfer.bits_per_word == 0 {
+            xfer.bits_per_word = settings.bits_per_word;
+        }
+        if xfer.speed_hz == 0 {
+            xfer.speed_hz = settings.max_speed_hz;
+        }
+        xfer.speed_hz = xfer.speed_hz.min(self.controller.max_speed_hz());
+        xfer.tx_nbits = xfer.tx_nbits.max(1);
+        xfer.rx_nbits = xfer.rx_nbits.max(1);
+        let mode = settings.mode;
+        if !self.bits_supported(xfer.bits_per_word)
+            || xfer.len % word_size(xfer.bits_per_word) != 0
+            || (xfer.tx.is_some()
+                && !nbits_valid(xfer.tx_nbits, mode, SPI_TX_DUAL, SPI_TX_QUAD, SPI_TX_OCTAL))
+            || (xfer.rx.is_some()
+                && !nbits_valid(xfer.rx_nbits, mode, SPI_RX_DUAL, SPI_RX_QUAD, SPI_RX_OCTAL))
+        {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(())
+    }
+
+    fn run(&self, transfers: &mut [SpiTransfer]) -> AxResult {
+        let settings = *self.settings.lock();
+        for xfer in transfers.iter_mut() {
+            self.validate(&settings, xfer)?;
+        }
+        self.controller.transfer(self.cs, settings.mode, transfers)
+    }
+
+    fn transfer(
+        &self,
+        tx: Option<Vec<u8>>,
+        rx: Option<Vec<u8>>,
+        len: usize,
+    ) -> AxResult<SpiTransfer> {
+        let mut transfers = [SpiTransfer {
+            tx,
+            rx,
+            len,
+            speed_hz: 0,
+            bits_per_word: 0,
+            tx_nbits: 0,
+            rx_nbits: 0,
+            delay_us: 0,
+            word_delay_us: 0,
+            cs_change: false,
+        }];
+        self.run(&mut transfers)?;
+        let [xfer] = transfers;
+        Ok(xfer)
+    }
+
+    /// Runs `SPI_IOC_MESSAGE` with the transfers at `arg`, returning the
+    /// number of bytes transferred.
+    fn message(&self, arg: usize, count: usize) -> AxResult<usize> {
+        let user_xfers = vm_load(arg as *const SpiIocTransfer, count)?;
+        let (mut tx_total, mut rx_total) = (0, 0);
+        let mut transfers = Vec::with_capacity(count);
+        for user in &user_xfers {
+            let len = user.len as usize;
+            if user.tx_buf != 0 {
+                tx_total += len;
+            }
+            if user.rx_buf != 0 {
+                rx_total += len;
+            }
+            if tx_total > BUFSIZ || rx_total > BUFSIZ {
+                return Err(AxError::Other(LinuxError::EMSGSIZE));
+            }
+            transfers.push(SpiTransfer {
+                tx: match user.tx_buf {
+                    0 => None,
+                    buf => Some(vm_load(buf as *const u8, len)?),
+                },
+                rx: (user.rx_buf != 0).then(|| vec![0; len]),
+                len,
+                speed_hz: user.speed_hz,
+                bits_per_word: user.bits_per_word,
+                tx_nbits: user.tx_nbits,
+                rx_nbits: user.rx_nbits,
+                delay_us: user.delay_usecs,
+                word_delay_us: user.word_delay_usecs,
+                cs_change: user.cs_change != 0,
+            });
+        }
+
+        self.run(&mut transfers)?;
+
+        let mut total = 0;
+        for (user, xfer) in user_xfers.iter().zip(&transfers) {
+            if let Some(rx) = &xfer.rx {
+                vm_write_slice(user.rx_buf as *mut u8, rx)?;
+            }
+            total += xfer.len;
+        }
+        Ok(total)
+    }
+}
+
+impl DeviceOps for SpiDevice {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        if buf.len() > BUFSIZ {
+            return Err(VfsError::Other(LinuxError::EMSGSIZE));
+        }
+        let xfer = self.transfer(None, Some(vec![0; buf.len()]), buf.len())?;
+        buf.copy_from_slice(xfer.rx.as_deref().unwrap_or_default());
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        if buf.len() > BUFSIZ {
+            return Err(VfsError::Other(LinuxError::EMSGSIZE));
+        }
+        self.transfer(Some(buf.to_vec()), None, buf.len())?;
+        Ok(buf.len())
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        match cmd {
+            SPI_IOC_RD_MODE => (arg as *mut u8).vm_write(self.settings.lock().mode as u8)?,
+            SPI_IOC_RD_MODE32 => (arg as *mut u32).vm_write(self.settings.lock().mode)?,
+            SPI_IOC_RD_LSB_FIRST => {
+                let lsb_first = self.settings.lock().mode & SPI_LSB_FIRST != 0;
+                (arg as *mut u8).vm_write(lsb_first as u8)?;
+            }
+            SPI_IOC_RD_BITS_PER_WORD => {
+                (arg as *mut u8).vm_write(self.settings.lock().bits_per_word)?;
+            }
+            SPI_IOC_RD_MAX_SPEED_HZ => {
+                (arg as *mut u32).vm_write(self.settings.lock().max_speed_hz)?;
+            }
+            SPI_IOC_WR_MODE => {
+                // The 8-bit mode only replaces the low bits.
+                let low = (arg as *const u8).vm_read()? as u32;
+                let mode = self.settings.lock().mode;
+                self.set_mode((mode & !0xff) | low)?;
+            }
+            SPI_IOC_WR_MODE32 => self.set_mode((arg as *const u32).vm_read()?)?,
+            SPI_IOC_WR_LSB_FIRST => {
+                let lsb_first = (arg as *const u8).vm_read()? != 0;
+                let mut mode = self.settings.lock().mode & !SPI_LSB_FIRST;
+                if lsb_first {
+                    mode |= SPI_LSB_FIRST;
+                }
+                self.set_mode(mode)?;
+            }
+            SPI_IOC_WR_BITS_PER_WORD => {
+                let bits = match (arg as *const u8).vm_read()? {
+                    0 => 8,
+                    bits => bits,
+                };
+                if !self.bits_supported(bits) {
+                    return Err(VfsError::InvalidInput);
+                }
+                self.settings.lock().bits_per_word = bits;
+            }
+            SPI_IOC_WR_MAX_SPEED_HZ => {
+                let speed = (arg as *const u32).vm_read()?;
+                if speed == 0 {
+                    return Err(VfsError::InvalidInput);
+                }
+                self.settings.lock().max_speed_hz = speed;
+            }
+            _ if cmd & !IOC_SIZE_MASK == SPI_IOC_MESSAGE_BASE => {
+                let size = ((cmd & IOC_SIZE_MASK) >> 16) as usize;
+                let count = size / size_of::<SpiIocTransfer>();
+                if count == 0 || size % size_of::<SpiIocTransfer>() != 0 {
+                    return Err(VfsError::InvalidInput);
+                }
+                return Ok(self.message(arg, count)?);
+            }
+            _ => return Err(VfsError::BadIoctl),
+        }
+        Ok(0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
@@ -0,0 +1,227 @@
+//! SPI buses bit-banged on GPIO lines, described in the device tree as
+//! `spi-gpio`.
+//!
+//! The clock is required; without a MOSI line zeroes are sent, and without
+//! a MISO line zeroes are received. A bus without chip select lines has a
+//! single device, which is always selected.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use axerrno::{AxError, AxResult};
+use spin::Mutex;
+use starry_core::boot::{self, FdtDevice};
+
+use super::{
+    SPI_CPHA, SPI_CPOL, SPI_CS_HIGH, SPI_LSB_FIRST, SPI_NO_CS, SpiController, SpiTransfer,
+    word_size,
+};
+use crate::{
+    time::udelay,
+    vfs::dev::gpio::{self, Bias, Direction, Drive, DtLine, LineSettings},
+};
+
+/// How fast the clock is driven at most; the GPIO accesses are slower than
+/// this on most boards anyway.
+const MAX_SPEED_HZ: u32 = 1_000_000;
+
+pub struct GpioSpi {
+    name: String,
+    sck: DtLine,
+    mosi: Option<DtLine>,
+    miso: Option<DtLine>,
+    cs: Vec<DtLine>,
+    /// Serializes messages.
+    bus: Mutex<()>,
+}
+
+fn setup(line: &DtLine, direction: Direction, value: bool) -> AxResult {
+    let settings = LineSettings {
+        direction,
+        drive: Drive::PushPull,
+        bias: Bias::AsIs,
+        debounce_us: 0,
+        rising: false,
+        falling: false,
+    };
+    line.chip.configure(line.offset, &settings, value)
+}
+
+impl GpioSpi {
+    fn set_cs(&self, cs: u8, mode: u32, selected: bool) {
+        if let Some(line) = self.cs.get(cs as usize) {
+            line.chip
+                .set(line.offset, selected == (mode & SPI_CS_HIGH != 0));
+        }
+    }
+
+    /// Shifts a word out and in, in SPI mode `mode`, with the clock idle
+    /// before and after.
+    fn word(&self, mode: u32, bits: u8, half_period_us: u64, out: u32) -> u32 {
+        let idle = mode & SPI_CPOL != 0;
+        let sck = |level| self.sck.chip.set(self.sck.offset, level);
+        let mosi = |level| {
+            if let Some(line) = &self.mosi {
+                line.chip.set(line.offset, level);
+            }
+        };
+        let miso = || {
+            self.miso
+                .as_ref()
+                .is_some_and(|line| line.chip.get(line.offset))
+        };
+
+        let mut word = 0;
+        for i in 0..bits {
+            let bit = if mode & SPI_LSB_FIRST != 0 {
+                i
+            } else {
+                bits - 1 - i
+            };
+            let level = out & (1 << bit) != 0;
+            let sampled = if mode & SPI_CPHA == 0 {
+                // Data is valid before the leading edge and sampled on it.
+                mosi(level);
+                udelay(half_period_us);
+                sck(!idle);
+                let sampled = miso();
+                udelay(half_period_us);
+                sck(idle);
+                sampled
+            } else {
+                // Data changes on the leading edge and is sampled on the
+                // trailing one.
+                sck(!idle);
+                mosi(level);
+                udelay(half_period_us);
+                sck(idle);
+                let sampled = miso();
+                udelay(half_period_us);
+                sampled
+            };
+            word |= (sampled as u32) << bit;
+        }
+        word
+    }
+
+    fn run(&self, xfer: &mut SpiTransfer, mode: u32) {
+        let size = word_size(xfer.bits_per_word);
+        let half_period_us = 500_000 / xfer.speed_hz.max(1) as u64;
+        for pos in (0..xfer.len).step_by(size) {
+            let mut bytes = [0; 4];
+            if let Some(tx) = &xfer.tx {
+                bytes[..size].copy_from_slice(&tx[pos..pos + size]);
+            }
+            let out = u32::from_ne_bytes(bytes);
+            let word = self.word(mode, xfer.bits_per_word, half_period_us, out);
+            if let Some(rx) = &mut xfer.rx {
+                rx[pos..pos + size].copy_from_slice(&word.to_ne_bytes()[..size]);
+            }
+            if pos + size < xfer.len {
+                udelay(xfer.word_delay_us as u64);
+            }
+        }
+    }
+}
+
+impl SpiController for GpioSpi {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn num_chip_selects(&self) -> u8 {
+        self.cs.len().max(1) as u8
+    }
+
+    fn max_speed_hz(&self) -> u32 {
+        MAX_SPEED_HZ
+    }
+
+    fn mode_bits(&self) -> u32 {
+        let mut bits = SPI_CPHA | SPI_CPOL | SPI_LSB_FIRST;
+        if self.cs.is_empty() {
+            bits |= SPI_NO_CS;
+        } else {
+            bits |= SPI_CS_HIGH;
+        }
+        bits
+    }
+
+    fn bits_per_word_mask(&self) -> u32 {
+        u32::MAX
+    }
+
+    fn transfer(&self, cs: u8, mode: u32, transfers: &mut [SpiTransfer]) -> AxResult {
+        if cs >= self.num_chip_selects() {
+            return Err(AxError::InvalidInput);
+        }
+        let _bus = self.bus.lock();
+        // The clock must be idle before the device is selected.
+        self.sck.chip.set(self.sck.offset, mode & SPI_CPOL != 0);
+        self.set_cs(cs, mode, true);
+        let count = transfers.len();
+        for (i, xfer) in transfers.iter_mut().enumerate() {
+            self.run(xfer, mode);
+            udelay(xfer.delay_us as u64);
+            if xfer.cs_change && i + 1 < count {
+                self.set_cs(cs, mode, false);
+                udelay(1);
+                self.set_cs(cs, mode, true);
+            }
+        }
+        if !transfers.last().is_some_and(|xfer| xfer.cs_change) {
+            self.set_cs(cs, mode, false);
+        }
+        Ok(())
+    }
+}
+
+fn add(dev: &FdtDevice) -> AxResult<Arc<GpioSpi>> {
+    let node = dev.node;
+    // Older device trees name the lines `gpio-*`.
+    let line = |name: &str, legacy: &str| {
+        gpio::dt_line(node, name, 0).or_else(|| gpio::dt_line(node, legacy, 0))
+    };
+    // The lines are missing, or their controller has no driver.
+    let sck = line("sck-gpios", "gpio-sck").ok_or(AxError::NoSuchDevice)?;
+    let mosi = line("mosi-gpios", "gpio-mosi");
+    let miso = line("miso-gpios", "gpio-miso");
+    let num_cs = node
+        .property_u32("num-chipselects")
+        .map_or(usize::MAX, |it| it as usize);
+    let cs: Vec<_> = (0..num_cs.min(u8::MAX as usize))
+        .map_while(|index| gpio::dt_line(node, "cs-gpios", index))
+        .collect();
+
+    setup(&sck, Direction::Output, false)?;
+    if let Some(mosi) = &mosi {
+        setup(mosi, Direction::Output, false)?;
+    }
+    if let Some(miso) = &miso {
+        setup(miso, Direction::Input, false)?;
+    }
+    for line in &cs {
+        // Chip selects are active low unless a device asks otherwise.
+        setup(line, Direction::Output, true)?;
+    }
+    Ok(Arc::new(GpioSpi {
+        name: node.name.clone(),
+        sck,
+        mosi,
+        miso,
+        cs,
+        bus: Mutex::new(()),
+    }))
+}
+
+/// Finds the buses in the device tree. The GPIO controllers must have been
+/// probed first.
+pub fn probe() -> Vec<Arc<dyn SpiController>> {
+    let mut controllers = Vec::new();
+    for dev in boot::find_compatible(&["spi-gpio"]) {
+        match add(&dev) {
+            Ok(controller) => controllers.push(controller as _),
+            Err(err) => warn!("spi: failed to set up {}: {err:?}", dev.node.name),
+        }
+    }
+    controllers
+}
@@ -19,12 +19,15 @@ use crate::{
     vfs::DeviceOps,
 };
 
+mod console;
+mod flip;
 mod ntty;
 mod ptm;
 mod pts;
 mod pty;
 
-pub use ntty::{N_TTY, NTtyDriver};
+pub use console::{ConsoleBackend, HVC_MAJOR, TTYS_MAJOR};
+pub use ntty::{N_TTY, NTtyDriver, consoles, new_n_tty, register_console};
 pub use ptm::Ptmx;
 pub use pts::PtsDir;
 pub use pty::PtyDriver;
@@ -35,6 +38,24 @@ pub fn create_pty_master(fs: Arc<SimpleFs>) -> AxResult<Arc<PtyDriver>> {
     Ok(master)
 }
 
+/// `struct serial_icounter_struct`.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct SerialICounter {
+    cts: i32,
+    dsr: i32,
+    rng: i32,
+    dcd: i32,
+    rx: i32,
+    tx: i32,
+    frame: i32,
+    overrun: i32,
+    parity: i32,
+    brk: i32,
+    buf_overrun: i32,
+    reserved: [i32; 9],
+}
+
 /// Tty device
 pub struct Tty<R, W> {
     this: Weak<Self>,
@@ -91,7 +112,16 @@ impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
     }
 
     fn write_at(&self, buf: &[u8], _offset: u64) -> AxResult<usize> {
+        // Output stopped with `VSTOP` waits for `VSTART`; the file layer polls
+        // for `OUT` and retries. The master side of a pty is never stopped.
+        if !self.is_ptm && self.terminal.output_stopped() {
+            return Err(AxError::WouldBlock);
+        }
         self.writer.write(buf);
+        self.terminal
+            .counters
+            .tx
+            .fetch_add(buf.len() as u32, Ordering::Relaxed);
         Ok(buf.len())
     }
 
@@ -139,6 +169,15 @@ impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
             TIOCSWINSZ => {
                 *self.terminal.window_size.lock() = (arg as *const WindowSize).vm_read()?;
             }
+            TIOCGICOUNT => {
+                let counters = &self.terminal.counters;
+                (arg as *mut SerialICounter).vm_write(SerialICounter {
+                    rx: counters.rx.load(Ordering::Relaxed) as _,
+                    tx: counters.tx.load(Ordering::Relaxed) as _,
+                    buf_overrun: counters.buf_overrun.load(Ordering::Relaxed) as _,
+                    ..Default::default()
+                })?;
+            }
             TIOCSPTLCK => {}
             TIOCGPTN => {
                 (arg as *mut u32).vm_write(self.pty_number())?;
@@ -187,7 +226,11 @@ impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
 
 impl<R: TtyRead, W: TtyWrite> Pollable for Tty<R, W> {
     fn poll(&self) -> IoEvents {
-        let mut events = IoEvents::OUT | self.terminal.job_control.poll();
+        let mut events = self.terminal.job_control.poll();
+        events.set(
+            IoEvents::OUT,
+            self.is_ptm || !self.terminal.output_stopped(),
+        );
         if self.is_ptm || events.contains(IoEvents::IN) {
             events.set(IoEvents::IN, self.ldisc.lock().poll_read());
         }
@@ -201,6 +244,9 @@ impl<R: TtyRead, W: TtyWrite> Pollable for Tty<R, W> {
         if events.contains(IoEvents::IN) {
             self.ldisc.lock().register_rx_waker(context.waker());
         }
+        if events.contains(IoEvents::OUT) {
+            self.terminal.poll_output().register(context.waker());
+        }
     }
 }
 
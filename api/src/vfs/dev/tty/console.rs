@@ -0,0 +1,133 @@
+//! Console transports.
+//!
+//! Each backend becomes a tty of its own, `/dev/<name>`. The platform
+//! console of axhal is always there; on RISC-V, the SBI debug console is
+//! added as `hvc0` when the firmware or hypervisor implements it. Drivers for
+//! other transports, such as virtio-console, add theirs with
+//! [`register_console`](super::register_console).
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::task::Waker;
+
+use axfs_ng_vfs::DeviceId;
+
+/// The major number of `ttyS*`.
+pub const TTYS_MAJOR: u32 = 4;
+/// The major number of `hvc*`.
+pub const HVC_MAJOR: u32 = 229;
+
+/// A console transport.
+pub trait ConsoleBackend: Send + Sync {
+    /// Returns the name of the device, e.g. `ttyS0` or `hvc0`.
+    fn name(&self) -> &str;
+
+    fn device_id(&self) -> DeviceId;
+
+    /// Reads the input received so far, without blocking.
+    fn read(&self, buf: &mut [u8]) -> usize;
+
+    fn write(&self, buf: &[u8]);
+
+    /// Returns the interrupt raised on input. Consoles without one are only
+    /// read when the tty is.
+    fn irq(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns whether the backend wakes the tty on input through
+    /// [`set_rx_waker`](Self::set_rx_waker), as consoles without an
+    /// interrupt of their own but a driver behind them do.
+    fn notifies_rx(&self) -> bool {
+        false
+    }
+
+    /// Sets the waker to wake when input arrives.
+    fn set_rx_waker(&self, _waker: Waker) {}
+}
+
+/// The console of axhal, which the kernel log also goes to.
+struct PlatformConsole;
+
+impl ConsoleBackend for PlatformConsole {
+    fn name(&self) -> &str {
+        if cfg!(target_arch = "aarch64") {
+            "ttyAMA0"
+        } else {
+            "ttyS0"
+        }
+    }
+
+    fn device_id(&self) -> DeviceId {
+        if cfg!(target_arch = "aarch64") {
+            DeviceId::new(204, 64)
+        } else {
+            DeviceId::new(TTYS_MAJOR, 64)
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        axhal::console::read_bytes(buf)
+    }
+
+    fn write(&self, buf: &[u8]) {
+        axhal::console::write_bytes(buf);
+    }
+
+    fn irq(&self) -> Option<usize> {
+        axhal::console::irq_number().map(|irq| irq as _)
+    }
+}
+
+/// The SBI debug console extension (DBCN).
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+struct SbiConsole;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+impl SbiConsole {
+    fn probe() -> bool {
+        sbi_rt::probe_extension(sbi_rt::Console).is_available()
+    }
+
+    fn physical(buf: &[u8]) -> sbi_rt::Physical<&[u8]> {
+        let paddr = axhal::mem::virt_to_phys(buf.as_ptr().into()).as_usize();
+        sbi_rt::Physical::new(buf.len(), paddr, 0)
+    }
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+impl ConsoleBackend for SbiConsole {
+    fn name(&self) -> &str {
+        "hvc0"
+    }
+
+    fn device_id(&self) -> DeviceId {
+        DeviceId::new(HVC_MAJOR, 0)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let paddr = axhal::mem::virt_to_phys(buf.as_ptr().into()).as_usize();
+        let ret = sbi_rt::console_read(sbi_rt::Physical::new(buf.len(), paddr, 0));
+        ret.ok().unwrap_or(0)
+    }
+
+    fn write(&self, mut buf: &[u8]) {
+        // The firmware may write only part of the buffer at a time.
+        while !buf.is_empty() {
+            match sbi_rt::console_write(Self::physical(buf)).ok() {
+                Some(written) if written > 0 => buf = &buf[written.min(buf.len())..],
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Returns the consoles every system has.
+pub(super) fn default_backends() -> Vec<Arc<dyn ConsoleBackend>> {
+    #[allow(unused_mut)]
+    let mut backends: Vec<Arc<dyn ConsoleBackend>> = vec![Arc::new(PlatformConsole)];
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    if SbiConsole::probe() {
+        backends.push(Arc::new(SbiConsole));
+    }
+    backends
+}
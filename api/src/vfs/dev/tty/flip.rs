@@ -0,0 +1,160 @@
+//! Interrupt-driven console input.
+//!
+//! The interrupt handler drains the UART into a flip buffer right away, so
+//! that input is not lost to a small hardware FIFO while the tty reader task
+//! waits to be scheduled. The task then feeds the line discipline from the
+//! flip buffer. Input arriving while the flip buffer is full is dropped and
+//! counted as a buffer overrun.
+//!
+//! With `IXOFF` set, the sender is asked to pause with `VSTOP` once the
+//! buffer is three quarters full, and to resume with `VSTART` once it has
+//! drained to a quarter.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    task::Waker,
+};
+
+use kspin::SpinNoIrq;
+use ringbuf::{
+    StaticRb,
+    traits::{Consumer, Observer, Producer},
+};
+
+use super::ConsoleBackend;
+use crate::terminal::Terminal;
+
+const FLIP_SIZE: usize = 4096;
+const HIGH_WATER: usize = FLIP_SIZE * 3 / 4;
+const LOW_WATER: usize = FLIP_SIZE / 4;
+
+/// Set in [`FlipBuffer::input_flow`] when `IXOFF` is on.
+const IXOFF_ON: u32 = 1 << 16;
+
+struct FlipState {
+    buf: StaticRb<u8, FLIP_SIZE>,
+    waker: Option<Waker>,
+    /// Whether `VSTOP` was sent and `VSTART` is still due.
+    throttled: bool,
+}
+
+pub struct FlipBuffer {
+    backend: Arc<dyn ConsoleBackend>,
+    terminal: Arc<Terminal>,
+    state: SpinNoIrq<FlipState>,
+    /// `VSTOP` in the low byte and `VSTART` in the next, with [`IXOFF_ON`].
+    input_flow: AtomicU32,
+}
+
+/// The consoles read from interrupt handlers.
+static FLIPS: SpinNoIrq<Vec<Arc<FlipBuffer>>> = SpinNoIrq::new(Vec::new());
+/// The interrupts a handler was registered for.
+static IRQS: SpinNoIrq<Vec<usize>> = SpinNoIrq::new(Vec::new());
+
+/// Drains every interrupt-driven console. The handler is shared between all
+/// console interrupts, since it isn't told which one fired.
+fn handle_irq() {
+    for flip in FLIPS.lock().iter() {
+        flip.receive();
+    }
+}
+
+impl FlipBuffer {
+    /// Starts receiving from `backend` on interrupt `irq`.
+    pub fn new(backend: Arc<dyn ConsoleBackend>, terminal: Arc<Terminal>, irq: usize) -> Arc<Self> {
+        let flip = Arc::new(Self {
+            backend,
+            terminal,
+            state: SpinNoIrq::new(FlipState {
+                buf: StaticRb::default(),
+                waker: None,
+                throttled: false,
+            }),
+            input_flow: AtomicU32::new(0),
+        });
+        FLIPS.lock().push(flip.clone());
+        let mut irqs = IRQS.lock();
+        if !irqs.contains(&irq) {
+            if axhal::irq::register(irq, handle_irq) {
+                irqs.push(irq);
+            } else {
+                warn!("console: failed to register handler for IRQ {irq}");
+            }
+        }
+        flip
+    }
+
+    fn input_flow(&self) -> Option<(u8, u8)> {
+        let flow = self.input_flow.load(Ordering::Relaxed);
+        (flow & IXOFF_ON != 0).then_some((flow as u8, (flow >> 8) as u8))
+    }
+
+    /// Moves the pending input into the buffer. Called from the interrupt
+    /// handler.
+    fn receive(&self) {
+        let mut state = self.state.lock();
+        let mut chunk = [0; 64];
+        loop {
+            let read = self.backend.read(&mut chunk);
+            if read == 0 {
+                break;
+            }
+            let pushed = state.buf.push_slice(&chunk[..read]);
+            if pushed < read {
+                let counters = &self.terminal.counters;
+                counters
+                    .buf_overrun
+                    .fetch_add((read - pushed) as u32, Ordering::Relaxed);
+                // Dropped input never reaches the line discipline, which
+                // counts the rest.
+                counters
+                    .rx
+                    .fetch_add((read - pushed) as u32, Ordering::Relaxed);
+            }
+        }
+        if !state.throttled
+            && state.buf.occupied_len() >= HIGH_WATER
+            && let Some((stop, _)) = self.input_flow()
+        {
+            self.backend.write(&[stop]);
+            state.throttled = true;
+        }
+        if !state.buf.is_empty()
+            && let Some(waker) = &state.waker
+        {
+            waker.wake_by_ref();
+        }
+    }
+
+    /// Takes input out of the buffer.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut state = self.state.lock();
+        let read = state.buf.pop_slice(buf);
+        if state.throttled && state.buf.occupied_len() <= LOW_WATER {
+            // `VSTART` is still sent if `IXOFF` was turned off meanwhile, so
+            // the sender isn't left paused.
+            let start = match self.input_flow() {
+                Some((_, start)) => start,
+                None => (self.input_flow.load(Ordering::Relaxed) >> 8) as u8,
+            };
+            self.backend.write(&[start]);
+            state.throttled = false;
+        }
+        read
+    }
+
+    /// Sets the task woken when input arrives.
+    pub fn set_waker(&self, waker: Waker) {
+        self.state.lock().waker = Some(waker);
+    }
+
+    pub fn set_input_flow(&self, chars: Option<(u8, u8)>) {
+        let flow = match chars {
+            Some((stop, start)) => IXOFF_ON | (start as u32) << 8 | stop as u32,
+            // Keep `VSTART` around for a pending resume.
+            None => self.input_flow.load(Ordering::Relaxed) & 0xff00,
+        };
+        self.input_flow.store(flow, Ordering::Relaxed);
+    }
+}
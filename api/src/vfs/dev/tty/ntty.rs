@@ -1,45 +1,116 @@
-use alloc::{boxed::Box, sync::Arc};
-use axhal::irq::register_irq_waker;
-use core::sync::atomic::AtomicBool;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 
 use lazy_static::lazy_static;
+use spin::RwLock;
 
-use super::Tty;
-use crate::terminal::ldisc::{ProcessMode, TtyConfig, TtyRead, TtyWrite};
+use super::{
+    Tty,
+    console::{ConsoleBackend, default_backends},
+    flip::FlipBuffer,
+};
+use crate::terminal::{
+    Terminal,
+    ldisc::{ProcessMode, TtyConfig, TtyRead, TtyWrite},
+};
 
 pub type NTtyDriver = Tty<Console, Console>;
 
-#[derive(Clone, Copy)]
-pub struct Console;
+/// A console backend, as the input and output of a tty.
+///
+/// Consoles with an interrupt are read through a [`FlipBuffer`] filled by the
+/// interrupt handler.
+#[derive(Clone)]
+pub struct Console {
+    backend: Arc<dyn ConsoleBackend>,
+    flip: Option<Arc<FlipBuffer>>,
+}
 impl TtyRead for Console {
     fn read(&mut self, buf: &mut [u8]) -> usize {
-        axhal::console::read_bytes(buf)
+        match &self.flip {
+            Some(flip) => flip.read(buf),
+            None => self.backend.read(buf),
+        }
+    }
+
+    fn set_input_flow(&mut self, chars: Option<(u8, u8)>) {
+        if let Some(flip) = &self.flip {
+            flip.set_input_flow(chars);
+        }
     }
 }
 impl TtyWrite for Console {
     fn write(&self, buf: &[u8]) {
-        axhal::console::write_bytes(buf);
+        self.backend.write(buf);
+    }
+}
+
+impl NTtyDriver {
+    /// Returns the console backing the tty.
+    pub fn backend(&self) -> &Arc<dyn ConsoleBackend> {
+        &self.writer.backend
     }
 }
 
 lazy_static! {
-    /// The default TTY device.
-    pub static ref N_TTY: Arc<NTtyDriver> = new_n_tty();
+    static ref CONSOLES: RwLock<Vec<Arc<NTtyDriver>>> =
+        RwLock::new(default_backends().into_iter().map(new_n_tty).collect());
+
+    /// The default TTY device, `/dev/console`.
+    ///
+    /// This is the console named by `console=` on the kernel command line,
+    /// or the first one.
+    pub static ref N_TTY: Arc<NTtyDriver> = {
+        let consoles = CONSOLES.read();
+        starry_core::cmdline::console()
+            .and_then(|name| consoles.iter().find(|it| it.backend().name() == name))
+            .unwrap_or(&consoles[0])
+            .clone()
+    };
+}
+
+/// Adds a console, which shows up as `/dev/<name>`.
+///
+/// Consoles must be registered before devfs is mounted.
+pub fn register_console(backend: Arc<dyn ConsoleBackend>) {
+    let mut consoles = CONSOLES.write();
+    if consoles
+        .iter()
+        .any(|it| it.backend().name() == backend.name())
+    {
+        warn!("console {} registered twice", backend.name());
+        return;
+    }
+    info!("console: registered {}", backend.name());
+    consoles.push(new_n_tty(backend));
+}
+
+/// Returns all consoles.
+pub fn consoles() -> Vec<Arc<NTtyDriver>> {
+    CONSOLES.read().clone()
 }
 
-fn new_n_tty() -> Arc<NTtyDriver> {
+/// Creates a tty on `backend` without registering it, for consoles that come
+/// and go, such as USB serial ports.
+pub fn new_n_tty(backend: Arc<dyn ConsoleBackend>) -> Arc<NTtyDriver> {
+    let terminal = Arc::new(Terminal::default());
+    let flip = backend
+        .irq()
+        .map(|irq| FlipBuffer::new(backend.clone(), terminal.clone(), irq));
+    let process_mode = if let Some(flip) = flip.clone() {
+        ProcessMode::External(Box::new(move |waker| flip.set_waker(waker)) as _)
+    } else if backend.notifies_rx() {
+        let backend = backend.clone();
+        ProcessMode::External(Box::new(move |waker| backend.set_rx_waker(waker)) as _)
+    } else {
+        ProcessMode::Manual
+    };
+    let console = Console { backend, flip };
     Tty::new(
-        Arc::default(),
+        terminal,
         TtyConfig {
-            reader: Console,
-            writer: Console,
-            process_mode: if let Some(irq) = axhal::console::irq_number() {
-                ProcessMode::External(
-                    Box::new(move |waker| register_irq_waker(irq as _, &waker)) as _
-                )
-            } else {
-                ProcessMode::Manual
-            },
+            reader: console.clone(),
+            writer: console,
+            process_mode,
         },
     )
 }
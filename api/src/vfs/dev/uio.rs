@@ -0,0 +1,389 @@
+//! Userspace I/O devices, `/dev/uioN`, for drivers living in userspace.
+//!
+//! Each device has memory regions to map, map `N` being mapped at offset
+//! `N * PAGE_SIZE`, and an interrupt. Reading the device blocks until the
+//! interrupt fires and returns the number of interrupts so far as a `u32`.
+//! The interrupt is masked when it fires, and writing a `u32` of 1 to the
+//! device unmasks it again (0 masks it). Eventfds bound with
+//! [`UIO_SET_EVENTFD`] are signalled on each interrupt too, so that drivers
+//! can wait for several devices with epoll, or hand interrupts to a VMM as
+//! irqfds.
+//!
+//! PCI functions are turned into UIO devices with [`register_pci`], raising
+//! MSI-X or MSI if the platform registered an MSI controller, and their
+//! legacy interrupt otherwise. Platform drivers register other devices with
+//! [`register`]. Devices must be registered before devfs is mounted.
+//!
+//! [`probe`] registers the `generic-uio` nodes of the device tree, and the
+//! PCI functions whose IDs are listed in the `uio_pci_generic.ids=` kernel
+//! parameter, as `vendor:device` pairs in hex separated by commas.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng_vfs::{DeviceId, NodeFlags, VfsResult};
+use axpoll::{IoEvents, PollSet, Pollable};
+use kspin::SpinNoIrq;
+use memory_addr::{PhysAddr, PhysAddrRange, align_down_4k, align_up_4k};
+use starry_core::{
+    boot::{self, FdtDevice},
+    cmdline,
+    vfs::{DeviceMmap, DeviceOps},
+};
+
+use crate::{
+    file::{FileLike, event::EventFd, get_file_like},
+    pci::{self, PciDevice},
+};
+
+/// Binds the eventfd passed as the argument to the interrupt, or unbinds
+/// all of them if it is -1. This is specific to StarryOS.
+pub const UIO_SET_EVENTFD: u32 = 0x4004_7500;
+
+/// The major number of `/dev/uioN`, dynamic on Linux.
+pub const UIO_MAJOR: u32 = 241;
+
+/// How many UIO devices there may be, one interrupt handler each.
+const MAX_UIO: usize = 16;
+
+/// A memory region of a device.
+#[derive(Debug, Clone)]
+pub struct UioMap {
+    pub name: String,
+    /// The physical address of the region.
+    pub addr: u64,
+    pub size: u64,
+}
+
+enum UioIrq {
+    None,
+    /// A platform interrupt, masked at the interrupt controller.
+    Platform(usize),
+    /// The legacy interrupt of a PCI function, masked at the function as the
+    /// line may be shared.
+    Intx(Arc<PciDevice>),
+    /// An MSI or MSI-X of a PCI function, which isn't masked but dropped
+    /// while disabled.
+    Msi,
+}
+
+pub struct UioDevice {
+    index: usize,
+    name: String,
+    maps: Vec<UioMap>,
+    irq: UioIrq,
+    /// Whether the interrupt is unmasked.
+    enabled: AtomicBool,
+    count: AtomicU32,
+    /// The count last returned by a read.
+    read_count: AtomicU32,
+    eventfds: SpinNoIrq<Vec<Arc<EventFd>>>,
+    poll_rx: PollSet,
+}
+
+static DEVICES: SpinNoIrq<Vec<Arc<UioDevice>>> = SpinNoIrq::new(Vec::new());
+
+fn handle_irq<const N: usize>() {
+    let dev = DEVICES.lock().get(N).cloned();
+    if let Some(dev) = dev {
+        dev.interrupt();
+    }
+}
+
+/// The interrupt handlers, one for each device, as handlers aren't told
+/// which interrupt fired.
+const HANDLERS: [fn(); MAX_UIO] = [
+    handle_irq::<0>,
+    handle_irq::<1>,
+    handle_irq::<2>,
+    handle_irq::<3>,
+    handle_irq::<4>,
+    handle_irq::<5>,
+    handle_irq::<6>,
+    handle_irq::<7>,
+    handle_irq::<8>,
+    handle_irq::<9>,
+    handle_irq::<10>,
+    handle_irq::<11>,
+    handle_irq::<12>,
+    handle_irq::<13>,
+    handle_irq::<14>,
+    handle_irq::<15>,
+];
+
+fn add(name: &str, maps: Vec<UioMap>, irq: UioIrq, irq_number: Option<usize>) -> AxResult<usize> {
+    let mut devices = DEVICES.lock();
+    let index = devices.len();
+    if index >= MAX_UIO {
+        return Err(AxError::NoMemory);
+    }
+    devices.push(Arc::new(UioDevice {
+        index,
+        name: name.into(),
+        maps,
+        irq,
+        enabled: AtomicBool::new(true),
+        count: AtomicU32::new(0),
+        read_count: AtomicU32::new(0),
+        eventfds: SpinNoIrq::new(Vec::new()),
+        poll_rx: PollSet::new(),
+    }));
+    drop(devices);
+
+    if let Some(irq) = irq_number
+        && !axhal::irq::register(irq, HANDLERS[index])
+    {
+        warn!("uio: failed to register handler for IRQ {irq}, which may be shared");
+    }
+    info!("uio: uio{index} is {name}");
+    Ok(index)
+}
+
+/// Adds a device with memory regions `maps` and interrupt `irq`, returning
+/// its number.
+pub fn register(name: &str, maps: Vec<UioMap>, irq: Option<usize>) -> AxResult<usize> {
+    let kind = irq.map_or(UioIrq::None, UioIrq::Platform);
+    add(name, maps, kind, irq)
+}
+
+/// Adds a PCI function, with its memory BARs as the regions, returning its
+/// number.
+pub fn register_pci(dev: Arc<PciDevice>) -> AxResult<usize> {
+    let maps = dev
+        .resources()
+        .iter()
+        .enumerate()
+        .filter(|(_, bar)| bar.is_mem() && bar.size != 0)
+        .map(|(index, bar)| UioMap {
+            name: format!("BAR{index}"),
+            addr: bar.start,
+            size: bar.size,
+        })
+        .collect();
+    let name = format!("uio_pci_generic {}", dev.address());
+    match dev.enable_msi() {
+        Ok(irq) => add(&name, maps, UioIrq::Msi, Some(irq)),
+        Err(_) => {
+            // 0xff is "not connected" on x86.
+            let line = dev.irq();
+            let irq = (line != 0 && line != 0xff).then_some(line as usize);
+            let kind = if irq.is_some() {
+                UioIrq::Intx(dev)
+            } else {
+                UioIrq::None
+            };
+            add(&name, maps, kind, irq)
+        }
+    }
+}
+
+fn register_dt(dev: &FdtDevice) -> AxResult<usize> {
+    let names: Vec<_> = dev.node.strings("reg-names").collect();
+    let maps = dev
+        .regs()
+        .into_iter()
+        .enumerate()
+        .map(|(index, (addr, size))| UioMap {
+            name: names
+                .get(index)
+                .map_or_else(|| format!("map{index}"), |name| String::from(*name)),
+            addr,
+            size,
+        })
+        .collect();
+    register(&dev.node.name, maps, dev.irq(0))
+}
+
+/// Parses a `vendor:device` pair of IDs.
+fn parse_id(id: &str) -> Option<(u16, u16)> {
+    let (vendor, device) = id.split_once(':')?;
+    Some((
+        u16::from_str_radix(vendor, 16).ok()?,
+        u16::from_str_radix(device, 16).ok()?,
+    ))
+}
+
+/// Registers the devices the device tree and the command line hand to
+/// userspace. The PCI host bridges must have been registered first.
+pub fn probe() {
+    for dev in boot::find_compatible(&["generic-uio"]) {
+        if let Err(err) = register_dt(&dev) {
+            warn!("uio: failed to set up {}: {err:?}", dev.node.name);
+        }
+    }
+
+    let Some(ids) = cmdline::get("uio_pci_generic.ids") else {
+        return;
+    };
+    let mut wanted = Vec::new();
+    for id in ids.split(',').filter(|id| !id.is_empty()) {
+        match parse_id(id) {
+            Some(id) => wanted.push(id),
+            None => warn!("uio: ignoring bad PCI ID {id:?}"),
+        }
+    }
+    for dev in pci::devices() {
+        if dev.is_bridge() || !wanted.contains(&(dev.vendor(), dev.device())) {
+            continue;
+        }
+        let address = dev.address();
+        if let Err(err) = register_pci(dev) {
+            warn!("uio: failed to set up PCI function {address}: {err:?}");
+        }
+    }
+}
+
+/// Returns all UIO devices.
+pub fn devices() -> Vec<Arc<UioDevice>> {
+    DEVICES.lock().clone()
+}
+
+impl UioDevice {
+    pub fn name(&self) -> String {
+        format!("uio{}", self.index)
+    }
+
+    pub fn device_id(&self) -> DeviceId {
+        DeviceId::new(UIO_MAJOR, self.index as _)
+    }
+
+    /// Returns the name of the driver, as in `/sys/class/uio/uioN/name`.
+    pub fn driver_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn maps(&self) -> &[UioMap] {
+        &self.maps
+    }
+
+    /// Returns the number of interrupts so far.
+    pub fn event_count(&self) -> u32 {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Counts an interrupt and masks it. Called from the interrupt handler.
+    fn interrupt(&self) {
+        match &self.irq {
+            UioIrq::None => return,
+            UioIrq::Platform(irq) => axhal::irq::set_enable(*irq, false),
+            UioIrq::Intx(dev) => {
+                // Another function on the line raised it.
+                if !dev.intx_pending() {
+                    return;
+                }
+                dev.set_intx_disabled(true);
+            }
+            UioIrq::Msi => {
+                if !self.enabled.load(Ordering::Acquire) {
+                    return;
+                }
+            }
+        }
+        self.enabled.store(false, Ordering::Release);
+        self.count.fetch_add(1, Ordering::AcqRel);
+        for eventfd in self.eventfds.lock().iter() {
+            eventfd.signal(1);
+        }
+        self.poll_rx.wake();
+    }
+
+    fn set_irq_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+        match &self.irq {
+            UioIrq::Platform(irq) => axhal::irq::set_enable(*irq, enabled),
+            UioIrq::Intx(dev) => dev.set_intx_disabled(!enabled),
+            UioIrq::None | UioIrq::Msi => {}
+        }
+    }
+}
+
+impl DeviceOps for UioDevice {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        if matches!(self.irq, UioIrq::None) {
+            return Err(AxError::Other(LinuxError::EIO));
+        }
+        if buf.len() != size_of::<u32>() {
+            return Err(AxError::InvalidInput);
+        }
+        let count = self.count.load(Ordering::Acquire);
+        if self.read_count.swap(count, Ordering::AcqRel) == count {
+            return Err(AxError::WouldBlock);
+        }
+        buf.copy_from_slice(&count.to_ne_bytes());
+        Ok(size_of::<u32>())
+    }
+
+    fn write_at(&self, buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        if matches!(self.irq, UioIrq::None) {
+            return Err(AxError::Other(LinuxError::EIO));
+        }
+        let value: [u8; 4] = buf.try_into().map_err(|_| AxError::InvalidInput)?;
+        self.set_irq_enabled(u32::from_ne_bytes(value) != 0);
+        Ok(size_of::<u32>())
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        match cmd {
+            UIO_SET_EVENTFD => {
+                let fd = arg as i32;
+                if fd == -1 {
+                    self.eventfds.lock().clear();
+                    return Ok(0);
+                }
+                let eventfd = get_file_like(fd)?
+                    .into_any()
+                    .downcast::<EventFd>()
+                    .map_err(|_| AxError::InvalidInput)?;
+                self.eventfds.lock().push(eventfd);
+                Ok(0)
+            }
+            _ => Err(AxError::BadIoctl),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_pollable(&self) -> Option<&dyn Pollable> {
+        Some(self)
+    }
+
+    fn mmap_at(&self, offset: usize) -> DeviceMmap {
+        let Some(map) = self.maps.get(offset / 4096) else {
+            return DeviceMmap::None;
+        };
+        let start = align_down_4k(map.addr as usize);
+        let end = align_up_4k((map.addr + map.size) as usize);
+        DeviceMmap::Physical(PhysAddrRange::new(
+            PhysAddr::from(start),
+            PhysAddr::from(end),
+        ))
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE | NodeFlags::STREAM
+    }
+}
+
+impl Pollable for UioDevice {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::OUT;
+        events.set(
+            IoEvents::IN,
+            self.count.load(Ordering::Acquire) != self.read_count.load(Ordering::Acquire),
+        );
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
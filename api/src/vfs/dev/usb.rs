@@ -0,0 +1,492 @@
+//! Devices on USB ports: CDC-ACM serial ports as `/dev/ttyACMn` and mass
+//! storage as `/dev/sdX`.
+//!
+//! The USB host stack calls [`attach_serial`] or [`attach_storage`] once it
+//! has configured an interface, and [`detach`] when the device goes away.
+//! Each of them announces the change with a uevent, so udev or mdev can
+//! follow along. The nodes are kept once created: opening a detached device
+//! fails with `ENODEV`, and reattaching a device under the same name brings
+//! its node back to life.
+//!
+//! [`probe`] starts the xHCI controllers on the PCI buses, which hand the
+//! CDC-ACM and bulk-only mass storage interfaces to the class drivers here.
+
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::btree_map::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    task::{Context, Waker},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng_vfs::{DeviceId, NodeFlags, NodeType, VfsResult};
+use axpoll::{IoEvents, Pollable};
+use linux_raw_sys::ioctl::{BLKFLSBUF, BLKGETSIZE, BLKGETSIZE64, BLKROGET, BLKSSZGET};
+use spin::{Mutex, RwLock};
+use starry_core::vfs::{Device, DeviceOps, NodeOpsMux, SimpleDirOps, SimpleFs};
+use starry_vm::VmMutPtr;
+
+use super::tty::{ConsoleBackend, new_n_tty};
+use crate::file::netlink::send_uevent;
+
+mod acm;
+mod storage;
+mod xhci;
+
+/// The major number of `/dev/ttyACMn`.
+pub const ACM_MAJOR: u32 = 166;
+/// The major number of the first 16 SCSI disks, `/dev/sda` to `/dev/sdp`.
+pub const SCSI_DISK0_MAJOR: u32 = 8;
+/// The minor numbers each disk takes, for itself and its partitions.
+const SD_MINORS: u32 = 16;
+
+/// A CDC-ACM serial port.
+pub trait UsbSerial: Send + Sync {
+    /// Reads the data received so far, without blocking.
+    fn read(&self, buf: &mut [u8]) -> usize;
+
+    fn write(&self, buf: &[u8]);
+
+    /// Sets the waker to wake when data arrives.
+    fn set_rx_waker(&self, waker: Waker);
+}
+
+/// A USB mass storage logical unit.
+pub trait UsbStorage: Send + Sync {
+    /// Returns the size of a block, in bytes.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Reads whole blocks starting at block `lba`.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> AxResult;
+
+    /// Writes whole blocks starting at block `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> AxResult;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Waits for the writes so far to reach the medium.
+    fn flush(&self) -> AxResult {
+        Ok(())
+    }
+}
+
+/// A serial port as the console backend of a tty.
+struct AcmConsole {
+    name: String,
+    index: u32,
+    port: Arc<dyn UsbSerial>,
+}
+
+impl ConsoleBackend for AcmConsole {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn device_id(&self) -> DeviceId {
+        DeviceId::new(ACM_MAJOR, self.index)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.port.read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) {
+        self.port.write(buf);
+    }
+
+    fn notifies_rx(&self) -> bool {
+        true
+    }
+
+    fn set_rx_waker(&self, waker: Waker) {
+        self.port.set_rx_waker(waker);
+    }
+}
+
+/// A mass storage unit as a block device.
+struct UsbDisk {
+    storage: Arc<dyn UsbStorage>,
+    /// Serializes the read-modify-write of partial blocks.
+    write_lock: Mutex<()>,
+}
+
+impl UsbDisk {
+    fn size(&self) -> u64 {
+        self.storage.num_blocks() * self.storage.block_size() as u64
+    }
+
+    /// Reads the blocks covering `len` bytes at `offset`, returning them and
+    /// where in them `offset` is.
+    fn read_covering(&self, offset: u64, len: usize) -> AxResult<(Vec<u8>, usize)> {
+        let block_size = self.storage.block_size() as u64;
+        let first = offset / block_size;
+        let last = (offset + len as u64).div_ceil(block_size);
+        let mut buf = vec![0; ((last - first) * block_size) as usize];
+        self.storage.read_blocks(first, &mut buf)?;
+        Ok((buf, (offset - first * block_size) as usize))
+    }
+}
+
+impl DeviceOps for UsbDisk {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let len = buf.len().min(self.size().saturating_sub(offset) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let (blocks, start) = self.read_covering(offset, len)?;
+        buf[..len].copy_from_slice(&blocks[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        if self.storage.is_read_only() {
+            return Err(AxError::ReadOnlyFilesystem);
+        }
+        let len = buf.len().min(self.size().saturating_sub(offset) as usize);
+        if len == 0 {
+            return if buf.is_empty() {
+                Ok(0)
+            } else {
+                Err(AxError::StorageFull)
+            };
+        }
+        let block_size = self.storage.block_size() as u64;
+        if offset % block_size == 0 && len as u64 % block_size == 0 {
+            self.storage
+                .write_blocks(offset / block_size, &buf[..len])?;
+            return Ok(len);
+        }
+        let _guard = self.write_lock.lock();
+        let (mut blocks, start) = self.read_covering(offset, len)?;
+        blocks[start..start + len].copy_from_slice(&buf[..len]);
+        self.storage.write_blocks(offset / block_size, &blocks)?;
+        Ok(len)
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        match cmd {
+            BLKGETSIZE => (arg as *mut u32).vm_write((self.size() / 512) as _)?,
+            BLKGETSIZE64 => (arg as *mut u64).vm_write(self.size())?,
+            BLKSSZGET => (arg as *mut u32).vm_write(self.storage.block_size() as _)?,
+            BLKROGET => (arg as *mut u32).vm_write(self.storage.is_read_only() as _)?,
+            BLKFLSBUF => self.storage.flush()?,
+            _ => return Err(AxError::BadIoctl),
+        }
+        Ok(0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+/// The node of a device that comes and goes, which stays in devfs once
+/// created.
+struct HotplugSlot {
+    node_type: NodeType,
+    device_id: DeviceId,
+    device: RwLock<Option<Arc<dyn DeviceOps>>>,
+}
+
+impl HotplugSlot {
+    fn device(&self) -> VfsResult<Arc<dyn DeviceOps>> {
+        self.device
+            .read()
+            .clone()
+            .ok_or(AxError::Other(LinuxError::ENODEV))
+    }
+}
+
+impl DeviceOps for HotplugSlot {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        self.device()?.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.device()?.write_at(buf, offset)
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        self.device()?.ioctl(cmd, arg)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_pollable(&self) -> Option<&dyn Pollable> {
+        Some(self)
+    }
+
+    fn flags(&self) -> NodeFlags {
+        match self.node_type {
+            NodeType::CharacterDevice => NodeFlags::NON_CACHEABLE | NodeFlags::STREAM,
+            _ => NodeFlags::NON_CACHEABLE,
+        }
+    }
+
+    fn open(&self) -> VfsResult<()> {
+        self.device()?.open()
+    }
+
+    fn release(&self) {
+        if let Ok(device) = self.device() {
+            device.release();
+        }
+    }
+}
+
+impl Pollable for HotplugSlot {
+    fn poll(&self) -> IoEvents {
+        match self.device() {
+            Ok(device) => device
+                .as_pollable()
+                .map_or(IoEvents::IN | IoEvents::OUT, |it| it.poll()),
+            Err(_) => IoEvents::HUP | IoEvents::ERR,
+        }
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if let Some(pollable) = self.device().ok().as_ref().and_then(|it| it.as_pollable()) {
+            pollable.register(context, events);
+        }
+    }
+}
+
+static SLOTS: RwLock<BTreeMap<String, Arc<HotplugSlot>>> = RwLock::new(BTreeMap::new());
+
+/// Returns the lowest index not taken by an attached device named
+/// `name(index)`.
+fn free_index(slots: &BTreeMap<String, Arc<HotplugSlot>>, name: impl Fn(u32) -> String) -> u32 {
+    (0..)
+        .find(|&index| {
+            slots
+                .get(&name(index))
+                .is_none_or(|slot| slot.device.read().is_none())
+        })
+        .unwrap()
+}
+
+fn attach(
+    name: &str,
+    node_type: NodeType,
+    device_id: DeviceId,
+    device: Arc<dyn DeviceOps>,
+    slots: &mut BTreeMap<String, Arc<HotplugSlot>>,
+) {
+    let slot = slots.entry(name.to_string()).or_insert_with(|| {
+        Arc::new(HotplugSlot {
+            node_type,
+            device_id,
+            device: RwLock::new(None),
+        })
+    });
+    *slot.device.write() = Some(device);
+}
+
+fn sd_name(index: u32) -> String {
+    let mut name = String::from("sd");
+    // sda..sdz, then sdaa..sdzz, as Linux names them.
+    if index >= 26 {
+        name.push((b'a' + (index / 26 - 1) as u8) as char);
+    }
+    name.push((b'a' + (index % 26) as u8) as char);
+    name
+}
+
+/// Adds a serial port on `port`, the path of the interface on the bus, e.g.
+/// `1-1:1.0`. Returns the name of its tty.
+pub fn attach_serial(port: &str, serial: Arc<dyn UsbSerial>) -> AxResult<String> {
+    let mut slots = SLOTS.write();
+    let index = free_index(&slots, |index| format!("ttyACM{index}"));
+    let name = format!("ttyACM{index}");
+    let tty = new_n_tty(Arc::new(AcmConsole {
+        name: name.clone(),
+        index,
+        port: serial,
+    }));
+    let device_id = DeviceId::new(ACM_MAJOR, index);
+    attach(&name, NodeType::CharacterDevice, device_id, tty, &mut slots);
+    drop(slots);
+
+    info!("usb: {port} is {name}");
+    send_uevent(
+        "add",
+        &format!("/devices/usb/{port}/tty/{name}"),
+        &[
+            ("SUBSYSTEM", "tty"),
+            ("DEVNAME", &name),
+            ("MAJOR", &ACM_MAJOR.to_string()),
+            ("MINOR", &index.to_string()),
+        ],
+    );
+    Ok(name)
+}
+
+/// Adds a mass storage unit on `port`, the path of the interface on the
+/// bus, e.g. `1-1:1.0`. Returns the name of its disk.
+pub fn attach_storage(port: &str, storage: Arc<dyn UsbStorage>) -> AxResult<String> {
+    if !storage.block_size().is_power_of_two() {
+        return Err(AxError::InvalidInput);
+    }
+    let mut slots = SLOTS.write();
+    let index = free_index(&slots, sd_name);
+    // Past `sdp`, disks take majors of their own on Linux.
+    if index >= SD_MINORS {
+        return Err(AxError::NoMemory);
+    }
+    let name = sd_name(index);
+    let disk = Arc::new(UsbDisk {
+        storage,
+        write_lock: Mutex::new(()),
+    });
+    let device_id = DeviceId::new(SCSI_DISK0_MAJOR, index * SD_MINORS);
+    attach(&name, NodeType::BlockDevice, device_id, disk, &mut slots);
+    drop(slots);
+
+    info!("usb: {port} is {name}");
+    send_uevent(
+        "add",
+        &format!("/devices/usb/{port}/block/{name}"),
+        &[
+            ("SUBSYSTEM", "block"),
+            ("DEVTYPE", "disk"),
+            ("DEVNAME", &name),
+            ("MAJOR", &SCSI_DISK0_MAJOR.to_string()),
+            ("MINOR", &(index * SD_MINORS).to_string()),
+        ],
+    );
+    Ok(name)
+}
+
+/// Removes the device named `name` from `port`, as returned when it was
+/// attached.
+pub fn detach(port: &str, name: &str) -> AxResult {
+    let slots = SLOTS.read();
+    let slot = slots.get(name).ok_or(AxError::NotFound)?;
+    if slot.device.write().take().is_none() {
+        return Err(AxError::NotFound);
+    }
+    let subsystem = match slot.node_type {
+        NodeType::BlockDevice => "block",
+        _ => "tty",
+    };
+    let device_id = slot.device_id;
+    drop(slots);
+
+    info!("usb: {name} on {port} is gone");
+    send_uevent(
+        "remove",
+        &format!("/devices/usb/{port}/{subsystem}/{name}"),
+        &[
+            ("SUBSYSTEM", subsystem),
+            ("DEVNAME", name),
+            ("MAJOR", &device_id.major().to_string()),
+            ("MINOR", &device_id.minor().to_string()),
+        ],
+    );
+    Ok(())
+}
+
+/// Hands the interfaces of a newly configured device to the class drivers,
+/// which attach them.
+fn bind(dev: &Arc<xhci::UsbDevice>, interfaces: &[xhci::Interface]) {
+    for iface in interfaces {
+        let port = dev.interface_path(iface.number);
+        let result = match (iface.class, iface.subclass, iface.protocol) {
+            (acm::USB_CLASS_COMM, acm::USB_CDC_SUBCLASS_ACM, _) => {
+                acm::probe(dev, iface, interfaces)
+                    .and_then(|serial| attach_serial(&port, serial))
+                    .map(|name| dev.add_attached(port.clone(), name))
+            }
+            (storage::USB_CLASS_MASS_STORAGE, storage::USB_SC_SCSI, storage::USB_PR_BULK) => {
+                storage::probe(dev, iface).and_then(|luns| {
+                    for lun in luns {
+                        let name = attach_storage(&port, lun)?;
+                        dev.add_attached(port.clone(), name);
+                    }
+                    Ok(())
+                })
+            }
+            _ => continue,
+        };
+        if let Err(err) = result {
+            warn!("usb: failed to set up {port}: {err:?}");
+        }
+    }
+}
+
+/// Starts the USB host controllers, attaching the devices plugged in and
+/// then following them coming and going. The PCI host bridges must have
+/// been registered first.
+pub fn probe() {
+    xhci::probe();
+}
+
+/// The USB devices in the root of devfs.
+pub(super) struct UsbDir {
+    fs: Arc<SimpleFs>,
+    nodes: Mutex<BTreeMap<String, Arc<Device>>>,
+}
+
+impl UsbDir {
+    pub fn new(fs: Arc<SimpleFs>) -> Self {
+        Self {
+            fs,
+            nodes: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl SimpleDirOps for UsbDir {
+    fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let names = SLOTS
+            .read()
+            .iter()
+            .filter(|(_, slot)| slot.device.read().is_some())
+            .map(|(name, _)| Cow::Owned(name.clone()))
+            .collect::<Vec<_>>();
+        Box::new(names.into_iter())
+    }
+
+    fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        let slot = SLOTS
+            .read()
+            .get(name)
+            .filter(|slot| slot.device.read().is_some())
+            .cloned()
+            .ok_or(AxError::NotFound)?;
+        // Slots live on after their device is detached, so the node may be
+        // cached by the directory above.
+        let node = self
+            .nodes
+            .lock()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Device::new(
+                    self.fs.clone(),
+                    slot.node_type,
+                    slot.device_id,
+                    slot.clone(),
+                )
+            })
+            .clone();
+        Ok(NodeOpsMux::File(node))
+    }
+}
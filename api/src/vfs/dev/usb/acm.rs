@@ -0,0 +1,145 @@
+//! CDC-ACM serial ports, such as modems and the consoles of boards.
+//!
+//! The data interface is taken to be the first one of the data class after
+//! the communications interface, as it is on nearly all devices, rather
+//! than looked up in the union descriptor. A kernel task per port keeps a
+//! read pending on its bulk IN endpoint.
+
+use alloc::{collections::vec_deque::VecDeque, format, sync::Arc, vec};
+use core::task::Waker;
+
+use axerrno::{AxError, AxResult};
+use kspin::SpinNoIrq;
+
+use super::{
+    UsbSerial,
+    xhci::{Data, Interface, Setup, UsbDevice},
+};
+
+pub const USB_CLASS_COMM: u8 = 0x02;
+pub const USB_CDC_SUBCLASS_ACM: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+
+const USB_TYPE_CLASS_INTERFACE: u8 = 0x21;
+const USB_CDC_REQ_SET_LINE_CODING: u8 = 0x20;
+const USB_CDC_REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+/// DTR and RTS.
+const ACM_CTRL_LINES: u16 = 0x3;
+
+/// How much received data is kept for the tty to read, past which more is
+/// dropped.
+const RX_BUFFER: usize = 0x1_0000;
+
+struct Rx {
+    buf: VecDeque<u8>,
+    waker: Option<Waker>,
+}
+
+pub struct Acm {
+    dev: Arc<UsbDevice>,
+    bulk_out: u8,
+    rx: SpinNoIrq<Rx>,
+}
+
+impl UsbSerial for Acm {
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut rx = self.rx.lock();
+        let len = buf.len().min(rx.buf.len());
+        for (dst, src) in buf.iter_mut().zip(rx.buf.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+
+    fn write(&self, buf: &[u8]) {
+        if let Err(err) = self.dev.bulk(self.bulk_out, Data::Out(buf), None) {
+            warn!("usb: failed to write to ACM port: {err:?}");
+        }
+    }
+
+    fn set_rx_waker(&self, waker: Waker) {
+        self.rx.lock().waker = Some(waker);
+    }
+}
+
+impl Acm {
+    /// Reads from the device until it is unplugged.
+    fn receive(&self, bulk_in: u8, max_packet: usize) {
+        let mut buf = vec![0; max_packet.max(64) * 8];
+        loop {
+            match self.dev.bulk(bulk_in, Data::In(&mut buf), None) {
+                Ok(len) => {
+                    let mut rx = self.rx.lock();
+                    let room = RX_BUFFER - rx.buf.len();
+                    rx.buf.extend(&buf[..len.min(room)]);
+                    if let Some(waker) = rx.waker.take() {
+                        waker.wake();
+                    }
+                }
+                Err(AxError::NoSuchDevice) => return,
+                Err(err) => {
+                    warn!("usb: failed to read from ACM port: {err:?}");
+                    axtask::sleep(core::time::Duration::from_millis(100));
+                }
+            }
+        }
+    }
+}
+
+/// Sets up the serial port of communications interface `comm`.
+pub fn probe(
+    dev: &Arc<UsbDevice>,
+    comm: &Interface,
+    interfaces: &[Interface],
+) -> AxResult<Arc<dyn UsbSerial>> {
+    let data = interfaces
+        .iter()
+        .find(|iface| iface.number > comm.number && iface.class == USB_CLASS_CDC_DATA)
+        .ok_or(AxError::NoSuchDevice)?;
+    let bulk_in = data
+        .endpoints
+        .iter()
+        .find(|ep| ep.is_bulk() && ep.is_in())
+        .ok_or(AxError::NoSuchDevice)?;
+    let bulk_out = data
+        .endpoints
+        .iter()
+        .find(|ep| ep.is_bulk() && !ep.is_in())
+        .ok_or(AxError::NoSuchDevice)?;
+
+    // 115200 8N1; devices that don't care about the line stall these.
+    let request = |request, value, data| {
+        dev.control(
+            Setup {
+                request_type: USB_TYPE_CLASS_INTERFACE,
+                request,
+                value,
+                index: comm.number as u16,
+            },
+            data,
+        )
+    };
+    let coding = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+    let _ = request(USB_CDC_REQ_SET_LINE_CODING, 0, Data::Out(&coding));
+    let _ = request(
+        USB_CDC_REQ_SET_CONTROL_LINE_STATE,
+        ACM_CTRL_LINES,
+        Data::None,
+    );
+
+    let acm = Arc::new(Acm {
+        dev: dev.clone(),
+        bulk_out: bulk_out.address,
+        rx: SpinNoIrq::new(Rx {
+            buf: VecDeque::new(),
+            waker: None,
+        }),
+    });
+    let receiver = acm.clone();
+    let (address, max_packet) = (bulk_in.address, bulk_in.max_packet as usize);
+    axtask::spawn(
+        move || receiver.receive(address, max_packet),
+        format!("usb-acm {}", dev.interface_path(comm.number)),
+    );
+    Ok(acm)
+}
@@ -0,0 +1,342 @@
+//! USB mass storage, the bulk-only transport carrying SCSI commands, as
+//! spoken by flash drives and card readers.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axsync::Mutex;
+
+use super::{
+    UsbStorage,
+    xhci::{Data, Interface, Setup, UsbDevice},
+};
+
+pub const USB_CLASS_MASS_STORAGE: u8 = 0x08;
+pub const USB_SC_SCSI: u8 = 0x06;
+pub const USB_PR_BULK: u8 = 0x50;
+
+const USB_TYPE_CLASS_INTERFACE: u8 = 0x21;
+const USB_TYPE_CLASS_INTERFACE_IN: u8 = 0xa1;
+const US_BULK_RESET_REQUEST: u8 = 0xff;
+const US_BULK_GET_MAX_LUN: u8 = 0xfe;
+
+const US_BULK_CB_SIGN: u32 = 0x4342_5355;
+const US_BULK_CS_SIGN: u32 = 0x5342_5355;
+const US_BULK_FLAG_IN: u8 = 0x80;
+const US_BULK_STAT_OK: u8 = 0;
+const US_BULK_STAT_FAIL: u8 = 1;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const SAI_READ_CAPACITY_16: u8 = 0x10;
+/// The peripheral device type of CD and DVD drives.
+const TYPE_ROM: u8 = 0x05;
+
+/// How long a command may take; writes to slow flash can take a while.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+/// The most moved by one command.
+const MAX_TRANSFER: usize = 0x1_0000;
+
+/// The bulk-only transport of an interface, shared by its logical units.
+struct Bot {
+    dev: Arc<UsbDevice>,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    tag: AtomicU32,
+    /// Serializes commands.
+    lock: Mutex<()>,
+}
+
+impl Bot {
+    /// Runs a command, returning how much data was transferred.
+    fn command(&self, lun: u8, cdb: &[u8], mut data: Data) -> AxResult<usize> {
+        let _guard = self.lock.lock();
+        let tag = self.tag.fetch_add(1, Ordering::Relaxed);
+        let len = match &data {
+            Data::None => 0,
+            Data::In(buf) => buf.len(),
+            Data::Out(buf) => buf.len(),
+        };
+        let mut cbw = [0; 31];
+        cbw[0..4].copy_from_slice(&US_BULK_CB_SIGN.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = if matches!(data, Data::In(_)) {
+            US_BULK_FLAG_IN
+        } else {
+            0
+        };
+        cbw[13] = lun;
+        cbw[14] = cdb.len() as u8;
+        cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+        if let Err(err) = self
+            .dev
+            .bulk(self.bulk_out, Data::Out(&cbw), Some(COMMAND_TIMEOUT))
+        {
+            self.reset();
+            return Err(err);
+        }
+
+        // A stall ends the data stage early, and is cleared already.
+        let result = match &mut data {
+            Data::None => Ok(0),
+            Data::In(buf) => self
+                .dev
+                .bulk(self.bulk_in, Data::In(buf), Some(COMMAND_TIMEOUT)),
+            Data::Out(buf) => self
+                .dev
+                .bulk(self.bulk_out, Data::Out(buf), Some(COMMAND_TIMEOUT)),
+        };
+        let transferred = match result {
+            Ok(len) => len,
+            Err(AxError::Other(LinuxError::EPIPE)) => 0,
+            Err(err) => {
+                self.reset();
+                return Err(err);
+            }
+        };
+
+        let mut csw = [0; 13];
+        let mut status = self
+            .dev
+            .bulk(self.bulk_in, Data::In(&mut csw), Some(COMMAND_TIMEOUT));
+        if matches!(status, Err(AxError::Other(LinuxError::EPIPE))) {
+            status = self
+                .dev
+                .bulk(self.bulk_in, Data::In(&mut csw), Some(COMMAND_TIMEOUT));
+        }
+        let valid = matches!(status, Ok(13))
+            && csw[0..4] == US_BULK_CS_SIGN.to_le_bytes()
+            && csw[4..8] == tag.to_le_bytes();
+        if !valid {
+            self.reset();
+            return Err(AxError::Io);
+        }
+        match csw[12] {
+            US_BULK_STAT_OK => Ok(transferred),
+            US_BULK_STAT_FAIL => Err(AxError::Io),
+            // A phase error.
+            _ => {
+                self.reset();
+                Err(AxError::Io)
+            }
+        }
+    }
+
+    /// Gets the device back in sync after a failed command.
+    fn reset(&self) {
+        let _ = self.dev.control(
+            Setup {
+                request_type: USB_TYPE_CLASS_INTERFACE,
+                request: US_BULK_RESET_REQUEST,
+                value: 0,
+                index: self.interface as u16,
+            },
+            Data::None,
+        );
+        let _ = self.dev.clear_halt(self.bulk_in);
+        let _ = self.dev.clear_halt(self.bulk_out);
+    }
+}
+
+/// A logical unit, a disk of its own.
+pub struct Lun {
+    bot: Arc<Bot>,
+    lun: u8,
+    block_size: usize,
+    num_blocks: u64,
+    read_only: bool,
+}
+
+impl Lun {
+    /// Builds a `READ` or `WRITE` command for `count` blocks at `lba`.
+    fn rw_command(&self, write: bool, lba: u64, count: usize) -> Vec<u8> {
+        if self.num_blocks > u32::MAX as u64 {
+            let mut cdb = vec![0; 16];
+            cdb[0] = if write { WRITE_16 } else { READ_16 };
+            cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+            cdb[10..14].copy_from_slice(&(count as u32).to_be_bytes());
+            cdb
+        } else {
+            let mut cdb = vec![0; 10];
+            cdb[0] = if write { WRITE_10 } else { READ_10 };
+            cdb[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+            cdb[7..9].copy_from_slice(&(count as u16).to_be_bytes());
+            cdb
+        }
+    }
+
+    fn check(&self, lba: u64, len: usize) -> AxResult {
+        let blocks = (len / self.block_size) as u64;
+        if len % self.block_size != 0 || lba + blocks > self.num_blocks {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(())
+    }
+}
+
+impl UsbStorage for Lun {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> AxResult {
+        self.check(lba, buf.len())?;
+        let chunk = MAX_TRANSFER / self.block_size * self.block_size;
+        for (i, part) in buf.chunks_mut(chunk).enumerate() {
+            let lba = lba + (i * chunk / self.block_size) as u64;
+            let cdb = self.rw_command(false, lba, part.len() / self.block_size);
+            let len = part.len();
+            if self.bot.command(self.lun, &cdb, Data::In(part))? != len {
+                return Err(AxError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> AxResult {
+        if self.read_only {
+            return Err(AxError::ReadOnlyFilesystem);
+        }
+        self.check(lba, buf.len())?;
+        let chunk = MAX_TRANSFER / self.block_size * self.block_size;
+        for (i, part) in buf.chunks(chunk).enumerate() {
+            let lba = lba + (i * chunk / self.block_size) as u64;
+            let cdb = self.rw_command(true, lba, part.len() / self.block_size);
+            if self.bot.command(self.lun, &cdb, Data::Out(part))? != part.len() {
+                return Err(AxError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn flush(&self) -> AxResult {
+        let mut cdb = [0; 10];
+        cdb[0] = SYNCHRONIZE_CACHE_10;
+        // Devices without a write cache may not know the command.
+        let _ = self.bot.command(self.lun, &cdb, Data::None);
+        Ok(())
+    }
+}
+
+/// Waits for the unit to be ready and reads its capacity.
+fn setup_lun(bot: &Arc<Bot>, lun: u8) -> AxResult<Lun> {
+    let mut inquiry = [0; 36];
+    bot.command(lun, &[INQUIRY, 0, 0, 0, 36, 0], Data::In(&mut inquiry))?;
+    // The first commands fail with a unit attention, or while the medium
+    // spins up.
+    let mut ready = false;
+    for _ in 0..10 {
+        if bot
+            .command(lun, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], Data::None)
+            .is_ok()
+        {
+            ready = true;
+            break;
+        }
+        let mut sense = [0; 18];
+        let _ = bot.command(lun, &[REQUEST_SENSE, 0, 0, 0, 18, 0], Data::In(&mut sense));
+        axtask::sleep(Duration::from_millis(100));
+    }
+    if !ready {
+        // Card readers without a card.
+        return Err(AxError::Other(LinuxError::ENOMEDIUM));
+    }
+
+    let mut capacity = [0; 8];
+    bot.command(
+        lun,
+        &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        Data::In(&mut capacity),
+    )?;
+    let mut last = u32::from_be_bytes(capacity[0..4].try_into().unwrap()) as u64;
+    let mut block_size = u32::from_be_bytes(capacity[4..8].try_into().unwrap());
+    if last == u32::MAX as u64 {
+        let mut cdb = [0; 16];
+        cdb[0] = SERVICE_ACTION_IN_16;
+        cdb[1] = SAI_READ_CAPACITY_16;
+        cdb[13] = 32;
+        let mut capacity = [0; 32];
+        bot.command(lun, &cdb, Data::In(&mut capacity))?;
+        last = u64::from_be_bytes(capacity[0..8].try_into().unwrap());
+        block_size = u32::from_be_bytes(capacity[8..12].try_into().unwrap());
+    }
+    if block_size == 0 || block_size as usize > MAX_TRANSFER {
+        return Err(AxError::InvalidData);
+    }
+    Ok(Lun {
+        bot: bot.clone(),
+        lun,
+        block_size: block_size as usize,
+        num_blocks: last + 1,
+        read_only: inquiry[0] & 0x1f == TYPE_ROM,
+    })
+}
+
+/// Sets up the logical units of a bulk-only interface.
+pub fn probe(dev: &Arc<UsbDevice>, iface: &Interface) -> AxResult<Vec<Arc<dyn UsbStorage>>> {
+    let endpoint = |is_in: bool| {
+        iface
+            .endpoints
+            .iter()
+            .find(|ep| ep.is_bulk() && ep.is_in() == is_in)
+            .map(|ep| ep.address)
+            .ok_or(AxError::NoSuchDevice)
+    };
+    let bot = Arc::new(Bot {
+        dev: dev.clone(),
+        interface: iface.number,
+        bulk_in: endpoint(true)?,
+        bulk_out: endpoint(false)?,
+        tag: AtomicU32::new(1),
+        lock: Mutex::new(()),
+    });
+
+    // Devices with a single unit may stall this.
+    let mut max_lun = [0];
+    let max_lun = match dev.control(
+        Setup {
+            request_type: USB_TYPE_CLASS_INTERFACE_IN,
+            request: US_BULK_GET_MAX_LUN,
+            value: 0,
+            index: iface.number as u16,
+        },
+        Data::In(&mut max_lun),
+    ) {
+        Ok(()) => max_lun[0].min(15),
+        Err(_) => 0,
+    };
+
+    let mut luns = Vec::new();
+    for lun in 0..=max_lun {
+        match setup_lun(&bot, lun) {
+            Ok(lun) => luns.push(Arc::new(lun) as _),
+            Err(err) => warn!(
+                "usb: failed to set up LUN {lun} of {}: {err:?}",
+                dev.interface_path(iface.number)
+            ),
+        }
+    }
+    Ok(luns)
+}
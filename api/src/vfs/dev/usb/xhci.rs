@@ -0,0 +1,1178 @@
+//! xHCI host controllers, found as PCI functions of class 0x0c0330.
+//!
+//! Only devices plugged into the root ports are supported, not those behind
+//! hubs. Nothing is interrupt driven: commands and transfers are waited for
+//! by polling the event ring, and a kernel task per controller polls the
+//! ports to enumerate the devices plugged in and drop those unplugged. DMA
+//! is taken to be cache coherent, as it is on x86 and for PCI on the QEMU
+//! machines.
+
+use alloc::{collections::btree_map::BTreeMap, format, string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, Ordering, fence},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::{mem::virt_to_phys, time::monotonic_time};
+use axsync::Mutex;
+use kspin::SpinNoIrq;
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+
+use crate::pci::{self, PciDevice};
+
+const PCI_CLASS_SERIAL_USB_XHCI: u32 = 0x0c_0330;
+
+/// How often the ports are checked for devices coming and going.
+const HOTPLUG_INTERVAL: Duration = Duration::from_millis(500);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the controller and the ports may take to change state.
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The TRBs in a transfer or command ring, the last one linking back to the
+/// first.
+const RING_SIZE: usize = 256;
+const EVENT_RING_SIZE: usize = 256;
+/// The most a bulk endpoint moves per TRB; its bounce buffer is aligned to
+/// this, as a TRB must not cross a 64 KiB boundary.
+const BULK_CHUNK: usize = 0x1_0000;
+/// The most a control transfer moves.
+const CONTROL_MAX: usize = PAGE_SIZE_4K;
+
+// Capability registers.
+const CAPLENGTH: usize = 0x00;
+const HCSPARAMS1: usize = 0x04;
+const HCSPARAMS2: usize = 0x08;
+const HCCPARAMS1: usize = 0x10;
+const DBOFF: usize = 0x14;
+const RTSOFF: usize = 0x18;
+
+// Operational registers.
+const USBCMD: usize = 0x00;
+const USBSTS: usize = 0x04;
+const CRCR: usize = 0x18;
+const DCBAAP: usize = 0x30;
+const CONFIG: usize = 0x38;
+/// The status and control register of port 1, those of the others follow.
+const PORTSC: usize = 0x400;
+
+// The registers of interrupter 0, in the runtime registers.
+const IR0: usize = 0x20;
+const ERSTSZ: usize = 0x08;
+const ERSTBA: usize = 0x10;
+const ERDP: usize = 0x18;
+
+const CMD_RUN: u32 = 1 << 0;
+const CMD_RESET: u32 = 1 << 1;
+const STS_HALTED: u32 = 1 << 0;
+const STS_NOT_READY: u32 = 1 << 11;
+/// Contexts are 64 bytes rather than 32.
+const HCC_CSZ: u32 = 1 << 2;
+/// Ports have power switches.
+const HCC_PPC: u32 = 1 << 3;
+const ERDP_BUSY: u64 = 1 << 3;
+
+const PORT_CONNECT: u32 = 1 << 0;
+const PORT_ENABLED: u32 = 1 << 1;
+const PORT_RESET: u32 = 1 << 4;
+const PORT_POWER: u32 = 1 << 9;
+const PORT_CONNECT_CHANGE: u32 = 1 << 17;
+const PORT_RESET_CHANGE: u32 = 1 << 21;
+/// The bits of `PORTSC` that keep their value when written back, the
+/// others being cleared by writing 1.
+const PORT_PRESERVE: u32 =
+    1 | 1 << 3 | 0xf << 5 | 1 << 9 | 0xf << 10 | 0x3 << 14 | 0x7 << 25 | 1 << 30;
+
+/// The extended capability through which the firmware hands the controller
+/// over.
+const EXT_CAP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// The SMI enables, in the control register following the capability.
+const LEGACY_SMI_ENABLES: u32 = 1 | 1 << 4 | 0x7 << 13;
+/// The SMIs pending, cleared by writing 1.
+const LEGACY_SMI_EVENTS: u32 = 0x7 << 29;
+
+// TRB types.
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_STOP_ENDPOINT: u32 = 15;
+const TRB_SET_DEQUEUE: u32 = 16;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+
+const TRB_CYCLE: u32 = 1 << 0;
+/// Toggles the cycle bit, in link TRBs.
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+/// Posts an event for short IN transfers.
+const TRB_ISP: u32 = 1 << 2;
+const TRB_IOC: u32 = 1 << 5;
+/// The parameter is the data, in setup TRBs.
+const TRB_IDT: u32 = 1 << 6;
+const TRB_DIR_IN: u32 = 1 << 16;
+
+// Completion codes.
+const CC_SUCCESS: u8 = 1;
+const CC_STALL: u8 = 6;
+const CC_SHORT_PACKET: u8 = 13;
+
+// Endpoint types, in endpoint contexts.
+const EP_CONTROL: u32 = 4;
+
+// Port speeds.
+const SPEED_FULL: u32 = 1;
+const SPEED_LOW: u32 = 2;
+const SPEED_HIGH: u32 = 3;
+
+// Standard requests and descriptors.
+const USB_DIR_IN: u8 = 0x80;
+const USB_RECIP_ENDPOINT: u8 = 0x02;
+const USB_REQ_CLEAR_FEATURE: u8 = 0x01;
+const USB_REQ_GET_DESCRIPTOR: u8 = 0x06;
+const USB_REQ_SET_CONFIGURATION: u8 = 0x09;
+const USB_DT_DEVICE: u8 = 1;
+const USB_DT_CONFIG: u8 = 2;
+const USB_DT_INTERFACE: u8 = 4;
+const USB_DT_ENDPOINT: u8 = 5;
+const USB_ENDPOINT_HALT: u16 = 0;
+
+/// Memory the controller accesses, zeroed and physically contiguous.
+struct DmaBuf {
+    vaddr: usize,
+    pages: usize,
+}
+
+impl DmaBuf {
+    fn new(size: usize) -> AxResult<Self> {
+        let pages = size.div_ceil(PAGE_SIZE_4K);
+        // Aligned to its size, so that buffers of up to 64 KiB cross no
+        // 64 KiB boundary.
+        let align = (pages * PAGE_SIZE_4K).next_power_of_two();
+        let vaddr = axalloc::global_allocator()
+            .alloc_pages(pages, align)
+            .map_err(|_| AxError::NoMemory)?;
+        // SAFETY: the pages were just allocated.
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, pages * PAGE_SIZE_4K) };
+        Ok(Self { vaddr, pages })
+    }
+
+    fn len(&self) -> usize {
+        self.pages * PAGE_SIZE_4K
+    }
+
+    fn paddr(&self) -> u64 {
+        virt_to_phys(self.vaddr.into()).as_usize() as u64
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        (self.vaddr + offset) as *mut T
+    }
+
+    fn read(&self, buf: &mut [u8]) {
+        // SAFETY: callers stay within the buffer.
+        unsafe { core::ptr::copy_nonoverlapping(self.ptr(0), buf.as_mut_ptr(), buf.len()) };
+    }
+
+    fn write(&self, buf: &[u8]) {
+        // SAFETY: as above.
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr(0), buf.len()) };
+    }
+}
+
+impl Drop for DmaBuf {
+    fn drop(&mut self) {
+        axalloc::global_allocator().dealloc_pages(self.vaddr, self.pages);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Mmio(usize);
+
+impl Mmio {
+    fn read(self, reg: usize) -> u32 {
+        // SAFETY: the registers are mapped by `Xhci::new`.
+        unsafe { ((self.0 + reg) as *const u32).read_volatile() }
+    }
+
+    fn write(self, reg: usize, value: u32) {
+        // SAFETY: as above.
+        unsafe { ((self.0 + reg) as *mut u32).write_volatile(value) }
+    }
+
+    /// Writes a 64-bit register, low half first as the controller may not
+    /// take 64-bit accesses.
+    fn write64(self, reg: usize, value: u64) {
+        self.write(reg, value as u32);
+        self.write(reg + 4, (value >> 32) as u32);
+    }
+}
+
+/// Polls `done` until it returns true or `timeout` passes.
+fn poll_until(timeout: Duration, mut done: impl FnMut() -> bool) -> AxResult {
+    let deadline = monotonic_time() + timeout;
+    while !done() {
+        if monotonic_time() >= deadline {
+            return Err(AxError::TimedOut);
+        }
+        axtask::yield_now();
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C, align(16))]
+struct Trb {
+    param: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(ty: u32, param: u64, status: u32, control: u32) -> Self {
+        Self {
+            param,
+            status,
+            control: control | ty << 10,
+        }
+    }
+}
+
+/// A command or transfer ring, which software produces TRBs on.
+struct Ring {
+    buf: DmaBuf,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> AxResult<Self> {
+        let buf = DmaBuf::new(RING_SIZE * size_of::<Trb>())?;
+        let link = Trb::new(TRB_LINK, buf.paddr(), 0, TRB_TOGGLE_CYCLE);
+        // SAFETY: the ring holds `RING_SIZE` TRBs.
+        unsafe { buf.ptr::<Trb>(0).add(RING_SIZE - 1).write_volatile(link) };
+        Ok(Self {
+            buf,
+            enqueue: 0,
+            cycle: true,
+        })
+    }
+
+    /// Returns the enqueue pointer with the cycle state, as the dequeue
+    /// pointer of an empty ring.
+    fn dequeue_pointer(&self) -> u64 {
+        self.buf.paddr() + (self.enqueue * size_of::<Trb>()) as u64 | self.cycle as u64
+    }
+
+    /// Hands a TRB to the controller, returning its address.
+    fn push(&mut self, trb: Trb) -> u64 {
+        let slot = self.buf.ptr::<Trb>(0);
+        let addr = self.buf.paddr() + (self.enqueue * size_of::<Trb>()) as u64;
+        // SAFETY: the enqueue index stays within the ring. The cycle bit is
+        // written last, as it hands the TRB over.
+        unsafe {
+            let trb_ptr = slot.add(self.enqueue);
+            (&raw mut (*trb_ptr).param).write_volatile(trb.param);
+            (&raw mut (*trb_ptr).status).write_volatile(trb.status);
+            fence(Ordering::SeqCst);
+            (&raw mut (*trb_ptr).control)
+                .write_volatile(trb.control & !TRB_CYCLE | self.cycle as u32);
+        }
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            // SAFETY: as above, for the link TRB.
+            unsafe {
+                let link = &raw mut (*slot.add(RING_SIZE - 1)).control;
+                link.write_volatile(link.read_volatile() & !TRB_CYCLE | self.cycle as u32);
+            }
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        addr
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Completion {
+    code: u8,
+    slot: u8,
+    /// The bytes not transferred, in transfer events.
+    residual: u32,
+}
+
+/// The event ring, and the completions read from it not yet collected.
+struct Events {
+    buf: DmaBuf,
+    /// The registers of the interrupter.
+    ir: Mmio,
+    dequeue: usize,
+    cycle: bool,
+    /// By the address of the command TRB.
+    commands: BTreeMap<u64, Completion>,
+    /// By slot and endpoint, which have a single transfer in flight each.
+    transfers: BTreeMap<(u8, u8), Completion>,
+}
+
+impl Events {
+    fn poll(&mut self) {
+        let trbs = self.buf.ptr::<Trb>(0);
+        let mut any = false;
+        loop {
+            // SAFETY: the dequeue index stays within the ring.
+            let trb = unsafe { trbs.add(self.dequeue).read_volatile() };
+            if (trb.control & TRB_CYCLE != 0) != self.cycle {
+                break;
+            }
+            fence(Ordering::SeqCst);
+            let completion = Completion {
+                code: (trb.status >> 24) as u8,
+                slot: (trb.control >> 24) as u8,
+                residual: trb.status & 0xff_ffff,
+            };
+            match (trb.control >> 10) & 0x3f {
+                TRB_COMMAND_COMPLETION => {
+                    self.commands.insert(trb.param, completion);
+                }
+                TRB_TRANSFER_EVENT => {
+                    let endpoint = ((trb.control >> 16) & 0x1f) as u8;
+                    self.transfers
+                        .insert((completion.slot, endpoint), completion);
+                }
+                // Port changes are picked up by polling the ports.
+                _ => {}
+            }
+            self.dequeue += 1;
+            if self.dequeue == EVENT_RING_SIZE {
+                self.dequeue = 0;
+                self.cycle = !self.cycle;
+            }
+            any = true;
+        }
+        if any {
+            let erdp = self.buf.paddr() + (self.dequeue * size_of::<Trb>()) as u64;
+            self.ir.write64(ERDP, erdp | ERDP_BUSY);
+        }
+    }
+}
+
+/// What is plugged into a root port.
+enum Port {
+    Empty,
+    /// A device that couldn't be enumerated, not retried until unplugged.
+    Failed,
+    Device(Arc<UsbDevice>),
+}
+
+pub struct Xhci {
+    /// The number of the bus, from 1.
+    bus: usize,
+    op: Mmio,
+    db: Mmio,
+    /// The size of a context, 32 or 64 bytes.
+    ctx_size: usize,
+    num_ports: usize,
+    dcbaa: DmaBuf,
+    _scratchpad: Vec<DmaBuf>,
+    commands: Mutex<Ring>,
+    events: SpinNoIrq<Events>,
+    ports: Mutex<Vec<Port>>,
+}
+
+impl Xhci {
+    fn new(dev: &PciDevice, bus: usize) -> AxResult<Arc<Self>> {
+        let bar = dev
+            .resources()
+            .first()
+            .filter(|bar| bar.is_mem() && bar.start != 0)
+            .ok_or(AxError::NoSuchDevice)?;
+        dev.set_master();
+        let base = starry_core::mm::ioremap(PhysAddr::from(bar.start as usize), bar.size as usize)?;
+        let cap = Mmio(base.as_usize());
+        let op = Mmio(cap.0 + (cap.read(CAPLENGTH) & 0xff) as usize);
+        let rt = Mmio(cap.0 + (cap.read(RTSOFF) & !0x1f) as usize);
+        let db = Mmio(cap.0 + (cap.read(DBOFF) & !0x3) as usize);
+        let hcs1 = cap.read(HCSPARAMS1);
+        let hcs2 = cap.read(HCSPARAMS2);
+        let hcc1 = cap.read(HCCPARAMS1);
+        let max_slots = (hcs1 & 0xff) as usize;
+        let num_ports = (hcs1 >> 24) as usize;
+
+        take_ownership(cap, hcc1);
+        op.write(USBCMD, op.read(USBCMD) & !CMD_RUN);
+        poll_until(RESET_TIMEOUT, || op.read(USBSTS) & STS_HALTED != 0)?;
+        op.write(USBCMD, CMD_RESET);
+        poll_until(RESET_TIMEOUT, || {
+            op.read(USBCMD) & CMD_RESET == 0 && op.read(USBSTS) & STS_NOT_READY == 0
+        })?;
+
+        let dcbaa = DmaBuf::new((max_slots + 1) * 8)?;
+        let num_scratchpad = ((hcs2 >> 21) & 0x1f) << 5 | (hcs2 >> 27) & 0x1f;
+        let mut scratchpad = Vec::new();
+        if num_scratchpad != 0 {
+            let array = DmaBuf::new(num_scratchpad as usize * 8)?;
+            for i in 0..num_scratchpad as usize {
+                let page = DmaBuf::new(PAGE_SIZE_4K)?;
+                // SAFETY: the array holds `num_scratchpad` entries.
+                unsafe { array.ptr::<u64>(0).add(i).write_volatile(page.paddr()) };
+                scratchpad.push(page);
+            }
+            // SAFETY: entry 0 of the DCBAA points to the scratchpad array.
+            unsafe { dcbaa.ptr::<u64>(0).write_volatile(array.paddr()) };
+            scratchpad.push(array);
+        }
+
+        let commands = Ring::new()?;
+        let events = DmaBuf::new(EVENT_RING_SIZE * size_of::<Trb>())?;
+        // The event ring segment table, of one segment.
+        let erst = DmaBuf::new(16)?;
+        // SAFETY: the table holds one entry, of the address and the size.
+        unsafe {
+            erst.ptr::<u64>(0).write_volatile(events.paddr());
+            erst.ptr::<u32>(8).write_volatile(EVENT_RING_SIZE as u32);
+        }
+        let ir = Mmio(rt.0 + IR0);
+
+        op.write(CONFIG, max_slots as u32);
+        op.write64(DCBAAP, dcbaa.paddr());
+        op.write64(CRCR, commands.dequeue_pointer());
+        ir.write(ERSTSZ, 1);
+        ir.write64(ERDP, events.paddr());
+        ir.write64(ERSTBA, erst.paddr());
+        scratchpad.push(erst);
+        op.write(USBCMD, CMD_RUN);
+        poll_until(RESET_TIMEOUT, || op.read(USBSTS) & STS_HALTED == 0)?;
+
+        let hc = Arc::new(Self {
+            bus,
+            op,
+            db,
+            ctx_size: if hcc1 & HCC_CSZ != 0 { 64 } else { 32 },
+            num_ports,
+            dcbaa,
+            _scratchpad: scratchpad,
+            commands: Mutex::new(commands),
+            events: SpinNoIrq::new(Events {
+                buf: events,
+                ir,
+                dequeue: 0,
+                cycle: true,
+                commands: BTreeMap::new(),
+                transfers: BTreeMap::new(),
+            }),
+            ports: Mutex::new((0..num_ports).map(|_| Port::Empty).collect()),
+        });
+        if hcc1 & HCC_PPC != 0 {
+            for port in 0..num_ports {
+                hc.port_write(port, PORT_POWER);
+            }
+            axtask::sleep(Duration::from_millis(20));
+        }
+        info!(
+            "usb: {} is bus {bus}, with {num_ports} ports",
+            dev.address()
+        );
+        Ok(hc)
+    }
+
+    fn portsc(&self, port: usize) -> u32 {
+        self.op.read(PORTSC + port * 0x10)
+    }
+
+    /// Sets `bits` in the `PORTSC` of `port`, leaving the others alone.
+    fn port_write(&self, port: usize, bits: u32) {
+        let value = self.portsc(port) & PORT_PRESERVE | bits;
+        self.op.write(PORTSC + port * 0x10, value);
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        fence(Ordering::SeqCst);
+        self.db.write(slot as usize * 4, target as u32);
+    }
+
+    /// Polls the event ring until `take` finds what it waits for, `timeout`
+    /// passes or the device is `gone`.
+    fn wait<T>(
+        &self,
+        timeout: Option<Duration>,
+        gone: Option<&AtomicBool>,
+        mut take: impl FnMut(&mut Events) -> Option<T>,
+    ) -> AxResult<T> {
+        let start = monotonic_time();
+        loop {
+            {
+                let mut events = self.events.lock();
+                events.poll();
+                if let Some(it) = take(&mut events) {
+                    return Ok(it);
+                }
+            }
+            if gone.is_some_and(|gone| gone.load(Ordering::Acquire)) {
+                return Err(AxError::NoSuchDevice);
+            }
+            let elapsed = monotonic_time() - start;
+            if timeout.is_some_and(|timeout| elapsed >= timeout) {
+                return Err(AxError::TimedOut);
+            }
+            // Spin a little for the quick ones, then stop hogging the CPU.
+            if elapsed < Duration::from_millis(1) {
+                axtask::yield_now();
+            } else {
+                axtask::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    /// Runs a command, returning the slot of its completion event.
+    fn command(&self, trb: Trb) -> AxResult<u8> {
+        let mut ring = self.commands.lock();
+        let addr = ring.push(trb);
+        self.ring_doorbell(0, 0);
+        let done = self.wait(Some(COMMAND_TIMEOUT), None, |events| {
+            events.commands.remove(&addr)
+        })?;
+        drop(ring);
+        if done.code != CC_SUCCESS {
+            warn!(
+                "usb: command {} failed with code {}",
+                (trb.control >> 10) & 0x3f,
+                done.code
+            );
+            return Err(AxError::Io);
+        }
+        Ok(done.slot)
+    }
+
+    fn set_dcbaa(&self, slot: u8, paddr: u64) {
+        // SAFETY: the slot IDs handed out are at most the slots enabled.
+        unsafe {
+            self.dcbaa
+                .ptr::<u64>(0)
+                .add(slot as usize)
+                .write_volatile(paddr)
+        };
+    }
+
+    /// Checks the ports for devices plugged in or unplugged.
+    fn scan(self: &Arc<Self>) {
+        // Keep the event ring from filling up with port changes.
+        self.events.lock().poll();
+        for port in 0..self.num_ports {
+            let status = self.portsc(port);
+            let changed = status & PORT_CONNECT_CHANGE != 0;
+            if changed {
+                self.port_write(port, PORT_CONNECT_CHANGE);
+            }
+            let connected = status & PORT_CONNECT != 0;
+            let mut ports = self.ports.lock();
+            let present = !matches!(ports[port], Port::Empty);
+            if present && (changed || !connected) {
+                if let Port::Device(dev) = core::mem::replace(&mut ports[port], Port::Empty) {
+                    dev.remove();
+                }
+            }
+            if connected && matches!(ports[port], Port::Empty) {
+                drop(ports);
+                let state = match self.enumerate(port) {
+                    Ok(dev) => Port::Device(dev),
+                    Err(err) => {
+                        warn!(
+                            "usb: failed to enumerate device on {}-{}: {err:?}",
+                            self.bus,
+                            port + 1
+                        );
+                        Port::Failed
+                    }
+                };
+                self.ports.lock()[port] = state;
+            }
+        }
+    }
+
+    fn enumerate(self: &Arc<Self>, port: usize) -> AxResult<Arc<UsbDevice>> {
+        // Let the connection settle.
+        axtask::sleep(Duration::from_millis(100));
+        // USB 3 ports enable themselves once the link is up, USB 2 ones
+        // after a reset.
+        if self.portsc(port) & PORT_ENABLED == 0 {
+            self.port_write(port, PORT_RESET);
+            poll_until(RESET_TIMEOUT, || self.portsc(port) & PORT_RESET_CHANGE != 0)?;
+            self.port_write(port, PORT_RESET_CHANGE);
+            axtask::sleep(Duration::from_millis(10));
+            if self.portsc(port) & PORT_ENABLED == 0 {
+                return Err(AxError::NoSuchDevice);
+            }
+        }
+        let speed = (self.portsc(port) >> 10) & 0xf;
+        let slot = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?;
+        let dev = Arc::new(UsbDevice {
+            hc: self.clone(),
+            slot,
+            path: format!("{}-{}", self.bus, port + 1),
+            config: SpinNoIrq::new(1),
+            input: DmaBuf::new(33 * self.ctx_size)?,
+            output: DmaBuf::new(32 * self.ctx_size)?,
+            endpoints: (0..32).map(|_| Mutex::new(None)).collect(),
+            gone: AtomicBool::new(false),
+            attached: SpinNoIrq::new(Vec::new()),
+        });
+        self.set_dcbaa(slot, dev.output.paddr());
+        match dev.setup(port, speed) {
+            Ok(()) => Ok(dev),
+            Err(err) => {
+                dev.remove();
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Asks the firmware to hand the controller over, if it still drives it.
+fn take_ownership(cap: Mmio, hcc1: u32) {
+    let mut offset = ((hcc1 >> 16) << 2) as usize;
+    while offset != 0 {
+        let header = cap.read(offset);
+        if header & 0xff == EXT_CAP_LEGACY {
+            cap.write(offset, header | LEGACY_OS_OWNED);
+            if poll_until(RESET_TIMEOUT, || cap.read(offset) & LEGACY_BIOS_OWNED == 0).is_err() {
+                warn!("usb: the firmware didn't let go of the controller");
+            }
+            // No more SMIs, and clear those pending.
+            let control = cap.read(offset + 4);
+            cap.write(
+                offset + 4,
+                control & !LEGACY_SMI_ENABLES | LEGACY_SMI_EVENTS,
+            );
+            return;
+        }
+        let next = ((header >> 8) & 0xff) as usize;
+        offset = if next == 0 { 0 } else { offset + (next << 2) };
+    }
+}
+
+/// The setup packet of a control transfer.
+#[derive(Debug, Clone, Copy)]
+pub struct Setup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+/// The data stage of a transfer, and which way it goes.
+pub enum Data<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+impl Data<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Data::None => 0,
+            Data::In(buf) => buf.len(),
+            Data::Out(buf) => buf.len(),
+        }
+    }
+}
+
+/// An endpoint descriptor.
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointDesc {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet: u16,
+    pub interval: u8,
+}
+
+impl EndpointDesc {
+    pub fn is_in(&self) -> bool {
+        self.address & USB_DIR_IN != 0
+    }
+
+    pub fn is_bulk(&self) -> bool {
+        self.attributes & 3 == 2
+    }
+
+    /// Returns the index of the endpoint context, the DCI.
+    fn dci(&self) -> u8 {
+        dci(self.address)
+    }
+}
+
+fn dci(address: u8) -> u8 {
+    (address & 0xf) * 2 + (address & USB_DIR_IN != 0) as u8
+}
+
+/// An interface of the active configuration, in its default setting.
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDesc>,
+}
+
+/// Parses the interfaces out of a configuration descriptor.
+fn parse_config(desc: &[u8]) -> Vec<Interface> {
+    let mut interfaces: Vec<Interface> = Vec::new();
+    // Whether the endpoints that follow belong to a default setting.
+    let mut default = false;
+    let mut pos = 0;
+    while pos + 2 <= desc.len() {
+        let len = desc[pos] as usize;
+        if len < 2 || pos + len > desc.len() {
+            break;
+        }
+        let d = &desc[pos..pos + len];
+        match d[1] {
+            USB_DT_INTERFACE if len >= 9 => {
+                default = d[3] == 0;
+                if default {
+                    interfaces.push(Interface {
+                        number: d[2],
+                        class: d[5],
+                        subclass: d[6],
+                        protocol: d[7],
+                        endpoints: Vec::new(),
+                    });
+                }
+            }
+            USB_DT_ENDPOINT if len >= 7 && default => {
+                if let Some(iface) = interfaces.last_mut() {
+                    iface.endpoints.push(EndpointDesc {
+                        address: d[2],
+                        attributes: d[3],
+                        max_packet: u16::from_le_bytes([d[4], d[5]]) & 0x7ff,
+                        interval: d[6],
+                    });
+                }
+            }
+            _ => {}
+        }
+        pos += len;
+    }
+    interfaces
+}
+
+/// A transfer ring with the buffer its transfers bounce through.
+struct Endpoint {
+    ring: Ring,
+    bounce: DmaBuf,
+}
+
+impl Endpoint {
+    fn new(bounce: usize) -> AxResult<Self> {
+        Ok(Self {
+            ring: Ring::new()?,
+            bounce: DmaBuf::new(bounce)?,
+        })
+    }
+}
+
+/// A device on a root port, addressed and configured.
+pub struct UsbDevice {
+    hc: Arc<Xhci>,
+    slot: u8,
+    /// The path of the device on the bus, e.g. `1-2`.
+    path: String,
+    /// The value of the active configuration.
+    config: SpinNoIrq<u8>,
+    input: DmaBuf,
+    output: DmaBuf,
+    /// By DCI.
+    endpoints: Vec<Mutex<Option<Endpoint>>>,
+    gone: AtomicBool,
+    /// The ports and names the class drivers attached the interfaces as.
+    attached: SpinNoIrq<Vec<(String, String)>>,
+}
+
+impl UsbDevice {
+    /// Returns the path of interface `number` on the bus, e.g. `1-2:1.0`.
+    pub fn interface_path(&self, number: u8) -> String {
+        format!("{}:{}.{number}", self.path, *self.config.lock())
+    }
+
+    /// Records a device a class driver attached, to detach it when the
+    /// device is unplugged.
+    pub fn add_attached(&self, port: String, name: String) {
+        self.attached.lock().push((port, name));
+    }
+
+    /// Returns dword `dword` of input context `index`, 0 being the input
+    /// control context, 1 the slot context and the DCIs following.
+    fn input_ctx(&self, index: usize, dword: usize) -> *mut u32 {
+        self.input.ptr(index * self.hc.ctx_size + dword * 4)
+    }
+
+    fn clear_input(&self) {
+        // SAFETY: the input context is owned by the device.
+        unsafe { core::ptr::write_bytes(self.input.ptr::<u8>(0), 0, self.input.len()) };
+    }
+
+    /// Copies the slot context of the output context into the input one.
+    fn copy_slot_ctx(&self) {
+        for dword in 0..8 {
+            // SAFETY: both hold a slot context.
+            unsafe {
+                let value = self.output.ptr::<u32>(dword * 4).read_volatile();
+                self.input_ctx(1, dword).write_volatile(value);
+            }
+        }
+    }
+
+    /// Addresses and configures the device, then binds drivers to it.
+    fn setup(self: &Arc<Self>, port: usize, speed: u32) -> AxResult {
+        let max_packet = match speed {
+            SPEED_FULL | SPEED_LOW => 8,
+            SPEED_HIGH => 64,
+            _ => 512,
+        };
+        let ep0 = Endpoint::new(CONTROL_MAX)?;
+        self.clear_input();
+        // SAFETY: the input context is owned by the device.
+        unsafe {
+            // Add the slot and EP0.
+            self.input_ctx(0, 1).write_volatile(0b11);
+            self.input_ctx(1, 0).write_volatile(speed << 20 | 1 << 27);
+            self.input_ctx(1, 1)
+                .write_volatile(((port + 1) as u32) << 16);
+            self.input_ctx(2, 1)
+                .write_volatile(3 << 1 | EP_CONTROL << 3 | (max_packet as u32) << 16);
+            let dequeue = ep0.ring.dequeue_pointer();
+            self.input_ctx(2, 2).write_volatile(dequeue as u32);
+            self.input_ctx(2, 3).write_volatile((dequeue >> 32) as u32);
+            self.input_ctx(2, 4).write_volatile(8);
+        }
+        *self.endpoints[1].lock() = Some(ep0);
+        self.hc.command(Trb::new(
+            TRB_ADDRESS_DEVICE,
+            self.input.paddr(),
+            0,
+            (self.slot as u32) << 24,
+        ))?;
+
+        let mut desc = [0; 18];
+        self.get_descriptor(USB_DT_DEVICE, &mut desc[..8])?;
+        // USB 3 devices give it as a power of two.
+        let actual = if desc[7] == 9 && speed > SPEED_HIGH {
+            512
+        } else {
+            desc[7] as u32
+        };
+        if actual != max_packet as u32 && actual != 0 {
+            self.clear_input();
+            // SAFETY: as above.
+            unsafe {
+                self.input_ctx(0, 1).write_volatile(0b10);
+                self.input_ctx(2, 1)
+                    .write_volatile(3 << 1 | EP_CONTROL << 3 | actual << 16);
+            }
+            self.hc.command(Trb::new(
+                TRB_EVALUATE_CONTEXT,
+                self.input.paddr(),
+                0,
+                (self.slot as u32) << 24,
+            ))?;
+        }
+        self.get_descriptor(USB_DT_DEVICE, &mut desc)?;
+        info!(
+            "usb: {} is [{:04x}:{:04x}]",
+            self.path,
+            u16::from_le_bytes([desc[8], desc[9]]),
+            u16::from_le_bytes([desc[10], desc[11]])
+        );
+
+        let mut header = [0; 9];
+        self.get_descriptor(USB_DT_CONFIG, &mut header)?;
+        let total = (u16::from_le_bytes([header[2], header[3]]) as usize).min(CONTROL_MAX);
+        let mut config = vec![0; total.max(9)];
+        self.get_descriptor(USB_DT_CONFIG, &mut config)?;
+        let interfaces = parse_config(&config);
+        self.configure(speed, &interfaces)?;
+        self.control(
+            Setup {
+                request_type: 0,
+                request: USB_REQ_SET_CONFIGURATION,
+                value: config[5] as u16,
+                index: 0,
+            },
+            Data::None,
+        )?;
+        *self.config.lock() = config[5];
+
+        super::bind(self, &interfaces);
+        Ok(())
+    }
+
+    fn get_descriptor(&self, ty: u8, buf: &mut [u8]) -> AxResult {
+        self.control(
+            Setup {
+                request_type: USB_DIR_IN,
+                request: USB_REQ_GET_DESCRIPTOR,
+                value: (ty as u16) << 8,
+                index: 0,
+            },
+            Data::In(buf),
+        )
+    }
+
+    /// Sets up the bulk and interrupt endpoints of the interfaces.
+    fn configure(&self, speed: u32, interfaces: &[Interface]) -> AxResult {
+        self.clear_input();
+        self.copy_slot_ctx();
+        let mut add = 1;
+        let mut last = 1;
+        for ep in interfaces.iter().flat_map(|iface| &iface.endpoints) {
+            let ty = match ep.attributes & 3 {
+                2 => 2,
+                3 => 3,
+                // Isochronous endpoints aren't supported.
+                _ => continue,
+            };
+            let dci = ep.dci();
+            let interval = match (ty, speed) {
+                (2, _) => 0,
+                // In frames, to a power of two of microframes.
+                (_, SPEED_FULL | SPEED_LOW) => (ep.interval.max(1) as u32 * 8).ilog2(),
+                _ => ep.interval.clamp(1, 16) as u32 - 1,
+            };
+            let endpoint = Endpoint::new(if ty == 2 { BULK_CHUNK } else { PAGE_SIZE_4K })?;
+            let dequeue = endpoint.ring.dequeue_pointer();
+            let ty = ty + if ep.is_in() { 4 } else { 0 };
+            let max_packet = ep.max_packet as u32;
+            // SAFETY: the input context is owned by the device.
+            unsafe {
+                self.input_ctx(dci as usize + 1, 0)
+                    .write_volatile(interval << 16);
+                self.input_ctx(dci as usize + 1, 1)
+                    .write_volatile(3 << 1 | ty << 3 | max_packet << 16);
+                self.input_ctx(dci as usize + 1, 2)
+                    .write_volatile(dequeue as u32);
+                self.input_ctx(dci as usize + 1, 3)
+                    .write_volatile((dequeue >> 32) as u32);
+                let average = if ep.is_bulk() { 3072 } else { max_packet };
+                self.input_ctx(dci as usize + 1, 4)
+                    .write_volatile(average | if ep.is_bulk() { 0 } else { max_packet << 16 });
+            }
+            *self.endpoints[dci as usize].lock() = Some(endpoint);
+            add |= 1 << dci;
+            last = last.max(dci as u32);
+        }
+        // SAFETY: as above.
+        unsafe {
+            self.input_ctx(0, 1).write_volatile(add);
+            let slot = self.input_ctx(1, 0).read_volatile();
+            self.input_ctx(1, 0)
+                .write_volatile(slot & !(0x1f << 27) | last << 27);
+        }
+        self.hc.command(Trb::new(
+            TRB_CONFIGURE_ENDPOINT,
+            self.input.paddr(),
+            0,
+            (self.slot as u32) << 24,
+        ))?;
+        Ok(())
+    }
+
+    /// Rings the doorbell of endpoint `dci` and waits for its transfer.
+    fn run(&self, dci: u8, ep: &mut Endpoint, timeout: Option<Duration>) -> AxResult<Completion> {
+        self.hc.events.lock().transfers.remove(&(self.slot, dci));
+        self.hc.ring_doorbell(self.slot, dci);
+        let result = self.hc.wait(timeout, Some(&self.gone), |events| {
+            events.transfers.remove(&(self.slot, dci))
+        });
+        match result {
+            Ok(done) => match done.code {
+                CC_SUCCESS | CC_SHORT_PACKET => Ok(done),
+                CC_STALL => {
+                    self.reset_endpoint(dci, ep)?;
+                    Err(AxError::Other(LinuxError::EPIPE))
+                }
+                code => {
+                    warn!("usb: transfer on {} failed with code {code}", self.path);
+                    self.reset_endpoint(dci, ep)?;
+                    Err(AxError::Io)
+                }
+            },
+            Err(AxError::TimedOut) => {
+                self.cancel(dci, ep)?;
+                Err(AxError::TimedOut)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Recovers the endpoint from a halt, dropping what was queued on it.
+    fn reset_endpoint(&self, dci: u8, ep: &Endpoint) -> AxResult {
+        let target = (self.slot as u32) << 24 | (dci as u32) << 16;
+        self.hc
+            .command(Trb::new(TRB_RESET_ENDPOINT, 0, 0, target))?;
+        self.hc.command(Trb::new(
+            TRB_SET_DEQUEUE,
+            ep.ring.dequeue_pointer(),
+            0,
+            target,
+        ))?;
+        Ok(())
+    }
+
+    /// Stops the endpoint, dropping what was queued on it.
+    fn cancel(&self, dci: u8, ep: &Endpoint) -> AxResult {
+        let target = (self.slot as u32) << 24 | (dci as u32) << 16;
+        // This fails if the endpoint halted or stopped meanwhile.
+        let _ = self.hc.command(Trb::new(TRB_STOP_ENDPOINT, 0, 0, target));
+        self.hc.command(Trb::new(
+            TRB_SET_DEQUEUE,
+            ep.ring.dequeue_pointer(),
+            0,
+            target,
+        ))?;
+        self.hc.events.lock().transfers.remove(&(self.slot, dci));
+        Ok(())
+    }
+
+    /// Runs a control transfer on the default endpoint. A stall fails with
+    /// `EPIPE`.
+    pub fn control(&self, setup: Setup, data: Data) -> AxResult {
+        let len = data.len();
+        if len > CONTROL_MAX {
+            return Err(AxError::InvalidInput);
+        }
+        let mut ep = self.endpoints[1].lock();
+        let ep = ep.as_mut().ok_or(AxError::NoSuchDevice)?;
+        let dir_in = matches!(data, Data::In(_));
+        if let Data::Out(buf) = &data {
+            ep.bounce.write(buf);
+        }
+        let param = setup.request_type as u64
+            | (setup.request as u64) << 8
+            | (setup.value as u64) << 16
+            | (setup.index as u64) << 32
+            | (len as u64) << 48;
+        let transfer_type = match (len, dir_in) {
+            (0, _) => 0,
+            (_, false) => 2,
+            (_, true) => 3,
+        };
+        ep.ring
+            .push(Trb::new(TRB_SETUP, param, 8, TRB_IDT | transfer_type << 16));
+        if len != 0 {
+            let dir = if dir_in { TRB_DIR_IN } else { 0 };
+            ep.ring
+                .push(Trb::new(TRB_DATA, ep.bounce.paddr(), len as u32, dir));
+        }
+        // The status stage goes the other way from the data.
+        let dir = if dir_in { 0 } else { TRB_DIR_IN };
+        ep.ring.push(Trb::new(TRB_STATUS, 0, 0, TRB_IOC | dir));
+        self.run(1, ep, Some(CONTROL_TIMEOUT))?;
+        if let Data::In(buf) = data {
+            ep.bounce.read(buf);
+        }
+        Ok(())
+    }
+
+    /// Runs a bulk transfer on the endpoint at `address`, returning how much
+    /// was transferred, which is less than asked for IN transfers ended by a
+    /// short packet. A stall is cleared, and fails with `EPIPE`.
+    pub fn bulk(&self, address: u8, mut data: Data, timeout: Option<Duration>) -> AxResult<usize> {
+        let dci = dci(address);
+        let mut guard = self.endpoints[dci as usize].lock();
+        let ep = guard.as_mut().ok_or(AxError::NoSuchDevice)?;
+        let len = data.len();
+        let mut done = 0;
+        let result = loop {
+            let chunk = (len - done).min(BULK_CHUNK);
+            if let Data::Out(buf) = &data {
+                ep.bounce.write(&buf[done..done + chunk]);
+            }
+            ep.ring.push(Trb::new(
+                TRB_NORMAL,
+                ep.bounce.paddr(),
+                chunk as u32,
+                TRB_IOC | TRB_ISP,
+            ));
+            let completion = match self.run(dci, ep, timeout) {
+                Ok(completion) => completion,
+                Err(err) => break Err(err),
+            };
+            let actual = chunk.saturating_sub(completion.residual as usize);
+            if let Data::In(buf) = &mut data {
+                ep.bounce.read(&mut buf[done..done + actual]);
+            }
+            done += actual;
+            if actual < chunk || done == len {
+                break Ok(done);
+            }
+        };
+        drop(guard);
+        if matches!(result, Err(AxError::Other(LinuxError::EPIPE))) {
+            self.clear_halt(address)?;
+        }
+        result
+    }
+
+    /// Tells the device to clear the halt of the endpoint at `address`.
+    pub fn clear_halt(&self, address: u8) -> AxResult {
+        self.control(
+            Setup {
+                request_type: USB_RECIP_ENDPOINT,
+                request: USB_REQ_CLEAR_FEATURE,
+                value: USB_ENDPOINT_HALT,
+                index: address as u16,
+            },
+            Data::None,
+        )
+    }
+
+    /// Detaches what the class drivers attached, and releases the slot.
+    fn remove(&self) {
+        self.gone.store(true, Ordering::Release);
+        for (port, name) in self.attached.lock().drain(..) {
+            if let Err(err) = super::detach(&port, &name) {
+                warn!("usb: failed to detach {name}: {err:?}");
+            }
+        }
+        let _ = self
+            .hc
+            .command(Trb::new(TRB_DISABLE_SLOT, 0, 0, (self.slot as u32) << 24));
+        self.hc.set_dcbaa(self.slot, 0);
+        info!("usb: {} is gone", self.path);
+    }
+}
+
+/// Starts the controllers on the PCI buses, enumerating the devices plugged
+/// in already and polling for others from then on.
+pub fn probe() {
+    let mut bus = 0;
+    for dev in pci::devices() {
+        if dev.class() != PCI_CLASS_SERIAL_USB_XHCI {
+            continue;
+        }
+        bus += 1;
+        let hc = match Xhci::new(&dev, bus) {
+            Ok(hc) => hc,
+            Err(err) => {
+                warn!("usb: failed to set up {}: {err:?}", dev.address());
+                continue;
+            }
+        };
+        hc.scan();
+        axtask::spawn(
+            move || loop {
+                axtask::sleep(HOTPLUG_INTERVAL);
+                hc.scan();
+            },
+            format!("usb{bus}"),
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! The watchdog device, `/dev/watchdog`.
+//!
+//! Opening the device starts the watchdog, which resets the system unless
+//! userspace keeps writing to the device or issues `WDIOC_KEEPALIVE` within
+//! the timeout. Closing it only stops the watchdog if the magic character
+//! `V` was written right before, so that a crashed daemon still triggers a
+//! reset.
+//!
+//! Platform code registers the SoC watchdog with [`register`]. Without one,
+//! a soft watchdog built on kernel timers is used, which cannot catch a hung
+//! kernel but still catches a hung userspace.
+
+use alloc::sync::Arc;
+use core::{
+    any::Any,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use axfs_ng_vfs::{DeviceId, NodeFlags, VfsError, VfsResult};
+use axsync::Mutex;
+use spin::RwLock;
+use starry_core::timer::{TimerHandle, add_timer};
+use starry_vm::{VmMutPtr, VmPtr};
+
+use crate::vfs::DeviceOps;
+
+/// The device ID for /dev/watchdog
+pub const WATCHDOG_DEVICE_ID: DeviceId = DeviceId::new(10, 130);
+
+/// The timeout the watchdog starts with.
+const DEFAULT_TIMEOUT: u32 = 60;
+
+const WDIOC_GETSUPPORT: u32 = 0x8028_5700;
+const WDIOC_GETSTATUS: u32 = 0x8004_5701;
+const WDIOC_GETBOOTSTATUS: u32 = 0x8004_5702;
+const WDIOC_SETOPTIONS: u32 = 0x8004_5704;
+const WDIOC_KEEPALIVE: u32 = 0x8004_5705;
+const WDIOC_SETTIMEOUT: u32 = 0xc004_5706;
+const WDIOC_GETTIMEOUT: u32 = 0x8004_5707;
+const WDIOC_GETTIMELEFT: u32 = 0x8004_570a;
+
+const WDIOF_SETTIMEOUT: u32 = 0x0080;
+const WDIOF_MAGICCLOSE: u32 = 0x0100;
+const WDIOF_KEEPALIVEPING: u32 = 0x8000;
+
+const WDIOS_DISABLECARD: u32 = 0x0001;
+const WDIOS_ENABLECARD: u32 = 0x0002;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct watchdog_info {
+    options: u32,
+    firmware_version: u32,
+    identity: [u8; 32],
+}
+
+/// A hardware watchdog.
+pub trait WatchdogDriver: Send + Sync {
+    /// Returns the name reported by `WDIOC_GETSUPPORT`.
+    fn identity(&self) -> &str;
+
+    /// Starts the watchdog with a timeout of `timeout` seconds.
+    fn start(&self, timeout: u32) -> VfsResult<()>;
+
+    /// Stops the watchdog.
+    fn stop(&self) -> VfsResult<()>;
+
+    /// Restarts the countdown.
+    fn ping(&self) -> VfsResult<()>;
+
+    /// Sets the timeout to about `timeout` seconds, returning the timeout
+    /// actually used.
+    fn set_timeout(&self, timeout: u32) -> VfsResult<u32>;
+
+    /// Returns the seconds left before the system is reset, if the hardware
+    /// can tell.
+    fn time_left(&self) -> Option<u32> {
+        None
+    }
+}
+
+static DRIVER: RwLock<Option<Arc<dyn WatchdogDriver>>> = RwLock::new(None);
+
+/// Registers the hardware watchdog to back `/dev/watchdog`.
+pub fn register(driver: Arc<dyn WatchdogDriver>) {
+    info!("watchdog: using {}", driver.identity());
+    *DRIVER.write() = Some(driver);
+}
+
+struct SoftState {
+    timeout: u32,
+    deadline: Option<Duration>,
+    timer: Option<TimerHandle>,
+}
+
+/// A watchdog that resets the system from a kernel timer.
+struct SoftWatchdog {
+    state: Mutex<SoftState>,
+}
+
+impl SoftWatchdog {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(SoftState {
+                timeout: DEFAULT_TIMEOUT,
+                deadline: None,
+                timer: None,
+            }),
+        }
+    }
+
+    fn arm(state: &mut SoftState, timeout: u32) {
+        if let Some(timer) = state.timer.take() {
+            timer.cancel();
+        }
+        let deadline = axhal::time::monotonic_time() + Duration::from_secs(timeout as u64);
+        state.deadline = Some(deadline);
+        state.timer = Some(add_timer(deadline, || {
+            error!("watchdog: timed out, restarting system");
+            crate::power::emergency_restart();
+        }));
+    }
+}
+
+impl WatchdogDriver for SoftWatchdog {
+    fn identity(&self) -> &str {
+        "Software Watchdog"
+    }
+
+    fn start(&self, timeout: u32) -> VfsResult<()> {
+        Self::arm(&mut self.state.lock(), timeout);
+        Ok(())
+    }
+
+    fn stop(&self) -> VfsResult<()> {
+        let mut state = self.state.lock();
+        if let Some(timer) = state.timer.take() {
+            timer.cancel();
+        }
+        state.deadline = None;
+        Ok(())
+    }
+
+    fn ping(&self) -> VfsResult<()> {
+        let mut state = self.state.lock();
+        if state.deadline.is_some() {
+            let timeout = state.timeout;
+            Self::arm(&mut state, timeout);
+        }
+        Ok(())
+    }
+
+    fn set_timeout(&self, timeout: u32) -> VfsResult<u32> {
+        let mut state = self.state.lock();
+        state.timeout = timeout;
+        Ok(timeout)
+    }
+
+    fn time_left(&self) -> Option<u32> {
+        let deadline = self.state.lock().deadline?;
+        Some(
+            deadline
+                .saturating_sub(axhal::time::monotonic_time())
+                .as_secs() as u32,
+        )
+    }
+}
+
+struct WatchdogState {
+    timeout: u32,
+    running: bool,
+    expect_close: bool,
+}
+
+/// The watchdog device.
+pub struct Watchdog {
+    soft: Arc<dyn WatchdogDriver>,
+    open: AtomicBool,
+    state: Mutex<WatchdogState>,
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        Self {
+            soft: Arc::new(SoftWatchdog::new()),
+            open: AtomicBool::new(false),
+            state: Mutex::new(WatchdogState {
+                timeout: DEFAULT_TIMEOUT,
+                running: false,
+                expect_close: false,
+            }),
+        }
+    }
+
+    fn driver(&self) -> Arc<dyn WatchdogDriver> {
+        DRIVER.read().clone().unwrap_or_else(|| self.soft.clone())
+    }
+
+    fn start(&self, state: &mut WatchdogState) -> VfsResult<()> {
+        let driver = self.driver();
+        state.timeout = driver.set_timeout(state.timeout)?;
+        driver.start(state.timeout)?;
+        state.running = true;
+        Ok(())
+    }
+}
+
+impl DeviceOps for Watchdog {
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        if !buf.is_empty() {
+            self.state.lock().expect_close = buf.contains(&b'V');
+            self.driver().ping()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        match cmd {
+            WDIOC_GETSUPPORT => {
+                let mut info = watchdog_info {
+                    options: WDIOF_SETTIMEOUT | WDIOF_MAGICCLOSE | WDIOF_KEEPALIVEPING,
+                    firmware_version: 0,
+                    identity: [0; 32],
+                };
+                let driver = self.driver();
+                let identity = driver.identity().as_bytes();
+                let len = identity.len().min(info.identity.len() - 1);
+                info.identity[..len].copy_from_slice(&identity[..len]);
+                (arg as *mut watchdog_info).vm_write(info)?;
+            }
+            WDIOC_GETSTATUS | WDIOC_GETBOOTSTATUS => {
+                (arg as *mut u32).vm_write(0)?;
+            }
+            WDIOC_SETOPTIONS => {
+                let options = (arg as *const u32).vm_read()?;
+                let mut state = self.state.lock();
+                if options & WDIOS_DISABLECARD != 0 {
+                    self.driver().stop()?;
+                    state.running = false;
+                }
+                if options & WDIOS_ENABLECARD != 0 {
+                    self.start(&mut state)?;
+                }
+            }
+            WDIOC_KEEPALIVE => {
+                self.driver().ping()?;
+            }
+            WDIOC_SETTIMEOUT => {
+                let timeout = (arg as *const u32).vm_read()?;
+                if timeout == 0 {
+                    return Err(VfsError::InvalidInput);
+                }
+                let driver = self.driver();
+                let mut state = self.state.lock();
+                state.timeout = driver.set_timeout(timeout)?;
+                if state.running {
+                    driver.start(state.timeout)?;
+                }
+                (arg as *mut u32).vm_write(state.timeout)?;
+            }
+            WDIOC_GETTIMEOUT => {
+                (arg as *mut u32).vm_write(self.state.lock().timeout)?;
+            }
+            WDIOC_GETTIMELEFT => {
+                let left = self.driver().time_left().ok_or(VfsError::Unsupported)?;
+                (arg as *mut u32).vm_write(left)?;
+            }
+            _ => return Err(VfsError::BadIoctl),
+        }
+        Ok(0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE | NodeFlags::STREAM
+    }
+
+    fn open(&self) -> VfsResult<()> {
+        if self.open.swap(true, Ordering::AcqRel) {
+            return Err(VfsError::ResourceBusy);
+        }
+        let mut state = self.state.lock();
+        state.expect_close = false;
+        if let Err(err) = self.start(&mut state) {
+            self.open.store(false, Ordering::Release);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock();
+        if state.expect_close {
+            if let Err(err) = self.driver().stop() {
+                warn!("watchdog: failed to stop: {err:?}");
+            } else {
+                state.running = false;
+            }
+        } else if state.running {
+            warn!("watchdog: closed unexpectedly, not stopping");
+        }
+        state.expect_close = false;
+        self.open.store(false, Ordering::Release);
+    }
+}
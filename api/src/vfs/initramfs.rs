@@ -0,0 +1,239 @@
+//! Unpacking of the initramfs, a `cpio` archive in the `newc` format that
+//! becomes the root filesystem during early boot.
+//!
+//! The archive is either embedded into the kernel image with the
+//! `initramfs` feature, from the path in `STARRY_INITRAMFS` at build time,
+//! or loaded by the bootloader as the initrd. Both are unpacked if present,
+//! the loaded one last so that it can override embedded files.
+
+use alloc::{collections::BTreeMap, string::String};
+use core::time::Duration;
+
+use axerrno::{AxError, AxResult};
+use axfs_ng::{FsContext, OpenOptions};
+use axfs_ng_vfs::{DeviceId, MetadataUpdate, NodePermission, NodeType, path::Path};
+use linux_raw_sys::general::{
+    S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK,
+};
+
+use super::set_device_id;
+
+#[cfg(feature = "initramfs")]
+static EMBEDDED: &[u8] = include_bytes!(env!("STARRY_INITRAMFS"));
+
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// A `newc` archive member.
+struct Entry<'a> {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    rdev: DeviceId,
+    dev: (u32, u32),
+    name: &'a str,
+    data: &'a [u8],
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn hex(field: &[u8]) -> AxResult<u32> {
+    let field = core::str::from_utf8(field).map_err(|_| AxError::InvalidData)?;
+    u32::from_str_radix(field, 16).map_err(|_| AxError::InvalidData)
+}
+
+/// Parses the member at `offset`, returning it and the offset of the next.
+fn parse_entry(archive: &[u8], offset: usize) -> AxResult<(Entry<'_>, usize)> {
+    let header = archive
+        .get(offset..offset + HEADER_SIZE)
+        .ok_or(AxError::InvalidData)?;
+    // `070702` is the same format with checksums, which we don't verify.
+    if &header[..6] != b"070701" && &header[..6] != b"070702" {
+        return Err(AxError::InvalidData);
+    }
+    let field = |i: usize| hex(&header[6 + i * 8..][..8]);
+
+    let file_size = field(6)? as usize;
+    let name_size = field(11)? as usize;
+    let name_start = offset + HEADER_SIZE;
+    let data_start = align4(name_start + name_size);
+    let name = archive
+        .get(name_start..name_start + name_size)
+        .and_then(|name| name.strip_suffix(b"\0"))
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .ok_or(AxError::InvalidData)?;
+    let data = archive
+        .get(data_start..data_start + file_size)
+        .ok_or(AxError::InvalidData)?;
+
+    let entry = Entry {
+        ino: field(0)?,
+        mode: field(1)?,
+        uid: field(2)?,
+        gid: field(3)?,
+        nlink: field(4)?,
+        mtime: field(5)?,
+        dev: (field(7)?, field(8)?),
+        rdev: DeviceId::new(field(9)?, field(10)?),
+        name,
+        data,
+    };
+    Ok((entry, align4(data_start + file_size)))
+}
+
+struct Unpacker<'a> {
+    fs: &'a FsContext,
+    /// The first path of each hard-linked inode, by device and inode number.
+    links: BTreeMap<(u32, u32, u32), String>,
+}
+
+impl Unpacker<'_> {
+    fn unpack(&mut self, archive: &[u8]) -> AxResult<()> {
+        let mut offset = 0;
+        while offset < archive.len() {
+            // Archives can be concatenated, with zero padding in between.
+            if archive[offset] == 0 {
+                offset += 1;
+                continue;
+            }
+            let (entry, next) = parse_entry(archive, offset)?;
+            offset = next;
+            if entry.name == TRAILER {
+                self.links.clear();
+                continue;
+            }
+            let name = entry.name.trim_start_matches("./").trim_start_matches('/');
+            if name.is_empty() || name == "." {
+                continue;
+            }
+            if let Err(err) = self.create(&entry, name) {
+                warn!("initramfs: failed to create /{name}: {err:?}");
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, entry: &Entry, name: &str) -> AxResult<()> {
+        let fs = self.fs;
+        let perm = NodePermission::from_bits_truncate((entry.mode & 0o7777) as u16);
+        let ty = entry.mode & S_IFMT;
+
+        // Replace what an earlier archive put there, except for directories,
+        // which are merged.
+        if let Ok(old) = fs.resolve_no_follow(name) {
+            if ty == S_IFDIR && old.node_type() == NodeType::Directory {
+                return self.set_metadata(entry, name);
+            }
+            fs.remove_file(name)?;
+        }
+
+        if ty == S_IFREG && entry.nlink > 1 {
+            let key = (entry.dev.0, entry.dev.1, entry.ino);
+            if let Some(first) = self.links.get(&key) {
+                // `newc` stores the content with the last link only.
+                let first = fs.resolve(first.as_str())?;
+                let (dir, file_name) = fs.resolve_nonexistent(Path::new(name))?;
+                dir.link(file_name, &first)?;
+                if !entry.data.is_empty() {
+                    self.write(name, entry.data)?;
+                }
+                return Ok(());
+            }
+            self.links.insert(key, name.into());
+        }
+
+        match ty {
+            S_IFDIR => {
+                fs.create_dir(name, perm)?;
+            }
+            S_IFREG => {
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(fs, name)?;
+                self.write(name, entry.data)?;
+            }
+            S_IFLNK => {
+                let target = core::str::from_utf8(entry.data).map_err(|_| AxError::InvalidData)?;
+                fs.symlink(target, name)?;
+                return Ok(());
+            }
+            S_IFCHR | S_IFBLK | S_IFIFO | S_IFSOCK => {
+                let node_type = match ty {
+                    S_IFCHR => NodeType::CharacterDevice,
+                    S_IFBLK => NodeType::BlockDevice,
+                    S_IFIFO => NodeType::Fifo,
+                    _ => NodeType::Socket,
+                };
+                let (dir, file_name) = fs.resolve_nonexistent(Path::new(name))?;
+                let loc = dir.create(file_name, node_type, perm)?;
+                if matches!(ty, S_IFCHR | S_IFBLK) {
+                    set_device_id(loc.entry(), entry.rdev);
+                }
+            }
+            _ => return Err(AxError::InvalidData),
+        }
+        self.set_metadata(entry, name)
+    }
+
+    fn write(&self, name: &str, mut data: &[u8]) -> AxResult<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(self.fs, name)?
+            .into_file()?;
+        let len = data.len();
+        if file.backend()?.write_at(&mut data, 0)? != len {
+            return Err(AxError::StorageFull);
+        }
+        Ok(())
+    }
+
+    fn set_metadata(&self, entry: &Entry, name: &str) -> AxResult<()> {
+        let mtime = Duration::from_secs(entry.mtime as u64);
+        self.fs
+            .resolve_no_follow(name)?
+            .update_metadata(MetadataUpdate {
+                mode: Some(NodePermission::from_bits_truncate(
+                    (entry.mode & 0o7777) as u16,
+                )),
+                owner: Some((entry.uid, entry.gid)),
+                atime: Some(mtime),
+                mtime: Some(mtime),
+                ..Default::default()
+            })?;
+        Ok(())
+    }
+}
+
+/// Unpacks the initramfs archives into `fs`.
+///
+/// Returns whether there was any archive.
+pub fn unpack(fs: &FsContext) -> bool {
+    #[cfg(feature = "initramfs")]
+    let embedded = Some(EMBEDDED);
+    #[cfg(not(feature = "initramfs"))]
+    let embedded = None;
+
+    let mut found = false;
+    for archive in [embedded, starry_core::boot::initrd()]
+        .into_iter()
+        .flatten()
+    {
+        info!("Unpacking initramfs ({} bytes)...", archive.len());
+        let mut unpacker = Unpacker {
+            fs,
+            links: BTreeMap::new(),
+        };
+        if let Err(err) = unpacker.unpack(archive) {
+            warn!("initramfs: malformed archive: {err:?}");
+        }
+        found = true;
+    }
+    found
+}
@@ -1,38 +1,144 @@
 //! Virtual filesystems
 
 pub mod dev;
+mod initramfs;
+mod mqueue;
+mod pci;
 mod proc;
+mod sys;
+mod thermal;
 mod tmp;
+mod tracefs;
+mod uio;
+
+use alloc::string::{String, ToString};
 
 use axerrno::LinuxResult;
 use axfs_ng::{FS_CONTEXT, FsContext};
 use axfs_ng_vfs::{
-    Filesystem, NodePermission,
+    Filesystem, Location, Mountpoint, NodePermission, NodeType,
     path::{Path, PathBuf},
 };
+pub use mqueue::new_mqueuefs;
+use spin::Once;
 pub use starry_core::vfs::{Device, DeviceOps, DirMapping, SimpleFs};
-pub use tmp::MemoryFs;
+use starry_core::{
+    cmdline,
+    vfs::{MountFlags, add_mount, mount_roots},
+};
+pub use tmp::{MemoryFs, disk_quota, inode_generation, set_device_id};
 
 const DIR_PERMISSION: NodePermission = NodePermission::from_bits_truncate(0o755);
 
-fn mount_at(fs: &FsContext, path: &str, mount_fs: Filesystem) -> LinuxResult<()> {
+fn mount_at(
+    fs: &FsContext,
+    path: &str,
+    mount_fs: Filesystem,
+    flags: MountFlags,
+) -> LinuxResult<()> {
     if fs.resolve(path).is_err() {
         fs.create_dir(path, DIR_PERMISSION)?;
     }
     fs.resolve(path)?.mount(&mount_fs)?;
+    let name = mount_fs.name().to_string();
+    add_mount(
+        fs.resolve(path)?,
+        name.clone(),
+        path.to_string(),
+        name,
+        flags,
+    );
     info!("Mounted {} at {}", mount_fs.name(), path);
     Ok(())
 }
 
+/// The root of the filesystem mounted by the platform at boot.
+static BOOT_ROOT: Once<Location> = Once::new();
+/// The init process of the initramfs, if it is the root.
+static RDINIT: Once<String> = Once::new();
+
+/// Returns the root of the filesystem mounted by the platform at boot.
+pub fn boot_root() -> &'static Location {
+    BOOT_ROOT.get().expect("root not set up")
+}
+
+/// Returns the init process to run from the initramfs, if there is one.
+pub fn rdinit() -> Option<&'static str> {
+    RDINIT.get().map(String::as_str)
+}
+
+/// Writes back the data of every mounted filesystem.
+///
+/// All of them are flushed even if one fails, whose error is returned.
+pub fn sync_all() -> LinuxResult<()> {
+    let mut result = boot_root().filesystem().flush();
+    for root in mount_roots() {
+        result = result.and(root.filesystem().flush());
+    }
+    result.map_err(Into::into)
+}
+
+/// Sets up the root directory.
+///
+/// If there is an initramfs, it is unpacked into a memory filesystem that
+/// becomes the root, with the boot filesystem mounted at `/root` so that its
+/// init (`rdinit=`, `/init` by default) can `switch_root` there. If it has
+/// no init, the kernel switches to the boot filesystem itself, as Linux does.
+///
+/// Otherwise `root=` selects a directory of the boot filesystem as the root,
+/// as the device the boot filesystem is mounted from is chosen by the
+/// platform.
+fn setup_root() -> LinuxResult<()> {
+    let mut fs = FS_CONTEXT.lock();
+    let boot_root = BOOT_ROOT.call_once(|| fs.root_dir().clone());
+
+    let ramfs = FsContext::new(Mountpoint::new_root(&MemoryFs::new()).root_location());
+    if initramfs::unpack(&ramfs) {
+        let rdinit = cmdline::get("rdinit").unwrap_or("/init");
+        if ramfs.resolve(rdinit).is_ok() {
+            mount_at(
+                &ramfs,
+                "/root",
+                boot_root.filesystem().clone(),
+                MountFlags::empty(),
+            )?;
+            *fs = ramfs;
+            RDINIT.call_once(|| rdinit.into());
+            return Ok(());
+        }
+        warn!("initramfs has no {rdinit}, switching to the boot filesystem");
+    }
+
+    let Some(root) = cmdline::get("root") else {
+        return Ok(());
+    };
+    if let Some(ty) = cmdline::get("rootfstype") {
+        warn!("rootfstype={ty} is ignored, the root is on the boot filesystem");
+    }
+    match fs.resolve(root) {
+        Ok(loc) if loc.node_type() == NodeType::Directory => {
+            info!("Switching root to {root}");
+            *fs = FsContext::new(loc);
+        }
+        _ => warn!("root={root} is not a directory of the boot filesystem, ignoring"),
+    }
+    Ok(())
+}
+
 /// Mount all filesystems
 pub fn mount_all() -> LinuxResult<()> {
+    setup_root()?;
+
     let fs = FS_CONTEXT.lock();
-    mount_at(&fs, "/dev", dev::new_devfs())?;
-    mount_at(&fs, "/dev/shm", tmp::MemoryFs::new())?;
-    mount_at(&fs, "/tmp", tmp::MemoryFs::new())?;
-    mount_at(&fs, "/proc", proc::new_procfs())?;
+    let nosuid_nodev = MountFlags::NOSUID | MountFlags::NODEV;
+    let pseudo = nosuid_nodev | MountFlags::NOEXEC;
+    mount_at(&fs, "/dev", dev::new_devfs(), MountFlags::NOSUID)?;
+    mount_at(&fs, "/dev/shm", tmp::MemoryFs::new(), nosuid_nodev)?;
+    mount_at(&fs, "/dev/mqueue", mqueue::new_mqueuefs(), pseudo)?;
+    mount_at(&fs, "/tmp", tmp::MemoryFs::new(), nosuid_nodev)?;
+    mount_at(&fs, "/proc", proc::new_procfs(), pseudo)?;
 
-    mount_at(&fs, "/sys", tmp::MemoryFs::new())?;
+    mount_at(&fs, "/sys", tmp::MemoryFs::new(), pseudo)?;
     let mut path = PathBuf::new();
     for comp in Path::new("/sys/class/graphics/fb0/device").components() {
         path.push(comp.as_str());
@@ -42,6 +148,40 @@ pub fn mount_all() -> LinuxResult<()> {
     }
     path.push("subsystem");
     fs.symlink("whatever", &path)?;
+
+    let mut path = PathBuf::new();
+    for comp in Path::new("/sys/devices/system/cpu").components() {
+        path.push(comp.as_str());
+        if fs.resolve(&path).is_err() {
+            fs.create_dir(&path, DIR_PERMISSION)?;
+        }
+    }
+    mount_at(&fs, "/sys/devices/system/cpu", sys::new_cpufs(), pseudo)?;
+    fs.create_dir("/sys/devices/system/node", DIR_PERMISSION)?;
+    mount_at(&fs, "/sys/devices/system/node", sys::new_nodefs(), pseudo)?;
+
+    fs.create_dir("/sys/class/thermal", DIR_PERMISSION)?;
+    mount_at(&fs, "/sys/class/thermal", thermal::new_thermalfs(), pseudo)?;
+    fs.create_dir("/sys/class/hwmon", DIR_PERMISSION)?;
+    mount_at(&fs, "/sys/class/hwmon", thermal::new_hwmonfs(), pseudo)?;
+    fs.create_dir("/sys/class/uio", DIR_PERMISSION)?;
+    mount_at(&fs, "/sys/class/uio", uio::new_uiofs(), pseudo)?;
+    fs.create_dir("/sys/kernel", DIR_PERMISSION)?;
+    // `trace_pipe` is a device node, so this can't be `nodev`.
+    mount_at(
+        &fs,
+        "/sys/kernel/tracing",
+        tracefs::new_tracefs(),
+        MountFlags::NOSUID | MountFlags::NOEXEC,
+    )?;
+    fs.create_dir("/sys/bus", DIR_PERMISSION)?;
+    // `config` and `resourceN` are device nodes, so this can't be `nodev`.
+    mount_at(
+        &fs,
+        "/sys/bus/pci",
+        pci::new_pcifs(),
+        MountFlags::NOSUID | MountFlags::NOEXEC,
+    )?;
     drop(fs);
 
     #[cfg(feature = "dev-log")]
@@ -0,0 +1,46 @@
+//! The mqueue filesystem, usually mounted at `/dev/mqueue`, listing the
+//! POSIX message queues. Reading a queue shows its status and unlinking it
+//! is `mq_unlink`.
+
+use alloc::{borrow::Cow, boxed::Box, sync::Arc};
+
+use axfs_ng_vfs::{Filesystem, MetadataUpdate, NodeOps, NodePermission, VfsError, VfsResult};
+use starry_core::vfs::{NodeOpsMux, SimpleDir, SimpleDirOps, SimpleFile, SimpleFs};
+
+use crate::file::mqueue;
+
+struct QueueDir {
+    fs: Arc<SimpleFs>,
+}
+
+impl SimpleDirOps for QueueDir {
+    fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        Box::new(mqueue::names().into_iter().map(Cow::Owned))
+    }
+
+    fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        let queue = mqueue::lookup(name).ok_or(VfsError::NotFound)?;
+        let (owner, mode) = (queue.owner(), queue.mode());
+        let file = SimpleFile::new_regular(self.fs.clone(), move || Ok(queue.status()));
+        file.update_metadata(MetadataUpdate {
+            owner: Some(owner),
+            mode: Some(NodePermission::from_bits_truncate(mode as u16)),
+            ..Default::default()
+        })?;
+        Ok(NodeOpsMux::File(file))
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+
+    fn remove_child(&self, name: &str) -> VfsResult<()> {
+        mqueue::unlink(name)
+    }
+}
+
+pub fn new_mqueuefs() -> Filesystem {
+    SimpleFs::new_with("mqueue".into(), 0x19800202, |fs| {
+        SimpleDir::new_maker(fs.clone(), Arc::new(QueueDir { fs }))
+    })
+}
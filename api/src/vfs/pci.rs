@@ -0,0 +1,237 @@
+//! `/sys/bus/pci`, generated from the functions found behind the registered
+//! PCI host bridges.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+
+use axerrno::AxError;
+use axfs_ng_vfs::{DeviceId, Filesystem, NodeFlags, NodeType, VfsResult};
+use memory_addr::{PhysAddr, PhysAddrRange};
+use starry_core::vfs::{
+    Device, DeviceMmap, DeviceOps, DirMaker, DirMapping, SimpleDir, SimpleFile, SimpleFs,
+};
+
+use crate::pci::{self, PCI_CFG_SPACE_PUBLIC, PciDevice};
+
+/// `config`, the configuration space of a function.
+///
+/// Unprivileged processes only read the standard header, and only
+/// privileged ones write it, as on Linux.
+struct PciConfig(Arc<PciDevice>);
+
+impl DeviceOps for PciConfig {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let size = match pci::check_privileged() {
+            Ok(()) => self.0.config_size(),
+            Err(_) => PCI_CFG_SPACE_PUBLIC,
+        };
+        let len = buf.len().min(size.saturating_sub(offset as usize));
+        Ok(self.0.read_config(&mut buf[..len], offset as usize))
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        pci::check_privileged()?;
+        Ok(self.0.write_config(buf, offset as usize))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+/// `resourceN`, which maps memory BAR `N`.
+struct PciBar {
+    dev: Arc<PciDevice>,
+    index: usize,
+}
+
+impl DeviceOps for PciBar {
+    // Port I/O BARs can't be read or written through the file yet.
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn mmap(&self) -> DeviceMmap {
+        let bar = self.dev.resources()[self.index];
+        if !bar.is_mem() {
+            return DeviceMmap::None;
+        }
+        DeviceMmap::Physical(PhysAddrRange::from_start_size(
+            PhysAddr::from(bar.start as usize),
+            bar.size as usize,
+        ))
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+
+    fn open(&self) -> VfsResult<()> {
+        pci::check_privileged()
+    }
+}
+
+fn device_dir(fs: &Arc<SimpleFs>, dev: Arc<PciDevice>) -> DirMaker {
+    let mut dir = DirMapping::new();
+    let entries = [
+        ("vendor", format!("0x{:04x}\n", dev.vendor())),
+        ("device", format!("0x{:04x}\n", dev.device())),
+        (
+            "subsystem_vendor",
+            format!("0x{:04x}\n", dev.subsystem_vendor()),
+        ),
+        (
+            "subsystem_device",
+            format!("0x{:04x}\n", dev.subsystem_device()),
+        ),
+        ("class", format!("0x{:06x}\n", dev.class())),
+        ("revision", format!("0x{:02x}\n", dev.revision())),
+        ("irq", format!("{}\n", dev.irq())),
+        ("numa_node", "-1\n".into()),
+    ];
+    for (name, content) in entries {
+        dir.add(
+            name,
+            SimpleFile::new_regular(fs.clone(), move || Ok(content.clone())),
+        );
+    }
+
+    // The BARs, then the expansion ROM and, for bridges, their windows,
+    // which aren't decoded.
+    let mut resource = String::new();
+    let count = if dev.is_bridge() { 17 } else { 7 };
+    for index in 0..count {
+        let res = dev.resources().get(index).copied().unwrap_or_default();
+        resource += &format!(
+            "0x{:016x} 0x{:016x} 0x{:016x}\n",
+            res.start,
+            res.end(),
+            res.flags
+        );
+    }
+    dir.add(
+        "resource",
+        SimpleFile::new_regular(fs.clone(), move || Ok(resource.clone())),
+    );
+
+    for (index, res) in dev.resources().iter().enumerate() {
+        if res.size == 0 {
+            continue;
+        }
+        dir.add(
+            format!("resource{index}"),
+            Device::new(
+                fs.clone(),
+                NodeType::RegularFile,
+                DeviceId::default(),
+                Arc::new(PciBar {
+                    dev: dev.clone(),
+                    index,
+                }),
+            ),
+        );
+    }
+    dir.add(
+        "config",
+        Device::new(
+            fs.clone(),
+            NodeType::RegularFile,
+            DeviceId::default(),
+            Arc::new(PciConfig(dev)),
+        ),
+    );
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
+
+fn builder(fs: Arc<SimpleFs>) -> DirMaker {
+    let mut devices = DirMapping::new();
+    for dev in pci::devices() {
+        devices.add(dev.address().to_string(), device_dir(&fs, dev));
+    }
+
+    let mut root = DirMapping::new();
+    root.add(
+        "devices",
+        SimpleDir::new_maker(fs.clone(), Arc::new(devices)),
+    );
+    // No drivers are bound from userspace.
+    root.add(
+        "drivers",
+        SimpleDir::new_maker(fs.clone(), Arc::new(DirMapping::new())),
+    );
+    SimpleDir::new_maker(fs, Arc::new(root))
+}
+
+pub fn new_pcifs() -> Filesystem {
+    SimpleFs::new_with("sysfs".into(), 0x62656572, builder)
+}
+
+/// `/proc/bus/pci`: the `devices` list and the configuration space of each
+/// function as `BB/DD.F`, read-only.
+pub fn proc_bus_pci(fs: &Arc<SimpleFs>) -> DirMaker {
+    let mut dir = DirMapping::new();
+    dir.add(
+        "devices",
+        SimpleFile::new_regular(fs.clone(), || {
+            Ok(pci::devices()
+                .iter()
+                .map(|dev| pci::proc_devices_line(dev))
+                .collect::<String>())
+        }),
+    );
+
+    let devices = pci::devices();
+    let mut buses: Vec<(u16, u8)> = devices
+        .iter()
+        .map(|dev| (dev.address().segment, dev.address().bus))
+        .collect();
+    buses.dedup();
+    for (segment, bus) in buses {
+        let mut bus_dir = DirMapping::new();
+        for dev in devices
+            .iter()
+            .filter(|dev| (dev.address().segment, dev.address().bus) == (segment, bus))
+        {
+            let address = dev.address();
+            let dev = dev.clone();
+            bus_dir.add(
+                format!("{:02x}.{:x}", address.device, address.function),
+                SimpleFile::new_regular(fs.clone(), move || {
+                    let size = match pci::check_privileged() {
+                        Ok(()) => dev.config_size(),
+                        Err(_) => PCI_CFG_SPACE_PUBLIC,
+                    };
+                    let mut config = vec![0; size];
+                    dev.read_config(&mut config, 0);
+                    Ok(config)
+                }),
+            );
+        }
+        // Buses of other domains than the first are prefixed with theirs.
+        let name = if segment == 0 {
+            format!("{bus:02x}")
+        } else {
+            format!("{segment:04x}:{bus:02x}")
+        };
+        dir.add(name, SimpleDir::new_maker(fs.clone(), Arc::new(bus_dir)));
+    }
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
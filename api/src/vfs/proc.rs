@@ -7,21 +7,38 @@ use alloc::{
     vec,
     vec::Vec,
 };
-use core::{ffi::CStr, iter};
+use core::{
+    ffi::CStr,
+    fmt::Write,
+    iter,
+    net::{IpAddr, SocketAddr},
+    ptr,
+};
 
+use axfs_ng::FS_CONTEXT;
 use axfs_ng_vfs::{Filesystem, NodeType, VfsError, VfsResult};
-use axtask::{AxTaskRef, WeakAxTaskRef, current};
+use axnet::{
+    device::interfaces,
+    stats::{SocketKind, sockets},
+};
+use axtask::{AxTaskRef, TaskState, WeakAxTaskRef, current};
 use indoc::indoc;
 use starry_core::{
+    boot::FdtNode,
+    mm::shrink_caches,
+    sysctl::{DROP_CACHES, SYSCTLS, Sysctl},
     task::{AsThread, TaskStat, get_task, tasks},
     vfs::{
         DirMaker, DirMapping, NodeOpsMux, RwFile, SimpleDir, SimpleDirOps, SimpleFile,
-        SimpleFileOperation, SimpleFs,
+        SimpleFileOperation, SimpleFs, mounts_info,
     },
 };
 use starry_process::Process;
 
-use crate::file::FD_TABLE;
+use crate::{
+    file::FD_TABLE,
+    oom::{OOM_SCORE_ADJ_MAX, OOM_SCORE_ADJ_MIN, oom_score},
+};
 
 const DUMMY_MEMINFO: &str = indoc! {"
     MemTotal:       32536204 kB
@@ -83,6 +100,90 @@ const DUMMY_MEMINFO: &str = indoc! {"
     DirectMap1G:     1048576 kB
 "};
 
+/// Generates `/proc/net/dev`.
+fn net_dev() -> String {
+    let mut out = String::from(indoc! {"
+        Inter-|   Receive                                                |  Transmit
+         face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    "});
+    for iface in interfaces() {
+        let stats = iface.stats();
+        writeln!(
+            out,
+            "{:>6}:{:>8} {:>7} {:>4} {:>4} {:>4} {:>5} {:>10} {:>9} {:>8} {:>7} {:>4} {:>4} {:>4} \
+             {:>5} {:>7} {:>10}",
+            iface.name(),
+            stats.rx_bytes,
+            stats.rx_packets,
+            stats.rx_errors,
+            stats.rx_dropped,
+            0,
+            0,
+            0,
+            0,
+            stats.tx_bytes,
+            stats.tx_packets,
+            stats.tx_errors,
+            stats.tx_dropped,
+            0,
+            0,
+            0,
+            0,
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Formats an address the way Linux does in `/proc/net/{tcp,udp}{,6}`: the
+/// raw network-order words printed as native integers, followed by the port.
+fn net_addr(addr: &SocketAddr) -> String {
+    let mut out = String::new();
+    match addr.ip() {
+        IpAddr::V4(ip) => write!(out, "{:08X}", u32::from_ne_bytes(ip.octets())).unwrap(),
+        IpAddr::V6(ip) => {
+            for word in ip.octets().chunks_exact(4) {
+                write!(out, "{:08X}", u32::from_ne_bytes(word.try_into().unwrap())).unwrap();
+            }
+        }
+    }
+    write!(out, ":{:04X}", addr.port()).unwrap();
+    out
+}
+
+/// Generates `/proc/net/{tcp,udp}{,6}`.
+fn net_sockets(kind: SocketKind, ipv6: bool) -> String {
+    let mut out = String::new();
+    let width = if ipv6 { 33 } else { 13 };
+    writeln!(
+        out,
+        "  sl  {:<width$} {:<width$} st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout \
+         inode",
+        "local_address", "rem_address",
+    )
+    .unwrap();
+    let entries = sockets()
+        .into_iter()
+        .filter(|entry| entry.kind == kind && entry.local.is_ipv6() == ipv6);
+    for (sl, entry) in entries.enumerate() {
+        writeln!(
+            out,
+            "{:>4}: {} {} {:02X} {:08X}:{:08X} 00:00000000 00000000 {:>5} {:>8} {}",
+            sl,
+            net_addr(&entry.local),
+            net_addr(&entry.remote),
+            entry.state,
+            entry.tx_queue,
+            entry.rx_queue,
+            0,
+            0,
+            0,
+        )
+        .unwrap();
+    }
+    out
+}
+
 pub fn new_procfs() -> Filesystem {
     SimpleFs::new_with("proc".into(), 0x9fa0, builder)
 }
@@ -118,6 +219,7 @@ impl SimpleDirOps for ProcessTaskDir {
             Arc::new(ThreadDir {
                 fs: self.fs.clone(),
                 task: Arc::downgrade(&task),
+                thread: true,
             }),
         )))
     }
@@ -127,19 +229,45 @@ impl SimpleDirOps for ProcessTaskDir {
     }
 }
 
+/// The status of `task`, or of its process unless `thread` is set.
 #[rustfmt::skip]
-fn task_status(task: &AxTaskRef) -> String {
+fn task_status(task: &AxTaskRef, thread: bool) -> String {
+    let proc_data = &task.as_thread().proc_data;
+    let proc = &proc_data.proc;
+    let state = match task.state() {
+        TaskState::Running | TaskState::Ready => "R (running)",
+        TaskState::Blocked => "S (sleeping)",
+        TaskState::Exited => "Z (zombie)",
+    };
+    let pid = if thread {
+        task.id().as_u64() as u32
+    } else {
+        proc.pid()
+    };
+    let cred = proc_data.cred.read();
     format!(
-        "Tgid:\t{}\n\
+        "Name:\t{}\n\
+        State:\t{}\n\
+        Tgid:\t{}\n\
         Pid:\t{}\n\
-        Uid:\t0 0 0 0\n\
-        Gid:\t0 0 0 0\n\
+        PPid:\t{}\n\
+        Uid:\t{} {} {} {}\n\
+        Gid:\t{} {} {} {}\n\
+        Threads:\t{}\n\
+        NoNewPrivs:\t{}\n\
         Cpus_allowed:\t1\n\
         Cpus_allowed_list:\t0\n\
         Mems_allowed:\t1\n\
         Mems_allowed_list:\t0",
-        task.as_thread().proc_data.proc.pid(),
-        task.id().as_u64()
+        task.name(),
+        state,
+        proc.pid(),
+        pid,
+        proc.parent().map_or(0, |p| p.pid()),
+        cred.uid, cred.euid, cred.suid, cred.fsuid,
+        cred.gid, cred.egid, cred.sgid, cred.fsgid,
+        proc.threads().len(),
+        cred.no_new_privs as u8,
     )
 }
 
@@ -183,28 +311,36 @@ impl SimpleDirOps for ThreadFdDir {
     }
 }
 
-/// The /proc/[pid] directory
+/// The /proc/[pid] and /proc/[pid]/task/[tid] directories
 struct ThreadDir {
     fs: Arc<SimpleFs>,
     task: WeakAxTaskRef,
+    /// Whether this describes the thread alone rather than its process, as
+    /// /proc/[pid]/task/[tid] does.
+    thread: bool,
 }
 
 impl SimpleDirOps for ThreadDir {
     fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let thread = self.thread;
         Box::new(
             [
                 "stat",
                 "status",
+                "oom_score",
                 "oom_score_adj",
                 "task",
                 "maps",
                 "mounts",
                 "cmdline",
+                "environ",
                 "comm",
                 "exe",
+                "root",
                 "fd",
             ]
             .into_iter()
+            .filter(move |name| !thread || *name != "task")
             .map(Cow::Borrowed),
         )
     }
@@ -212,12 +348,23 @@ impl SimpleDirOps for ThreadDir {
     fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
         let fs = self.fs.clone();
         let task = self.task.upgrade().ok_or(VfsError::NotFound)?;
+        let thread = self.thread;
         Ok(match name {
             "stat" => SimpleFile::new_regular(fs, move || {
-                Ok(format!("{}", TaskStat::from_thread(&task)?).into_bytes())
+                let stat = if thread {
+                    TaskStat::from_single_thread(&task)?
+                } else {
+                    TaskStat::from_thread(&task)?
+                };
+                Ok(format!("{stat}").into_bytes())
+            })
+            .into(),
+            "status" => SimpleFile::new_regular(fs, move || Ok(task_status(&task, thread))).into(),
+            "oom_score" => SimpleFile::new_regular(fs, move || {
+                let score = oom_score(&task.as_thread().proc_data).unwrap_or(0);
+                Ok(format!("{score}\n").into_bytes())
             })
             .into(),
-            "status" => SimpleFile::new_regular(fs, move || Ok(task_status(&task))).into(),
             "oom_score_adj" => SimpleFile::new_regular(
                 fs,
                 RwFile::new(move |req| match req {
@@ -228,7 +375,8 @@ impl SimpleDirOps for ThreadDir {
                         if !data.is_empty() {
                             let value = str::from_utf8(data)
                                 .ok()
-                                .and_then(|it| it.parse::<i32>().ok())
+                                .and_then(|it| it.trim().parse::<i32>().ok())
+                                .filter(|it| (OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(it))
                                 .ok_or(VfsError::InvalidInput)?;
                             task.as_thread().set_oom_score_adj(value);
                         }
@@ -237,7 +385,7 @@ impl SimpleDirOps for ThreadDir {
                 }),
             )
             .into(),
-            "task" => SimpleDir::new_maker(
+            "task" if !thread => SimpleDir::new_maker(
                 fs.clone(),
                 Arc::new(ProcessTaskDir {
                     fs,
@@ -254,10 +402,7 @@ impl SimpleDirOps for ThreadDir {
                 "})
             })
             .into(),
-            "mounts" => SimpleFile::new_regular(fs, move || {
-                Ok("proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n")
-            })
-            .into(),
+            "mounts" => SimpleFile::new_regular(fs, move || Ok(mounts_info())).into(),
             "cmdline" => SimpleFile::new_regular(fs, move || {
                 let cmdline = task.as_thread().proc_data.cmdline.read();
                 let mut buf = Vec::new();
@@ -268,6 +413,16 @@ impl SimpleDirOps for ThreadDir {
                 Ok(buf)
             })
             .into(),
+            "environ" => SimpleFile::new_regular(fs, move || {
+                let environ = task.as_thread().proc_data.environ.read();
+                let mut buf = Vec::new();
+                for var in environ.iter() {
+                    buf.extend_from_slice(var.as_bytes());
+                    buf.push(0);
+                }
+                Ok(buf)
+            })
+            .into(),
             "comm" => SimpleFile::new_regular(
                 fs,
                 RwFile::new(move |req| match req {
@@ -277,6 +432,7 @@ impl SimpleDirOps for ThreadDir {
                         let copy_len = name.len().min(15);
                         bytes[..copy_len].copy_from_slice(&name.as_bytes()[..copy_len]);
                         bytes[copy_len] = b'\n';
+                        bytes.truncate(copy_len + 1);
                         Ok(Some(bytes))
                     }
                     SimpleFileOperation::Write(data) => {
@@ -296,8 +452,22 @@ impl SimpleDirOps for ThreadDir {
                 }),
             )
             .into(),
+            // Resolved on every read, so that the link follows the executable
+            // if it is renamed.
             "exe" => SimpleFile::new(fs, NodeType::Symlink, move || {
-                Ok(task.as_thread().proc_data.exe_path.read().clone())
+                Ok(task
+                    .as_thread()
+                    .proc_data
+                    .exe
+                    .read()
+                    .absolute_path()?
+                    .to_string())
+            })
+            .into(),
+            "root" => SimpleFile::new(fs, NodeType::Symlink, move || {
+                let scope = task.as_thread().proc_data.scope.read();
+                let root = FS_CONTEXT.scope(&scope).lock().root_dir().clone();
+                Ok(root.absolute_path()?.to_string())
             })
             .into(),
             "fd" => SimpleDir::new_maker(
@@ -317,7 +487,41 @@ impl SimpleDirOps for ThreadDir {
     }
 }
 
-/// Handles /proc/[pid] & /proc/self
+/// A node of /proc/device-tree: its subnodes are directories and its
+/// properties files holding their raw values.
+struct DeviceTreeDir {
+    fs: Arc<SimpleFs>,
+    node: &'static FdtNode,
+}
+
+impl SimpleDirOps for DeviceTreeDir {
+    fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let children = self.node.children.iter().map(|child| child.name.as_str());
+        let properties = self.node.properties.iter().map(|(name, _)| name.as_str());
+        Box::new(children.chain(properties).map(Cow::Borrowed))
+    }
+
+    fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        let fs = self.fs.clone();
+        if let Some(node) = self.node.children.iter().find(|child| child.name == name) {
+            return Ok(
+                SimpleDir::new_maker(fs.clone(), Arc::new(DeviceTreeDir { fs, node })).into(),
+            );
+        }
+        let (_, value) = self
+            .node
+            .properties
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .ok_or(VfsError::NotFound)?;
+        Ok(SimpleFile::new_regular(fs, move || Ok(value.as_slice())).into())
+    }
+}
+
+/// Handles /proc/[pid], /proc/self & /proc/thread-self
+///
+/// Only processes are listed, but the directories of other threads may be
+/// looked up by their TID too, as on Linux.
 struct ProcFsHandler(Arc<SimpleFs>);
 
 impl SimpleDirOps for ProcFsHandler {
@@ -325,12 +529,27 @@ impl SimpleDirOps for ProcFsHandler {
         Box::new(
             tasks()
                 .into_iter()
+                .filter(|task| task.id().as_u64() as u32 == task.as_thread().proc_data.proc.pid())
                 .map(|task| task.id().as_u64().to_string().into())
-                .chain([Cow::Borrowed("self")]),
+                .chain([Cow::Borrowed("self"), Cow::Borrowed("thread-self")]),
         )
     }
 
     fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        if name == "thread-self" {
+            let curr = current();
+            let path = format!(
+                "{}/task/{}",
+                curr.as_thread().proc_data.proc.pid(),
+                curr.id().as_u64()
+            );
+            return Ok(SimpleFile::new(
+                self.0.clone(),
+                NodeType::Symlink,
+                move || Ok(path.clone()),
+            )
+            .into());
+        }
         let task = if name == "self" {
             current().clone()
         } else {
@@ -342,6 +561,7 @@ impl SimpleDirOps for ProcFsHandler {
             Arc::new(ThreadDir {
                 fs: self.0.clone(),
                 task: Arc::downgrade(&task),
+                thread: false,
             }),
         ));
         Ok(node)
@@ -352,12 +572,63 @@ impl SimpleDirOps for ProcFsHandler {
     }
 }
 
+fn sysctl_file(fs: &Arc<SimpleFs>, sysctl: &'static Sysctl) -> Arc<SimpleFile> {
+    SimpleFile::new_regular(
+        fs.clone(),
+        RwFile::new(move |req| match req {
+            SimpleFileOperation::Read => Ok(Some(format!("{}\n", sysctl.get()))),
+            SimpleFileOperation::Write(data) => {
+                if !current().as_thread().proc_data.cred.read().is_privileged() {
+                    return Err(VfsError::PermissionDenied);
+                }
+                let value = str::from_utf8(data)
+                    .ok()
+                    .and_then(|it| it.trim().parse().ok())
+                    .ok_or(VfsError::InvalidInput)?;
+                sysctl.set(value)?;
+                if ptr::eq(sysctl, &DROP_CACHES) && value & 3 != 0 {
+                    // Dirty pages are never dropped, so write them back first
+                    // as `sync` would.
+                    super::sync_all()?;
+                    let freed = shrink_caches();
+                    info!("drop_caches: {}, freed {} pages", value, freed);
+                }
+                Ok(None)
+            }
+        }),
+    )
+}
+
+/// Builds the directory of the sysctls whose paths start with `prefix`.
+fn sysctl_dir(fs: &Arc<SimpleFs>, prefix: &str) -> DirMaker {
+    let mut dir = DirMapping::new();
+    let mut subdirs = Vec::new();
+    for &sysctl in SYSCTLS {
+        let Some(rest) = sysctl.path.strip_prefix(prefix) else {
+            continue;
+        };
+        match rest.split_once('/') {
+            Some((sub, _)) if !subdirs.contains(&sub) => subdirs.push(sub),
+            Some(_) => {}
+            None => dir.add(rest, sysctl_file(fs, sysctl)),
+        }
+    }
+    for sub in subdirs {
+        dir.add(sub, sysctl_dir(fs, &format!("{prefix}{sub}/")));
+    }
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
+
 fn builder(fs: Arc<SimpleFs>) -> DirMaker {
     let mut root = DirMapping::new();
     root.add(
         "mounts",
+        SimpleFile::new_regular(fs.clone(), || Ok(mounts_info())),
+    );
+    root.add(
+        "cmdline",
         SimpleFile::new_regular(fs.clone(), || {
-            Ok("proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n")
+            Ok(format!("{}\n", starry_core::cmdline::raw()))
         }),
     );
     root.add(
@@ -384,26 +655,67 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             }
         }),
     );
+    root.add(
+        "readahead",
+        SimpleFile::new_regular(fs.clone(), || Ok(crate::file::readahead::stats())),
+    );
+    root.add(
+        "syscalls",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(|req| match req {
+                SimpleFileOperation::Read => Ok(Some(crate::syscall::stats::render())),
+                SimpleFileOperation::Write(_) => {
+                    crate::syscall::stats::clear();
+                    Ok(None)
+                }
+            }),
+        ),
+    );
     root.add(
         "interrupts",
         SimpleFile::new_regular(fs.clone(), || Ok(format!("0: {}", crate::time::irq_cnt()))),
     );
 
-    root.add("sys", {
-        let mut sys = DirMapping::new();
-
-        sys.add("kernel", {
-            let mut kernel = DirMapping::new();
-
-            kernel.add(
-                "pid_max",
-                SimpleFile::new_regular(fs.clone(), || Ok("32768\n")),
-            );
-
-            SimpleDir::new_maker(fs.clone(), Arc::new(kernel))
-        });
+    root.add("net", {
+        let mut net = DirMapping::new();
+        net.add("dev", SimpleFile::new_regular(fs.clone(), || Ok(net_dev())));
+        net.add(
+            "tcp",
+            SimpleFile::new_regular(fs.clone(), || Ok(net_sockets(SocketKind::Tcp, false))),
+        );
+        net.add(
+            "tcp6",
+            SimpleFile::new_regular(fs.clone(), || Ok(net_sockets(SocketKind::Tcp, true))),
+        );
+        net.add(
+            "udp",
+            SimpleFile::new_regular(fs.clone(), || Ok(net_sockets(SocketKind::Udp, false))),
+        );
+        net.add(
+            "udp6",
+            SimpleFile::new_regular(fs.clone(), || Ok(net_sockets(SocketKind::Udp, true))),
+        );
+        SimpleDir::new_maker(fs.clone(), Arc::new(net))
+    });
 
-        SimpleDir::new_maker(fs.clone(), Arc::new(sys))
+    root.add("sys", sysctl_dir(&fs, ""));
+    if let Some(node) = starry_core::boot::device_tree() {
+        root.add(
+            "device-tree",
+            SimpleDir::new_maker(
+                fs.clone(),
+                Arc::new(DeviceTreeDir {
+                    fs: fs.clone(),
+                    node,
+                }),
+            ),
+        );
+    }
+    root.add("bus", {
+        let mut bus = DirMapping::new();
+        bus.add("pci", super::pci::proc_bus_pci(&fs));
+        SimpleDir::new_maker(fs.clone(), Arc::new(bus))
     });
 
     let proc_dir = ProcFsHandler(fs.clone());
@@ -0,0 +1,484 @@
+//! `/sys/devices/system/cpu` and `/sys/devices/system/node`, generated from
+//! the platform CPU enumeration, the caches described by the device tree or
+//! CPUID, the registered cpufreq policies and the NUMA nodes.
+
+use alloc::{borrow::Cow, boxed::Box, format, string::String, sync::Arc, vec::Vec};
+
+use axerrno::AxResult;
+use axfs_ng_vfs::{Filesystem, NodeType, VfsError, VfsResult};
+#[cfg(not(target_arch = "x86_64"))]
+use starry_core::boot::{self, FdtNode};
+use starry_core::{
+    cpu, numa,
+    vfs::{
+        DirMaker, DirMapping, NodeOpsMux, RwFile, SimpleDir, SimpleDirOps, SimpleFile,
+        SimpleFileOperation, SimpleFs,
+    },
+};
+
+use crate::cpufreq::{self, Governor, Policy};
+
+/// Describes one cache as seen by a single CPU.
+///
+/// Attributes the platform doesn't describe are left out of sysfs.
+struct CacheInfo {
+    level: u32,
+    kind: &'static str,
+    size: u32,
+    line_size: Option<u32>,
+    ways: Option<u32>,
+    sets: Option<u32>,
+    /// The CPUs sharing this cache.
+    shared: Vec<usize>,
+}
+
+/// Bounds the `next-level-cache` chains, which may be malformed.
+#[cfg(not(target_arch = "x86_64"))]
+const MAX_CACHE_LEVELS: usize = 8;
+
+/// Returns the caches of `cpu` described by the `/cpus` node of the device
+/// tree: the L1 caches are given by the CPU node itself, and each further
+/// level by the node its predecessor's `next-level-cache` refers to.
+#[cfg(not(target_arch = "x86_64"))]
+fn platform_caches(cpu: usize) -> Vec<CacheInfo> {
+    let Some(root) = boot::device_tree() else {
+        return Vec::new();
+    };
+    let Some(cpus) = root.children.iter().find(|node| node.name == "cpus") else {
+        return Vec::new();
+    };
+    let chains: Vec<Vec<&FdtNode>> = cpus
+        .children
+        .iter()
+        .filter(|node| node.property("device_type") == Some(b"cpu\0".as_slice()))
+        .map(|node| {
+            let mut chain = alloc::vec![node];
+            while chain.len() < MAX_CACHE_LEVELS {
+                let next = chain[chain.len() - 1]
+                    .property_u32("next-level-cache")
+                    .and_then(|phandle| root.find_phandle(phandle));
+                match next {
+                    Some(next) => chain.push(next),
+                    None => break,
+                }
+            }
+            chain
+        })
+        .collect();
+    let Some(chain) = chains.get(cpu) else {
+        return Vec::new();
+    };
+
+    let mut caches = Vec::new();
+    for (depth, &node) in chain.iter().enumerate() {
+        let level = node.property_u32("cache-level").unwrap_or(depth as u32 + 1);
+        let shared: Vec<usize> = (0..chains.len())
+            .filter(|&other| chains[other].iter().any(|&it| core::ptr::eq(it, node)))
+            .collect();
+        for (prefix, kind) in [("d-", "Data"), ("i-", "Instruction"), ("", "Unified")] {
+            let prop = |name: &str| node.property_u32(&format!("{prefix}{name}"));
+            let Some(size) = prop("cache-size") else {
+                continue;
+            };
+            let line_size = prop("cache-line-size").or_else(|| prop("cache-block-size"));
+            let sets = prop("cache-sets");
+            let ways = line_size
+                .zip(sets)
+                .and_then(|(line_size, sets)| size.checked_div(line_size.checked_mul(sets)?));
+            caches.push(CacheInfo {
+                level,
+                kind,
+                size,
+                line_size,
+                ways,
+                sets,
+                shared: shared.clone(),
+            });
+        }
+    }
+    caches
+}
+
+/// Returns the caches of `cpu` enumerated by the deterministic cache
+/// parameters leaf of CPUID: leaf 4 on Intel, 0x8000_001d on AMD.
+#[cfg(target_arch = "x86_64")]
+fn platform_caches(cpu: usize) -> Vec<CacheInfo> {
+    use core::arch::x86_64::__cpuid_count;
+
+    let max_leaf = unsafe { __cpuid_count(0, 0) }.eax;
+    let max_ext_leaf = unsafe { __cpuid_count(0x8000_0000, 0) }.eax;
+    let leaf = if max_leaf >= 4 && unsafe { __cpuid_count(4, 0) }.eax & 0x1f != 0 {
+        4
+    } else if max_ext_leaf >= 0x8000_001d {
+        0x8000_001d
+    } else {
+        return Vec::new();
+    };
+
+    let mut caches = Vec::new();
+    for index in 0..16 {
+        let regs = unsafe { __cpuid_count(leaf, index) };
+        let kind = match regs.eax & 0x1f {
+            1 => "Data",
+            2 => "Instruction",
+            3 => "Unified",
+            _ => break,
+        };
+        let line_size = (regs.ebx & 0xfff) + 1;
+        let partitions = ((regs.ebx >> 12) & 0x3ff) + 1;
+        let ways = (regs.ebx >> 22) + 1;
+        let sets = regs.ecx + 1;
+        // Given in APIC IDs, which are assigned to CPUs in order.
+        let shared_by = (((regs.eax >> 14) & 0xfff) as usize + 1).min(cpu_num());
+        let first = cpu / shared_by * shared_by;
+        caches.push(CacheInfo {
+            level: (regs.eax >> 5) & 0x7,
+            kind,
+            size: ways * partitions * line_size * sets,
+            line_size: Some(line_size),
+            ways: Some(ways),
+            sets: Some(sets),
+            shared: (first..(first + shared_by).min(cpu_num())).collect(),
+        });
+    }
+    caches
+}
+
+fn cpu_num() -> usize {
+    axconfig::plat::CPU_NUM
+}
+
+/// Formats `start..end` the way the kernel prints CPU lists, e.g. `0-3`.
+fn cpu_list(start: usize, end: usize) -> String {
+    if end - start == 1 {
+        format!("{start}\n")
+    } else {
+        format!("{}-{}\n", start, end - 1)
+    }
+}
+
+/// Formats the CPUs for which `f` holds as a list, e.g. `0-1,3`.
+fn cpu_list_of(f: impl Fn(usize) -> bool) -> String {
+    let mut out = String::new();
+    let mut cpu = 0;
+    while cpu < cpu_num() {
+        if !f(cpu) {
+            cpu += 1;
+            continue;
+        }
+        let start = cpu;
+        while cpu < cpu_num() && f(cpu) {
+            cpu += 1;
+        }
+        if !out.is_empty() {
+            out.push(',');
+        }
+        out += cpu_list(start, cpu).trim_end();
+    }
+    out.push('\n');
+    out
+}
+
+/// Formats `start..end` as a hexadecimal CPU mask, e.g. `f`.
+fn cpu_map(start: usize, end: usize) -> String {
+    cpu_map_of(|cpu| (start..end).contains(&cpu))
+}
+
+/// Formats the CPUs for which `f` holds as a hexadecimal CPU mask.
+fn cpu_map_of(f: impl Fn(usize) -> bool) -> String {
+    let mut words = alloc::vec![0u32; cpu_num().div_ceil(32)];
+    for cpu in (0..cpu_num()).filter(|&cpu| f(cpu)) {
+        words[cpu / 32] |= 1 << (cpu % 32);
+    }
+    let mut out = String::new();
+    for (i, word) in words.iter().rev().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out += &format!("{word:08x}");
+    }
+    out.push('\n');
+    out
+}
+
+fn cache_dir(fs: &Arc<SimpleFs>, caches: Vec<CacheInfo>) -> DirMaker {
+    let mut cache = DirMapping::new();
+    for (index, info) in caches.into_iter().enumerate() {
+        let shared = |cpu| info.shared.contains(&cpu);
+        let optional = [
+            ("coherency_line_size", info.line_size),
+            ("ways_of_associativity", info.ways),
+            ("number_of_sets", info.sets),
+        ];
+        let entries = [
+            ("level", format!("{}\n", info.level)),
+            ("type", format!("{}\n", info.kind)),
+            ("size", format!("{}K\n", info.size / 1024)),
+            ("shared_cpu_list", cpu_list_of(shared)),
+            ("shared_cpu_map", cpu_map_of(shared)),
+        ]
+        .into_iter()
+        .chain(
+            optional
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, format!("{}\n", value?)))),
+        );
+
+        let mut dir = DirMapping::new();
+        for (name, content) in entries {
+            dir.add(
+                name,
+                SimpleFile::new_regular(fs.clone(), move || Ok(content.clone())),
+            );
+        }
+        cache.add(
+            format!("index{index}"),
+            SimpleDir::new_maker(fs.clone(), Arc::new(dir)),
+        );
+    }
+    SimpleDir::new_maker(fs.clone(), Arc::new(cache))
+}
+
+fn cpu_dir(fs: &Arc<SimpleFs>, cpu: usize) -> DirMaker {
+    let mut topology = DirMapping::new();
+    let entries = [
+        ("core_id", format!("{cpu}\n")),
+        ("package_id", "0\n".into()),
+        ("physical_package_id", "0\n".into()),
+        ("core_siblings_list", cpu_list(0, cpu_num())),
+        ("core_cpus_list", cpu_list(cpu, cpu + 1)),
+        ("thread_siblings_list", cpu_list(cpu, cpu + 1)),
+    ];
+    for (name, content) in entries {
+        topology.add(
+            name,
+            SimpleFile::new_regular(fs.clone(), move || Ok(content.clone())),
+        );
+    }
+
+    let mut dir = DirMapping::new();
+    dir.add(
+        "online",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(move |req| match req {
+                SimpleFileOperation::Read => Ok(Some(format!("{}\n", cpu::is_online(cpu) as u8))),
+                SimpleFileOperation::Write(data) => {
+                    let online = match str::from_utf8(data).map(str::trim) {
+                        Ok("0") => false,
+                        Ok("1") => true,
+                        _ => return Err(VfsError::InvalidInput),
+                    };
+                    cpu::set_online(cpu, online)?;
+                    Ok(None)
+                }
+            }),
+        ),
+    );
+    dir.add(
+        "topology",
+        SimpleDir::new_maker(fs.clone(), Arc::new(topology)),
+    );
+    let caches = platform_caches(cpu);
+    if !caches.is_empty() {
+        dir.add("cache", cache_dir(fs, caches));
+    }
+    // Dangles until a policy covering the CPU is registered.
+    dir.add(
+        "cpufreq",
+        SimpleFile::new(fs.clone(), NodeType::Symlink, move || {
+            let id = cpufreq::policy_of(cpu).map_or(cpu, |(id, _)| id);
+            Ok(format!("../cpufreq/policy{id}"))
+        }),
+    );
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
+
+fn parse_khz(data: &[u8]) -> VfsResult<u32> {
+    str::from_utf8(data)
+        .ok()
+        .and_then(|it| it.trim().parse().ok())
+        .ok_or(VfsError::InvalidInput)
+}
+
+fn policy_dir(fs: &Arc<SimpleFs>, policy: Arc<Policy>) -> DirMaker {
+    let mut dir = DirMapping::new();
+    let cpus = policy.cpus();
+    let frequencies = policy
+        .driver()
+        .frequencies()
+        .iter()
+        .map(|it| format!("{it}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let governors = Governor::ALL.map(Governor::as_str).join(" ");
+    let entries = [
+        ("affected_cpus", cpu_list(cpus.start, cpus.end)),
+        ("related_cpus", cpu_list(cpus.start, cpus.end)),
+        ("cpuinfo_min_freq", format!("{}\n", policy.cpuinfo_min())),
+        ("cpuinfo_max_freq", format!("{}\n", policy.cpuinfo_max())),
+        (
+            "cpuinfo_transition_latency",
+            format!("{}\n", policy.driver().transition_latency()),
+        ),
+        ("scaling_available_frequencies", format!("{frequencies}\n")),
+        ("scaling_available_governors", format!("{governors}\n")),
+        ("scaling_driver", format!("{}\n", policy.driver().name())),
+    ];
+    for (name, content) in entries {
+        dir.add(
+            name,
+            SimpleFile::new_regular(fs.clone(), move || Ok(content.clone())),
+        );
+    }
+
+    let p = policy.clone();
+    let cur_freq = move || Ok(format!("{}\n", p.driver().get()));
+    dir.add(
+        "scaling_cur_freq",
+        SimpleFile::new_regular(fs.clone(), cur_freq.clone()),
+    );
+    dir.add(
+        "cpuinfo_cur_freq",
+        SimpleFile::new_regular(fs.clone(), cur_freq),
+    );
+
+    let p = policy.clone();
+    dir.add(
+        "scaling_governor",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(move |req| match req {
+                SimpleFileOperation::Read => Ok(Some(format!("{}\n", p.governor().as_str()))),
+                SimpleFileOperation::Write(data) => {
+                    let governor = str::from_utf8(data)
+                        .ok()
+                        .and_then(|it| Governor::from_name(it.trim()))
+                        .ok_or(VfsError::InvalidInput)?;
+                    p.set_governor(governor)?;
+                    Ok(None)
+                }
+            }),
+        ),
+    );
+    let knobs: [(&str, fn(&Policy) -> u32, fn(&Policy, u32) -> AxResult<()>); 3] = [
+        ("scaling_min_freq", Policy::min, Policy::set_min),
+        ("scaling_max_freq", Policy::max, Policy::set_max),
+        ("scaling_setspeed", Policy::setspeed, Policy::set_setspeed),
+    ];
+    for (name, get, set) in knobs {
+        let p = policy.clone();
+        dir.add(
+            name,
+            SimpleFile::new_regular(
+                fs.clone(),
+                RwFile::new(move |req| match req {
+                    SimpleFileOperation::Read => Ok(Some(format!("{}\n", get(&p)))),
+                    SimpleFileOperation::Write(data) => {
+                        set(&p, parse_khz(data)?)?;
+                        Ok(None)
+                    }
+                }),
+            ),
+        );
+    }
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
+
+/// `cpufreq`, listing the policies registered so far.
+struct CpufreqDir {
+    fs: Arc<SimpleFs>,
+}
+
+impl SimpleDirOps for CpufreqDir {
+    fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        Box::new((0..cpufreq::policies().len()).map(|id| Cow::Owned(format!("policy{id}"))))
+    }
+
+    fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        let policy = name
+            .strip_prefix("policy")
+            .and_then(|it| it.parse::<usize>().ok())
+            .and_then(|id| cpufreq::policies().get(id).cloned())
+            .ok_or(VfsError::NotFound)?;
+        Ok(policy_dir(&self.fs, policy).into())
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+}
+
+fn builder(fs: Arc<SimpleFs>) -> DirMaker {
+    let mut root = DirMapping::new();
+    for name in ["possible", "present"] {
+        root.add(
+            name,
+            SimpleFile::new_regular(fs.clone(), || Ok(cpu_list(0, cpu_num()))),
+        );
+    }
+    root.add(
+        "online",
+        SimpleFile::new_regular(fs.clone(), || Ok(cpu_list_of(cpu::is_online))),
+    );
+    root.add(
+        "offline",
+        SimpleFile::new_regular(fs.clone(), || Ok(cpu_list_of(|it| !cpu::is_online(it)))),
+    );
+    root.add(
+        "kernel_max",
+        SimpleFile::new_regular(fs.clone(), || Ok(format!("{}\n", cpu_num() - 1))),
+    );
+    for cpu in 0..cpu_num() {
+        root.add(format!("cpu{cpu}"), cpu_dir(&fs, cpu));
+    }
+    root.add(
+        "cpufreq",
+        SimpleDir::new_maker(fs.clone(), Arc::new(CpufreqDir { fs: fs.clone() })),
+    );
+    SimpleDir::new_maker(fs, Arc::new(root))
+}
+
+pub fn new_cpufs() -> Filesystem {
+    SimpleFs::new_with("sysfs".into(), 0x62656572, builder)
+}
+
+fn node_dir(fs: &Arc<SimpleFs>, node: usize) -> DirMaker {
+    let cpus = numa::node_cpus(node);
+    let distance = (0..numa::num_nodes())
+        .map(|to| format!("{}", numa::distance(node, to)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let entries = [
+        ("cpulist", cpu_list(cpus.start, cpus.end)),
+        ("cpumap", cpu_map(cpus.start, cpus.end)),
+        ("distance", format!("{distance}\n")),
+    ];
+    let mut dir = DirMapping::new();
+    for (name, content) in entries {
+        dir.add(
+            name,
+            SimpleFile::new_regular(fs.clone(), move || Ok(content.clone())),
+        );
+    }
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
+
+fn node_builder(fs: Arc<SimpleFs>) -> DirMaker {
+    let mut root = DirMapping::new();
+    // Every node has CPUs and memory.
+    for name in ["online", "possible", "has_cpu", "has_normal_memory"] {
+        root.add(
+            name,
+            SimpleFile::new_regular(fs.clone(), || Ok(cpu_list(0, numa::num_nodes()))),
+        );
+    }
+    for node in 0..numa::num_nodes() {
+        root.add(format!("node{node}"), node_dir(&fs, node));
+    }
+    SimpleDir::new_maker(fs, Arc::new(root))
+}
+
+pub fn new_nodefs() -> Filesystem {
+    SimpleFs::new_with("sysfs".into(), 0x62656572, node_builder)
+}
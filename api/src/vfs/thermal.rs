@@ -0,0 +1,280 @@
+//! `/sys/class/thermal` and `/sys/class/hwmon`, generated from the thermal
+//! zones and cooling devices registered by the platform drivers.
+
+use alloc::{borrow::Cow, boxed::Box, format, sync::Arc, vec::Vec};
+
+use axfs_ng_vfs::{Filesystem, NodeType, VfsError, VfsResult};
+use starry_core::vfs::{
+    DirMaker, DirMapping, NodeOpsMux, RwFile, SimpleDir, SimpleDirOps, SimpleFile,
+    SimpleFileOperation, SimpleFs,
+};
+
+use crate::thermal::{self, ThermalZone, TripType};
+
+fn parse<T: core::str::FromStr>(data: &[u8]) -> VfsResult<T> {
+    str::from_utf8(data)
+        .ok()
+        .and_then(|it| it.trim().parse().ok())
+        .ok_or(VfsError::InvalidInput)
+}
+
+fn zone_dir(fs: &Arc<SimpleFs>, zone: Arc<ThermalZone>) -> DirMaker {
+    let mut dir = DirMapping::new();
+    let name = format!("{}\n", zone.sensor().name());
+    dir.add(
+        "type",
+        SimpleFile::new_regular(fs.clone(), move || Ok(name.clone())),
+    );
+    let z = zone.clone();
+    dir.add(
+        "temp",
+        SimpleFile::new_regular(fs.clone(), move || {
+            Ok(format!("{}\n", z.sensor().temperature()?))
+        }),
+    );
+    let z = zone.clone();
+    dir.add(
+        "mode",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(move |req| match req {
+                SimpleFileOperation::Read => Ok(Some(if z.enabled() {
+                    "enabled\n"
+                } else {
+                    "disabled\n"
+                })),
+                SimpleFileOperation::Write(data) => {
+                    match str::from_utf8(data).map(str::trim) {
+                        Ok("enabled") => z.set_enabled(true),
+                        Ok("disabled") => z.set_enabled(false),
+                        _ => return Err(VfsError::InvalidInput),
+                    }
+                    Ok(None)
+                }
+            }),
+        ),
+    );
+    let z = zone.clone();
+    dir.add(
+        "policy",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(move |req| match req {
+                SimpleFileOperation::Read => Ok(Some(format!("{}\n", z.policy()))),
+                SimpleFileOperation::Write(data) => {
+                    let policy = str::from_utf8(data).map_err(|_| VfsError::InvalidInput)?;
+                    z.set_policy(policy.trim())?;
+                    Ok(None)
+                }
+            }),
+        ),
+    );
+    dir.add(
+        "available_policies",
+        SimpleFile::new_regular(fs.clone(), || {
+            Ok(format!("{}\n", thermal::POLICIES.join(" ")))
+        }),
+    );
+
+    for (index, trip) in zone.trips().iter().enumerate() {
+        let ty = format!("{}\n", trip.ty().as_str());
+        dir.add(
+            format!("trip_point_{index}_type"),
+            SimpleFile::new_regular(fs.clone(), move || Ok(ty.clone())),
+        );
+        let z = zone.clone();
+        dir.add(
+            format!("trip_point_{index}_temp"),
+            SimpleFile::new_regular(
+                fs.clone(),
+                RwFile::new(move |req| {
+                    let trip = &z.trips()[index];
+                    match req {
+                        SimpleFileOperation::Read => Ok(Some(format!("{}\n", trip.temp()))),
+                        SimpleFileOperation::Write(data) => {
+                            trip.set_temp(parse(data)?);
+                            Ok(None)
+                        }
+                    }
+                }),
+            ),
+        );
+        let z = zone.clone();
+        dir.add(
+            format!("trip_point_{index}_hyst"),
+            SimpleFile::new_regular(
+                fs.clone(),
+                RwFile::new(move |req| {
+                    let trip = &z.trips()[index];
+                    match req {
+                        SimpleFileOperation::Read => Ok(Some(format!("{}\n", trip.hyst()))),
+                        SimpleFileOperation::Write(data) => {
+                            trip.set_hyst(parse(data)?)?;
+                            Ok(None)
+                        }
+                    }
+                }),
+            ),
+        );
+    }
+
+    for (index, binding) in zone.bindings().into_iter().enumerate() {
+        let target = format!("../cooling_device{}", binding.cdev);
+        dir.add(
+            format!("cdev{index}"),
+            SimpleFile::new(fs.clone(), NodeType::Symlink, move || Ok(target.clone())),
+        );
+        dir.add(
+            format!("cdev{index}_trip_point"),
+            SimpleFile::new_regular(fs.clone(), move || Ok(format!("{}\n", binding.trip))),
+        );
+    }
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
+
+fn cooling_device_dir(fs: &Arc<SimpleFs>, id: usize) -> VfsResult<DirMaker> {
+    let device = thermal::cooling_devices()
+        .get(id)
+        .cloned()
+        .ok_or(VfsError::NotFound)?;
+    let mut dir = DirMapping::new();
+    let name = format!("{}\n", device.name());
+    dir.add(
+        "type",
+        SimpleFile::new_regular(fs.clone(), move || Ok(name.clone())),
+    );
+    let max_state = format!("{}\n", device.max_state());
+    dir.add(
+        "max_state",
+        SimpleFile::new_regular(fs.clone(), move || Ok(max_state.clone())),
+    );
+    dir.add(
+        "cur_state",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(move |req| match req {
+                SimpleFileOperation::Read => Ok(Some(format!("{}\n", device.cur_state()))),
+                SimpleFileOperation::Write(data) => {
+                    let state = parse(data)?;
+                    if state > device.max_state() {
+                        return Err(VfsError::InvalidInput);
+                    }
+                    device.set_state(state)?;
+                    Ok(None)
+                }
+            }),
+        ),
+    );
+    Ok(SimpleDir::new_maker(fs.clone(), Arc::new(dir)))
+}
+
+/// `/sys/class/thermal`, listing the devices registered so far.
+struct ThermalDir {
+    fs: Arc<SimpleFs>,
+}
+
+impl SimpleDirOps for ThermalDir {
+    fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let zones = (0..thermal::zones().len()).map(|id| format!("thermal_zone{id}"));
+        let devices = (0..thermal::cooling_devices().len()).map(|id| format!("cooling_device{id}"));
+        Box::new(zones.chain(devices).map(Cow::Owned))
+    }
+
+    fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        let id = |prefix: &str| {
+            name.strip_prefix(prefix)
+                .and_then(|it| it.parse::<usize>().ok())
+        };
+        if let Some(id) = id("thermal_zone") {
+            let zone = thermal::zones()
+                .get(id)
+                .cloned()
+                .ok_or(VfsError::NotFound)?;
+            Ok(zone_dir(&self.fs, zone).into())
+        } else if let Some(id) = id("cooling_device") {
+            Ok(cooling_device_dir(&self.fs, id)?.into())
+        } else {
+            Err(VfsError::NotFound)
+        }
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+}
+
+/// `/sys/class/hwmon`, with one sensor per thermal zone.
+struct HwmonDir {
+    fs: Arc<SimpleFs>,
+}
+
+impl SimpleDirOps for HwmonDir {
+    fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        Box::new((0..thermal::zones().len()).map(|id| Cow::Owned(format!("hwmon{id}"))))
+    }
+
+    fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
+        let zone = name
+            .strip_prefix("hwmon")
+            .and_then(|it| it.parse::<usize>().ok())
+            .and_then(|id| thermal::zones().get(id).cloned())
+            .ok_or(VfsError::NotFound)?;
+        let fs = &self.fs;
+
+        let mut dir = DirMapping::new();
+        // hwmon names can't contain dashes.
+        let name = format!("{}\n", zone.sensor().name().replace('-', "_"));
+        dir.add(
+            "name",
+            SimpleFile::new_regular(fs.clone(), move || Ok(name.clone())),
+        );
+        let label = format!("{}\n", zone.sensor().name());
+        dir.add(
+            "temp1_label",
+            SimpleFile::new_regular(fs.clone(), move || Ok(label.clone())),
+        );
+        let sensor = zone.sensor().clone();
+        dir.add(
+            "temp1_input",
+            SimpleFile::new_regular(fs.clone(), move || {
+                Ok(format!("{}\n", sensor.temperature()?))
+            }),
+        );
+        let limits: Vec<(&str, usize)> = zone
+            .trips()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, trip)| match trip.ty() {
+                TripType::Critical => Some(("temp1_crit", index)),
+                TripType::Hot => Some(("temp1_max", index)),
+                _ => None,
+            })
+            .collect();
+        for (file, index) in limits {
+            let z = zone.clone();
+            dir.add(
+                file,
+                SimpleFile::new_regular(fs.clone(), move || {
+                    Ok(format!("{}\n", z.trips()[index].temp()))
+                }),
+            );
+        }
+        Ok(SimpleDir::new_maker(fs.clone(), Arc::new(dir)).into())
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+}
+
+pub fn new_thermalfs() -> Filesystem {
+    SimpleFs::new_with("sysfs".into(), 0x62656572, |fs| {
+        SimpleDir::new_maker(fs.clone(), Arc::new(ThermalDir { fs }))
+    })
+}
+
+pub fn new_hwmonfs() -> Filesystem {
+    SimpleFs::new_with("sysfs".into(), 0x62656572, |fs| {
+        SimpleDir::new_maker(fs.clone(), Arc::new(HwmonDir { fs }))
+    })
+}
@@ -1,5 +1,12 @@
-use alloc::{borrow::ToOwned, string::String, sync::Arc};
-use core::{any::Any, borrow::Borrow, cmp::Ordering, task::Context, time::Duration};
+use alloc::{borrow::ToOwned, collections::BTreeMap, string::String, sync::Arc};
+use core::{
+    any::Any,
+    borrow::Borrow,
+    cmp::Ordering,
+    sync::atomic::{self, AtomicU32},
+    task::Context,
+    time::Duration,
+};
 
 use axfs_ng_vfs::{
     DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, Filesystem,
@@ -8,9 +15,14 @@ use axfs_ng_vfs::{
 };
 use axpoll::{IoEvents, Pollable};
 use axsync::Mutex;
+use axtask::current;
 use hashbrown::HashMap;
+use memory_addr::PAGE_SIZE_4K;
 use slab::Slab;
-use starry_core::vfs::dummy_stat_fs;
+use starry_core::{
+    task::AsThread,
+    vfs::{DiskQuota, dummy_stat_fs},
+};
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct FileName(String);
@@ -53,6 +65,9 @@ impl Borrow<str> for FileName {
 pub struct MemoryFs {
     inodes: Mutex<Slab<Arc<Inode>>>,
     root: Mutex<Option<DirEntry>>,
+    /// Bumped for every new inode, since inode numbers get reused.
+    generation: AtomicU32,
+    quota: Arc<DiskQuota>,
 }
 
 impl MemoryFs {
@@ -62,13 +77,17 @@ impl MemoryFs {
         let fs = Arc::new(Self {
             inodes: Mutex::new(Slab::new()),
             root: Mutex::default(),
+            generation: AtomicU32::new(0),
+            quota: Arc::new(DiskQuota::new()),
         });
         let root_ino = Inode::new(
             &fs,
             None,
             NodeType::Directory,
             NodePermission::from_bits_truncate(0o755),
-        );
+            (0, 0),
+        )
+        .expect("no quota limits on a new filesystem");
         *fs.root.lock() = Some(DirEntry::new_dir(
             |this| DirNode::new(MemoryNode::new(fs.clone(), root_ino, Some(this))),
             Reference::root(),
@@ -95,12 +114,53 @@ impl FilesystemOps for MemoryFs {
     }
 }
 
+/// Returns the generation of the inode behind `entry`, which tells apart
+/// files that reused the inode number of a deleted one.
+///
+/// Entries from other filesystems are in generation 0.
+pub fn inode_generation(entry: &DirEntry) -> u32 {
+    entry
+        .downcast::<MemoryNode>()
+        .map_or(0, |node| node.inode.generation)
+}
+
+/// Returns the quotas of the filesystem behind `entry`, if it is a memory
+/// filesystem.
+pub fn disk_quota(entry: &DirEntry) -> Option<Arc<DiskQuota>> {
+    entry
+        .downcast::<MemoryNode>()
+        .ok()
+        .map(|node| node.fs.quota.clone())
+}
+
+/// Sets the device number of a device node created on a memory filesystem.
+///
+/// Returns `false` if `entry` is not on a memory filesystem.
+pub fn set_device_id(entry: &DirEntry, rdev: DeviceId) -> bool {
+    let Ok(node) = entry.downcast::<MemoryNode>() else {
+        return false;
+    };
+    node.inode.metadata.lock().rdev = rdev;
+    true
+}
+
+/// Returns the space charged to the quota of a file of `len` bytes: the
+/// pages holding its contents.
+fn quota_space(len: u64) -> i64 {
+    len.next_multiple_of(PAGE_SIZE_4K as u64) as i64
+}
+
 fn release_inode(fs: &MemoryFs, inode: &Arc<Inode>, nlink: u64) {
     let mut inodes = fs.inodes.lock();
     let mut metadata = inode.metadata.lock();
     metadata.nlink -= nlink;
     if metadata.nlink == 0 && Arc::strong_count(inode) == 2 {
         inodes.remove(metadata.inode as usize - 1);
+        let space = inode.as_file().map_or(0, |file| *file.length.lock());
+        // Credits never fail.
+        let _ = fs
+            .quota
+            .charge((metadata.uid, metadata.gid), -quota_space(space), -1);
     }
 }
 
@@ -114,9 +174,64 @@ struct FileContent {
     symlink: Mutex<Option<String>>,
 }
 
+/// The entries of a directory.
+///
+/// Each entry gets a cookie, increasing in the order the entries were added,
+/// which `read_dir` uses as offsets: listing from an offset resumes after the
+/// same entry however the directory has changed since.
+#[derive(Default)]
+struct DirEntries {
+    by_name: HashMap<FileName, (u64, InodeRef)>,
+    by_cookie: BTreeMap<u64, FileName>,
+    next_cookie: u64,
+}
+
+impl DirEntries {
+    fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    fn contains_key(&self, name: &str) -> bool {
+        self.by_name.contains_key(name)
+    }
+
+    fn get(&self, name: &str) -> Option<&InodeRef> {
+        self.by_name.get(name).map(|(_, inode)| inode)
+    }
+
+    fn insert(&mut self, name: FileName, inode: InodeRef) -> Option<InodeRef> {
+        let cookie = self.next_cookie;
+        self.next_cookie += 1;
+        self.by_cookie.insert(cookie, name.clone());
+        let (old_cookie, old) = self.by_name.insert(name, (cookie, inode))?;
+        self.by_cookie.remove(&old_cookie);
+        Some(old)
+    }
+
+    fn remove(&mut self, name: &str) -> Option<InodeRef> {
+        let (cookie, inode) = self.by_name.remove(name)?;
+        self.by_cookie.remove(&cookie);
+        Some(inode)
+    }
+
+    fn clear(&mut self) {
+        self.by_cookie.clear();
+        self.by_name.clear();
+    }
+
+    /// Iterates over the entries from the one with `cookie` on, along with
+    /// the offset following each.
+    fn iter_from(&self, cookie: u64) -> impl Iterator<Item = (&FileName, &InodeRef, u64)> {
+        self.by_cookie.range(cookie..).map(|(&cookie, name)| {
+            let (_, inode) = &self.by_name[name];
+            (name, inode, cookie + 1)
+        })
+    }
+}
+
 #[derive(Default)]
 struct DirContent {
-    entries: Mutex<HashMap<FileName, InodeRef>>,
+    entries: Mutex<DirEntries>,
 }
 
 enum NodeContent {
@@ -126,6 +241,7 @@ enum NodeContent {
 
 struct Inode {
     ino: u64,
+    generation: u32,
     metadata: Mutex<Metadata>,
     content: NodeContent,
 }
@@ -136,7 +252,9 @@ impl Inode {
         parent: Option<u64>,
         node_type: NodeType,
         permission: NodePermission,
-    ) -> Arc<Inode> {
+        (uid, gid): (u32, u32),
+    ) -> VfsResult<Arc<Inode>> {
+        fs.quota.charge((uid, gid), 0, 1)?;
         let mut inodes = fs.inodes.lock();
         let entry = inodes.vacant_entry();
         let ino = entry.key() as u64 + 1;
@@ -146,8 +264,8 @@ impl Inode {
             nlink: 0,
             mode: permission,
             node_type,
-            uid: 0,
-            gid: 0,
+            uid,
+            gid,
             size: 0,
             block_size: 0,
             blocks: 0,
@@ -162,6 +280,7 @@ impl Inode {
         };
         let result = Arc::new(Self {
             ino,
+            generation: fs.generation.fetch_add(1, atomic::Ordering::Relaxed),
             metadata: Mutex::new(metadata),
             content,
         });
@@ -175,7 +294,7 @@ impl Inode {
                 InodeRef::new(fs.clone(), parent.unwrap_or(ino)),
             );
         }
-        result
+        Ok(result)
     }
 
     fn as_file(&self) -> VfsResult<&FileContent> {
@@ -226,6 +345,37 @@ impl MemoryNode {
         Arc::new(Self { fs, inode, this })
     }
 
+    /// Returns the owner of a new inode in this directory: the filesystem
+    /// ids of the creator, with the group of the directory instead if it is
+    /// set-group-id.
+    fn new_owner(&self) -> (u32, u32) {
+        let (uid, gid) = current().try_as_thread().map_or((0, 0), |thr| {
+            let cred = thr.proc_data.cred.read();
+            (cred.fsuid, cred.fsgid)
+        });
+        let metadata = self.inode.metadata.lock();
+        if metadata.mode.contains(NodePermission::SET_GID) {
+            (uid, metadata.gid)
+        } else {
+            (uid, gid)
+        }
+    }
+
+    /// Sets the length of a file, charging the quota of its owner for the
+    /// difference.
+    fn resize(&self, file: &FileContent, len: u64) -> VfsResult<()> {
+        let owner = {
+            let metadata = self.inode.metadata.lock();
+            (metadata.uid, metadata.gid)
+        };
+        let mut length = file.length.lock();
+        self.fs
+            .quota
+            .charge(owner, quota_space(len) - quota_space(*length), 0)?;
+        *length = len;
+        Ok(())
+    }
+
     fn new_entry(&self, name: &str, node_type: NodeType, inode: Arc<Inode>) -> VfsResult<DirEntry> {
         let fs = self.fs.clone();
         let reference = Reference::new(
@@ -271,6 +421,12 @@ impl NodeOps for MemoryNode {
             metadata.mode = mode;
         }
         if let Some((uid, gid)) = update.owner {
+            let space = self.inode.as_file().map_or(0, |file| *file.length.lock());
+            self.fs.quota.transfer(
+                (metadata.uid, metadata.gid),
+                (uid, gid),
+                quota_space(space) as u64,
+            )?;
             metadata.uid = uid;
             metadata.gid = gid;
         }
@@ -321,13 +477,12 @@ impl FileNodeOps for MemoryNode {
     }
 
     fn set_len(&self, len: u64) -> VfsResult<()> {
-        *self.inode.as_file()?.length.lock() = len;
-        Ok(())
+        self.resize(self.inode.as_file()?, len)
     }
 
     fn set_symlink(&self, target: &str) -> VfsResult<()> {
         let file = self.inode.as_file()?;
-        *file.length.lock() = target.len() as u64;
+        self.resize(file, target.len() as u64)?;
         *file.symlink.lock() = Some(target.to_owned());
         Ok(())
     }
@@ -343,20 +498,13 @@ impl Pollable for MemoryNode {
 impl DirNodeOps for MemoryNode {
     fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
         let mut count = 0;
-        for (i, (name, entry)) in self
-            .inode
-            .as_dir()?
-            .entries
-            .lock()
-            .iter()
-            .enumerate()
-            .skip(offset as usize)
-        {
+        let entries = self.inode.as_dir()?.entries.lock();
+        for (name, entry, next) in entries.iter_from(offset) {
             if !sink.accept(
                 &name.0,
                 entry.ino,
                 entry.get().metadata.lock().node_type,
-                i as u64 + 1,
+                next,
             ) {
                 return Ok(count);
             }
@@ -387,7 +535,13 @@ impl DirNodeOps for MemoryNode {
         if entries.contains_key(name) {
             return Err(VfsError::AlreadyExists);
         }
-        let inode = Inode::new(&self.fs, Some(self.inode.ino), node_type, permission);
+        let inode = Inode::new(
+            &self.fs,
+            Some(self.inode.ino),
+            node_type,
+            permission,
+            self.new_owner(),
+        )?;
         entries.insert(name.into(), InodeRef::new(self.fs.clone(), inode.ino));
         self.new_entry(name, node_type, inode)
     }
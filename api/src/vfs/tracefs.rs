@@ -0,0 +1,217 @@
+//! `/sys/kernel/tracing`, the control and output files of the static
+//! tracepoints.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{any::Any, task::Context};
+
+use axfs_ng_vfs::{DeviceId, Filesystem, NodeFlags, NodeType, VfsError, VfsResult};
+use axpoll::{IoEvents, Pollable};
+use axsync::Mutex;
+use starry_core::{
+    trace::{self, Tracepoint},
+    vfs::{
+        Device, DeviceOps, DirMaker, DirMapping, RwFile, SimpleDir, SimpleFile,
+        SimpleFileOperation, SimpleFs,
+    },
+};
+
+fn parse_bool(data: &[u8]) -> VfsResult<bool> {
+    match str::from_utf8(data).map(str::trim) {
+        Ok("0") => Ok(false),
+        Ok("1") => Ok(true),
+        _ => Err(VfsError::InvalidInput),
+    }
+}
+
+/// An `enable` file controlling `tracepoints` together, reading `X` when
+/// they disagree.
+fn enable_file(fs: &Arc<SimpleFs>, tracepoints: Vec<Tracepoint>) -> Arc<SimpleFile> {
+    SimpleFile::new_regular(
+        fs.clone(),
+        RwFile::new(move |req| match req {
+            SimpleFileOperation::Read => {
+                let enabled = tracepoints.iter().filter(|it| it.enabled()).count();
+                Ok(Some(if enabled == 0 {
+                    "0\n"
+                } else if enabled == tracepoints.len() {
+                    "1\n"
+                } else {
+                    "X\n"
+                }))
+            }
+            SimpleFileOperation::Write(data) => {
+                let enabled = parse_bool(data)?;
+                for tp in &tracepoints {
+                    tp.set_enabled(enabled);
+                }
+                Ok(None)
+            }
+        }),
+    )
+}
+
+fn events_dir(fs: &Arc<SimpleFs>) -> DirMaker {
+    let mut systems: Vec<&str> = Tracepoint::ALL.iter().map(|it| it.system()).collect();
+    systems.dedup();
+
+    let mut root = DirMapping::new();
+    for system in systems {
+        let tracepoints: Vec<_> = Tracepoint::ALL
+            .into_iter()
+            .filter(|it| it.system() == system)
+            .collect();
+        let mut dir = DirMapping::new();
+        for &tp in &tracepoints {
+            let mut event = DirMapping::new();
+            event.add("enable", enable_file(fs, alloc::vec![tp]));
+            dir.add(tp.name(), SimpleDir::new_maker(fs.clone(), Arc::new(event)));
+        }
+        dir.add("enable", enable_file(fs, tracepoints));
+        root.add(system, SimpleDir::new_maker(fs.clone(), Arc::new(dir)));
+    }
+    root.add("enable", enable_file(fs, Tracepoint::ALL.to_vec()));
+    SimpleDir::new_maker(fs.clone(), Arc::new(root))
+}
+
+/// `trace_pipe`, which consumes the entries as they are read and blocks while
+/// there are none.
+struct TracePipe {
+    /// The rest of an entry that didn't fit in the last read.
+    pending: Mutex<Vec<u8>>,
+}
+
+impl DeviceOps for TracePipe {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        let mut pending = self.pending.lock();
+        let mut read = 0;
+        while read < buf.len() {
+            if pending.is_empty() {
+                let Some(entry) = trace::consume() else {
+                    break;
+                };
+                *pending = entry.to_string().into_bytes();
+            }
+            let len = pending.len().min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&pending[..len]);
+            pending.drain(..len);
+            read += len;
+        }
+        if read == 0 && !buf.is_empty() {
+            return Err(VfsError::WouldBlock);
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_pollable(&self) -> Option<&dyn Pollable> {
+        Some(self)
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE | NodeFlags::STREAM
+    }
+}
+
+impl Pollable for TracePipe {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(
+            IoEvents::IN,
+            trace::entries() > 0 || !self.pending.lock().is_empty(),
+        );
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            trace::poll_set().register(context.waker());
+        }
+    }
+}
+
+fn builder(fs: Arc<SimpleFs>) -> DirMaker {
+    let mut root = DirMapping::new();
+    root.add(
+        "tracing_on",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(|req| match req {
+                SimpleFileOperation::Read => {
+                    Ok(Some(if trace::tracing_on() { "1\n" } else { "0\n" }))
+                }
+                SimpleFileOperation::Write(data) => {
+                    trace::set_tracing_on(parse_bool(data)?);
+                    Ok(None)
+                }
+            }),
+        ),
+    );
+    // Any write, including truncating on open, clears the buffers.
+    root.add(
+        "trace",
+        SimpleFile::new_regular(
+            fs.clone(),
+            RwFile::new(|req| match req {
+                SimpleFileOperation::Read => {
+                    let mut out = trace::header();
+                    for entry in trace::snapshot() {
+                        out.push_str(&entry.to_string());
+                    }
+                    Ok(Some(out))
+                }
+                SimpleFileOperation::Write(_) => {
+                    trace::clear();
+                    Ok(None)
+                }
+            }),
+        ),
+    );
+    root.add(
+        "trace_pipe",
+        Device::new(
+            fs.clone(),
+            NodeType::RegularFile,
+            DeviceId::default(),
+            Arc::new(TracePipe {
+                pending: Mutex::new(Vec::new()),
+            }),
+        ),
+    );
+    root.add(
+        "available_events",
+        SimpleFile::new_regular(fs.clone(), || {
+            Ok(Tracepoint::ALL
+                .iter()
+                .map(|it| format!("{}:{}\n", it.system(), it.name()))
+                .collect::<String>())
+        }),
+    );
+    root.add(
+        "per_cpu_overrun",
+        SimpleFile::new_regular(fs.clone(), || {
+            Ok(trace::overruns()
+                .iter()
+                .enumerate()
+                .map(|(cpu, overrun)| format!("cpu{cpu}: {overrun}\n"))
+                .collect::<String>())
+        }),
+    );
+    root.add("events", events_dir(&fs));
+    SimpleDir::new_maker(fs, Arc::new(root))
+}
+
+pub fn new_tracefs() -> Filesystem {
+    SimpleFs::new_with("tracefs".into(), 0x74726163, builder)
+}
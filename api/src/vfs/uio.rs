@@ -0,0 +1,69 @@
+//! `/sys/class/uio`, describing the UIO devices and the regions to map
+//! from each.
+
+use alloc::{format, sync::Arc};
+
+use axfs_ng_vfs::Filesystem;
+use starry_core::vfs::{DirMaker, DirMapping, SimpleDir, SimpleFile, SimpleFs};
+
+use super::dev::uio::{self, UioDevice};
+
+fn device_dir(fs: &Arc<SimpleFs>, dev: Arc<UioDevice>) -> DirMaker {
+    let mut maps = DirMapping::new();
+    for (index, map) in dev.maps().iter().enumerate() {
+        let mut dir = DirMapping::new();
+        let entries = [
+            ("name", format!("{}\n", map.name)),
+            ("addr", format!("0x{:016x}\n", map.addr)),
+            ("size", format!("0x{:016x}\n", map.size)),
+            // Where the region starts in the first page mapped.
+            ("offset", format!("0x{:x}\n", map.addr & 0xfff)),
+        ];
+        for (name, content) in entries {
+            dir.add(
+                name,
+                SimpleFile::new_regular(fs.clone(), move || Ok(content.clone())),
+            );
+        }
+        maps.add(
+            format!("map{index}"),
+            SimpleDir::new_maker(fs.clone(), Arc::new(dir)),
+        );
+    }
+
+    let mut dir = DirMapping::new();
+    let name = format!("{}\n", dev.driver_name());
+    dir.add(
+        "name",
+        SimpleFile::new_regular(fs.clone(), move || Ok(name.clone())),
+    );
+    dir.add(
+        "version",
+        SimpleFile::new_regular(fs.clone(), || Ok("0.0.1\n")),
+    );
+    let device_id = dev.device_id();
+    dir.add(
+        "dev",
+        SimpleFile::new_regular(fs.clone(), move || {
+            Ok(format!("{}:{}\n", device_id.major(), device_id.minor()))
+        }),
+    );
+    dir.add(
+        "event",
+        SimpleFile::new_regular(fs.clone(), move || Ok(format!("{}\n", dev.event_count()))),
+    );
+    dir.add("maps", SimpleDir::new_maker(fs.clone(), Arc::new(maps)));
+    SimpleDir::new_maker(fs.clone(), Arc::new(dir))
+}
+
+fn builder(fs: Arc<SimpleFs>) -> DirMaker {
+    let mut root = DirMapping::new();
+    for dev in uio::devices() {
+        root.add(dev.name(), device_dir(&fs, dev));
+    }
+    SimpleDir::new_maker(fs, Arc::new(root))
+}
+
+pub fn new_uiofs() -> Filesystem {
+    SimpleFs::new_with("sysfs".into(), 0x62656572, builder)
+}
@@ -0,0 +1,443 @@
+//! Information passed by the bootloader.
+//!
+//! The platform hands over a single boot argument: the physical address of
+//! the flattened device tree on most platforms, or of the multiboot
+//! information on x86.
+
+use alloc::{string::String, vec::Vec};
+
+use spin::Once;
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn c_str(data: &[u8]) -> Option<&str> {
+    let len = data.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&data[..len]).ok()
+}
+
+/// Returns the memory at physical address `addr`.
+///
+/// # Safety
+///
+/// The memory must be mapped and remain untouched for `'static`.
+unsafe fn phys_slice(addr: usize, len: usize) -> &'static [u8] {
+    let ptr = axhal::mem::phys_to_virt(addr.into()).as_ptr();
+    unsafe { core::slice::from_raw_parts(ptr, len) }
+}
+
+/// A node of the device tree, with its properties and subnodes.
+#[derive(Default)]
+pub struct FdtNode {
+    /// The name of the node, with its unit address, or empty for the root.
+    pub name: String,
+    /// The raw values of the properties, by name.
+    pub properties: Vec<(String, Vec<u8>)>,
+    /// The subnodes.
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    /// Returns the raw value of property `name`.
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns property `name` holding a single cell.
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        self.property(name)
+            .filter(|value| value.len() == 4)
+            .and_then(|value| be32(value, 0))
+    }
+
+    /// Returns the cells of property `name`.
+    pub fn property_cells(&self, name: &str) -> impl Iterator<Item = u32> {
+        self.property(name)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|cell| be32(cell, 0).unwrap())
+    }
+
+    /// Returns the strings of the string-list property `name`, such as
+    /// `compatible`.
+    pub fn strings(&self, name: &str) -> impl Iterator<Item = &str> {
+        let value = self.property(name).unwrap_or_default();
+        let value = value.strip_suffix(&[0]).unwrap_or(value);
+        (!value.is_empty())
+            .then_some(value)
+            .into_iter()
+            .flat_map(|value| value.split(|&b| b == 0))
+            .map(|s| core::str::from_utf8(s).unwrap_or_default())
+    }
+
+    /// Returns whether the node is compatible with one of `compatible`.
+    pub fn is_compatible(&self, compatible: &[&str]) -> bool {
+        self.strings("compatible")
+            .any(|it| compatible.contains(&it))
+    }
+
+    /// Returns whether the device the node describes is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.property("status")
+            .and_then(c_str)
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    /// Finds the node in this subtree that `phandle` refers to.
+    pub fn find_phandle(&self, phandle: u32) -> Option<&FdtNode> {
+        let own = self
+            .property_u32("phandle")
+            .or_else(|| self.property_u32("linux,phandle"));
+        if own == Some(phandle) {
+            return Some(self);
+        }
+        self.children
+            .iter()
+            .find_map(|child| child.find_phandle(phandle))
+    }
+}
+
+static DEVICE_TREE: Once<Option<FdtNode>> = Once::new();
+
+/// Reads a big-endian number of any number of cells, keeping the low 64 bits.
+fn read_cells(value: &[u8]) -> u64 {
+    value
+        .chunks_exact(4)
+        .fold(0, |acc, cell| acc << 32 | be32(cell, 0).unwrap() as u64)
+}
+
+/// A device described by the device tree, as found by
+/// [`find_compatible`].
+pub struct FdtDevice {
+    /// The node describing the device.
+    pub node: &'static FdtNode,
+    /// `#address-cells` and `#size-cells` of the parent bus.
+    address_cells: usize,
+    size_cells: usize,
+    /// The `interrupt-parent` of the node, which is inherited.
+    interrupt_parent: Option<u32>,
+}
+
+impl FdtDevice {
+    /// Returns the `(address, size)` pairs of `reg`.
+    ///
+    /// The addresses are those of the parent bus, which are the physical
+    /// addresses for devices that aren't behind a translating bus.
+    pub fn regs(&self) -> Vec<(u64, u64)> {
+        let entry = (self.address_cells + self.size_cells) * 4;
+        if entry == 0 {
+            return Vec::new();
+        }
+        self.node
+            .property("reg")
+            .unwrap_or_default()
+            .chunks_exact(entry)
+            .map(|reg| {
+                let (address, size) = reg.split_at(self.address_cells * 4);
+                (read_cells(address), read_cells(size))
+            })
+            .collect()
+    }
+
+    /// Returns interrupt `index` of `interrupts`, numbered as axhal numbers
+    /// interrupts: GIC interrupt IDs for the three-cell Arm binding, and
+    /// the first cell, the line of the controller, otherwise.
+    pub fn irq(&self, index: usize) -> Option<usize> {
+        let controller = device_tree()?.find_phandle(self.interrupt_parent?)?;
+        let cells = controller.property_u32("#interrupt-cells")? as usize;
+        let spec = self
+            .node
+            .property("interrupts")?
+            .chunks_exact(cells * 4)
+            .nth(index)?;
+        let cell = |i| be32(spec, i * 4).map(|it| it as usize);
+        let is_gic = controller
+            .strings("compatible")
+            .any(|it| it.starts_with("arm,") && it.contains("gic"));
+        if is_gic && cells >= 3 {
+            const GIC_SPI: usize = 0;
+            const GIC_PPI: usize = 1;
+            match cell(0)? {
+                GIC_SPI => Some(cell(1)? + 32),
+                GIC_PPI => Some(cell(1)? + 16),
+                _ => None,
+            }
+        } else {
+            cell(0)
+        }
+    }
+}
+
+fn find_in(
+    node: &'static FdtNode,
+    cells: (usize, usize),
+    interrupt_parent: Option<u32>,
+    compatible: &[&str],
+    found: &mut Vec<FdtDevice>,
+) {
+    let interrupt_parent = node.property_u32("interrupt-parent").or(interrupt_parent);
+    if node.is_compatible(compatible) && node.is_enabled() {
+        found.push(FdtDevice {
+            node,
+            address_cells: cells.0,
+            size_cells: cells.1,
+            interrupt_parent,
+        });
+    }
+    // The defaults the devicetree specification gives.
+    let child_cells = (
+        node.property_u32("#address-cells").unwrap_or(2) as usize,
+        node.property_u32("#size-cells").unwrap_or(1) as usize,
+    );
+    for child in &node.children {
+        find_in(child, child_cells, interrupt_parent, compatible, found);
+    }
+}
+
+/// Returns the enabled devices compatible with one of `compatible`, in the
+/// order of the device tree.
+pub fn find_compatible(compatible: &[&str]) -> Vec<FdtDevice> {
+    let mut found = Vec::new();
+    if let Some(root) = device_tree() {
+        find_in(root, (2, 1), None, compatible, &mut found);
+    }
+    found
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod fdt {
+    use alloc::vec::Vec;
+
+    use super::{FdtNode, be32, c_str, phys_slice};
+
+    const FDT_MAGIC: u32 = 0xd00d_feed;
+    const FDT_BEGIN_NODE: u32 = 1;
+    const FDT_END_NODE: u32 = 2;
+    const FDT_PROP: u32 = 3;
+    const FDT_NOP: u32 = 4;
+
+    /// Returns the device tree passed by the bootloader.
+    pub fn get() -> Option<&'static [u8]> {
+        let arg = axhal::dtb::get_bootarg();
+        if arg == 0 {
+            return None;
+        }
+        let header = unsafe { phys_slice(arg, 8) };
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            warn!("boot: boot argument {arg:#x} is not a device tree");
+            return None;
+        }
+        Some(unsafe { phys_slice(arg, be32(header, 4)? as usize) })
+    }
+
+    /// Finds property `prop` of the top-level node `node`.
+    pub fn property(fdt: &'static [u8], node: &str, prop: &str) -> Option<&'static [u8]> {
+        let structs = fdt.get(be32(fdt, 8)? as usize..)?;
+        let strings = fdt.get(be32(fdt, 12)? as usize..)?;
+
+        let mut offset = 0;
+        let mut depth = 0;
+        let mut in_node = false;
+        loop {
+            let token = be32(structs, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(structs.get(offset..)?)?;
+                    offset += (name.len() + 4) & !3;
+                    depth += 1;
+                    in_node = depth == 2
+                        && name
+                            .strip_prefix(node)
+                            .is_some_and(|rest| rest.is_empty() || rest.starts_with('@'));
+                }
+                FDT_END_NODE => {
+                    if in_node {
+                        return None;
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = be32(structs, offset)? as usize;
+                    let name = c_str(strings.get(be32(structs, offset + 4)? as usize..)?)?;
+                    let value = structs.get(offset + 8..offset + 8 + len)?;
+                    offset += (8 + len + 3) & !3;
+                    if in_node && name == prop {
+                        return Some(value);
+                    }
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+
+    /// Parses the whole tree.
+    pub fn parse(fdt: &[u8]) -> Option<FdtNode> {
+        let structs = fdt.get(be32(fdt, 8)? as usize..)?;
+        let strings = fdt.get(be32(fdt, 12)? as usize..)?;
+
+        let mut offset = 0;
+        let mut nodes: Vec<FdtNode> = Vec::new();
+        loop {
+            let token = be32(structs, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(structs.get(offset..)?)?;
+                    offset += (name.len() + 4) & !3;
+                    nodes.push(FdtNode {
+                        name: name.into(),
+                        ..Default::default()
+                    });
+                }
+                FDT_END_NODE => {
+                    let node = nodes.pop()?;
+                    match nodes.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => return Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    let len = be32(structs, offset)? as usize;
+                    let name = c_str(strings.get(be32(structs, offset + 4)? as usize..)?)?;
+                    let value = structs.get(offset + 8..offset + 8 + len)?;
+                    offset += (8 + len + 3) & !3;
+                    nodes
+                        .last_mut()?
+                        .properties
+                        .push((name.into(), value.into()));
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+
+    /// Reads a big-endian cell property of 1 or 2 cells.
+    pub fn read_cells(value: &[u8]) -> Option<usize> {
+        match value.len() {
+            4 => Some(be32(value, 0)? as usize),
+            8 => Some(((be32(value, 0)? as u64) << 32 | be32(value, 4)? as u64) as usize),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod multiboot {
+    use super::phys_slice;
+
+    /// The `cmdline` field is valid.
+    pub const INFO_CMDLINE: u32 = 1 << 2;
+    /// The `mods_count` and `mods_addr` fields are valid.
+    pub const INFO_MODS: u32 = 1 << 3;
+
+    fn le32(data: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    /// Returns the multiboot information, if it has the fields in `flags`.
+    pub fn info(flags: u32) -> Option<&'static [u8]> {
+        let arg = axhal::dtb::get_bootarg();
+        if arg == 0 {
+            return None;
+        }
+        let info = unsafe { phys_slice(arg, 28) };
+        (le32(info, 0) as u32 & flags == flags).then_some(info)
+    }
+
+    pub fn cmdline(info: &[u8]) -> usize {
+        le32(info, 16)
+    }
+
+    /// Returns the start and end of the first module.
+    pub fn first_module(info: &[u8]) -> Option<(usize, usize)> {
+        if le32(info, 20) == 0 {
+            return None;
+        }
+        let module = unsafe { phys_slice(le32(info, 24), 16) };
+        Some((le32(module, 0), le32(module, 4)))
+    }
+}
+
+/// Returns property `prop` of the top-level node `node` of the device tree,
+/// such as `method` of `psci`.
+#[cfg(not(target_arch = "x86_64"))]
+pub fn fdt_property(node: &str, prop: &str) -> Option<&'static [u8]> {
+    fdt::property(fdt::get()?, node, prop)
+}
+
+/// Returns the device tree passed by the bootloader, as shown in
+/// `/proc/device-tree`.
+///
+/// The tree is copied out the first time, so this should first be called
+/// during boot, before the memory the bootloader left it in may be reused.
+pub fn device_tree() -> Option<&'static FdtNode> {
+    DEVICE_TREE
+        .call_once(|| {
+            #[cfg(target_arch = "x86_64")]
+            {
+                None
+            }
+
+            #[cfg(not(target_arch = "x86_64"))]
+            {
+                let tree = fdt::parse(fdt::get()?);
+                if tree.is_none() {
+                    warn!("boot: malformed device tree");
+                }
+                tree
+            }
+        })
+        .as_ref()
+}
+
+/// Returns the kernel command line passed by the bootloader.
+///
+/// It is read from `/chosen/bootargs` of the device tree, or from the
+/// multiboot information on x86.
+pub fn bootargs() -> Option<String> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let info = multiboot::info(multiboot::INFO_CMDLINE)?;
+        let cmdline = unsafe { phys_slice(multiboot::cmdline(info), 4096) };
+        c_str(cmdline).map(String::from)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        c_str(fdt::property(fdt::get()?, "chosen", "bootargs")?).map(String::from)
+    }
+}
+
+/// Returns the initial ramdisk loaded by the bootloader.
+///
+/// It is given by `/chosen/linux,initrd-start` and `linux,initrd-end` in
+/// the device tree, or is the first module on x86.
+///
+/// The memory is not reserved from the allocator on all platforms, so it
+/// must be consumed early during boot.
+pub fn initrd() -> Option<&'static [u8]> {
+    #[cfg(target_arch = "x86_64")]
+    let (start, end) = multiboot::first_module(multiboot::info(multiboot::INFO_MODS)?)?;
+
+    #[cfg(not(target_arch = "x86_64"))]
+    let (start, end) = {
+        let fdt = fdt::get()?;
+        (
+            fdt::read_cells(fdt::property(fdt, "chosen", "linux,initrd-start")?)?,
+            fdt::read_cells(fdt::property(fdt, "chosen", "linux,initrd-end")?)?,
+        )
+    };
+
+    if end <= start {
+        return None;
+    }
+    Some(unsafe { phys_slice(start, end - start) })
+}
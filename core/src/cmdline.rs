@@ -0,0 +1,144 @@
+//! The kernel command line.
+//!
+//! The command line is passed by the bootloader, see [`boot::bootargs`].
+//! Kernels built for a board without a bootloader that sets it can bake one
+//! in with the `STARRY_CMDLINE` environment variable at build time.
+//!
+//! Parameters are whitespace separated `key=value` pairs or bare flags.
+//! Values can be double-quoted to contain spaces, and everything after `--`
+//! is passed to the init process as arguments. Parameters named like
+//! environment variables are passed to it in its environment.
+
+use alloc::{string::String, vec::Vec};
+
+use spin::Once;
+
+use crate::boot;
+
+struct Cmdline {
+    raw: String,
+    params: Vec<(String, Option<String>)>,
+    init_args: Vec<String>,
+}
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+/// Splits the command line into words, removing quotes.
+fn split(raw: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in raw.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(core::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+fn parse(raw: String) -> Cmdline {
+    let mut params = Vec::new();
+    let mut init_args = Vec::new();
+    let mut words = split(&raw).into_iter();
+    for word in words.by_ref() {
+        if word == "--" {
+            init_args.extend(words.by_ref());
+            break;
+        }
+        match word.split_once('=') {
+            Some((key, value)) => params.push((key.into(), Some(value.into()))),
+            None => params.push((word, None)),
+        }
+    }
+    Cmdline {
+        raw,
+        params,
+        init_args,
+    }
+}
+
+/// Reads and parses the kernel command line.
+///
+/// Must be called once during boot, before any of the other functions.
+pub fn init() {
+    CMDLINE.call_once(|| {
+        let raw = boot::bootargs()
+            .filter(|it| !it.trim().is_empty())
+            .or_else(|| option_env!("STARRY_CMDLINE").map(String::from))
+            .unwrap_or_default();
+        info!("Kernel command line: {}", raw);
+        parse(raw)
+    });
+}
+
+fn cmdline() -> &'static Cmdline {
+    CMDLINE.get().expect("kernel command line not initialized")
+}
+
+/// Returns the command line as passed by the bootloader.
+pub fn raw() -> &'static str {
+    &cmdline().raw
+}
+
+/// Returns the value of parameter `key`.
+///
+/// If the parameter is given more than once, the last value wins, as in
+/// Linux. Flags without a value are returned as an empty string.
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline()
+        .params
+        .iter()
+        .rev()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_deref().unwrap_or(""))
+}
+
+/// Returns the arguments for the init process, given after `--`.
+pub fn init_args() -> &'static [String] {
+    &cmdline().init_args
+}
+
+/// Returns the parameters meant for the environment of the init process, as
+/// `(key, value)` pairs.
+///
+/// Linux passes every parameter it doesn't know on to init. Here they are
+/// the `KEY=value` pairs whose key is made of uppercase letters, digits and
+/// underscores, as in `LD_LIBRARY_PATH=/usr/lib` or `LANG=C.UTF-8`.
+pub fn init_envs() -> impl Iterator<Item = (&'static str, &'static str)> {
+    cmdline().params.iter().filter_map(|(key, value)| {
+        let value = value.as_deref()?;
+        let is_env = !key.is_empty()
+            && !key.starts_with(|c: char| c.is_ascii_digit())
+            && key
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+        is_env.then_some((key.as_str(), value))
+    })
+}
+
+/// Returns the device the console is on, from `console=`.
+///
+/// Options after a comma, such as the baud rate in `console=ttyS0,115200`,
+/// are ignored.
+pub fn console() -> Option<&'static str> {
+    get("console")
+        .map(|it| it.split(',').next().unwrap_or(it))
+        .filter(|it| !it.is_empty())
+}
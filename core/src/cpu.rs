@@ -0,0 +1,150 @@
+//! CPU hotplug.
+//!
+//! The runtime starts every CPU of the platform at boot. Taking a CPU
+//! offline takes it away from the scheduler: the affinity of every task
+//! leaves it out, so no task is placed on it afterwards. The current task
+//! moves off right away. Tasks already queued there stay until they sleep,
+//! as axtask doesn't migrate queued tasks.
+//! `/sys/devices/system/cpu/cpuN/online` does that at run time.
+//!
+//! `maxcpus=` and `nosmp` on the kernel command line go further. The runtime
+//! offers no way to hold back the secondary CPUs, so the CPUs beyond the
+//! limit are stopped as soon as they run: a task pinned to each of them
+//! hands it back to the firmware, and they can't be brought online again.
+
+use alloc::format;
+
+use axconfig::plat::CPU_NUM;
+use axerrno::{AxError, AxResult};
+use axtask::{AxCpuMask, TaskInner, current};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{cmdline, task::tasks};
+
+lazy_static! {
+    static ref ONLINE: Mutex<AxCpuMask> = Mutex::new({
+        let mut mask = AxCpuMask::new();
+        for cpu in 0..CPU_NUM {
+            mask.set(cpu, true);
+        }
+        mask
+    });
+    /// CPUs stopped at boot, which can't come back.
+    static ref STOPPED: Mutex<AxCpuMask> = Mutex::new(AxCpuMask::new());
+}
+
+/// Takes the CPUs beyond `maxcpus=` offline, or all but the boot CPU with
+/// `nosmp`, and stops them with `stop_cpu`, which must not return.
+///
+/// Must be called during boot, after [`cmdline::init`].
+pub fn init(stop_cpu: fn() -> !) {
+    let max_cpus = if cmdline::get("nosmp").is_some() {
+        1
+    } else {
+        match cmdline::get("maxcpus").map(str::parse::<usize>) {
+            Some(Ok(max)) => max,
+            Some(Err(_)) => {
+                warn!("cpu: invalid maxcpus=, ignoring");
+                CPU_NUM
+            }
+            None => CPU_NUM,
+        }
+    };
+    // `maxcpus=0` disables SMP as `nosmp` does, the boot CPU always stays.
+    let mut online = ONLINE.lock();
+    let mut stopped = STOPPED.lock();
+    for cpu in max_cpus.max(1)..CPU_NUM {
+        online.set(cpu, false);
+        stopped.set(cpu, true);
+
+        let mut mask = AxCpuMask::new();
+        mask.set(cpu, true);
+        let mut task = TaskInner::new(
+            move || stop_cpu(),
+            format!("cpuhp/{cpu}"),
+            axconfig::TASK_STACK_SIZE,
+        );
+        task.set_cpumask(mask);
+        axtask::spawn_task(task);
+    }
+    if max_cpus < CPU_NUM {
+        info!("cpu: {} of {} CPUs online", max_cpus.max(1), CPU_NUM);
+    }
+}
+
+/// Returns the mask of online CPUs.
+pub fn online_mask() -> AxCpuMask {
+    *ONLINE.lock()
+}
+
+/// Returns whether `cpu` is online.
+pub fn is_online(cpu: usize) -> bool {
+    cpu < CPU_NUM && ONLINE.lock().get(cpu)
+}
+
+/// Restricts the affinity `mask` to the online CPUs.
+///
+/// If none of them is online, the task may run on any online CPU, as Linux
+/// breaks the affinity of a task whose last CPU goes down.
+pub fn restrict(mask: &AxCpuMask) -> AxCpuMask {
+    let online = ONLINE.lock();
+    let mut restricted = AxCpuMask::new();
+    for cpu in 0..CPU_NUM {
+        if mask.get(cpu) && online.get(cpu) {
+            restricted.set(cpu, true);
+        }
+    }
+    if restricted.is_empty() {
+        *online
+    } else {
+        restricted
+    }
+}
+
+/// Brings `cpu` online or takes it offline.
+///
+/// The boot CPU and the last online CPU can't be taken offline, and CPUs
+/// stopped at boot can't be brought online.
+pub fn set_online(cpu: usize, online: bool) -> AxResult<()> {
+    if cpu >= CPU_NUM {
+        return Err(AxError::InvalidInput);
+    }
+    if online && STOPPED.lock().get(cpu) {
+        return Err(AxError::Io);
+    }
+    let mut mask = ONLINE.lock();
+    if mask.get(cpu) == online {
+        return Ok(());
+    }
+    if !online && (cpu == 0 || (0..CPU_NUM).filter(|&it| mask.get(it)).count() == 1) {
+        return Err(AxError::ResourceBusy);
+    }
+    mask.set(cpu, online);
+    drop(mask);
+
+    info!(
+        "cpu: CPU {} {}",
+        cpu,
+        if online { "online" } else { "offline" }
+    );
+    if !online {
+        evacuate();
+    }
+    Ok(())
+}
+
+/// Restricts the affinity of every task to the online CPUs.
+fn evacuate() {
+    for task in tasks() {
+        let mask = restrict(&task.cpumask());
+        if task.id() == current().id() {
+            // Migrates right away if the current CPU went down.
+            axtask::set_current_affinity(mask);
+        } else {
+            // A task queued on an offline CPU stays there until it sleeps,
+            // its next wakeup picks an online one.
+            task.set_cpumask(mask);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Process credentials.
+
+use alloc::sync::Arc;
+
+use crate::keys::Key;
+
+/// The user and group identities of a process.
+///
+/// Every id starts out as root, so processes that never touch their
+/// credentials keep behaving as before.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// Real user id.
+    pub uid: u32,
+    /// Effective user id.
+    pub euid: u32,
+    /// Saved set-user-id.
+    pub suid: u32,
+    /// Filesystem user id.
+    pub fsuid: u32,
+    /// Real group id.
+    pub gid: u32,
+    /// Effective group id.
+    pub egid: u32,
+    /// Saved set-group-id.
+    pub sgid: u32,
+    /// Filesystem group id.
+    pub fsgid: u32,
+    /// Whether `execve` may no longer grant privileges, as set with
+    /// `PR_SET_NO_NEW_PRIVS`. Never cleared once set.
+    pub no_new_privs: bool,
+    /// The session keyring joined with `KEYCTL_JOIN_SESSION_KEYRING`, if
+    /// any. Inherited across `fork` and `execve`.
+    pub session_keyring: Option<Arc<Key>>,
+}
+
+impl Credentials {
+    /// Whether the process is privileged, i.e. has an effective uid of 0.
+    pub fn is_privileged(&self) -> bool {
+        self.euid == 0
+    }
+
+    /// Whether `uid` is one of the real, effective or saved user ids, which
+    /// an unprivileged process may switch between.
+    pub fn has_uid(&self, uid: u32) -> bool {
+        uid == self.uid || uid == self.euid || uid == self.suid
+    }
+
+    /// Whether `gid` is one of the real, effective or saved group ids.
+    pub fn has_gid(&self, gid: u32) -> bool {
+        gid == self.gid || gid == self.egid || gid == self.sgid
+    }
+
+    /// Whether the process is in group `gid` for file access.
+    ///
+    /// There are no supplementary groups, so this is the filesystem group
+    /// id alone.
+    pub fn in_group(&self, gid: u32) -> bool {
+        gid == self.fsgid
+    }
+
+    /// Applies the set-user-id and set-group-id bits of an executable being
+    /// loaded.
+    ///
+    /// As in POSIX, the saved ids are then set from the effective ones.
+    pub fn apply_exec(&mut self, setuid: Option<u32>, setgid: Option<u32>) {
+        if let Some(uid) = setuid {
+            self.euid = uid;
+        }
+        if let Some(gid) = setgid {
+            self.egid = gid;
+        }
+        self.suid = self.euid;
+        self.sgid = self.egid;
+        self.fsuid = self.euid;
+        self.fsgid = self.egid;
+    }
+}
@@ -13,7 +13,8 @@ use core::{
     time::Duration,
 };
 
-use axerrno::AxResult;
+use axerrno::{AxError, AxResult};
+use axhal::paging::MappingFlags;
 use axmm::{
     AddrSpace,
     backend::{Backend, SharedPages},
@@ -21,13 +22,13 @@ use axmm::{
 use axsync::Mutex;
 use axtask::{
     current,
-    future::{self, block_on, interruptible},
+    future::{block_on, interruptible},
 };
 use hashbrown::HashMap;
 use kspin::SpinNoIrq;
-use memory_addr::VirtAddr;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr};
 
-use crate::task::AsThread;
+use crate::{task::AsThread, timer};
 
 /// Wait queue used by futex.
 #[derive(Default)]
@@ -51,7 +52,7 @@ impl WaitQueue {
         condition: impl FnOnce() -> bool,
     ) -> AxResult<bool> {
         let mut condition = Some(condition);
-        block_on(interruptible(future::timeout(
+        block_on(interruptible(timer::timeout(
             timeout,
             poll_fn(|cx| {
                 if let Some(cond) = condition.take() {
@@ -69,6 +70,59 @@ impl WaitQueue {
         )))??
     }
 
+    /// Waits on several wait queues at once, as `futex_waitv` does.
+    ///
+    /// `condition(i)` is checked with the `i`-th queue locked, right before
+    /// the current task is enqueued on it. Returns `None` if a condition is
+    /// not met, otherwise the index of the queue the task was woken from.
+    pub fn wait_any(
+        queues: &[&WaitQueue],
+        timeout: Option<Duration>,
+        mut condition: impl FnMut(usize) -> bool,
+    ) -> AxResult<Option<usize>> {
+        let mut enqueued = None;
+        let result = block_on(interruptible(timer::timeout(
+            timeout,
+            poll_fn(|cx| {
+                let Some(waker) = &enqueued else {
+                    for (i, wq) in queues.iter().enumerate() {
+                        let mut queue = wq.queue.lock();
+                        if !condition(i) {
+                            drop(queue);
+                            for wq in &queues[..i] {
+                                wq.dequeue(cx.waker());
+                            }
+                            return Poll::Ready(None);
+                        }
+                        queue.push_back((cx.waker().clone(), u32::MAX));
+                    }
+                    enqueued = Some(cx.waker().clone());
+                    return Poll::Pending;
+                };
+                // Whoever woke us also took us off their queue.
+                match queues.iter().position(|wq| !wq.contains(waker)) {
+                    Some(i) => Poll::Ready(Some(i)),
+                    None => Poll::Pending,
+                }
+            }),
+        )));
+        // Leave every queue we are still on, whatever the outcome.
+        if let Some(waker) = &enqueued {
+            for wq in queues {
+                wq.dequeue(waker);
+            }
+        }
+        Ok(result??)
+    }
+
+    fn contains(&self, waker: &Waker) -> bool {
+        self.queue.lock().iter().any(|(w, _)| w.will_wake(waker))
+    }
+
+    fn dequeue(&self, waker: &Waker) {
+        self.queue.lock().retain(|(w, _)| !w.will_wake(waker));
+    }
+
     /// Wakes up at most `count` tasks whose bitset intersects with the given
     /// bitmask.
     pub fn wake(&self, count: usize, mask: u32) -> usize {
@@ -115,45 +169,66 @@ pub enum FutexKey {
 
     /// A futex in a shared memory region.
     Shared {
-        /// The offset of the futex within the shared memory region.
-        offset: usize,
+        /// The physical address of the futex.
+        paddr: usize,
         /// The shared memory region.
         region: Result<Weak<SharedPages>, Weak<()>>,
     },
 }
 
 impl FutexKey {
-    /// Creates a new `FutexKey`.
-    pub fn new(aspace: &AddrSpace, address: usize) -> Self {
-        if let Some(area) = aspace.find_area(VirtAddr::from_usize(address)) {
-            match area.backend() {
-                Backend::Shared(backend) => {
-                    return Self::Shared {
-                        offset: address - area.start().as_usize(),
-                        region: Ok(Arc::downgrade(backend.pages())),
-                    };
-                }
-                Backend::File(file) => {
-                    return Self::Shared {
-                        offset: address - area.start().as_usize(),
-                        region: Err(file.futex_handle()),
-                    };
-                }
-                _ => {}
-            }
+    /// Creates the key of the futex at `address`, which is shared with other
+    /// processes if `shared` is set and it lies in a shared mapping, like
+    /// futexes without `FUTEX_PRIVATE_FLAG` on Linux.
+    ///
+    /// A shared futex is named by the physical address of its word, so that
+    /// processes agree on it wherever they map the region and whichever
+    /// part of it. The page is faulted in for that.
+    pub fn new(aspace: &mut AddrSpace, address: usize, shared: bool) -> AxResult<Self> {
+        let addr = VirtAddr::from_usize(address);
+        let Some(area) = aspace.find_area(addr).filter(|_| shared) else {
+            return Ok(Self::Private { address });
+        };
+        let region = match area.backend() {
+            Backend::Shared(backend) => Ok(Arc::downgrade(backend.pages())),
+            Backend::File(file) => Err(file.futex_handle()),
+            _ => return Ok(Self::Private { address }),
+        };
+        let access = area.flags() & (MappingFlags::READ | MappingFlags::WRITE);
+        let page = addr.align_down_4k();
+        if aspace.page_table().query(page).is_err() {
+            aspace.populate_area(page, PAGE_SIZE_4K, access)?;
         }
-        Self::Private { address }
+        let (paddr, ..) = aspace
+            .page_table()
+            .query(page)
+            .map_err(|_| AxError::BadAddress)?;
+        Ok(Self::Shared {
+            paddr: paddr.as_usize() + addr.align_offset_4k(),
+            region,
+        })
     }
 
-    /// Shortcut to create a `FutexKey` for the current task's address space.
-    pub fn new_current(address: usize) -> Self {
-        Self::new(&current().as_thread().proc_data.aspace.lock(), address)
+    /// Shortcut to create a shared `FutexKey` for the current task's address
+    /// space.
+    pub fn new_current(address: usize) -> AxResult<Self> {
+        Self::new(
+            &mut current().as_thread().proc_data.aspace.lock(),
+            address,
+            true,
+        )
+    }
+
+    /// Creates a `FutexKey` private to the current process, as
+    /// `FUTEX_PRIVATE_FLAG` asks for.
+    pub fn new_private(address: usize) -> Self {
+        Self::Private { address }
     }
 
     fn as_usize(&self) -> usize {
         match self {
             FutexKey::Private { address } => *address,
-            FutexKey::Shared { offset, .. } => *offset,
+            FutexKey::Shared { paddr, .. } => *paddr,
         }
     }
 }
@@ -0,0 +1,611 @@
+//! Kernel key retention, the keys of `add_key`, `request_key` and `keyctl`.
+//!
+//! Keys are linked into keyrings, which are keys themselves. A thread finds
+//! keys through its thread, process and session keyrings; keys reachable
+//! from them are *possessed* and get the possessor permissions on top of the
+//! user, group or other ones. Every user also has a user keyring and a
+//! user-session keyring, which stands in for the session keyring of threads
+//! that have not joined one.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    format,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicI32, AtomicU32, Ordering},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axsync::spin::SpinNoIrq;
+use axtask::current;
+use spin::RwLock;
+
+use crate::task::AsThread;
+
+/// The largest payload of a key.
+pub const MAX_PAYLOAD: usize = 32767;
+
+/// Permission to see the attributes of a key.
+pub const KEY_VIEW: u32 = 0x01;
+/// Permission to read the payload of a key, or the keys in a keyring.
+pub const KEY_READ: u32 = 0x02;
+/// Permission to update a key, or to add keys to and remove them from a
+/// keyring.
+pub const KEY_WRITE: u32 = 0x04;
+/// Permission to find a key, or to search a keyring.
+pub const KEY_SEARCH: u32 = 0x08;
+/// Permission to link a key into a keyring.
+pub const KEY_LINK: u32 = 0x10;
+/// Permission to change the owner and permissions of a key.
+pub const KEY_SETATTR: u32 = 0x20;
+/// All the permissions of one class.
+pub const KEY_ALL: u32 = 0x3f;
+
+/// The special id of the thread keyring.
+pub const KEY_SPEC_THREAD_KEYRING: i32 = -1;
+/// The special id of the process keyring.
+pub const KEY_SPEC_PROCESS_KEYRING: i32 = -2;
+/// The special id of the session keyring.
+pub const KEY_SPEC_SESSION_KEYRING: i32 = -3;
+/// The special id of the user keyring.
+pub const KEY_SPEC_USER_KEYRING: i32 = -4;
+/// The special id of the user-session keyring.
+pub const KEY_SPEC_USER_SESSION_KEYRING: i32 = -5;
+
+/// The permissions of keys added by users: everything for the possessor,
+/// view for the owner.
+const DEFAULT_PERM: u32 = (KEY_ALL << 24) | (KEY_VIEW << 16);
+/// The permissions of session keyrings, which the owner may also read,
+/// search and link.
+const SESSION_PERM: u32 = (KEY_ALL << 24) | ((KEY_VIEW | KEY_READ | KEY_SEARCH | KEY_LINK) << 16);
+/// The permissions of user keyrings.
+const USER_PERM: u32 = (KEY_ALL << 24) | (KEY_ALL << 16);
+
+/// The types of keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    /// A blob of data that can be read back.
+    User,
+    /// Like [`KeyType::User`], but only the kernel can read it.
+    Logon,
+    /// A list of keys.
+    Keyring,
+}
+
+impl KeyType {
+    /// Parses a key type name.
+    pub fn from_name(name: &str) -> AxResult<Self> {
+        match name {
+            "user" => Ok(Self::User),
+            "logon" => Ok(Self::Logon),
+            "keyring" => Ok(Self::Keyring),
+            _ => Err(AxError::Other(LinuxError::ENODEV)),
+        }
+    }
+
+    /// Returns the name of the type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Logon => "logon",
+            Self::Keyring => "keyring",
+        }
+    }
+}
+
+enum Payload {
+    Data(Vec<u8>),
+    Keyring(Vec<Arc<Key>>),
+    Revoked,
+    /// Invalidated: the key can no longer be found and is dropped from the
+    /// keyrings it is linked into as they are walked.
+    Dead,
+}
+
+impl Payload {
+    /// Zeroes key material so that it isn't left behind in freed memory.
+    fn wipe(&mut self) {
+        if let Payload::Data(data) = self {
+            data.fill(0);
+        }
+    }
+}
+
+/// A key.
+pub struct Key {
+    /// The serial number.
+    pub serial: i32,
+    /// The type.
+    pub ty: KeyType,
+    /// The description the key is searched by.
+    pub description: String,
+    owner: RwLock<(u32, u32)>,
+    perm: AtomicU32,
+    payload: RwLock<Payload>,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("serial", &self.serial)
+            .field("ty", &self.ty)
+            .field("description", &self.description)
+            .finish_non_exhaustive()
+    }
+}
+
+static KEYS: RwLock<BTreeMap<i32, Weak<Key>>> = RwLock::new(BTreeMap::new());
+static NEXT_SERIAL: AtomicI32 = AtomicI32::new(1);
+
+/// The user keyring and user-session keyring of each user.
+static USER_KEYRINGS: RwLock<BTreeMap<u32, (Arc<Key>, Arc<Key>)>> = RwLock::new(BTreeMap::new());
+
+impl Key {
+    fn new(ty: KeyType, description: &str, owner: (u32, u32), perm: u32) -> Arc<Self> {
+        let serial = NEXT_SERIAL.fetch_add(1, Ordering::Relaxed);
+        let payload = match ty {
+            KeyType::Keyring => Payload::Keyring(Vec::new()),
+            _ => Payload::Data(Vec::new()),
+        };
+        let key = Arc::new(Self {
+            serial,
+            ty,
+            description: description.to_string(),
+            owner: RwLock::new(owner),
+            perm: AtomicU32::new(perm),
+            payload: RwLock::new(payload),
+        });
+        KEYS.write().insert(serial, Arc::downgrade(&key));
+        key
+    }
+
+    /// Creates an anonymous keyring that is not linked anywhere, such as a
+    /// new session keyring.
+    pub fn new_keyring(description: &str, owner: (u32, u32)) -> Arc<Self> {
+        Self::new(KeyType::Keyring, description, owner, SESSION_PERM)
+    }
+
+    /// Returns the owning user and group.
+    pub fn owner(&self) -> (u32, u32) {
+        *self.owner.read()
+    }
+
+    /// Returns the permission mask.
+    pub fn perm(&self) -> u32 {
+        self.perm.load(Ordering::Relaxed)
+    }
+
+    /// Whether the key has been revoked or invalidated.
+    pub fn is_revoked(&self) -> bool {
+        matches!(*self.payload.read(), Payload::Revoked | Payload::Dead)
+    }
+
+    fn is_dead(&self) -> bool {
+        matches!(*self.payload.read(), Payload::Dead)
+    }
+
+    /// Checks that the requester has all the permissions in `need` on the
+    /// key.
+    pub fn check(&self, req: &Requester, need: u32) -> AxResult<()> {
+        let perm = self.perm();
+        let (uid, gid) = self.owner();
+        let mut granted = if uid == req.uid {
+            perm >> 16
+        } else if gid == req.gid {
+            perm >> 8
+        } else {
+            perm
+        } & KEY_ALL;
+        if granted & need != need && req.possesses(self) {
+            granted |= (perm >> 24) & KEY_ALL;
+        }
+        if granted & need != need {
+            return Err(AxError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the payload of a data key.
+    pub fn with_payload<R>(&self, f: impl FnOnce(&[u8]) -> R) -> AxResult<R> {
+        match &*self.payload.read() {
+            Payload::Data(data) => Ok(f(data)),
+            Payload::Keyring(_) => Err(AxError::Other(LinuxError::EOPNOTSUPP)),
+            Payload::Revoked | Payload::Dead => Err(AxError::Other(LinuxError::EKEYREVOKED)),
+        }
+    }
+
+    /// Returns the keys linked into a keyring.
+    pub fn links(&self) -> AxResult<Vec<Arc<Key>>> {
+        match &mut *self.payload.write() {
+            Payload::Keyring(keys) => {
+                keys.retain(|key| !key.is_dead());
+                Ok(keys.clone())
+            }
+            Payload::Data(_) => Err(AxError::Other(LinuxError::ENOTDIR)),
+            Payload::Revoked | Payload::Dead => Err(AxError::Other(LinuxError::EKEYREVOKED)),
+        }
+    }
+
+    fn set_payload(&self, payload: Payload) {
+        // The old payload is dropped outside of the lock, as dropping the
+        // keys linked into a keyring may take it.
+        let mut old = core::mem::replace(&mut *self.payload.write(), payload);
+        old.wipe();
+    }
+
+    /// Replaces the payload of a data key.
+    pub fn update(&self, payload: &[u8]) -> AxResult<()> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(AxError::InvalidInput);
+        }
+        let mut current = self.payload.write();
+        match &mut *current {
+            Payload::Data(data) => {
+                data.fill(0);
+                *data = payload.to_vec();
+                Ok(())
+            }
+            Payload::Keyring(_) => Err(AxError::Other(LinuxError::EOPNOTSUPP)),
+            Payload::Revoked | Payload::Dead => Err(AxError::Other(LinuxError::EKEYREVOKED)),
+        }
+    }
+
+    /// Revokes the key: it stays where it is linked, but can no longer be
+    /// used.
+    pub fn revoke(&self) {
+        self.set_payload(Payload::Revoked);
+    }
+
+    /// Invalidates the key: it can no longer be found, and goes away.
+    pub fn invalidate(&self) {
+        self.set_payload(Payload::Dead);
+        KEYS.write().remove(&self.serial);
+    }
+
+    /// Changes the owner of the key. `None` leaves the user or group as it
+    /// is.
+    pub fn chown(&self, uid: Option<u32>, gid: Option<u32>) {
+        let mut owner = self.owner.write();
+        if let Some(uid) = uid {
+            owner.0 = uid;
+        }
+        if let Some(gid) = gid {
+            owner.1 = gid;
+        }
+    }
+
+    /// Changes the permission mask of the key.
+    pub fn set_perm(&self, perm: u32) -> AxResult<()> {
+        if perm & !0x3f3f3f3f != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        self.perm.store(perm, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns the `type;uid;gid;perm;description` line of `KEYCTL_DESCRIBE`.
+    pub fn describe(&self) -> String {
+        let (uid, gid) = self.owner();
+        format!(
+            "{};{};{};{:08x};{}",
+            self.ty.name(),
+            uid,
+            gid,
+            self.perm(),
+            self.description
+        )
+    }
+
+    /// Links `key` into this keyring, displacing a key of the same type and
+    /// description.
+    pub fn link(self: &Arc<Self>, key: Arc<Key>) -> AxResult<()> {
+        if key.ty == KeyType::Keyring && (Arc::ptr_eq(self, &key) || reachable(&key, self)) {
+            return Err(AxError::Other(LinuxError::EDEADLK));
+        }
+        let old = match &mut *self.payload.write() {
+            Payload::Keyring(keys) => {
+                let old = keys
+                    .iter()
+                    .position(|it| it.ty == key.ty && it.description == key.description)
+                    .map(|pos| keys.swap_remove(pos));
+                keys.push(key);
+                old
+            }
+            Payload::Data(_) => return Err(AxError::Other(LinuxError::ENOTDIR)),
+            Payload::Revoked | Payload::Dead => {
+                return Err(AxError::Other(LinuxError::EKEYREVOKED));
+            }
+        };
+        drop(old);
+        Ok(())
+    }
+
+    /// Removes `key` from this keyring.
+    pub fn unlink(&self, key: &Arc<Key>) -> AxResult<()> {
+        let old = match &mut *self.payload.write() {
+            Payload::Keyring(keys) => {
+                let pos = keys
+                    .iter()
+                    .position(|it| Arc::ptr_eq(it, key))
+                    .ok_or(AxError::NotFound)?;
+                keys.swap_remove(pos)
+            }
+            Payload::Data(_) => return Err(AxError::Other(LinuxError::ENOTDIR)),
+            Payload::Revoked | Payload::Dead => {
+                return Err(AxError::Other(LinuxError::EKEYREVOKED));
+            }
+        };
+        drop(old);
+        Ok(())
+    }
+
+    /// Removes all keys from this keyring.
+    pub fn clear(&self) -> AxResult<()> {
+        let old = match &mut *self.payload.write() {
+            Payload::Keyring(keys) => core::mem::take(keys),
+            Payload::Data(_) => return Err(AxError::Other(LinuxError::ENOTDIR)),
+            Payload::Revoked | Payload::Dead => {
+                return Err(AxError::Other(LinuxError::EKEYREVOKED));
+            }
+        };
+        drop(old);
+        Ok(())
+    }
+
+    /// Searches this keyring and the keyrings linked into it, breadth first,
+    /// for a key of type `ty` with `description`.
+    ///
+    /// Only keyrings and keys the requester may search are considered.
+    pub fn search(&self, ty: KeyType, description: &str, req: &Requester) -> AxResult<Arc<Key>> {
+        self.check(req, KEY_SEARCH)?;
+        let mut visited = Vec::from([self.serial]);
+        let mut queue = VecDeque::from([self.links()?]);
+        while let Some(keys) = queue.pop_front() {
+            for key in keys {
+                if key.ty == ty
+                    && key.description == description
+                    && !key.is_revoked()
+                    && key.check(req, KEY_SEARCH).is_ok()
+                {
+                    return Ok(key);
+                }
+                if key.ty == KeyType::Keyring
+                    && !visited.contains(&key.serial)
+                    && key.check(req, KEY_SEARCH).is_ok()
+                {
+                    visited.push(key.serial);
+                    if let Ok(links) = key.links() {
+                        queue.push_back(links);
+                    }
+                }
+            }
+        }
+        Err(AxError::Other(LinuxError::ENOKEY))
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.payload.get_mut().wipe();
+        KEYS.write().remove(&self.serial);
+    }
+}
+
+/// Whether `target` can be reached from the keyring `from` through links,
+/// following keyrings that pass the `follow` check.
+fn reachable_by(from: &Key, target: &Key, follow: impl Fn(&Key) -> bool) -> bool {
+    let mut visited = Vec::from([from.serial]);
+    let mut queue = VecDeque::from([from.links().unwrap_or_default()]);
+    while let Some(keys) = queue.pop_front() {
+        for key in keys {
+            if key.serial == target.serial {
+                return true;
+            }
+            if key.ty == KeyType::Keyring && !visited.contains(&key.serial) && follow(&key) {
+                visited.push(key.serial);
+                queue.push_back(key.links().unwrap_or_default());
+            }
+        }
+    }
+    false
+}
+
+fn reachable(from: &Key, target: &Key) -> bool {
+    reachable_by(from, target, |_| true)
+}
+
+/// Returns the user keyring and user-session keyring of `uid`, creating
+/// them on first use.
+fn user_keyrings(uid: u32) -> (Arc<Key>, Arc<Key>) {
+    if let Some(keyrings) = USER_KEYRINGS.read().get(&uid) {
+        return keyrings.clone();
+    }
+    USER_KEYRINGS
+        .write()
+        .entry(uid)
+        .or_insert_with(|| {
+            let user = Key::new(
+                KeyType::Keyring,
+                &format!("_uid.{uid}"),
+                (uid, 0),
+                USER_PERM,
+            );
+            let session = Key::new(
+                KeyType::Keyring,
+                &format!("_uid_ses.{uid}"),
+                (uid, 0),
+                USER_PERM,
+            );
+            let _ = session.link(user.clone());
+            (user, session)
+        })
+        .clone()
+}
+
+/// The identity keys are used with: the filesystem ids and keyrings of the
+/// calling thread.
+pub struct Requester {
+    /// The filesystem user id.
+    pub uid: u32,
+    /// The filesystem group id.
+    pub gid: u32,
+    keyrings: Vec<Arc<Key>>,
+}
+
+impl Requester {
+    /// Returns the requester for the current thread.
+    pub fn current() -> Self {
+        let curr = current();
+        let thr = curr.as_thread();
+        let cred = thr.proc_data.cred.read();
+        let mut keyrings = Vec::new();
+        keyrings.extend(thr.keyring.lock().clone());
+        keyrings.extend(thr.proc_data.keyring.lock().clone());
+        match &cred.session_keyring {
+            Some(session) => keyrings.push(session.clone()),
+            None => keyrings.push(user_keyrings(cred.fsuid).1),
+        }
+        Self {
+            uid: cred.fsuid,
+            gid: cred.fsgid,
+            keyrings,
+        }
+    }
+
+    /// Whether the requester possesses `key`, i.e. can reach it from its
+    /// thread, process or session keyring through keyrings it possesses
+    /// with search permission.
+    pub fn possesses(&self, key: &Key) -> bool {
+        self.keyrings.iter().any(|keyring| {
+            keyring.serial == key.serial
+                || reachable_by(keyring, key, |it| (it.perm() >> 24) & KEY_SEARCH != 0)
+        })
+    }
+
+    /// Searches the thread, process and session keyrings, in that order,
+    /// for a key of type `ty` with `description`.
+    pub fn search(&self, ty: KeyType, description: &str) -> AxResult<Arc<Key>> {
+        self.keyrings
+            .iter()
+            .find_map(|keyring| keyring.search(ty, description, self).ok())
+            .ok_or(AxError::Other(LinuxError::ENOKEY))
+    }
+}
+
+/// Returns the key with id `id`, which may be one of the `KEY_SPEC_*` ids of
+/// the current thread's keyrings. With `create`, a missing thread, process
+/// or session keyring is created.
+pub fn lookup(id: i32, create: bool) -> AxResult<Arc<Key>> {
+    let curr = current();
+    let thr = curr.as_thread();
+    let owner = {
+        let cred = thr.proc_data.cred.read();
+        (cred.fsuid, cred.fsgid)
+    };
+    let special = |slot: &SpinNoIrq<Option<Arc<Key>>>, name: &str| {
+        let mut slot = slot.lock();
+        if slot.is_none() && create {
+            *slot = Some(Key::new(KeyType::Keyring, name, owner, DEFAULT_PERM));
+        }
+        slot.clone().ok_or(AxError::Other(LinuxError::ENOKEY))
+    };
+    match id {
+        KEY_SPEC_THREAD_KEYRING => special(&thr.keyring, "_tid"),
+        KEY_SPEC_PROCESS_KEYRING => special(&thr.proc_data.keyring, "_pid"),
+        KEY_SPEC_SESSION_KEYRING => {
+            if let Some(session) = thr.proc_data.cred.read().session_keyring.clone() {
+                return Ok(session);
+            }
+            if create {
+                join_session(None)
+            } else {
+                Ok(user_keyrings(owner.0).1)
+            }
+        }
+        KEY_SPEC_USER_KEYRING => Ok(user_keyrings(owner.0).0),
+        KEY_SPEC_USER_SESSION_KEYRING => Ok(user_keyrings(owner.0).1),
+        id if id > 0 => {
+            // Upgrade outside of the lock, as dropping a key takes it.
+            let key = KEYS.read().get(&id).cloned();
+            key.and_then(|key| key.upgrade())
+                .filter(|key| !key.is_dead())
+                .ok_or(AxError::Other(LinuxError::ENOKEY))
+        }
+        _ => Err(AxError::InvalidInput),
+    }
+}
+
+/// Makes the current process join the session keyring `name`, or a new
+/// anonymous one. A keyring with that name the caller may search is joined
+/// if there is one; otherwise it is created.
+pub fn join_session(name: Option<&str>) -> AxResult<Arc<Key>> {
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let req = Requester::current();
+    let existing = name.and_then(|name| {
+        let keys = KEYS.read().values().cloned().collect::<Vec<_>>();
+        keys.iter().filter_map(Weak::upgrade).find(|key| {
+            key.ty == KeyType::Keyring
+                && key.description == name
+                && !key.is_revoked()
+                && key.check(&req, KEY_SEARCH).is_ok()
+        })
+    });
+    let session =
+        existing.unwrap_or_else(|| Key::new_keyring(name.unwrap_or("_ses"), (req.uid, req.gid)));
+    proc_data.update_cred(|cred| {
+        cred.session_keyring = Some(session.clone());
+        Ok(())
+    })?;
+    Ok(session)
+}
+
+/// Adds a key to `keyring`, or updates the payload of the key of the same
+/// type and description already linked there if the requester may write
+/// it. Returns the key.
+pub fn add(
+    keyring: &Arc<Key>,
+    ty: KeyType,
+    description: &str,
+    payload: &[u8],
+    req: &Requester,
+) -> AxResult<Arc<Key>> {
+    if description.is_empty() || payload.len() > MAX_PAYLOAD {
+        return Err(AxError::InvalidInput);
+    }
+    match ty {
+        // Logon keys are namespaced by a service prefix, as in `fscrypt:...`.
+        KeyType::Logon
+            if !description
+                .split_once(':')
+                .is_some_and(|(prefix, _)| !prefix.is_empty()) =>
+        {
+            return Err(AxError::InvalidInput);
+        }
+        KeyType::Keyring if !payload.is_empty() => return Err(AxError::InvalidInput),
+        _ => {}
+    }
+    keyring.check(req, KEY_WRITE)?;
+
+    if ty != KeyType::Keyring
+        && let Some(key) = keyring
+            .links()?
+            .into_iter()
+            .find(|key| key.ty == ty && key.description == description)
+        && key.check(req, KEY_WRITE).is_ok()
+        && key.update(payload).is_ok()
+    {
+        return Ok(key);
+    }
+
+    let key = Key::new(ty, description, (req.uid, req.gid), DEFAULT_PERM);
+    if ty != KeyType::Keyring {
+        key.update(payload)?;
+    }
+    keyring.link(key.clone())?;
+    Ok(key)
+}
@@ -10,11 +10,20 @@ extern crate alloc;
 #[macro_use]
 extern crate axlog;
 
+pub mod boot;
+pub mod cmdline;
 pub mod config;
+pub mod cpu;
+pub mod cred;
 pub mod futex;
+pub mod keys;
 pub mod mm;
+pub mod numa;
 pub mod resources;
 pub mod shm;
+pub mod sysctl;
 pub mod task;
 pub mod time;
+pub mod timer;
+pub mod trace;
 pub mod vfs;
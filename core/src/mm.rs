@@ -1,20 +1,30 @@
 //! User address space management.
 
-use alloc::{borrow::ToOwned, string::String, vec, vec::Vec};
+mod auxv;
+mod range_map;
+
+use alloc::{
+    borrow::ToOwned,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
 use core::{
     ffi::CStr,
     hint::unlikely,
     iter,
     mem::MaybeUninit,
+    ops::Range,
     sync::atomic::{AtomicBool, Ordering},
 };
 
-use axerrno::{AxError, AxResult};
-use axfs_ng::{CachedFile, FS_CONTEXT, FileBackend};
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::{CachedFile, FS_CONTEXT, FileBackend, FileFlags};
 use axfs_ng_vfs::Location;
 use axhal::{
     asm::user_copy,
-    mem::virt_to_phys,
+    mem::{phys_to_virt, virt_to_phys},
     paging::{MappingFlags, PageSize},
 };
 use axmm::{AddrSpace, backend::Backend};
@@ -22,12 +32,17 @@ use axsync::Mutex;
 use extern_trait::extern_trait;
 use kernel_elf_parser::{AuxEntry, ELFHeaders, ELFHeadersBuilder, ELFParser, app_stack_region};
 use kernel_guard::IrqSave;
-use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr, VirtAddr};
 use ouroboros::self_referencing;
 use starry_vm::{VmError, VmIo, VmResult};
 use uluru::LRUCache;
 
-use crate::config::{USER_SPACE_BASE, USER_SPACE_SIZE};
+pub use self::range_map::RangeMap;
+use crate::{
+    config::{USER_SPACE_BASE, USER_SPACE_SIZE},
+    cred::Credentials,
+    task::processes,
+};
 
 /// Creates a new empty user address space.
 pub fn new_user_aspace_empty() -> AxResult<AddrSpace> {
@@ -50,6 +65,37 @@ pub fn copy_from_kernel(_aspace: &mut AddrSpace) -> AxResult {
     Ok(())
 }
 
+/// Maps the device memory at `paddr` into the kernel's linear mapping,
+/// returning its virtual address.
+///
+/// Only the device memory the platform configuration lists is mapped at
+/// boot, so drivers of devices found in the device tree map their
+/// registers with this. It must be called before user address spaces are
+/// created, as some architectures copy the kernel mappings into them.
+pub fn ioremap(paddr: PhysAddr, size: usize) -> AxResult<VirtAddr> {
+    let start = phys_to_virt(paddr.align_down_4k());
+    let end = phys_to_virt((paddr + size).align_up_4k());
+    let mut aspace = axmm::kernel_aspace().lock();
+    let mut addr = start;
+    while addr < end {
+        if let Ok((_, _, size)) = aspace.page_table().query(addr) {
+            addr = addr.align_down(size as usize) + size as usize;
+            continue;
+        }
+        let run = addr;
+        while addr < end && aspace.page_table().query(addr).is_err() {
+            addr += PAGE_SIZE_4K;
+        }
+        aspace.map_linear(
+            run,
+            virt_to_phys(run),
+            addr - run,
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+        )?;
+    }
+    Ok(phys_to_virt(paddr))
+}
+
 /// Map the signal trampoline to the user address space.
 pub fn map_trampoline(aspace: &mut AddrSpace) -> AxResult {
     let signal_trampoline_paddr =
@@ -77,21 +123,158 @@ fn mapping_flags(flags: xmas_elf::program::Flags) -> MappingFlags {
     mapping_flags
 }
 
+/// Whether the object has relocations against its read-only segments, which
+/// the dynamic linker patches after making them writable.
+fn has_text_relocations(entry: &ElfCacheEntry) -> AxResult<bool> {
+    const DT_TEXTREL: u64 = 22;
+    const DT_FLAGS: u64 = 30;
+    const DF_TEXTREL: u64 = 0x4;
+
+    let Some(ph) = entry
+        .borrow_elf()
+        .ph
+        .iter()
+        .find(|ph| ph.get_type() == Ok(xmas_elf::program::Type::Dynamic))
+    else {
+        return Ok(false);
+    };
+    let mut data = vec![0; ph.file_size as usize];
+    let read = entry
+        .borrow_cache()
+        .read_at(&mut data.as_mut_slice(), ph.offset)?;
+    data.truncate(read);
+
+    for dyn_entry in data.chunks_exact(16) {
+        let tag = u64::from_ne_bytes(dyn_entry[..8].try_into().unwrap());
+        let val = u64::from_ne_bytes(dyn_entry[8..].try_into().unwrap());
+        match tag {
+            0 => break,
+            DT_TEXTREL => return Ok(true),
+            DT_FLAGS if val & DF_TEXTREL != 0 => return Ok(true),
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// The page caches of ELF files whose read-only segments [`map_elf`] maps
+/// shared.
+static SHARED_TEXT: Mutex<Vec<Weak<()>>> = Mutex::new(Vec::new());
+
+/// Whether `backend` maps the page cache of an ELF file whose read-only
+/// segments are shared between processes.
+///
+/// Such a mapping must not become writable as it is: a write would reach the
+/// file and every process running it. See [`privatize_shared_text`].
+pub fn is_shared_text(backend: &Backend) -> bool {
+    let Backend::File(file) = backend else {
+        return false;
+    };
+    let handle = file.futex_handle();
+    SHARED_TEXT
+        .lock()
+        .iter()
+        .any(|it| Weak::ptr_eq(it, &handle))
+}
+
+/// Gives the shared ELF text in `range` of `aspace` private copies of its
+/// pages, as a private mapping would have had all along, so that it can be
+/// made writable.
+pub fn privatize_shared_text(aspace: &mut AddrSpace, range: Range<usize>) -> AxResult<()> {
+    let areas = aspace
+        .areas()
+        .filter(|area| is_shared_text(area.backend()))
+        .map(|area| {
+            let start = area.start().as_usize().max(range.start);
+            let end = area.end().as_usize().min(range.end);
+            (start..end, area.flags())
+        })
+        .filter(|(overlap, _)| !overlap.is_empty())
+        .collect::<Vec<_>>();
+    for (overlap, flags) in areas {
+        let start = VirtAddr::from_usize(overlap.start);
+        let mut data = vec![0; overlap.len()];
+        aspace.populate_area(start, overlap.len(), MappingFlags::READ)?;
+        aspace.read(start, &mut data)?;
+        aspace.unmap(start, overlap.len())?;
+        aspace.map(
+            start,
+            overlap.len(),
+            flags,
+            true,
+            Backend::new_alloc(start, PageSize::Size4K),
+        )?;
+        aspace.write(start, &data)?;
+    }
+    Ok(())
+}
+
+/// Returns how many pages of `range` in `aspace` are backed by a frame,
+/// which is what makes up the resident set size.
+pub fn resident_pages(aspace: &AddrSpace, range: Range<usize>) -> usize {
+    let mut count = 0;
+    for area in aspace.areas() {
+        let mut addr = area.start().as_usize().max(range.start).align_down_4k();
+        let end = area.end().as_usize().min(range.end);
+        while addr < end {
+            match aspace.page_table().query(VirtAddr::from_usize(addr)) {
+                Ok((_, _, size)) => {
+                    // Step over a huge page at once.
+                    let next = addr.align_down(size as usize) + size as usize;
+                    count += (next.min(end) - addr).div_ceil(PAGE_SIZE_4K);
+                    addr = next;
+                }
+                Err(_) => addr += PAGE_SIZE_4K,
+            }
+        }
+    }
+    count
+}
+
+/// Fails with `ETXTBSY` if `loc` is the executable of a running process,
+/// which must be neither written to nor truncated while it runs.
+pub fn deny_write_access(loc: &Location) -> AxResult<()> {
+    let Ok(metadata) = loc.metadata() else {
+        return Ok(());
+    };
+    let device = loc.mountpoint().device();
+    let busy = processes().iter().any(|proc_data| {
+        if proc_data.proc.is_zombie() {
+            return false;
+        }
+        let exe = proc_data.exe.read();
+        exe.mountpoint().device() == device
+            && exe.metadata().is_ok_and(|it| it.inode == metadata.inode)
+    });
+    if busy {
+        return Err(AxError::Other(LinuxError::ETXTBSY));
+    }
+    Ok(())
+}
+
 /// Map the elf file to the user address space.
 ///
+/// Read-only segments map the page cache directly, so every process running
+/// the same binary shares their frames until it makes them writable (see
+/// [`privatize_shared_text`]). Writable segments, and every segment of an
+/// object with text relocations, are copy-on-write instead.
+///
 /// # Arguments
 /// - `uspace`: The address space of the user app.
+/// - `handle`: The shared handle of `uspace`.
 /// - `elf`: The elf file.
 ///
 /// # Returns
 /// - The entry point of the user app.
 fn map_elf<'a>(
     uspace: &mut AddrSpace,
+    handle: &Arc<Mutex<AddrSpace>>,
     base: usize,
     entry: &'a ElfCacheEntry,
 ) -> AxResult<ELFParser<'a>> {
     let elf_parser = ELFParser::new(entry.borrow_elf(), base).map_err(|_| AxError::InvalidData)?;
     let cache = entry.borrow_cache();
+    let textrel = has_text_relocations(entry)?;
 
     for ph in elf_parser
         .headers()
@@ -107,21 +290,45 @@ fn map_elf<'a>(
             ph.flags
         );
         let seg_pad = vaddr.align_offset_4k();
-        assert_eq!(seg_pad, ph.offset as usize % PAGE_SIZE_4K);
+        if seg_pad != ph.offset as usize % PAGE_SIZE_4K
+            || ph.offset.checked_add(ph.file_size).is_none()
+        {
+            return Err(AxError::InvalidExecutable);
+        }
 
         let seg_align_size =
             (ph.mem_size as usize + seg_pad + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
         let seg_start = VirtAddr::from_usize(vaddr);
 
-        // Note that `offset` might not be aligned to 4K here, and it's
-        // backend's responsibility to properly handle it.
-        let backend = Backend::new_cow(
-            seg_start,
-            PageSize::Size4K,
-            FileBackend::Cached(cache.clone()),
-            ph.offset,
-            Some(ph.offset + ph.file_size),
-        );
+        let shared = !textrel && !ph.flags.is_write() && ph.mem_size == ph.file_size;
+        let backend = if shared {
+            let backend = Backend::new_file(
+                seg_start.align_down_4k(),
+                cache.clone(),
+                FileFlags::READ,
+                ph.offset as usize - seg_pad,
+                handle,
+            );
+            if let Backend::File(file) = &backend {
+                let handle = file.futex_handle();
+                let mut shared_text = SHARED_TEXT.lock();
+                shared_text.retain(|it| it.strong_count() > 0);
+                if !shared_text.iter().any(|it| Weak::ptr_eq(it, &handle)) {
+                    shared_text.push(handle);
+                }
+            }
+            backend
+        } else {
+            // Note that `offset` might not be aligned to 4K here, and it's
+            // backend's responsibility to properly handle it.
+            Backend::new_cow(
+                seg_start,
+                PageSize::Size4K,
+                FileBackend::Cached(cache.clone()),
+                ph.offset,
+                Some(ph.offset + ph.file_size),
+            )
+        };
         uspace.map(
             seg_start.align_down_4k(),
             seg_align_size,
@@ -136,6 +343,41 @@ fn map_elf<'a>(
     Ok(elf_parser)
 }
 
+/// The `e_machine` of executables this kernel can run.
+const ELF_MACHINE: u16 = if cfg!(target_arch = "x86_64") {
+    62 // EM_X86_64
+} else if cfg!(target_arch = "aarch64") {
+    183 // EM_AARCH64
+} else if cfg!(target_arch = "riscv64") {
+    243 // EM_RISCV
+} else {
+    258 // EM_LOONGARCH
+};
+
+/// Checks that an ELF file is a 64-bit little-endian executable for this
+/// architecture.
+///
+/// There is no compat layer for 32-bit code (such as armhf binaries on
+/// aarch64), so anything else is refused instead of being mapped and run in
+/// the wrong execution state.
+fn check_elf_target(data: &[u8]) -> AxResult {
+    const ELFCLASS64: u8 = 2;
+    const ELFDATA2LSB: u8 = 1;
+
+    if data.len() < 20 {
+        return Err(AxError::InvalidExecutable);
+    }
+    let machine = u16::from_le_bytes([data[18], data[19]]);
+    if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || machine != ELF_MACHINE {
+        debug!(
+            "Unsupported ELF target: class {}, data {}, machine {}",
+            data[4], data[5], machine
+        );
+        return Err(AxError::InvalidExecutable);
+    }
+    Ok(())
+}
+
 fn map_elf_error(err: &'static str) -> AxError {
     debug!("Failed to parse ELF file: {err}");
     AxError::InvalidExecutable
@@ -157,6 +399,9 @@ impl ElfCacheEntry {
         let mut data = vec![0; 4096];
         let read = cache.read_at(&mut data.as_mut_slice(), 0)?;
         data.truncate(read);
+        if data.starts_with(b"\x7fELF") {
+            check_elf_target(&data)?;
+        }
         match ElfCacheEntry::try_new_or_recover::<AxError>(cache.clone(), data, |data| {
             let builder = ELFHeadersBuilder::new(data).map_err(map_elf_error)?;
             let range = builder.ph_range();
@@ -184,7 +429,12 @@ impl ElfLoader {
         Self(LRUCache::new())
     }
 
-    fn load(&mut self, uspace: &mut AddrSpace, path: &str) -> AxResult<LoadResult> {
+    fn load(
+        &mut self,
+        uspace: &mut AddrSpace,
+        handle: &Arc<Mutex<AddrSpace>>,
+        path: &str,
+    ) -> AxResult<LoadResult> {
         let loc = FS_CONTEXT.lock().resolve(path)?;
 
         if !self.0.touch(|e| e.borrow_cache().location().ptr_eq(&loc)) {
@@ -238,9 +488,9 @@ impl ElfLoader {
             (entry, None)
         };
 
-        let elf = map_elf(uspace, crate::config::USER_SPACE_BASE, elf)?;
+        let elf = map_elf(uspace, handle, crate::config::USER_SPACE_BASE, elf)?;
         let ldso = ldso
-            .map(|elf| map_elf(uspace, crate::config::USER_INTERP_BASE, elf))
+            .map(|elf| map_elf(uspace, handle, crate::config::USER_INTERP_BASE, elf))
             .transpose()?;
 
         let entry = VirtAddr::from_usize(
@@ -264,22 +514,49 @@ pub fn clear_elf_cache() {
     ELF_LOADER.lock().0.clear();
 }
 
+static SHRINKERS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+
+/// Registers `shrinker` to empty a kernel cache when memory runs low or
+/// `vm.drop_caches` is written.
+pub fn register_shrinker(shrinker: fn()) {
+    SHRINKERS.lock().push(shrinker);
+}
+
+/// Empties the kernel caches, returning how many pages that freed.
+///
+/// The page cache itself belongs to `axfs_ng`, which has no interface to
+/// evict pages: only the cached files kept alive by nothing but the emptied
+/// caches are freed, the pages of files still in use stay.
+pub fn shrink_caches() -> usize {
+    let allocator = axalloc::global_allocator();
+    let before = allocator.available_pages();
+    clear_elf_cache();
+    let shrinkers = SHRINKERS.lock().clone();
+    for shrinker in shrinkers {
+        shrinker();
+    }
+    allocator.available_pages().saturating_sub(before)
+}
+
 /// Load the user app to the user address space.
 ///
 /// # Arguments
-/// - `uspace`: The address space of the user app.
+/// - `aspace`: The address space of the user app.
 /// - `args`: The arguments of the user app. The first argument is the path of
 ///   the user app.
 /// - `envs`: The environment variables of the user app.
+/// - `cred`: The credentials the user app will run with, reported through the
+///   auxiliary vector.
 ///
 /// # Returns
 /// - The entry point of the user app.
 /// - The stack pointer of the user app.
 pub fn load_user_app(
-    uspace: &mut AddrSpace,
+    aspace: &Arc<Mutex<AddrSpace>>,
     path: Option<&str>,
     args: &[String],
     envs: &[String],
+    cred: &Credentials,
 ) -> AxResult<(VirtAddr, VirtAddr)> {
     let path = path
         .or_else(|| args.first().map(String::as_str))
@@ -290,10 +567,11 @@ pub fn load_user_app(
         let new_args: Vec<String> = iter::once("/bin/sh".to_owned())
             .chain(args.iter().cloned())
             .collect();
-        return load_user_app(uspace, None, &new_args, envs);
+        return load_user_app(aspace, None, &new_args, envs, cred);
     }
 
-    let (entry, auxv) = match { ELF_LOADER.lock().load(uspace, path)? } {
+    let loaded = ELF_LOADER.lock().load(&mut aspace.lock(), aspace, path)?;
+    let (entry, mut auxv) = match loaded {
         Ok((entry, auxv)) => (entry, auxv),
         Err(data) => {
             if data.starts_with(b"#!") {
@@ -308,11 +586,12 @@ pub fn load_user_app(
                     .chain(iter::once(path.to_owned()))
                     .chain(args.iter().skip(1).cloned())
                     .collect();
-                return load_user_app(uspace, None, &new_args, envs);
+                return load_user_app(aspace, None, &new_args, envs, cred);
             }
             return Err(AxError::InvalidExecutable);
         }
     };
+    let mut uspace = aspace.lock();
 
     let ustack_top = VirtAddr::from_usize(crate::config::USER_STACK_TOP);
     let ustack_size = crate::config::USER_STACK_SIZE;
@@ -330,8 +609,25 @@ pub fn load_user_app(
         Backend::new_alloc(ustack_start, PageSize::Size4K),
     )?;
 
-    let stack_data = app_stack_region(args, envs, &auxv, ustack_top.into());
-    let user_sp = ustack_top - stack_data.len();
+    // The platform string lives above everything else on the stack.
+    let mut stack_base = ustack_top;
+    let platform = if let Some(platform) = auxv::PLATFORM {
+        stack_base = (stack_base - (platform.len() + 1)).align_down(16usize);
+        uspace.populate_area(
+            stack_base.align_down_4k(),
+            (ustack_top - stack_base.align_down_4k()).align_up_4k(),
+            MappingFlags::READ | MappingFlags::WRITE,
+        )?;
+        uspace.write(stack_base, platform.as_bytes())?;
+        uspace.write(stack_base + platform.len(), &[0])?;
+        Some(stack_base.as_usize())
+    } else {
+        None
+    };
+    auxv.extend(auxv::extra_auxv(cred, platform));
+
+    let stack_data = app_stack_region(args, envs, &auxv, stack_base.into());
+    let user_sp = stack_base - stack_data.len();
     let user_sp_aligned = user_sp.align_down_4k();
     uspace.populate_area(
         user_sp_aligned,
@@ -358,9 +654,9 @@ static ACCESSING_USER_MEM: AtomicBool = AtomicBool::new(false);
 /// Enables scoped access into user memory, allowing page faults to occur inside
 /// kernel.
 pub fn access_user_memory<R>(f: impl FnOnce() -> R) -> R {
-    ACCESSING_USER_MEM.store(true, Ordering::Release);
+    let prev = ACCESSING_USER_MEM.swap(true, Ordering::AcqRel);
     let result = f();
-    ACCESSING_USER_MEM.store(false, Ordering::Release);
+    ACCESSING_USER_MEM.store(prev, Ordering::Release);
     result
 }
 
@@ -369,6 +665,58 @@ pub fn is_accessing_user_memory() -> bool {
     ACCESSING_USER_MEM.load(Ordering::Acquire)
 }
 
+/// A fault that stopped a copy between kernel and user memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserCopyFault {
+    /// The number of bytes copied before the fault.
+    pub copied: usize,
+}
+
+impl From<UserCopyFault> for AxError {
+    fn from(_: UserCopyFault) -> Self {
+        AxError::BadAddress
+    }
+}
+
+impl From<UserCopyFault> for VmError {
+    fn from(_: UserCopyFault) -> Self {
+        VmError::AccessDenied
+    }
+}
+
+fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> Result<(), UserCopyFault> {
+    let _guard = IrqSave::new();
+    // `user_copy` resumes after page faults the kernel can handle, and jumps
+    // to its exception table fixup on the others, returning how many bytes
+    // were left uncopied.
+    let left = access_user_memory(|| unsafe { user_copy(dst, src, len) });
+    if unlikely(left != 0) {
+        Err(UserCopyFault {
+            copied: len.saturating_sub(left),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Copies `dst.len()` bytes from the user address `src` into `dst`.
+///
+/// On failure, the first [`UserCopyFault::copied`] bytes of `dst` have been
+/// filled in.
+pub fn copy_from_user(dst: &mut [MaybeUninit<u8>], src: usize) -> Result<(), UserCopyFault> {
+    check_access(src, dst.len()).map_err(|_| UserCopyFault { copied: 0 })?;
+    copy_user(dst.as_mut_ptr().cast(), src as _, dst.len())
+}
+
+/// Copies `src` to the user address `dst`.
+///
+/// On failure, the first [`UserCopyFault::copied`] bytes have reached user
+/// memory.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserCopyFault> {
+    check_access(dst, src.len()).map_err(|_| UserCopyFault { copied: 0 })?;
+    copy_user(dst as _, src.as_ptr(), src.len())
+}
+
 #[allow(dead_code)]
 struct Vm(IrqSave);
 
@@ -390,26 +738,10 @@ unsafe impl VmIo for Vm {
     }
 
     fn read(&mut self, start: usize, buf: &mut [MaybeUninit<u8>]) -> VmResult {
-        check_access(start, buf.len())?;
-        let failed_at = access_user_memory(|| unsafe {
-            user_copy(buf.as_mut_ptr() as *mut _, start as _, buf.len())
-        });
-        if unlikely(failed_at != 0) {
-            Err(VmError::AccessDenied)
-        } else {
-            Ok(())
-        }
+        Ok(copy_from_user(buf, start)?)
     }
 
     fn write(&mut self, start: usize, buf: &[u8]) -> VmResult {
-        check_access(start, buf.len())?;
-        let failed_at = access_user_memory(|| unsafe {
-            user_copy(start as _, buf.as_ptr() as *const _, buf.len())
-        });
-        if unlikely(failed_at != 0) {
-            Err(VmError::AccessDenied)
-        } else {
-            Ok(())
-        }
+        Ok(copy_to_user(start, buf)?)
     }
 }
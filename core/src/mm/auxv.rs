@@ -0,0 +1,146 @@
+//! Architecture-specific auxiliary vector entries.
+
+use alloc::vec::Vec;
+
+use kernel_elf_parser::{AuxEntry, AuxType};
+
+use crate::cred::Credentials;
+
+/// Clock ticks per second reported through `AT_CLKTCK`.
+const CLOCK_TICKS: usize = 100;
+
+/// The `AT_PLATFORM` string, if the architecture defines one.
+#[cfg(target_arch = "x86_64")]
+pub const PLATFORM: Option<&str> = Some("x86_64");
+#[cfg(target_arch = "aarch64")]
+pub const PLATFORM: Option<&str> = Some("aarch64");
+#[cfg(target_arch = "loongarch64")]
+pub const PLATFORM: Option<&str> = Some("loongarch");
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "loongarch64"
+)))]
+pub const PLATFORM: Option<&str> = None;
+
+/// Returns the `AT_HWCAP` and `AT_HWCAP2` words, in the layout Linux uses for
+/// the architecture.
+#[cfg(target_arch = "x86_64")]
+fn hwcap() -> (usize, usize) {
+    // The feature flags from CPUID leaf 1.
+    let edx = unsafe { core::arch::x86_64::__cpuid(1) }.edx;
+    (edx as usize, 0)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn hwcap() -> (usize, usize) {
+    let (isar0, pfr0): (u64, u64);
+    unsafe {
+        core::arch::asm!("mrs {}, ID_AA64ISAR0_EL1", out(reg) isar0);
+        core::arch::asm!("mrs {}, ID_AA64PFR0_EL1", out(reg) pfr0);
+    }
+    let field = |reg: u64, shift: u32| (reg >> shift) & 0xf;
+
+    let mut hwcap = 0;
+    let fp = field(pfr0, 16);
+    let asimd = field(pfr0, 20);
+    if fp != 0xf {
+        hwcap |= 1 << 0; // FP
+        if fp >= 1 {
+            hwcap |= 1 << 9; // FPHP
+        }
+    }
+    if asimd != 0xf {
+        hwcap |= 1 << 1; // ASIMD
+        if asimd >= 1 {
+            hwcap |= 1 << 10; // ASIMDHP
+        }
+    }
+    match field(isar0, 4) {
+        0 => {}
+        1 => hwcap |= 1 << 3,          // AES
+        _ => hwcap |= 1 << 3 | 1 << 4, // AES, PMULL
+    }
+    if field(isar0, 8) >= 1 {
+        hwcap |= 1 << 5; // SHA1
+    }
+    if field(isar0, 12) >= 1 {
+        hwcap |= 1 << 6; // SHA2
+    }
+    if field(isar0, 12) >= 2 {
+        hwcap |= 1 << 21; // SHA512
+    }
+    if field(isar0, 16) >= 1 {
+        hwcap |= 1 << 7; // CRC32
+    }
+    if field(isar0, 20) >= 2 {
+        hwcap |= 1 << 8; // ATOMICS
+    }
+    if field(isar0, 28) >= 1 {
+        hwcap |= 1 << 12; // ASIMDRDM
+    }
+    (hwcap, 0)
+}
+
+#[cfg(target_arch = "riscv64")]
+fn hwcap() -> (usize, usize) {
+    // `misa` can't be read from S-mode, so report the RV64GC baseline every
+    // supported platform provides, as one bit per single-letter extension.
+    let hwcap = b"imafdc".iter().fold(0, |acc, ext| acc | 1 << (ext - b'a'));
+    (hwcap, 0)
+}
+
+#[cfg(target_arch = "loongarch64")]
+fn hwcap() -> (usize, usize) {
+    let cpucfg = |word: usize| {
+        let value: usize;
+        unsafe { core::arch::asm!("cpucfg {}, {}", out(reg) value, in(reg) word) };
+        value
+    };
+    let (cfg1, cfg2) = (cpucfg(1), cpucfg(2));
+
+    let mut hwcap = 1 << 0; // CPUCFG
+    let bits = [
+        (cfg2, 22, 1), // LAM
+        (cfg1, 20, 2), // UAL
+        (cfg2, 0, 3),  // FPU
+        (cfg2, 6, 4),  // LSX
+        (cfg2, 7, 5),  // LASX
+        (cfg1, 25, 6), // CRC32
+        (cfg2, 8, 7),  // COMPLEX
+        (cfg2, 9, 8),  // CRYPTO
+    ];
+    for (cfg, bit, cap) in bits {
+        if cfg & (1 << bit) != 0 {
+            hwcap |= 1 << cap;
+        }
+    }
+    (hwcap, 0)
+}
+
+/// Builds the auxiliary vector entries the ELF parser doesn't provide.
+///
+/// `platform` is the user address of the [`PLATFORM`] string, if any. The
+/// program headers, entry point, interpreter base, `AT_RANDOM` and
+/// `AT_EXECFN` are filled in by the ELF parser itself.
+pub fn extra_auxv(cred: &Credentials, platform: Option<usize>) -> Vec<AuxEntry> {
+    let (hwcap, hwcap2) = hwcap();
+    // Secure mode makes the dynamic linker ignore `LD_PRELOAD` and friends
+    // for set-user-id and set-group-id programs.
+    let secure = cred.euid != cred.uid || cred.egid != cred.gid;
+
+    let mut auxv = alloc::vec![
+        AuxEntry::new(AuxType::HWCAP, hwcap),
+        AuxEntry::new(AuxType::HWCAP2, hwcap2),
+        AuxEntry::new(AuxType::CLKTCK, CLOCK_TICKS),
+        AuxEntry::new(AuxType::UID, cred.uid as usize),
+        AuxEntry::new(AuxType::EUID, cred.euid as usize),
+        AuxEntry::new(AuxType::GID, cred.gid as usize),
+        AuxEntry::new(AuxType::EGID, cred.egid as usize),
+        AuxEntry::new(AuxType::SECURE, secure as usize),
+    ];
+    if let Some(platform) = platform {
+        auxv.push(AuxEntry::new(AuxType::PLATFORM, platform));
+    }
+    auxv
+}
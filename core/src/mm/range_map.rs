@@ -0,0 +1,68 @@
+//! Values attached to ranges of an address space.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ops::Range;
+
+/// A map from disjoint ranges of addresses to values, for the state kept
+/// about parts of an address space beside its areas.
+#[derive(Debug, Clone)]
+pub struct RangeMap<T>(BTreeMap<usize, (usize, T)>);
+
+impl<T> Default for RangeMap<T> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<T: Copy> RangeMap<T> {
+    /// Sets the value of `range`, or removes it if `value` is `None`.
+    ///
+    /// The parts of other ranges outside `range` keep their values.
+    pub fn set(&mut self, range: Range<usize>, value: Option<T>) {
+        let overlapping: Vec<_> = self
+            .0
+            .range(..range.end)
+            .filter(|(_, (end, _))| *end > range.start)
+            .map(|(&start, &(end, value))| (start, end, value))
+            .collect();
+        for (start, end, old) in overlapping {
+            self.0.remove(&start);
+            if start < range.start {
+                self.0.insert(start, (range.start, old));
+            }
+            if end > range.end {
+                self.0.insert(range.end, (end, old));
+            }
+        }
+        if let Some(value) = value {
+            self.0.insert(range.start, (range.end, value));
+        }
+    }
+
+    /// Returns the range containing `addr` and its value.
+    pub fn get(&self, addr: usize) -> Option<(Range<usize>, T)> {
+        self.0
+            .range(..=addr)
+            .next_back()
+            .filter(|(_, (end, _))| addr < *end)
+            .map(|(&start, &(end, value))| (start..end, value))
+    }
+
+    /// Whether any part of `range` has a value.
+    pub fn overlaps(&self, range: Range<usize>) -> bool {
+        self.0
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, (end, _))| *end > range.start)
+    }
+
+    /// Returns the total length of the ranges with values.
+    pub fn total_len(&self) -> usize {
+        self.0.iter().map(|(start, (end, _))| end - start).sum()
+    }
+
+    /// Removes all values.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
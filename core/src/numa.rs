@@ -0,0 +1,200 @@
+//! NUMA topology and memory policies.
+//!
+//! The platform describes its nodes, each a range of CPUs and the physical
+//! memory local to them, with [`register_node`]; until it does, there is a
+//! single node spanning everything. On big.LITTLE SoCs such as the RK3588,
+//! each cluster is made a node.
+//!
+//! Threads choose the nodes their pages come from with a [`MemPolicy`], set
+//! for the thread with `set_mempolicy` or for a range of the address space
+//! with `mbind`. The page allocator asks [`preferred_memory`] where to
+//! allocate from and falls back to any memory once that is exhausted, so
+//! `MPOL_BIND` only makes a preference.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axerrno::{AxError, AxResult};
+use memory_addr::{PhysAddr, PhysAddrRange};
+use spin::{Once, RwLock};
+
+use crate::{mm::RangeMap, task::AsThread};
+
+/// The most nodes there may be, the bits of a node mask.
+pub const MAX_NUMNODES: usize = u64::BITS as usize;
+
+/// The distance between a node and itself, as in ACPI SLIT.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// The distance between two different nodes.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// A NUMA node.
+#[derive(Debug, Clone)]
+pub struct NumaNode {
+    /// The CPUs of the node.
+    pub cpus: Range<usize>,
+    /// The memory local to the node.
+    pub memory: PhysAddrRange,
+}
+
+static NODES: RwLock<Vec<NumaNode>> = RwLock::new(Vec::new());
+static HINT: Once = Once::new();
+
+/// Adds a node, returning its number.
+pub fn register_node(cpus: Range<usize>, memory: PhysAddrRange) -> AxResult<usize> {
+    if cpus.is_empty() || cpus.end > axconfig::plat::CPU_NUM {
+        return Err(AxError::InvalidInput);
+    }
+    let mut nodes = NODES.write();
+    if nodes.len() >= MAX_NUMNODES {
+        return Err(AxError::NoMemory);
+    }
+    nodes.push(NumaNode { cpus, memory });
+    HINT.call_once(|| axalloc::set_page_hint(preferred_memory));
+    info!(
+        "numa: node {} is {:?}",
+        nodes.len() - 1,
+        nodes.last().unwrap()
+    );
+    Ok(nodes.len() - 1)
+}
+
+/// Returns the number of nodes.
+pub fn num_nodes() -> usize {
+    NODES.read().len().max(1)
+}
+
+/// Returns the mask of all nodes.
+pub fn online_nodes() -> u64 {
+    u64::MAX >> (MAX_NUMNODES - num_nodes())
+}
+
+/// Returns the CPUs of `node`.
+pub fn node_cpus(node: usize) -> Range<usize> {
+    match NODES.read().get(node) {
+        Some(node) => node.cpus.clone(),
+        None => 0..axconfig::plat::CPU_NUM,
+    }
+}
+
+/// Returns the node `cpu` belongs to.
+pub fn node_of_cpu(cpu: usize) -> usize {
+    NODES
+        .read()
+        .iter()
+        .position(|node| node.cpus.contains(&cpu))
+        .unwrap_or(0)
+}
+
+/// Returns the node the memory at `paddr` is local to.
+pub fn node_of_paddr(paddr: PhysAddr) -> usize {
+    NODES
+        .read()
+        .iter()
+        .position(|node| node.memory.contains(paddr))
+        .unwrap_or(0)
+}
+
+/// Returns the distance between nodes `from` and `to`.
+pub fn distance(from: usize, to: usize) -> u8 {
+    if from == to {
+        LOCAL_DISTANCE
+    } else {
+        REMOTE_DISTANCE
+    }
+}
+
+/// The memory policy modes, `MPOL_*`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PolicyMode {
+    /// Allocate on the node of the CPU running the thread, or fall back to
+    /// the policy of the thread for a range.
+    #[default]
+    Default    = 0,
+    /// Allocate on the first node of the mask, or the local node if it is
+    /// empty.
+    Preferred  = 1,
+    /// Allocate on the nodes of the mask only.
+    Bind       = 2,
+    /// Allocate on the nodes of the mask in turn.
+    Interleave = 3,
+    /// Allocate on the node of the CPU running the thread.
+    Local      = 4,
+}
+
+/// A memory policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemPolicy {
+    /// The mode.
+    pub mode: PolicyMode,
+    /// The nodes, as a mask.
+    pub nodes: u64,
+}
+
+impl MemPolicy {
+    /// Creates a policy from `mode` and `nodes` as passed to
+    /// `set_mempolicy`, checking that they go together.
+    pub fn new(mode: u32, nodes: u64) -> AxResult<Self> {
+        let mode = match mode {
+            0 => PolicyMode::Default,
+            1 => PolicyMode::Preferred,
+            2 => PolicyMode::Bind,
+            3 => PolicyMode::Interleave,
+            4 => PolicyMode::Local,
+            _ => return Err(AxError::InvalidInput),
+        };
+        if nodes & !online_nodes() != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let valid = match mode {
+            PolicyMode::Default | PolicyMode::Local => nodes == 0,
+            PolicyMode::Preferred => true,
+            PolicyMode::Bind | PolicyMode::Interleave => nodes != 0,
+        };
+        if !valid {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(Self { mode, nodes })
+    }
+
+    /// Whether memory on `node` is allowed by the policy.
+    pub fn allows(&self, node: usize) -> bool {
+        self.mode != PolicyMode::Bind || self.nodes & (1 << node) != 0
+    }
+
+    /// Returns the node to allocate from, `index` counting the pages
+    /// allocated under an interleaving policy.
+    pub fn node(&self, index: usize) -> usize {
+        let local = node_of_cpu(axhal::percpu::this_cpu_id());
+        match self.mode {
+            PolicyMode::Default | PolicyMode::Local => local,
+            PolicyMode::Preferred if self.nodes == 0 => local,
+            PolicyMode::Bind if self.allows(local) => local,
+            PolicyMode::Preferred | PolicyMode::Bind => self.nodes.trailing_zeros() as usize,
+            PolicyMode::Interleave => {
+                let nth = index % self.nodes.count_ones() as usize;
+                (0..MAX_NUMNODES)
+                    .filter(|node| self.nodes & (1 << node) != 0)
+                    .nth(nth)
+                    .unwrap_or(local)
+            }
+        }
+    }
+}
+
+/// The policies set with `mbind` for ranges of an address space.
+pub type RangePolicies = RangeMap<MemPolicy>;
+
+/// Returns the memory to allocate pages from for the current thread, or
+/// `None` for any. Called by the page allocator.
+pub fn preferred_memory() -> Option<PhysAddrRange> {
+    // Registering a node may allocate with the lock held.
+    let nodes = NODES.try_read()?;
+    if nodes.len() < 2 {
+        return None;
+    }
+    let curr = axtask::current_may_uninit()?;
+    let node = curr.try_as_thread()?.next_alloc_node();
+    nodes.get(node).map(|node| node.memory)
+}
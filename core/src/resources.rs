@@ -2,11 +2,19 @@
 
 use core::ops::{Index, IndexMut};
 
-use linux_raw_sys::general::{RLIM_NLIMITS, RLIMIT_NOFILE, RLIMIT_STACK};
+use linux_raw_sys::general::{
+    RLIM_NLIMITS, RLIMIT_MEMLOCK, RLIMIT_NOFILE, RLIMIT_SIGPENDING, RLIMIT_STACK,
+};
 
 /// The maximum number of open files
 pub const AX_FILE_LIMIT: usize = 1024;
 
+/// The maximum number of queued realtime signals per process
+pub const AX_SIGPENDING_LIMIT: usize = 4096;
+
+/// The maximum number of bytes of memory a process may lock
+pub const AX_MEMLOCK_LIMIT: usize = 8 * 1024 * 1024;
+
 /// The limit for a specific resource
 #[derive(Default)]
 pub struct Rlimit {
@@ -43,6 +51,8 @@ impl Default for Rlimits {
         let mut result = Self(Default::default());
         result[RLIMIT_STACK] = (crate::config::USER_STACK_SIZE as u64).into();
         result[RLIMIT_NOFILE] = (AX_FILE_LIMIT as u64).into();
+        result[RLIMIT_SIGPENDING] = (AX_SIGPENDING_LIMIT as u64).into();
+        result[RLIMIT_MEMLOCK] = (AX_MEMLOCK_LIMIT as u64).into();
         result
     }
 }
@@ -0,0 +1,162 @@
+//! Kernel tunables, exposed under `/proc/sys`.
+
+use core::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use axerrno::{AxError, AxResult};
+
+use crate::resources::AX_FILE_LIMIT;
+
+/// A numeric kernel tunable.
+pub struct Sysctl {
+    /// The path of the knob below `/proc/sys`, e.g. `vm/overcommit_memory`.
+    pub path: &'static str,
+    value: AtomicUsize,
+    range: RangeInclusive<usize>,
+}
+
+impl Sysctl {
+    const fn new(path: &'static str, default: usize, range: RangeInclusive<usize>) -> Self {
+        Self {
+            path,
+            value: AtomicUsize::new(default),
+            range,
+        }
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Sets the value, which must be within the knob's valid range.
+    pub fn set(&self, value: usize) -> AxResult<()> {
+        if !self.range.contains(&value) {
+            return Err(AxError::InvalidInput);
+        }
+        self.value.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Overcommit policy: 0 is heuristic, 1 always overcommits, 2 never does.
+pub static OVERCOMMIT_MEMORY: Sysctl = Sysctl::new("vm/overcommit_memory", 0, 0..=2);
+
+/// The amount of free memory, in KiB, below which the OOM killer steps in.
+pub static MIN_FREE_KBYTES: Sysctl = Sysctl::new("vm/min_free_kbytes", 4096, 128..=1048576);
+
+/// Whether running out of memory panics instead of killing a process.
+pub static PANIC_ON_OOM: Sysctl = Sysctl::new("vm/panic_on_oom", 0, 0..=1);
+
+/// Whether memory may be both writable and executable: 0 allows it, 1 logs
+/// offenders, 2 denies them too.
+pub static ENFORCE_WX: Sysctl = Sysctl::new("vm/enforce_wx", 0, 0..=2);
+
+/// What to print on a panic besides the backtrace, as a mask of Linux's
+/// bits. Only `0x10`, the trace buffers, is supported.
+pub static PANIC_PRINT: Sysctl = Sysctl::new("kernel/panic_print", 0, 0..=0x7f);
+
+/// What to do once the init process has exited: 0 powers off, 1 restarts
+/// and 2 panics, as Linux does.
+pub static INIT_EXIT_ACTION: Sysctl = Sysctl::new("kernel/init_exit_action", 0, 0..=2);
+
+/// The maximum number of file descriptors open in the whole system. Only
+/// privileged processes may open more.
+pub static FILE_MAX: Sysctl = Sysctl::new("fs/file-max", AX_FILE_LIMIT * 64, 1..=i32::MAX as usize);
+
+/// The largest size an unprivileged process may give a pipe with
+/// `F_SETPIPE_SZ`.
+pub static PIPE_MAX_SIZE: Sysctl =
+    Sysctl::new("fs/pipe-max-size", 1048576, 4096..=i32::MAX as usize);
+
+/// One more than the largest PID.
+pub static PID_MAX: Sysctl = Sysctl::new("kernel/pid_max", 32768, 301..=4194304);
+
+/// The upper bound of a socket's listen backlog.
+pub static SOMAXCONN: Sysctl = Sysctl::new("net/core/somaxconn", 4096, 0..=i32::MAX as usize);
+
+/// Which sides of TCP Fast Open are enabled, a combination of
+/// [`TFO_CLIENT_ENABLE`] and [`TFO_SERVER_ENABLE`].
+pub static TCP_FASTOPEN: Sysctl = Sysctl::new("net/ipv4/tcp_fastopen", TFO_CLIENT_ENABLE, 0..=3);
+
+/// The bit of [`TCP_FASTOPEN`] enabling it for clients.
+pub const TFO_CLIENT_ENABLE: usize = 1;
+/// The bit of [`TCP_FASTOPEN`] enabling it for servers.
+pub const TFO_SERVER_ENABLE: usize = 2;
+
+/// The `SO_BUSY_POLL` new sockets start with: how many microseconds a
+/// blocking receive spins before sleeping.
+pub static BUSY_READ: Sysctl = Sysctl::new("net/core/busy_read", 0, 0..=i32::MAX as usize);
+
+/// How many microseconds `poll`, `select` and `epoll_wait` spin before
+/// sleeping.
+pub static BUSY_POLL: Sysctl = Sysctl::new("net/core/busy_poll", 0, 0..=i32::MAX as usize);
+
+/// The most messages a POSIX message queue may hold, unless created by a
+/// privileged process.
+pub static MQ_MSG_MAX: Sysctl = Sysctl::new("fs/mqueue/msg_max", 10, 1..=65536);
+
+/// The largest message a POSIX message queue may take, unless created by a
+/// privileged process.
+pub static MQ_MSGSIZE_MAX: Sysctl = Sysctl::new("fs/mqueue/msgsize_max", 8192, 128..=16777216);
+
+/// The most POSIX message queues there may be.
+pub static MQ_QUEUES_MAX: Sysctl = Sysctl::new("fs/mqueue/queues_max", 256, 0..=i32::MAX as usize);
+
+/// How many messages a POSIX message queue holds if `mq_open` is given no
+/// attributes.
+pub static MQ_MSG_DEFAULT: Sysctl = Sysctl::new("fs/mqueue/msg_default", 10, 1..=65536);
+
+/// How large a message a POSIX message queue takes if `mq_open` is given no
+/// attributes.
+pub static MQ_MSGSIZE_DEFAULT: Sysctl =
+    Sysctl::new("fs/mqueue/msgsize_default", 8192, 128..=16777216);
+
+/// How many seconds the holder of a file lease gets to give it up once it is
+/// broken, before it is revoked.
+pub static LEASE_BREAK_TIME: Sysctl = Sysctl::new("fs/lease-break-time", 45, 0..=i32::MAX as usize);
+
+/// The most events the native AIO contexts of the system may hold together.
+pub static AIO_MAX_NR: Sysctl = Sysctl::new("fs/aio-max-nr", 65536, 0..=i32::MAX as usize);
+
+/// The most a sequentially read file is read ahead into the page cache, in
+/// KiB.
+pub static READ_AHEAD_KB: Sysctl = Sysctl::new("vm/read_ahead_kb", 128, 0..=65536);
+
+/// Writing 1, 2 or 3 writes back dirty data and empties the kernel caches.
+///
+/// The pages cached for files still in use are not dropped, see
+/// [`crate::mm::shrink_caches`].
+pub static DROP_CACHES: Sysctl = Sysctl::new("vm/drop_caches", 0, 0..=4);
+
+/// Whether syscalls are counted and timed for `/proc/syscalls`.
+pub static SYSCALL_STATS: Sysctl = Sysctl::new("kernel/syscall_stats", 0, 0..=1);
+
+/// All registered tunables.
+pub static SYSCTLS: &[&Sysctl] = &[
+    &OVERCOMMIT_MEMORY,
+    &MIN_FREE_KBYTES,
+    &PANIC_ON_OOM,
+    &ENFORCE_WX,
+    &PANIC_PRINT,
+    &INIT_EXIT_ACTION,
+    &FILE_MAX,
+    &PIPE_MAX_SIZE,
+    &PID_MAX,
+    &SOMAXCONN,
+    &TCP_FASTOPEN,
+    &BUSY_READ,
+    &BUSY_POLL,
+    &MQ_MSG_MAX,
+    &MQ_MSGSIZE_MAX,
+    &MQ_QUEUES_MAX,
+    &MQ_MSG_DEFAULT,
+    &MQ_MSGSIZE_DEFAULT,
+    &LEASE_BREAK_TIME,
+    &AIO_MAX_NR,
+    &READ_AHEAD_KB,
+    &DROP_CACHES,
+    &SYSCALL_STATS,
+];
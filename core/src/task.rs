@@ -1,5 +1,6 @@
 //! User task management.
 
+mod rusage;
 mod stat;
 
 use alloc::{
@@ -11,10 +12,12 @@ use alloc::{
 use core::{
     cell::RefCell,
     ops::Deref,
-    sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering},
 };
 
 use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::Location;
+use axhal::time::monotonic_time_nanos;
 use axmm::AddrSpace;
 use axpoll::PollSet;
 use axsync::{Mutex, spin::SpinNoIrq};
@@ -22,22 +25,36 @@ use axtask::{AxTaskRef, TaskExt, TaskInner, WeakAxTaskRef, current};
 use extern_trait::extern_trait;
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
+use linux_raw_sys::general::{RLIMIT_SIGPENDING, SI_USER, SIG_IGN, kernel_sigaction};
+use memory_addr::PAGE_SIZE_4K;
 use scope_local::{ActiveScope, Scope};
 use spin::RwLock;
 use starry_process::{Pid, Process, ProcessGroup, Session};
 use starry_signal::{
-    SignalInfo, Signo,
+    SignalInfo, SignalStack, Signo,
     api::{ProcessSignalManager, SignalActions, ThreadSignalManager},
 };
 use weak_map::WeakMap;
 
-pub use self::stat::TaskStat;
+pub use self::{rusage::Rusage, stat::TaskStat};
 use crate::{
+    cred::Credentials,
     futex::{FutexKey, FutexTable},
+    keys::Key,
+    mm::RangeMap,
+    numa::{MemPolicy, PolicyMode, RangePolicies},
     resources::Rlimits,
-    time::{TimeManager, TimerState},
+    time::{ITimers, TimeManager, TimerState},
 };
 
+/// Personality flag that makes every readable mapping executable.
+pub const READ_IMPLIES_EXEC: u32 = 0x040_0000;
+/// Personality flag that disables address space randomization.
+pub const ADDR_NO_RANDOMIZE: u32 = 0x004_0000;
+/// Personality flags dropped when executing a set-user-id or set-group-id
+/// program.
+pub const PER_CLEAR_ON_SETID: u32 = READ_IMPLIES_EXEC | ADDR_NO_RANDOMIZE | 0x020_0000 | 0x010_0000;
+
 ///  A wrapper type that assumes the inner type is `Sync`.
 #[repr(transparent)]
 pub struct AssumeSync<T>(pub T);
@@ -57,6 +74,9 @@ pub struct ThreadInner {
     /// The process data shared by all threads in the process.
     pub proc_data: Arc<ProcessData>,
 
+    /// The thread ID
+    tid: u32,
+
     /// The clear thread tid field
     ///
     /// See <https://manpages.debian.org/unstable/manpages-dev/set_tid_address.2.en.html#clear_child_tid>
@@ -72,9 +92,16 @@ pub struct ThreadInner {
     /// sequences.
     rseq_area: AtomicUsize,
 
+    /// The signature that must precede every rseq abort handler.
+    rseq_sig: AtomicU32,
+
     /// The thread-level signal manager
     pub signal: Arc<ThreadSignalManager>,
 
+    /// The alternate signal stack disarmed by `SS_AUTODISARM` while a
+    /// handler runs on it.
+    pub disarmed_stack: SpinNoIrq<Option<SignalStack>>,
+
     /// Time manager
     ///
     /// This is assumed to be `Sync` because it's only borrowed mutably during
@@ -84,6 +111,42 @@ pub struct ThreadInner {
     /// The OOM score adjustment value.
     oom_score_adj: AtomicI32,
 
+    /// The I/O priority set with `ioprio_set`, as its class and level, or 0
+    /// if none was.
+    ioprio: AtomicU32,
+
+    /// The memory policy set with `set_mempolicy`.
+    mempolicy: SpinNoIrq<MemPolicy>,
+    /// The policy overriding `mempolicy` while serving a fault in a range
+    /// with its own, and the index of the page in the range.
+    fault_policy: SpinNoIrq<Option<(MemPolicy, usize)>>,
+    /// The number of pages allocated under an interleaving `mempolicy`.
+    interleave_index: AtomicUsize,
+
+    /// The user PC and SP at the last trap into the kernel
+    user_pc: AtomicUsize,
+    user_sp: AtomicUsize,
+
+    /// The number of minor page faults
+    min_flt: AtomicU64,
+    /// The number of major page faults
+    maj_flt: AtomicU64,
+
+    /// When the thread was last switched in, or 0 while it is switched out
+    on_cpu_since: AtomicU64,
+    /// The time spent running, up to the last switch out
+    on_cpu_ns: AtomicU64,
+    /// The number of times the thread was switched out
+    nr_switches: AtomicU64,
+
+    /// When the thread was last switched out, or 0 while it runs
+    off_cpu_since: AtomicU64,
+    /// The time spent switched out since the last [`set_timer_state`]
+    off_cpu_ns: AtomicU64,
+
+    /// The thread keyring
+    pub keyring: SpinNoIrq<Option<Arc<Key>>>,
+
     /// Ready to exit
     exit: AtomicBool,
 }
@@ -93,16 +156,38 @@ impl ThreadInner {
     pub fn new(tid: u32, proc_data: Arc<ProcessData>) -> Self {
         ThreadInner {
             signal: ThreadSignalManager::new(tid, proc_data.signal.clone()),
+            disarmed_stack: SpinNoIrq::new(None),
             proc_data,
+            tid,
             clear_child_tid: AtomicUsize::new(0),
             robust_list_head: AtomicUsize::new(0),
             rseq_area: AtomicUsize::new(0),
+            rseq_sig: AtomicU32::new(0),
             time: AssumeSync(RefCell::new(TimeManager::new())),
             oom_score_adj: AtomicI32::new(200),
+            ioprio: AtomicU32::new(0),
+            mempolicy: SpinNoIrq::new(MemPolicy::default()),
+            fault_policy: SpinNoIrq::new(None),
+            interleave_index: AtomicUsize::new(0),
+            user_pc: AtomicUsize::new(0),
+            user_sp: AtomicUsize::new(0),
+            min_flt: AtomicU64::new(0),
+            maj_flt: AtomicU64::new(0),
+            on_cpu_since: AtomicU64::new(0),
+            on_cpu_ns: AtomicU64::new(0),
+            nr_switches: AtomicU64::new(0),
+            off_cpu_since: AtomicU64::new(0),
+            off_cpu_ns: AtomicU64::new(0),
+            keyring: SpinNoIrq::new(None),
             exit: AtomicBool::new(false),
         }
     }
 
+    /// Get the thread ID.
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
     /// Get the clear child tid field.
     pub fn clear_child_tid(&self) -> usize {
         self.clear_child_tid.load(Ordering::Relaxed)
@@ -135,6 +220,16 @@ impl ThreadInner {
         self.rseq_area.store(addr, Ordering::SeqCst);
     }
 
+    /// Get the rseq abort handler signature.
+    pub fn rseq_sig(&self) -> u32 {
+        self.rseq_sig.load(Ordering::SeqCst)
+    }
+
+    /// Set the rseq abort handler signature.
+    pub fn set_rseq_sig(&self, sig: u32) {
+        self.rseq_sig.store(sig, Ordering::SeqCst);
+    }
+
     /// Get the oom score adjustment value.
     pub fn oom_score_adj(&self) -> i32 {
         self.oom_score_adj.load(Ordering::SeqCst)
@@ -145,6 +240,107 @@ impl ThreadInner {
         self.oom_score_adj.store(value, Ordering::SeqCst);
     }
 
+    /// Get the I/O priority.
+    pub fn ioprio(&self) -> u32 {
+        self.ioprio.load(Ordering::Relaxed)
+    }
+
+    /// Set the I/O priority.
+    pub fn set_ioprio(&self, ioprio: u32) {
+        self.ioprio.store(ioprio, Ordering::Relaxed);
+    }
+
+    /// Get the memory policy.
+    pub fn mempolicy(&self) -> MemPolicy {
+        *self.mempolicy.lock()
+    }
+
+    /// Set the memory policy.
+    pub fn set_mempolicy(&self, policy: MemPolicy) {
+        *self.mempolicy.lock() = policy;
+    }
+
+    /// Sets the policy of the range a fault is being served in, or clears it
+    /// once done.
+    pub fn set_fault_policy(&self, policy: Option<(MemPolicy, usize)>) {
+        *self.fault_policy.lock() = policy;
+    }
+
+    /// Returns the node to allocate the next page from.
+    pub fn next_alloc_node(&self) -> usize {
+        if let Some((policy, index)) = *self.fault_policy.lock() {
+            return policy.node(index);
+        }
+        let policy = self.mempolicy();
+        let index = if policy.mode == PolicyMode::Interleave {
+            self.interleave_index.fetch_add(1, Ordering::Relaxed)
+        } else {
+            0
+        };
+        policy.node(index)
+    }
+
+    /// Notes where the thread was in user space when it trapped into the
+    /// kernel.
+    pub fn set_user_context(&self, pc: usize, sp: usize) {
+        self.user_pc.store(pc, Ordering::Relaxed);
+        self.user_sp.store(sp, Ordering::Relaxed);
+    }
+
+    /// Returns the user PC and SP at the last trap into the kernel.
+    pub fn user_context(&self) -> (usize, usize) {
+        (
+            self.user_pc.load(Ordering::Relaxed),
+            self.user_sp.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Counts a page fault served for the thread.
+    pub fn record_page_fault(&self, major: bool) {
+        if major {
+            self.maj_flt.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.min_flt.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of minor and major page faults served for the
+    /// thread.
+    pub fn page_faults(&self) -> (u64, u64) {
+        (
+            self.min_flt.load(Ordering::Relaxed),
+            self.maj_flt.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Returns the time the thread has spent on the CPU, in nanoseconds.
+    pub fn cpu_time_ns(&self) -> u64 {
+        let since = self.on_cpu_since.load(Ordering::Relaxed);
+        let running = if since != 0 {
+            monotonic_time_nanos() - since
+        } else {
+            0
+        };
+        self.on_cpu_ns.load(Ordering::Relaxed) + running
+    }
+
+    /// Returns the number of times the thread was switched out.
+    pub fn context_switches(&self) -> u64 {
+        self.nr_switches.load(Ordering::Relaxed)
+    }
+
+    /// Returns the resource usage of the thread.
+    pub fn rusage(&self) -> Rusage {
+        let (utime, stime) = self.time.borrow().output();
+        Rusage {
+            utime,
+            stime,
+            maxrss: self.proc_data.max_rss_pages(),
+            minflt: self.min_flt.load(Ordering::Relaxed),
+            majflt: self.maj_flt.load(Ordering::Relaxed),
+        }
+    }
+
     /// Check if the thread is ready to exit.
     pub fn pending_exit(&self) -> bool {
         self.exit.load(Ordering::Acquire)
@@ -170,6 +366,14 @@ impl Deref for Thread {
 #[extern_trait]
 unsafe impl TaskExt for Thread {
     fn on_enter(&self) {
+        let now = monotonic_time_nanos();
+        let since = self.off_cpu_since.swap(0, Ordering::Relaxed);
+        if since != 0 {
+            self.off_cpu_ns.fetch_add(now - since, Ordering::Relaxed);
+        }
+        self.on_cpu_since.store(now, Ordering::Relaxed);
+        crate::trace::sched_switch(self.tid);
+
         let scope = self.proc_data.scope.read();
         unsafe { ActiveScope::set(&scope) };
         core::mem::forget(scope);
@@ -178,6 +382,15 @@ unsafe impl TaskExt for Thread {
     fn on_leave(&self) {
         ActiveScope::set_global();
         unsafe { self.proc_data.scope.force_read_decrement() };
+
+        let now = monotonic_time_nanos();
+        let since = self.on_cpu_since.swap(0, Ordering::Relaxed);
+        if since != 0 {
+            self.on_cpu_ns.fetch_add(now - since, Ordering::Relaxed);
+        }
+        self.nr_switches.fetch_add(1, Ordering::Relaxed);
+        self.off_cpu_since.store(now, Ordering::Relaxed);
+        crate::trace::sched_out(self.tid);
     }
 }
 
@@ -211,8 +424,12 @@ pub struct ProcessData {
     pub proc: Arc<Process>,
     /// The executable path
     pub exe_path: RwLock<String>,
+    /// The executable, which /proc/[pid]/exe links to
+    pub exe: RwLock<Location>,
     /// The command line arguments
     pub cmdline: RwLock<Arc<Vec<String>>>,
+    /// The environment the process was executed with
+    pub environ: RwLock<Arc<Vec<String>>>,
     /// The virtual memory address space.
     // TODO: scopify
     pub aspace: Arc<Mutex<AddrSpace>>,
@@ -222,25 +439,72 @@ pub struct ProcessData {
     heap_bottom: AtomicUsize,
     /// The user heap top
     heap_top: AtomicUsize,
+    /// The number of pages allocated for the process's memory
+    rss: AtomicUsize,
+    /// The peak of `rss`
+    max_rss: AtomicUsize,
 
     /// The resource limits
     pub rlim: RwLock<Rlimits>,
+    /// The user and group identities
+    pub cred: RwLock<Credentials>,
+    /// Whether the process may be core dumped or ptrace-attached
+    dumpable: AtomicBool,
+    /// The signal sent to the process when its parent exits, or 0
+    pdeath_signal: AtomicU32,
+    /// The process keyring
+    pub keyring: SpinNoIrq<Option<Arc<Key>>>,
 
     /// The child exit wait event
     pub child_exit_event: Arc<PollSet>,
     /// Self exit event
     pub exit_event: Arc<PollSet>,
+    /// Woken whenever a thread of the process exits
+    pub thread_exit_event: Arc<PollSet>,
+    /// Whether a thread has started tearing the whole process down
+    group_exiting: AtomicBool,
+    /// Whether the parent is suspended until the process, a `vfork` child,
+    /// execs or exits
+    vfork_pending: AtomicBool,
+    /// Woken when the process releases its `vfork` parent
+    pub vfork_done: Arc<PollSet>,
     /// The exit signal of the thread
     pub exit_signal: Option<Signo>,
 
     /// The process signal manager
     pub signal: Arc<ProcessSignalManager>,
+    /// The number of realtime signals queued to the process or its threads
+    sigqueue_len: AtomicUsize,
 
     /// The futex table.
     futex_table: Arc<FutexTable>,
 
+    /// The interval timers
+    pub itimers: SpinNoIrq<ITimers>,
+
+    /// The resource usage of threads that have exited
+    exited_usage: SpinNoIrq<Rusage>,
+    /// The resource usage of children that have been waited for
+    children_usage: SpinNoIrq<Rusage>,
+    /// The final resource usage of children that have exited but not been
+    /// waited for yet
+    zombie_usage: SpinNoIrq<HashMap<Pid, Rusage>>,
+
     /// The default mask for file permissions.
     umask: AtomicU32,
+    /// The execution domain and compatibility flags set by `personality`.
+    personality: AtomicU32,
+
+    /// The memory policies set with `mbind`.
+    pub range_policies: SpinNoIrq<RangePolicies>,
+    /// The ranges that have been mapped writable, which may not be made
+    /// executable under `vm/enforce_wx`.
+    pub written_ranges: SpinNoIrq<RangeMap<()>>,
+    /// The ranges locked into memory with `mlock` or `mlockall`.
+    pub locked_ranges: SpinNoIrq<RangeMap<()>>,
+    /// The `MCL_FUTURE` and `MCL_ONFAULT` flags of the last `mlockall`,
+    /// which new mappings are locked with.
+    mlockall_flags: AtomicU32,
 }
 
 impl ProcessData {
@@ -248,7 +512,9 @@ impl ProcessData {
     pub fn new(
         proc: Arc<Process>,
         exe_path: String,
+        exe: Location,
         cmdline: Arc<Vec<String>>,
+        environ: Arc<Vec<String>>,
         aspace: Arc<Mutex<AddrSpace>>,
         signal_actions: Arc<SpinNoIrq<SignalActions>>,
         exit_signal: Option<Signo>,
@@ -256,29 +522,171 @@ impl ProcessData {
         Arc::new(Self {
             proc,
             exe_path: RwLock::new(exe_path),
+            exe: RwLock::new(exe),
             cmdline: RwLock::new(cmdline),
+            environ: RwLock::new(environ),
             aspace,
             scope: RwLock::new(Scope::new()),
             heap_bottom: AtomicUsize::new(crate::config::USER_HEAP_BASE),
             heap_top: AtomicUsize::new(crate::config::USER_HEAP_BASE),
+            rss: AtomicUsize::new(0),
+            max_rss: AtomicUsize::new(0),
 
             rlim: RwLock::default(),
+            cred: RwLock::default(),
+            dumpable: AtomicBool::new(true),
+            pdeath_signal: AtomicU32::new(0),
+            keyring: SpinNoIrq::new(None),
 
             child_exit_event: Arc::default(),
             exit_event: Arc::default(),
+            thread_exit_event: Arc::default(),
+            group_exiting: AtomicBool::new(false),
+            vfork_pending: AtomicBool::new(false),
+            vfork_done: Arc::default(),
             exit_signal,
 
             signal: Arc::new(ProcessSignalManager::new(
                 signal_actions,
                 crate::config::SIGNAL_TRAMPOLINE,
             )),
+            sigqueue_len: AtomicUsize::new(0),
 
             futex_table: Arc::new(FutexTable::new()),
 
+            itimers: SpinNoIrq::new(ITimers::default()),
+
+            exited_usage: SpinNoIrq::new(Rusage::default()),
+            children_usage: SpinNoIrq::new(Rusage::default()),
+            zombie_usage: SpinNoIrq::new(HashMap::new()),
+
             umask: AtomicU32::new(0o022),
+            personality: AtomicU32::new(0),
+
+            range_policies: SpinNoIrq::new(RangePolicies::default()),
+            written_ranges: SpinNoIrq::new(RangeMap::default()),
+            locked_ranges: SpinNoIrq::new(RangeMap::default()),
+            mlockall_flags: AtomicU32::new(0),
         })
     }
 
+    /// Returns the policy `mbind` set for the page at `addr` and the index
+    /// of the page in the range it was set for.
+    pub fn range_policy(&self, addr: usize) -> Option<(MemPolicy, usize)> {
+        let (range, policy) = self.range_policies.lock().get(addr)?;
+        Some((policy, (addr - range.start) / PAGE_SIZE_4K))
+    }
+
+    /// Returns the number of pages mapped in the process's address space.
+    pub fn rss_pages(&self) -> usize {
+        self.rss.load(Ordering::Relaxed)
+    }
+
+    /// Adjusts the resident set size by `delta` pages.
+    pub fn add_rss(&self, delta: isize) {
+        if let Ok(rss) = self
+            .rss
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |rss| {
+                Some(rss.saturating_add_signed(delta))
+            })
+        {
+            self.max_rss
+                .fetch_max(rss.saturating_add_signed(delta), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the peak resident set size in pages.
+    pub fn max_rss_pages(&self) -> usize {
+        self.max_rss.load(Ordering::Relaxed)
+    }
+
+    /// Returns the resource usage of the whole process.
+    pub fn rusage(&self) -> Rusage {
+        let exited = *self.exited_usage.lock();
+        self.proc
+            .threads()
+            .into_iter()
+            .filter_map(|tid| get_task(tid).ok())
+            .fold(exited, |acc, task| acc.collate(task.as_thread().rusage()))
+    }
+
+    /// Returns the resource usage of the children that have been waited
+    /// for.
+    pub fn children_rusage(&self) -> Rusage {
+        *self.children_usage.lock()
+    }
+
+    /// Keeps the resource usage of an exited thread, which is no longer
+    /// listed in the process.
+    pub fn account_thread_exit(&self, thread: &ThreadInner) {
+        let mut usage = self.exited_usage.lock();
+        *usage = usage.collate(thread.rusage());
+    }
+
+    /// Records the final resource usage of the exited child `pid`.
+    pub fn add_zombie_rusage(&self, pid: Pid, usage: Rusage) {
+        self.zombie_usage.lock().insert(pid, usage);
+    }
+
+    /// Returns the final resource usage of the exited child `pid`.
+    ///
+    /// If `reap` is set, the child is being waited for and its usage is
+    /// added to that of the children.
+    pub fn child_rusage(&self, pid: Pid, reap: bool) -> Rusage {
+        let mut zombies = self.zombie_usage.lock();
+        if !reap {
+            return zombies.get(&pid).copied().unwrap_or_default();
+        }
+        let usage = zombies.remove(&pid).unwrap_or_default();
+        let mut children = self.children_usage.lock();
+        *children = children.collate(usage);
+        usage
+    }
+
+    /// Forgets the resident set, e.g. when `execve` replaces the address
+    /// space.
+    pub fn reset_rss(&self) {
+        self.rss.store(0, Ordering::Relaxed);
+    }
+
+    /// Accounts for a signal about to be queued to the process or one of its
+    /// threads.
+    ///
+    /// Only realtime signals queue up, so only they count against
+    /// `RLIMIT_SIGPENDING`, and not when they are ignored, which drops them
+    /// instead. Like on Linux, `kill` always succeeds; other senders get
+    /// `EAGAIN` once the limit is reached.
+    ///
+    /// Each charged signal must be uncharged once it leaves the queue, be it
+    /// delivered, waited for or discarded.
+    pub fn charge_signal(&self, sig: &SignalInfo) -> AxResult<()> {
+        if !sig.signo().is_realtime() || self.ignores(sig.signo()) {
+            return Ok(());
+        }
+        let limit = self.rlim.read()[RLIMIT_SIGPENDING].current as usize;
+        let queued = self.sigqueue_len.fetch_add(1, Ordering::AcqRel);
+        if queued >= limit && sig.code() != SI_USER as i32 {
+            self.sigqueue_len.fetch_sub(1, Ordering::AcqRel);
+            return Err(AxError::WouldBlock);
+        }
+        Ok(())
+    }
+
+    /// Whether the process ignores `signo`.
+    fn ignores(&self, signo: Signo) -> bool {
+        let action: kernel_sigaction = self.signal.actions.lock()[signo].clone().into();
+        action.sa_handler_kernel.map_or(0, |f| f as usize) == SIG_IGN as usize
+    }
+
+    /// Releases the accounting of a signal taken off the queue.
+    pub fn uncharge_signal(&self, signo: Signo) {
+        if signo.is_realtime() {
+            let _ = self
+                .sigqueue_len
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        }
+    }
+
     /// Get the bottom address of the user heap.
     pub fn get_heap_bottom(&self) -> usize {
         self.heap_bottom.load(Ordering::Acquire)
@@ -319,6 +727,76 @@ impl ProcessData {
         }
     }
 
+    /// Updates the credentials through `f`, committing them only if it
+    /// succeeds.
+    ///
+    /// Like on Linux, a process whose effective or filesystem ids change stops
+    /// being dumpable and loses its parent-death signal.
+    pub fn update_cred<R>(&self, f: impl FnOnce(&mut Credentials) -> AxResult<R>) -> AxResult<R> {
+        let mut cred = self.cred.write();
+        let mut new = cred.clone();
+        let result = f(&mut new)?;
+        if (new.euid, new.egid, new.fsuid, new.fsgid)
+            != (cred.euid, cred.egid, cred.fsuid, cred.fsgid)
+        {
+            self.set_dumpable(false);
+            self.set_pdeath_signal(None);
+        }
+        *cred = new;
+        Ok(result)
+    }
+
+    /// Whether the process is exiting as a whole, so that its threads
+    /// should stop at the next chance instead of doing anything more.
+    pub fn group_exiting(&self) -> bool {
+        self.group_exiting.load(Ordering::Acquire)
+    }
+
+    /// Marks the process as exiting as a whole, returning whether it wasn't
+    /// already, i.e. whether the caller is the one to stop the other threads.
+    pub fn start_group_exit(&self) -> bool {
+        !self.group_exiting.swap(true, Ordering::AcqRel)
+    }
+
+    /// Whether the parent of the process, a `vfork` child, still waits for
+    /// it to exec or exit.
+    pub fn vfork_pending(&self) -> bool {
+        self.vfork_pending.load(Ordering::Acquire)
+    }
+
+    /// Makes the parent of the process wait for it to exec or exit.
+    pub fn set_vfork_pending(&self) {
+        self.vfork_pending.store(true, Ordering::Release);
+    }
+
+    /// Lets the `vfork` parent of the process, if any, go on.
+    pub fn release_vfork(&self) {
+        if self.vfork_pending.swap(false, Ordering::AcqRel) {
+            self.vfork_done.wake();
+        }
+    }
+
+    /// Whether the process is dumpable.
+    pub fn dumpable(&self) -> bool {
+        self.dumpable.load(Ordering::SeqCst)
+    }
+
+    /// Set whether the process is dumpable.
+    pub fn set_dumpable(&self, dumpable: bool) {
+        self.dumpable.store(dumpable, Ordering::SeqCst);
+    }
+
+    /// Returns the signal sent to the process when its parent exits.
+    pub fn pdeath_signal(&self) -> Option<Signo> {
+        Signo::from_repr(self.pdeath_signal.load(Ordering::SeqCst) as u8)
+    }
+
+    /// Sets the signal sent to the process when its parent exits.
+    pub fn set_pdeath_signal(&self, signo: Option<Signo>) {
+        self.pdeath_signal
+            .store(signo.map_or(0, |signo| signo as u32), Ordering::SeqCst);
+    }
+
     /// Get the umask.
     pub fn umask(&self) -> u32 {
         self.umask.load(Ordering::SeqCst)
@@ -333,6 +811,26 @@ impl ProcessData {
     pub fn replace_umask(&self, umask: u32) -> u32 {
         self.umask.swap(umask, Ordering::SeqCst)
     }
+
+    /// Get the personality.
+    pub fn personality(&self) -> u32 {
+        self.personality.load(Ordering::SeqCst)
+    }
+
+    /// Returns the flags new mappings are locked with.
+    pub fn mlockall_flags(&self) -> u32 {
+        self.mlockall_flags.load(Ordering::SeqCst)
+    }
+
+    /// Sets the flags new mappings are locked with.
+    pub fn set_mlockall_flags(&self, flags: u32) {
+        self.mlockall_flags.store(flags, Ordering::SeqCst);
+    }
+
+    /// Set the personality and return the old value.
+    pub fn replace_personality(&self, personality: u32) -> u32 {
+        self.personality.swap(personality, Ordering::SeqCst)
+    }
 }
 
 struct FutexTables {
@@ -455,21 +953,10 @@ pub fn get_session(sid: Pid) -> AxResult<Arc<Session>> {
     SESSION_TABLE.read().get(&sid).ok_or(AxError::NoSuchProcess)
 }
 
-/// Poll the timer
-pub fn poll_timer(task: &TaskInner) {
-    let Some(thr) = task.try_as_thread() else {
-        return;
-    };
-    let Ok(mut time) = thr.time.try_borrow_mut() else {
-        // reentrant borrow, likely IRQ
-        return;
-    };
-    time.poll(|signo| {
-        send_signal_thread_inner(task, thr, SignalInfo::new_kernel(signo));
-    });
-}
-
 /// Sets the timer state.
+///
+/// The time since the last change is charged to the thread and to the
+/// CPU-time interval timers of its process.
 pub fn set_timer_state(task: &TaskInner, state: TimerState) {
     let Some(thr) = task.try_as_thread() else {
         return;
@@ -478,10 +965,20 @@ pub fn set_timer_state(task: &TaskInner, state: TimerState) {
         // reentrant borrow, likely IRQ
         return;
     };
-    time.poll(|signo| {
-        send_signal_thread_inner(task, thr, SignalInfo::new_kernel(signo));
-    });
+    let off_cpu_ns = thr.off_cpu_ns.swap(0, Ordering::Relaxed);
+    let (user_ns, kernel_ns) = time.poll(off_cpu_ns as usize);
     time.set_state(state);
+    drop(time);
+
+    if user_ns + kernel_ns > 0 {
+        let pid = thr.proc_data.proc.pid();
+        thr.proc_data
+            .itimers
+            .lock()
+            .account(user_ns, kernel_ns, |signo| {
+                let _ = send_signal_to_process(pid, Some(SignalInfo::new_kernel(signo)));
+            });
+    }
 }
 
 fn send_signal_thread_inner(task: &TaskInner, thr: &Thread, sig: SignalInfo) {
@@ -500,6 +997,7 @@ pub fn send_signal_to_thread(tgid: Option<Pid>, tid: Pid, sig: Option<SignalInfo
 
     if let Some(sig) = sig {
         info!("Send signal {:?} to thread {}", sig.signo(), tid);
+        thread.proc_data.charge_signal(&sig)?;
         send_signal_thread_inner(&task, thread, sig);
     }
 
@@ -513,6 +1011,7 @@ pub fn send_signal_to_process(pid: Pid, sig: Option<SignalInfo>) -> AxResult<()>
     if let Some(sig) = sig {
         let signo = sig.signo();
         info!("Send signal {:?} to process {}", signo, pid);
+        proc_data.charge_signal(&sig)?;
         if let Some(tid) = proc_data.signal.send_signal(sig)
             && let Ok(task) = get_task(tid)
         {
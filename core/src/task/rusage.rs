@@ -0,0 +1,31 @@
+use axhal::time::TimeValue;
+
+/// Resource usage of a thread, a process, or the children of a process.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Rusage {
+    /// Time spent in user mode.
+    pub utime: TimeValue,
+    /// Time spent in kernel mode.
+    pub stime: TimeValue,
+    /// The largest resident set size, in pages.
+    pub maxrss: usize,
+    /// Page faults served without any I/O.
+    pub minflt: u64,
+    /// Page faults that needed I/O.
+    pub majflt: u64,
+}
+
+impl Rusage {
+    /// Adds up the usage of `self` and `other`.
+    ///
+    /// Times and fault counts are summed, while `maxrss` is the largest of
+    /// the two.
+    pub fn collate(mut self, other: Rusage) -> Self {
+        self.utime += other.utime;
+        self.stime += other.stime;
+        self.maxrss = self.maxrss.max(other.maxrss);
+        self.minflt += other.minflt;
+        self.majflt += other.majflt;
+        self
+    }
+}
@@ -1,4 +1,5 @@
 use alloc::{borrow::ToOwned, fmt, string::String};
+use core::time::Duration;
 
 use axerrno::AxResult;
 use axtask::{TaskInner, TaskState};
@@ -84,6 +85,8 @@ impl TaskStat {
         let ppid = proc.parent().map_or(0, |p| p.pid());
         let pgrp = proc.group().pgid();
         let session = proc.group().session().sid();
+        let usage = proc_data.rusage();
+        let children = proc_data.children_rusage();
         Ok(Self {
             pid,
             comm: comm.to_owned(),
@@ -91,12 +94,41 @@ impl TaskStat {
             ppid,
             pgrp,
             session,
+            minflt: usage.minflt,
+            cminflt: children.minflt,
+            majflt: usage.majflt,
+            cmajflt: children.majflt,
+            utime: ticks(usage.utime),
+            stime: ticks(usage.stime),
+            cutime: ticks(children.utime),
+            cstime: ticks(children.stime),
             num_threads: proc.threads().len() as u32,
+            rss: proc_data.rss_pages() as i64,
             exit_signal: proc_data.exit_signal.unwrap_or(Signo::SIGCHLD) as u8,
             exit_code: proc.exit_code(),
             ..Default::default()
         })
     }
+
+    /// Create a new [`TaskStat`] of a single thread, as in
+    /// `/proc/[pid]/task/[tid]/stat`: the ID, times and faults are those of
+    /// the thread rather than of its process.
+    pub fn from_single_thread(task: &TaskInner) -> AxResult<Self> {
+        let usage = task.as_thread().rusage();
+        Ok(Self {
+            pid: task.id().as_u64() as u32,
+            minflt: usage.minflt,
+            majflt: usage.majflt,
+            utime: ticks(usage.utime),
+            stime: ticks(usage.stime),
+            ..Self::from_thread(task)?
+        })
+    }
+}
+
+/// Converts a time to clock ticks of 1/100 s, the unit of times in `stat`.
+fn ticks(time: Duration) -> u64 {
+    (time.as_millis() / 10) as u64
 }
 
 impl fmt::Display for TaskStat {
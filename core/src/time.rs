@@ -1,20 +1,16 @@
 //! Time management module.
 
-use alloc::{borrow::ToOwned, collections::binary_heap::BinaryHeap, sync::Arc};
-use core::{mem, time::Duration};
+use alloc::sync::{Arc, Weak};
+use core::time::Duration;
 
-use axhal::time::{NANOS_PER_SEC, TimeValue, monotonic_time_nanos, wall_time};
-use axtask::{
-    WeakAxTaskRef, current,
-    future::{block_on, timeout_at},
-};
-use event_listener::{Event, listener};
-use lazy_static::lazy_static;
-use spin::Mutex;
-use starry_signal::Signo;
+use axhal::time::{NANOS_PER_SEC, TimeValue, monotonic_time, monotonic_time_nanos};
+use starry_signal::{SignalInfo, Signo};
 use strum::FromRepr;
 
-use crate::task::poll_timer;
+use crate::{
+    task::{ProcessData, send_signal_to_process},
+    timer::{TimerHandle, add_timer},
+};
 
 fn time_value_from_nanos(nanos: usize) -> TimeValue {
     let secs = nanos as u64 / NANOS_PER_SEC;
@@ -22,32 +18,6 @@ fn time_value_from_nanos(nanos: usize) -> TimeValue {
     TimeValue::new(secs, nsecs as u32)
 }
 
-struct Entry {
-    deadline: Duration,
-    task: WeakAxTaskRef,
-}
-impl PartialEq for Entry {
-    fn eq(&self, other: &Self) -> bool {
-        self.deadline == other.deadline
-    }
-}
-impl Eq for Entry {}
-impl PartialOrd for Entry {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(other.deadline.cmp(&self.deadline))
-    }
-}
-impl Ord for Entry {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        other.deadline.cmp(&self.deadline)
-    }
-}
-
-lazy_static! {
-    static ref ALARM_LIST: Mutex<BinaryHeap<Entry>> = Mutex::new(BinaryHeap::new());
-    static ref EVENT_NEW_TIMER: Event = Event::new();
-}
-
 /// The type of interval timer.
 #[repr(i32)]
 #[allow(non_camel_case_types)]
@@ -72,23 +42,15 @@ impl ITimerType {
     }
 }
 
+/// A timer counting down in CPU time.
 #[derive(Default)]
-struct ITimer {
+struct CpuITimer {
     interval_ns: usize,
     remained_ns: usize,
 }
 
-impl ITimer {
-    pub fn new(interval_ns: usize, remained_ns: usize) -> Self {
-        let result = Self {
-            interval_ns,
-            remained_ns,
-        };
-        result.renew_timer();
-        result
-    }
-
-    pub fn update(&mut self, delta: usize) -> bool {
+impl CpuITimer {
+    fn update(&mut self, delta: usize) -> bool {
         if self.remained_ns == 0 {
             return false;
         }
@@ -97,25 +59,140 @@ impl ITimer {
             false
         } else {
             self.remained_ns = self.interval_ns;
-            self.renew_timer();
             true
         }
     }
+}
+
+/// A timer counting down in wall time, fired from the timer wheel.
+#[derive(Default)]
+struct RealITimer {
+    interval: Duration,
+    deadline: Option<Duration>,
+    timer: Option<TimerHandle>,
+    /// Bumped on every rearm, so that a firing already under way for an old
+    /// setting is ignored.
+    generation: u64,
+}
 
-    pub fn renew_timer(&self) {
-        if self.remained_ns > 0 {
-            let deadline = wall_time() + Duration::from_nanos(self.remained_ns as u64);
-            let mut guard = ALARM_LIST.lock();
-            let should_wake = guard.peek().is_none_or(|it| it.deadline > deadline);
-            guard.push(Entry {
-                deadline,
-                task: Arc::downgrade(&current()),
-            });
-            drop(guard);
-            if should_wake {
-                EVENT_NEW_TIMER.notify(1);
+impl RealITimer {
+    fn arm(&mut self, proc_data: Weak<ProcessData>, deadline: Option<Duration>) {
+        if let Some(timer) = self.timer.take() {
+            timer.cancel();
+        }
+        self.generation += 1;
+        self.deadline = deadline;
+        if let Some(deadline) = deadline {
+            let generation = self.generation;
+            self.timer = Some(add_timer(deadline, move || {
+                fire_real_itimer(proc_data, generation)
+            }));
+        }
+    }
+}
+
+fn fire_real_itimer(proc_data: Weak<ProcessData>, generation: u64) {
+    let Some(data) = proc_data.upgrade() else {
+        return;
+    };
+    {
+        let mut itimers = data.itimers.lock();
+        let real = &mut itimers.real;
+        if real.generation != generation {
+            return;
+        }
+        let next = real
+            .deadline
+            .filter(|_| !real.interval.is_zero())
+            .map(|deadline| (deadline + real.interval).max(monotonic_time()));
+        real.timer = None;
+        real.arm(proc_data, next);
+    }
+    let _ = send_signal_to_process(
+        data.proc.pid(),
+        Some(SignalInfo::new_kernel(ITimerType::Real.signo())),
+    );
+}
+
+/// The interval timers of a process.
+///
+/// `ITIMER_REAL` counts down in wall time, while `ITIMER_VIRTUAL` and
+/// `ITIMER_PROF` count down in the CPU time consumed by all threads of the
+/// process, as reported to [`ITimers::account`].
+#[derive(Default)]
+pub struct ITimers {
+    real: RealITimer,
+    virt: CpuITimer,
+    prof: CpuITimer,
+}
+
+impl ITimers {
+    /// Gets the interval and remaining time of the timer.
+    pub fn get(&self, ty: ITimerType) -> (TimeValue, TimeValue) {
+        match ty {
+            ITimerType::Real => (
+                self.real.interval,
+                self.real
+                    .deadline
+                    .map_or(Duration::ZERO, |it| it.saturating_sub(monotonic_time())),
+            ),
+            ITimerType::Virtual => (
+                time_value_from_nanos(self.virt.interval_ns),
+                time_value_from_nanos(self.virt.remained_ns),
+            ),
+            ITimerType::Prof => (
+                time_value_from_nanos(self.prof.interval_ns),
+                time_value_from_nanos(self.prof.remained_ns),
+            ),
+        }
+    }
+
+    /// Sets the interval and remaining time of the timer of `proc_data`,
+    /// returning the old ones.
+    pub fn set(
+        proc_data: &Arc<ProcessData>,
+        ty: ITimerType,
+        interval: Duration,
+        remained: Duration,
+    ) -> (TimeValue, TimeValue) {
+        let mut itimers = proc_data.itimers.lock();
+        let old = itimers.get(ty);
+        match ty {
+            ITimerType::Real => {
+                itimers.real.interval = interval;
+                let deadline = (!remained.is_zero()).then(|| monotonic_time() + remained);
+                itimers.real.arm(Arc::downgrade(proc_data), deadline);
+            }
+            ITimerType::Virtual | ITimerType::Prof => {
+                let timer = if ty == ITimerType::Virtual {
+                    &mut itimers.virt
+                } else {
+                    &mut itimers.prof
+                };
+                timer.interval_ns = interval.as_nanos() as usize;
+                timer.remained_ns = remained.as_nanos() as usize;
             }
         }
+        old
+    }
+
+    /// Charges CPU time to the CPU-time timers and calls `emitter` with the
+    /// signal of every timer that expired.
+    pub fn account(&mut self, user_ns: usize, kernel_ns: usize, mut emitter: impl FnMut(Signo)) {
+        if self.virt.update(user_ns) {
+            emitter(ITimerType::Virtual.signo());
+        }
+        if self.prof.update(user_ns + kernel_ns) {
+            emitter(ITimerType::Prof.signo());
+        }
+    }
+}
+
+impl Drop for ITimers {
+    fn drop(&mut self) {
+        if let Some(timer) = &self.real.timer {
+            timer.cancel();
+        }
     }
 }
 
@@ -130,14 +207,12 @@ pub enum TimerState {
     Kernel,
 }
 
-// TODO(mivik): preempting does not change the timer state currently
-/// A manager for time-related operations.
+/// Accounts the CPU time of a thread.
 pub struct TimeManager {
     utime_ns: usize,
     stime_ns: usize,
     last_wall_ns: usize,
     state: TimerState,
-    itimers: [ITimer; 3],
 }
 
 impl Default for TimeManager {
@@ -153,7 +228,6 @@ impl TimeManager {
             stime_ns: 0,
             last_wall_ns: 0,
             state: TimerState::None,
-            itimers: Default::default(),
         }
     }
 
@@ -164,113 +238,29 @@ impl TimeManager {
         (utime, stime)
     }
 
-    /// Polls the time manager to update the timers and emit signals if
-    /// necessary.
-    pub fn poll(&mut self, emitter: impl Fn(Signo)) {
+    /// Charges the time since the last poll to the current state, leaving
+    /// out the `off_cpu_ns` the thread spent switched out.
+    ///
+    /// Returns the user and kernel time charged.
+    pub fn poll(&mut self, off_cpu_ns: usize) -> (usize, usize) {
         let now_ns = monotonic_time_nanos() as usize;
-        let delta = now_ns - self.last_wall_ns;
+        let delta = (now_ns - self.last_wall_ns).saturating_sub(off_cpu_ns);
+        self.last_wall_ns = now_ns;
         match self.state {
             TimerState::User => {
                 self.utime_ns += delta;
-                self.update_itimer(ITimerType::Virtual, delta, &emitter);
-                self.update_itimer(ITimerType::Prof, delta, &emitter);
+                (delta, 0)
             }
             TimerState::Kernel => {
                 self.stime_ns += delta;
-                self.update_itimer(ITimerType::Prof, delta, &emitter);
+                (0, delta)
             }
-            TimerState::None => {}
+            TimerState::None => (0, 0),
         }
-        self.update_itimer(ITimerType::Real, delta, &emitter);
-        self.last_wall_ns = now_ns;
     }
 
     /// Updates the timer state.
     pub fn set_state(&mut self, state: TimerState) {
         self.state = state;
     }
-
-    /// Sets the interval timer of the specified type with the given interval
-    /// and remaining time.
-    pub fn set_itimer(
-        &mut self,
-        ty: ITimerType,
-        interval_ns: usize,
-        remained_ns: usize,
-    ) -> (TimeValue, TimeValue) {
-        let old = mem::replace(
-            &mut self.itimers[ty as usize],
-            ITimer::new(interval_ns, remained_ns),
-        );
-        (
-            time_value_from_nanos(old.interval_ns),
-            time_value_from_nanos(old.remained_ns),
-        )
-    }
-
-    /// Gets the current interval and remaining time.
-    pub fn get_itimer(&self, ty: ITimerType) -> (TimeValue, TimeValue) {
-        let itimer = &self.itimers[ty as usize];
-        (
-            time_value_from_nanos(itimer.interval_ns),
-            time_value_from_nanos(itimer.remained_ns),
-        )
-    }
-
-    fn update_itimer(&mut self, ty: ITimerType, delta: usize, emitter: impl Fn(Signo)) {
-        if self.itimers[ty as usize].update(delta) {
-            emitter(ty.signo());
-        }
-    }
-}
-
-async fn alarm_task() {
-    loop {
-        let guard = ALARM_LIST.lock();
-        let Some(entry) = guard.peek() else {
-            drop(guard);
-            listener!(EVENT_NEW_TIMER => listener);
-
-            if !ALARM_LIST.lock().is_empty() {
-                continue;
-            }
-            listener.await;
-
-            continue;
-        };
-
-        let now = wall_time();
-        if entry.deadline <= now {
-            let entry_deadline = entry.deadline;
-            if let Some(task) = entry.task.upgrade() {
-                drop(guard);
-                poll_timer(&task);
-            } else {
-                drop(guard);
-            }
-            let mut guard = ALARM_LIST.lock();
-            assert!(guard.pop().is_some_and(|it| it.deadline == entry_deadline));
-        } else {
-            let deadline = entry.deadline;
-            drop(guard);
-            listener!(EVENT_NEW_TIMER => listener);
-            if ALARM_LIST
-                .lock()
-                .peek()
-                .is_none_or(|it| it.deadline != deadline)
-            {
-                continue;
-            }
-            let _ = timeout_at(Some(deadline), listener).await;
-        }
-    }
-}
-
-/// Spawns the alarm task.
-pub fn spawn_alarm_task() {
-    axtask::spawn_raw(
-        || block_on(alarm_task()),
-        "alarm_task".to_owned(),
-        axconfig::TASK_STACK_SIZE,
-    );
 }
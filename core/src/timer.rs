@@ -0,0 +1,309 @@
+//! Kernel-internal timers and deferred work.
+//!
+//! Timers are kept in a hierarchical timer wheel and fired by a single
+//! `ksoftirqd` task, which also runs work deferred with [`defer`]. Callbacks
+//! therefore never run in interrupt context and may take sleeping locks, but
+//! they should not block for long as they delay every other timer.
+
+use alloc::{borrow::ToOwned, boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    array, mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axhal::time::{NANOS_PER_MILLIS, monotonic_time, monotonic_time_nanos, wall_time};
+use axtask::future::{block_on, timeout_at};
+use event_listener::{Event, listener};
+use kspin::SpinNoIrq;
+use lazy_static::lazy_static;
+use slab::Slab;
+
+/// The resolution of the timer wheel.
+const TICK_NANOS: u64 = NANOS_PER_MILLIS;
+
+const LEVEL_BITS: u32 = 6;
+const LEVEL_SLOTS: usize = 1 << LEVEL_BITS;
+const LEVELS: usize = 5;
+
+/// How many ticks one slot of `level` spans.
+const fn granularity(level: usize) -> u64 {
+    1 << (LEVEL_BITS as usize * level)
+}
+
+fn duration_to_tick(time: Duration) -> u64 {
+    (time.as_nanos() as u64).div_ceil(TICK_NANOS)
+}
+
+fn tick_to_duration(tick: u64) -> Duration {
+    Duration::from_nanos(tick.saturating_mul(TICK_NANOS))
+}
+
+fn current_tick() -> u64 {
+    monotonic_time_nanos() / TICK_NANOS
+}
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+struct TimerEntry {
+    id: u64,
+    expires: u64,
+    level: usize,
+    slot: usize,
+    callback: Callback,
+}
+
+/// A hierarchical timer wheel.
+///
+/// Level `n` has [`LEVEL_SLOTS`] slots, each spanning `64^n` ticks. A timer
+/// is filed at the lowest level that covers its remaining time and moves
+/// down a level each time its slot comes around, so adding and cancelling
+/// are O(1) and only the slots due are ever looked at.
+struct TimerWheel {
+    now: u64,
+    next_id: u64,
+    timers: Slab<TimerEntry>,
+    levels: [[Vec<usize>; LEVEL_SLOTS]; LEVELS],
+}
+
+impl TimerWheel {
+    fn new() -> Self {
+        Self {
+            now: current_tick(),
+            next_id: 0,
+            timers: Slab::new(),
+            levels: array::from_fn(|_| array::from_fn(|_| Vec::new())),
+        }
+    }
+
+    fn add(&mut self, expires: u64, callback: Callback) -> TimerHandle {
+        let id = self.next_id;
+        self.next_id += 1;
+        let key = self.timers.insert(TimerEntry {
+            id,
+            expires,
+            level: 0,
+            slot: 0,
+            callback,
+        });
+        self.file(key);
+        TimerHandle { key, id }
+    }
+
+    /// Puts the timer `key` into the slot matching its remaining time.
+    fn file(&mut self, key: usize) {
+        let entry = &mut self.timers[key];
+        // Timers that are already due fire on the next tick.
+        let expires = entry.expires.max(self.now + 1);
+        let delta = expires - self.now;
+        let level = (0..LEVELS)
+            .find(|&level| delta < granularity(level + 1))
+            .unwrap_or(LEVELS - 1);
+        // Timers beyond the range of the wheel park in the farthest slot and
+        // are refiled when it comes around.
+        let expires = expires.min(self.now + granularity(LEVELS) - 1);
+        entry.level = level;
+        entry.slot = (expires >> (LEVEL_BITS as usize * level)) as usize % LEVEL_SLOTS;
+        self.levels[level][entry.slot].push(key);
+    }
+
+    fn cancel(&mut self, handle: &TimerHandle) -> bool {
+        let Some(entry) = self.timers.get(handle.key) else {
+            return false;
+        };
+        if entry.id != handle.id {
+            return false;
+        }
+        let (level, slot) = (entry.level, entry.slot);
+        self.levels[level][slot].retain(|&key| key != handle.key);
+        self.timers.remove(handle.key);
+        true
+    }
+
+    fn level_empty(&self, level: usize) -> bool {
+        self.levels[level].iter().all(Vec::is_empty)
+    }
+
+    /// Advances the wheel to `target` and collects the callbacks of every
+    /// timer that expired on the way.
+    fn advance(&mut self, target: u64, expired: &mut Vec<Callback>) {
+        while self.now < target {
+            // Nothing happens until the next slot of the lowest non-empty
+            // level comes around, so skip straight to it.
+            let next = match (0..LEVELS).find(|&level| !self.level_empty(level)) {
+                Some(0) => self.now + 1,
+                Some(level) => (self.now | (granularity(level) - 1)) + 1,
+                None => target,
+            };
+            self.now = next.min(target);
+
+            // Refile the timers of the higher levels whose slot is due, then
+            // fire the timers of the current tick.
+            for level in (1..LEVELS).rev() {
+                if self.now % granularity(level) != 0 {
+                    continue;
+                }
+                let slot = (self.now >> (LEVEL_BITS as usize * level)) as usize % LEVEL_SLOTS;
+                for key in mem::take(&mut self.levels[level][slot]) {
+                    if self.timers[key].expires <= self.now {
+                        expired.push(self.timers.remove(key).callback);
+                    } else {
+                        self.file(key);
+                    }
+                }
+            }
+            let slot = self.now as usize % LEVEL_SLOTS;
+            for key in mem::take(&mut self.levels[0][slot]) {
+                expired.push(self.timers.remove(key).callback);
+            }
+        }
+    }
+
+    fn next_expiry(&self) -> Option<u64> {
+        self.timers.iter().map(|(_, entry)| entry.expires).min()
+    }
+}
+
+/// A handle to a timer added with [`add_timer`].
+pub struct TimerHandle {
+    key: usize,
+    id: u64,
+}
+
+impl TimerHandle {
+    /// Cancels the timer.
+    ///
+    /// Returns `false` if it has already fired or been cancelled.
+    pub fn cancel(&self) -> bool {
+        TIMER_WHEEL.lock().cancel(self)
+    }
+}
+
+lazy_static! {
+    static ref TIMER_WHEEL: SpinNoIrq<TimerWheel> = SpinNoIrq::new(TimerWheel::new());
+}
+static DEFERRED: SpinNoIrq<Vec<Callback>> = SpinNoIrq::new(Vec::new());
+static KSOFTIRQD_EVENT: Event = Event::new();
+
+/// Calls `callback` from `ksoftirqd` once the monotonic clock reaches
+/// `deadline`.
+pub fn add_timer(deadline: Duration, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    let handle = TIMER_WHEEL
+        .lock()
+        .add(duration_to_tick(deadline), Box::new(callback));
+    KSOFTIRQD_EVENT.notify(1);
+    handle
+}
+
+/// Runs `work` later from `ksoftirqd`.
+pub fn defer(work: impl FnOnce() + Send + 'static) {
+    DEFERRED.lock().push(Box::new(work));
+    KSOFTIRQD_EVENT.notify(1);
+}
+
+async fn ksoftirqd() {
+    let mut expired = Vec::new();
+    loop {
+        for work in mem::take(&mut *DEFERRED.lock()) {
+            work();
+        }
+        TIMER_WHEEL.lock().advance(current_tick(), &mut expired);
+        for callback in expired.drain(..) {
+            callback();
+        }
+
+        listener!(KSOFTIRQD_EVENT => listener);
+        if !DEFERRED.lock().is_empty() {
+            continue;
+        }
+        let next = TIMER_WHEEL.lock().next_expiry();
+        match next {
+            Some(next) if next <= current_tick() => continue,
+            // The scheduler sleeps on the wall clock.
+            Some(next) => {
+                let deadline =
+                    wall_time() + tick_to_duration(next).saturating_sub(monotonic_time());
+                let _ = timeout_at(Some(deadline), listener).await;
+            }
+            None => listener.await,
+        }
+    }
+}
+
+/// Spawns the `ksoftirqd` task.
+pub fn spawn_ksoftirqd() {
+    axtask::spawn_raw(
+        || block_on(ksoftirqd()),
+        "ksoftirqd".to_owned(),
+        axconfig::TASK_STACK_SIZE,
+    );
+}
+
+/// A future that completes once the monotonic clock reaches a deadline.
+pub struct Sleep {
+    deadline: Duration,
+    timer: Option<(TimerHandle, Arc<SpinNoIrq<Option<Waker>>>)>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if monotonic_time() >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.timer {
+            Some((_, waker)) => {
+                waker.lock().replace(cx.waker().clone());
+            }
+            None => {
+                let waker = Arc::new(SpinNoIrq::new(Some(cx.waker().clone())));
+                let handle = add_timer(self.deadline, {
+                    let waker = waker.clone();
+                    move || {
+                        if let Some(waker) = waker.lock().take() {
+                            waker.wake();
+                        }
+                    }
+                });
+                self.timer = Some((handle, waker));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((handle, _)) = &self.timer {
+            handle.cancel();
+        }
+    }
+}
+
+/// Returns a future that completes at `deadline` on the monotonic clock.
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Runs `f` for at most `timeout`, failing with [`AxError::TimedOut`] if it
+/// does not complete in time. `None` means no timeout.
+pub async fn timeout<F: Future>(timeout: Option<Duration>, f: F) -> AxResult<F::Output> {
+    let Some(timeout) = timeout else {
+        return Ok(f.await);
+    };
+    let mut f = core::pin::pin!(f);
+    let mut sleep = core::pin::pin!(sleep_until(monotonic_time() + timeout));
+    core::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = f.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        sleep.as_mut().poll(cx).map(|_| Err(AxError::TimedOut))
+    })
+    .await
+}
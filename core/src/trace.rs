@@ -0,0 +1,374 @@
+//! Static tracepoints.
+//!
+//! The kernel records a few events, syscall entry and exit, context switches
+//! and user page faults, into a ring buffer per CPU. Each event is disabled by
+//! default and is turned on through `/sys/kernel/tracing`, where the buffers
+//! are read back in the format of Linux's ftrace.
+//!
+//! A tracepoint costs a single atomic load while its event is disabled. When
+//! a buffer is full, the oldest entries are overwritten.
+
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use axconfig::plat::CPU_NUM;
+use axhal::{percpu::this_cpu_id, time::monotonic_time_nanos};
+use axpoll::PollSet;
+use axsync::spin::SpinNoIrq;
+use lazy_static::lazy_static;
+
+/// The number of entries each per-CPU buffer holds.
+const BUFFER_ENTRIES: usize = 4096;
+
+/// A static tracepoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Tracepoint {
+    /// `syscalls:sys_enter`
+    SysEnter,
+    /// `syscalls:sys_exit`
+    SysExit,
+    /// `sched:sched_switch`
+    SchedSwitch,
+    /// `exceptions:page_fault_user`
+    PageFaultUser,
+}
+
+impl Tracepoint {
+    /// All tracepoints.
+    pub const ALL: [Tracepoint; 4] = [
+        Tracepoint::SysEnter,
+        Tracepoint::SysExit,
+        Tracepoint::SchedSwitch,
+        Tracepoint::PageFaultUser,
+    ];
+
+    /// Returns the subsystem the tracepoint belongs to.
+    pub fn system(self) -> &'static str {
+        match self {
+            Tracepoint::SysEnter | Tracepoint::SysExit => "syscalls",
+            Tracepoint::SchedSwitch => "sched",
+            Tracepoint::PageFaultUser => "exceptions",
+        }
+    }
+
+    /// Returns the name of the tracepoint.
+    pub fn name(self) -> &'static str {
+        match self {
+            Tracepoint::SysEnter => "sys_enter",
+            Tracepoint::SysExit => "sys_exit",
+            Tracepoint::SchedSwitch => "sched_switch",
+            Tracepoint::PageFaultUser => "page_fault_user",
+        }
+    }
+
+    /// Returns whether the tracepoint is enabled.
+    pub fn enabled(self) -> bool {
+        ENABLED[self as usize].load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables the tracepoint.
+    pub fn set_enabled(self, enabled: bool) {
+        ENABLED[self as usize].store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// A recorded event.
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    /// A syscall was entered.
+    SysEnter {
+        /// The syscall number.
+        nr: usize,
+        /// The arguments.
+        args: [usize; 6],
+    },
+    /// A syscall returned.
+    SysExit {
+        /// The syscall number.
+        nr: usize,
+        /// The return value.
+        ret: isize,
+    },
+    /// The CPU switched from one thread to another.
+    SchedSwitch {
+        /// The thread switched out, or 0 if none ran before on this CPU.
+        prev: u32,
+        /// The thread switched in.
+        next: u32,
+    },
+    /// A user access faulted.
+    PageFaultUser {
+        /// The faulting address.
+        address: usize,
+        /// The faulting instruction.
+        ip: usize,
+        /// The access flags of the fault.
+        error_code: usize,
+    },
+}
+
+impl Event {
+    fn tracepoint(&self) -> Tracepoint {
+        match self {
+            Event::SysEnter { .. } => Tracepoint::SysEnter,
+            Event::SysExit { .. } => Tracepoint::SysExit,
+            Event::SchedSwitch { .. } => Tracepoint::SchedSwitch,
+            Event::PageFaultUser { .. } => Tracepoint::PageFaultUser,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.tracepoint().name())?;
+        match *self {
+            Event::SysEnter { nr, args } => write!(
+                f,
+                "NR {nr} ({:x}, {:x}, {:x}, {:x}, {:x}, {:x})",
+                args[0], args[1], args[2], args[3], args[4], args[5]
+            ),
+            Event::SysExit { nr, ret } => write!(f, "NR {nr} = {ret}"),
+            Event::SchedSwitch { prev, next } => {
+                write!(f, "prev_pid={prev} ==> next_pid={next}")
+            }
+            Event::PageFaultUser {
+                address,
+                ip,
+                error_code,
+            } => write!(
+                f,
+                "address=0x{address:x} ip=0x{ip:x} error_code=0x{error_code:x}"
+            ),
+        }
+    }
+}
+
+/// An entry in a trace buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    /// When the event happened, in nanoseconds since boot.
+    pub timestamp: u64,
+    /// The CPU the event happened on.
+    pub cpu: usize,
+    /// The thread the event happened in.
+    pub tid: u32,
+    comm: [u8; 16],
+    /// The event.
+    pub event: Event,
+}
+
+impl Entry {
+    /// Returns the name of the task the event happened in, if it was known.
+    pub fn comm(&self) -> Option<&str> {
+        let len = self.comm.iter().position(|&b| b == 0).unwrap_or(16);
+        (len > 0).then(|| core::str::from_utf8(&self.comm[..len]).unwrap_or("?"))
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let task = format!("{}-{}", self.comm().unwrap_or("<...>"), self.tid);
+        writeln!(
+            f,
+            "{task:>22} [{:03}] {:5}.{:06}: {}",
+            self.cpu,
+            self.timestamp / 1_000_000_000,
+            self.timestamp % 1_000_000_000 / 1000,
+            self.event
+        )
+    }
+}
+
+struct Buffer {
+    entries: VecDeque<Entry>,
+    overrun: u64,
+}
+
+static TRACING_ON: AtomicBool = AtomicBool::new(true);
+static ENABLED: [AtomicBool; Tracepoint::ALL.len()] =
+    [const { AtomicBool::new(false) }; Tracepoint::ALL.len()];
+static BUFFERS: [SpinNoIrq<Buffer>; CPU_NUM] = [const {
+    SpinNoIrq::new(Buffer {
+        entries: VecDeque::new(),
+        overrun: 0,
+    })
+}; CPU_NUM];
+static ENTRIES: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref POLL: PollSet = PollSet::new();
+}
+
+/// The last thread switched out on each CPU.
+static PREV_TID: [AtomicU32; CPU_NUM] = [const { AtomicU32::new(0) }; CPU_NUM];
+
+/// Returns whether recording is on.
+pub fn tracing_on() -> bool {
+    TRACING_ON.load(Ordering::Relaxed)
+}
+
+/// Turns recording on or off, keeping the buffers.
+pub fn set_tracing_on(on: bool) {
+    TRACING_ON.store(on, Ordering::Relaxed);
+}
+
+#[inline]
+fn should_record(tp: Tracepoint) -> bool {
+    tp.enabled() && tracing_on()
+}
+
+fn record(tid: u32, comm: &str, event: Event) {
+    let cpu = this_cpu_id();
+    let mut entry = Entry {
+        timestamp: monotonic_time_nanos(),
+        cpu,
+        tid,
+        comm: [0; 16],
+        event,
+    };
+    let len = comm.len().min(15);
+    entry.comm[..len].copy_from_slice(&comm.as_bytes()[..len]);
+
+    let mut buffer = BUFFERS[cpu].lock();
+    if buffer.entries.len() >= BUFFER_ENTRIES {
+        buffer.entries.pop_front();
+        buffer.overrun += 1;
+    } else {
+        ENTRIES.fetch_add(1, Ordering::Relaxed);
+    }
+    buffer.entries.push_back(entry);
+}
+
+fn record_current(event: Event) {
+    let curr = axtask::current();
+    let tid = curr.id().as_u64() as u32;
+    record(tid, curr.name(), event);
+    drop(curr);
+    POLL.wake();
+}
+
+/// The `sys_enter` tracepoint.
+#[inline]
+pub fn sys_enter(nr: usize, args: [usize; 6]) {
+    if should_record(Tracepoint::SysEnter) {
+        record_current(Event::SysEnter { nr, args });
+    }
+}
+
+/// The `sys_exit` tracepoint.
+#[inline]
+pub fn sys_exit(nr: usize, ret: isize) {
+    if should_record(Tracepoint::SysExit) {
+        record_current(Event::SysExit { nr, ret });
+    }
+}
+
+/// The `page_fault_user` tracepoint.
+#[inline]
+pub fn page_fault_user(address: usize, ip: usize, error_code: usize) {
+    if should_record(Tracepoint::PageFaultUser) {
+        record_current(Event::PageFaultUser {
+            address,
+            ip,
+            error_code,
+        });
+    }
+}
+
+/// Notes that thread `tid` was switched out.
+pub(crate) fn sched_out(tid: u32) {
+    PREV_TID[this_cpu_id()].store(tid, Ordering::Relaxed);
+}
+
+/// The `sched_switch` tracepoint, hit when thread `tid` is switched in.
+///
+/// Readers are not woken from here since this runs in the middle of a
+/// context switch; the entry is picked up along with the next event.
+pub(crate) fn sched_switch(tid: u32) {
+    // Kernel tasks have no thread hooks, so switches through them are folded
+    // into a switch between the user threads on either side.
+    let prev = PREV_TID[this_cpu_id()].load(Ordering::Relaxed);
+    if should_record(Tracepoint::SchedSwitch) {
+        record(tid, "", Event::SchedSwitch { prev, next: tid });
+    }
+}
+
+/// Returns the total number of entries in the buffers.
+pub fn entries() -> u64 {
+    ENTRIES.load(Ordering::Relaxed)
+}
+
+/// Returns the number of entries overwritten on each CPU.
+pub fn overruns() -> Vec<u64> {
+    BUFFERS.iter().map(|it| it.lock().overrun).collect()
+}
+
+/// Returns a copy of all entries, oldest first.
+pub fn snapshot() -> Vec<Entry> {
+    let mut entries: Vec<Entry> = BUFFERS
+        .iter()
+        .flat_map(|it| it.lock().entries.iter().copied().collect::<Vec<_>>())
+        .collect();
+    entries.sort_by_key(|it| it.timestamp);
+    entries
+}
+
+/// Calls `f` with the last `count` entries of each CPU, skipping buffers
+/// that are locked. Nothing is allocated, so this is fit for panics.
+pub fn for_each_recent(count: usize, mut f: impl FnMut(&Entry)) {
+    for buffer in &BUFFERS {
+        let Some(buffer) = buffer.try_lock() else {
+            continue;
+        };
+        let skip = buffer.entries.len().saturating_sub(count);
+        buffer.entries.iter().skip(skip).for_each(&mut f);
+    }
+}
+
+/// Removes and returns the oldest entry across all CPUs.
+pub fn consume() -> Option<Entry> {
+    let cpu = BUFFERS
+        .iter()
+        .enumerate()
+        .filter_map(|(cpu, it)| Some((cpu, it.lock().entries.front()?.timestamp)))
+        .min_by_key(|(_, timestamp)| *timestamp)?
+        .0;
+    let entry = BUFFERS[cpu].lock().entries.pop_front()?;
+    ENTRIES.fetch_sub(1, Ordering::Relaxed);
+    Some(entry)
+}
+
+/// Empties all buffers.
+pub fn clear() {
+    for buffer in &BUFFERS {
+        let mut buffer = buffer.lock();
+        ENTRIES.fetch_sub(buffer.entries.len() as u64, Ordering::Relaxed);
+        buffer.entries.clear();
+        buffer.overrun = 0;
+    }
+}
+
+/// Returns the readers waiting for new entries.
+pub fn poll_set() -> &'static PollSet {
+    &POLL
+}
+
+/// Formats the header printed at the top of `trace`.
+pub fn header() -> String {
+    let mut out = String::new();
+    let overrun: u64 = overruns().iter().sum();
+    let entries = entries();
+    let _ = write!(
+        out,
+        "# tracer: nop\n#\n# entries-in-buffer/entries-written: {entries}/{}   \
+         #P:{CPU_NUM}\n#\n#{:>21}   CPU  TIMESTAMP  FUNCTION\n#{:>21}    |      |         |\n",
+        entries + overrun,
+        "TASK-PID",
+        "| |"
+    );
+    out
+}
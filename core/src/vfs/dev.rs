@@ -48,10 +48,35 @@ pub trait DeviceOps: Send + Sync {
         DeviceMmap::None
     }
 
+    /// Returns the memory mapping behavior of the device for a mapping
+    /// starting at `offset`, for devices mapping different regions at
+    /// different offsets. A [`DeviceMmap::Physical`] range returned starts
+    /// at `offset`.
+    fn mmap_at(&self, offset: usize) -> DeviceMmap {
+        match self.mmap() {
+            DeviceMmap::Physical(mut range) => {
+                range.start += offset;
+                DeviceMmap::Physical(range)
+            }
+            mmap => mmap,
+        }
+    }
+
     /// Returns the flags for the device node.
     fn flags(&self) -> NodeFlags {
         NodeFlags::empty()
     }
+
+    /// Called when the device is opened by userspace.
+    fn open(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Called when the last reference to a file opened with [`open`] is
+    /// closed.
+    ///
+    /// [`open`]: DeviceOps::open
+    fn release(&self) {}
 }
 
 /// A device node in the filesystem.
@@ -87,6 +112,11 @@ impl Device {
     pub fn mmap(&self) -> DeviceMmap {
         self.ops.mmap()
     }
+
+    /// Returns the memory mapping behavior of the device at `offset`.
+    pub fn mmap_at(&self, offset: usize) -> DeviceMmap {
+        self.ops.mmap_at(offset)
+    }
 }
 
 #[inherit_methods(from = "self.node")]
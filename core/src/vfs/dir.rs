@@ -4,6 +4,7 @@ use alloc::{
     collections::btree_map::BTreeMap,
     string::String,
     sync::Arc,
+    vec::Vec,
 };
 use core::any::Any;
 
@@ -30,6 +31,12 @@ pub trait SimpleDirOps: Send + Sync + 'static {
         true
     }
 
+    /// Removes a child by name, as `unlink` does. Directories are fixed
+    /// unless they say otherwise.
+    fn remove_child(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
     /// Combines two directories into one.
     fn chain<N: SimpleDirOps>(self, other: N) -> ChainedDirOps<Self, N>
     where
@@ -91,6 +98,14 @@ impl<A: SimpleDirOps, B: SimpleDirOps> SimpleDirOps for ChainedDirOps<A, B> {
         // behavior is undefined.
         self.0.is_cacheable() && self.1.is_cacheable()
     }
+
+    fn remove_child(&self, name: &str) -> VfsResult<()> {
+        match self.0.lookup_child(name) {
+            Ok(_) => self.0.remove_child(name),
+            Err(VfsError::NotFound) => self.1.remove_child(name),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 /// Simple directory.
@@ -138,29 +153,55 @@ impl<O: SimpleDirOps> NodeOps for SimpleDir<O> {
     }
 }
 
+/// Returns the position of the child `name` in the listing of a
+/// [`SimpleDir`].
+///
+/// The children are generated anew for each listing, so they are listed in
+/// the order of a hash of their names, which lets a listing resume at the
+/// same place however the children have changed since.
+fn name_cookie(name: &str) -> u64 {
+    match name {
+        DOT => 0,
+        DOTDOT => 1,
+        // FNV-1a, kept small enough for the offsets to be valid `off_t`s.
+        _ => {
+            let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x100_0000_01b3)
+            });
+            (hash >> 2).max(2)
+        }
+    }
+}
+
 impl<O: SimpleDirOps> DirNodeOps for SimpleDir<O> {
     fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
-        let children = [DOT, DOTDOT]
+        let mut children = [DOT, DOTDOT]
             .into_iter()
             .map(Cow::Borrowed)
-            .chain(self.ops.child_names());
+            .chain(self.ops.child_names())
+            .map(|name| (name_cookie(&name), name))
+            .filter(|(cookie, _)| *cookie >= offset)
+            .collect::<Vec<_>>();
+        children.sort_unstable_by_key(|(cookie, _)| *cookie);
 
         let this_entry = self.this.upgrade().unwrap();
         let this_dir = this_entry.as_dir()?;
 
         let mut count = 0;
-        for (i, name) in children.enumerate().skip(offset as usize) {
+        for (cookie, name) in children {
             let metadata = match name.as_ref() {
                 DOT => this_entry.metadata(),
                 DOTDOT => this_entry
                     .parent()
                     .map_or_else(|| this_entry.metadata(), |parent| parent.metadata()),
-                other => {
-                    let entry = this_dir.lookup(other)?;
-                    entry.metadata()
-                }
+                other => match this_dir.lookup(other) {
+                    Ok(entry) => entry.metadata(),
+                    // Gone since the names were taken.
+                    Err(VfsError::NotFound) => continue,
+                    Err(err) => Err(err),
+                },
             }?;
-            if !sink.accept(&name, metadata.inode, metadata.node_type, i as u64 + 1) {
+            if !sink.accept(&name, metadata.inode, metadata.node_type, cookie + 1) {
                 break;
             }
             count += 1;
@@ -200,8 +241,8 @@ impl<O: SimpleDirOps> DirNodeOps for SimpleDir<O> {
         Err(VfsError::OperationNotPermitted)
     }
 
-    fn unlink(&self, _name: &str) -> VfsResult<()> {
-        Err(VfsError::OperationNotPermitted)
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        self.ops.remove_child(name)
     }
 
     fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
@@ -0,0 +1,178 @@
+//! Persistent file handles, as used by `name_to_handle_at(2)` and
+//! `open_by_handle_at(2)`.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::FsContext;
+use axfs_ng_vfs::{Location, Mountpoint, NodeType, WeakDirEntry};
+use spin::Mutex;
+
+use super::mount_roots;
+
+/// The size in bytes of an encoded [`FileHandle`].
+pub const FILE_HANDLE_SIZE: usize = 20;
+
+/// An opaque reference to a file that outlives any open descriptor of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHandle {
+    /// The device of the filesystem the file lives on.
+    pub fsid: u64,
+    /// The inode number of the file.
+    pub ino: u64,
+    /// Distinguishes files that reuse the inode number of a deleted one.
+    pub generation: u32,
+}
+
+impl FileHandle {
+    /// Encodes the handle into the bytes handed out to user space.
+    pub fn to_bytes(&self) -> [u8; FILE_HANDLE_SIZE] {
+        let mut buf = [0; FILE_HANDLE_SIZE];
+        buf[..8].copy_from_slice(&self.fsid.to_ne_bytes());
+        buf[8..16].copy_from_slice(&self.ino.to_ne_bytes());
+        buf[16..].copy_from_slice(&self.generation.to_ne_bytes());
+        buf
+    }
+
+    /// Decodes a handle previously produced by [`FileHandle::to_bytes`].
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() != FILE_HANDLE_SIZE {
+            return None;
+        }
+        Some(Self {
+            fsid: u64::from_ne_bytes(buf[..8].try_into().unwrap()),
+            ino: u64::from_ne_bytes(buf[8..16].try_into().unwrap()),
+            generation: u32::from_ne_bytes(buf[16..].try_into().unwrap()),
+        })
+    }
+}
+
+/// A file a handle has been handed out for.
+///
+/// The file is referred to weakly, so that handles neither keep it around
+/// nor keep its filesystem from being unmounted. Once it is gone from
+/// memory, it is looked up by inode number instead.
+struct Exported {
+    mountpoint: Weak<Mountpoint>,
+    entry: WeakDirEntry,
+}
+
+impl Exported {
+    fn location(&self) -> Option<Location> {
+        Some(Location::new(
+            self.mountpoint.upgrade()?,
+            self.entry.upgrade()?,
+        ))
+    }
+}
+
+/// Files that handles have been handed out for, keyed by filesystem and
+/// inode number.
+static EXPORTED: Mutex<BTreeMap<(u64, u64), Exported>> = Mutex::new(BTreeMap::new());
+
+/// Creates a handle for `loc`, whose inode is currently in its
+/// `generation`-th incarnation.
+///
+/// Filesystems that never reuse inode numbers can pass 0 as `generation`.
+pub fn export_handle(loc: &Location, generation: u32) -> AxResult<FileHandle> {
+    let handle = FileHandle {
+        fsid: loc.mountpoint().device() as u64,
+        ino: loc.metadata()?.inode,
+        generation,
+    };
+    let mut exported = EXPORTED.lock();
+    exported.retain(|_, it| it.location().is_some());
+    exported.insert(
+        (handle.fsid, handle.ino),
+        Exported {
+            mountpoint: Arc::downgrade(loc.mountpoint()),
+            entry: loc.entry().downgrade(),
+        },
+    );
+    Ok(handle)
+}
+
+/// Searches the mount whose root is `root` for the file with inode number
+/// `ino`, without descending into the mounts below it.
+fn find_inode(root: &Location, ino: u64) -> Option<Location> {
+    let same_mount = |loc: &Location| Arc::ptr_eq(loc.mountpoint(), root.mountpoint());
+    if root.metadata().ok()?.inode == ino {
+        return Some(root.clone());
+    }
+    let fs = FsContext::new(root.clone());
+    let mut dirs = Vec::from([String::new()]);
+    while let Some(dir) = dirs.pop() {
+        let Ok(loc) = fs.resolve_no_follow(if dir.is_empty() { "/" } else { &dir }) else {
+            continue;
+        };
+        if !same_mount(&loc) {
+            continue;
+        }
+        let mut found = None;
+        let _ = loc.read_dir(0, &mut |name: &str, entry_ino, node_type, _| {
+            if name == "." || name == ".." {
+                return true;
+            }
+            let path = format!("{dir}/{name}");
+            if entry_ino == ino {
+                found = Some(path);
+                return false;
+            }
+            if node_type == NodeType::Directory {
+                dirs.push(path);
+            }
+            true
+        });
+        if let Some(loc) = found.and_then(|path| fs.resolve_no_follow(&path).ok())
+            && same_mount(&loc)
+            && loc.metadata().is_ok_and(|it| it.inode == ino)
+        {
+            return Some(loc);
+        }
+    }
+    None
+}
+
+/// Resolves a handle back to the file it was created for.
+///
+/// A file that is still in memory is found directly. Otherwise the mount
+/// the handle was made on is searched for its inode number, and the file
+/// found is checked against the handle with `generation`.
+///
+/// Fails with `ESTALE` if that file has since been deleted or its
+/// filesystem unmounted.
+pub fn lookup_handle(
+    handle: &FileHandle,
+    generation: impl Fn(&Location) -> u32,
+) -> AxResult<Location> {
+    const ESTALE: AxError = AxError::Other(LinuxError::ESTALE);
+
+    let key = (handle.fsid, handle.ino);
+    let cached = EXPORTED.lock().get(&key).and_then(Exported::location);
+    let loc = match cached {
+        Some(loc) => loc,
+        None => mount_roots()
+            .iter()
+            .filter(|root| root.mountpoint().device() as u64 == handle.fsid)
+            .find_map(|root| find_inode(root, handle.ino))
+            .ok_or(ESTALE)?,
+    };
+    if loc.metadata()?.nlink == 0 || generation(&loc) != handle.generation {
+        EXPORTED.lock().remove(&key);
+        return Err(ESTALE);
+    }
+    EXPORTED.lock().insert(
+        key,
+        Exported {
+            mountpoint: Arc::downgrade(loc.mountpoint()),
+            entry: loc.entry().downgrade(),
+        },
+    );
+    Ok(loc)
+}
@@ -4,6 +4,9 @@ mod dev;
 mod dir;
 mod file;
 mod fs;
+mod handle;
+mod mount;
+mod quota;
 
 use alloc::sync::Arc;
 
@@ -12,6 +15,9 @@ pub use dev::*;
 pub use dir::*;
 pub use file::*;
 pub use fs::*;
+pub use handle::*;
+pub use mount::*;
+pub use quota::*;
 
 /// A callback that builds a `Arc<dyn DirNodeOps>` for a given
 /// `WeakDirEntry`.
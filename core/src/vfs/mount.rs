@@ -0,0 +1,227 @@
+//! The mount table and per-mount options.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng::FS_CONTEXT;
+use axfs_ng_vfs::Location;
+use bitflags::bitflags;
+use linux_raw_sys::general::{MS_NODEV, MS_NOEXEC, MS_NOSUID, MS_RDONLY};
+use spin::RwLock;
+
+use crate::mm::shrink_caches;
+
+bitflags! {
+    /// Options of a mount.
+    ///
+    /// The values match both the `MS_*` flags of `mount(2)` and the `ST_*`
+    /// flags reported in `f_flags` by `statfs(2)`.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct MountFlags: u32 {
+        /// Read-only.
+        const RDONLY = MS_RDONLY;
+        /// Ignore set-user-id and set-group-id bits.
+        const NOSUID = MS_NOSUID;
+        /// Disallow access to device files.
+        const NODEV = MS_NODEV;
+        /// Disallow program execution.
+        const NOEXEC = MS_NOEXEC;
+    }
+}
+
+impl MountFlags {
+    /// Formats the options the way `/proc/mounts` does.
+    fn options(&self) -> String {
+        let mut opts = String::from(if self.contains(Self::RDONLY) {
+            "ro"
+        } else {
+            "rw"
+        });
+        for (flag, name) in [
+            (Self::NOSUID, "nosuid"),
+            (Self::NODEV, "nodev"),
+            (Self::NOEXEC, "noexec"),
+        ] {
+            if self.contains(flag) {
+                opts.push(',');
+                opts.push_str(name);
+            }
+        }
+        opts
+    }
+}
+
+struct Mount {
+    id: u32,
+    root: Location,
+    source: String,
+    target: String,
+    fs_type: String,
+    flags: MountFlags,
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// The id of the root filesystem as long as it isn't recorded.
+const ROOT_MOUNT_ID: u32 = 1;
+
+static NEXT_MOUNT_ID: AtomicU32 = AtomicU32::new(ROOT_MOUNT_ID + 1);
+
+fn alloc_mount_id() -> u32 {
+    NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Records a mount whose root is `root`.
+pub fn add_mount(
+    root: Location,
+    source: String,
+    target: String,
+    fs_type: String,
+    flags: MountFlags,
+) {
+    MOUNTS.write().push(Mount {
+        id: alloc_mount_id(),
+        root,
+        source,
+        target,
+        fs_type,
+        flags,
+    });
+}
+
+/// Forgets the mount whose root is `root`.
+pub fn remove_mount(root: &Location) {
+    MOUNTS.write().retain(|m| !m.root.ptr_eq(root));
+}
+
+/// Returns how many references are held to the mount whose root is `root`
+/// from outside the mount tree, by open files, working directories, file
+/// mappings and the like.
+///
+/// Every `Location` on a mount keeps its `Mountpoint` alive, so these are
+/// the strong references left after discounting `root` itself, the one held
+/// by the directory it is mounted on and the one in the mount table.
+/// Mounts below it hold a `Location` on it too and so also count.
+///
+/// Kernel caches, such as the ELF cache, hold `Location`s as well. They are
+/// emptied before giving a non-zero count, so that they aren't taken for
+/// users.
+pub fn mount_users(root: &Location) -> usize {
+    let count = || {
+        let recorded = MOUNTS.read().iter().filter(|m| m.root.ptr_eq(root)).count();
+        Arc::strong_count(root.mountpoint()).saturating_sub(2 + recorded)
+    };
+    if count() == 0 {
+        return 0;
+    }
+    shrink_caches();
+    count()
+}
+
+/// Updates the mount whose root was `old_root` after it was moved to
+/// `target`, where its root is now `new_root`.
+pub fn move_mount(old_root: &Location, new_root: Location, target: String) {
+    if let Some(mount) = MOUNTS.write().iter_mut().find(|m| m.root.ptr_eq(old_root)) {
+        mount.root = new_root;
+        mount.target = target;
+    }
+}
+
+/// Replaces the options of the mount whose root is `root`.
+///
+/// A filesystem mounted without going through [`add_mount`], such as the
+/// root filesystem, is recorded now so that the options stick.
+pub fn set_mount_flags(root: &Location, flags: MountFlags) -> AxResult<()> {
+    let mut mounts = MOUNTS.write();
+    if let Some(mount) = mounts.iter_mut().find(|m| m.root.ptr_eq(root)) {
+        mount.flags = flags;
+        return Ok(());
+    }
+    mounts.push(Mount {
+        id: ROOT_MOUNT_ID,
+        root: root.clone(),
+        source: "rootfs".into(),
+        target: root.absolute_path()?.to_string(),
+        fs_type: root.filesystem().name().into(),
+        flags,
+    });
+    Ok(())
+}
+
+/// Returns the options of the mount containing `loc`.
+///
+/// Filesystems mounted without going through [`add_mount`], such as the
+/// root filesystem, have no options.
+pub fn mount_flags(loc: &Location) -> MountFlags {
+    let root = loc.mountpoint().root_location();
+    MOUNTS
+        .read()
+        .iter()
+        .find(|m| m.root.ptr_eq(&root))
+        .map_or(MountFlags::empty(), |m| m.flags)
+}
+
+/// Returns the unique id of the mount containing `loc`, as reported by
+/// `name_to_handle_at(2)`.
+pub fn mount_id(loc: &Location) -> u32 {
+    let root = loc.mountpoint().root_location();
+    MOUNTS
+        .read()
+        .iter()
+        .find(|m| m.root.ptr_eq(&root))
+        .map_or(ROOT_MOUNT_ID, |m| m.id)
+}
+
+/// Returns the roots of all mounts, the root filesystem first.
+pub fn mount_roots() -> Vec<Location> {
+    let root = FS_CONTEXT.lock().root_dir().clone();
+    let mut roots = Vec::from([root.mountpoint().root_location()]);
+    for m in MOUNTS.read().iter() {
+        if !roots.iter().any(|it| it.ptr_eq(&m.root)) {
+            roots.push(m.root.clone());
+        }
+    }
+    roots
+}
+
+/// Returns the root of the mount whose source is `source`, as `quotactl`
+/// finds a filesystem by the device it is mounted from.
+pub fn mount_by_source(source: &str) -> Option<Location> {
+    MOUNTS
+        .read()
+        .iter()
+        .find(|m| m.source == source)
+        .map(|m| m.root.clone())
+}
+
+/// Fails with `EROFS` if `loc` lives on a read-only mount.
+pub fn check_mount_writable(loc: &Location) -> AxResult<()> {
+    if mount_flags(loc).contains(MountFlags::RDONLY) {
+        return Err(AxError::ReadOnlyFilesystem);
+    }
+    Ok(())
+}
+
+/// Renders the mount table in the format of `/proc/mounts`.
+pub fn mounts_info() -> String {
+    let mut buf = String::from("rootfs / rootfs rw 0 0\n");
+    for m in MOUNTS.read().iter() {
+        let _ = writeln!(
+            buf,
+            "{} {} {} {} 0 0",
+            m.source,
+            m.target,
+            m.fs_type,
+            m.flags.options()
+        );
+    }
+    buf
+}
@@ -0,0 +1,290 @@
+//! Disk quotas, the per-user and per-group limits of `quotactl`.
+//!
+//! A filesystem keeps a [`DiskQuota`] and charges it whenever an inode is
+//! created or freed, a file changes size or an owner changes. Usage is always
+//! tracked, so turning quotas on needs no scan of the filesystem; the limits
+//! are only enforced while they are on. Privileged processes, like those
+//! with `CAP_SYS_RESOURCE` on Linux, may go over the limits.
+
+use alloc::collections::btree_map::BTreeMap;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::wall_time;
+use axsync::spin::SpinNoIrq;
+use axtask::current;
+
+use crate::task::AsThread;
+
+/// The default time usage may stay over a soft limit: a week, in seconds.
+const DEFAULT_GRACE: u64 = 7 * 24 * 60 * 60;
+
+/// The kinds of quotas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaType {
+    /// Limits per user (`USRQUOTA`).
+    User,
+    /// Limits per group (`GRPQUOTA`).
+    Group,
+}
+
+impl QuotaType {
+    /// Parses the type of a `quotactl` command.
+    pub fn from_raw(ty: u32) -> AxResult<Self> {
+        match ty {
+            0 => Ok(Self::User),
+            1 => Ok(Self::Group),
+            _ => Err(AxError::InvalidInput),
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The limits and usage of one user or group.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dquot {
+    /// The space that may never be exceeded, in bytes, or 0 for no limit.
+    pub space_hard: u64,
+    /// The space that may only be exceeded for the grace time, in bytes, or
+    /// 0 for no limit.
+    pub space_soft: u64,
+    /// The space in use, in bytes.
+    pub space: u64,
+    /// The number of inodes that may never be exceeded, or 0 for no limit.
+    pub inodes_hard: u64,
+    /// The number of inodes that may only be exceeded for the grace time,
+    /// or 0 for no limit.
+    pub inodes_soft: u64,
+    /// The number of inodes in use.
+    pub inodes: u64,
+    /// When the space soft limit starts being enforced, in seconds since the
+    /// epoch, or 0 while usage is under it.
+    pub space_deadline: u64,
+    /// When the inode soft limit starts being enforced, or 0 while usage is
+    /// under it.
+    pub inodes_deadline: u64,
+}
+
+impl Dquot {
+    fn is_empty(&self) -> bool {
+        self.space_hard == 0
+            && self.space_soft == 0
+            && self.space == 0
+            && self.inodes_hard == 0
+            && self.inodes_soft == 0
+            && self.inodes == 0
+    }
+
+    /// Checks that `space` more bytes and `inodes` more inodes stay within
+    /// the limits.
+    fn check(&self, space: i64, inodes: i64, now: u64) -> AxResult<()> {
+        fn over(usage: u64, delta: i64, hard: u64, soft: u64, deadline: u64, now: u64) -> bool {
+            if delta <= 0 {
+                return false;
+            }
+            let usage = usage.saturating_add_signed(delta);
+            (hard != 0 && usage > hard)
+                || (soft != 0 && usage > soft && deadline != 0 && now >= deadline)
+        }
+        if over(
+            self.space,
+            space,
+            self.space_hard,
+            self.space_soft,
+            self.space_deadline,
+            now,
+        ) || over(
+            self.inodes,
+            inodes,
+            self.inodes_hard,
+            self.inodes_soft,
+            self.inodes_deadline,
+            now,
+        ) {
+            return Err(AxError::Other(LinuxError::EDQUOT));
+        }
+        Ok(())
+    }
+
+    /// Starts or stops the grace periods as usage goes over or under the
+    /// soft limits.
+    fn update_deadlines(&mut self, now: u64, info: &QuotaInfo) {
+        if self.space_soft != 0 && self.space > self.space_soft {
+            if self.space_deadline == 0 {
+                self.space_deadline = now + info.space_grace;
+            }
+        } else {
+            self.space_deadline = 0;
+        }
+        if self.inodes_soft != 0 && self.inodes > self.inodes_soft {
+            if self.inodes_deadline == 0 {
+                self.inodes_deadline = now + info.inodes_grace;
+            }
+        } else {
+            self.inodes_deadline = 0;
+        }
+    }
+}
+
+/// The settings of one kind of quota.
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaInfo {
+    /// How long usage may stay over the space soft limit, in seconds.
+    pub space_grace: u64,
+    /// How long usage may stay over the inode soft limit, in seconds.
+    pub inodes_grace: u64,
+}
+
+impl Default for QuotaInfo {
+    fn default() -> Self {
+        Self {
+            space_grace: DEFAULT_GRACE,
+            inodes_grace: DEFAULT_GRACE,
+        }
+    }
+}
+
+#[derive(Default)]
+struct QuotaState {
+    enabled: bool,
+    info: QuotaInfo,
+    dquots: BTreeMap<u32, Dquot>,
+}
+
+impl QuotaState {
+    fn charge(&mut self, id: u32, space: i64, inodes: i64, now: u64) {
+        let dquot = self.dquots.entry(id).or_default();
+        dquot.space = dquot.space.saturating_add_signed(space);
+        dquot.inodes = dquot.inodes.saturating_add_signed(inodes);
+        dquot.update_deadlines(now, &self.info);
+        if dquot.is_empty() {
+            self.dquots.remove(&id);
+        }
+    }
+}
+
+/// Whether the current task may go over quota limits.
+fn exempt() -> bool {
+    current()
+        .try_as_thread()
+        .is_none_or(|thr| thr.proc_data.cred.read().is_privileged())
+}
+
+/// The user and group quotas of a filesystem.
+#[derive(Default)]
+pub struct DiskQuota {
+    states: SpinNoIrq<[QuotaState; 2]>,
+}
+
+impl DiskQuota {
+    /// Creates the quotas of a new filesystem, with no limits and quotas
+    /// off.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Charges the user and group `owner` with `space` more bytes and
+    /// `inodes` more inodes, or credits them if negative. Fails with
+    /// `EDQUOT` if that would go over the limits of either.
+    pub fn charge(&self, owner: (u32, u32), space: i64, inodes: i64) -> AxResult<()> {
+        let enforce = !exempt();
+        let now = wall_time().as_secs();
+        let mut states = self.states.lock();
+        let [user, group] = &mut *states;
+        for (state, id) in [(&*user, owner.0), (&*group, owner.1)] {
+            if enforce
+                && state.enabled
+                && let Some(dquot) = state.dquots.get(&id)
+            {
+                dquot.check(space, inodes, now)?;
+            }
+        }
+        user.charge(owner.0, space, inodes, now);
+        group.charge(owner.1, space, inodes, now);
+        Ok(())
+    }
+
+    /// Moves the usage of an inode holding `space` bytes from the owner
+    /// `from` to `to`, as `chown` does. Fails with `EDQUOT` if that would
+    /// go over the limits of the new owner.
+    pub fn transfer(&self, from: (u32, u32), to: (u32, u32), space: u64) -> AxResult<()> {
+        let enforce = !exempt();
+        let now = wall_time().as_secs();
+        let space = space as i64;
+        let mut states = self.states.lock();
+        let [user, group] = &mut *states;
+        for (state, from, to) in [(&*user, from.0, to.0), (&*group, from.1, to.1)] {
+            if enforce
+                && state.enabled
+                && from != to
+                && let Some(dquot) = state.dquots.get(&to)
+            {
+                dquot.check(space, 1, now)?;
+            }
+        }
+        for (state, from, to) in [(user, from.0, to.0), (group, from.1, to.1)] {
+            if from != to {
+                state.charge(from, -space, -1, now);
+                state.charge(to, space, 1, now);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether quotas of type `ty` are enforced.
+    pub fn is_on(&self, ty: QuotaType) -> bool {
+        self.states.lock()[ty.index()].enabled
+    }
+
+    /// Turns quotas of type `ty` on or off.
+    pub fn set_on(&self, ty: QuotaType, on: bool) -> AxResult<()> {
+        let state = &mut self.states.lock()[ty.index()];
+        if on && state.enabled {
+            return Err(AxError::ResourceBusy);
+        }
+        state.enabled = on;
+        Ok(())
+    }
+
+    /// Returns the limits and usage of user or group `id`.
+    pub fn get(&self, ty: QuotaType, id: u32) -> Dquot {
+        self.states.lock()[ty.index()]
+            .dquots
+            .get(&id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the first user or group from `id` on with limits or usage.
+    pub fn next(&self, ty: QuotaType, id: u32) -> Option<(u32, Dquot)> {
+        self.states.lock()[ty.index()]
+            .dquots
+            .range(id..)
+            .next()
+            .map(|(id, dquot)| (*id, *dquot))
+    }
+
+    /// Changes the limits or usage of user or group `id` with `f`.
+    pub fn update(&self, ty: QuotaType, id: u32, f: impl FnOnce(&mut Dquot)) {
+        let now = wall_time().as_secs();
+        let state = &mut self.states.lock()[ty.index()];
+        let dquot = state.dquots.entry(id).or_default();
+        f(dquot);
+        dquot.update_deadlines(now, &state.info);
+        if dquot.is_empty() {
+            state.dquots.remove(&id);
+        }
+    }
+
+    /// Returns the settings of quotas of type `ty`.
+    pub fn info(&self, ty: QuotaType) -> QuotaInfo {
+        self.states.lock()[ty.index()].info
+    }
+
+    /// Replaces the settings of quotas of type `ty`.
+    pub fn set_info(&self, ty: QuotaType, info: QuotaInfo) {
+        self.states.lock()[ty.index()].info = info;
+    }
+}
@@ -1,82 +1,156 @@
 use alloc::{
+    format,
     string::{String, ToString},
     sync::Arc,
+    vec,
+    vec::Vec,
 };
 
+use axerrno::AxResult;
 use axfs_ng::FS_CONTEXT;
 use axhal::uspace::UserContext;
 use axsync::Mutex;
-use axtask::{TaskExtProxy, spawn_task};
-use starry_api::{file::FD_TABLE, task::new_user_task, vfs::dev::tty::N_TTY};
+use axtask::{AxTaskRef, TaskExtProxy, spawn_task};
+use starry_api::{
+    file::{FD_TABLE, add_stdio},
+    task::{adopt_orphans, new_user_task},
+    vfs::dev::tty::N_TTY,
+};
 use starry_core::{
+    cmdline,
+    cred::Credentials,
     mm::{copy_from_kernel, load_user_app, new_user_aspace_empty},
     task::{ProcessData, Thread, add_task_to_table},
 };
 use starry_process::{Pid, Process};
 
+/// The file in the root filesystem with the environment of the init process,
+/// as `KEY=value` lines.
+const ENVIRONMENT_PATH: &str = "/etc/environment";
+
+/// Returns the environment of the init process.
+///
+/// It starts with `HOME=/` and `TERM=linux`, as on Linux, and takes the
+/// variables of `/etc/environment` and then those of the kernel command
+/// line, later ones overriding earlier ones.
+pub fn init_envs() -> Vec<String> {
+    let mut envs: Vec<(String, String)> =
+        vec![("HOME".into(), "/".into()), ("TERM".into(), "linux".into())];
+    let mut set = |key: &str, value: &str| match envs.iter_mut().find(|(k, _)| k == key) {
+        Some(env) => env.1 = value.into(),
+        None => envs.push((key.into(), value.into())),
+    };
+
+    if let Ok(content) = FS_CONTEXT.lock().read_to_string(ENVIRONMENT_PATH) {
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                warn!(
+                    "{}:{}: ignoring invalid entry",
+                    ENVIRONMENT_PATH,
+                    lineno + 1
+                );
+                continue;
+            };
+            let value = value.trim();
+            let value = ['"', '\'']
+                .into_iter()
+                .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote))
+                .unwrap_or(value);
+            set(key.trim(), value);
+        }
+    }
+    for (key, value) in cmdline::init_envs() {
+        set(key, value);
+    }
+
+    envs.into_iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect()
+}
+
 pub fn run_initproc(args: &[String], envs: &[String]) -> i32 {
-    let mut uspace = new_user_aspace_empty()
-        .and_then(|mut it| {
-            copy_from_kernel(&mut it)?;
-            Ok(it)
-        })
-        .expect("Failed to create user address space");
-
-    // Change working directory to /rknn_yolov8_demo before resolving the executable
-    let rknn_dir = {
+    let (task, proc) = spawn_initproc(args, envs, None)
+        .unwrap_or_else(|e| panic!("Failed to start init process: {}", e));
+    let exit_code = task.join();
+    adopt_orphans(proc);
+    exit_code
+}
+
+/// Starts a process without a parent running `args`, with its standard I/O
+/// on the console `tty` (see [`add_stdio`]).
+///
+/// The process takes the console as its controlling terminal unless `tty`
+/// is given.
+pub fn spawn_initproc(
+    args: &[String],
+    envs: &[String],
+    tty: Option<&str>,
+) -> AxResult<(AxTaskRef, Arc<Process>)> {
+    let mut uspace = new_user_aspace_empty()?;
+    copy_from_kernel(&mut uspace)?;
+    let uspace = Arc::new(Mutex::new(uspace));
+
+    // Start init in the directory it is in, so that it can find the files
+    // next to it by relative paths.
+    let init_dir = {
         let mut cx = FS_CONTEXT.lock();
-        if let Ok(dir) = cx.resolve("/rknn_yolov8_demo") {
+        let dir = args[0]
+            .rsplit_once('/')
+            .map(|(dir, _)| if dir.is_empty() { "/" } else { dir })
+            .and_then(|dir| cx.resolve(dir).ok());
+        if let Some(dir) = &dir {
             let _ = cx.set_current_dir(dir.clone());
-            Some(dir)
-        } else {
-            None
         }
+        dir
     };
 
-    let loc = FS_CONTEXT
-        .lock()
-        .resolve(&args[0])
-        .expect("Failed to resolve executable path");
-    let path = loc
-        .absolute_path()
-        .expect("Failed to get executable absolute path");
+    let loc = FS_CONTEXT.lock().resolve(&args[0])?;
+    let path = loc.absolute_path()?;
     let name = loc.name();
 
-    let (entry_vaddr, ustack_top) = load_user_app(&mut uspace, None, args, envs)
-        .unwrap_or_else(|e| panic!("Failed to load user app: {}", e));
+    let (entry_vaddr, ustack_top) =
+        load_user_app(&uspace, None, args, envs, &Credentials::default())?;
 
     let uctx = UserContext::new(entry_vaddr.into(), ustack_top, 0);
 
 
     info!("Init process: {}", name);
     let mut task = new_user_task(name, uctx, None);
-    task.ctx_mut().set_page_table_root(uspace.page_table_root());
+    task.ctx_mut().set_page_table_root(uspace.lock().page_table_root());
 
     let pid = task.id().as_u64() as Pid;
     let proc = Process::new_init(pid);
     proc.add_thread(pid);
 
-    N_TTY.bind_to(&proc).expect("Failed to bind ntty");
+    if tty.is_none() {
+        N_TTY.bind_to(&proc)?;
+    }
 
     let proc_data = ProcessData::new(
-        proc,
+        proc.clone(),
         path.to_string(),
+        loc,
         Arc::new(args.to_vec()),
-        Arc::new(Mutex::new(uspace)),
+        Arc::new(envs.to_vec()),
+        uspace,
         Arc::default(),
         None,
     );
     
     // Set the working directory for the process
-    if let Some(dir) = rknn_dir {
+    if let Some(dir) = init_dir {
         let mut scope = proc_data.scope.write();
         FS_CONTEXT.scope_mut(&mut scope).lock().set_current_dir(dir).unwrap();
     }
     
     {
         let mut scope = proc_data.scope.write();
-        starry_api::file::add_stdio(&mut FD_TABLE.scope_mut(&mut scope).write())
-            .expect("Failed to add stdio");
+        add_stdio(&mut FD_TABLE.scope_mut(&mut scope).write(), tty)?;
     }
     let thr = Thread::new(pid, proc_data);
 
@@ -84,7 +158,5 @@ pub fn run_initproc(args: &[String], envs: &[String]) -> i32 {
 
     let task = spawn_task(task);
     add_task_to_table(&task);
-
-    // TODO: wait for all processes to finish
-    task.join()
+    Ok((task, proc))
 }
\ No newline at end of file
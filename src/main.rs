@@ -11,33 +11,70 @@ extern crate axdriver_dyn;
 extern crate axruntime;
 
 use alloc::{borrow::ToOwned, vec::Vec};
+use core::iter;
 
-use axfs_ng::FS_CONTEXT;
+use starry_api::{
+    power::{self, PowerAction},
+    vfs,
+};
+use starry_core::{cmdline, sysctl::INIT_EXIT_ACTION};
 
 mod entry;
+mod supervisor;
 
 pub const CMDLINE: &[&str] = &["/rknn_yolov8_demo/rknn_yolov8_demo", "/rknn_yolov8_demo/model/yolov8.rknn", "/rknn_yolov8_demo/model/bus.jpg"];
 #[unsafe(no_mangle)]
 fn main() {
+    axruntime::set_panic_hook(starry_api::panic::report);
     starry_api::init();
 
-    let args = CMDLINE
-        .iter()
-        .copied()
-        .map(str::to_owned)
-        .collect::<Vec<_>>();
-    let envs = [];
-    let exit_code = entry::run_initproc(&args, &envs);
-    info!("Init process exited with code: {:?}", exit_code);
-
-    let cx = FS_CONTEXT.lock();
-    cx.root_dir()
-        .unmount_all()
-        .expect("Failed to unmount all filesystems");
-    cx.root_dir()
-        .filesystem()
-        .flush()
-        .expect("Failed to flush rootfs");
+    let exit_code = match cmdline::get("inittab") {
+        // `inittab=` on the kernel command line makes the kernel supervise
+        // the services listed there itself.
+        Some(inittab) => {
+            if let Err(err) = supervisor::run_services(inittab) {
+                panic!("Failed to run the services of {inittab}: {err:?}");
+            }
+            0
+        }
+        None => run_init(),
+    };
+
+    match INIT_EXIT_ACTION.get() {
+        1 => power::shutdown(PowerAction::Restart),
+        2 => panic!("Attempted to kill init! exitcode={exit_code:#010x}"),
+        _ => power::shutdown(PowerAction::PowerOff),
+    }
+}
+
+/// Runs the init process, starting it again on soft reboots, and returns
+/// its exit code.
+fn run_init() -> i32 {
+    // The init of the initramfs or `init=` on the kernel command line
+    // replace the built-in init process. Arguments after `--` replace those
+    // of the built-in one.
+    let args = match vfs::rdinit().or_else(|| cmdline::get("init")) {
+        Some(init) => iter::once(init.to_owned())
+            .chain(cmdline::init_args().iter().cloned())
+            .collect::<Vec<_>>(),
+        None if !cmdline::init_args().is_empty() => iter::once(CMDLINE[0].to_owned())
+            .chain(cmdline::init_args().iter().cloned())
+            .collect::<Vec<_>>(),
+        None => CMDLINE
+            .iter()
+            .copied()
+            .map(str::to_owned)
+            .collect::<Vec<_>>(),
+    };
+    let envs = entry::init_envs();
+    loop {
+        let exit_code = entry::run_initproc(&args, &envs);
+        info!("Init process exited with code: {:?}", exit_code);
+        if !power::take_soft_reboot() {
+            break exit_code;
+        }
+        info!("Soft reboot, starting the init process again");
+    }
 }
 
 #[cfg(feature = "vf2")]
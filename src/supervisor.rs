@@ -0,0 +1,165 @@
+//! Service supervisor mode.
+//!
+//! With `inittab=<path>` on the kernel command line, the kernel starts the
+//! services listed in that file instead of a single init process. Each line
+//! is `<tty>::<action>:<command>`, as in busybox's inittab:
+//!
+//! - `sysinit` and `wait` run the command and wait for it to exit before going
+//!   on to the next line;
+//! - `once` starts the command;
+//! - `respawn` starts the command and starts it again whenever it exits,
+//!   waiting twice as long each time it exits within [`STABLE_RUN`], up to
+//!   [`MAX_BACKOFF`].
+//!
+//! `<tty>` names the device below `/dev` the command's standard I/O goes to,
+//! the console if empty. Blank lines and lines starting with `#` are
+//! ignored. The supervisor returns once every service is done for good.
+
+use alloc::{
+    borrow::ToOwned,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::time::Duration;
+
+use axerrno::{AxError, AxResult};
+use axfs_ng::FS_CONTEXT;
+use axhal::time::monotonic_time;
+use starry_api::task::adopt_orphans;
+
+use crate::entry::{init_envs, spawn_initproc};
+
+/// A service that ran at least this long before exiting is restarted right
+/// away.
+const STABLE_RUN: Duration = Duration::from_secs(10);
+/// The delay before restarting a service that keeps exiting quickly starts
+/// at this and doubles each time.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest delay before restarting a service.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    SysInit,
+    Wait,
+    Once,
+    Respawn,
+}
+
+struct Service {
+    tty: Option<String>,
+    action: Action,
+    args: Vec<String>,
+}
+
+impl Service {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(4, ':');
+        let tty = fields.next()?.trim();
+        let _runlevels = fields.next()?;
+        let action = match fields.next()?.trim() {
+            "sysinit" => Action::SysInit,
+            "wait" => Action::Wait,
+            "once" => Action::Once,
+            "respawn" => Action::Respawn,
+            _ => return None,
+        };
+        let args = fields
+            .next()?
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if args.is_empty() {
+            return None;
+        }
+        Some(Self {
+            tty: (!tty.is_empty()).then(|| tty.trim_start_matches("/dev/").to_string()),
+            action,
+            args,
+        })
+    }
+
+    /// Runs the service once, returning its exit code.
+    fn run(&self) -> AxResult<i32> {
+        let (task, proc) = spawn_initproc(&self.args, &init_envs(), self.tty.as_deref())?;
+        let exit_code = task.join();
+        adopt_orphans(proc);
+        Ok(exit_code)
+    }
+
+    /// Runs the service, again and again for `respawn`.
+    fn supervise(&self) {
+        let mut backoff = Duration::ZERO;
+        loop {
+            let started = monotonic_time();
+            match self.run() {
+                Ok(exit_code) => {
+                    info!("Service {} exited with code: {:?}", self.args[0], exit_code)
+                }
+                Err(err) => warn!("Failed to start service {}: {:?}", self.args[0], err),
+            }
+            if self.action != Action::Respawn {
+                return;
+            }
+
+            backoff = if monotonic_time() - started >= STABLE_RUN {
+                Duration::ZERO
+            } else {
+                (backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF)
+            };
+            if !backoff.is_zero() {
+                info!(
+                    "Restarting service {} in {}s",
+                    self.args[0],
+                    backoff.as_secs()
+                );
+                axtask::sleep(backoff);
+            }
+        }
+    }
+}
+
+/// Reads the services listed in the inittab at `path`.
+fn read_inittab(path: &str) -> AxResult<Vec<Service>> {
+    let content = FS_CONTEXT.lock().read_to_string(path)?;
+    let mut services = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Service::parse(line) {
+            Some(service) => services.push(service),
+            None => warn!("{}:{}: ignoring invalid entry", path, lineno + 1),
+        }
+    }
+    if services.is_empty() {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(services)
+}
+
+/// Starts the services listed in the inittab at `path` and supervises them
+/// until they are all done.
+pub fn run_services(path: &str) -> AxResult<()> {
+    let services = read_inittab(path)?;
+    info!("Supervising {} services from {}", services.len(), path);
+
+    // `sysinit` entries come first, whatever their place in the file.
+    let (sysinit, rest): (Vec<_>, Vec<_>) = services
+        .into_iter()
+        .partition(|it| it.action == Action::SysInit);
+    let mut tasks = Vec::new();
+    for service in sysinit.into_iter().chain(rest) {
+        if service.action == Action::Wait || service.action == Action::SysInit {
+            service.supervise();
+        } else {
+            let name = service.args[0].clone();
+            tasks.push(axtask::spawn(move || service.supervise(), name));
+        }
+    }
+    for task in tasks {
+        task.join();
+    }
+    Ok(())
+}